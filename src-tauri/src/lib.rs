@@ -4,14 +4,14 @@ use std::ffi::OsStr;
 use std::os::windows::{ffi::OsStrExt, process::CommandExt};
 use std::{
     borrow::Cow,
-    collections::{HashSet, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     fs,
     hash::{Hash, Hasher},
-    io::{Cursor, ErrorKind},
+    io::{Cursor, ErrorKind, Write},
     path::{Path, PathBuf},
     process::{Command, Stdio},
     sync::{
-        atomic::{AtomicBool, AtomicIsize, AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicIsize, AtomicU64, AtomicUsize, Ordering},
         Mutex,
     },
     time::{Duration, SystemTime, UNIX_EPOCH},
@@ -19,13 +19,14 @@ use std::{
 
 use arboard::{Clipboard, ImageData};
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Local, Utc};
 use image::{DynamicImage, ImageFormat, RgbaImage};
 use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
 use rfd::FileDialog;
 use screenshots::Screen;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use tauri::{
     image::Image as TauriImage,
     menu::MenuBuilder,
@@ -68,8 +69,8 @@ use windows_sys::Win32::UI::Shell::ShellExecuteW;
 #[cfg(target_os = "windows")]
 use windows_sys::Win32::UI::WindowsAndMessaging::{
     GetAncestor, GetClassNameW, GetCursorPos, GetForegroundWindow, GetWindowInfo, GetWindowRect,
-    GetWindowThreadProcessId, IsIconic, IsWindow, SetForegroundWindow, ShowWindow, WindowFromPoint,
-    GA_ROOT, SW_RESTORE, SW_SHOWNORMAL, WINDOWINFO,
+    GetWindowTextW, GetWindowThreadProcessId, IsIconic, IsWindow, SetForegroundWindow, ShowWindow,
+    WindowFromPoint, GA_ROOT, SW_RESTORE, SW_SHOWNORMAL, WINDOWINFO,
 };
 
 const SETTINGS_VERSION: u32 = 9;
@@ -79,6 +80,11 @@ const DEFAULT_TOGGLE_OCR_SHORTCUT: &str = "Alt+S";
 const SETTINGS_FILENAME: &str = "settings.json";
 const SETTINGS_BACKUP_FILENAME: &str = "settings.bak.json";
 const HISTORY_FILENAME: &str = "clipboard_history.json";
+const RESPONSE_CACHE_FILENAME: &str = "llm_response_cache.json";
+const USAGE_LEDGER_FILENAME: &str = "model_usage.json";
+const ACTION_LOG_FILENAME: &str = "action_history.jsonl";
+const WORD_LIST_FILENAME: &str = "word_list.json";
+const OCR_CAPTURE_IMAGE_DIR: &str = "ocr_captures";
 const SETTINGS_UPDATED_EVENT: &str = "snapparse://settings-updated";
 const SETTINGS_WINDOW_SHOWN_EVENT: &str = "snapparse://settings-window-shown";
 const MAIN_WINDOW_SHOWN_EVENT: &str = "snapparse://main-window-shown";
@@ -121,7 +127,6 @@ const DEFAULT_TTS_VOICE_EN_US: &str = "en-US-JennyNeural";
 const MIN_TTS_RATE_PERCENT: i32 = -50;
 const MAX_TTS_RATE_PERCENT: i32 = 100;
 const MAX_TTS_TEXT_CHARS: usize = 12_000;
-const TASK_REPLACED_ERROR: &str = "__TASK_REPLACED__";
 const SELECTION_REPEAT_DEDUPE_WINDOW_MS: u64 = 900;
 const SELECTION_TEXT_COOLDOWN_MS: u64 = 2_500;
 const SELECTION_RECENT_DISMISS_COOLDOWN_MS: u64 = 950;
@@ -137,9 +142,192 @@ const COPY_TRIGGER_CTRL_HOLD_MS: u64 = 380;
 const MODEL_REQUEST_MAX_ATTEMPTS: usize = 3;
 const MODEL_REQUEST_RETRY_BASE_DELAY_MS: u64 = 140;
 const MODEL_REQUEST_RETRY_MAX_DELAY_MS: u64 = 850;
+const MODEL_REQUEST_RETRY_AFTER_MAX_MS: u64 = 30_000;
+const MODEL_RATE_LIMIT_MAX_WAIT_SLICE_MS: u64 = 1_000;
+const MAX_MODEL_REQUESTS_PER_MINUTE: u32 = 10_000;
+const MAX_MODEL_TOKENS_PER_MINUTE: u32 = 10_000_000;
+const VISION_IMAGE_TOKEN_ESTIMATE: u32 = 1_024;
 const GLM_OCR_TEST_IMAGE_URL: &str = "https://cdn.bigmodel.cn/static/logo/introduction.png";
 const MAX_SETTINGS_FILE_BYTES: u64 = 2 * 1024 * 1024;
 const MAX_HISTORY_FILE_BYTES: u64 = 256 * 1024 * 1024;
+const MIN_RESPONSE_CACHE_SIZE_MB: u32 = 1;
+const MAX_RESPONSE_CACHE_SIZE_MB: u32 = 512;
+const MIN_RESPONSE_CACHE_TTL_HOURS: u32 = 1;
+const MAX_RESPONSE_CACHE_TTL_HOURS: u32 = 24 * 90;
+const MAX_EXTRA_HEADERS_PER_PROFILE: usize = 32;
+const MAX_GLOSSARY_ENTRIES: usize = 2_000;
+const MAX_DO_NOT_TRANSLATE_TERMS: usize = 500;
+const MAX_PROMPT_GLOSSARY_TERMS: usize = 60;
+const MAX_STOP_SEQUENCES: usize = 4;
+const MAX_LLM_FALLBACK_PROFILES: usize = 8;
+const DEFAULT_CHUNK_TOKENS: u32 = 3_000;
+const MIN_CHUNK_TOKENS: u32 = 500;
+const MAX_CHUNK_TOKENS: u32 = 64_000;
+const MAX_SUMMARY_REDUCE_ROUNDS: usize = 3;
+const PROVIDER_FAILURE_COOLDOWN_MS: u64 = 30_000;
+const PROVIDER_FAILURE_MAX_COOLDOWN_MS: u64 = 10 * 60_000;
+/// Request fields set from settings or per action, which `extra_body` may not override.
+const RESERVED_REQUEST_BODY_KEYS: [&str; 10] = [
+    "model",
+    "messages",
+    "stream",
+    "stream_options",
+    "temperature",
+    "max_tokens",
+    "top_p",
+    "tools",
+    "tool_choice",
+    "response_format",
+];
+const RESPONSE_CACHE_ENTRY_OVERHEAD_BYTES: usize = 160;
+/// Cache hits only touch `last_used_at_ms` in memory; this many of them trigger a save.
+const RESPONSE_CACHE_HIT_PERSIST_BATCH: u32 = 16;
+/// Saves requested within this window share one write of the cache file.
+const RESPONSE_CACHE_PERSIST_DELAY_MS: u64 = 2_000;
+const USAGE_LEDGER_RETENTION_DAYS: i64 = 400;
+const DEFAULT_USAGE_STATS_DAYS: u32 = 30;
+const MAX_USAGE_STATS_DAYS: u32 = 366;
+const MAX_USAGE_PRICE_OVERRIDES: usize = 64;
+const MAX_MONTHLY_BUDGET_USD: f64 = 100_000.0;
+const MAX_CONVERSATION_SESSIONS: usize = 24;
+const MAX_CONVERSATION_FOLLOW_UPS: usize = 20;
+const MAX_CONVERSATION_MESSAGE_CHARS: usize = 8_000;
+const DEFAULT_CONTEXT_TOKENS: u32 = 16_000;
+const MIN_CONTEXT_TOKENS: u32 = 2_000;
+const MAX_CONTEXT_TOKENS: u32 = 2_000_000;
+const MAX_ACTION_LOG_ENTRIES: usize = 500;
+/// The append-only log is rewritten with only the retained entries once it holds this many.
+const ACTION_LOG_COMPACT_RECORDS: usize = MAX_ACTION_LOG_ENTRIES * 2;
+const DEFAULT_ACTION_LOG_PAGE_SIZE: usize = 50;
+const MAX_WORD_LIST_ENTRIES: usize = 5_000;
+const APP_DISPLAY_NAME: &str = "SnapParse";
+const PROMPT_TEMPLATE_VARIABLES: &[&str] = &[
+    "text",
+    "output_language",
+    "target_language",
+    "source_language",
+    "app_name",
+    "date",
+];
+const CUSTOM_AGENT_TEMPLATE_VARIABLES: &[&str] = &[
+    "text",
+    "ui_language",
+    "target_language",
+    "clipboard",
+    "source_app",
+    "window_title",
+    "date",
+    "time",
+    "app_name",
+];
+const MAX_PROMPT_CONSTANTS: usize = 50;
+const MAX_CUSTOM_AGENTS: usize = 30;
+const MAX_WORKFLOW_STEPS: usize = 8;
+const MAX_OUTPUT_SCHEMA_CHARS: usize = 20_000;
+const STRUCTURED_OUTPUT_MAX_ATTEMPTS: usize = 3;
+const MAX_TOOL_CALL_ROUNDS: usize = 5;
+const MAX_TOOL_OUTPUT_CHARS: usize = 4000;
+const MAX_TOOL_HISTORY_RESULTS: usize = 20;
+// Tool calls one streamed reply may carry; higher indexes are ignored.
+const MAX_TOOL_CALLS_PER_REPLY: usize = 32;
+const MAX_MATH_EXPRESSION_CHARS: usize = 1000;
+const MAX_MATH_NESTING: usize = 64;
+// Token pairs the Polish diff table may hold; about 8 MB of u16 lengths.
+const MAX_DIFF_CELLS: usize = 4_000_000;
+const JSON_SCHEMA_TYPES: [&str; 7] = [
+    "object", "array", "string", "number", "integer", "boolean", "null",
+];
+const AGENT_PACK_FORMAT: &str = "snapparse-agent-pack";
+const AGENT_PACK_VERSION: u32 = 1;
+/// Icon keys the settings UI can render for custom agents.
+const SUPPORTED_AGENT_ICONS: &[&str] = &[
+    "Activity",
+    "Archive",
+    "Bell",
+    "Sparkles",
+    "Bot",
+    "Info",
+    "Brain",
+    "Briefcase",
+    "Bug",
+    "Calculator",
+    "Calendar",
+    "Camera",
+    "CheckCircle2",
+    "Cloud",
+    "Lightbulb",
+    "MessageSquare",
+    "BookOpen",
+    "FileText",
+    "Code2",
+    "Compass",
+    "Cpu",
+    "Crown",
+    "Feather",
+    "Gem",
+    "Heart",
+    "KeyRound",
+    "Landmark",
+    "Layers",
+    "Lock",
+    "Mail",
+    "Mic",
+    "Monitor",
+    "Palette",
+    "Terminal",
+    "PenTool",
+    "Ruler",
+    "Sun",
+    "Timer",
+    "Wand2",
+    "Rocket",
+    "Zap",
+    "Globe2",
+    "Search",
+    "Languages",
+    "Copy",
+    "Star",
+    "Link2",
+    "Type",
+    "ScanSearch",
+    "SlidersHorizontal",
+    "ClipboardList",
+    "ImageIcon",
+    "Shield",
+    "Moon",
+    "Pin",
+    "Settings",
+    "ExternalLink",
+];
+const DICTIONARY_MAX_WORDS: usize = 4;
+const DICTIONARY_MAX_CHARS: usize = 48;
+const DICTIONARY_MAX_CJK_CHARS: usize = 8;
+// USD per million prompt / completion tokens. Matched by longest model-name prefix; entries in
+// `usage.priceOverrides` take precedence.
+const MODEL_PRICE_TABLE: &[(&str, f64, f64)] = &[
+    ("gpt-4o-mini", 0.15, 0.6),
+    ("gpt-4o", 2.5, 10.0),
+    ("gpt-4.1-nano", 0.1, 0.4),
+    ("gpt-4.1-mini", 0.4, 1.6),
+    ("gpt-4.1", 2.0, 8.0),
+    ("gpt-3.5-turbo", 0.5, 1.5),
+    ("o3-mini", 1.1, 4.4),
+    ("o4-mini", 1.1, 4.4),
+    ("deepseek-chat", 0.27, 1.1),
+    ("deepseek-reasoner", 0.55, 2.19),
+    ("glm-4-flash", 0.0, 0.0),
+    ("glm-4-air", 0.07, 0.07),
+    ("glm-4-plus", 0.7, 0.7),
+    ("glm-4v-flash", 0.0, 0.0),
+    ("qwen-turbo", 0.05, 0.2),
+    ("qwen-plus", 0.4, 1.2),
+    ("qwen-max", 1.6, 6.4),
+    ("qwen-vl-plus", 0.21, 0.63),
+    ("claude-3-5-haiku", 0.8, 4.0),
+    ("claude-3-5-sonnet", 3.0, 15.0),
+    ("gemini-1.5-flash", 0.075, 0.3),
+    ("gemini-2.0-flash", 0.1, 0.4),
+];
 const SELECTION_DETECTOR_STALE_MS: u64 = 8_000;
 const MAX_CLIPBOARD_TEXT_CHARS: usize = 120_000;
 const MAX_CLIPBOARD_IMAGE_DATA_URL_CHARS: usize = 8_000_000;
@@ -164,15 +352,24 @@ const TRAY_MENU_SETTINGS_ID: &str = "tray-open-settings";
 const TRAY_MENU_QUIT_ID: &str = "tray-quit";
 const TRAY_ICON_PNG_BYTES: &[u8] = include_bytes!("../icons/tray-icon.png");
 const AUTOSTART_ARG: &str = "--autostart";
-const BUILTIN_SELECTION_BAR_KEYS: [&str; 6] = [
+const BUILTIN_SELECTION_BAR_KEYS: [&str; 7] = [
     "copy",
     "summarize",
     "polish",
     "explain",
     "translate",
     "search",
+    POLISH_REPLACE_BAR_KEY,
 ];
+/// Polishes the selection and pastes the result over it without opening the result window.
+const POLISH_REPLACE_BAR_KEY: &str = "polish-replace";
+// How long the target application gets to read the clipboard before it is restored.
+const REPLACE_CLIPBOARD_RESTORE_DELAY_MS: u64 = 350;
 const MAX_ENABLED_SELECTION_BAR_ITEMS: usize = 8;
+const MAX_APP_SELECTION_RULES: usize = 32;
+// The classifier only looks at this many leading characters so it stays cheap on every detection.
+const CONTENT_CLASSIFIER_SAMPLE_CHARS: usize = 4_000;
+const LONG_PARAGRAPH_MIN_CHARS: usize = 600;
 const MIN_SELECTION_BAR_OPACITY: f32 = 0.35;
 const MAX_SELECTION_BAR_OPACITY: f32 = 0.94;
 
@@ -269,6 +466,26 @@ enum TtsRuntimeMode {
     PythonModuleOnly,
 }
 
+/// How the result window presents the reasoning text of thinking models.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+enum ReasoningDisplay {
+    Hide,
+    #[default]
+    Collapse,
+    Show,
+}
+
+/// Whether translations are aligned with their source, and at which granularity.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+enum BilingualMode {
+    #[default]
+    Off,
+    Paragraph,
+    Sentence,
+}
+
 #[derive(Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase", default)]
 struct SelectionAssistantSettings {
@@ -282,8 +499,19 @@ struct SelectionAssistantSettings {
     max_chars: usize,
     blocked_apps: Vec<String>,
     default_translate_to: String,
+    /// Translate into the UI language unless the text is already in it. Settings saved before
+    /// this option existed keep their fixed target.
+    #[serde(default)]
+    smart_translate_target: bool,
+    bilingual_mode: BilingualMode,
+    /// Look up short selections as dictionary entries instead of translating them.
+    dictionary_mode: bool,
     result_window_always_on_top: bool,
     remember_result_window_position: bool,
+    /// Per-application overrides, matched against the source window's process.
+    app_rules: Vec<AppSelectionRule>,
+    /// Move and highlight bar actions that suit the detected content type.
+    smart_bar_suggestions: bool,
 }
 
 impl Default for SelectionAssistantSettings {
@@ -299,8 +527,82 @@ impl Default for SelectionAssistantSettings {
             max_chars: 12_000,
             blocked_apps: Vec::new(),
             default_translate_to: "en-US".to_string(),
+            smart_translate_target: true,
+            bilingual_mode: BilingualMode::Off,
+            dictionary_mode: true,
             result_window_always_on_top: true,
             remember_result_window_position: true,
+            app_rules: Vec::new(),
+            smart_bar_suggestions: true,
+        }
+    }
+}
+
+/// Overrides the selection bar for selections made in a matching application. `app` is a
+/// case-insensitive fragment of the executable name or path, like the blocked apps list.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+struct AppSelectionRule {
+    id: String,
+    app: String,
+    enabled: bool,
+    /// Bar keys shown in this order; empty keeps the global bar order.
+    bar_items: Vec<String>,
+    /// Bar key run immediately instead of showing the bar; empty shows the bar.
+    auto_run: String,
+    /// Source language the selection must be in for `auto_run`, also sent as the translation
+    /// source; empty detects it.
+    translate_from: String,
+    /// Translation target for this application; empty uses the global target.
+    translate_to: String,
+}
+
+impl Default for AppSelectionRule {
+    fn default() -> Self {
+        Self {
+            id: String::new(),
+            app: String::new(),
+            enabled: true,
+            bar_items: Vec::new(),
+            auto_run: String::new(),
+            translate_from: String::new(),
+            translate_to: String::new(),
+        }
+    }
+}
+
+/// An extra HTTP header sent with every request to one model provider.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct ExtraHeader {
+    name: String,
+    value: String,
+}
+
+/// An alternative OpenAI-compatible endpoint. Sampling, timeout and rate limit settings are
+/// shared with the primary LLM settings; an empty model reuses the primary model name.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+struct LlmProviderProfile {
+    id: String,
+    name: String,
+    enabled: bool,
+    base_url: String,
+    api_key: String,
+    model: String,
+    extra_headers: Vec<ExtraHeader>,
+}
+
+impl Default for LlmProviderProfile {
+    fn default() -> Self {
+        Self {
+            id: String::new(),
+            name: String::new(),
+            enabled: true,
+            base_url: String::new(),
+            api_key: String::new(),
+            model: String::new(),
+            extra_headers: Vec::new(),
         }
     }
 }
@@ -315,6 +617,16 @@ struct LlmSettings {
     temperature: f32,
     max_tokens: u32,
     timeout_ms: u64,
+    requests_per_minute: u32,
+    tokens_per_minute: u32,
+    reasoning_display: ReasoningDisplay,
+    extra_headers: Vec<ExtraHeader>,
+    /// Providers tried in order when the primary endpoint fails.
+    fallback_profiles: Vec<LlmProviderProfile>,
+    /// Estimated input tokens per chunk for long summarize/translate input; 0 disables chunking.
+    chunk_tokens: u32,
+    /// Context window of the model; follow-up conversations are trimmed to fit it.
+    context_tokens: u32,
 }
 
 impl Default for LlmSettings {
@@ -327,6 +639,13 @@ impl Default for LlmSettings {
             temperature: 0.3,
             max_tokens: 1024,
             timeout_ms: 30_000,
+            requests_per_minute: 0,
+            tokens_per_minute: 0,
+            reasoning_display: ReasoningDisplay::Collapse,
+            extra_headers: Vec::new(),
+            fallback_profiles: Vec::new(),
+            chunk_tokens: DEFAULT_CHUNK_TOKENS,
+            context_tokens: DEFAULT_CONTEXT_TOKENS,
         }
     }
 }
@@ -341,6 +660,9 @@ struct VisionSettings {
     temperature: f32,
     max_tokens: u32,
     timeout_ms: u64,
+    requests_per_minute: u32,
+    tokens_per_minute: u32,
+    extra_headers: Vec<ExtraHeader>,
 }
 
 impl Default for VisionSettings {
@@ -353,6 +675,9 @@ impl Default for VisionSettings {
             temperature: 0.0,
             max_tokens: 2048,
             timeout_ms: 30_000,
+            requests_per_minute: 0,
+            tokens_per_minute: 0,
+            extra_headers: Vec::new(),
         }
     }
 }
@@ -410,8 +735,17 @@ struct CustomAgent {
     name: String,
     icon: String,
     prompt: String,
+    /// Replaces the default custom agent system prompt when not empty.
+    system_prompt: String,
+    /// Makes the agent a workflow: steps run in order and `prompt` is ignored.
+    steps: Vec<WorkflowStep>,
+    /// JSON Schema the reply must satisfy; empty keeps free-form text output.
+    output_schema: String,
+    /// Local tools the model may call through the provider's tool-calling protocol.
+    tools: Vec<AgentTool>,
     enabled: bool,
     order: u32,
+    params: GenerationParams,
 }
 
 impl Default for CustomAgent {
@@ -421,8 +755,209 @@ impl Default for CustomAgent {
             name: String::new(),
             icon: "Sparkles".to_string(),
             prompt: String::new(),
+            system_prompt: String::new(),
+            steps: Vec::new(),
+            output_schema: String::new(),
+            tools: Vec::new(),
             enabled: true,
             order: 0,
+            params: GenerationParams::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+enum AgentTool {
+    ReadClipboard,
+    SearchClipboardHistory,
+    CurrentDatetime,
+    EvaluateMath,
+    OpenUrl,
+    SaveSnippet,
+}
+
+impl AgentTool {
+    const ALL: [AgentTool; 6] = [
+        AgentTool::ReadClipboard,
+        AgentTool::SearchClipboardHistory,
+        AgentTool::CurrentDatetime,
+        AgentTool::EvaluateMath,
+        AgentTool::OpenUrl,
+        AgentTool::SaveSnippet,
+    ];
+
+    fn function_name(self) -> &'static str {
+        match self {
+            AgentTool::ReadClipboard => "read_clipboard",
+            AgentTool::SearchClipboardHistory => "search_clipboard_history",
+            AgentTool::CurrentDatetime => "get_current_datetime",
+            AgentTool::EvaluateMath => "evaluate_math",
+            AgentTool::OpenUrl => "open_url",
+            AgentTool::SaveSnippet => "save_snippet",
+        }
+    }
+
+    fn from_function_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|tool| tool.function_name() == name)
+    }
+
+    /// Tool definition in the OpenAI-compatible `tools` format.
+    fn definition(self) -> serde_json::Value {
+        let (description, parameters) = match self {
+            AgentTool::ReadClipboard => (
+                "Read the text currently on the user's clipboard.",
+                json!({ "type": "object", "properties": {} }),
+            ),
+            AgentTool::SearchClipboardHistory => (
+                "Search the user's clipboard history for text entries containing a query, newest first.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "query": { "type": "string", "description": "Case-insensitive text to look for." },
+                        "limit": { "type": "integer", "description": "Maximum number of entries, up to 20." }
+                    },
+                    "required": ["query"]
+                }),
+            ),
+            AgentTool::CurrentDatetime => (
+                "Get the current local date, time, weekday and UTC offset.",
+                json!({ "type": "object", "properties": {} }),
+            ),
+            AgentTool::EvaluateMath => (
+                "Evaluate an arithmetic expression with + - * / % ^, parentheses, pi, e and sqrt, abs, ln, log, exp, sin, cos, tan, round, floor, ceil.",
+                json!({
+                    "type": "object",
+                    "properties": { "expression": { "type": "string" } },
+                    "required": ["expression"]
+                }),
+            ),
+            AgentTool::OpenUrl => (
+                "Open an http or https URL in the user's default browser.",
+                json!({
+                    "type": "object",
+                    "properties": { "url": { "type": "string" } },
+                    "required": ["url"]
+                }),
+            ),
+            AgentTool::SaveSnippet => (
+                "Save a piece of text to the user's favorites in the clipboard history.",
+                json!({
+                    "type": "object",
+                    "properties": { "text": { "type": "string" } },
+                    "required": ["text"]
+                }),
+            ),
+        };
+        json!({
+            "type": "function",
+            "function": {
+                "name": self.function_name(),
+                "description": description,
+                "parameters": parameters
+            }
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+enum ResponseFormatMode {
+    #[default]
+    Text,
+    JsonObject,
+}
+
+/// Optional sampling overrides sent with one action's chat completion request. Unset fields
+/// are left out of the request body so the provider defaults apply.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct GenerationParams {
+    top_p: Option<f32>,
+    frequency_penalty: Option<f32>,
+    presence_penalty: Option<f32>,
+    stop: Vec<String>,
+    seed: Option<i64>,
+    response_format: ResponseFormatMode,
+    /// JSON object merged into the request body last, for provider-specific options.
+    extra_body: String,
+    /// Output schema of a structured-output agent, set per request and never persisted.
+    #[serde(skip)]
+    json_schema: Option<serde_json::Value>,
+    /// Tool definitions of a tool-calling agent, set per request and never persisted.
+    #[serde(skip)]
+    tools: Vec<serde_json::Value>,
+    /// Makes the model answer in text once the tool-call round limit is reached.
+    #[serde(skip)]
+    disable_tool_calls: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct BuiltinActionConfig {
+    params: GenerationParams,
+    /// Overrides the default system prompt template; empty uses the default.
+    system_prompt: String,
+    /// Overrides the default user prompt template; empty uses the default.
+    user_prompt: String,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BuiltinPromptDefaultsPayload {
+    summarize: BuiltinPromptTemplatePayload,
+    polish: BuiltinPromptTemplatePayload,
+    explain: BuiltinPromptTemplatePayload,
+    translate: BuiltinPromptTemplatePayload,
+    variables: Vec<&'static str>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BuiltinPromptTemplatePayload {
+    system_prompt: &'static str,
+    user_prompt: &'static str,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct BuiltinActionSettings {
+    summarize: BuiltinActionConfig,
+    polish: BuiltinActionConfig,
+    explain: BuiltinActionConfig,
+    translate: BuiltinActionConfig,
+}
+
+impl BuiltinActionSettings {
+    fn entries_mut(&mut self) -> [&mut BuiltinActionConfig; 4] {
+        [
+            &mut self.summarize,
+            &mut self.polish,
+            &mut self.explain,
+            &mut self.translate,
+        ]
+    }
+
+    /// Looks up a built-in action by the action name carried in result payloads.
+    fn for_action(&self, action_name: &str) -> Option<&BuiltinActionConfig> {
+        match action_name {
+            "summary" => Some(&self.summarize),
+            "polish" => Some(&self.polish),
+            "explain" => Some(&self.explain),
+            "translate" => Some(&self.translate),
+            _ => None,
+        }
+    }
+
+    fn for_kind(&self, kind: SelectionActionKind) -> Option<&BuiltinActionConfig> {
+        match kind {
+            SelectionActionKind::Summarize => Some(&self.summarize),
+            SelectionActionKind::Polish => Some(&self.polish),
+            SelectionActionKind::Explain => Some(&self.explain),
+            SelectionActionKind::Translate => Some(&self.translate),
+            SelectionActionKind::Custom => None,
         }
     }
 }
@@ -450,6 +985,9 @@ impl Default for SelectionBarItemConfig {
 struct AgentSettings {
     custom: Vec<CustomAgent>,
     bar_order: Vec<SelectionBarItemConfig>,
+    builtin: BuiltinActionSettings,
+    /// User-defined `{name}` values available to custom agent prompts.
+    constants: Vec<PromptConstant>,
 }
 
 impl Default for AgentSettings {
@@ -457,10 +995,133 @@ impl Default for AgentSettings {
         Self {
             custom: Vec::new(),
             bar_order: builtin_selection_bar_order(),
+            builtin: BuiltinActionSettings::default(),
+            constants: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct PromptConstant {
+    name: String,
+    value: String,
+}
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+enum WorkflowStepKind {
+    #[default]
+    Builtin,
+    Prompt,
+    Transform,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+enum WorkflowBuiltinAction {
+    #[default]
+    Translate,
+    Summarize,
+    Polish,
+    Explain,
+}
+
+impl WorkflowBuiltinAction {
+    fn kind(self) -> SelectionActionKind {
+        match self {
+            Self::Translate => SelectionActionKind::Translate,
+            Self::Summarize => SelectionActionKind::Summarize,
+            Self::Polish => SelectionActionKind::Polish,
+            Self::Explain => SelectionActionKind::Explain,
+        }
+    }
+
+    fn action_name(self) -> &'static str {
+        match self {
+            Self::Translate => "translate",
+            Self::Summarize => "summary",
+            Self::Polish => "polish",
+            Self::Explain => "explain",
         }
     }
 }
 
+/// Text clean-ups that run locally without a model request.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+enum LocalTransform {
+    #[default]
+    FixLineBreaks,
+    CollapseWhitespace,
+    RemoveEmptyLines,
+    StripMarkdown,
+}
+
+/// One step of a workflow agent; only the field matching `kind` is used.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct WorkflowStep {
+    kind: WorkflowStepKind,
+    action: WorkflowBuiltinAction,
+    prompt: String,
+    transform: LocalTransform,
+}
+
+/// Shareable agent pack; never contains API keys or other settings.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AgentPack {
+    format: String,
+    version: u32,
+    #[serde(default)]
+    exported_at_ms: u64,
+    agents: Vec<AgentPackEntry>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AgentPackEntry {
+    id: String,
+    name: String,
+    #[serde(default)]
+    icon: String,
+    prompt: String,
+    #[serde(default)]
+    system_prompt: String,
+    #[serde(default)]
+    steps: Vec<WorkflowStep>,
+    #[serde(default)]
+    output_schema: String,
+    #[serde(default)]
+    tools: Vec<AgentTool>,
+    #[serde(default)]
+    params: GenerationParams,
+    /// Template variables the prompts rely on, including user-defined constants.
+    #[serde(default)]
+    required_variables: Vec<String>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AgentPackPreviewItem {
+    id: String,
+    name: String,
+    icon: String,
+    icon_supported: bool,
+    id_conflict: bool,
+    missing_variables: Vec<String>,
+    tools: Vec<AgentTool>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AgentImportPayload {
+    settings: AppSettings,
+    imported: usize,
+    /// Constants the imported prompts need that were added with empty values.
+    added_constants: Vec<String>,
+}
+
 #[derive(Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase", default)]
 struct WindowSettings {
@@ -555,6 +1216,9 @@ struct HistorySettings {
     promote_after_paste: bool,
     open_at_top_on_show: bool,
     storage_path: String,
+    /// Keep a local log of AI actions for the action history view.
+    #[serde(default = "default_true")]
+    record_ai_actions: bool,
 }
 
 impl Default for HistorySettings {
@@ -574,10 +1238,78 @@ impl Default for HistorySettings {
             promote_after_paste: true,
             open_at_top_on_show: true,
             storage_path: String::new(),
+            record_ai_actions: true,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+struct ResponseCacheSettings {
+    enabled: bool,
+    max_size_mb: u32,
+    ttl_hours: u32,
+}
+
+/// Outbound connection options shared by every model request.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct NetworkSettings {
+    /// `http://`, `https://`, `socks5://` or `socks5h://` proxy; empty uses the system proxy.
+    proxy_url: String,
+    proxy_username: String,
+    proxy_password: String,
+    /// Comma separated hosts, domains or CIDR ranges that bypass the proxy.
+    no_proxy: String,
+    /// PEM file with extra trusted root certificates, e.g. for TLS-inspecting gateways.
+    extra_ca_cert_path: String,
+}
+
+impl Default for ResponseCacheSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_size_mb: 32,
+            ttl_hours: 24 * 7,
         }
     }
 }
 
+/// One preferred translation. Empty language codes match any language.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct GlossaryEntry {
+    source: String,
+    target: String,
+    source_language: String,
+    target_language: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct GlossarySettings {
+    entries: Vec<GlossaryEntry>,
+    /// Product names, identifiers and similar terms that must be kept verbatim.
+    do_not_translate: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct ModelPriceOverride {
+    model: String,
+    input_per_million_usd: f64,
+    output_per_million_usd: f64,
+}
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct UsageSettings {
+    // 0 disables the corresponding monthly limit.
+    monthly_budget_usd: f64,
+    monthly_token_budget: u64,
+    price_overrides: Vec<ModelPriceOverride>,
+}
+
 #[derive(Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase", default)]
 struct AppSettings {
@@ -593,6 +1325,10 @@ struct AppSettings {
     ocr: OcrSettings,
     appearance: AppearanceSettings,
     history: HistorySettings,
+    response_cache: ResponseCacheSettings,
+    usage: UsageSettings,
+    network: NetworkSettings,
+    glossary: GlossarySettings,
     main_window_width: Option<u32>,
     main_window_height: Option<u32>,
     main_window_x: Option<i32>,
@@ -622,6 +1358,10 @@ impl Default for AppSettings {
             ocr: OcrSettings::default(),
             appearance: AppearanceSettings::default(),
             history: HistorySettings::default(),
+            response_cache: ResponseCacheSettings::default(),
+            usage: UsageSettings::default(),
+            network: NetworkSettings::default(),
+            glossary: GlossarySettings::default(),
             main_window_width: Some(DEFAULT_MAIN_WINDOW_WIDTH),
             main_window_height: Some(DEFAULT_MAIN_WINDOW_HEIGHT),
             main_window_x: None,
@@ -661,8 +1401,13 @@ struct SelectionAssistantSettingsPatch {
     max_chars: Option<usize>,
     blocked_apps: Option<Vec<String>>,
     default_translate_to: Option<String>,
+    smart_translate_target: Option<bool>,
+    bilingual_mode: Option<BilingualMode>,
+    dictionary_mode: Option<bool>,
     result_window_always_on_top: Option<bool>,
     remember_result_window_position: Option<bool>,
+    app_rules: Option<Vec<AppSelectionRule>>,
+    smart_bar_suggestions: Option<bool>,
 }
 
 #[derive(Debug, Deserialize, Default)]
@@ -675,6 +1420,13 @@ struct LlmSettingsPatch {
     temperature: Option<f32>,
     max_tokens: Option<u32>,
     timeout_ms: Option<u64>,
+    requests_per_minute: Option<u32>,
+    tokens_per_minute: Option<u32>,
+    reasoning_display: Option<ReasoningDisplay>,
+    extra_headers: Option<Vec<ExtraHeader>>,
+    fallback_profiles: Option<Vec<LlmProviderProfile>>,
+    chunk_tokens: Option<u32>,
+    context_tokens: Option<u32>,
 }
 
 #[derive(Debug, Deserialize, Default)]
@@ -696,6 +1448,9 @@ struct VisionSettingsPatch {
     temperature: Option<f32>,
     max_tokens: Option<u32>,
     timeout_ms: Option<u64>,
+    requests_per_minute: Option<u32>,
+    tokens_per_minute: Option<u32>,
+    extra_headers: Option<Vec<ExtraHeader>>,
 }
 
 #[derive(Debug, Deserialize, Default)]
@@ -715,6 +1470,8 @@ struct OcrSettingsPatch {
 struct AgentSettingsPatch {
     custom: Option<Vec<CustomAgent>>,
     bar_order: Option<Vec<SelectionBarItemConfig>>,
+    builtin: Option<BuiltinActionSettings>,
+    constants: Option<Vec<PromptConstant>>,
 }
 
 #[derive(Debug, Deserialize, Default)]
@@ -758,6 +1515,40 @@ struct HistorySettingsPatch {
     promote_after_paste: Option<bool>,
     open_at_top_on_show: Option<bool>,
     storage_path: Option<String>,
+    record_ai_actions: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct NetworkSettingsPatch {
+    proxy_url: Option<String>,
+    proxy_username: Option<String>,
+    proxy_password: Option<String>,
+    no_proxy: Option<String>,
+    extra_ca_cert_path: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct GlossarySettingsPatch {
+    entries: Option<Vec<GlossaryEntry>>,
+    do_not_translate: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct ResponseCacheSettingsPatch {
+    enabled: Option<bool>,
+    max_size_mb: Option<u32>,
+    ttl_hours: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct UsageSettingsPatch {
+    monthly_budget_usd: Option<f64>,
+    monthly_token_budget: Option<u64>,
+    price_overrides: Option<Vec<ModelPriceOverride>>,
 }
 
 #[derive(Debug, Deserialize, Default)]
@@ -774,6 +1565,10 @@ struct SettingsPatch {
     ocr: Option<OcrSettingsPatch>,
     appearance: Option<AppearanceSettingsPatch>,
     history: Option<HistorySettingsPatch>,
+    response_cache: Option<ResponseCacheSettingsPatch>,
+    usage: Option<UsageSettingsPatch>,
+    network: Option<NetworkSettingsPatch>,
+    glossary: Option<GlossarySettingsPatch>,
     selection_result_window_width: Option<u32>,
     selection_result_window_height: Option<u32>,
     ocr_result_window_width: Option<u32>,
@@ -849,6 +1644,7 @@ struct SelectionRuntimeState {
     last_clipboard_observed: Mutex<String>,
     active_result_request_nonce: AtomicU64,
     suppress_result_blur_until_ms: AtomicU64,
+    selection_source: Mutex<SelectionSourceWindow>,
 }
 
 struct OcrCaptureSnapshot {
@@ -894,5237 +1690,11459 @@ struct HistoryLoadResult {
     should_persist: bool,
 }
 
+/// The shared model HTTP client. It is rebuilt whenever the network settings change; a
+/// configuration that fails to build is kept as an error so requests report it instead of
+/// silently bypassing the proxy.
 struct HttpClientState {
-    client: reqwest::Client,
+    client: Mutex<Result<reqwest::Client, String>>,
 }
 
 impl Default for HttpClientState {
     fn default() -> Self {
-        let client = reqwest::Client::builder()
-            .pool_max_idle_per_host(32)
-            .pool_idle_timeout(Duration::from_secs(90))
-            .tcp_nodelay(true)
-            .build()
+        let client = build_http_client(&NetworkSettings::default())
             .unwrap_or_else(|_| reqwest::Client::new());
-        Self { client }
+        Self {
+            client: Mutex::new(Ok(client)),
+        }
     }
 }
 
-#[derive(Clone, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct SelectionDetectedPayload {
-    text: String,
-    x: i32,
-    y: i32,
-    mode: SelectionTriggerMode,
-}
+impl HttpClientState {
+    fn client(&self) -> Result<reqwest::Client, CommandError> {
+        match &*self.client.lock().map_err(|_| CommandError::Lock)? {
+            Ok(client) => Ok(client.clone()),
+            Err(error) => Err(CommandError::Settings(error.clone())),
+        }
+    }
 
-#[derive(Clone, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct MainWindowShownPayload {
-    collapse_top_bar: bool,
-    open_to_top: bool,
+    fn rebuild(&self, network: &NetworkSettings) {
+        let built = build_http_client(network);
+        if let Err(error) = &built {
+            eprintln!("[Network] http client rebuild failed: {error}");
+        }
+        if let Ok(mut client) = self.client.lock() {
+            *client = built;
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct SelectionBarOpenPayload {
-    text: String,
-    x: i32,
-    y: i32,
-    mode: SelectionTriggerMode,
+struct ModelRateBucket {
+    request_allowance: f64,
+    token_allowance: f64,
+    refilled_at_ms: u64,
+    blocked_until_ms: u64,
 }
 
-#[derive(Clone, Copy, Debug, Deserialize)]
-#[serde(rename_all = "kebab-case")]
-enum SelectionActionKind {
-    Summarize,
-    Polish,
-    Explain,
-    Translate,
-    Custom,
-}
+impl ModelRateBucket {
+    fn new(requests_per_minute: u32, tokens_per_minute: u32, now_ms: u64) -> Self {
+        Self {
+            request_allowance: requests_per_minute as f64,
+            token_allowance: tokens_per_minute as f64,
+            refilled_at_ms: now_ms,
+            blocked_until_ms: 0,
+        }
+    }
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct RunSelectionActionPayload {
-    action: SelectionActionKind,
-    text: String,
-    custom_agent_id: Option<String>,
-    translate_from: Option<String>,
-    translate_to: Option<String>,
-}
+    fn refill(&mut self, requests_per_minute: u32, tokens_per_minute: u32, now_ms: u64) {
+        let elapsed_ms = now_ms.saturating_sub(self.refilled_at_ms) as f64;
+        self.refilled_at_ms = now_ms;
+        if requests_per_minute > 0 {
+            let capacity = requests_per_minute as f64;
+            self.request_allowance =
+                (self.request_allowance + elapsed_ms * capacity / 60_000.0).min(capacity);
+        }
+        if tokens_per_minute > 0 {
+            let capacity = tokens_per_minute as f64;
+            self.token_allowance =
+                (self.token_allowance + elapsed_ms * capacity / 60_000.0).min(capacity);
+        }
+    }
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct RunOcrActionPayload {
-    action: SelectionActionKind,
-    ocr_text: String,
-    custom_agent_id: Option<String>,
-    translate_from: Option<String>,
-    translate_to: Option<String>,
+    /// Takes one request slot and `estimated_tokens` from the bucket, or returns how long the
+    /// caller should wait before trying again.
+    fn try_acquire(
+        &mut self,
+        requests_per_minute: u32,
+        tokens_per_minute: u32,
+        estimated_tokens: u32,
+        now_ms: u64,
+    ) -> u64 {
+        self.refill(requests_per_minute, tokens_per_minute, now_ms);
+        if self.blocked_until_ms > now_ms {
+            return self.blocked_until_ms - now_ms;
+        }
+
+        let mut wait_ms = 0f64;
+        if requests_per_minute > 0 && self.request_allowance < 1.0 {
+            wait_ms =
+                wait_ms.max((1.0 - self.request_allowance) * 60_000.0 / requests_per_minute as f64);
+        }
+        // A single request larger than the whole budget must still be able to pass.
+        let needed_tokens = estimated_tokens.min(tokens_per_minute) as f64;
+        if tokens_per_minute > 0 && self.token_allowance < needed_tokens {
+            wait_ms = wait_ms
+                .max((needed_tokens - self.token_allowance) * 60_000.0 / tokens_per_minute as f64);
+        }
+        if wait_ms > 0.0 {
+            return (wait_ms.ceil() as u64).max(1);
+        }
+
+        if requests_per_minute > 0 {
+            self.request_allowance -= 1.0;
+        }
+        if tokens_per_minute > 0 {
+            self.token_allowance -= needed_tokens;
+        }
+        0
+    }
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct SynthesizeTtsPayload {
-    text: String,
-    language_hint: Option<String>,
-    voice_override: Option<String>,
+#[derive(Default)]
+struct ProviderHealth {
+    consecutive_failures: u32,
+    skip_until_ms: u64,
 }
 
-#[derive(Clone, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct SelectionResultPayload {
-    request_id: String,
-    action: String,
-    source_text: String,
-    output_text: String,
-    translate_from: Option<String>,
-    translate_to: Option<String>,
-    custom_agent_name: Option<String>,
-    custom_agent_icon: Option<String>,
-    is_streaming: bool,
-    error_message: Option<String>,
+/// Recent failures per provider endpoint, used to move failing providers to the back of the
+/// failover chain until their cooldown expires.
+#[derive(Default)]
+struct ProviderHealthState {
+    entries: Mutex<HashMap<String, ProviderHealth>>,
 }
 
-#[derive(Clone, Serialize)]
+impl ProviderHealthState {
+    fn is_cooling_down(&self, key: &str, now_ms: u64) -> bool {
+        self.entries
+            .lock()
+            .ok()
+            .and_then(|entries| entries.get(key).map(|health| health.skip_until_ms > now_ms))
+            .unwrap_or(false)
+    }
+
+    fn record_success(&self, key: &str) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.remove(key);
+        }
+    }
+
+    fn record_failure(&self, key: &str, now_ms: u64) {
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        let health = entries.entry(key.to_string()).or_default();
+        health.consecutive_failures = health.consecutive_failures.saturating_add(1);
+        let cooldown = PROVIDER_FAILURE_COOLDOWN_MS
+            .saturating_mul(1 << health.consecutive_failures.min(6).saturating_sub(1))
+            .min(PROVIDER_FAILURE_MAX_COOLDOWN_MS);
+        health.skip_until_ms = now_ms.saturating_add(cooldown);
+    }
+}
+
+#[derive(Default)]
+struct ModelRateLimitState {
+    buckets: Mutex<HashMap<String, ModelRateBucket>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct OcrResultPayload {
-    request_id: String,
-    action: String,
-    ocr_text: String,
+struct ResponseCacheEntry {
+    base_url: String,
+    model: String,
     output_text: String,
-    translate_from: Option<String>,
-    translate_to: Option<String>,
-    custom_agent_name: Option<String>,
-    custom_agent_icon: Option<String>,
-    is_streaming: bool,
-    error_message: Option<String>,
+    created_at_ms: u64,
+    last_used_at_ms: u64,
 }
 
-#[derive(Clone, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct SynthesizeTtsResult {
-    audio_base64: String,
-    mime_type: String,
-    voice_used: String,
+impl ResponseCacheEntry {
+    fn approximate_size_bytes(&self, key: &str) -> usize {
+        key.len()
+            + self.base_url.len()
+            + self.model.len()
+            + self.output_text.len()
+            + RESPONSE_CACHE_ENTRY_OVERHEAD_BYTES
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct ResponseCacheStore {
+    entries: HashMap<String, ResponseCacheEntry>,
+    #[serde(skip)]
+    unsaved_hits: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct OcrCaptureAreaPayload {
-    x: f64,
-    y: f64,
-    width: f64,
-    height: f64,
+struct TokenUsage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    total_tokens: u64,
+    // True when the provider did not report usage and the counts are local estimates.
+    estimated: bool,
 }
 
-#[derive(Clone)]
-struct ClipboardImageSnapshot {
-    width: usize,
-    height: usize,
-    bytes: Vec<u8>,
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ChatMessage {
+    role: String,
+    content: String,
+    /// Tool calls requested by an assistant turn, in the provider's wire format.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<serde_json::Value>,
+    /// Set on `tool` messages to answer one of those calls.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
 }
 
-#[derive(Clone)]
-enum ClipboardSnapshot {
-    Empty,
-    Text(String),
-    Image(ClipboardImageSnapshot),
+impl ChatMessage {
+    fn new(role: &str, content: impl Into<String>) -> Self {
+        Self {
+            role: role.to_string(),
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
 }
 
-#[derive(Debug, Error)]
-enum CommandError {
-    #[error("Clipboard access failed: {0}")]
-    Clipboard(String),
-    #[error("History item not found")]
-    NotFound,
-    #[error("State lock poisoned")]
-    Lock,
-    #[error("Image processing failed: {0}")]
-    InvalidImage(String),
-    #[error("Settings error: {0}")]
-    Settings(String),
-    #[error("Serialization error: {0}")]
-    Serialization(String),
-    #[error("Shortcut registration failed: {0}")]
-    Shortcut(String),
+/// A tool call the model asked for, merged from streamed fragments when needed.
+#[derive(Clone, Debug, Default)]
+struct ToolCallRequest {
+    id: String,
+    name: String,
+    arguments: String,
 }
 
-impl Serialize for CommandError {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        serializer.serialize_str(self.to_string().as_ref())
+impl ToolCallRequest {
+    fn to_wire(&self) -> serde_json::Value {
+        json!({
+            "id": self.id,
+            "type": "function",
+            "function": { "name": self.name, "arguments": self.arguments }
+        })
     }
 }
 
-fn now_id() -> String {
-    format!("{}", Utc::now().timestamp_nanos_opt().unwrap_or_default())
+/// One tool call made by a tool-calling agent, shown in the result window.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ToolCallTracePayload {
+    name: String,
+    arguments: String,
+    output: String,
+    is_error: bool,
 }
 
-fn now_epoch_millis() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map(|duration| duration.as_millis() as u64)
-        .unwrap_or(0)
+fn single_turn_messages(system_prompt: &str, user_prompt: &str) -> Vec<ChatMessage> {
+    vec![
+        ChatMessage::new("system", system_prompt),
+        ChatMessage::new("user", user_prompt),
+    ]
 }
 
-fn should_retry_http_status(status: reqwest::StatusCode) -> bool {
-    let code = status.as_u16();
-    status.is_server_error() || matches!(code, 408 | 409 | 425 | 429)
+/// Progress of a long selection processed in chunks. `phase` is `map` and `reduce` for
+/// summaries and `translate` for chunked translation.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ChunkProgressPayload {
+    phase: &'static str,
+    completed: usize,
+    total: usize,
 }
 
-fn should_retry_network_error(error: &reqwest::Error) -> bool {
-    error.is_timeout() || error.is_connect() || error.is_request() || error.is_body()
+/// The provider that produced a result, reported so failovers are visible in the result window.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ProviderPayload {
+    id: String,
+    name: String,
+    model: String,
+    is_fallback: bool,
 }
 
-fn retry_backoff_delay_ms(attempt: usize) -> u64 {
-    let multiplier = 1u64 << attempt.min(4);
-    MODEL_REQUEST_RETRY_BASE_DELAY_MS
-        .saturating_mul(multiplier)
-        .min(MODEL_REQUEST_RETRY_MAX_DELAY_MS)
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FollowUpTurnPayload {
+    question: String,
+    answer: String,
+    reasoning: String,
 }
 
-async fn sleep_with_backoff(attempt: usize) {
-    let delay = retry_backoff_delay_ms(attempt);
-    if delay == 0 {
-        return;
-    }
-    let _ = tauri::async_runtime::spawn_blocking(move || {
-        std::thread::sleep(Duration::from_millis(delay));
-    })
-    .await;
+#[derive(Clone)]
+enum ConversationOrigin {
+    Selection(Box<SelectionResultPayload>),
+    Ocr(Box<OcrResultPayload>),
 }
 
-async fn sleep_for_ms(ms: u64) {
-    if ms == 0 {
-        return;
-    }
-    let _ = tauri::async_runtime::spawn_blocking(move || {
-        std::thread::sleep(Duration::from_millis(ms));
-    })
-    .await;
+struct ConversationSession {
+    origin: ConversationOrigin,
+    // The original exchange ending with the first answer, followed by follow-up pairs.
+    messages: Vec<ChatMessage>,
+    /// Number of leading messages that form the original exchange and are never trimmed.
+    base_len: usize,
+    follow_ups: Vec<FollowUpTurnPayload>,
+    updated_at_ms: u64,
 }
 
-fn format_response_body_for_error(raw_body: &str) -> String {
-    let trimmed = raw_body.trim();
-    if trimmed.is_empty() {
-        return "Empty response body".to_string();
+#[derive(Default)]
+struct ConversationState {
+    sessions: Mutex<HashMap<String, ConversationSession>>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ActionSurface {
+    Selection,
+    Ocr,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ActionLogEntry {
+    id: String,
+    request_id: String,
+    created_at_ms: u64,
+    surface: ActionSurface,
+    action: String,
+    #[serde(default)]
+    custom_agent_id: Option<String>,
+    #[serde(default)]
+    custom_agent_name: Option<String>,
+    #[serde(default)]
+    custom_agent_icon: Option<String>,
+    #[serde(default)]
+    translate_from: Option<String>,
+    #[serde(default)]
+    translate_to: Option<String>,
+    source_text: String,
+    #[serde(default)]
+    ocr_image_path: Option<String>,
+    #[serde(default)]
+    system_prompt: String,
+    #[serde(default)]
+    user_prompt: String,
+    model: String,
+    output_text: String,
+    latency_ms: u64,
+    #[serde(default)]
+    from_cache: bool,
+    #[serde(default)]
+    error_code: Option<String>,
+    #[serde(default)]
+    error_message: Option<String>,
+}
+
+impl ActionLogEntry {
+    fn from_selection(
+        payload: &SelectionResultPayload,
+        messages: &[ChatMessage],
+        model: &str,
+        started_at_ms: u64,
+    ) -> Self {
+        let mut entry = Self::new(
+            ActionSurface::Selection,
+            &payload.request_id,
+            &payload.action,
+            &payload.source_text,
+            messages,
+            payload
+                .provider
+                .as_ref()
+                .map_or(model, |provider| provider.model.as_str()),
+            started_at_ms,
+        );
+        entry.custom_agent_name = payload.custom_agent_name.clone();
+        entry.custom_agent_icon = payload.custom_agent_icon.clone();
+        entry.translate_from = payload.translate_from.clone();
+        entry.translate_to = payload.translate_to.clone();
+        entry.output_text = payload.output_text.clone();
+        entry.from_cache = payload.from_cache;
+        entry.error_code = payload
+            .error_detail
+            .as_ref()
+            .map(|detail| detail.code.clone());
+        entry.error_message = payload.error_message.clone();
+        entry
+    }
+
+    fn from_ocr(
+        payload: &OcrResultPayload,
+        messages: &[ChatMessage],
+        model: &str,
+        started_at_ms: u64,
+    ) -> Self {
+        let mut entry = Self::new(
+            ActionSurface::Ocr,
+            &payload.request_id,
+            &payload.action,
+            &payload.ocr_text,
+            messages,
+            payload
+                .provider
+                .as_ref()
+                .map_or(model, |provider| provider.model.as_str()),
+            started_at_ms,
+        );
+        entry.custom_agent_name = payload.custom_agent_name.clone();
+        entry.custom_agent_icon = payload.custom_agent_icon.clone();
+        entry.translate_from = payload.translate_from.clone();
+        entry.translate_to = payload.translate_to.clone();
+        entry.output_text = payload.output_text.clone();
+        entry.from_cache = payload.from_cache;
+        entry.error_code = payload
+            .error_detail
+            .as_ref()
+            .map(|detail| detail.code.clone());
+        entry.error_message = payload.error_message.clone();
+        entry
+    }
+
+    fn new(
+        surface: ActionSurface,
+        request_id: &str,
+        action: &str,
+        source_text: &str,
+        messages: &[ChatMessage],
+        model: &str,
+        started_at_ms: u64,
+    ) -> Self {
+        let prompt_of = |role: &str| {
+            messages
+                .iter()
+                .find(|message| message.role == role)
+                .map(|message| message.content.clone())
+                .unwrap_or_default()
+        };
+        let now = now_epoch_millis();
+        Self {
+            id: now_id(),
+            request_id: request_id.to_string(),
+            created_at_ms: now,
+            surface,
+            action: action.to_string(),
+            custom_agent_id: None,
+            custom_agent_name: None,
+            custom_agent_icon: None,
+            translate_from: None,
+            translate_to: None,
+            source_text: source_text.to_string(),
+            ocr_image_path: None,
+            system_prompt: prompt_of("system"),
+            user_prompt: prompt_of("user"),
+            model: model.trim().to_string(),
+            output_text: String::new(),
+            latency_ms: now.saturating_sub(started_at_ms),
+            from_cache: false,
+            error_code: None,
+            error_message: None,
+        }
     }
 
-    const MAX_CHARS: usize = 1200;
-    let mut output = trimmed.chars().take(MAX_CHARS).collect::<String>();
-    if trimmed.chars().nth(MAX_CHARS).is_some() {
-        output.push_str("...");
+    fn matches_query(&self, lowered_query: &str) -> bool {
+        [
+            Some(self.source_text.as_str()),
+            Some(self.output_text.as_str()),
+            Some(self.action.as_str()),
+            Some(self.model.as_str()),
+            self.custom_agent_name.as_deref(),
+            self.error_message.as_deref(),
+        ]
+        .into_iter()
+        .flatten()
+        .any(|value| value.to_lowercase().contains(lowered_query))
     }
-    output
 }
 
-fn with_history_lock<'a>(
-    state: &'a State<'_, Mutex<ClipboardState>>,
-) -> Result<std::sync::MutexGuard<'a, ClipboardState>, CommandError> {
-    state.lock().map_err(|_| CommandError::Lock)
+#[derive(Default)]
+struct ActionLogState {
+    // Newest first; loaded lazily on first access.
+    entries: Mutex<Option<Vec<ActionLogEntry>>>,
+    // Lines in the log file, including entries already evicted from `entries`.
+    file_records: AtomicUsize,
 }
 
-fn with_settings_lock<'a>(
-    state: &'a State<'_, AppSettingsState>,
-) -> Result<std::sync::MutexGuard<'a, AppSettings>, CommandError> {
-    state.data.lock().map_err(|_| CommandError::Lock)
+struct ModelCompletion {
+    text: String,
+    reasoning: String,
+    usage: Option<TokenUsage>,
+    tool_calls: Vec<ToolCallRequest>,
 }
 
-fn clamp_poll_ms(value: u64) -> u64 {
-    value.clamp(MIN_POLL_MS, MAX_POLL_MS)
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RequestUsagePayload {
+    model: String,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    total_tokens: u64,
+    estimated: bool,
+    estimated_cost_usd: Option<f64>,
 }
 
-fn clamp_history_items(value: usize) -> usize {
-    value.clamp(MIN_HISTORY_ITEMS, MAX_HISTORY_ITEMS)
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UsageLedgerRow {
+    day: String,
+    model: String,
+    action: String,
+    requests: u64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    estimated_cost_usd: f64,
 }
 
-fn clamp_main_window_width(value: u32) -> u32 {
-    value.clamp(MIN_MAIN_WINDOW_WIDTH, MAX_MAIN_WINDOW_WIDTH)
+#[derive(Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct UsageLedger {
+    rows: Vec<UsageLedgerRow>,
 }
 
-fn clamp_main_window_height(value: u32) -> u32 {
-    value.clamp(MIN_MAIN_WINDOW_HEIGHT, MAX_MAIN_WINDOW_HEIGHT)
+#[derive(Default)]
+struct UsageLedgerState {
+    ledger: Mutex<Option<UsageLedger>>,
 }
 
-fn clamp_selection_result_window_width(value: u32) -> u32 {
-    value.clamp(
-        MIN_SELECTION_RESULT_WINDOW_WIDTH,
-        MAX_SELECTION_RESULT_WINDOW_WIDTH,
-    )
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UsageTotals {
+    requests: u64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    total_tokens: u64,
+    estimated_cost_usd: f64,
 }
 
-fn clamp_selection_result_window_height(value: u32) -> u32 {
-    value.clamp(
-        MIN_SELECTION_RESULT_WINDOW_HEIGHT,
-        MAX_SELECTION_RESULT_WINDOW_HEIGHT,
-    )
+impl UsageTotals {
+    fn add_row(&mut self, row: &UsageLedgerRow) {
+        self.requests += row.requests;
+        self.prompt_tokens += row.prompt_tokens;
+        self.completion_tokens += row.completion_tokens;
+        self.total_tokens += row.prompt_tokens + row.completion_tokens;
+        self.estimated_cost_usd += row.estimated_cost_usd;
+    }
 }
 
-fn clamp_ocr_result_window_width(value: u32) -> u32 {
-    value.clamp(MIN_OCR_RESULT_WINDOW_WIDTH, MAX_OCR_RESULT_WINDOW_WIDTH)
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UsageBreakdownItem {
+    key: String,
+    #[serde(flatten)]
+    totals: UsageTotals,
 }
 
-fn clamp_ocr_result_window_height(value: u32) -> u32 {
-    value.clamp(MIN_OCR_RESULT_WINDOW_HEIGHT, MAX_OCR_RESULT_WINDOW_HEIGHT)
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UsageStatsPayload {
+    days: u32,
+    totals: UsageTotals,
+    by_day: Vec<UsageBreakdownItem>,
+    by_model: Vec<UsageBreakdownItem>,
+    by_action: Vec<UsageBreakdownItem>,
+    current_month: UsageTotals,
+    monthly_budget_usd: f64,
+    monthly_token_budget: u64,
 }
 
-fn clamp_selection_min_chars(value: usize) -> usize {
-    value.clamp(1, 64)
+#[derive(Default)]
+struct ResponseCacheState {
+    // Loaded lazily on first lookup so startup does not pay for the disk read.
+    store: Mutex<Option<ResponseCacheStore>>,
+    /// Set while a delayed save is scheduled.
+    persist_pending: AtomicBool,
+    /// Held while the cache file is written or removed so the two never interleave.
+    file_lock: Mutex<()>,
 }
 
-fn clamp_selection_max_chars(value: usize) -> usize {
-    value.clamp(128, 100_000)
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SelectionDetectedPayload {
+    text: String,
+    x: i32,
+    y: i32,
+    mode: SelectionTriggerMode,
+    /// Bar keys to show, in order, after applying any matching app rule.
+    bar_items: Vec<String>,
+    translate_from: Option<String>,
+    translate_to: Option<String>,
+    content_kinds: Vec<SelectionContentKind>,
+    /// Bar keys suggested for the detected content, most relevant first.
+    suggested_items: Vec<String>,
 }
 
-fn to_i32(value: u32) -> i32 {
-    value.min(i32::MAX as u32) as i32
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MainWindowShownPayload {
+    collapse_top_bar: bool,
+    open_to_top: bool,
 }
 
-fn monitor_bounds(monitor: &tauri::Monitor) -> (i32, i32, i32, i32) {
-    let position = monitor.position();
-    let size = monitor.size();
-    let max_x = position.x.saturating_add(to_i32(size.width));
-    let max_y = position.y.saturating_add(to_i32(size.height));
-    (position.x, position.y, max_x, max_y)
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SelectionBarOpenPayload {
+    text: String,
+    x: i32,
+    y: i32,
+    mode: SelectionTriggerMode,
 }
 
-fn monitor_contains_point(monitor: &tauri::Monitor, point: PhysicalPosition<i32>) -> bool {
-    let (min_x, min_y, max_x, max_y) = monitor_bounds(monitor);
-    point.x >= min_x && point.x < max_x && point.y >= min_y && point.y < max_y
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum SelectionActionKind {
+    Summarize,
+    Polish,
+    Explain,
+    Translate,
+    Custom,
 }
 
-fn clamp_position_to_monitor(
-    monitor: &tauri::Monitor,
-    candidate: PhysicalPosition<i32>,
-    width: u32,
-    height: u32,
-) -> PhysicalPosition<i32> {
-    let monitor_pos = monitor.position();
-    let monitor_size = monitor.size();
-    let scale_factor = monitor.scale_factor().max(0.1);
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RunSelectionActionPayload {
+    action: SelectionActionKind,
+    text: String,
+    custom_agent_id: Option<String>,
+    translate_from: Option<String>,
+    translate_to: Option<String>,
+    #[serde(default)]
+    regenerate: bool,
+    /// Overrides the configured bilingual mode for this translation.
+    #[serde(default)]
+    bilingual_mode: Option<BilingualMode>,
+    /// Pastes the finished output over the source selection instead of showing the result window.
+    #[serde(default)]
+    replace_selection: bool,
+}
 
-    // `width`/`height` are logical window sizes from settings/config.
-    // Convert to physical pixels before clamping against monitor bounds.
-    let window_width_px = ((width as f64) * scale_factor)
-        .round()
-        .clamp(1.0, i32::MAX as f64) as i32;
-    let window_height_px = ((height as f64) * scale_factor)
-        .round()
-        .clamp(1.0, i32::MAX as f64) as i32;
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReplaceSelectionPayload {
+    source_text: String,
+    output_text: String,
+}
 
-    let min_x = monitor_pos.x;
-    let min_y = monitor_pos.y;
-    let max_x = min_x
-        .saturating_add(to_i32(monitor_size.width))
-        .saturating_sub(window_width_px)
-        .max(min_x);
-    let max_y = min_y
-        .saturating_add(to_i32(monitor_size.height))
-        .saturating_sub(window_height_px)
-        .max(min_y);
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RunOcrActionPayload {
+    action: SelectionActionKind,
+    ocr_text: String,
+    custom_agent_id: Option<String>,
+    translate_from: Option<String>,
+    translate_to: Option<String>,
+    #[serde(default)]
+    regenerate: bool,
+}
 
-    PhysicalPosition::new(
-        candidate.x.clamp(min_x, max_x),
-        candidate.y.clamp(min_y, max_y),
-    )
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ActionLogQueryPayload {
+    #[serde(default)]
+    query: Option<String>,
+    #[serde(default)]
+    surface: Option<ActionSurface>,
+    #[serde(default)]
+    offset: Option<usize>,
+    #[serde(default)]
+    limit: Option<usize>,
 }
 
-fn monitor_for_point<R: Runtime>(
-    app: &AppHandle<R>,
-    point: PhysicalPosition<i32>,
-) -> Option<tauri::Monitor> {
-    let monitors = app.available_monitors().ok()?;
-    monitors
-        .into_iter()
-        .find(|monitor| monitor_contains_point(monitor, point))
-        .or_else(|| app.primary_monitor().ok().flatten())
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RerunActionLogPayload {
+    id: String,
+    action: SelectionActionKind,
+    custom_agent_id: Option<String>,
+    translate_from: Option<String>,
+    translate_to: Option<String>,
 }
 
-#[cfg(target_os = "windows")]
-fn current_pointer_position() -> Option<PhysicalPosition<i32>> {
-    let mut point = POINT { x: 0, y: 0 };
-    if unsafe { GetCursorPos(&mut point as *mut POINT) } != 0 {
-        Some(PhysicalPosition::new(point.x, point.y))
-    } else {
-        None
-    }
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ContinueConversationPayload {
+    request_id: String,
+    message: String,
+    #[serde(default)]
+    regenerate: bool,
 }
 
-#[cfg(not(target_os = "windows"))]
-fn current_pointer_position() -> Option<PhysicalPosition<i32>> {
-    None
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SynthesizeTtsPayload {
+    text: String,
+    language_hint: Option<String>,
+    voice_override: Option<String>,
 }
 
-fn is_saved_size_near_monitor_bounds(
-    width: u32,
-    height: u32,
-    monitor_width: u32,
-    monitor_height: u32,
-) -> bool {
-    if monitor_width == 0 || monitor_height == 0 {
-        return false;
-    }
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct DictionarySense {
+    part_of_speech: String,
+    definitions: Vec<String>,
+    examples: Vec<String>,
+}
 
-    let width_limit = (monitor_width as f64 * 0.92).round() as u32;
-    let height_limit = (monitor_height as f64 * 0.92).round() as u32;
-    width >= width_limit && height >= height_limit
+/// A structured dictionary lookup for a short selection.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+struct DictionaryEntryPayload {
+    headword: String,
+    pronunciation: String,
+    language: String,
+    translation: String,
+    senses: Vec<DictionarySense>,
+    synonyms: Vec<String>,
 }
 
-fn normalize_shortcut(value: &str, fallback: &str) -> String {
-    let trimmed = value.trim();
-    if trimmed.is_empty() {
-        fallback.to_string()
-    } else {
-        trimmed.to_string()
-    }
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WordListEntry {
+    id: String,
+    entry: DictionaryEntryPayload,
+    #[serde(default)]
+    context: String,
+    saved_at_ms: u64,
+    #[serde(default = "default_lookup_count")]
+    lookup_count: u32,
 }
 
-fn normalize_language(value: &str) -> String {
-    if value.eq_ignore_ascii_case("en-US") {
-        "en-US".to_string()
-    } else {
-        "zh-CN".to_string()
-    }
+fn default_lookup_count() -> u32 {
+    1
 }
 
-fn normalize_translate_language(value: &str, fallback: &str) -> String {
-    let trimmed = value.trim();
-    match trimmed {
-        "zh-CN" | "en-US" | "ja-JP" | "ko-KR" => trimmed.to_string(),
-        _ => fallback.to_string(),
-    }
+#[derive(Default)]
+struct WordListState {
+    // Newest first; loaded lazily on first access.
+    entries: Mutex<Option<Vec<WordListEntry>>>,
 }
 
-fn default_translate_target_for_language(language: &str) -> &'static str {
-    if language.eq_ignore_ascii_case("en-US") {
-        "zh-CN"
-    } else {
-        "en-US"
-    }
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WorkflowStepPayload {
+    label: String,
+    output: String,
+    done: bool,
 }
 
-fn normalize_tts_voice(value: &str, fallback: &str) -> String {
-    let trimmed = value.trim();
-    if trimmed.is_empty() {
-        fallback.to_string()
-    } else {
-        trimmed.to_string()
-    }
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BilingualSegmentPayload {
+    source: String,
+    translation: String,
 }
 
-fn is_cjk_char(ch: char) -> bool {
-    matches!(
-        ch as u32,
-        0x3400..=0x4DBF
-            | 0x4E00..=0x9FFF
-            | 0xF900..=0xFAFF
-            | 0x20000..=0x2A6DF
-            | 0x2A700..=0x2B73F
-            | 0x2B740..=0x2B81F
-            | 0x2B820..=0x2CEAF
-    )
+/// A glossary or do-not-translate term the translation did not follow.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GlossaryWarningPayload {
+    term: String,
+    expected: String,
+    kind: &'static str,
 }
 
-fn trim_name_by_units(value: &str, max_units: u32) -> String {
-    let mut result = String::new();
-    let mut used = 0u32;
-    for ch in value.trim().chars() {
-        let weight = if is_cjk_char(ch) { 2 } else { 1 };
-        if used.saturating_add(weight) > max_units {
-            break;
-        }
-        result.push(ch);
-        used = used.saturating_add(weight);
-    }
-    result.trim().to_string()
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SelectionResultPayload {
+    request_id: String,
+    action: String,
+    source_text: String,
+    output_text: String,
+    reasoning_text: String,
+    translate_from: Option<String>,
+    translate_to: Option<String>,
+    /// Agent that produced a `custom` result, so the window can run it again.
+    custom_agent_id: Option<String>,
+    custom_agent_name: Option<String>,
+    custom_agent_icon: Option<String>,
+    is_streaming: bool,
+    from_cache: bool,
+    usage: Option<RequestUsagePayload>,
+    provider: Option<ProviderPayload>,
+    chunk_progress: Option<ChunkProgressPayload>,
+    follow_ups: Vec<FollowUpTurnPayload>,
+    glossary_warnings: Vec<GlossaryWarningPayload>,
+    /// Step outputs of a workflow agent; empty for single-prompt actions.
+    workflow_steps: Vec<WorkflowStepPayload>,
+    /// Source/translation pairs of a bilingual translation; empty otherwise.
+    segments: Vec<BilingualSegmentPayload>,
+    dictionary: Option<DictionaryEntryPayload>,
+    /// Parsed reply of a structured-output agent.
+    structured_output: Option<serde_json::Value>,
+    /// Tool calls a tool-calling agent made before answering.
+    tool_calls: Vec<ToolCallTracePayload>,
+    /// What Polish changed in the source text; empty for other actions.
+    diff_hunks: Vec<DiffHunkPayload>,
+    error_message: Option<String>,
+    error_detail: Option<ModelErrorPayload>,
 }
 
-fn is_builtin_selection_bar_key(value: &str) -> bool {
-    BUILTIN_SELECTION_BAR_KEYS.contains(&value)
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OcrResultPayload {
+    request_id: String,
+    action: String,
+    ocr_text: String,
+    output_text: String,
+    reasoning_text: String,
+    translate_from: Option<String>,
+    translate_to: Option<String>,
+    /// Agent that produced a `custom` result, so the window can run it again.
+    custom_agent_id: Option<String>,
+    custom_agent_name: Option<String>,
+    custom_agent_icon: Option<String>,
+    is_streaming: bool,
+    from_cache: bool,
+    usage: Option<RequestUsagePayload>,
+    provider: Option<ProviderPayload>,
+    follow_ups: Vec<FollowUpTurnPayload>,
+    glossary_warnings: Vec<GlossaryWarningPayload>,
+    workflow_steps: Vec<WorkflowStepPayload>,
+    structured_output: Option<serde_json::Value>,
+    tool_calls: Vec<ToolCallTracePayload>,
+    error_message: Option<String>,
+    error_detail: Option<ModelErrorPayload>,
 }
 
-fn parse_custom_selection_bar_key(value: &str) -> Option<&str> {
-    value
-        .strip_prefix("custom:")
-        .map(str::trim)
-        .filter(|item| !item.is_empty())
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ModelErrorPayload {
+    code: String,
+    message: String,
+    status: Option<u16>,
+    retry_after_ms: Option<u64>,
 }
 
-fn builtin_selection_bar_order() -> Vec<SelectionBarItemConfig> {
-    BUILTIN_SELECTION_BAR_KEYS
-        .iter()
-        .enumerate()
-        .map(|(index, key)| SelectionBarItemConfig {
-            key: (*key).to_string(),
-            enabled: true,
-            order: index as u32,
-        })
-        .collect()
+/// Language-neutral form of a model error returned from commands; the frontend localizes it by code.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ModelErrorKindPayload<'a> {
+    code: &'static str,
+    detail: Option<&'a str>,
+    status: Option<u16>,
+    retry_after_ms: Option<u64>,
 }
 
-fn normalize_selection_bar_order(
-    input: Vec<SelectionBarItemConfig>,
-    custom_agents: &[CustomAgent],
-) -> Vec<SelectionBarItemConfig> {
-    let custom_keys: HashSet<String> = custom_agents
-        .iter()
-        .map(|item| format!("custom:{}", item.id))
-        .collect();
-    let mut seen = HashSet::new();
-    let mut normalized: Vec<SelectionBarItemConfig> = Vec::new();
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SynthesizeTtsResult {
+    audio_base64: String,
+    mime_type: String,
+    voice_used: String,
+}
 
-    let mut sorted = input;
-    sorted.sort_by_key(|item| item.order);
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OcrCaptureAreaPayload {
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+}
 
-    for item in sorted {
-        let key = item.key.trim().to_string();
-        if key.is_empty() || seen.contains(&key) {
-            continue;
+#[derive(Clone)]
+struct ClipboardImageSnapshot {
+    width: usize,
+    height: usize,
+    bytes: Vec<u8>,
+}
+
+#[derive(Clone)]
+enum ClipboardSnapshot {
+    Empty,
+    Text(String),
+    Image(ClipboardImageSnapshot),
+}
+
+#[derive(Debug, Error)]
+enum CommandError {
+    #[error("Clipboard access failed: {0}")]
+    Clipboard(String),
+    #[error("History item not found")]
+    NotFound,
+    #[error("State lock poisoned")]
+    Lock,
+    #[error("Image processing failed: {0}")]
+    InvalidImage(String),
+    #[error("Settings error: {0}")]
+    Settings(String),
+    #[error("Serialization error: {0}")]
+    Serialization(String),
+    #[error("Shortcut registration failed: {0}")]
+    Shortcut(String),
+    #[error("{0}")]
+    Model(#[from] ModelError),
+}
+
+impl CommandError {
+    fn localized_message(&self, language: &str) -> String {
+        match self {
+            CommandError::Model(error) => error.localized_message(language),
+            _ => self.to_string(),
         }
-        let valid = is_builtin_selection_bar_key(&key)
-            || parse_custom_selection_bar_key(&key)
-                .map(|custom_id| custom_keys.contains(&format!("custom:{}", custom_id)))
-                .unwrap_or(false);
-        if !valid {
-            continue;
+    }
+
+    fn model_error_payload(&self, language: &str) -> Option<ModelErrorPayload> {
+        match self {
+            CommandError::Model(error) => Some(error.to_payload(language)),
+            _ => None,
         }
-        seen.insert(key.clone());
-        normalized.push(SelectionBarItemConfig {
-            key,
-            enabled: item.enabled,
-            order: normalized.len() as u32,
-        });
     }
+}
 
-    for key in BUILTIN_SELECTION_BAR_KEYS {
-        if seen.contains(key) {
-            continue;
+impl Serialize for CommandError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            CommandError::Model(error) => error.serialize(serializer),
+            _ => serializer.serialize_str(self.to_string().as_ref()),
         }
-        seen.insert(key.to_string());
-        normalized.push(SelectionBarItemConfig {
-            key: key.to_string(),
-            enabled: true,
-            order: normalized.len() as u32,
-        });
     }
+}
 
-    for agent in custom_agents {
-        let key = format!("custom:{}", agent.id);
-        if seen.contains(&key) {
-            continue;
+#[derive(Debug, Clone, Error)]
+enum ModelError {
+    #[error("模型接口鉴权失败（{status}），请检查 API Key: {body}")]
+    Unauthorized { status: u16, body: String },
+    #[error("模型接口请求过于频繁，已被限流: {body}")]
+    RateLimited {
+        retry_after_ms: Option<u64>,
+        body: String,
+    },
+    #[error("模型接口请求超时，请稍后重试")]
+    Timeout,
+    #[error("网络连接失败: {0}")]
+    Network(String),
+    #[error("模型接口调用失败（{status}）: {body}")]
+    ProviderError { status: u16, body: String },
+    /// An error event inside a stream that carries no HTTP status.
+    #[error("模型在输出过程中返回错误: {body}")]
+    StreamError { body: String },
+    #[error("模型返回内容为空，请检查模型和提示词配置")]
+    EmptyResponse,
+    #[error("输入内容超出模型上下文长度，请缩短文本或更换模型: {body}")]
+    ContextTooLong { body: String },
+    #[error("模型因内容安全策略拒绝了本次请求")]
+    ContentFiltered,
+    #[error("本月模型用量已达到预算上限（{detail}），请在设置中调整预算后重试")]
+    BudgetExceeded { detail: String },
+    #[error("模型输出未通过 JSON Schema 校验：{detail}")]
+    InvalidStructuredOutput { detail: String },
+    #[error("工具调用超过 {rounds} 轮仍未得到回答")]
+    ToolLoopExceeded { rounds: usize },
+    #[error("请求已取消")]
+    Canceled,
+}
+
+impl ModelError {
+    fn code(&self) -> &'static str {
+        match self {
+            ModelError::Unauthorized { .. } => "unauthorized",
+            ModelError::RateLimited { .. } => "rate_limited",
+            ModelError::Timeout => "timeout",
+            ModelError::Network(_) => "network",
+            ModelError::ProviderError { .. } => "provider_error",
+            ModelError::StreamError { .. } => "stream_error",
+            ModelError::EmptyResponse => "empty_response",
+            ModelError::ContextTooLong { .. } => "context_too_long",
+            ModelError::ContentFiltered => "content_filtered",
+            ModelError::BudgetExceeded { .. } => "budget_exceeded",
+            ModelError::InvalidStructuredOutput { .. } => "invalid_output",
+            ModelError::ToolLoopExceeded { .. } => "tool_loop_exceeded",
+            ModelError::Canceled => "canceled",
         }
-        seen.insert(key.clone());
-        normalized.push(SelectionBarItemConfig {
-            key,
-            enabled: agent.enabled,
-            order: normalized.len() as u32,
-        });
     }
 
-    let mut enabled_count = 0usize;
-    for item in normalized.iter_mut() {
-        if !item.enabled {
-            continue;
+    fn status(&self) -> Option<u16> {
+        match self {
+            ModelError::Unauthorized { status, .. } | ModelError::ProviderError { status, .. } => {
+                Some(*status)
+            }
+            ModelError::RateLimited { .. } => Some(429),
+            _ => None,
         }
-        if enabled_count >= MAX_ENABLED_SELECTION_BAR_ITEMS {
-            item.enabled = false;
-            continue;
+    }
+
+    fn retry_after_ms(&self) -> Option<u64> {
+        match self {
+            ModelError::RateLimited { retry_after_ms, .. } => *retry_after_ms,
+            _ => None,
         }
-        enabled_count += 1;
     }
 
-    normalized
+    fn detail(&self) -> Option<&str> {
+        match self {
+            ModelError::Unauthorized { body, .. }
+            | ModelError::RateLimited { body, .. }
+            | ModelError::ProviderError { body, .. }
+            | ModelError::StreamError { body }
+            | ModelError::ContextTooLong { body } => Some(body),
+            ModelError::Network(detail)
+            | ModelError::BudgetExceeded { detail }
+            | ModelError::InvalidStructuredOutput { detail } => Some(detail),
+            _ => None,
+        }
+    }
+
+    fn localized_message(&self, language: &str) -> String {
+        if !language.eq_ignore_ascii_case("en-US") {
+            return self.to_string();
+        }
+        match self {
+            ModelError::Unauthorized { status, body } => {
+                format!("Model API rejected the credentials ({status}), check the API key: {body}")
+            }
+            ModelError::RateLimited { body, .. } => {
+                format!("Model API rate limit reached: {body}")
+            }
+            ModelError::Timeout => "Model API request timed out, please retry later".to_string(),
+            ModelError::Network(detail) => format!("Network request failed: {detail}"),
+            ModelError::ProviderError { status, body } => {
+                format!("Model API request failed ({status}): {body}")
+            }
+            ModelError::StreamError { body } => {
+                format!("Model API returned an error while streaming: {body}")
+            }
+            ModelError::EmptyResponse => {
+                "Model returned an empty response, check the model and prompt settings".to_string()
+            }
+            ModelError::ContextTooLong { body } => format!(
+                "Input exceeds the model context window, shorten the text or switch models: {body}"
+            ),
+            ModelError::ContentFiltered => {
+                "The model refused this request due to its content policy".to_string()
+            }
+            ModelError::BudgetExceeded { detail } => format!(
+                "Monthly model usage budget reached ({detail}), raise the budget in settings to continue"
+            ),
+            ModelError::InvalidStructuredOutput { detail } => {
+                format!("Model output failed JSON Schema validation: {detail}")
+            }
+            ModelError::ToolLoopExceeded { rounds } => {
+                format!("The model kept calling tools after {rounds} rounds without answering")
+            }
+            ModelError::Canceled => "Request canceled".to_string(),
+        }
+    }
+
+    fn to_payload(&self, language: &str) -> ModelErrorPayload {
+        ModelErrorPayload {
+            code: self.code().to_string(),
+            message: self.localized_message(language),
+            status: self.status(),
+            retry_after_ms: self.retry_after_ms(),
+        }
+    }
 }
 
-fn clamp_f32(value: f32, min: f32, max: f32) -> f32 {
-    if !value.is_finite() {
-        return min;
+impl Serialize for ModelError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        ModelErrorKindPayload {
+            code: self.code(),
+            detail: self.detail(),
+            status: self.status(),
+            retry_after_ms: self.retry_after_ms(),
+        }
+        .serialize(serializer)
     }
-    value.clamp(min, max)
 }
 
-fn normalize_hex_color(input: &str, fallback: &str) -> String {
-    let trimmed = input.trim();
-    let hex = trimmed.strip_prefix('#').unwrap_or(trimmed);
-    if hex.len() != 6 || !hex.chars().all(|ch| ch.is_ascii_hexdigit()) {
-        return fallback.to_string();
+impl From<reqwest::Error> for ModelError {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            ModelError::Timeout
+        } else {
+            ModelError::Network(error.to_string())
+        }
     }
-    format!("#{}", hex.to_ascii_uppercase())
 }
 
-fn normalize_settings(settings: &mut AppSettings) {
-    let previous_version = settings.version;
-    settings.version = SETTINGS_VERSION;
-    settings.language = normalize_language(&settings.language);
-    settings.shortcuts.toggle_main =
-        normalize_shortcut(&settings.shortcuts.toggle_main, DEFAULT_TOGGLE_SHORTCUT);
-    settings.shortcuts.toggle_ocr =
-        normalize_shortcut(&settings.shortcuts.toggle_ocr, DEFAULT_TOGGLE_OCR_SHORTCUT);
-    settings.history.poll_ms = clamp_poll_ms(settings.history.poll_ms);
-    settings.history.max_items = clamp_history_items(settings.history.max_items);
-    settings.history.storage_path = settings.history.storage_path.trim().to_string();
-    settings.theme_preset = ThemePreset::Dark;
-    settings.appearance.blur_px = clamp_f32(settings.appearance.blur_px, 0.0, 36.0).round();
-    settings.appearance.saturate_percent =
-        clamp_f32(settings.appearance.saturate_percent, 60.0, 220.0).round();
-    settings.appearance.window_opacity = clamp_f32(settings.appearance.window_opacity, 0.0, 0.5);
-    settings.appearance.surface_opacity = 0.15;
-    settings.appearance.card_opacity = 0.15;
-    settings.appearance.border_opacity = clamp_f32(settings.appearance.border_opacity, 0.02, 0.45);
-    settings.appearance.shadow_opacity = clamp_f32(settings.appearance.shadow_opacity, 0.0, 1.5);
-    settings.appearance.corner_radius =
-        clamp_f32(settings.appearance.corner_radius, 4.0, 16.0).round();
-    settings.appearance.font_scale = clamp_f32(settings.appearance.font_scale, 0.85, 1.25);
-    settings.appearance.accent_color =
-        normalize_hex_color(&settings.appearance.accent_color, "#8E8E8E");
-    settings.appearance.text_color =
-        normalize_hex_color(&settings.appearance.text_color, "#F5F5F5");
-    settings.appearance.text_muted_color =
-        normalize_hex_color(&settings.appearance.text_muted_color, "#C8C8C8");
+fn is_context_length_error_text(lowered: &str) -> bool {
+    lowered.contains("context_length_exceeded")
+        || lowered.contains("maximum context length")
+        || lowered.contains("context window")
+        || lowered.contains("too many tokens")
+        || lowered.contains("prompt is too long")
+}
 
-    let mut width = settings
-        .main_window_width
-        .unwrap_or(DEFAULT_MAIN_WINDOW_WIDTH);
-    let mut height = settings
-        .main_window_height
-        .unwrap_or(DEFAULT_MAIN_WINDOW_HEIGHT);
-    if previous_version < WINDOW_LAYOUT_MIGRATION_VERSION
-        || (width == 420 && height == 720)
-        || (width == 380 && height == 620)
-        || (width == 340 && height == 560)
-        || width >= 900
-        || height >= 1300
-    {
-        width = DEFAULT_MAIN_WINDOW_WIDTH;
-        height = DEFAULT_MAIN_WINDOW_HEIGHT;
-    }
-    settings.main_window_width = Some(clamp_main_window_width(width));
-    settings.main_window_height = Some(clamp_main_window_height(height));
-    if previous_version < WINDOW_LAYOUT_MIGRATION_VERSION {
-        settings.main_window_x = None;
-        settings.main_window_y = None;
+fn is_content_filter_error_text(lowered: &str) -> bool {
+    lowered.contains("content_filter")
+        || lowered.contains("content_policy")
+        || lowered.contains("content management policy")
+        || lowered.contains("data_inspection_failed")
+}
+
+fn classify_http_failure(
+    status: reqwest::StatusCode,
+    headers: &reqwest::header::HeaderMap,
+    raw_body: &str,
+) -> ModelError {
+    let code = status.as_u16();
+    let body = format_response_body_for_error(raw_body);
+    let lowered = raw_body.to_ascii_lowercase();
+    match code {
+        401 | 403 => ModelError::Unauthorized { status: code, body },
+        429 => ModelError::RateLimited {
+            retry_after_ms: rate_limit_retry_delay_ms(headers),
+            body,
+        },
+        408 | 504 => ModelError::Timeout,
+        413 => ModelError::ContextTooLong { body },
+        _ if is_context_length_error_text(&lowered) => ModelError::ContextTooLong { body },
+        400 if is_content_filter_error_text(&lowered) => ModelError::ContentFiltered,
+        _ => ModelError::ProviderError { status: code, body },
     }
+}
 
-    let mut selection_result_width = settings
-        .selection_result_window_width
-        .unwrap_or(DEFAULT_SELECTION_RESULT_WINDOW_WIDTH);
-    let mut selection_result_height = settings
-        .selection_result_window_height
-        .unwrap_or(DEFAULT_SELECTION_RESULT_WINDOW_HEIGHT);
-    if previous_version < WINDOW_LAYOUT_MIGRATION_VERSION {
-        selection_result_width = DEFAULT_SELECTION_RESULT_WINDOW_WIDTH;
-        selection_result_height = DEFAULT_SELECTION_RESULT_WINDOW_HEIGHT;
+fn classify_stream_error_payload(error: &serde_json::Value) -> ModelError {
+    let raw = error.to_string();
+    let lowered = raw.to_ascii_lowercase();
+    if is_context_length_error_text(&lowered) {
+        return ModelError::ContextTooLong {
+            body: format_response_body_for_error(&raw),
+        };
     }
-    settings.selection_result_window_width =
-        Some(clamp_selection_result_window_width(selection_result_width));
-    settings.selection_result_window_height = Some(clamp_selection_result_window_height(
-        selection_result_height,
-    ));
-    if previous_version < WINDOW_LAYOUT_MIGRATION_VERSION {
-        settings.selection_result_window_x = None;
-        settings.selection_result_window_y = None;
+    if is_content_filter_error_text(&lowered) {
+        return ModelError::ContentFiltered;
     }
-
-    let mut ocr_result_width = settings
-        .ocr_result_window_width
-        .unwrap_or(DEFAULT_OCR_RESULT_WINDOW_WIDTH);
-    let mut ocr_result_height = settings
-        .ocr_result_window_height
-        .unwrap_or(DEFAULT_OCR_RESULT_WINDOW_HEIGHT);
-    if previous_version < WINDOW_LAYOUT_MIGRATION_VERSION {
-        ocr_result_width = DEFAULT_OCR_RESULT_WINDOW_WIDTH;
-        ocr_result_height = DEFAULT_OCR_RESULT_WINDOW_HEIGHT;
-    } else if ocr_result_width == 380 && ocr_result_height == 520 {
-        // Migrate legacy default OCR result size to the new, more compact default.
-        ocr_result_width = DEFAULT_OCR_RESULT_WINDOW_WIDTH;
-        ocr_result_height = DEFAULT_OCR_RESULT_WINDOW_HEIGHT;
+    if lowered.contains("rate_limit") || lowered.contains("rate limit") {
+        return ModelError::RateLimited {
+            retry_after_ms: None,
+            body: format_response_body_for_error(&raw),
+        };
     }
-    settings.ocr_result_window_width = Some(clamp_ocr_result_window_width(ocr_result_width));
-    settings.ocr_result_window_height = Some(clamp_ocr_result_window_height(ocr_result_height));
-    if previous_version < WINDOW_LAYOUT_MIGRATION_VERSION {
-        settings.ocr_result_window_x = None;
-        settings.ocr_result_window_y = None;
+    let body = format_response_body_for_error(&raw);
+    match error
+        .get("code")
+        .and_then(|value| value.as_u64())
+        .and_then(|value| u16::try_from(value).ok())
+        .filter(|status| (100..600).contains(status))
+    {
+        Some(status) => ModelError::ProviderError { status, body },
+        None => ModelError::StreamError { body },
     }
+}
 
-    if previous_version < SETTINGS_VERSION {
-        settings.selection_assistant.enabled = true;
-        settings.ocr.enabled = true;
-        if previous_version < 9 {
-            let legacy_remember_position = settings.window.remember_position;
-            settings.selection_assistant.remember_result_window_position = legacy_remember_position;
-            settings.ocr.remember_result_window_position = legacy_remember_position;
-            settings.history.promote_after_paste = true;
-            settings.history.open_at_top_on_show = true;
-        }
-    }
+fn header_text<'a>(headers: &'a reqwest::header::HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
 
-    settings.selection_assistant.bar_opacity = clamp_f32(
-        settings.selection_assistant.bar_opacity,
-        MIN_SELECTION_BAR_OPACITY,
-        MAX_SELECTION_BAR_OPACITY,
-    );
-    settings.selection_assistant.min_chars =
-        clamp_selection_min_chars(settings.selection_assistant.min_chars);
-    settings.selection_assistant.max_chars =
-        clamp_selection_max_chars(settings.selection_assistant.max_chars);
-    if settings.selection_assistant.max_chars < settings.selection_assistant.min_chars {
-        settings.selection_assistant.max_chars = settings.selection_assistant.min_chars.max(128);
+/// Parses rate-limit reset values such as `20ms`, `1.5s`, `6m0s` or a plain number of seconds.
+/// Values that look like a Unix timestamp are converted into a delay from now.
+fn parse_rate_limit_duration_ms(value: &str) -> Option<u64> {
+    let trimmed = value.trim();
+    if let Ok(number) = trimmed.parse::<f64>() {
+        if !number.is_finite() || number < 0.0 {
+            return None;
+        }
+        if number > 1_000_000_000.0 {
+            let now_seconds = now_epoch_millis() as f64 / 1000.0;
+            return Some(((number - now_seconds).max(0.0) * 1000.0).round() as u64);
+        }
+        return Some((number * 1000.0).round() as u64);
     }
-    let search_template = settings.selection_assistant.search_url_template.trim();
-    settings.selection_assistant.search_url_template = if search_template.is_empty() {
-        "https://www.google.com/search?q={query}".to_string()
-    } else {
-        search_template.to_string()
-    };
-    let blocked_apps = std::mem::take(&mut settings.selection_assistant.blocked_apps);
-    settings.selection_assistant.blocked_apps = blocked_apps
-        .into_iter()
-        .map(|item| item.trim().to_ascii_lowercase())
-        .filter(|item| !item.is_empty())
-        .take(64)
-        .collect();
-    let default_translate_fallback = default_translate_target_for_language(&settings.language);
-    settings.selection_assistant.default_translate_to = normalize_translate_language(
-        &settings.selection_assistant.default_translate_to,
-        default_translate_fallback,
-    );
 
-    settings.llm.base_url = settings.llm.base_url.trim().to_string();
-    if settings.llm.base_url.is_empty() {
-        settings.llm.base_url = "https://api.openai.com/v1/chat/completions".to_string();
+    let mut total_ms = 0f64;
+    let mut number = String::new();
+    let mut chars = trimmed.chars().peekable();
+    let mut saw_unit = false;
+    while let Some(ch) = chars.next() {
+        if ch.is_ascii_digit() || ch == '.' {
+            number.push(ch);
+            continue;
+        }
+        let amount = number.parse::<f64>().ok()?;
+        number.clear();
+        let factor = match ch {
+            'h' => 3_600_000.0,
+            'm' if chars.peek() == Some(&'s') => {
+                chars.next();
+                1.0
+            }
+            'm' => 60_000.0,
+            's' => 1000.0,
+            _ => return None,
+        };
+        total_ms += amount * factor;
+        saw_unit = true;
     }
-    settings.llm.enabled = true;
-    settings.llm.model = settings.llm.model.trim().to_string();
-    if settings.llm.model.is_empty() {
-        settings.llm.model = "gpt-4o-mini".to_string();
+    if !number.is_empty() || !saw_unit {
+        return None;
     }
-    settings.llm.temperature = settings.llm.temperature.clamp(0.0, 2.0);
-    settings.llm.max_tokens = settings.llm.max_tokens.clamp(128, 8192);
-    settings.llm.timeout_ms = settings.llm.timeout_ms.clamp(5_000, 120_000);
+    Some(total_ms.round() as u64)
+}
 
-    settings.tts.voice_zh_cn =
-        normalize_tts_voice(&settings.tts.voice_zh_cn, DEFAULT_TTS_VOICE_ZH_CN);
-    settings.tts.voice_en_us =
-        normalize_tts_voice(&settings.tts.voice_en_us, DEFAULT_TTS_VOICE_EN_US);
-    settings.tts.rate_percent = settings
-        .tts
-        .rate_percent
-        .clamp(MIN_TTS_RATE_PERCENT, MAX_TTS_RATE_PERCENT);
+fn build_http_client(network: &NetworkSettings) -> Result<reqwest::Client, String> {
+    let mut builder = reqwest::Client::builder()
+        .pool_max_idle_per_host(32)
+        .pool_idle_timeout(Duration::from_secs(90))
+        .tcp_nodelay(true);
 
-    settings.ocr.custom_agent_id = settings.ocr.custom_agent_id.trim().to_string();
-    settings.ocr.vision.api_key = settings.ocr.vision.api_key.trim().to_string();
-    settings.ocr.vision.base_url = settings.ocr.vision.base_url.trim().to_string();
-    if settings.ocr.vision.base_url.is_empty() {
-        settings.ocr.vision.base_url = "https://api.openai.com/v1/chat/completions".to_string();
-    }
-    settings.ocr.vision.model = settings.ocr.vision.model.trim().to_string();
-    if settings.ocr.vision.model.is_empty() {
-        settings.ocr.vision.model = "gpt-4o-mini".to_string();
+    let proxy_url = network.proxy_url.trim();
+    if !proxy_url.is_empty() {
+        let mut url =
+            reqwest::Url::parse(proxy_url).map_err(|error| format!("代理地址无效：{error}"))?;
+        if !matches!(url.scheme(), "http" | "https" | "socks5" | "socks5h") {
+            return Err(format!(
+                "不支持的代理协议：{}（可用 http、https、socks5、socks5h）",
+                url.scheme()
+            ));
+        }
+        let username = network.proxy_username.trim();
+        if !username.is_empty() && url.username().is_empty() {
+            url.set_username(username)
+                .and_then(|_| url.set_password(Some(&network.proxy_password)))
+                .map_err(|_| "代理地址无法携带用户名和密码".to_string())?;
+        }
+        let proxy = reqwest::Proxy::all(url)
+            .map_err(|error| format!("代理地址无效：{error}"))?
+            .no_proxy(reqwest::NoProxy::from_string(&network.no_proxy));
+        builder = builder.proxy(proxy);
+    }
+
+    let ca_path = network.extra_ca_cert_path.trim();
+    if !ca_path.is_empty() {
+        let pem = fs::read(ca_path).map_err(|error| format!("读取根证书文件失败：{error}"))?;
+        let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+            .map_err(|error| format!("根证书文件不是有效的 PEM：{error}"))?;
+        if certificates.is_empty() {
+            return Err("根证书文件中没有找到 PEM 证书".to_string());
+        }
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
     }
-    settings.ocr.vision.temperature = settings.ocr.vision.temperature.clamp(0.0, 2.0);
-    settings.ocr.vision.max_tokens = settings.ocr.vision.max_tokens.clamp(256, 8192);
-    settings.ocr.vision.timeout_ms = settings.ocr.vision.timeout_ms.clamp(5_000, 120_000);
-    settings.ocr.vision.enabled = !settings.ocr.vision.api_key.is_empty();
-    if settings.ocr.default_action == OcrDefaultAction::Custom
-        && settings.ocr.custom_agent_id.is_empty()
-    {
-        settings.ocr.default_action = OcrDefaultAction::Translate;
+
+    builder
+        .build()
+        .map_err(|error| format!("网络客户端初始化失败：{error}"))
+}
+
+fn normalize_network_settings(network: &mut NetworkSettings) {
+    network.proxy_url = network.proxy_url.trim().to_string();
+    network.proxy_username = network.proxy_username.trim().to_string();
+    network.no_proxy = network.no_proxy.trim().to_string();
+    network.extra_ca_cert_path = network.extra_ca_cert_path.trim().to_string();
+}
+
+fn normalize_glossary_settings(glossary: &mut GlossarySettings) {
+    let mut seen = HashSet::new();
+    glossary.entries.retain_mut(|entry| {
+        entry.source = entry.source.trim().to_string();
+        entry.target = entry.target.trim().to_string();
+        entry.source_language = glossary_language_code(&entry.source_language);
+        entry.target_language = glossary_language_code(&entry.target_language);
+        !entry.source.is_empty()
+            && seen.insert((
+                entry.source.to_lowercase(),
+                entry.source_language.clone(),
+                entry.target_language.clone(),
+            ))
+    });
+    glossary.entries.truncate(MAX_GLOSSARY_ENTRIES);
+
+    let mut seen = HashSet::new();
+    glossary.do_not_translate.retain_mut(|term| {
+        *term = term.trim().to_string();
+        !term.is_empty() && seen.insert(term.to_lowercase())
+    });
+    glossary
+        .do_not_translate
+        .truncate(MAX_DO_NOT_TRANSLATE_TERMS);
+}
+
+/// Letters and digits of scripts that separate words with spaces; CJK and kana text has no
+/// word boundaries to respect.
+fn is_term_word_char(ch: char) -> bool {
+    ch == '_' || (ch.is_alphanumeric() && (ch as u32) < 0x2E80)
+}
+
+/// Finds `term` in `haystack` as a whole word: a term that starts or ends with a letter or digit
+/// only matches where the neighbouring character is not one, so "cat" does not match "category".
+fn contains_whole_term(haystack: &str, term: &str) -> bool {
+    if term.is_empty() {
+        return false;
     }
+    let check_start = term.chars().next().is_some_and(is_term_word_char);
+    let check_end = term.chars().next_back().is_some_and(is_term_word_char);
+    haystack.match_indices(term).any(|(index, _)| {
+        let before = haystack[..index].chars().next_back();
+        let after = haystack[index + term.len()..].chars().next();
+        let joins_before = check_start && before.is_some_and(is_term_word_char);
+        let joins_after = check_end && after.is_some_and(is_term_word_char);
+        !joins_before && !joins_after
+    })
+}
 
-    let custom_agents = std::mem::take(&mut settings.agents.custom);
-    settings.agents.custom = custom_agents
-        .into_iter()
-        .enumerate()
-        .map(|(index, mut agent)| {
-            if agent.id.trim().is_empty() {
-                agent.id = format!("agent-{}-{}", index, now_epoch_millis());
-            } else {
-                agent.id = agent.id.trim().to_string();
-            }
-            if agent.name.trim().is_empty() {
-                agent.name = format!("Agent {}", index + 1);
-            } else {
-                let trimmed = trim_name_by_units(&agent.name, 8);
-                agent.name = if trimmed.is_empty() {
-                    format!("Agent {}", index + 1)
-                } else {
-                    trimmed
-                };
-            }
-            agent.icon = {
-                let icon = agent.icon.trim();
-                if icon.is_empty() {
-                    "Sparkles".to_string()
-                } else {
-                    icon.to_string()
+/// Maps a glossary language to its registry code, so `en` and `en-US` are stored alike.
+/// Unknown tags are kept as typed.
+fn glossary_language_code(language: &str) -> String {
+    let language = language.trim();
+    find_language(language)
+        .map(|info| info.code.to_string())
+        .unwrap_or_else(|| language.to_string())
+}
+
+fn glossary_language_matches(entry_language: &str, language: Option<&str>) -> bool {
+    entry_language.is_empty()
+        || language.is_none_or(|language| {
+            language == "auto"
+                || match (find_language(entry_language), find_language(language)) {
+                    (Some(entry), Some(language)) => entry.code == language.code,
+                    _ => is_same_language(entry_language, language),
                 }
-            };
-            agent.prompt = agent.prompt.trim().to_string();
-            agent.enabled = true;
-            agent.order = index as u32;
-            agent
         })
-        .take(30)
+}
+
+/// Glossary entries and do-not-translate terms that occur in `text` for this language pair.
+fn matching_glossary_terms<'a>(
+    glossary: &'a GlossarySettings,
+    text: &str,
+    translate_from: Option<&str>,
+    translate_to: Option<&str>,
+) -> (Vec<&'a GlossaryEntry>, Vec<&'a str>) {
+    let lowered = text.to_lowercase();
+    let entries = glossary
+        .entries
+        .iter()
+        .filter(|entry| {
+            glossary_language_matches(&entry.source_language, translate_from)
+                && glossary_language_matches(&entry.target_language, translate_to)
+                && contains_whole_term(&lowered, &entry.source.to_lowercase())
+        })
+        .take(MAX_PROMPT_GLOSSARY_TERMS)
         .collect();
-    let raw_bar_order = std::mem::take(&mut settings.agents.bar_order);
-    settings.agents.bar_order =
-        normalize_selection_bar_order(raw_bar_order, &settings.agents.custom);
+    let keep = glossary
+        .do_not_translate
+        .iter()
+        .filter(|term| contains_whole_term(&lowered, &term.to_lowercase()))
+        .map(String::as_str)
+        .take(MAX_PROMPT_GLOSSARY_TERMS)
+        .collect();
+    (entries, keep)
 }
 
-fn apply_settings_patch(settings: &mut AppSettings, patch: SettingsPatch) {
-    if let Some(theme_preset) = patch.theme_preset {
-        settings.theme_preset = theme_preset;
+/// The built-in translate prompt with the glossary terms found in `text` appended.
+fn translate_prompt(
+    settings: &AppSettings,
+    text: &str,
+    translate_from: Option<&str>,
+    translate_to: Option<&str>,
+) -> (String, String) {
+    let (mut system_prompt, user_prompt) = choose_builtin_prompt(
+        SelectionActionKind::Translate,
+        text,
+        translate_from,
+        translate_to,
+        settings,
+    );
+    let (entries, keep) =
+        matching_glossary_terms(&settings.glossary, text, translate_from, translate_to);
+    if !entries.is_empty() {
+        system_prompt.push_str("\nGlossary (always translate these terms exactly as given):");
+        for entry in entries {
+            if entry.target.is_empty() {
+                system_prompt.push_str(&format!("\n- {} => keep as is", entry.source));
+            } else {
+                system_prompt.push_str(&format!("\n- {} => {}", entry.source, entry.target));
+            }
+        }
+    }
+    if !keep.is_empty() {
+        system_prompt.push_str("\nDo not translate these terms; copy them verbatim:");
+        for term in keep {
+            system_prompt.push_str(&format!("\n- {term}"));
+        }
     }
+    (system_prompt, user_prompt)
+}
 
-    if let Some(language) = patch.language {
-        settings.language = language;
+/// A source segment of a bilingual translation with the whitespace that followed it.
+struct BilingualSource {
+    text: String,
+    separator: String,
+}
+
+/// Splits `text` into the paragraphs (lines) or sentences that a bilingual translation aligns.
+fn split_bilingual_sources(text: &str, mode: BilingualMode) -> Vec<BilingualSource> {
+    let mut sources: Vec<BilingualSource> = Vec::new();
+    for line in split_keeping_boundaries(text, |ch, _| ch == '\n') {
+        let pieces = if mode == BilingualMode::Sentence {
+            split_sentences(line)
+        } else {
+            vec![line]
+        };
+        for piece in pieces {
+            let body = piece.trim();
+            if body.is_empty() {
+                if let Some(last) = sources.last_mut() {
+                    last.separator.push_str(piece);
+                }
+                continue;
+            }
+            let start = piece.len() - piece.trim_start().len();
+            if let Some(last) = sources.last_mut() {
+                last.separator.push_str(&piece[..start]);
+            }
+            sources.push(BilingualSource {
+                text: body.to_string(),
+                separator: piece[start + body.len()..].to_string(),
+            });
+        }
     }
+    sources
+}
 
-    if let Some(window_patch) = patch.window {
-        if let Some(enabled) = window_patch.auto_hide_on_blur {
-            settings.window.auto_hide_on_blur = enabled;
+fn bilingual_translate_prompt(
+    settings: &AppSettings,
+    sources: &[BilingualSource],
+    translate_from: Option<&str>,
+    translate_to: Option<&str>,
+) -> (String, String) {
+    // The marked segments are rendered as the template's {text}, so custom templates keep working.
+    let segmented = sources
+        .iter()
+        .enumerate()
+        .map(|(index, source)| format!("[[{}]]\n{}", index + 1, source.text))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let (mut system_prompt, user_prompt) =
+        translate_prompt(settings, &segmented, translate_from, translate_to);
+    system_prompt.push_str(
+        "\nThe text is split into numbered segments for a side-by-side bilingual view, which \
+         overrides any instruction above to return plain translated text only. Translate each \
+         segment on its own and start every translation with its marker line, for example [[1]]. \
+         Output only the markers and translations, in the original order, without repeating the \
+         source.",
+    );
+    (system_prompt, user_prompt)
+}
+
+fn parse_bilingual_marker(line: &str) -> Option<usize> {
+    line.trim()
+        .strip_prefix("[[")?
+        .strip_suffix("]]")?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Pairs the marker-delimited model output with its sources and returns the segments together
+/// with the plain translation. Output without any marker is kept as one segment so nothing the
+/// model returned is lost.
+fn parse_bilingual_output(
+    output: &str,
+    sources: &[BilingualSource],
+) -> (Vec<BilingualSegmentPayload>, String) {
+    let mut translations = vec![String::new(); sources.len()];
+    let mut current = None;
+    let mut found_marker = false;
+    let mut lines = output.lines().peekable();
+    while let Some(line) = lines.next() {
+        if let Some(number) = parse_bilingual_marker(line) {
+            found_marker = true;
+            current = number.checked_sub(1).filter(|index| *index < sources.len());
+            continue;
         }
-        if let Some(enabled) = window_patch.remember_position {
-            settings.window.remember_position = enabled;
+        // A marker that is still being streamed in.
+        if lines.peek().is_none() && line.trim_start().starts_with("[[") {
+            continue;
         }
-        if let Some(enabled) = window_patch.remember_main_window_size {
-            settings.window.remember_main_window_size = enabled;
+        if let Some(index) = current {
+            let translation: &mut String = &mut translations[index];
+            if !translation.is_empty() {
+                translation.push('\n');
+            }
+            translation.push_str(line);
         }
-        if let Some(enabled) = window_patch.launch_on_system_startup {
-            settings.window.launch_on_system_startup = enabled;
+    }
+
+    if !found_marker {
+        let source: String = sources
+            .iter()
+            .map(|source| format!("{}{}", source.text, source.separator))
+            .collect();
+        let translation = output.trim().to_string();
+        let segments = vec![BilingualSegmentPayload {
+            source: source.trim().to_string(),
+            translation: translation.clone(),
+        }];
+        return (segments, translation);
+    }
+
+    let mut joined = String::new();
+    let mut segments = Vec::with_capacity(sources.len());
+    for (source, translation) in sources.iter().zip(translations) {
+        let translation = translation.trim().to_string();
+        joined.push_str(&translation);
+        if !source.separator.is_empty() {
+            joined.push_str(&source.separator);
+        } else if !translation.chars().last().is_some_and(is_cjk_char) {
+            joined.push(' ');
         }
-        if let Some(enabled) = window_patch.silent_startup {
-            settings.window.silent_startup = enabled;
+        segments.push(BilingualSegmentPayload {
+            source: source.text.clone(),
+            translation,
+        });
+    }
+    (segments, joined.trim_end().to_string())
+}
+
+/// Short words and phrases get a dictionary lookup instead of a plain translation.
+fn is_dictionary_lookup(settings: &SelectionAssistantSettings, text: &str) -> bool {
+    if !settings.dictionary_mode || !selection_text_in_range(settings, text) {
+        return false;
+    }
+    let trimmed = text.trim();
+    if trimmed.contains('\n') || trimmed.chars().any(|ch| ch != '.' && is_sentence_end(ch)) {
+        return false;
+    }
+    let cjk_chars = trimmed.chars().filter(|ch| is_cjk_char(*ch)).count();
+    if cjk_chars > 0 {
+        return trimmed.chars().count() <= DICTIONARY_MAX_CJK_CHARS;
+    }
+    trimmed.split_whitespace().count() <= DICTIONARY_MAX_WORDS
+        && trimmed.chars().count() <= DICTIONARY_MAX_CHARS
+}
+
+fn dictionary_prompt(text: &str, translate_to: Option<&str>) -> (String, String) {
+    let target = translate_to
+        .and_then(find_language)
+        .map(|language| language.english_name)
+        .unwrap_or("Chinese (Simplified)");
+    let system_prompt = format!(
+        "You are a concise bilingual dictionary.
+Explanation language: {target}.
+Return only one JSON object, without markdown fences, in exactly this shape:
+{{\"headword\": string, \"pronunciation\": string, \"language\": string, \"translation\": string, \"senses\": [{{\"partOfSpeech\": string, \"definitions\": [string], \"examples\": [string]}}], \"synonyms\": [string]}}
+Requirements:
+- \"language\" is the BCP-47 code of the headword's language.
+- \"pronunciation\" uses IPA for alphabetic languages and pinyin or kana readings where usual.
+- \"translation\" is the most common rendering in the explanation language.
+- Definitions are written in the explanation language; examples are short sentences in the headword's language followed by their translation in parentheses.
+- List at most 4 senses, 3 examples per sense and 6 synonyms. Use empty strings or arrays when unknown."
+    );
+    let user_prompt = format!("Look up:\n{text}");
+    (system_prompt, user_prompt)
+}
+
+/// Parses the model's dictionary JSON, tolerating code fences or text around the object.
+fn parse_dictionary_entry(raw: &str, lookup: &str) -> Option<DictionaryEntryPayload> {
+    let start = raw.find('{')?;
+    let end = raw.rfind('}')?;
+    let mut entry = serde_json::from_str::<DictionaryEntryPayload>(raw.get(start..=end)?).ok()?;
+    entry.senses.retain(|sense| !sense.definitions.is_empty());
+    if entry.senses.is_empty() && entry.translation.trim().is_empty() {
+        return None;
+    }
+    if entry.headword.trim().is_empty() {
+        entry.headword = lookup.trim().to_string();
+    }
+    Some(entry)
+}
+
+/// Plain-text rendering of a dictionary entry for copy, speech, history and follow-ups.
+fn dictionary_entry_markdown(entry: &DictionaryEntryPayload) -> String {
+    let mut markdown = format!("**{}**", entry.headword.trim());
+    if !entry.pronunciation.trim().is_empty() {
+        markdown.push_str(&format!(
+            " /{}/",
+            entry.pronunciation.trim().trim_matches('/')
+        ));
+    }
+    if !entry.translation.trim().is_empty() {
+        markdown.push_str(&format!("\n\n{}", entry.translation.trim()));
+    }
+    for sense in &entry.senses {
+        markdown.push_str("\n\n");
+        if !sense.part_of_speech.trim().is_empty() {
+            markdown.push_str(&format!("*{}*\n", sense.part_of_speech.trim()));
         }
-        if let Some(enabled) = window_patch.check_updates_on_startup {
-            settings.window.check_updates_on_startup = enabled;
+        for (index, definition) in sense.definitions.iter().enumerate() {
+            markdown.push_str(&format!("{}. {}\n", index + 1, definition.trim()));
         }
+        for example in &sense.examples {
+            markdown.push_str(&format!("> {}\n", example.trim()));
+        }
+    }
+    if !entry.synonyms.is_empty() {
+        markdown.push_str(&format!("\n\n{}", entry.synonyms.join(", ")));
     }
+    markdown.trim_end().to_string()
+}
 
-    if let Some(selection_patch) = patch.selection_assistant {
-        if let Some(enabled) = selection_patch.enabled {
-            settings.selection_assistant.enabled = enabled;
+/// Glossary terms present in the source whose required rendering is missing from the output.
+fn glossary_warnings(
+    glossary: &GlossarySettings,
+    source_text: &str,
+    output_text: &str,
+    translate_from: Option<&str>,
+    translate_to: Option<&str>,
+) -> Vec<GlossaryWarningPayload> {
+    let (entries, keep) =
+        matching_glossary_terms(glossary, source_text, translate_from, translate_to);
+    let lowered_output = output_text.to_lowercase();
+    let mut warnings = Vec::new();
+    for entry in entries {
+        let expected = if entry.target.is_empty() {
+            &entry.source
+        } else {
+            &entry.target
+        };
+        if !contains_whole_term(&lowered_output, &expected.to_lowercase()) {
+            warnings.push(GlossaryWarningPayload {
+                term: entry.source.clone(),
+                expected: expected.clone(),
+                kind: "glossary",
+            });
         }
-        if let Some(mode) = selection_patch.mode {
-            settings.selection_assistant.mode = mode;
-        }
-        if let Some(show) = selection_patch.show_icon_animation {
-            settings.selection_assistant.show_icon_animation = show;
-        }
-        if let Some(compact) = selection_patch.compact_mode {
-            settings.selection_assistant.compact_mode = compact;
-        }
-        if let Some(bar_opacity) = selection_patch.bar_opacity {
-            settings.selection_assistant.bar_opacity = clamp_f32(
-                bar_opacity,
-                MIN_SELECTION_BAR_OPACITY,
-                MAX_SELECTION_BAR_OPACITY,
-            );
-        }
-        if let Some(template) = selection_patch.search_url_template {
-            settings.selection_assistant.search_url_template = template;
-        }
-        if let Some(min_chars) = selection_patch.min_chars {
-            settings.selection_assistant.min_chars = min_chars;
-        }
-        if let Some(max_chars) = selection_patch.max_chars {
-            settings.selection_assistant.max_chars = max_chars;
-        }
-        if let Some(blocked_apps) = selection_patch.blocked_apps {
-            settings.selection_assistant.blocked_apps = blocked_apps;
-        }
-        if let Some(default_translate_to) = selection_patch.default_translate_to {
-            settings.selection_assistant.default_translate_to = default_translate_to;
-        }
-        if let Some(pinned) = selection_patch.result_window_always_on_top {
-            settings.selection_assistant.result_window_always_on_top = pinned;
-        }
-        if let Some(remember_position) = selection_patch.remember_result_window_position {
-            settings.selection_assistant.remember_result_window_position = remember_position;
+    }
+    for term in keep {
+        if !contains_whole_term(output_text, term) {
+            warnings.push(GlossaryWarningPayload {
+                term: term.to_string(),
+                expected: term.to_string(),
+                kind: "do-not-translate",
+            });
         }
     }
+    warnings
+}
 
-    if let Some(llm_patch) = patch.llm {
-        let _ = llm_patch.enabled;
-        if let Some(base_url) = llm_patch.base_url {
-            settings.llm.base_url = base_url;
-        }
-        if let Some(api_key) = llm_patch.api_key {
-            settings.llm.api_key = api_key;
-        }
-        if let Some(model) = llm_patch.model {
-            settings.llm.model = model;
-        }
-        if let Some(temperature) = llm_patch.temperature {
-            settings.llm.temperature = temperature;
-        }
-        if let Some(max_tokens) = llm_patch.max_tokens {
-            settings.llm.max_tokens = max_tokens;
-        }
-        if let Some(timeout_ms) = llm_patch.timeout_ms {
-            settings.llm.timeout_ms = timeout_ms;
+/// One run of a Polish diff: unchanged text, or a change the user can accept or reject.
+#[derive(Clone, Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct DiffHunkPayload {
+    changed: bool,
+    original: String,
+    revised: String,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum DiffTokenClass {
+    Word,
+    Space,
+    Single,
+}
+
+fn diff_token_class(ch: char) -> DiffTokenClass {
+    if is_cjk_char(ch) || script_of_char(ch) == Some(Script::Kana) {
+        DiffTokenClass::Single
+    } else if ch.is_whitespace() {
+        DiffTokenClass::Space
+    } else if ch.is_alphanumeric() || ch == '_' || ch == '\'' {
+        DiffTokenClass::Word
+    } else {
+        DiffTokenClass::Single
+    }
+}
+
+/// Splits text for diffing: words and whitespace runs stay whole, while each ideograph, kana and
+/// punctuation mark is its own token because CJK text has no spaces between words.
+fn diff_tokens(text: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = 0usize;
+    let mut current: Option<DiffTokenClass> = None;
+    for (index, ch) in text.char_indices() {
+        let class = diff_token_class(ch);
+        let joins = class != DiffTokenClass::Single && current == Some(class);
+        if !joins && index > start {
+            tokens.push(&text[start..index]);
+            start = index;
         }
+        current = Some(class);
+    }
+    if start < text.len() {
+        tokens.push(&text[start..]);
     }
+    tokens
+}
 
-    if let Some(tts_patch) = patch.tts {
-        if let Some(runtime_mode) = tts_patch.runtime_mode {
-            settings.tts.runtime_mode = runtime_mode;
-        }
-        if let Some(voice_zh_cn) = tts_patch.voice_zh_cn {
-            settings.tts.voice_zh_cn = voice_zh_cn;
-        }
-        if let Some(voice_en_us) = tts_patch.voice_en_us {
-            settings.tts.voice_en_us = voice_en_us;
+/// Token-level diff of a Polish result against its source, as alternating unchanged and changed
+/// hunks; empty when nothing changed. Inputs too large for the LCS table become one change.
+fn polish_diff_hunks(source: &str, output: &str) -> Vec<DiffHunkPayload> {
+    let old = diff_tokens(source);
+    let new = diff_tokens(output);
+    let prefix = old
+        .iter()
+        .zip(&new)
+        .take_while(|(left, right)| left == right)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(left, right)| left == right)
+        .count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+
+    // (changed, original, revised) per token.
+    let mut steps: Vec<(bool, &str, &str)> = old[..prefix]
+        .iter()
+        .map(|token| (false, *token, *token))
+        .collect();
+    if old_middle.len().saturating_mul(new_middle.len()) > MAX_DIFF_CELLS {
+        steps.extend(old_middle.iter().map(|token| (true, *token, "")));
+        steps.extend(new_middle.iter().map(|token| (true, "", *token)));
+    } else {
+        // lengths[i * width + j] is the LCS length of old_middle[i..] and new_middle[j..].
+        let width = new_middle.len() + 1;
+        let mut lengths = vec![0u16; (old_middle.len() + 1) * width];
+        for i in (0..old_middle.len()).rev() {
+            for j in (0..new_middle.len()).rev() {
+                lengths[i * width + j] = if old_middle[i] == new_middle[j] {
+                    lengths[(i + 1) * width + j + 1] + 1
+                } else {
+                    lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+                };
+            }
         }
-        if let Some(rate_percent) = tts_patch.rate_percent {
-            settings.tts.rate_percent = rate_percent;
+        let (mut i, mut j) = (0usize, 0usize);
+        while i < old_middle.len() || j < new_middle.len() {
+            if i < old_middle.len() && j < new_middle.len() && old_middle[i] == new_middle[j] {
+                steps.push((false, old_middle[i], new_middle[j]));
+                i += 1;
+                j += 1;
+            } else if i < old_middle.len()
+                && (j == new_middle.len()
+                    || lengths[(i + 1) * width + j] >= lengths[i * width + j + 1])
+            {
+                steps.push((true, old_middle[i], ""));
+                i += 1;
+            } else {
+                steps.push((true, "", new_middle[j]));
+                j += 1;
+            }
         }
     }
+    steps.extend(
+        old[old.len() - suffix..]
+            .iter()
+            .map(|token| (false, *token, *token)),
+    );
+    if !steps.iter().any(|(changed, _, _)| *changed) {
+        return Vec::new();
+    }
 
-    if let Some(agent_patch) = patch.agents {
-        if let Some(custom) = agent_patch.custom {
-            settings.agents.custom = custom;
-        }
-        if let Some(bar_order) = agent_patch.bar_order {
-            settings.agents.bar_order = bar_order;
+    let mut hunks: Vec<DiffHunkPayload> = Vec::new();
+    for (changed, original, revised) in steps {
+        match hunks.last_mut() {
+            Some(last) if last.changed == changed => {
+                last.original.push_str(original);
+                last.revised.push_str(revised);
+            }
+            _ => hunks.push(DiffHunkPayload {
+                changed,
+                original: original.to_string(),
+                revised: revised.to_string(),
+            }),
         }
     }
 
-    if let Some(shortcuts_patch) = patch.shortcuts {
-        if let Some(shortcut) = shortcuts_patch.toggle_main {
-            settings.shortcuts.toggle_main = shortcut;
-        }
-        if let Some(shortcut) = shortcuts_patch.toggle_ocr {
-            settings.shortcuts.toggle_ocr = shortcut;
+    // Fold whitespace between two changes into one hunk so a rewritten phrase is accepted or
+    // rejected as a whole.
+    let mut merged: Vec<DiffHunkPayload> = Vec::with_capacity(hunks.len());
+    let mut pending = hunks.into_iter().peekable();
+    while let Some(hunk) = pending.next() {
+        let bridges = !hunk.changed
+            && hunk.original.chars().all(char::is_whitespace)
+            && merged.last().is_some_and(|last| last.changed)
+            && pending.peek().is_some_and(|next| next.changed);
+        if bridges {
+            if let (Some(last), Some(next)) = (merged.last_mut(), pending.next()) {
+                last.original.push_str(&hunk.original);
+                last.original.push_str(&next.original);
+                last.revised.push_str(&hunk.revised);
+                last.revised.push_str(&next.revised);
+            }
+            continue;
         }
+        merged.push(hunk);
     }
+    merged
+}
 
-    if let Some(ocr_patch) = patch.ocr {
-        if let Some(enabled) = ocr_patch.enabled {
-            settings.ocr.enabled = enabled;
-        }
-        if let Some(auto_run) = ocr_patch.auto_run_after_capture {
-            settings.ocr.auto_run_after_capture = auto_run;
-        }
-        if let Some(action) = ocr_patch.default_action {
-            settings.ocr.default_action = action;
-        }
-        if let Some(custom_agent_id) = ocr_patch.custom_agent_id {
-            settings.ocr.custom_agent_id = custom_agent_id;
-        }
-        if let Some(always_on_top) = ocr_patch.result_window_always_on_top {
-            settings.ocr.result_window_always_on_top = always_on_top;
-        }
-        if let Some(remember_position) = ocr_patch.remember_result_window_position {
-            settings.ocr.remember_result_window_position = remember_position;
-        }
-        if let Some(vision_patch) = ocr_patch.vision {
-            if let Some(enabled) = vision_patch.enabled {
-                settings.ocr.vision.enabled = enabled;
-            }
-            if let Some(base_url) = vision_patch.base_url {
-                settings.ocr.vision.base_url = base_url;
-            }
-            if let Some(api_key) = vision_patch.api_key {
-                settings.ocr.vision.api_key = api_key;
-            }
-            if let Some(model) = vision_patch.model {
-                settings.ocr.vision.model = model;
-            }
-            if let Some(temperature) = vision_patch.temperature {
-                settings.ocr.vision.temperature = temperature;
+/// Splits CSV or TSV text into records. The delimiter is a tab when the first line has one,
+/// otherwise a comma; double-quoted fields may contain delimiters, quotes (`""`) and newlines.
+fn parse_delimited_records(payload: &str) -> Vec<Vec<String>> {
+    let first_line = payload.lines().find(|line| !line.trim().is_empty());
+    let delimiter = if first_line.is_some_and(|line| line.contains('\t')) {
+        '\t'
+    } else {
+        ','
+    };
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = payload.trim_start_matches('\u{feff}').chars().peekable();
+    while let Some(ch) = chars.next() {
+        if in_quotes {
+            match ch {
+                '"' if chars.peek() == Some(&'"') => {
+                    field.push('"');
+                    chars.next();
+                }
+                '"' => in_quotes = false,
+                _ => field.push(ch),
             }
-            if let Some(max_tokens) = vision_patch.max_tokens {
-                settings.ocr.vision.max_tokens = max_tokens;
+            continue;
+        }
+        match ch {
+            '"' if field.trim().is_empty() => {
+                field.clear();
+                in_quotes = true;
             }
-            if let Some(timeout_ms) = vision_patch.timeout_ms {
-                settings.ocr.vision.timeout_ms = timeout_ms;
+            '\r' => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
             }
+            _ if ch == delimiter => record.push(std::mem::take(&mut field)),
+            _ => field.push(ch),
         }
     }
+    record.push(field);
+    records.push(record);
+    records.retain(|record| record.iter().any(|field| !field.trim().is_empty()));
+    records
+}
 
-    if let Some(appearance_patch) = patch.appearance {
-        if let Some(blur_px) = appearance_patch.blur_px {
-            settings.appearance.blur_px = blur_px;
-        }
-        if let Some(saturate_percent) = appearance_patch.saturate_percent {
-            settings.appearance.saturate_percent = saturate_percent;
-        }
-        if let Some(window_opacity) = appearance_patch.window_opacity {
-            settings.appearance.window_opacity = window_opacity;
-        }
-        if let Some(border_opacity) = appearance_patch.border_opacity {
-            settings.appearance.border_opacity = border_opacity;
-        }
-        if let Some(shadow_opacity) = appearance_patch.shadow_opacity {
-            settings.appearance.shadow_opacity = shadow_opacity;
-        }
-        if let Some(corner_radius) = appearance_patch.corner_radius {
-            settings.appearance.corner_radius = corner_radius;
-        }
-        if let Some(font_scale) = appearance_patch.font_scale {
-            settings.appearance.font_scale = font_scale;
-        }
-        if let Some(accent_color) = appearance_patch.accent_color {
-            settings.appearance.accent_color = accent_color;
+/// Reads `source, target[, source language, target language]` rows. Rows without a target
+/// become do-not-translate terms; a leading header row is skipped.
+fn parse_glossary_table(
+    payload: &str,
+    source_language: &str,
+    target_language: &str,
+) -> (Vec<GlossaryEntry>, Vec<String>, usize) {
+    let mut entries = Vec::new();
+    let mut keep = Vec::new();
+    let mut skipped = 0;
+    for (index, record) in parse_delimited_records(payload).into_iter().enumerate() {
+        let field = |position: usize| {
+            record
+                .get(position)
+                .map(|value| value.trim().to_string())
+                .unwrap_or_default()
+        };
+        let source = field(0);
+        if index == 0
+            && matches!(
+                source.to_lowercase().as_str(),
+                "source" | "term" | "原文" | "术语" | "源术语"
+            )
+        {
+            continue;
         }
-        if let Some(text_color) = appearance_patch.text_color {
-            settings.appearance.text_color = text_color;
+        if source.is_empty() {
+            skipped += 1;
+            continue;
         }
-        if let Some(text_muted_color) = appearance_patch.text_muted_color {
-            settings.appearance.text_muted_color = text_muted_color;
+        let target = field(1);
+        if target.is_empty() {
+            keep.push(source);
+            continue;
         }
+        let row_source_language = field(2);
+        let row_target_language = field(3);
+        entries.push(GlossaryEntry {
+            source,
+            target,
+            source_language: glossary_language_code(if row_source_language.is_empty() {
+                source_language
+            } else {
+                &row_source_language
+            }),
+            target_language: glossary_language_code(if row_target_language.is_empty() {
+                target_language
+            } else {
+                &row_target_language
+            }),
+        });
     }
-    settings.appearance.surface_opacity = 0.15;
-    settings.appearance.card_opacity = 0.15;
+    (entries, keep, skipped)
+}
 
-    if let Some(history_patch) = patch.history {
-        if let Some(poll_ms) = history_patch.poll_ms {
-            settings.history.poll_ms = poll_ms;
-        }
-        if let Some(max_items) = history_patch.max_items {
-            settings.history.max_items = max_items;
-        }
-        if let Some(dedupe) = history_patch.dedupe {
-            settings.history.dedupe = dedupe;
-        }
-        if let Some(enabled) = history_patch.capture_text {
-            settings.history.capture_text = enabled;
-        }
-        if let Some(enabled) = history_patch.capture_link {
-            settings.history.capture_link = enabled;
-        }
-        if let Some(enabled) = history_patch.capture_image {
-            settings.history.capture_image = enabled;
-        }
-        if let Some(enabled) = history_patch.enable_item_gradients {
-            settings.history.enable_item_gradients = enabled;
-        }
-        if let Some(default_open_category) = history_patch.default_open_category {
-            settings.history.default_open_category = default_open_category;
-        }
-        if let Some(default_category) = history_patch.default_category {
-            settings.history.default_category = default_category;
-        }
-        if let Some(paste_behavior) = history_patch.paste_behavior {
-            settings.history.paste_behavior = paste_behavior;
-        }
-        if let Some(collapse_top_bar) = history_patch.collapse_top_bar {
-            settings.history.collapse_top_bar = collapse_top_bar;
-        }
-        if let Some(promote_after_paste) = history_patch.promote_after_paste {
-            settings.history.promote_after_paste = promote_after_paste;
-        }
-        if let Some(open_at_top_on_show) = history_patch.open_at_top_on_show {
-            settings.history.open_at_top_on_show = open_at_top_on_show;
-        }
-        if let Some(storage_path) = history_patch.storage_path {
-            settings.history.storage_path = storage_path;
+fn normalize_llm_fallback_profiles(profiles: &mut Vec<LlmProviderProfile>) {
+    let mut seen = HashSet::new();
+    profiles.truncate(MAX_LLM_FALLBACK_PROFILES);
+    for (index, profile) in profiles.iter_mut().enumerate() {
+        profile.id = profile.id.trim().to_string();
+        if profile.id.is_empty() || !seen.insert(profile.id.clone()) {
+            profile.id = format!("provider-{}-{}", index, now_epoch_millis());
+            seen.insert(profile.id.clone());
         }
+        profile.name = trim_name_by_units(profile.name.trim(), 16);
+        profile.base_url = profile.base_url.trim().to_string();
+        profile.api_key = profile.api_key.trim().to_string();
+        profile.model = profile.model.trim().to_string();
+        normalize_extra_headers(&mut profile.extra_headers);
     }
+}
 
-    if let Some(width) = patch.selection_result_window_width {
-        settings.selection_result_window_width = Some(width);
-    }
-    if let Some(height) = patch.selection_result_window_height {
-        settings.selection_result_window_height = Some(height);
-    }
-    if let Some(width) = patch.ocr_result_window_width {
-        settings.ocr_result_window_width = Some(width);
+fn normalize_extra_headers(headers: &mut Vec<ExtraHeader>) {
+    let mut seen = HashSet::new();
+    headers.retain_mut(|header| {
+        header.name = header.name.trim().to_string();
+        header.value = header.value.trim().to_string();
+        reqwest::header::HeaderName::from_bytes(header.name.as_bytes()).is_ok()
+            && reqwest::header::HeaderValue::from_str(&header.value).is_ok()
+            && seen.insert(header.name.to_ascii_lowercase())
+    });
+    headers.truncate(MAX_EXTRA_HEADERS_PER_PROFILE);
+}
+
+/// Builds the provider's extra headers; applied after the defaults so they can override them.
+fn extra_header_map(headers: &[ExtraHeader]) -> reqwest::header::HeaderMap {
+    let mut map = reqwest::header::HeaderMap::new();
+    for header in headers {
+        let (Ok(name), Ok(value)) = (
+            reqwest::header::HeaderName::from_bytes(header.name.as_bytes()),
+            reqwest::header::HeaderValue::from_str(&header.value),
+        ) else {
+            continue;
+        };
+        map.insert(name, value);
     }
-    if let Some(height) = patch.ocr_result_window_height {
-        settings.ocr_result_window_height = Some(height);
+    map
+}
+
+fn parse_retry_after_ms(headers: &reqwest::header::HeaderMap) -> Option<u64> {
+    if let Some(ms) = header_text(headers, "retry-after-ms")
+        .and_then(|value| value.parse::<f64>().ok())
+        .filter(|value| value.is_finite() && *value >= 0.0)
+    {
+        return Some(ms.round() as u64);
     }
 
-    normalize_settings(settings);
-}
-fn settings_file_path<R: Runtime>(app: &AppHandle<R>) -> Result<PathBuf, CommandError> {
-    let mut dir = app
-        .path()
-        .app_config_dir()
-        .map_err(|error| CommandError::Settings(error.to_string()))?;
-    fs::create_dir_all(&dir).map_err(|error| CommandError::Settings(error.to_string()))?;
-    dir.push(SETTINGS_FILENAME);
-    Ok(dir)
+    let value = header_text(headers, reqwest::header::RETRY_AFTER.as_str())?;
+    if let Some(seconds) = value
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+    {
+        return Some((seconds * 1000.0).round() as u64);
+    }
+    DateTime::parse_from_rfc2822(value).ok().map(|retry_at| {
+        retry_at
+            .with_timezone(&Utc)
+            .signed_duration_since(Utc::now())
+            .num_milliseconds()
+            .max(0) as u64
+    })
 }
 
-fn settings_backup_path(path: &Path) -> PathBuf {
-    let mut backup = path.to_path_buf();
-    backup.set_file_name(SETTINGS_BACKUP_FILENAME);
-    backup
+/// Returns how long the provider asked us to back off, combining `Retry-After` with the
+/// `x-ratelimit-*` family when a request or token budget is exhausted.
+fn rate_limit_retry_delay_ms(headers: &reqwest::header::HeaderMap) -> Option<u64> {
+    let mut delay = parse_retry_after_ms(headers);
+    for (remaining_key, reset_key) in [
+        (
+            "x-ratelimit-remaining-requests",
+            "x-ratelimit-reset-requests",
+        ),
+        ("x-ratelimit-remaining-tokens", "x-ratelimit-reset-tokens"),
+        ("x-ratelimit-remaining", "x-ratelimit-reset"),
+    ] {
+        let exhausted = header_text(headers, remaining_key)
+            .and_then(|value| value.parse::<f64>().ok())
+            .map(|remaining| remaining <= 0.0)
+            .unwrap_or(false);
+        if !exhausted {
+            continue;
+        }
+        if let Some(reset_ms) =
+            header_text(headers, reset_key).and_then(parse_rate_limit_duration_ms)
+        {
+            delay = Some(delay.map_or(reset_ms, |current| current.max(reset_ms)));
+        }
+    }
+    delay
 }
 
-fn history_backup_path(path: &Path) -> PathBuf {
-    let mut backup = path.to_path_buf();
-    let base_name = path
-        .file_name()
-        .and_then(|name| name.to_str())
-        .unwrap_or(HISTORY_FILENAME);
-    backup.set_file_name(format!("{base_name}.bak"));
-    backup
+fn now_id() -> String {
+    format!("{}", Utc::now().timestamp_nanos_opt().unwrap_or_default())
 }
 
-fn normalize_enum_value_in_object(
-    object: &mut serde_json::Map<String, serde_json::Value>,
-    key: &str,
-    allowed: &[&str],
-    default: &str,
-) -> bool {
-    let normalized = object
-        .get(key)
-        .and_then(|value| value.as_str())
-        .map(|value| value.trim().to_ascii_lowercase());
+fn now_epoch_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
 
-    let should_replace = match normalized {
-        Some(value) => !allowed.iter().any(|candidate| *candidate == value),
-        None => true,
-    };
+fn should_retry_http_status(status: reqwest::StatusCode) -> bool {
+    let code = status.as_u16();
+    status.is_server_error() || matches!(code, 408 | 409 | 425 | 429)
+}
 
-    if should_replace {
-        object.insert(
-            key.to_string(),
-            serde_json::Value::String(default.to_string()),
-        );
-        return true;
-    }
+fn should_retry_network_error(error: &reqwest::Error) -> bool {
+    error.is_timeout() || error.is_connect() || error.is_request() || error.is_body()
+}
 
-    false
+fn retry_backoff_delay_ms(attempt: usize) -> u64 {
+    let multiplier = 1u64 << attempt.min(4);
+    MODEL_REQUEST_RETRY_BASE_DELAY_MS
+        .saturating_mul(multiplier)
+        .min(MODEL_REQUEST_RETRY_MAX_DELAY_MS)
 }
 
-fn parse_bool_like_json(value: &serde_json::Value) -> Option<bool> {
-    if let Some(flag) = value.as_bool() {
-        return Some(flag);
-    }
-    if let Some(number) = value.as_i64() {
-        return Some(number != 0);
-    }
-    if let Some(text) = value.as_str() {
-        return match text.trim().to_ascii_lowercase().as_str() {
-            "1" | "true" | "yes" | "on" => Some(true),
-            "0" | "false" | "no" | "off" => Some(false),
-            _ => None,
-        };
+async fn sleep_with_backoff(attempt: usize) {
+    let delay = retry_backoff_delay_ms(attempt);
+    if delay == 0 {
+        return;
     }
-    None
+    let _ = tauri::async_runtime::spawn_blocking(move || {
+        std::thread::sleep(Duration::from_millis(delay));
+    })
+    .await;
 }
 
-fn assign_window_bool_if_missing(
-    window_obj: &mut serde_json::Map<String, serde_json::Value>,
-    target_key: &str,
-    flag: bool,
-) -> bool {
-    let has_valid_value = window_obj
-        .get(target_key)
-        .and_then(parse_bool_like_json)
-        .is_some();
-    if has_valid_value {
-        return false;
+/// Waits before the next attempt, honoring a server-provided delay when it is longer than the
+/// local backoff. Returns `false` when the server asked for a pause too long to wait out.
+async fn sleep_before_retry(attempt: usize, retry_hint_ms: Option<u64>) -> bool {
+    match retry_hint_ms {
+        Some(hint) if hint > MODEL_REQUEST_RETRY_AFTER_MAX_MS => false,
+        Some(hint) => {
+            sleep_for_ms(hint.max(retry_backoff_delay_ms(attempt))).await;
+            true
+        }
+        None => {
+            sleep_with_backoff(attempt).await;
+            true
+        }
     }
-    window_obj.insert(target_key.to_string(), serde_json::Value::Bool(flag));
-    true
 }
 
-fn migrate_legacy_theme_preset(root: &mut serde_json::Map<String, serde_json::Value>) -> bool {
-    let incoming = root
-        .get("themePreset")
-        .and_then(|value| value.as_str())
-        .map(|value| value.trim().to_ascii_lowercase())
-        .or_else(|| {
-            root.get("theme")
-                .and_then(|value| value.as_str())
-                .map(|value| value.trim().to_ascii_lowercase())
-        });
+fn model_rate_limit_key(base_url: &str, model: &str) -> String {
+    format!("{}#{}", base_url.trim().to_ascii_lowercase(), model.trim())
+}
 
-    let Some(theme) = incoming else {
-        return false;
-    };
+/// Waits for a local rate-limit slot, giving up with `RateLimited` once the total wait would
+/// exceed `MODEL_REQUEST_RETRY_AFTER_MAX_MS` (e.g. after a long provider-reported block).
+async fn acquire_model_rate_slot(
+    limiter: &ModelRateLimitState,
+    key: &str,
+    requests_per_minute: u32,
+    tokens_per_minute: u32,
+    estimated_tokens: u32,
+) -> Result<(), ModelError> {
+    let mut waited_ms = 0u64;
+    loop {
+        let wait_ms = match limiter.buckets.lock() {
+            Ok(mut buckets) => {
+                let now = now_epoch_millis();
+                buckets
+                    .entry(key.to_string())
+                    .or_insert_with(|| {
+                        ModelRateBucket::new(requests_per_minute, tokens_per_minute, now)
+                    })
+                    .try_acquire(
+                        requests_per_minute,
+                        tokens_per_minute,
+                        estimated_tokens,
+                        now,
+                    )
+            }
+            Err(_) => 0,
+        };
+        if wait_ms == 0 {
+            return Ok(());
+        }
+        if waited_ms.saturating_add(wait_ms) > MODEL_REQUEST_RETRY_AFTER_MAX_MS {
+            return Err(ModelError::RateLimited {
+                retry_after_ms: Some(wait_ms),
+                body: format!("需要再等待 {} 秒才能发送请求", wait_ms.div_ceil(1000)),
+            });
+        }
+        let slice_ms = wait_ms.min(MODEL_RATE_LIMIT_MAX_WAIT_SLICE_MS);
+        sleep_for_ms(slice_ms).await;
+        waited_ms = waited_ms.saturating_add(slice_ms);
+    }
+}
 
-    let mapped = match theme.as_str() {
-        "dark" | "light" | "warm" | "blue" | "deep-black" | "black" | "md2-dark" | "midnight"
-        | "gray" | "graphite" | "white" | "daylight" | "sunrise" | "amber-mist" => "dark",
-        _ => return false,
+/// Pauses every queued request for a profile when the provider reports an exhausted budget.
+fn note_model_rate_limit_headers(
+    limiter: &ModelRateLimitState,
+    key: &str,
+    headers: &reqwest::header::HeaderMap,
+) {
+    let Some(delay_ms) = rate_limit_retry_delay_ms(headers) else {
+        return;
     };
+    if let Ok(mut buckets) = limiter.buckets.lock() {
+        let now = now_epoch_millis();
+        let bucket = buckets
+            .entry(key.to_string())
+            .or_insert_with(|| ModelRateBucket::new(0, 0, now));
+        bucket.blocked_until_ms = bucket.blocked_until_ms.max(now.saturating_add(delay_ms));
+    }
+}
 
-    let current = root
-        .get("themePreset")
-        .and_then(|value| value.as_str())
-        .map(|value| value.trim().to_ascii_lowercase());
-    if current.as_deref() == Some(mapped) {
-        return false;
+fn estimate_messages_tokens(messages: &[ChatMessage]) -> u32 {
+    messages.iter().fold(0u32, |total, message| {
+        total
+            .saturating_add(estimate_text_tokens(&message.content))
+            .saturating_add(4)
+    })
+}
+
+fn estimate_text_tokens(text: &str) -> u32 {
+    let mut cjk = 0u32;
+    let mut other = 0u32;
+    for ch in text.chars() {
+        if is_cjk_char(ch) {
+            cjk = cjk.saturating_add(1);
+        } else {
+            other = other.saturating_add(1);
+        }
     }
+    cjk.saturating_add(other.div_ceil(4))
+}
 
-    root.insert(
-        "themePreset".to_string(),
-        serde_json::Value::String(mapped.to_string()),
-    );
-    true
+fn is_sentence_end(ch: char) -> bool {
+    matches!(
+        ch,
+        '。' | '！' | '？' | '；' | '…' | '.' | '!' | '?' | ';' | '\n'
+    )
 }
 
-fn migrate_legacy_window_settings(root: &mut serde_json::Map<String, serde_json::Value>) -> bool {
-    let mut window_obj = root
-        .remove("window")
-        .and_then(|value| value.as_object().cloned())
-        .unwrap_or_default();
+/// Splits `text` after every position where `is_boundary` holds, keeping the separators so the
+/// pieces concatenate back to the original text.
+fn split_keeping_boundaries(
+    text: &str,
+    is_boundary: impl Fn(char, Option<char>) -> bool,
+) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((index, ch)) = chars.next() {
+        let next = chars.peek().map(|(_, next)| *next);
+        if is_boundary(ch, next) {
+            let end = index + ch.len_utf8();
+            pieces.push(&text[start..end]);
+            start = end;
+        }
+    }
+    if start < text.len() {
+        pieces.push(&text[start..]);
+    }
+    pieces
+}
 
-    let mut changed = false;
+fn split_sentences(text: &str) -> Vec<&str> {
+    // CJK sentence punctuation ends a sentence on its own; Latin punctuation needs a space.
+    split_keeping_boundaries(text, |ch, next| {
+        is_sentence_end(ch) && (!ch.is_ascii() || next.is_none_or(|next| next.is_whitespace()))
+    })
+}
 
-    for (legacy_key, target_key) in [
-        ("launchOnSystemStartup", "launchOnSystemStartup"),
-        ("launchOnStartup", "launchOnSystemStartup"),
-        ("launch_on_system_startup", "launchOnSystemStartup"),
-        ("silentStartup", "silentStartup"),
-        ("silentOnStartup", "silentStartup"),
-        ("silent_startup", "silentStartup"),
-        ("checkUpdatesOnStartup", "checkUpdatesOnStartup"),
-        ("checkUpdatesOnLaunch", "checkUpdatesOnStartup"),
-        ("check_updates_on_startup", "checkUpdatesOnStartup"),
-        ("autoHideOnBlur", "autoHideOnBlur"),
-        ("auto_hide_on_blur", "autoHideOnBlur"),
-        ("rememberPosition", "rememberPosition"),
-        ("remember_position", "rememberPosition"),
-        ("rememberMainWindowSize", "rememberMainWindowSize"),
-        ("remember_main_window_size", "rememberMainWindowSize"),
-    ] {
-        let root_flag = root.get(legacy_key).and_then(parse_bool_like_json);
-        let nested_flag = window_obj.get(legacy_key).and_then(parse_bool_like_json);
-        if let Some(flag) = root_flag {
-            changed |= assign_window_bool_if_missing(&mut window_obj, target_key, flag);
+/// Splits `text` into pieces of at most `max_tokens` estimated tokens, preferring paragraph
+/// breaks, then sentence ends (including CJK punctuation), and only cutting inside a sentence
+/// when a single sentence is larger than the budget.
+fn split_text_into_chunks(text: &str, max_tokens: u32) -> Vec<String> {
+    let max_tokens = max_tokens.max(1);
+    let mut units: Vec<&str> = Vec::new();
+    for paragraph in split_keeping_boundaries(text, |ch, _| ch == '\n') {
+        if estimate_text_tokens(paragraph) <= max_tokens {
+            units.push(paragraph);
+            continue;
         }
-        if let Some(flag) = nested_flag {
-            changed |= assign_window_bool_if_missing(&mut window_obj, target_key, flag);
+        for sentence in split_sentences(paragraph) {
+            if estimate_text_tokens(sentence) <= max_tokens {
+                units.push(sentence);
+                continue;
+            }
+            let mut piece_start = 0;
+            let mut piece_tokens = 0u32;
+            for (index, ch) in sentence.char_indices() {
+                let ch_tokens = estimate_text_tokens(ch.encode_utf8(&mut [0; 4]));
+                if piece_tokens + ch_tokens > max_tokens && index > piece_start {
+                    units.push(&sentence[piece_start..index]);
+                    piece_start = index;
+                    piece_tokens = 0;
+                }
+                piece_tokens += ch_tokens;
+            }
+            units.push(&sentence[piece_start..]);
         }
     }
 
-    if !window_obj.is_empty() {
-        root.insert("window".to_string(), serde_json::Value::Object(window_obj));
+    let mut chunks: Vec<String> = Vec::new();
+    let mut current = String::new();
+    let mut current_tokens = 0u32;
+    for unit in units {
+        let unit_tokens = estimate_text_tokens(unit);
+        if !current.is_empty() && current_tokens + unit_tokens > max_tokens {
+            chunks.push(std::mem::take(&mut current));
+            current_tokens = 0;
+        }
+        current.push_str(unit);
+        current_tokens += unit_tokens;
+    }
+    if !current.trim().is_empty() {
+        chunks.push(current);
+    } else if let Some(last) = chunks.last_mut() {
+        last.push_str(&current);
     }
+    chunks
+}
 
-    changed
+/// Returns the chunks for a long input, or `None` when it fits into one request.
+fn chunk_long_input(text: &str, chunk_tokens: u32) -> Option<Vec<String>> {
+    if chunk_tokens == 0 || estimate_text_tokens(text) <= chunk_tokens {
+        return None;
+    }
+    let chunks = split_text_into_chunks(text, chunk_tokens);
+    (chunks.len() > 1).then_some(chunks)
 }
 
-fn normalize_settings_json_value(value: &mut serde_json::Value) -> bool {
-    let Some(root) = value.as_object_mut() else {
-        return false;
-    };
+async fn sleep_for_ms(ms: u64) {
+    if ms == 0 {
+        return;
+    }
+    let _ = tauri::async_runtime::spawn_blocking(move || {
+        std::thread::sleep(Duration::from_millis(ms));
+    })
+    .await;
+}
 
-    let mut changed = false;
+fn format_response_body_for_error(raw_body: &str) -> String {
+    let trimmed = raw_body.trim();
+    if trimmed.is_empty() {
+        return "Empty response body".to_string();
+    }
 
-    changed |= migrate_legacy_theme_preset(root);
-    changed |= migrate_legacy_window_settings(root);
+    const MAX_CHARS: usize = 1200;
+    let mut output = trimmed.chars().take(MAX_CHARS).collect::<String>();
+    if trimmed.chars().nth(MAX_CHARS).is_some() {
+        output.push_str("...");
+    }
+    output
+}
 
-    changed |= normalize_enum_value_in_object(
-        root,
-        "themePreset",
-        &[
-            "dark",
-            "light",
-            "warm",
-            "blue",
-            "deep-black",
-            "gray",
-            "white",
-            "black",
-            "md2-dark",
-            "midnight",
-            "graphite",
-            "daylight",
-            "sunrise",
-            "amber-mist",
-        ],
-        "dark",
-    );
-
-    if let Some(selection) = root
-        .get_mut("selectionAssistant")
-        .and_then(|value| value.as_object_mut())
-    {
-        changed |= normalize_enum_value_in_object(
-            selection,
-            "mode",
-            &["auto-detect", "copy-trigger"],
-            "auto-detect",
-        );
-    }
-
-    if let Some(history) = root
-        .get_mut("history")
-        .and_then(|value| value.as_object_mut())
-    {
-        if !history.contains_key("defaultOpenCategory") {
-            if let Some(existing_default) = history
-                .get("defaultCategory")
-                .and_then(|value| value.as_str())
-                .map(|value| value.trim().to_ascii_lowercase())
-            {
-                let migrated = match existing_default.as_str() {
-                    "all" | "text" | "link" | "image" | "favorite" => {
-                        Some(existing_default.as_str())
-                    }
-                    _ => None,
-                };
-                if let Some(migrated) = migrated {
-                    history.insert(
-                        "defaultOpenCategory".to_string(),
-                        serde_json::Value::String(migrated.to_string()),
-                    );
-                    changed = true;
-                }
-            }
-        }
-        changed |= normalize_enum_value_in_object(
-            history,
-            "defaultOpenCategory",
-            &["all", "text", "link", "image", "favorite", "last-used"],
-            "all",
-        );
-        changed |= normalize_enum_value_in_object(
-            history,
-            "defaultCategory",
-            &["all", "text", "link", "image", "favorite"],
-            "all",
-        );
-        changed |= normalize_enum_value_in_object(
-            history,
-            "pasteBehavior",
-            &["copy-only", "copy-and-hide"],
-            "copy-and-hide",
-        );
-    }
-
-    if let Some(ocr) = root.get_mut("ocr").and_then(|value| value.as_object_mut()) {
-        changed |= normalize_enum_value_in_object(
-            ocr,
-            "defaultAction",
-            &["translate", "summarize", "polish", "explain", "custom"],
-            "translate",
-        );
-    }
-
-    if let Some(tts) = root.get_mut("tts").and_then(|value| value.as_object_mut()) {
-        changed |= normalize_enum_value_in_object(
-            tts,
-            "runtimeMode",
-            &["dual-fallback", "edge-cli-only", "python-module-only"],
-            "dual-fallback",
-        );
-    }
+fn with_history_lock<'a>(
+    state: &'a State<'_, Mutex<ClipboardState>>,
+) -> Result<std::sync::MutexGuard<'a, ClipboardState>, CommandError> {
+    state.lock().map_err(|_| CommandError::Lock)
+}
 
-    changed
+fn with_settings_lock<'a>(
+    state: &'a State<'_, AppSettingsState>,
+) -> Result<std::sync::MutexGuard<'a, AppSettings>, CommandError> {
+    state.data.lock().map_err(|_| CommandError::Lock)
 }
 
-fn merge_json_value_by_shape(target: &mut serde_json::Value, incoming: &serde_json::Value) {
-    if let (Some(target_map), Some(incoming_map)) = (target.as_object_mut(), incoming.as_object()) {
-        for (key, incoming_value) in incoming_map {
-            if let Some(target_value) = target_map.get_mut(key) {
-                merge_json_value_by_shape(target_value, incoming_value);
-            } else {
-                target_map.insert(key.clone(), incoming_value.clone());
-            }
-        }
-        return;
-    }
+fn clamp_poll_ms(value: u64) -> u64 {
+    value.clamp(MIN_POLL_MS, MAX_POLL_MS)
+}
 
-    let should_replace = target.is_null()
-        || (target.is_array() && incoming.is_array())
-        || (target.is_string() && incoming.is_string())
-        || (target.is_boolean() && incoming.is_boolean())
-        || (target.is_number() && incoming.is_number());
-    if should_replace {
-        *target = incoming.clone();
-    }
+fn clamp_history_items(value: usize) -> usize {
+    value.clamp(MIN_HISTORY_ITEMS, MAX_HISTORY_ITEMS)
 }
 
-fn parse_settings_from_text(text: &str) -> Result<(AppSettings, bool), CommandError> {
-    let mut incoming = serde_json::from_str::<serde_json::Value>(text)
-        .map_err(|error| CommandError::Serialization(error.to_string()))?;
-    let direct_parse_ok = serde_json::from_str::<AppSettings>(text).is_ok();
-    let repaired_json = normalize_settings_json_value(&mut incoming);
+fn clamp_main_window_width(value: u32) -> u32 {
+    value.clamp(MIN_MAIN_WINDOW_WIDTH, MAX_MAIN_WINDOW_WIDTH)
+}
 
-    let mut merged = serde_json::to_value(AppSettings::default())
-        .map_err(|error| CommandError::Serialization(error.to_string()))?;
-    merge_json_value_by_shape(&mut merged, &incoming);
+fn clamp_main_window_height(value: u32) -> u32 {
+    value.clamp(MIN_MAIN_WINDOW_HEIGHT, MAX_MAIN_WINDOW_HEIGHT)
+}
 
-    let mut settings = serde_json::from_value::<AppSettings>(merged)
-        .map_err(|error| CommandError::Serialization(error.to_string()))?;
-    normalize_settings(&mut settings);
-    Ok((settings, repaired_json || !direct_parse_ok))
+fn clamp_selection_result_window_width(value: u32) -> u32 {
+    value.clamp(
+        MIN_SELECTION_RESULT_WINDOW_WIDTH,
+        MAX_SELECTION_RESULT_WINDOW_WIDTH,
+    )
 }
 
-fn file_modified_epoch_ms(path: &Path) -> u64 {
-    fs::metadata(path)
-        .and_then(|metadata| metadata.modified())
-        .ok()
-        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
-        .map(|duration| duration.as_millis() as u64)
-        .unwrap_or(0)
+fn clamp_selection_result_window_height(value: u32) -> u32 {
+    value.clamp(
+        MIN_SELECTION_RESULT_WINDOW_HEIGHT,
+        MAX_SELECTION_RESULT_WINDOW_HEIGHT,
+    )
 }
 
-fn is_likely_snapparse_dir(path: &Path) -> bool {
-    path.file_name()
-        .and_then(|name| name.to_str())
-        .map(|name| {
-            let lowered = name.to_ascii_lowercase();
-            lowered.contains("snapparse") || lowered.contains("clipboard")
-        })
-        .unwrap_or(false)
+fn clamp_ocr_result_window_width(value: u32) -> u32 {
+    value.clamp(MIN_OCR_RESULT_WINDOW_WIDTH, MAX_OCR_RESULT_WINDOW_WIDTH)
 }
 
-fn discover_legacy_storage_dirs(settings_path: &Path) -> Vec<PathBuf> {
-    let Some(current_dir) = settings_path.parent() else {
-        return Vec::new();
-    };
-    let Some(parent_dir) = current_dir.parent() else {
-        return Vec::new();
-    };
+fn clamp_ocr_result_window_height(value: u32) -> u32 {
+    value.clamp(MIN_OCR_RESULT_WINDOW_HEIGHT, MAX_OCR_RESULT_WINDOW_HEIGHT)
+}
 
-    let mut dirs = Vec::<PathBuf>::new();
-    if let Ok(entries) = fs::read_dir(parent_dir) {
-        for entry in entries.flatten() {
-            let candidate = entry.path();
-            if !candidate.is_dir() || candidate == current_dir {
-                continue;
-            }
-            if !is_likely_snapparse_dir(&candidate) {
-                continue;
-            }
-            dirs.push(candidate);
-        }
-    }
-    dirs.sort_by_key(|path| std::cmp::Reverse(file_modified_epoch_ms(path)));
-    dirs
+fn clamp_selection_min_chars(value: usize) -> usize {
+    value.clamp(1, 64)
 }
 
-fn dedupe_paths_keep_order(input: Vec<PathBuf>) -> Vec<PathBuf> {
-    let mut seen = HashSet::<PathBuf>::new();
-    let mut output = Vec::<PathBuf>::new();
-    for item in input {
-        if seen.insert(item.clone()) {
-            output.push(item);
-        }
-    }
-    output
+fn clamp_selection_max_chars(value: usize) -> usize {
+    value.clamp(128, 100_000)
 }
 
-fn parse_history_kind(value: &serde_json::Value) -> Option<ClipboardKind> {
-    let raw = value.as_str()?.trim().to_ascii_lowercase();
-    match raw.as_str() {
-        "text" => Some(ClipboardKind::Text),
-        "link" => Some(ClipboardKind::Link),
-        "image" => Some(ClipboardKind::Image),
-        _ => None,
-    }
+fn to_i32(value: u32) -> i32 {
+    value.min(i32::MAX as u32) as i32
 }
 
-fn parse_history_timestamp(value: &serde_json::Value) -> Option<DateTime<Utc>> {
-    if let Some(text) = value.as_str() {
-        if let Ok(parsed) = DateTime::parse_from_rfc3339(text.trim()) {
-            return Some(parsed.with_timezone(&Utc));
-        }
-        return None;
-    }
+fn monitor_bounds(monitor: &tauri::Monitor) -> (i32, i32, i32, i32) {
+    let position = monitor.position();
+    let size = monitor.size();
+    let max_x = position.x.saturating_add(to_i32(size.width));
+    let max_y = position.y.saturating_add(to_i32(size.height));
+    (position.x, position.y, max_x, max_y)
+}
 
-    let numeric = value.as_i64()?;
-    if numeric > 4_000_000_000 {
-        DateTime::<Utc>::from_timestamp_millis(numeric)
-    } else {
-        DateTime::<Utc>::from_timestamp(numeric, 0)
-    }
+fn monitor_contains_point(monitor: &tauri::Monitor, point: PhysicalPosition<i32>) -> bool {
+    let (min_x, min_y, max_x, max_y) = monitor_bounds(monitor);
+    point.x >= min_x && point.x < max_x && point.y >= min_y && point.y < max_y
 }
 
-fn parse_history_entry_compat(raw: &serde_json::Value) -> Option<ClipboardEntry> {
-    let object = raw.as_object()?;
-    let content = object
-        .get("content")
-        .and_then(|value| value.as_str())
-        .or_else(|| object.get("text").and_then(|value| value.as_str()))
-        .unwrap_or_default()
-        .to_string();
-    let image_data_url = object
-        .get("imageDataUrl")
-        .and_then(|value| value.as_str())
-        .or_else(|| object.get("image").and_then(|value| value.as_str()))
-        .map(str::to_string);
+fn clamp_position_to_monitor(
+    monitor: &tauri::Monitor,
+    candidate: PhysicalPosition<i32>,
+    width: u32,
+    height: u32,
+) -> PhysicalPosition<i32> {
+    let monitor_pos = monitor.position();
+    let monitor_size = monitor.size();
+    let scale_factor = monitor.scale_factor().max(0.1);
 
-    let kind = object
-        .get("kind")
-        .and_then(parse_history_kind)
-        .or_else(|| {
-            if image_data_url.is_some() {
-                Some(ClipboardKind::Image)
-            } else if content.contains("://") {
-                Some(ClipboardKind::Link)
-            } else {
-                Some(ClipboardKind::Text)
-            }
-        })?;
-
-    let copied_at = object
-        .get("copiedAt")
-        .and_then(parse_history_timestamp)
-        .or_else(|| object.get("copied_at").and_then(parse_history_timestamp))
-        .or_else(|| object.get("createdAt").and_then(parse_history_timestamp))
-        .or_else(|| object.get("timestamp").and_then(parse_history_timestamp))
-        .unwrap_or_else(Utc::now);
+    // `width`/`height` are logical window sizes from settings/config.
+    // Convert to physical pixels before clamping against monitor bounds.
+    let window_width_px = ((width as f64) * scale_factor)
+        .round()
+        .clamp(1.0, i32::MAX as f64) as i32;
+    let window_height_px = ((height as f64) * scale_factor)
+        .round()
+        .clamp(1.0, i32::MAX as f64) as i32;
 
-    let pinned = object
-        .get("pinned")
-        .and_then(parse_bool_like_json)
-        .unwrap_or(false);
-    let id = object
-        .get("id")
-        .and_then(|value| value.as_str())
-        .map(str::trim)
-        .filter(|value| !value.is_empty())
-        .map(str::to_string)
-        .unwrap_or_else(now_id);
+    let min_x = monitor_pos.x;
+    let min_y = monitor_pos.y;
+    let max_x = min_x
+        .saturating_add(to_i32(monitor_size.width))
+        .saturating_sub(window_width_px)
+        .max(min_x);
+    let max_y = min_y
+        .saturating_add(to_i32(monitor_size.height))
+        .saturating_sub(window_height_px)
+        .max(min_y);
 
-    Some(ClipboardEntry {
-        id,
-        kind,
-        content,
-        image_data_url,
-        thumbnail_data_url: None,
-        copied_at,
-        pinned,
-    })
+    PhysicalPosition::new(
+        candidate.x.clamp(min_x, max_x),
+        candidate.y.clamp(min_y, max_y),
+    )
 }
 
-fn truncate_text_chars(input: &str, max_chars: usize) -> String {
-    if max_chars == 0 {
-        return String::new();
-    }
-    if input.chars().count() <= max_chars {
-        return input.to_string();
-    }
-    let mut output = String::with_capacity(input.len().min(max_chars.saturating_mul(3)));
-    for ch in input.chars().take(max_chars) {
-        output.push(ch);
-    }
-    output
+fn monitor_for_point<R: Runtime>(
+    app: &AppHandle<R>,
+    point: PhysicalPosition<i32>,
+) -> Option<tauri::Monitor> {
+    let monitors = app.available_monitors().ok()?;
+    monitors
+        .into_iter()
+        .find(|monitor| monitor_contains_point(monitor, point))
+        .or_else(|| app.primary_monitor().ok().flatten())
 }
 
-fn sanitize_clipboard_entry_for_storage(mut entry: ClipboardEntry) -> Option<ClipboardEntry> {
-    entry.id = entry.id.trim().to_string();
-    if entry.id.is_empty() {
-        entry.id = now_id();
-    }
-
-    match entry.kind {
-        ClipboardKind::Text | ClipboardKind::Link => {
-            let normalized = truncate_text_chars(entry.content.trim(), MAX_CLIPBOARD_TEXT_CHARS);
-            if normalized.is_empty() {
-                return None;
-            }
-            entry.content = normalized;
-            entry.image_data_url = None;
-        }
-        ClipboardKind::Image => {
-            let payload = entry.image_data_url.as_deref()?.trim();
-            let lowered = payload.to_ascii_lowercase();
-            if !lowered.starts_with("data:image/") || !lowered.contains("base64,") {
-                return None;
-            }
-            if payload.len() > MAX_CLIPBOARD_IMAGE_DATA_URL_CHARS {
-                return None;
-            }
-            entry.image_data_url = Some(payload.to_string());
-            if entry.content.trim().is_empty() {
-                entry.content = "Image".to_string();
-            }
-        }
+#[cfg(target_os = "windows")]
+fn current_pointer_position() -> Option<PhysicalPosition<i32>> {
+    let mut point = POINT { x: 0, y: 0 };
+    if unsafe { GetCursorPos(&mut point as *mut POINT) } != 0 {
+        Some(PhysicalPosition::new(point.x, point.y))
+    } else {
+        None
     }
-
-    Some(entry)
 }
 
-fn parse_history_entries(
-    text: &str,
-    max_items: usize,
-) -> Result<VecDeque<ClipboardEntry>, CommandError> {
-    let items = match serde_json::from_str::<Vec<ClipboardEntry>>(text) {
-        Ok(parsed) => parsed,
-        Err(_) => {
-            let raw_value = serde_json::from_str::<serde_json::Value>(text)
-                .map_err(|error| CommandError::Serialization(error.to_string()))?;
-            let raw_items = raw_value.as_array().ok_or_else(|| {
-                CommandError::Serialization("History snapshot must be a JSON array".to_string())
-            })?;
-            let mut repaired = Vec::<ClipboardEntry>::with_capacity(raw_items.len());
-            for raw_item in raw_items {
-                if let Ok(entry) = serde_json::from_value::<ClipboardEntry>(raw_item.clone()) {
-                    repaired.push(entry);
-                    continue;
-                }
-                if let Some(entry) = parse_history_entry_compat(raw_item) {
-                    repaired.push(entry);
-                }
-            }
-            repaired
-        }
-    };
-    let sanitized = items
-        .into_iter()
-        .filter_map(sanitize_clipboard_entry_for_storage)
-        .collect::<Vec<_>>();
-
-    let mut history = VecDeque::from(sanitized);
-    trim_history(&mut history, max_items);
-    normalize_history_order(&mut history);
-    Ok(history)
+#[cfg(not(target_os = "windows"))]
+fn current_pointer_position() -> Option<PhysicalPosition<i32>> {
+    None
 }
 
-fn read_text_with_retry_with_limit(
-    path: &Path,
-    max_bytes: Option<u64>,
-) -> Result<String, std::io::Error> {
-    const RETRY_COUNT: usize = 20;
-    const RETRY_DELAY_MS: u64 = 120;
-    if let Some(max_bytes) = max_bytes {
-        if let Ok(metadata) = fs::metadata(path) {
-            if metadata.len() > max_bytes {
-                return Err(std::io::Error::new(
-                    ErrorKind::InvalidData,
-                    format!(
-                        "file too large: {} bytes exceeds limit {} bytes",
-                        metadata.len(),
-                        max_bytes
-                    ),
-                ));
-            }
-        }
+fn is_saved_size_near_monitor_bounds(
+    width: u32,
+    height: u32,
+    monitor_width: u32,
+    monitor_height: u32,
+) -> bool {
+    if monitor_width == 0 || monitor_height == 0 {
+        return false;
     }
 
-    let mut last_error: Option<std::io::Error> = None;
+    let width_limit = (monitor_width as f64 * 0.92).round() as u32;
+    let height_limit = (monitor_height as f64 * 0.92).round() as u32;
+    width >= width_limit && height >= height_limit
+}
 
-    for attempt in 0..RETRY_COUNT {
-        match fs::read_to_string(path) {
-            Ok(value) => return Ok(value),
-            Err(error)
-                if matches!(
-                    error.kind(),
-                    ErrorKind::PermissionDenied | ErrorKind::WouldBlock | ErrorKind::Interrupted
-                ) && attempt + 1 < RETRY_COUNT =>
-            {
-                last_error = Some(error);
-                std::thread::sleep(Duration::from_millis(RETRY_DELAY_MS));
-            }
-            Err(error) => return Err(error),
-        }
+fn normalize_shortcut(value: &str, fallback: &str) -> String {
+    let trimmed = value.trim();
+    if trimmed.is_empty() {
+        fallback.to_string()
+    } else {
+        trimmed.to_string()
     }
-
-    Err(last_error
-        .unwrap_or_else(|| std::io::Error::other("history file read failed after retries")))
 }
 
-fn read_settings_text_with_retry(path: &Path) -> Result<String, std::io::Error> {
-    read_text_with_retry_with_limit(path, Some(MAX_SETTINGS_FILE_BYTES))
+fn normalize_language(value: &str) -> String {
+    if value.eq_ignore_ascii_case("en-US") {
+        "en-US".to_string()
+    } else {
+        "zh-CN".to_string()
+    }
 }
 
-fn read_history_text_with_retry(path: &Path) -> Result<String, std::io::Error> {
-    read_text_with_retry_with_limit(path, Some(MAX_HISTORY_FILE_BYTES))
+fn normalize_translate_language(value: &str, fallback: &str) -> String {
+    find_language(value)
+        .map(|language| language.code.to_string())
+        .unwrap_or_else(|| fallback.to_string())
 }
 
-fn resolve_history_path_from_base_dir(storage_path: &str, base_dir: &Path) -> PathBuf {
-    let trimmed = storage_path.trim();
-    if trimmed.is_empty() {
-        return base_dir.join(HISTORY_FILENAME);
-    }
-
-    let mut path = PathBuf::from(trimmed);
-    if path.is_relative() {
-        path = base_dir.join(path);
-    }
-
-    if !trimmed.to_ascii_lowercase().ends_with(".json") {
-        path.push(HISTORY_FILENAME);
+fn default_translate_target_for_language(language: &str) -> &'static str {
+    if language.eq_ignore_ascii_case("en-US") {
+        "zh-CN"
+    } else {
+        "en-US"
     }
-    path
 }
 
-fn app_config_history_file_path<R: Runtime>(app: &AppHandle<R>) -> Result<PathBuf, CommandError> {
-    let mut config_dir = app
-        .path()
-        .app_config_dir()
-        .map_err(|error| CommandError::Settings(error.to_string()))?;
-    fs::create_dir_all(&config_dir).map_err(|error| CommandError::Settings(error.to_string()))?;
-    config_dir.push(HISTORY_FILENAME);
-    Ok(config_dir)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Script {
+    Han,
+    Kana,
+    Hangul,
+    Latin,
+    Cyrillic,
+    Greek,
+    Arabic,
+    Hebrew,
+    Devanagari,
+    Thai,
+}
+
+struct LanguageInfo {
+    /// BCP-47 tag used in settings, prompts and payloads.
+    code: &'static str,
+    english_name: &'static str,
+    native_name: &'static str,
+    script: Script,
+    /// Edge TTS voice used when no configured voice covers the language.
+    tts_voice: &'static str,
 }
 
-fn resolve_history_file_path<R: Runtime>(
-    app: &AppHandle<R>,
-    settings: &AppSettings,
-) -> Result<PathBuf, CommandError> {
-    let config_dir = app_config_history_file_path(app)?
-        .parent()
-        .map(Path::to_path_buf)
-        .ok_or_else(|| CommandError::Settings("Invalid app config directory".to_string()))?;
-    let path = resolve_history_path_from_base_dir(&settings.history.storage_path, &config_dir);
-
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent).map_err(|error| CommandError::Settings(error.to_string()))?;
-    }
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LanguageOptionPayload {
+    code: &'static str,
+    english_name: &'static str,
+    native_name: &'static str,
+}
+
+const LANGUAGE_REGISTRY: &[LanguageInfo] = &[
+    LanguageInfo {
+        code: "zh-CN",
+        english_name: "Chinese (Simplified)",
+        native_name: "简体中文",
+        script: Script::Han,
+        tts_voice: DEFAULT_TTS_VOICE_ZH_CN,
+    },
+    LanguageInfo {
+        code: "zh-TW",
+        english_name: "Chinese (Traditional)",
+        native_name: "繁體中文",
+        script: Script::Han,
+        tts_voice: "zh-TW-HsiaoChenNeural",
+    },
+    LanguageInfo {
+        code: "en-US",
+        english_name: "English",
+        native_name: "English",
+        script: Script::Latin,
+        tts_voice: DEFAULT_TTS_VOICE_EN_US,
+    },
+    LanguageInfo {
+        code: "ja-JP",
+        english_name: "Japanese",
+        native_name: "日本語",
+        script: Script::Kana,
+        tts_voice: "ja-JP-NanamiNeural",
+    },
+    LanguageInfo {
+        code: "ko-KR",
+        english_name: "Korean",
+        native_name: "한국어",
+        script: Script::Hangul,
+        tts_voice: "ko-KR-SunHiNeural",
+    },
+    LanguageInfo {
+        code: "fr-FR",
+        english_name: "French",
+        native_name: "Français",
+        script: Script::Latin,
+        tts_voice: "fr-FR-DeniseNeural",
+    },
+    LanguageInfo {
+        code: "de-DE",
+        english_name: "German",
+        native_name: "Deutsch",
+        script: Script::Latin,
+        tts_voice: "de-DE-KatjaNeural",
+    },
+    LanguageInfo {
+        code: "es-ES",
+        english_name: "Spanish",
+        native_name: "Español",
+        script: Script::Latin,
+        tts_voice: "es-ES-ElviraNeural",
+    },
+    LanguageInfo {
+        code: "pt-BR",
+        english_name: "Portuguese",
+        native_name: "Português",
+        script: Script::Latin,
+        tts_voice: "pt-BR-FranciscaNeural",
+    },
+    LanguageInfo {
+        code: "it-IT",
+        english_name: "Italian",
+        native_name: "Italiano",
+        script: Script::Latin,
+        tts_voice: "it-IT-ElsaNeural",
+    },
+    LanguageInfo {
+        code: "nl-NL",
+        english_name: "Dutch",
+        native_name: "Nederlands",
+        script: Script::Latin,
+        tts_voice: "nl-NL-ColetteNeural",
+    },
+    LanguageInfo {
+        code: "sv-SE",
+        english_name: "Swedish",
+        native_name: "Svenska",
+        script: Script::Latin,
+        tts_voice: "sv-SE-SofieNeural",
+    },
+    LanguageInfo {
+        code: "pl-PL",
+        english_name: "Polish",
+        native_name: "Polski",
+        script: Script::Latin,
+        tts_voice: "pl-PL-ZofiaNeural",
+    },
+    LanguageInfo {
+        code: "tr-TR",
+        english_name: "Turkish",
+        native_name: "Türkçe",
+        script: Script::Latin,
+        tts_voice: "tr-TR-EmelNeural",
+    },
+    LanguageInfo {
+        code: "vi-VN",
+        english_name: "Vietnamese",
+        native_name: "Tiếng Việt",
+        script: Script::Latin,
+        tts_voice: "vi-VN-HoaiMyNeural",
+    },
+    LanguageInfo {
+        code: "id-ID",
+        english_name: "Indonesian",
+        native_name: "Bahasa Indonesia",
+        script: Script::Latin,
+        tts_voice: "id-ID-GadisNeural",
+    },
+    LanguageInfo {
+        code: "ru-RU",
+        english_name: "Russian",
+        native_name: "Русский",
+        script: Script::Cyrillic,
+        tts_voice: "ru-RU-SvetlanaNeural",
+    },
+    LanguageInfo {
+        code: "uk-UA",
+        english_name: "Ukrainian",
+        native_name: "Українська",
+        script: Script::Cyrillic,
+        tts_voice: "uk-UA-PolinaNeural",
+    },
+    LanguageInfo {
+        code: "el-GR",
+        english_name: "Greek",
+        native_name: "Ελληνικά",
+        script: Script::Greek,
+        tts_voice: "el-GR-AthinaNeural",
+    },
+    LanguageInfo {
+        code: "ar-SA",
+        english_name: "Arabic",
+        native_name: "العربية",
+        script: Script::Arabic,
+        tts_voice: "ar-SA-ZariyahNeural",
+    },
+    LanguageInfo {
+        code: "he-IL",
+        english_name: "Hebrew",
+        native_name: "עברית",
+        script: Script::Hebrew,
+        tts_voice: "he-IL-HilaNeural",
+    },
+    LanguageInfo {
+        code: "hi-IN",
+        english_name: "Hindi",
+        native_name: "हिन्दी",
+        script: Script::Devanagari,
+        tts_voice: "hi-IN-SwaraNeural",
+    },
+    LanguageInfo {
+        code: "th-TH",
+        english_name: "Thai",
+        native_name: "ไทย",
+        script: Script::Thai,
+        tts_voice: "th-TH-PremwadeeNeural",
+    },
+];
 
-    Ok(path)
-}
+/// Frequent character trigrams and distinctive letters of the Latin-script languages, used to
+/// tell them apart once the script is known.
+const LATIN_LANGUAGE_PROFILES: &[(&str, &[&str], &str)] = &[
+    (
+        "en-US",
+        &[
+            " th", "the", "he ", "and", "nd ", " an", "ing", "ng ", " of", "of ", " to", "to ",
+            " is", "is ", "ed ", "hat", "tha", " wh", "you", "for",
+        ],
+        "",
+    ),
+    (
+        "fr-FR",
+        &[
+            " de", "es ", " le", "le ", "ent", " la", "la ", "les", " et", "et ", "que", " qu",
+            " un", "ne ", "des", " po", "ous", "ait", " pa", "eur",
+        ],
+        "àâçèêëîïôûœ",
+    ),
+    (
+        "de-DE",
+        &[
+            "en ", "er ", "der", "ie ", "die", " di", "ich", "ein", "sch", "und", " un", "che",
+            "ch ", " ei", "den", "cht", " da", "ung", "ist", " zu",
+        ],
+        "äöüß",
+    ),
+    (
+        "es-ES",
+        &[
+            " de", "os ", " la", "la ", "el ", " el", "en ", " qu", "que", "ue ", "ció", "as ",
+            " lo", "los", " en", "ado", " co", " se", "ara", "por",
+        ],
+        "ñ¿¡áíóú",
+    ),
+    (
+        "pt-BR",
+        &[
+            " de", "os ", "ão ", " qu", "que", " a ", "do ", " do", "as ", " co", "da ", " da",
+            "ção", " pa", "em ", "um ", "nto", " nã", "não", "ões",
+        ],
+        "ãõçâêô",
+    ),
+    (
+        "it-IT",
+        &[
+            " di", "di ", "la ", "to ", "re ", " la", "che", " ch", "lla", "ell", " il", "il ",
+            "one", "no ", "ato", "del", "zio", "per", " pe", "gli",
+        ],
+        "àèìòù",
+    ),
+    (
+        "nl-NL",
+        &[
+            "en ", "de ", " de", "an ", "het", " he", "van", " va", "ij ", "een", " ee", "aar",
+            " ge", "oor", " ve", "ijk", "nie", "zij", " zi", "ook",
+        ],
+        "",
+    ),
+    (
+        "sv-SE",
+        &[
+            "en ", "och", " oc", "ch ", "ar ", "det", " de", "att", " at", "tt ", "för", " fö",
+            " so", "som", "om ", "ing", "ade", "lig", " är", "är ",
+        ],
+        "åäö",
+    ),
+    (
+        "pl-PL",
+        &[
+            "ie ", "nie", " ni", "prz", " pr", "rze", "ych", "ego", "ani", " po", "wie", " w ",
+            "ści", "czy", "się", " si", "nia", " je", "jes", "est",
+        ],
+        "ąćęłńśźż",
+    ),
+    (
+        "tr-TR",
+        &[
+            "lar", "ler", "bir", " bi", "ın ", "eri", "ara", "ini", " ve", "ve ", "da ", "nda",
+            "ile", " il", "ası", "için", "dır", "mak", "yor", " ol",
+        ],
+        "ğışİ",
+    ),
+    (
+        "vi-VN",
+        &[
+            " ng", "ng ", "nh ", " th", " tr", "ông", " kh", "của", " cá", "các", "là ", "và ",
+            " và", "như", "một", " mộ", "ược", " đư", "được", "này",
+        ],
+        "ăđơưạảấầẩẫậắằẳẵặẹẻẽếềểễệỉịọỏốồổỗộớờởỡợụủứừửữựỳỵỷỹ",
+    ),
+    (
+        "id-ID",
+        &[
+            "an ", "ang", " me", "kan", "nya", "ya ", " da", "yan", "dan", "men", "ber", " be",
+            "ada", " di", "ah ", "ka ", "per", "ini", " ya", "tid",
+        ],
+        "",
+    ),
+];
 
-fn recover_history_for_storage_path_switch<R: Runtime>(
-    app: &AppHandle<R>,
-    previous_settings: &AppSettings,
-    updated_settings: &AppSettings,
-) -> Option<VecDeque<ClipboardEntry>> {
-    if previous_settings.history.storage_path.trim() == updated_settings.history.storage_path.trim()
-    {
+const TRADITIONAL_CHINESE_MARKERS: &str =
+    "這個們說來會時學國對發還開關為與書長門問間東車見體點過從電話讓應實當麼經頭進機動給現後種樣聽覺風";
+const SIMPLIFIED_CHINESE_MARKERS: &str =
+    "这个们说来会时学国对发还开关为与书长门问间东车见体点过从电话让应实当么经头进机动给现后种样听觉风";
+
+/// Looks up a registry language by BCP-47 tag, falling back to the primary subtag
+/// (`en` and `en-GB` map to `en-US`, `zh-Hant` and `zh-HK` to `zh-TW`).
+fn find_language(code: &str) -> Option<&'static LanguageInfo> {
+    let lowered = code.trim().replace('_', "-").to_ascii_lowercase();
+    if lowered.is_empty() || lowered == "auto" {
         return None;
     }
-
-    let mut candidates = Vec::<PathBuf>::new();
-    if let Ok(previous_path) = resolve_history_file_path(app, previous_settings) {
-        candidates.push(previous_path.clone());
-        candidates.push(history_backup_path(&previous_path));
+    if let Some(language) = LANGUAGE_REGISTRY
+        .iter()
+        .find(|language| language.code.eq_ignore_ascii_case(&lowered))
+    {
+        return Some(language);
     }
-    if let Ok(app_config_path) = app_config_history_file_path(app) {
-        candidates.push(app_config_path.clone());
-        candidates.push(history_backup_path(&app_config_path));
+    if lowered.starts_with("zh-hant") || matches!(lowered.as_str(), "zh-hk" | "zh-mo") {
+        return find_language("zh-TW");
     }
+    let primary = lowered.split('-').next().unwrap_or_default();
+    LANGUAGE_REGISTRY
+        .iter()
+        .find(|language| language_primary_subtag(language.code).eq_ignore_ascii_case(primary))
+}
 
-    let mut candidates = dedupe_paths_keep_order(candidates);
-    candidates.sort_by_key(|path| std::cmp::Reverse(file_modified_epoch_ms(path)));
+fn language_primary_subtag(code: &str) -> &str {
+    code.split(['-', '_']).next().unwrap_or(code)
+}
 
-    for candidate in candidates {
-        if let Ok(text) = read_history_text_with_retry(&candidate) {
-            if let Ok(history) = parse_history_entries(&text, updated_settings.history.max_items) {
-                if !history.is_empty() {
-                    eprintln!(
-                        "[History] recovered {} items from previous storage path {}",
-                        history.len(),
-                        candidate.display()
-                    );
-                    return Some(history);
-                }
-            }
+fn is_same_language(left: &str, right: &str) -> bool {
+    language_primary_subtag(left.trim()).eq_ignore_ascii_case(language_primary_subtag(right.trim()))
+}
+
+fn script_of_char(ch: char) -> Option<Script> {
+    match ch {
+        '\u{3040}'..='\u{30FF}' | '\u{31F0}'..='\u{31FF}' => Some(Script::Kana),
+        '\u{4E00}'..='\u{9FFF}' | '\u{3400}'..='\u{4DBF}' | '\u{F900}'..='\u{FAFF}' => {
+            Some(Script::Han)
+        }
+        '\u{AC00}'..='\u{D7AF}' | '\u{1100}'..='\u{11FF}' | '\u{3130}'..='\u{318F}' => {
+            Some(Script::Hangul)
         }
+        '\u{0400}'..='\u{04FF}' => Some(Script::Cyrillic),
+        '\u{0370}'..='\u{03FF}' => Some(Script::Greek),
+        '\u{0600}'..='\u{06FF}' | '\u{0750}'..='\u{077F}' => Some(Script::Arabic),
+        '\u{0590}'..='\u{05FF}' => Some(Script::Hebrew),
+        '\u{0900}'..='\u{097F}' => Some(Script::Devanagari),
+        '\u{0E00}'..='\u{0E7F}' => Some(Script::Thai),
+        _ if ch.is_alphabetic() && (ch.is_ascii() || ('\u{00C0}'..='\u{024F}').contains(&ch)) => {
+            Some(Script::Latin)
+        }
+        '\u{1E00}'..='\u{1EFF}' => Some(Script::Latin),
+        _ => None,
     }
-
-    None
 }
 
-fn write_text_file_with_backup(
-    path: &Path,
-    payload: &str,
-    backup_path: &Path,
-) -> Result<bool, CommandError> {
-    if path.exists() {
-        if let Ok(existing) = fs::read_to_string(path) {
-            if existing == payload {
-                return Ok(false);
-            }
+/// Detects the language of `text` locally: the dominant script decides most languages, and
+/// Latin-script text is scored against per-language trigram profiles. Returns `None` when the
+/// text has too few letters to tell.
+fn detect_language(text: &str) -> Option<&'static LanguageInfo> {
+    const SAMPLE_CHARS: usize = 2_000;
+    // One ideograph or syllable block carries about as much text as a short Latin word.
+    const BLOCK_SCRIPT_WEIGHT: usize = 3;
+
+    let sample: String = text.chars().take(SAMPLE_CHARS).collect();
+    let mut counts: Vec<(Script, usize)> = Vec::new();
+    for script in sample.chars().filter_map(script_of_char) {
+        match counts.iter_mut().find(|(known, _)| *known == script) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((script, 1)),
         }
     }
-
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent).map_err(|error| CommandError::Settings(error.to_string()))?;
+    let count_of = |script: Script| {
+        counts
+            .iter()
+            .find(|(known, _)| *known == script)
+            .map(|(_, count)| *count)
+            .unwrap_or(0)
+    };
+    let kana = count_of(Script::Kana);
+    let han = count_of(Script::Han);
+    let weighted = |script: Script, count: usize| match script {
+        Script::Han | Script::Kana | Script::Hangul => count * BLOCK_SCRIPT_WEIGHT,
+        _ => count,
+    };
+    let (dominant, dominant_count) = counts
+        .iter()
+        .map(|(script, count)| (*script, weighted(*script, *count)))
+        .max_by_key(|(_, count)| *count)?;
+    if dominant_count < 2 {
+        return None;
     }
 
-    if path.exists() {
-        let _ = fs::copy(path, backup_path);
+    let code = match dominant {
+        // Japanese mixes kana with kanji; any real share of kana outweighs the ideographs.
+        Script::Han | Script::Kana if kana > 0 && kana * 5 >= han => "ja-JP",
+        Script::Han | Script::Kana => {
+            let traditional = sample
+                .chars()
+                .filter(|ch| TRADITIONAL_CHINESE_MARKERS.contains(*ch))
+                .count();
+            let simplified = sample
+                .chars()
+                .filter(|ch| SIMPLIFIED_CHINESE_MARKERS.contains(*ch))
+                .count();
+            if traditional > simplified {
+                "zh-TW"
+            } else {
+                "zh-CN"
+            }
+        }
+        Script::Cyrillic => {
+            if sample.chars().any(|ch| "іїєґІЇЄҐ".contains(ch)) {
+                "uk-UA"
+            } else {
+                "ru-RU"
+            }
+        }
+        Script::Latin => detect_latin_language(&sample),
+        // The remaining scripts are each written by one registry language.
+        script => {
+            return LANGUAGE_REGISTRY
+                .iter()
+                .find(|language| language.script == script)
+        }
+    };
+    find_language(code)
+}
+
+fn detect_latin_language(sample: &str) -> &'static str {
+    let mut normalized = String::with_capacity(sample.len() + 2);
+    normalized.push(' ');
+    let mut last_was_space = true;
+    for ch in sample.chars().flat_map(char::to_lowercase) {
+        if ch.is_alphabetic() {
+            normalized.push(ch);
+            last_was_space = false;
+        } else if !last_was_space {
+            normalized.push(' ');
+            last_was_space = true;
+        }
     }
+    normalized.push(' ');
 
-    let temp_path = path.with_extension("tmp");
-    fs::write(&temp_path, payload).map_err(|error| CommandError::Settings(error.to_string()))?;
+    LATIN_LANGUAGE_PROFILES
+        .iter()
+        .map(|(code, trigrams, letters)| {
+            let trigram_score: usize = trigrams
+                .iter()
+                .map(|trigram| normalized.matches(trigram).count())
+                .sum();
+            let letter_score = normalized
+                .chars()
+                .filter(|ch| letters.contains(*ch))
+                .count();
+            (*code, trigram_score + letter_score * 3)
+        })
+        .filter(|(_, score)| *score > 0)
+        .max_by_key(|(_, score)| *score)
+        .map(|(code, _)| code)
+        .unwrap_or("en-US")
+}
 
-    if path.exists() {
-        let _ = fs::remove_file(path);
+fn preferred_translate_target(settings: &AppSettings) -> String {
+    if settings.selection_assistant.default_translate_to.is_empty() {
+        default_translate_target_for_language(&settings.language).to_string()
+    } else {
+        settings.selection_assistant.default_translate_to.clone()
     }
+}
 
-    match fs::rename(&temp_path, path) {
-        Ok(_) => Ok(true),
-        Err(_) => {
-            fs::write(path, payload).map_err(|error| CommandError::Settings(error.to_string()))?;
-            let _ = fs::remove_file(&temp_path);
-            Ok(true)
+/// Picks the translation target: an explicit request wins; otherwise, with smart targeting,
+/// text in another language goes into the UI language and text already in the UI language goes
+/// to the configured default target.
+fn resolve_translate_target(settings: &AppSettings, requested: Option<&str>, text: &str) -> String {
+    let preferred = preferred_translate_target(settings);
+    if let Some(requested) = requested.filter(|value| !value.trim().is_empty()) {
+        return normalize_translate_language(requested, &preferred);
+    }
+    if !settings.selection_assistant.smart_translate_target {
+        return preferred;
+    }
+    let native = normalize_translate_language(&settings.language, "zh-CN");
+    match detect_language(text) {
+        Some(detected) if !is_same_language(detected.code, &native) => native,
+        Some(_) if is_same_language(&preferred, &native) => {
+            default_translate_target_for_language(&settings.language).to_string()
         }
+        _ => preferred,
     }
 }
 
-fn persist_settings(path: &Path, settings: &AppSettings) -> Result<bool, CommandError> {
-    let payload = serde_json::to_string_pretty(settings)
-        .map_err(|error| CommandError::Serialization(error.to_string()))?;
-    let backup_path = settings_backup_path(path);
-    write_text_file_with_backup(path, &payload, &backup_path)
+fn normalize_tts_voice(value: &str, fallback: &str) -> String {
+    let trimmed = value.trim();
+    if trimmed.is_empty() {
+        fallback.to_string()
+    } else {
+        trimmed.to_string()
+    }
 }
 
-fn persist_history_snapshot<R: Runtime>(
-    app: &AppHandle<R>,
-    settings: &AppSettings,
-    entries: &[ClipboardEntry],
-) -> Result<(), CommandError> {
-    let payload = serde_json::to_string_pretty(entries)
-        .map_err(|error| CommandError::Serialization(error.to_string()))?;
-    let write_snapshot = |path: &Path, payload: &str| -> Result<(), CommandError> {
-        let backup_path = history_backup_path(path);
-        let _ = write_text_file_with_backup(path, payload, &backup_path)?;
-        Ok(())
-    };
-
-    let primary_path = resolve_history_file_path(app, settings)?;
-    let app_config_path = app_config_history_file_path(app)?;
-    let primary_result = write_snapshot(&primary_path, &payload);
-    let mirror_result = if app_config_path != primary_path {
-        write_snapshot(&app_config_path, &payload)
-    } else {
-        Ok(())
-    };
+fn is_cjk_char(ch: char) -> bool {
+    matches!(
+        ch as u32,
+        0x3400..=0x4DBF
+            | 0x4E00..=0x9FFF
+            | 0xF900..=0xFAFF
+            | 0x20000..=0x2A6DF
+            | 0x2A700..=0x2B73F
+            | 0x2B740..=0x2B81F
+            | 0x2B820..=0x2CEAF
+    )
+}
 
-    match (primary_result, mirror_result) {
-        (Ok(_), _) => Ok(()),
-        (Err(primary_error), Ok(_)) => {
-            eprintln!(
-                "[History] primary snapshot write failed, mirrored to app config: {}",
-                primary_error
-            );
-            Ok(())
+fn trim_name_by_units(value: &str, max_units: u32) -> String {
+    let mut result = String::new();
+    let mut used = 0u32;
+    for ch in value.trim().chars() {
+        let weight = if is_cjk_char(ch) { 2 } else { 1 };
+        if used.saturating_add(weight) > max_units {
+            break;
         }
-        (Err(primary_error), Err(_mirror_error)) => Err(primary_error),
+        result.push(ch);
+        used = used.saturating_add(weight);
     }
+    result.trim().to_string()
 }
 
-fn load_history_snapshot<R: Runtime>(
-    app: &AppHandle<R>,
-    settings: &AppSettings,
-    canonical_settings_path: &Path,
-    settings_source_path: &Path,
-) -> Result<HistoryLoadResult, CommandError> {
-    let canonical_history_path = resolve_history_file_path(app, settings)?;
-    let app_config_history_path = app_config_history_file_path(app)?;
-    let mut empty_history_candidate: Option<HistoryLoadResult> = None;
+fn is_builtin_selection_bar_key(value: &str) -> bool {
+    BUILTIN_SELECTION_BAR_KEYS.contains(&value)
+}
 
-    if let Ok(text) = read_history_text_with_retry(&canonical_history_path) {
-        if let Ok(history) = parse_history_entries(&text, settings.history.max_items) {
-            if !history.is_empty() {
-                return Ok(HistoryLoadResult {
-                    history,
-                    source_path: Some(canonical_history_path.clone()),
-                    should_persist: false,
-                });
-            }
-            empty_history_candidate = Some(HistoryLoadResult {
-                history,
-                source_path: Some(canonical_history_path.clone()),
-                should_persist: false,
-            });
-        }
-        eprintln!(
-            "[History] primary snapshot parse failed {}",
-            canonical_history_path.display()
-        );
-    }
+fn parse_custom_selection_bar_key(value: &str) -> Option<&str> {
+    value
+        .strip_prefix("custom:")
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
 
-    let canonical_backup_path = history_backup_path(&canonical_history_path);
-    if let Ok(text) = read_history_text_with_retry(&canonical_backup_path) {
-        if let Ok(history) = parse_history_entries(&text, settings.history.max_items) {
-            if !history.is_empty() {
-                return Ok(HistoryLoadResult {
-                    history,
-                    source_path: Some(canonical_backup_path.clone()),
-                    should_persist: true,
-                });
-            }
-            if empty_history_candidate.is_none() {
-                empty_history_candidate = Some(HistoryLoadResult {
-                    history,
-                    source_path: Some(canonical_backup_path.clone()),
-                    should_persist: true,
-                });
-            }
-        }
-        eprintln!(
-            "[History] backup snapshot parse failed {}",
-            canonical_backup_path.display()
-        );
-    }
+// Writing into the source application is opt-in, so that item starts hidden on the bar.
+fn is_selection_bar_key_enabled_by_default(key: &str) -> bool {
+    key != POLISH_REPLACE_BAR_KEY
+}
 
-    let mut fallback_candidates = Vec::<PathBuf>::new();
-    if app_config_history_path != canonical_history_path {
-        fallback_candidates.push(app_config_history_path.clone());
-        fallback_candidates.push(history_backup_path(&app_config_history_path));
-    }
-    if let (Some(source_dir), Some(canonical_dir)) = (
-        settings_source_path.parent(),
-        canonical_settings_path.parent(),
-    ) {
-        if source_dir != canonical_dir {
-            let source_history_path =
-                resolve_history_path_from_base_dir(&settings.history.storage_path, source_dir);
-            fallback_candidates.push(source_history_path.clone());
-            fallback_candidates.push(history_backup_path(&source_history_path));
+fn builtin_selection_bar_order() -> Vec<SelectionBarItemConfig> {
+    BUILTIN_SELECTION_BAR_KEYS
+        .iter()
+        .enumerate()
+        .map(|(index, key)| SelectionBarItemConfig {
+            key: (*key).to_string(),
+            enabled: is_selection_bar_key_enabled_by_default(key),
+            order: index as u32,
+        })
+        .collect()
+}
+
+fn normalize_selection_bar_order(
+    input: Vec<SelectionBarItemConfig>,
+    custom_agents: &[CustomAgent],
+) -> Vec<SelectionBarItemConfig> {
+    let custom_keys: HashSet<String> = custom_agents
+        .iter()
+        .map(|item| format!("custom:{}", item.id))
+        .collect();
+    let mut seen = HashSet::new();
+    let mut normalized: Vec<SelectionBarItemConfig> = Vec::new();
+
+    let mut sorted = input;
+    sorted.sort_by_key(|item| item.order);
+
+    for item in sorted {
+        let key = item.key.trim().to_string();
+        if key.is_empty() || seen.contains(&key) {
+            continue;
+        }
+        let valid = is_builtin_selection_bar_key(&key)
+            || parse_custom_selection_bar_key(&key)
+                .map(|custom_id| custom_keys.contains(&format!("custom:{}", custom_id)))
+                .unwrap_or(false);
+        if !valid {
+            continue;
         }
+        seen.insert(key.clone());
+        normalized.push(SelectionBarItemConfig {
+            key,
+            enabled: item.enabled,
+            order: normalized.len() as u32,
+        });
     }
 
-    for legacy_dir in discover_legacy_storage_dirs(canonical_settings_path) {
-        let legacy_history_path =
-            resolve_history_path_from_base_dir(&settings.history.storage_path, &legacy_dir);
-        fallback_candidates.push(legacy_history_path.clone());
-        fallback_candidates.push(history_backup_path(&legacy_history_path));
+    for key in BUILTIN_SELECTION_BAR_KEYS {
+        if seen.contains(key) {
+            continue;
+        }
+        seen.insert(key.to_string());
+        normalized.push(SelectionBarItemConfig {
+            key: key.to_string(),
+            enabled: is_selection_bar_key_enabled_by_default(key),
+            order: normalized.len() as u32,
+        });
     }
 
-    let mut fallback_candidates = dedupe_paths_keep_order(fallback_candidates);
-    fallback_candidates.sort_by_key(|path| std::cmp::Reverse(file_modified_epoch_ms(path)));
-
-    for candidate in fallback_candidates {
-        if let Ok(text) = read_history_text_with_retry(&candidate) {
-            if let Ok(history) = parse_history_entries(&text, settings.history.max_items) {
-                if !history.is_empty() {
-                    return Ok(HistoryLoadResult {
-                        history,
-                        source_path: Some(candidate),
-                        should_persist: true,
-                    });
-                }
-                if empty_history_candidate.is_none() {
-                    empty_history_candidate = Some(HistoryLoadResult {
-                        history,
-                        source_path: Some(candidate),
-                        should_persist: true,
-                    });
-                }
-            }
+    for agent in custom_agents {
+        let key = format!("custom:{}", agent.id);
+        if seen.contains(&key) {
+            continue;
         }
+        seen.insert(key.clone());
+        normalized.push(SelectionBarItemConfig {
+            key,
+            enabled: agent.enabled,
+            order: normalized.len() as u32,
+        });
     }
 
-    if let Some(candidate) = empty_history_candidate {
-        return Ok(candidate);
+    let mut enabled_count = 0usize;
+    for item in normalized.iter_mut() {
+        if !item.enabled {
+            continue;
+        }
+        if enabled_count >= MAX_ENABLED_SELECTION_BAR_ITEMS {
+            item.enabled = false;
+            continue;
+        }
+        enabled_count += 1;
     }
 
-    Ok(HistoryLoadResult {
-        history: VecDeque::new(),
-        source_path: None,
-        should_persist: false,
-    })
-}
-
-fn persist_settings_state(settings_state: &AppSettingsState) -> Result<(), CommandError> {
-    let snapshot = settings_state
-        .data
-        .lock()
-        .map_err(|_| CommandError::Lock)?
-        .clone();
-    let _ = persist_settings(&settings_state.file_path, &snapshot)?;
-    Ok(())
+    normalized
 }
 
-fn load_settings(path: &Path) -> SettingsLoadResult {
-    let canonical_path = path.to_path_buf();
-    let canonical_backup = settings_backup_path(path);
-    let mut candidate_paths = vec![canonical_path.clone(), canonical_backup.clone()];
-    for legacy_dir in discover_legacy_storage_dirs(path) {
-        candidate_paths.push(legacy_dir.join(SETTINGS_FILENAME));
-        candidate_paths.push(legacy_dir.join(SETTINGS_BACKUP_FILENAME));
+/// Maps a bar key to the action it runs; copy and search have no model action.
+fn selection_action_for_bar_key(key: &str) -> Option<(SelectionActionKind, Option<String>)> {
+    match key {
+        "summarize" => Some((SelectionActionKind::Summarize, None)),
+        "polish" | POLISH_REPLACE_BAR_KEY => Some((SelectionActionKind::Polish, None)),
+        "explain" => Some((SelectionActionKind::Explain, None)),
+        "translate" => Some((SelectionActionKind::Translate, None)),
+        _ => parse_custom_selection_bar_key(key)
+            .map(|custom_id| (SelectionActionKind::Custom, Some(custom_id.to_string()))),
     }
-    candidate_paths = dedupe_paths_keep_order(candidate_paths);
+}
 
-    // Try canonical file and backup first to avoid restoring stale legacy snapshots.
-    for candidate in [&canonical_path, &canonical_backup] {
-        if let Ok(text) = read_settings_text_with_retry(candidate) {
-            match parse_settings_from_text(&text) {
-                Ok((settings, repaired)) => {
-                    return SettingsLoadResult {
-                        settings,
-                        source_path: candidate.clone(),
-                        should_persist: repaired || candidate.as_path() != canonical_path.as_path(),
-                    };
-                }
-                Err(error) => {
-                    eprintln!("[Settings] parse failed {}: {}", candidate.display(), error);
-                }
-            }
+/// The bar keys to show, taking a matching app rule's items over the enabled global order.
+fn selection_bar_keys(settings: &AppSettings, rule: Option<&AppSelectionRule>) -> Vec<String> {
+    let order =
+        normalize_selection_bar_order(settings.agents.bar_order.clone(), &settings.agents.custom);
+    if let Some(rule) = rule.filter(|rule| !rule.bar_items.is_empty()) {
+        let available: HashSet<&str> = order.iter().map(|item| item.key.as_str()).collect();
+        let keys: Vec<String> = rule
+            .bar_items
+            .iter()
+            .filter(|key| available.contains(key.as_str()))
+            .take(MAX_ENABLED_SELECTION_BAR_ITEMS)
+            .cloned()
+            .collect();
+        if !keys.is_empty() {
+            return keys;
         }
     }
-
-    let mut legacy_candidates = candidate_paths
+    order
         .into_iter()
-        .filter(|candidate| candidate != &canonical_path && candidate != &canonical_backup)
-        .collect::<Vec<_>>();
-    legacy_candidates.sort_by_key(|candidate| std::cmp::Reverse(file_modified_epoch_ms(candidate)));
+        .filter(|item| item.enabled)
+        .map(|item| item.key)
+        .collect()
+}
 
-    for candidate in legacy_candidates {
-        if let Ok(text) = read_settings_text_with_retry(&candidate) {
-            match parse_settings_from_text(&text) {
-                Ok((settings, _repaired)) => {
-                    return SettingsLoadResult {
-                        settings,
-                        source_path: candidate,
-                        should_persist: true,
-                    };
-                }
-                Err(error) => {
-                    eprintln!("[Settings] parse failed {}: {}", candidate.display(), error);
-                }
-            }
+/// Content types recognized by [`classify_selection_content`], in suggestion priority order.
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum SelectionContentKind {
+    StackTrace,
+    Json,
+    Code,
+    Url,
+    Math,
+    ForeignLanguage,
+    LongParagraph,
+}
+
+impl SelectionContentKind {
+    fn suggested_bar_key(self) -> &'static str {
+        match self {
+            Self::StackTrace | Self::Json | Self::Code | Self::Math => "explain",
+            Self::Url => "copy",
+            Self::ForeignLanguage => "translate",
+            Self::LongParagraph => "summarize",
         }
     }
-
-    SettingsLoadResult {
-        settings: AppSettings::default(),
-        source_path: canonical_path,
-        should_persist: true,
-    }
 }
 
-fn emit_settings_updated<R: Runtime>(app: &AppHandle<R>, settings: &AppSettings) {
-    let _ = app.emit(SETTINGS_UPDATED_EVENT, settings.clone());
+fn is_stack_frame_line(line: &str) -> bool {
+    let trimmed = line.trim_start();
+    (trimmed.starts_with("at ") && (trimmed.contains('(') || trimmed.contains(':')))
+        || (trimmed.starts_with("File \"") && trimmed.contains("\", line "))
+        || (trimmed.split_once(": ").is_some_and(|(index, rest)| {
+            !index.is_empty() && index.chars().all(|ch| ch.is_ascii_digit()) && rest.contains("::")
+        }))
 }
 
-#[cfg(target_os = "windows")]
-fn capture_last_foreground_window(flags: &RuntimeFlags) {
-    let hwnd = unsafe { GetForegroundWindow() };
-    if !hwnd.is_null() {
-        flags
-            .last_foreground_hwnd
-            .store(hwnd as isize, Ordering::Relaxed);
+fn is_stack_trace_header(line: &str) -> bool {
+    let trimmed = line.trim();
+    trimmed.starts_with("Traceback (most recent call last)")
+        || trimmed.starts_with("Exception in thread ")
+        || trimmed.starts_with("Caused by:")
+        || trimmed.contains("panicked at ")
+        || trimmed.starts_with("Unhandled exception")
+}
+
+fn is_code_line(line: &str) -> bool {
+    const KEYWORD_PREFIXES: [&str; 22] = [
+        "fn ",
+        "pub ",
+        "let ",
+        "const ",
+        "var ",
+        "def ",
+        "class ",
+        "import ",
+        "from ",
+        "return ",
+        "function ",
+        "if (",
+        "for (",
+        "while (",
+        "#include",
+        "package ",
+        "using ",
+        "struct ",
+        "impl ",
+        "public ",
+        "private ",
+        "async ",
+    ];
+    let trimmed = line.trim();
+    if trimmed.is_empty() {
+        return false;
     }
+    trimmed.ends_with(';')
+        || trimmed.ends_with('{')
+        || trimmed == "}"
+        || trimmed.starts_with("//")
+        || trimmed.contains(" => ")
+        || trimmed.contains("();")
+        || KEYWORD_PREFIXES
+            .iter()
+            .any(|prefix| trimmed.starts_with(prefix))
 }
 
-#[cfg(not(target_os = "windows"))]
-fn capture_last_foreground_window(_flags: &RuntimeFlags) {}
+fn looks_like_url(text: &str) -> bool {
+    let trimmed = text.trim();
+    !trimmed.chars().any(char::is_whitespace)
+        && ["http://", "https://", "www."]
+            .iter()
+            .any(|prefix| trimmed.to_ascii_lowercase().starts_with(prefix))
+        && trimmed.len() > 8
+}
 
-#[cfg(target_os = "windows")]
-fn restore_foreground_window(hwnd_raw: isize) -> bool {
-    if hwnd_raw == 0 {
-        return false;
+fn looks_like_math(text: &str) -> bool {
+    const LATEX_MARKERS: [&str; 6] = ["\\frac", "\\sum", "\\int", "\\sqrt", "$$", "\\begin{"];
+    if LATEX_MARKERS.iter().any(|marker| text.contains(marker))
+        || text.chars().any(|ch| "∑∫√≤≥≠±×÷∞∂∇".contains(ch))
+    {
+        return true;
     }
-
-    let hwnd = hwnd_raw as HWND;
-    if hwnd.is_null() {
+    let visible: Vec<char> = text.chars().filter(|ch| !ch.is_whitespace()).collect();
+    if visible.len() < 3 || visible.len() > 200 {
         return false;
     }
+    let has_digit = visible.iter().any(char::is_ascii_digit);
+    // A bare hyphen is too common in dates and identifiers to count as subtraction.
+    let has_operator = visible.iter().any(|ch| "+*/^=".contains(*ch)) || text.contains(" - ");
+    let mathy = visible
+        .iter()
+        .filter(|ch| ch.is_ascii_digit() || "+-*/^=().,%xyπ".contains(**ch))
+        .count();
+    has_digit && has_operator && mathy * 10 >= visible.len() * 8
+}
 
-    if unsafe { IsWindow(hwnd) } == 0 {
-        return false;
+/// Rule-based content classification for the selection bar. Line rules only scan a leading
+/// sample; JSON is confirmed with a single parse of the whole text.
+fn classify_selection_content(settings: &AppSettings, text: &str) -> Vec<SelectionContentKind> {
+    let trimmed = text.trim();
+    let mut kinds = Vec::new();
+    if trimmed.is_empty() {
+        return kinds;
     }
+    let sample: String = trimmed
+        .chars()
+        .take(CONTENT_CLASSIFIER_SAMPLE_CHARS)
+        .collect();
+    let lines: Vec<&str> = sample
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect();
 
-    let foreground = unsafe { GetForegroundWindow() };
-    if foreground == hwnd {
-        return true;
+    let frame_lines = lines
+        .iter()
+        .filter(|line| is_stack_frame_line(line))
+        .count();
+    if frame_lines >= 2
+        || (frame_lines >= 1 && lines.iter().any(|line| is_stack_trace_header(line)))
+    {
+        kinds.push(SelectionContentKind::StackTrace);
     }
-
-    unsafe {
-        if IsIconic(hwnd) != 0 {
-            ShowWindow(hwnd, SW_RESTORE);
-        }
-        SetForegroundWindow(hwnd) != 0
+    let is_json = (trimmed.starts_with('{') && trimmed.ends_with('}'))
+        || (trimmed.starts_with('[') && trimmed.ends_with(']'));
+    if is_json && serde_json::from_str::<serde_json::Value>(trimmed).is_ok() {
+        kinds.push(SelectionContentKind::Json);
     }
-}
-
-#[cfg(not(target_os = "windows"))]
-fn restore_foreground_window(_hwnd_raw: isize) -> bool {
-    false
-}
-
-#[cfg(target_os = "windows")]
-fn keyboard_input(vk: u16, key_up: bool) -> INPUT {
-    INPUT {
-        r#type: INPUT_KEYBOARD,
-        Anonymous: INPUT_0 {
-            ki: KEYBDINPUT {
-                wVk: vk,
-                wScan: 0,
-                dwFlags: if key_up { KEYEVENTF_KEYUP } else { 0 },
-                time: 0,
-                dwExtraInfo: 0,
-            },
-        },
+    let code_lines = lines.iter().filter(|line| is_code_line(line)).count();
+    if kinds.is_empty() && code_lines >= 2 && code_lines * 5 >= lines.len() * 2 {
+        kinds.push(SelectionContentKind::Code);
     }
-}
-
-#[cfg(target_os = "windows")]
-fn send_system_paste_shortcut(hwnd_raw: isize) -> Result<(), CommandError> {
-    const VK_VIRTUAL_V: u16 = 0x56;
-
-    let _ = restore_foreground_window(hwnd_raw);
-    std::thread::sleep(Duration::from_millis(18));
-
-    let mut inputs = [
-        keyboard_input(VK_CONTROL, false),
-        keyboard_input(VK_VIRTUAL_V, false),
-        keyboard_input(VK_VIRTUAL_V, true),
-        keyboard_input(VK_CONTROL, true),
-    ];
-
-    let sent = unsafe {
-        SendInput(
-            inputs.len() as u32,
-            inputs.as_mut_ptr(),
-            std::mem::size_of::<INPUT>() as i32,
-        )
-    };
-
-    if sent == inputs.len() as u32 {
-        Ok(())
-    } else {
-        Err(CommandError::Settings(
-            "Failed to dispatch Ctrl+V shortcut".to_string(),
-        ))
+    if looks_like_url(trimmed) {
+        kinds.push(SelectionContentKind::Url);
     }
-}
-
-#[cfg(not(target_os = "windows"))]
-fn send_system_paste_shortcut(_hwnd_raw: isize) -> Result<(), CommandError> {
-    Ok(())
-}
-
-#[cfg(target_os = "windows")]
-fn send_system_copy_shortcut(hwnd_raw: isize) -> Result<(), CommandError> {
-    const VK_VIRTUAL_C: u16 = 0x43;
-    const VK_VIRTUAL_INSERT: u16 = VK_INSERT;
-    const VK_VIRTUAL_SHIFT: u16 = VK_SHIFT;
-
-    if hwnd_raw == 0 {
-        return Err(CommandError::Settings(
-            "No foreground window available for copy shortcut".to_string(),
-        ));
+    if kinds.is_empty() && looks_like_math(&sample) {
+        kinds.push(SelectionContentKind::Math);
     }
-
-    let _ = restore_foreground_window(hwnd_raw);
-    std::thread::sleep(Duration::from_millis(16));
-
-    let send = |inputs: &mut [INPUT]| -> bool {
-        let sent = unsafe {
-            SendInput(
-                inputs.len() as u32,
-                inputs.as_mut_ptr(),
-                std::mem::size_of::<INPUT>() as i32,
-            )
-        };
-        sent == inputs.len() as u32
-    };
-
-    if is_windows_terminal_window(hwnd_raw) {
-        let mut primary = [
-            keyboard_input(VK_CONTROL, false),
-            keyboard_input(VK_VIRTUAL_SHIFT, false),
-            keyboard_input(VK_VIRTUAL_C, false),
-            keyboard_input(VK_VIRTUAL_C, true),
-            keyboard_input(VK_VIRTUAL_SHIFT, true),
-            keyboard_input(VK_CONTROL, true),
-        ];
-        let primary_sent = send(&mut primary);
-        std::thread::sleep(Duration::from_millis(10));
-        let mut fallback = [
-            keyboard_input(VK_CONTROL, false),
-            keyboard_input(VK_VIRTUAL_INSERT, false),
-            keyboard_input(VK_VIRTUAL_INSERT, true),
-            keyboard_input(VK_CONTROL, true),
-        ];
-        let fallback_sent = send(&mut fallback);
-        if primary_sent || fallback_sent {
-            return Ok(());
+    if kinds.is_empty() {
+        let native = normalize_translate_language(&settings.language, "zh-CN");
+        if detect_language(&sample)
+            .is_some_and(|language| !is_same_language(language.code, &native))
+        {
+            kinds.push(SelectionContentKind::ForeignLanguage);
         }
-        return Err(CommandError::Settings(
-            "Failed to dispatch terminal copy shortcut".to_string(),
-        ));
-    }
-
-    if is_console_like_window(hwnd_raw) {
-        let mut fallback = [
-            keyboard_input(VK_CONTROL, false),
-            keyboard_input(VK_VIRTUAL_INSERT, false),
-            keyboard_input(VK_VIRTUAL_INSERT, true),
-            keyboard_input(VK_CONTROL, true),
-        ];
-        if send(&mut fallback) {
-            return Ok(());
+        if trimmed.chars().count() >= LONG_PARAGRAPH_MIN_CHARS {
+            kinds.push(SelectionContentKind::LongParagraph);
         }
-        return Err(CommandError::Settings(
-            "Failed to dispatch console copy shortcut".to_string(),
-        ));
-    }
-
-    let mut inputs = [
-        keyboard_input(VK_CONTROL, false),
-        keyboard_input(VK_VIRTUAL_C, false),
-        keyboard_input(VK_VIRTUAL_C, true),
-        keyboard_input(VK_CONTROL, true),
-    ];
-    if send(&mut inputs) {
-        Ok(())
-    } else {
-        Err(CommandError::Settings(
-            "Failed to dispatch Ctrl+C shortcut".to_string(),
-        ))
     }
+    kinds
 }
 
-#[cfg(not(target_os = "windows"))]
-fn send_system_copy_shortcut(_hwnd_raw: isize) -> Result<(), CommandError> {
-    Ok(())
-}
-
-#[cfg(target_os = "windows")]
-fn is_terminal_host_process(hwnd_raw: isize) -> bool {
-    let (process_name, process_path) = window_process_identity(hwnd_raw);
-    let Some(name) = process_name else {
+/// Whether an app rule's auto-run fires for `text`: the selection has to be in the rule's source
+/// language when one is set, and a translation is skipped when the text is already in the target
+/// language so the bar is shown instead.
+fn app_rule_auto_run_applies(
+    settings: &AppSettings,
+    rule: &AppSelectionRule,
+    action: SelectionActionKind,
+    text: &str,
+) -> bool {
+    let detected = detect_language(text);
+    let is_detected =
+        |code: &str| detected.is_some_and(|language| is_same_language(language.code, code));
+    if !rule.translate_from.is_empty() && !is_detected(&rule.translate_from) {
         return false;
-    };
-
-    const TERMINAL_HOST_PROCESSES: &[&str] = &[
-        "windowsterminal.exe",
-        "wt.exe",
-        "powershell.exe",
-        "pwsh.exe",
-        "cmd.exe",
-        "conhost.exe",
-        "wezterm.exe",
-        "wezterm-gui.exe",
-        "mintty.exe",
-        "alacritty.exe",
-        "tabby.exe",
-        "hyper.exe",
-        "conemu.exe",
-        "conemu64.exe",
-        "cmder.exe",
-        "code.exe",
-        "code-insiders.exe",
-        "cursor.exe",
-        "vscodium.exe",
-        "windsurf.exe",
-    ];
-
-    if TERMINAL_HOST_PROCESSES
-        .iter()
-        .any(|candidate| name == *candidate)
-    {
+    }
+    if !matches!(action, SelectionActionKind::Translate) {
         return true;
     }
-
-    process_path
-        .as_deref()
-        .is_some_and(|path| path.contains("\\windowsapps\\wt.exe"))
-}
-
-#[cfg(not(target_os = "windows"))]
-fn is_terminal_host_process(_hwnd_raw: isize) -> bool {
-    false
+    let target = resolve_translate_target(settings, Some(&rule.translate_to), text);
+    !is_detected(&target)
 }
 
-#[cfg(target_os = "windows")]
-fn is_console_like_window(hwnd_raw: isize) -> bool {
-    let class_like = window_class_name(hwnd_raw)
-        .map(|class_name| {
-            class_name.contains("CONSOLEWINDOWCLASS")
-                || class_name.contains("CASCADIA_HOSTING_WINDOW_CLASS")
-                || class_name.contains("PSEUDOCONSOLEWINDOW")
-                || class_name.contains("VIRTUALCONSOLECLASS")
-                || class_name.contains("MINTTY")
-                || class_name.contains("WEZTERM")
-                || class_name.contains("ALACRITTY")
-        })
-        .unwrap_or(false);
-
-    class_like || is_terminal_host_process(hwnd_raw)
+/// Builds the bar payload: app rule items first, then content suggestions moved to the front
+/// unless the rule fixes the order itself.
+fn selection_detected_payload(
+    settings: &AppSettings,
+    text: String,
+    pointer: PhysicalPosition<i32>,
+    mode: SelectionTriggerMode,
+    rule: Option<&AppSelectionRule>,
+) -> SelectionDetectedPayload {
+    let mut bar_items = selection_bar_keys(settings, rule);
+    let mut content_kinds = Vec::new();
+    let mut suggested_items: Vec<String> = Vec::new();
+    if settings.selection_assistant.smart_bar_suggestions {
+        content_kinds = classify_selection_content(settings, &text);
+        for kind in &content_kinds {
+            let key = kind.suggested_bar_key();
+            if bar_items.iter().any(|item| item == key)
+                && !suggested_items.iter().any(|item| item == key)
+            {
+                suggested_items.push(key.to_string());
+            }
+        }
+        let rule_orders_bar = rule.is_some_and(|rule| !rule.bar_items.is_empty());
+        if !rule_orders_bar && !suggested_items.is_empty() {
+            bar_items.retain(|item| !suggested_items.contains(item));
+            bar_items.splice(0..0, suggested_items.iter().cloned());
+        }
+    }
+    SelectionDetectedPayload {
+        text,
+        x: pointer.x,
+        y: pointer.y,
+        mode,
+        bar_items,
+        translate_from: rule
+            .map(|rule| rule.translate_from.clone())
+            .filter(|value| !value.is_empty()),
+        translate_to: rule
+            .map(|rule| rule.translate_to.clone())
+            .filter(|value| !value.is_empty()),
+        content_kinds,
+        suggested_items,
+    }
 }
 
-#[cfg(target_os = "windows")]
-fn is_windows_terminal_window(hwnd_raw: isize) -> bool {
-    let class_like = window_class_name(hwnd_raw)
-        .map(|class_name| {
-            class_name.contains("CASCADIA_HOSTING_WINDOW_CLASS")
-                || class_name.contains("PSEUDOCONSOLEWINDOW")
-        })
-        .unwrap_or(false);
-
-    if class_like {
-        return true;
+fn clamp_f32(value: f32, min: f32, max: f32) -> f32 {
+    if !value.is_finite() {
+        return min;
     }
-
-    let (process_name, _) = window_process_identity(hwnd_raw);
-    process_name
-        .as_deref()
-        .is_some_and(|name| name == "windowsterminal.exe" || name == "wt.exe")
+    value.clamp(min, max)
 }
 
-#[cfg(not(target_os = "windows"))]
-fn is_console_like_window(_hwnd_raw: isize) -> bool {
-    false
+fn normalize_hex_color(input: &str, fallback: &str) -> String {
+    let trimmed = input.trim();
+    let hex = trimmed.strip_prefix('#').unwrap_or(trimmed);
+    if hex.len() != 6 || !hex.chars().all(|ch| ch.is_ascii_hexdigit()) {
+        return fallback.to_string();
+    }
+    format!("#{}", hex.to_ascii_uppercase())
 }
 
-#[cfg(not(target_os = "windows"))]
-fn is_windows_terminal_window(_hwnd_raw: isize) -> bool {
-    false
+fn normalize_usage_settings(usage: &mut UsageSettings) {
+    usage.monthly_budget_usd = if usage.monthly_budget_usd.is_finite() {
+        usage.monthly_budget_usd.clamp(0.0, MAX_MONTHLY_BUDGET_USD)
+    } else {
+        0.0
+    };
+    let mut seen = HashSet::new();
+    usage.price_overrides.retain_mut(|item| {
+        item.model = item.model.trim().to_string();
+        if !item.input_per_million_usd.is_finite() || item.input_per_million_usd < 0.0 {
+            item.input_per_million_usd = 0.0;
+        }
+        if !item.output_per_million_usd.is_finite() || item.output_per_million_usd < 0.0 {
+            item.output_per_million_usd = 0.0;
+        }
+        !item.model.is_empty() && seen.insert(item.model.to_ascii_lowercase())
+    });
+    usage.price_overrides.truncate(MAX_USAGE_PRICE_OVERRIDES);
 }
 
-#[cfg(target_os = "windows")]
-fn send_console_copy_shortcut(hwnd_raw: isize) -> Result<(), CommandError> {
-    const VK_VIRTUAL_INSERT: u16 = VK_INSERT;
-
-    if hwnd_raw == 0 {
-        return Err(CommandError::Settings(
-            "No foreground window available for console copy shortcut".to_string(),
-        ));
-    }
-
-    let _ = restore_foreground_window(hwnd_raw);
-    std::thread::sleep(Duration::from_millis(12));
-
-    let send = |inputs: &mut [INPUT]| -> bool {
-        let sent = unsafe {
-            SendInput(
-                inputs.len() as u32,
-                inputs.as_mut_ptr(),
-                std::mem::size_of::<INPUT>() as i32,
-            )
-        };
-        sent == inputs.len() as u32
-    };
+fn normalize_settings(settings: &mut AppSettings) {
+    let previous_version = settings.version;
+    settings.version = SETTINGS_VERSION;
+    settings.language = normalize_language(&settings.language);
+    settings.shortcuts.toggle_main =
+        normalize_shortcut(&settings.shortcuts.toggle_main, DEFAULT_TOGGLE_SHORTCUT);
+    settings.shortcuts.toggle_ocr =
+        normalize_shortcut(&settings.shortcuts.toggle_ocr, DEFAULT_TOGGLE_OCR_SHORTCUT);
+    settings.history.poll_ms = clamp_poll_ms(settings.history.poll_ms);
+    settings.history.max_items = clamp_history_items(settings.history.max_items);
+    settings.history.storage_path = settings.history.storage_path.trim().to_string();
+    settings.response_cache.max_size_mb = settings
+        .response_cache
+        .max_size_mb
+        .clamp(MIN_RESPONSE_CACHE_SIZE_MB, MAX_RESPONSE_CACHE_SIZE_MB);
+    settings.response_cache.ttl_hours = settings
+        .response_cache
+        .ttl_hours
+        .clamp(MIN_RESPONSE_CACHE_TTL_HOURS, MAX_RESPONSE_CACHE_TTL_HOURS);
+    normalize_usage_settings(&mut settings.usage);
+    normalize_network_settings(&mut settings.network);
+    normalize_glossary_settings(&mut settings.glossary);
+    normalize_extra_headers(&mut settings.llm.extra_headers);
+    normalize_llm_fallback_profiles(&mut settings.llm.fallback_profiles);
+    if settings.llm.chunk_tokens != 0 {
+        settings.llm.chunk_tokens = settings
+            .llm
+            .chunk_tokens
+            .clamp(MIN_CHUNK_TOKENS, MAX_CHUNK_TOKENS);
+    }
+    settings.llm.context_tokens = settings
+        .llm
+        .context_tokens
+        .clamp(MIN_CONTEXT_TOKENS, MAX_CONTEXT_TOKENS);
+    normalize_extra_headers(&mut settings.ocr.vision.extra_headers);
+    settings.theme_preset = ThemePreset::Dark;
+    settings.appearance.blur_px = clamp_f32(settings.appearance.blur_px, 0.0, 36.0).round();
+    settings.appearance.saturate_percent =
+        clamp_f32(settings.appearance.saturate_percent, 60.0, 220.0).round();
+    settings.appearance.window_opacity = clamp_f32(settings.appearance.window_opacity, 0.0, 0.5);
+    settings.appearance.surface_opacity = 0.15;
+    settings.appearance.card_opacity = 0.15;
+    settings.appearance.border_opacity = clamp_f32(settings.appearance.border_opacity, 0.02, 0.45);
+    settings.appearance.shadow_opacity = clamp_f32(settings.appearance.shadow_opacity, 0.0, 1.5);
+    settings.appearance.corner_radius =
+        clamp_f32(settings.appearance.corner_radius, 4.0, 16.0).round();
+    settings.appearance.font_scale = clamp_f32(settings.appearance.font_scale, 0.85, 1.25);
+    settings.appearance.accent_color =
+        normalize_hex_color(&settings.appearance.accent_color, "#8E8E8E");
+    settings.appearance.text_color =
+        normalize_hex_color(&settings.appearance.text_color, "#F5F5F5");
+    settings.appearance.text_muted_color =
+        normalize_hex_color(&settings.appearance.text_muted_color, "#C8C8C8");
 
-    let mut copy_insert = [
-        keyboard_input(VK_CONTROL, false),
-        keyboard_input(VK_VIRTUAL_INSERT, false),
-        keyboard_input(VK_VIRTUAL_INSERT, true),
-        keyboard_input(VK_CONTROL, true),
-    ];
-    if send(&mut copy_insert) {
-        return Ok(());
+    let mut width = settings
+        .main_window_width
+        .unwrap_or(DEFAULT_MAIN_WINDOW_WIDTH);
+    let mut height = settings
+        .main_window_height
+        .unwrap_or(DEFAULT_MAIN_WINDOW_HEIGHT);
+    if previous_version < WINDOW_LAYOUT_MIGRATION_VERSION
+        || (width == 420 && height == 720)
+        || (width == 380 && height == 620)
+        || (width == 340 && height == 560)
+        || width >= 900
+        || height >= 1300
+    {
+        width = DEFAULT_MAIN_WINDOW_WIDTH;
+        height = DEFAULT_MAIN_WINDOW_HEIGHT;
     }
-
-    Err(CommandError::Settings(
-        "Failed to dispatch safe console copy shortcut".to_string(),
-    ))
-}
-
-#[cfg(not(target_os = "windows"))]
-fn send_console_copy_shortcut(_hwnd_raw: isize) -> Result<(), CommandError> {
-    Ok(())
-}
-
-#[cfg(target_os = "windows")]
-fn window_class_name(hwnd_raw: isize) -> Option<String> {
-    let hwnd = hwnd_raw as HWND;
-    if hwnd_raw == 0 || hwnd.is_null() || unsafe { IsWindow(hwnd) } == 0 {
-        return None;
+    settings.main_window_width = Some(clamp_main_window_width(width));
+    settings.main_window_height = Some(clamp_main_window_height(height));
+    if previous_version < WINDOW_LAYOUT_MIGRATION_VERSION {
+        settings.main_window_x = None;
+        settings.main_window_y = None;
     }
 
-    let mut buffer = [0u16; 128];
-    let class_len = unsafe { GetClassNameW(hwnd, buffer.as_mut_ptr(), buffer.len() as i32) };
-    if class_len <= 0 {
-        return None;
+    let mut selection_result_width = settings
+        .selection_result_window_width
+        .unwrap_or(DEFAULT_SELECTION_RESULT_WINDOW_WIDTH);
+    let mut selection_result_height = settings
+        .selection_result_window_height
+        .unwrap_or(DEFAULT_SELECTION_RESULT_WINDOW_HEIGHT);
+    if previous_version < WINDOW_LAYOUT_MIGRATION_VERSION {
+        selection_result_width = DEFAULT_SELECTION_RESULT_WINDOW_WIDTH;
+        selection_result_height = DEFAULT_SELECTION_RESULT_WINDOW_HEIGHT;
     }
-
-    Some(String::from_utf16_lossy(&buffer[..class_len as usize]).to_ascii_uppercase())
-}
-
-#[cfg(target_os = "windows")]
-fn window_process_identity(hwnd_raw: isize) -> (Option<String>, Option<String>) {
-    let hwnd = hwnd_raw as HWND;
-    if hwnd_raw == 0 || hwnd.is_null() || unsafe { IsWindow(hwnd) } == 0 {
-        return (None, None);
+    settings.selection_result_window_width =
+        Some(clamp_selection_result_window_width(selection_result_width));
+    settings.selection_result_window_height = Some(clamp_selection_result_window_height(
+        selection_result_height,
+    ));
+    if previous_version < WINDOW_LAYOUT_MIGRATION_VERSION {
+        settings.selection_result_window_x = None;
+        settings.selection_result_window_y = None;
     }
 
-    let mut process_id = 0u32;
-    unsafe {
-        GetWindowThreadProcessId(hwnd, &mut process_id as *mut u32);
+    let mut ocr_result_width = settings
+        .ocr_result_window_width
+        .unwrap_or(DEFAULT_OCR_RESULT_WINDOW_WIDTH);
+    let mut ocr_result_height = settings
+        .ocr_result_window_height
+        .unwrap_or(DEFAULT_OCR_RESULT_WINDOW_HEIGHT);
+    if previous_version < WINDOW_LAYOUT_MIGRATION_VERSION {
+        ocr_result_width = DEFAULT_OCR_RESULT_WINDOW_WIDTH;
+        ocr_result_height = DEFAULT_OCR_RESULT_WINDOW_HEIGHT;
+    } else if ocr_result_width == 380 && ocr_result_height == 520 {
+        // Migrate legacy default OCR result size to the new, more compact default.
+        ocr_result_width = DEFAULT_OCR_RESULT_WINDOW_WIDTH;
+        ocr_result_height = DEFAULT_OCR_RESULT_WINDOW_HEIGHT;
     }
-    if process_id == 0 {
-        return (None, None);
+    settings.ocr_result_window_width = Some(clamp_ocr_result_window_width(ocr_result_width));
+    settings.ocr_result_window_height = Some(clamp_ocr_result_window_height(ocr_result_height));
+    if previous_version < WINDOW_LAYOUT_MIGRATION_VERSION {
+        settings.ocr_result_window_x = None;
+        settings.ocr_result_window_y = None;
     }
 
-    let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, process_id) };
-    if handle.is_null() {
-        return (None, None);
+    if previous_version < SETTINGS_VERSION {
+        settings.selection_assistant.enabled = true;
+        settings.ocr.enabled = true;
+        if previous_version < 9 {
+            let legacy_remember_position = settings.window.remember_position;
+            settings.selection_assistant.remember_result_window_position = legacy_remember_position;
+            settings.ocr.remember_result_window_position = legacy_remember_position;
+            settings.history.promote_after_paste = true;
+            settings.history.open_at_top_on_show = true;
+        }
     }
 
-    let mut buffer = vec![0u16; 2048];
-    let mut length = buffer.len() as u32;
-    let success =
-        unsafe { QueryFullProcessImageNameW(handle, 0, buffer.as_mut_ptr(), &mut length) } != 0;
-    unsafe {
-        CloseHandle(handle);
+    settings.selection_assistant.bar_opacity = clamp_f32(
+        settings.selection_assistant.bar_opacity,
+        MIN_SELECTION_BAR_OPACITY,
+        MAX_SELECTION_BAR_OPACITY,
+    );
+    settings.selection_assistant.min_chars =
+        clamp_selection_min_chars(settings.selection_assistant.min_chars);
+    settings.selection_assistant.max_chars =
+        clamp_selection_max_chars(settings.selection_assistant.max_chars);
+    if settings.selection_assistant.max_chars < settings.selection_assistant.min_chars {
+        settings.selection_assistant.max_chars = settings.selection_assistant.min_chars.max(128);
     }
+    let search_template = settings.selection_assistant.search_url_template.trim();
+    settings.selection_assistant.search_url_template = if search_template.is_empty() {
+        "https://www.google.com/search?q={query}".to_string()
+    } else {
+        search_template.to_string()
+    };
+    let blocked_apps = std::mem::take(&mut settings.selection_assistant.blocked_apps);
+    settings.selection_assistant.blocked_apps = blocked_apps
+        .into_iter()
+        .map(|item| item.trim().to_ascii_lowercase())
+        .filter(|item| !item.is_empty())
+        .take(64)
+        .collect();
+    let default_translate_fallback = default_translate_target_for_language(&settings.language);
+    settings.selection_assistant.default_translate_to = normalize_translate_language(
+        &settings.selection_assistant.default_translate_to,
+        default_translate_fallback,
+    );
 
-    if !success || length == 0 {
-        return (None, None);
+    settings.llm.base_url = settings.llm.base_url.trim().to_string();
+    if settings.llm.base_url.is_empty() {
+        settings.llm.base_url = "https://api.openai.com/v1/chat/completions".to_string();
+    }
+    settings.llm.enabled = true;
+    settings.llm.model = settings.llm.model.trim().to_string();
+    if settings.llm.model.is_empty() {
+        settings.llm.model = "gpt-4o-mini".to_string();
     }
+    settings.llm.temperature = settings.llm.temperature.clamp(0.0, 2.0);
+    settings.llm.max_tokens = settings.llm.max_tokens.clamp(128, 8192);
+    settings.llm.timeout_ms = settings.llm.timeout_ms.clamp(5_000, 120_000);
+    settings.llm.requests_per_minute = settings
+        .llm
+        .requests_per_minute
+        .min(MAX_MODEL_REQUESTS_PER_MINUTE);
+    settings.llm.tokens_per_minute = settings
+        .llm
+        .tokens_per_minute
+        .min(MAX_MODEL_TOKENS_PER_MINUTE);
 
-    let full_path = String::from_utf16_lossy(&buffer[..length as usize]).to_ascii_lowercase();
-    let executable = Path::new(&full_path)
-        .file_name()
-        .map(|value| value.to_string_lossy().to_ascii_lowercase());
-    (executable, Some(full_path))
-}
+    settings.tts.voice_zh_cn =
+        normalize_tts_voice(&settings.tts.voice_zh_cn, DEFAULT_TTS_VOICE_ZH_CN);
+    settings.tts.voice_en_us =
+        normalize_tts_voice(&settings.tts.voice_en_us, DEFAULT_TTS_VOICE_EN_US);
+    settings.tts.rate_percent = settings
+        .tts
+        .rate_percent
+        .clamp(MIN_TTS_RATE_PERCENT, MAX_TTS_RATE_PERCENT);
 
-#[cfg(target_os = "windows")]
-fn is_window_blocked_by_apps(hwnd_raw: isize, blocked_apps: &[String]) -> bool {
-    if blocked_apps.is_empty() || hwnd_raw == 0 {
-        return false;
+    settings.ocr.custom_agent_id = settings.ocr.custom_agent_id.trim().to_string();
+    settings.ocr.vision.api_key = settings.ocr.vision.api_key.trim().to_string();
+    settings.ocr.vision.base_url = settings.ocr.vision.base_url.trim().to_string();
+    if settings.ocr.vision.base_url.is_empty() {
+        settings.ocr.vision.base_url = "https://api.openai.com/v1/chat/completions".to_string();
+    }
+    settings.ocr.vision.model = settings.ocr.vision.model.trim().to_string();
+    if settings.ocr.vision.model.is_empty() {
+        settings.ocr.vision.model = "gpt-4o-mini".to_string();
+    }
+    settings.ocr.vision.temperature = settings.ocr.vision.temperature.clamp(0.0, 2.0);
+    settings.ocr.vision.max_tokens = settings.ocr.vision.max_tokens.clamp(256, 8192);
+    settings.ocr.vision.timeout_ms = settings.ocr.vision.timeout_ms.clamp(5_000, 120_000);
+    settings.ocr.vision.requests_per_minute = settings
+        .ocr
+        .vision
+        .requests_per_minute
+        .min(MAX_MODEL_REQUESTS_PER_MINUTE);
+    settings.ocr.vision.tokens_per_minute = settings
+        .ocr
+        .vision
+        .tokens_per_minute
+        .min(MAX_MODEL_TOKENS_PER_MINUTE);
+    settings.ocr.vision.enabled = !settings.ocr.vision.api_key.is_empty();
+    if settings.ocr.default_action == OcrDefaultAction::Custom
+        && settings.ocr.custom_agent_id.is_empty()
+    {
+        settings.ocr.default_action = OcrDefaultAction::Translate;
     }
 
-    let class_name = window_class_name(hwnd_raw).map(|value| value.to_ascii_lowercase());
-    let (process_name, process_path) = window_process_identity(hwnd_raw);
-
-    blocked_apps.iter().any(|item| {
-        let pattern = item.trim().to_ascii_lowercase();
-        if pattern.is_empty() {
-            return false;
-        }
-        class_name
-            .as_deref()
-            .is_some_and(|value| value.contains(&pattern))
-            || process_name
-                .as_deref()
-                .is_some_and(|value| value.contains(&pattern))
-            || process_path
-                .as_deref()
-                .is_some_and(|value| value.contains(&pattern))
-    })
-}
-
-#[cfg(not(target_os = "windows"))]
-fn is_window_blocked_by_apps(_hwnd_raw: isize, _blocked_apps: &[String]) -> bool {
-    false
-}
+    let custom_agents = std::mem::take(&mut settings.agents.custom);
+    settings.agents.custom = custom_agents
+        .into_iter()
+        .enumerate()
+        .map(|(index, mut agent)| {
+            if agent.id.trim().is_empty() {
+                agent.id = format!("agent-{}-{}", index, now_epoch_millis());
+            } else {
+                agent.id = agent.id.trim().to_string();
+            }
+            if agent.name.trim().is_empty() {
+                agent.name = format!("Agent {}", index + 1);
+            } else {
+                let trimmed = trim_name_by_units(&agent.name, 8);
+                agent.name = if trimmed.is_empty() {
+                    format!("Agent {}", index + 1)
+                } else {
+                    trimmed
+                };
+            }
+            agent.icon = {
+                let icon = agent.icon.trim();
+                if icon.is_empty() {
+                    "Sparkles".to_string()
+                } else {
+                    icon.to_string()
+                }
+            };
+            agent.prompt = agent.prompt.trim().to_string();
+            agent.system_prompt = agent.system_prompt.trim().to_string();
+            agent.steps.truncate(MAX_WORKFLOW_STEPS);
+            agent.tools.sort_unstable();
+            agent.tools.dedup();
+            normalize_generation_params(&mut agent.params);
+            agent.enabled = true;
+            agent.order = index as u32;
+            agent
+        })
+        .take(MAX_CUSTOM_AGENTS)
+        .collect();
+    for config in settings.agents.builtin.entries_mut() {
+        normalize_generation_params(&mut config.params);
+        for template in [&mut config.system_prompt, &mut config.user_prompt] {
+            if template.trim().is_empty() {
+                template.clear();
+            }
+        }
+    }
+    let mut seen_constants = HashSet::new();
+    settings.agents.constants.retain_mut(|constant| {
+        constant.name = constant
+            .name
+            .trim()
+            .chars()
+            .map(|ch| if ch.is_ascii_alphanumeric() { ch } else { '_' })
+            .collect();
+        !constant.name.is_empty()
+            && !constant.name.starts_with(|ch: char| ch.is_ascii_digit())
+            && !CUSTOM_AGENT_TEMPLATE_VARIABLES.contains(&constant.name.as_str())
+            && seen_constants.insert(constant.name.clone())
+    });
+    settings.agents.constants.truncate(MAX_PROMPT_CONSTANTS);
+    let raw_bar_order = std::mem::take(&mut settings.agents.bar_order);
+    settings.agents.bar_order =
+        normalize_selection_bar_order(raw_bar_order, &settings.agents.custom);
 
-#[cfg(target_os = "windows")]
-fn is_left_mouse_pressed() -> bool {
-    (unsafe { GetAsyncKeyState(VK_LBUTTON as i32) } as u16 & 0x8000) != 0
+    let available_keys: HashSet<String> = settings
+        .agents
+        .bar_order
+        .iter()
+        .map(|item| item.key.clone())
+        .collect();
+    let app_rules = std::mem::take(&mut settings.selection_assistant.app_rules);
+    settings.selection_assistant.app_rules = app_rules
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, mut rule)| {
+            rule.app = rule.app.trim().to_ascii_lowercase();
+            if rule.app.is_empty() {
+                return None;
+            }
+            if rule.id.trim().is_empty() {
+                rule.id = format!("rule-{}-{}", index, now_epoch_millis());
+            } else {
+                rule.id = rule.id.trim().to_string();
+            }
+            let mut seen = HashSet::new();
+            rule.bar_items = rule
+                .bar_items
+                .into_iter()
+                .map(|key| key.trim().to_string())
+                .filter(|key| available_keys.contains(key) && seen.insert(key.clone()))
+                .take(MAX_ENABLED_SELECTION_BAR_ITEMS)
+                .collect();
+            rule.auto_run = rule.auto_run.trim().to_string();
+            // Replacing text in another application always needs an explicit click.
+            if rule.auto_run == POLISH_REPLACE_BAR_KEY
+                || !available_keys.contains(&rule.auto_run)
+                || selection_action_for_bar_key(&rule.auto_run).is_none()
+            {
+                rule.auto_run.clear();
+            }
+            rule.translate_from = find_language(rule.translate_from.trim())
+                .map(|language| language.code.to_string())
+                .unwrap_or_default();
+            rule.translate_to = find_language(rule.translate_to.trim())
+                .map(|language| language.code.to_string())
+                .unwrap_or_default();
+            Some(rule)
+        })
+        .take(MAX_APP_SELECTION_RULES)
+        .collect();
 }
 
-#[cfg(not(target_os = "windows"))]
-fn is_left_mouse_pressed() -> bool {
-    false
+fn normalize_generation_params(params: &mut GenerationParams) {
+    let mut seen = HashSet::new();
+    params
+        .stop
+        .retain(|item| !item.is_empty() && seen.insert(item.clone()));
+    params.extra_body = params.extra_body.trim().to_string();
 }
 
-#[cfg(target_os = "windows")]
-fn is_escape_pressed() -> bool {
-    (unsafe { GetAsyncKeyState(VK_ESCAPE as i32) } as u16 & 0x8000) != 0
+fn validate_generation_params(params: &GenerationParams, owner: &str) -> Result<(), CommandError> {
+    let in_range = |value: Option<f32>, min: f32, max: f32| {
+        value.is_none_or(|value| value.is_finite() && (min..=max).contains(&value))
+    };
+    if !in_range(params.top_p, 0.0, 1.0) {
+        return Err(CommandError::Settings(format!(
+            "{owner} 的 top_p 必须在 0 到 1 之间"
+        )));
+    }
+    if !in_range(params.frequency_penalty, -2.0, 2.0)
+        || !in_range(params.presence_penalty, -2.0, 2.0)
+    {
+        return Err(CommandError::Settings(format!(
+            "{owner} 的 frequency/presence penalty 必须在 -2 到 2 之间"
+        )));
+    }
+    if params.stop.len() > MAX_STOP_SEQUENCES {
+        return Err(CommandError::Settings(format!(
+            "{owner} 最多设置 {MAX_STOP_SEQUENCES} 个停止序列"
+        )));
+    }
+    parse_extra_body(&params.extra_body)
+        .map(|_| ())
+        .map_err(|error| CommandError::Settings(format!("{owner} 的额外请求体无效：{error}")))
 }
 
-#[cfg(not(target_os = "windows"))]
-fn is_escape_pressed() -> bool {
-    false
+fn validate_builtin_prompt_templates(
+    config: &BuiltinActionConfig,
+    kind: SelectionActionKind,
+    owner: &str,
+) -> Result<(), CommandError> {
+    let Some((default_system, default_user)) = builtin_prompt_defaults(kind) else {
+        return Ok(());
+    };
+    let mut has_text = false;
+    for (custom, default) in [
+        (&config.system_prompt, default_system),
+        (&config.user_prompt, default_user),
+    ] {
+        let template = if custom.is_empty() { default } else { custom };
+        let used = validate_prompt_template(template, PROMPT_TEMPLATE_VARIABLES)
+            .map_err(|error| CommandError::Settings(format!("{owner} 的提示词{error}")))?;
+        has_text |= used.contains(&"text");
+    }
+    if !has_text {
+        return Err(CommandError::Settings(format!(
+            "{owner} 的提示词必须包含 {{text}} 变量"
+        )));
+    }
+    Ok(())
 }
 
-#[cfg(target_os = "windows")]
-fn is_ctrl_pressed() -> bool {
-    (unsafe { GetAsyncKeyState(VK_CONTROL as i32) } as u16 & 0x8000) != 0
+/// Validates prompt templates of custom agents that were added or edited, so prompts saved
+/// before variables existed keep working unchanged.
+fn validate_custom_agent_prompts(
+    agents: &AgentSettings,
+    previous: &AgentSettings,
+) -> Result<(), CommandError> {
+    let known = CUSTOM_AGENT_TEMPLATE_VARIABLES
+        .iter()
+        .copied()
+        .chain(
+            agents
+                .constants
+                .iter()
+                .map(|constant| constant.name.as_str()),
+        )
+        .collect::<Vec<_>>();
+    for agent in &agents.custom {
+        let unchanged = previous.custom.iter().any(|item| {
+            item.id == agent.id
+                && item.prompt == agent.prompt
+                && item.system_prompt == agent.system_prompt
+                && item.steps == agent.steps
+        });
+        if unchanged && agents.constants == previous.constants {
+            continue;
+        }
+        let step_prompts = agent
+            .steps
+            .iter()
+            .filter(|step| step.kind == WorkflowStepKind::Prompt)
+            .map(|step| &step.prompt);
+        for template in [&agent.system_prompt, &agent.prompt]
+            .into_iter()
+            .chain(step_prompts)
+        {
+            validate_prompt_template(template, &known).map_err(|error| {
+                CommandError::Settings(format!("{} 的提示词{error}", agent.name))
+            })?;
+        }
+    }
+    Ok(())
 }
 
-#[cfg(not(target_os = "windows"))]
-fn is_ctrl_pressed() -> bool {
-    false
+/// `open_url` can carry text out in a query string, so it never shares an agent with the tools
+/// that read the clipboard or with prompts that embed `{clipboard}`.
+fn check_agent_tool_combination(agent: &CustomAgent) -> Result<(), CommandError> {
+    let reads_clipboard = agent_template_uses(agent, "clipboard")
+        || agent.tools.iter().any(|tool| {
+            matches!(
+                tool,
+                AgentTool::ReadClipboard | AgentTool::SearchClipboardHistory
+            )
+        });
+    if reads_clipboard && agent.tools.contains(&AgentTool::OpenUrl) {
+        return Err(CommandError::Settings(format!(
+            "{} 不能同时使用打开链接和读取剪贴板的工具",
+            agent.name
+        )));
+    }
+    Ok(())
 }
 
-#[cfg(target_os = "windows")]
-fn extended_top_chrome_exclusion_height(hwnd_raw: isize) -> i32 {
-    let Some(class_name) = window_class_name(hwnd_raw).map(|value| value.to_ascii_uppercase())
-    else {
-        return 0;
-    };
-
-    if class_name.contains("CHROME_WIDGETWIN") {
-        88
-    } else if class_name.contains("MOZILLAWINDOWCLASS") {
-        92
-    } else if class_name.contains("CABINETWCLASS") || class_name.contains("EXPLOREWCLASS") {
-        84
-    } else if class_name.contains("APPLICATIONFRAMEWINDOW") {
-        72
-    } else {
-        0
+/// Checks agent settings after an update. Each check only runs where the values it covers
+/// changed, so a value saved by an older version cannot block unrelated updates.
+fn validate_agent_settings(
+    agents: &AgentSettings,
+    previous: &AgentSettings,
+) -> Result<(), CommandError> {
+    let builtin = &agents.builtin;
+    for (label, kind) in [
+        ("总结", SelectionActionKind::Summarize),
+        ("润色", SelectionActionKind::Polish),
+        ("解释", SelectionActionKind::Explain),
+        ("翻译", SelectionActionKind::Translate),
+    ] {
+        let Some(config) = builtin.for_kind(kind) else {
+            continue;
+        };
+        let previous_config = previous.builtin.for_kind(kind);
+        if previous_config.map(|item| &item.params) != Some(&config.params) {
+            validate_generation_params(&config.params, label)?;
+        }
+        let prompts_unchanged = previous_config.is_some_and(|item| {
+            item.system_prompt == config.system_prompt && item.user_prompt == config.user_prompt
+        });
+        if !prompts_unchanged {
+            validate_builtin_prompt_templates(config, kind, label)?;
+        }
+    }
+    for agent in &agents.custom {
+        let previous_agent = previous.custom.iter().find(|item| item.id == agent.id);
+        let unchanged = |same: fn(&CustomAgent, &CustomAgent) -> bool| {
+            previous_agent.is_some_and(|item| same(item, agent))
+        };
+        if !unchanged(|left, right| left.params == right.params) {
+            validate_generation_params(&agent.params, &agent.name)?;
+        }
+        if !unchanged(|left, right| left.output_schema == right.output_schema) {
+            parse_output_schema(&agent.output_schema).map_err(|error| {
+                CommandError::Settings(format!("{} 的输出 JSON Schema 无效：{error}", agent.name))
+            })?;
+        }
+        let kind_unchanged = unchanged(|left, right| {
+            left.tools == right.tools
+                && left.output_schema == right.output_schema
+                && left.steps.is_empty() == right.steps.is_empty()
+        });
+        if !kind_unchanged && !agent.tools.is_empty() && !agent.output_schema.trim().is_empty() {
+            return Err(CommandError::Settings(format!(
+                "{} 不能同时使用工具和输出 JSON Schema",
+                agent.name
+            )));
+        }
+        if !kind_unchanged
+            && !agent.steps.is_empty()
+            && (!agent.tools.is_empty() || !agent.output_schema.trim().is_empty())
+        {
+            return Err(CommandError::Settings(format!(
+                "{} 是工作流 Agent，不能使用工具或输出 JSON Schema",
+                agent.name
+            )));
+        }
+        if !unchanged(|left, right| {
+            left.tools == right.tools
+                && left.prompt == right.prompt
+                && left.system_prompt == right.system_prompt
+        }) {
+            check_agent_tool_combination(agent)?;
+        }
     }
+    Ok(())
 }
 
-#[cfg(target_os = "windows")]
-fn text_selection_surface_confidence_for_class(class_name: &str) -> TextSelectionSurfaceConfidence {
-    let class_name = class_name.to_ascii_uppercase();
-    if class_name.contains("EDIT")
-        || class_name.contains("RICHEDIT")
-        || class_name.contains("SCINTILLA")
-    {
-        TextSelectionSurfaceConfidence::Strong
-    } else if class_name.contains("RENDERWIDGET")
-        || class_name.contains("CHROME_RENDERWIDGETHOSTHWND")
-        || class_name.contains("WEBVIEW")
-        || class_name.contains("INTERNET EXPLORER_SERVER")
-        || class_name.contains("CEF")
-        || class_name.contains("DIRECTUIHWND")
+fn parse_extra_body(
+    extra_body: &str,
+) -> Result<serde_json::Map<String, serde_json::Value>, String> {
+    if extra_body.trim().is_empty() {
+        return Ok(serde_json::Map::new());
+    }
+    let value =
+        serde_json::from_str::<serde_json::Value>(extra_body).map_err(|error| error.to_string())?;
+    let serde_json::Value::Object(map) = value else {
+        return Err("必须是 JSON 对象".to_string());
+    };
+    if let Some(key) = map
+        .keys()
+        .find(|key| RESERVED_REQUEST_BODY_KEYS.contains(&key.as_str()))
     {
-        TextSelectionSurfaceConfidence::Weak
-    } else {
-        TextSelectionSurfaceConfidence::None
+        return Err(format!("不能覆盖 {key} 字段"));
     }
+    Ok(map)
 }
 
-#[cfg(target_os = "windows")]
-fn window_client_top(hwnd_raw: isize) -> Option<i32> {
-    if hwnd_raw == 0 {
-        return None;
+/// Adds the action's overrides to a chat completion request body. An `extra_body` saved by an
+/// older version may no longer parse, which fails the request instead of being dropped.
+fn apply_generation_params(
+    request_body: &mut serde_json::Value,
+    params: &GenerationParams,
+) -> Result<(), CommandError> {
+    let extra = parse_extra_body(&params.extra_body)
+        .map_err(|error| CommandError::Settings(format!("额外请求体无效：{error}")))?;
+    let Some(body) = request_body.as_object_mut() else {
+        return Ok(());
+    };
+    if let Some(top_p) = params.top_p {
+        body.insert("top_p".to_string(), json!(top_p));
     }
-
-    let hwnd = hwnd_raw as HWND;
-    if hwnd.is_null() || unsafe { IsWindow(hwnd) } == 0 {
-        return None;
+    if let Some(frequency_penalty) = params.frequency_penalty {
+        body.insert("frequency_penalty".to_string(), json!(frequency_penalty));
     }
-
-    let mut window_info = WINDOWINFO {
-        cbSize: std::mem::size_of::<WINDOWINFO>() as u32,
-        rcWindow: RECT {
-            left: 0,
-            top: 0,
-            right: 0,
-            bottom: 0,
-        },
-        rcClient: RECT {
-            left: 0,
-            top: 0,
-            right: 0,
-            bottom: 0,
-        },
-        dwStyle: 0,
-        dwExStyle: 0,
-        dwWindowStatus: 0,
-        cxWindowBorders: 0,
-        cyWindowBorders: 0,
-        atomWindowType: 0,
-        wCreatorVersion: 0,
-    };
-    if unsafe { GetWindowInfo(hwnd, &mut window_info as *mut WINDOWINFO) } == 0 {
-        return None;
+    if let Some(presence_penalty) = params.presence_penalty {
+        body.insert("presence_penalty".to_string(), json!(presence_penalty));
     }
-
-    Some(window_info.rcClient.top)
-}
-
-#[cfg(target_os = "windows")]
-fn text_selection_surface_confidence_at_point(
-    hwnd_raw: isize,
-    point: PhysicalPosition<i32>,
-) -> TextSelectionSurfaceConfidence {
-    if hwnd_raw == 0 {
-        return TextSelectionSurfaceConfidence::None;
+    if !params.stop.is_empty() {
+        body.insert("stop".to_string(), json!(params.stop));
     }
-
-    let hwnd = hwnd_raw as HWND;
-    if hwnd.is_null() || unsafe { IsWindow(hwnd) } == 0 {
-        return TextSelectionSurfaceConfidence::None;
+    if let Some(seed) = params.seed {
+        body.insert("seed".to_string(), json!(seed));
     }
-
-    let mut rect = RECT {
-        left: 0,
-        top: 0,
-        right: 0,
-        bottom: 0,
-    };
-    if unsafe { GetWindowRect(hwnd, &mut rect as *mut RECT) } == 0 {
-        return TextSelectionSurfaceConfidence::None;
+    if let Some(schema) = &params.json_schema {
+        body.insert(
+            "response_format".to_string(),
+            json!({
+                "type": "json_schema",
+                "json_schema": { "name": "agent_output", "schema": schema }
+            }),
+        );
+    } else if params.response_format == ResponseFormatMode::JsonObject {
+        body.insert(
+            "response_format".to_string(),
+            json!({ "type": "json_object" }),
+        );
     }
-
-    if point.x < rect.left || point.x >= rect.right || point.y < rect.top || point.y >= rect.bottom
-    {
-        return TextSelectionSurfaceConfidence::None;
+    if !params.tools.is_empty() {
+        body.insert("tools".to_string(), json!(params.tools));
+        if params.disable_tool_calls {
+            body.insert("tool_choice".to_string(), json!("none"));
+        }
     }
+    body.extend(extra);
+    Ok(())
+}
 
-    if window_client_top(hwnd_raw).is_some_and(|client_top| point.y < client_top) {
-        return TextSelectionSurfaceConfidence::None;
+fn apply_settings_patch(settings: &mut AppSettings, patch: SettingsPatch) {
+    if let Some(theme_preset) = patch.theme_preset {
+        settings.theme_preset = theme_preset;
+    }
+
+    if let Some(language) = patch.language {
+        settings.language = language;
+    }
+
+    if let Some(window_patch) = patch.window {
+        if let Some(enabled) = window_patch.auto_hide_on_blur {
+            settings.window.auto_hide_on_blur = enabled;
+        }
+        if let Some(enabled) = window_patch.remember_position {
+            settings.window.remember_position = enabled;
+        }
+        if let Some(enabled) = window_patch.remember_main_window_size {
+            settings.window.remember_main_window_size = enabled;
+        }
+        if let Some(enabled) = window_patch.launch_on_system_startup {
+            settings.window.launch_on_system_startup = enabled;
+        }
+        if let Some(enabled) = window_patch.silent_startup {
+            settings.window.silent_startup = enabled;
+        }
+        if let Some(enabled) = window_patch.check_updates_on_startup {
+            settings.window.check_updates_on_startup = enabled;
+        }
+    }
+
+    if let Some(selection_patch) = patch.selection_assistant {
+        if let Some(enabled) = selection_patch.enabled {
+            settings.selection_assistant.enabled = enabled;
+        }
+        if let Some(mode) = selection_patch.mode {
+            settings.selection_assistant.mode = mode;
+        }
+        if let Some(show) = selection_patch.show_icon_animation {
+            settings.selection_assistant.show_icon_animation = show;
+        }
+        if let Some(compact) = selection_patch.compact_mode {
+            settings.selection_assistant.compact_mode = compact;
+        }
+        if let Some(bar_opacity) = selection_patch.bar_opacity {
+            settings.selection_assistant.bar_opacity = clamp_f32(
+                bar_opacity,
+                MIN_SELECTION_BAR_OPACITY,
+                MAX_SELECTION_BAR_OPACITY,
+            );
+        }
+        if let Some(template) = selection_patch.search_url_template {
+            settings.selection_assistant.search_url_template = template;
+        }
+        if let Some(min_chars) = selection_patch.min_chars {
+            settings.selection_assistant.min_chars = min_chars;
+        }
+        if let Some(max_chars) = selection_patch.max_chars {
+            settings.selection_assistant.max_chars = max_chars;
+        }
+        if let Some(blocked_apps) = selection_patch.blocked_apps {
+            settings.selection_assistant.blocked_apps = blocked_apps;
+        }
+        if let Some(default_translate_to) = selection_patch.default_translate_to {
+            settings.selection_assistant.default_translate_to = default_translate_to;
+        }
+        if let Some(pinned) = selection_patch.result_window_always_on_top {
+            settings.selection_assistant.result_window_always_on_top = pinned;
+        }
+        if let Some(smart_translate_target) = selection_patch.smart_translate_target {
+            settings.selection_assistant.smart_translate_target = smart_translate_target;
+        }
+        if let Some(bilingual_mode) = selection_patch.bilingual_mode {
+            settings.selection_assistant.bilingual_mode = bilingual_mode;
+        }
+        if let Some(dictionary_mode) = selection_patch.dictionary_mode {
+            settings.selection_assistant.dictionary_mode = dictionary_mode;
+        }
+        if let Some(remember_position) = selection_patch.remember_result_window_position {
+            settings.selection_assistant.remember_result_window_position = remember_position;
+        }
+        if let Some(app_rules) = selection_patch.app_rules {
+            settings.selection_assistant.app_rules = app_rules;
+        }
+        if let Some(smart_bar_suggestions) = selection_patch.smart_bar_suggestions {
+            settings.selection_assistant.smart_bar_suggestions = smart_bar_suggestions;
+        }
+    }
+
+    if let Some(llm_patch) = patch.llm {
+        let _ = llm_patch.enabled;
+        if let Some(base_url) = llm_patch.base_url {
+            settings.llm.base_url = base_url;
+        }
+        if let Some(api_key) = llm_patch.api_key {
+            settings.llm.api_key = api_key;
+        }
+        if let Some(model) = llm_patch.model {
+            settings.llm.model = model;
+        }
+        if let Some(temperature) = llm_patch.temperature {
+            settings.llm.temperature = temperature;
+        }
+        if let Some(max_tokens) = llm_patch.max_tokens {
+            settings.llm.max_tokens = max_tokens;
+        }
+        if let Some(timeout_ms) = llm_patch.timeout_ms {
+            settings.llm.timeout_ms = timeout_ms;
+        }
+        if let Some(requests_per_minute) = llm_patch.requests_per_minute {
+            settings.llm.requests_per_minute = requests_per_minute;
+        }
+        if let Some(tokens_per_minute) = llm_patch.tokens_per_minute {
+            settings.llm.tokens_per_minute = tokens_per_minute;
+        }
+        if let Some(reasoning_display) = llm_patch.reasoning_display {
+            settings.llm.reasoning_display = reasoning_display;
+        }
+        if let Some(extra_headers) = llm_patch.extra_headers {
+            settings.llm.extra_headers = extra_headers;
+        }
+        if let Some(fallback_profiles) = llm_patch.fallback_profiles {
+            settings.llm.fallback_profiles = fallback_profiles;
+        }
+        if let Some(chunk_tokens) = llm_patch.chunk_tokens {
+            settings.llm.chunk_tokens = chunk_tokens;
+        }
+        if let Some(context_tokens) = llm_patch.context_tokens {
+            settings.llm.context_tokens = context_tokens;
+        }
+    }
+
+    if let Some(tts_patch) = patch.tts {
+        if let Some(runtime_mode) = tts_patch.runtime_mode {
+            settings.tts.runtime_mode = runtime_mode;
+        }
+        if let Some(voice_zh_cn) = tts_patch.voice_zh_cn {
+            settings.tts.voice_zh_cn = voice_zh_cn;
+        }
+        if let Some(voice_en_us) = tts_patch.voice_en_us {
+            settings.tts.voice_en_us = voice_en_us;
+        }
+        if let Some(rate_percent) = tts_patch.rate_percent {
+            settings.tts.rate_percent = rate_percent;
+        }
+    }
+
+    if let Some(agent_patch) = patch.agents {
+        if let Some(custom) = agent_patch.custom {
+            settings.agents.custom = custom;
+        }
+        if let Some(bar_order) = agent_patch.bar_order {
+            settings.agents.bar_order = bar_order;
+        }
+        if let Some(builtin) = agent_patch.builtin {
+            settings.agents.builtin = builtin;
+        }
+        if let Some(constants) = agent_patch.constants {
+            settings.agents.constants = constants;
+        }
+    }
+
+    if let Some(shortcuts_patch) = patch.shortcuts {
+        if let Some(shortcut) = shortcuts_patch.toggle_main {
+            settings.shortcuts.toggle_main = shortcut;
+        }
+        if let Some(shortcut) = shortcuts_patch.toggle_ocr {
+            settings.shortcuts.toggle_ocr = shortcut;
+        }
+    }
+
+    if let Some(ocr_patch) = patch.ocr {
+        if let Some(enabled) = ocr_patch.enabled {
+            settings.ocr.enabled = enabled;
+        }
+        if let Some(auto_run) = ocr_patch.auto_run_after_capture {
+            settings.ocr.auto_run_after_capture = auto_run;
+        }
+        if let Some(action) = ocr_patch.default_action {
+            settings.ocr.default_action = action;
+        }
+        if let Some(custom_agent_id) = ocr_patch.custom_agent_id {
+            settings.ocr.custom_agent_id = custom_agent_id;
+        }
+        if let Some(always_on_top) = ocr_patch.result_window_always_on_top {
+            settings.ocr.result_window_always_on_top = always_on_top;
+        }
+        if let Some(remember_position) = ocr_patch.remember_result_window_position {
+            settings.ocr.remember_result_window_position = remember_position;
+        }
+        if let Some(vision_patch) = ocr_patch.vision {
+            if let Some(enabled) = vision_patch.enabled {
+                settings.ocr.vision.enabled = enabled;
+            }
+            if let Some(base_url) = vision_patch.base_url {
+                settings.ocr.vision.base_url = base_url;
+            }
+            if let Some(api_key) = vision_patch.api_key {
+                settings.ocr.vision.api_key = api_key;
+            }
+            if let Some(model) = vision_patch.model {
+                settings.ocr.vision.model = model;
+            }
+            if let Some(temperature) = vision_patch.temperature {
+                settings.ocr.vision.temperature = temperature;
+            }
+            if let Some(max_tokens) = vision_patch.max_tokens {
+                settings.ocr.vision.max_tokens = max_tokens;
+            }
+            if let Some(timeout_ms) = vision_patch.timeout_ms {
+                settings.ocr.vision.timeout_ms = timeout_ms;
+            }
+            if let Some(requests_per_minute) = vision_patch.requests_per_minute {
+                settings.ocr.vision.requests_per_minute = requests_per_minute;
+            }
+            if let Some(tokens_per_minute) = vision_patch.tokens_per_minute {
+                settings.ocr.vision.tokens_per_minute = tokens_per_minute;
+            }
+            if let Some(extra_headers) = vision_patch.extra_headers {
+                settings.ocr.vision.extra_headers = extra_headers;
+            }
+        }
+    }
+
+    if let Some(appearance_patch) = patch.appearance {
+        if let Some(blur_px) = appearance_patch.blur_px {
+            settings.appearance.blur_px = blur_px;
+        }
+        if let Some(saturate_percent) = appearance_patch.saturate_percent {
+            settings.appearance.saturate_percent = saturate_percent;
+        }
+        if let Some(window_opacity) = appearance_patch.window_opacity {
+            settings.appearance.window_opacity = window_opacity;
+        }
+        if let Some(border_opacity) = appearance_patch.border_opacity {
+            settings.appearance.border_opacity = border_opacity;
+        }
+        if let Some(shadow_opacity) = appearance_patch.shadow_opacity {
+            settings.appearance.shadow_opacity = shadow_opacity;
+        }
+        if let Some(corner_radius) = appearance_patch.corner_radius {
+            settings.appearance.corner_radius = corner_radius;
+        }
+        if let Some(font_scale) = appearance_patch.font_scale {
+            settings.appearance.font_scale = font_scale;
+        }
+        if let Some(accent_color) = appearance_patch.accent_color {
+            settings.appearance.accent_color = accent_color;
+        }
+        if let Some(text_color) = appearance_patch.text_color {
+            settings.appearance.text_color = text_color;
+        }
+        if let Some(text_muted_color) = appearance_patch.text_muted_color {
+            settings.appearance.text_muted_color = text_muted_color;
+        }
+    }
+    settings.appearance.surface_opacity = 0.15;
+    settings.appearance.card_opacity = 0.15;
+
+    if let Some(history_patch) = patch.history {
+        if let Some(poll_ms) = history_patch.poll_ms {
+            settings.history.poll_ms = poll_ms;
+        }
+        if let Some(max_items) = history_patch.max_items {
+            settings.history.max_items = max_items;
+        }
+        if let Some(dedupe) = history_patch.dedupe {
+            settings.history.dedupe = dedupe;
+        }
+        if let Some(enabled) = history_patch.capture_text {
+            settings.history.capture_text = enabled;
+        }
+        if let Some(enabled) = history_patch.capture_link {
+            settings.history.capture_link = enabled;
+        }
+        if let Some(enabled) = history_patch.capture_image {
+            settings.history.capture_image = enabled;
+        }
+        if let Some(enabled) = history_patch.enable_item_gradients {
+            settings.history.enable_item_gradients = enabled;
+        }
+        if let Some(default_open_category) = history_patch.default_open_category {
+            settings.history.default_open_category = default_open_category;
+        }
+        if let Some(default_category) = history_patch.default_category {
+            settings.history.default_category = default_category;
+        }
+        if let Some(paste_behavior) = history_patch.paste_behavior {
+            settings.history.paste_behavior = paste_behavior;
+        }
+        if let Some(collapse_top_bar) = history_patch.collapse_top_bar {
+            settings.history.collapse_top_bar = collapse_top_bar;
+        }
+        if let Some(promote_after_paste) = history_patch.promote_after_paste {
+            settings.history.promote_after_paste = promote_after_paste;
+        }
+        if let Some(open_at_top_on_show) = history_patch.open_at_top_on_show {
+            settings.history.open_at_top_on_show = open_at_top_on_show;
+        }
+        if let Some(enabled) = history_patch.record_ai_actions {
+            settings.history.record_ai_actions = enabled;
+        }
+        if let Some(storage_path) = history_patch.storage_path {
+            settings.history.storage_path = storage_path;
+        }
+    }
+
+    if let Some(network_patch) = patch.network {
+        if let Some(proxy_url) = network_patch.proxy_url {
+            settings.network.proxy_url = proxy_url;
+        }
+        if let Some(proxy_username) = network_patch.proxy_username {
+            settings.network.proxy_username = proxy_username;
+        }
+        if let Some(proxy_password) = network_patch.proxy_password {
+            settings.network.proxy_password = proxy_password;
+        }
+        if let Some(no_proxy) = network_patch.no_proxy {
+            settings.network.no_proxy = no_proxy;
+        }
+        if let Some(extra_ca_cert_path) = network_patch.extra_ca_cert_path {
+            settings.network.extra_ca_cert_path = extra_ca_cert_path;
+        }
+    }
+
+    if let Some(glossary_patch) = patch.glossary {
+        if let Some(entries) = glossary_patch.entries {
+            settings.glossary.entries = entries;
+        }
+        if let Some(do_not_translate) = glossary_patch.do_not_translate {
+            settings.glossary.do_not_translate = do_not_translate;
+        }
+    }
+
+    if let Some(cache_patch) = patch.response_cache {
+        if let Some(enabled) = cache_patch.enabled {
+            settings.response_cache.enabled = enabled;
+        }
+        if let Some(max_size_mb) = cache_patch.max_size_mb {
+            settings.response_cache.max_size_mb = max_size_mb;
+        }
+        if let Some(ttl_hours) = cache_patch.ttl_hours {
+            settings.response_cache.ttl_hours = ttl_hours;
+        }
+    }
+
+    if let Some(usage_patch) = patch.usage {
+        if let Some(monthly_budget_usd) = usage_patch.monthly_budget_usd {
+            settings.usage.monthly_budget_usd = monthly_budget_usd;
+        }
+        if let Some(monthly_token_budget) = usage_patch.monthly_token_budget {
+            settings.usage.monthly_token_budget = monthly_token_budget;
+        }
+        if let Some(price_overrides) = usage_patch.price_overrides {
+            settings.usage.price_overrides = price_overrides;
+        }
+    }
+
+    if let Some(width) = patch.selection_result_window_width {
+        settings.selection_result_window_width = Some(width);
+    }
+    if let Some(height) = patch.selection_result_window_height {
+        settings.selection_result_window_height = Some(height);
+    }
+    if let Some(width) = patch.ocr_result_window_width {
+        settings.ocr_result_window_width = Some(width);
+    }
+    if let Some(height) = patch.ocr_result_window_height {
+        settings.ocr_result_window_height = Some(height);
+    }
+
+    normalize_settings(settings);
+}
+fn settings_file_path<R: Runtime>(app: &AppHandle<R>) -> Result<PathBuf, CommandError> {
+    let mut dir = app
+        .path()
+        .app_config_dir()
+        .map_err(|error| CommandError::Settings(error.to_string()))?;
+    fs::create_dir_all(&dir).map_err(|error| CommandError::Settings(error.to_string()))?;
+    dir.push(SETTINGS_FILENAME);
+    Ok(dir)
+}
+
+fn settings_backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.to_path_buf();
+    backup.set_file_name(SETTINGS_BACKUP_FILENAME);
+    backup
+}
+
+fn history_backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.to_path_buf();
+    let base_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(HISTORY_FILENAME);
+    backup.set_file_name(format!("{base_name}.bak"));
+    backup
+}
+
+fn normalize_enum_value_in_object(
+    object: &mut serde_json::Map<String, serde_json::Value>,
+    key: &str,
+    allowed: &[&str],
+    default: &str,
+) -> bool {
+    let normalized = object
+        .get(key)
+        .and_then(|value| value.as_str())
+        .map(|value| value.trim().to_ascii_lowercase());
+
+    let should_replace = match normalized {
+        Some(value) => !allowed.iter().any(|candidate| *candidate == value),
+        None => true,
+    };
+
+    if should_replace {
+        object.insert(
+            key.to_string(),
+            serde_json::Value::String(default.to_string()),
+        );
+        return true;
+    }
+
+    false
+}
+
+fn parse_bool_like_json(value: &serde_json::Value) -> Option<bool> {
+    if let Some(flag) = value.as_bool() {
+        return Some(flag);
+    }
+    if let Some(number) = value.as_i64() {
+        return Some(number != 0);
+    }
+    if let Some(text) = value.as_str() {
+        return match text.trim().to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Some(true),
+            "0" | "false" | "no" | "off" => Some(false),
+            _ => None,
+        };
+    }
+    None
+}
+
+fn assign_window_bool_if_missing(
+    window_obj: &mut serde_json::Map<String, serde_json::Value>,
+    target_key: &str,
+    flag: bool,
+) -> bool {
+    let has_valid_value = window_obj
+        .get(target_key)
+        .and_then(parse_bool_like_json)
+        .is_some();
+    if has_valid_value {
+        return false;
+    }
+    window_obj.insert(target_key.to_string(), serde_json::Value::Bool(flag));
+    true
+}
+
+fn migrate_legacy_theme_preset(root: &mut serde_json::Map<String, serde_json::Value>) -> bool {
+    let incoming = root
+        .get("themePreset")
+        .and_then(|value| value.as_str())
+        .map(|value| value.trim().to_ascii_lowercase())
+        .or_else(|| {
+            root.get("theme")
+                .and_then(|value| value.as_str())
+                .map(|value| value.trim().to_ascii_lowercase())
+        });
+
+    let Some(theme) = incoming else {
+        return false;
+    };
+
+    let mapped = match theme.as_str() {
+        "dark" | "light" | "warm" | "blue" | "deep-black" | "black" | "md2-dark" | "midnight"
+        | "gray" | "graphite" | "white" | "daylight" | "sunrise" | "amber-mist" => "dark",
+        _ => return false,
+    };
+
+    let current = root
+        .get("themePreset")
+        .and_then(|value| value.as_str())
+        .map(|value| value.trim().to_ascii_lowercase());
+    if current.as_deref() == Some(mapped) {
+        return false;
+    }
+
+    root.insert(
+        "themePreset".to_string(),
+        serde_json::Value::String(mapped.to_string()),
+    );
+    true
+}
+
+fn migrate_legacy_window_settings(root: &mut serde_json::Map<String, serde_json::Value>) -> bool {
+    let mut window_obj = root
+        .remove("window")
+        .and_then(|value| value.as_object().cloned())
+        .unwrap_or_default();
+
+    let mut changed = false;
+
+    for (legacy_key, target_key) in [
+        ("launchOnSystemStartup", "launchOnSystemStartup"),
+        ("launchOnStartup", "launchOnSystemStartup"),
+        ("launch_on_system_startup", "launchOnSystemStartup"),
+        ("silentStartup", "silentStartup"),
+        ("silentOnStartup", "silentStartup"),
+        ("silent_startup", "silentStartup"),
+        ("checkUpdatesOnStartup", "checkUpdatesOnStartup"),
+        ("checkUpdatesOnLaunch", "checkUpdatesOnStartup"),
+        ("check_updates_on_startup", "checkUpdatesOnStartup"),
+        ("autoHideOnBlur", "autoHideOnBlur"),
+        ("auto_hide_on_blur", "autoHideOnBlur"),
+        ("rememberPosition", "rememberPosition"),
+        ("remember_position", "rememberPosition"),
+        ("rememberMainWindowSize", "rememberMainWindowSize"),
+        ("remember_main_window_size", "rememberMainWindowSize"),
+    ] {
+        let root_flag = root.get(legacy_key).and_then(parse_bool_like_json);
+        let nested_flag = window_obj.get(legacy_key).and_then(parse_bool_like_json);
+        if let Some(flag) = root_flag {
+            changed |= assign_window_bool_if_missing(&mut window_obj, target_key, flag);
+        }
+        if let Some(flag) = nested_flag {
+            changed |= assign_window_bool_if_missing(&mut window_obj, target_key, flag);
+        }
+    }
+
+    if !window_obj.is_empty() {
+        root.insert("window".to_string(), serde_json::Value::Object(window_obj));
+    }
+
+    changed
+}
+
+fn normalize_settings_json_value(value: &mut serde_json::Value) -> bool {
+    let Some(root) = value.as_object_mut() else {
+        return false;
+    };
+
+    let mut changed = false;
+
+    changed |= migrate_legacy_theme_preset(root);
+    changed |= migrate_legacy_window_settings(root);
+
+    changed |= normalize_enum_value_in_object(
+        root,
+        "themePreset",
+        &[
+            "dark",
+            "light",
+            "warm",
+            "blue",
+            "deep-black",
+            "gray",
+            "white",
+            "black",
+            "md2-dark",
+            "midnight",
+            "graphite",
+            "daylight",
+            "sunrise",
+            "amber-mist",
+        ],
+        "dark",
+    );
+
+    if let Some(selection) = root
+        .get_mut("selectionAssistant")
+        .and_then(|value| value.as_object_mut())
+    {
+        changed |= normalize_enum_value_in_object(
+            selection,
+            "mode",
+            &["auto-detect", "copy-trigger"],
+            "auto-detect",
+        );
+    }
+
+    if let Some(history) = root
+        .get_mut("history")
+        .and_then(|value| value.as_object_mut())
+    {
+        if !history.contains_key("defaultOpenCategory") {
+            if let Some(existing_default) = history
+                .get("defaultCategory")
+                .and_then(|value| value.as_str())
+                .map(|value| value.trim().to_ascii_lowercase())
+            {
+                let migrated = match existing_default.as_str() {
+                    "all" | "text" | "link" | "image" | "favorite" => {
+                        Some(existing_default.as_str())
+                    }
+                    _ => None,
+                };
+                if let Some(migrated) = migrated {
+                    history.insert(
+                        "defaultOpenCategory".to_string(),
+                        serde_json::Value::String(migrated.to_string()),
+                    );
+                    changed = true;
+                }
+            }
+        }
+        changed |= normalize_enum_value_in_object(
+            history,
+            "defaultOpenCategory",
+            &["all", "text", "link", "image", "favorite", "last-used"],
+            "all",
+        );
+        changed |= normalize_enum_value_in_object(
+            history,
+            "defaultCategory",
+            &["all", "text", "link", "image", "favorite"],
+            "all",
+        );
+        changed |= normalize_enum_value_in_object(
+            history,
+            "pasteBehavior",
+            &["copy-only", "copy-and-hide"],
+            "copy-and-hide",
+        );
+    }
+
+    if let Some(ocr) = root.get_mut("ocr").and_then(|value| value.as_object_mut()) {
+        changed |= normalize_enum_value_in_object(
+            ocr,
+            "defaultAction",
+            &["translate", "summarize", "polish", "explain", "custom"],
+            "translate",
+        );
+    }
+
+    if let Some(tts) = root.get_mut("tts").and_then(|value| value.as_object_mut()) {
+        changed |= normalize_enum_value_in_object(
+            tts,
+            "runtimeMode",
+            &["dual-fallback", "edge-cli-only", "python-module-only"],
+            "dual-fallback",
+        );
+    }
+
+    changed
+}
+
+fn merge_json_value_by_shape(target: &mut serde_json::Value, incoming: &serde_json::Value) {
+    if let (Some(target_map), Some(incoming_map)) = (target.as_object_mut(), incoming.as_object()) {
+        for (key, incoming_value) in incoming_map {
+            if let Some(target_value) = target_map.get_mut(key) {
+                merge_json_value_by_shape(target_value, incoming_value);
+            } else {
+                target_map.insert(key.clone(), incoming_value.clone());
+            }
+        }
+        return;
+    }
+
+    let should_replace = target.is_null()
+        || (target.is_array() && incoming.is_array())
+        || (target.is_string() && incoming.is_string())
+        || (target.is_boolean() && incoming.is_boolean())
+        || (target.is_number() && incoming.is_number());
+    if should_replace {
+        *target = incoming.clone();
+    }
+}
+
+fn parse_settings_from_text(text: &str) -> Result<(AppSettings, bool), CommandError> {
+    let mut incoming = serde_json::from_str::<serde_json::Value>(text)
+        .map_err(|error| CommandError::Serialization(error.to_string()))?;
+    let direct_parse_ok = serde_json::from_str::<AppSettings>(text).is_ok();
+    let repaired_json = normalize_settings_json_value(&mut incoming);
+
+    let mut merged = serde_json::to_value(AppSettings::default())
+        .map_err(|error| CommandError::Serialization(error.to_string()))?;
+    merge_json_value_by_shape(&mut merged, &incoming);
+
+    let mut settings = serde_json::from_value::<AppSettings>(merged)
+        .map_err(|error| CommandError::Serialization(error.to_string()))?;
+    normalize_settings(&mut settings);
+    Ok((settings, repaired_json || !direct_parse_ok))
+}
+
+fn file_modified_epoch_ms(path: &Path) -> u64 {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn is_likely_snapparse_dir(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| {
+            let lowered = name.to_ascii_lowercase();
+            lowered.contains("snapparse") || lowered.contains("clipboard")
+        })
+        .unwrap_or(false)
+}
+
+fn discover_legacy_storage_dirs(settings_path: &Path) -> Vec<PathBuf> {
+    let Some(current_dir) = settings_path.parent() else {
+        return Vec::new();
+    };
+    let Some(parent_dir) = current_dir.parent() else {
+        return Vec::new();
+    };
+
+    let mut dirs = Vec::<PathBuf>::new();
+    if let Ok(entries) = fs::read_dir(parent_dir) {
+        for entry in entries.flatten() {
+            let candidate = entry.path();
+            if !candidate.is_dir() || candidate == current_dir {
+                continue;
+            }
+            if !is_likely_snapparse_dir(&candidate) {
+                continue;
+            }
+            dirs.push(candidate);
+        }
+    }
+    dirs.sort_by_key(|path| std::cmp::Reverse(file_modified_epoch_ms(path)));
+    dirs
+}
+
+fn dedupe_paths_keep_order(input: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut seen = HashSet::<PathBuf>::new();
+    let mut output = Vec::<PathBuf>::new();
+    for item in input {
+        if seen.insert(item.clone()) {
+            output.push(item);
+        }
+    }
+    output
+}
+
+fn parse_history_kind(value: &serde_json::Value) -> Option<ClipboardKind> {
+    let raw = value.as_str()?.trim().to_ascii_lowercase();
+    match raw.as_str() {
+        "text" => Some(ClipboardKind::Text),
+        "link" => Some(ClipboardKind::Link),
+        "image" => Some(ClipboardKind::Image),
+        _ => None,
+    }
+}
+
+fn parse_history_timestamp(value: &serde_json::Value) -> Option<DateTime<Utc>> {
+    if let Some(text) = value.as_str() {
+        if let Ok(parsed) = DateTime::parse_from_rfc3339(text.trim()) {
+            return Some(parsed.with_timezone(&Utc));
+        }
+        return None;
+    }
+
+    let numeric = value.as_i64()?;
+    if numeric > 4_000_000_000 {
+        DateTime::<Utc>::from_timestamp_millis(numeric)
+    } else {
+        DateTime::<Utc>::from_timestamp(numeric, 0)
+    }
+}
+
+fn parse_history_entry_compat(raw: &serde_json::Value) -> Option<ClipboardEntry> {
+    let object = raw.as_object()?;
+    let content = object
+        .get("content")
+        .and_then(|value| value.as_str())
+        .or_else(|| object.get("text").and_then(|value| value.as_str()))
+        .unwrap_or_default()
+        .to_string();
+    let image_data_url = object
+        .get("imageDataUrl")
+        .and_then(|value| value.as_str())
+        .or_else(|| object.get("image").and_then(|value| value.as_str()))
+        .map(str::to_string);
+
+    let kind = object
+        .get("kind")
+        .and_then(parse_history_kind)
+        .or_else(|| {
+            if image_data_url.is_some() {
+                Some(ClipboardKind::Image)
+            } else if content.contains("://") {
+                Some(ClipboardKind::Link)
+            } else {
+                Some(ClipboardKind::Text)
+            }
+        })?;
+
+    let copied_at = object
+        .get("copiedAt")
+        .and_then(parse_history_timestamp)
+        .or_else(|| object.get("copied_at").and_then(parse_history_timestamp))
+        .or_else(|| object.get("createdAt").and_then(parse_history_timestamp))
+        .or_else(|| object.get("timestamp").and_then(parse_history_timestamp))
+        .unwrap_or_else(Utc::now);
+
+    let pinned = object
+        .get("pinned")
+        .and_then(parse_bool_like_json)
+        .unwrap_or(false);
+    let id = object
+        .get("id")
+        .and_then(|value| value.as_str())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(now_id);
+
+    Some(ClipboardEntry {
+        id,
+        kind,
+        content,
+        image_data_url,
+        thumbnail_data_url: None,
+        copied_at,
+        pinned,
+    })
+}
+
+fn truncate_text_chars(input: &str, max_chars: usize) -> String {
+    if max_chars == 0 {
+        return String::new();
+    }
+    if input.chars().count() <= max_chars {
+        return input.to_string();
+    }
+    let mut output = String::with_capacity(input.len().min(max_chars.saturating_mul(3)));
+    for ch in input.chars().take(max_chars) {
+        output.push(ch);
+    }
+    output
+}
+
+fn sanitize_clipboard_entry_for_storage(mut entry: ClipboardEntry) -> Option<ClipboardEntry> {
+    entry.id = entry.id.trim().to_string();
+    if entry.id.is_empty() {
+        entry.id = now_id();
+    }
+
+    match entry.kind {
+        ClipboardKind::Text | ClipboardKind::Link => {
+            let normalized = truncate_text_chars(entry.content.trim(), MAX_CLIPBOARD_TEXT_CHARS);
+            if normalized.is_empty() {
+                return None;
+            }
+            entry.content = normalized;
+            entry.image_data_url = None;
+        }
+        ClipboardKind::Image => {
+            let payload = entry.image_data_url.as_deref()?.trim();
+            let lowered = payload.to_ascii_lowercase();
+            if !lowered.starts_with("data:image/") || !lowered.contains("base64,") {
+                return None;
+            }
+            if payload.len() > MAX_CLIPBOARD_IMAGE_DATA_URL_CHARS {
+                return None;
+            }
+            entry.image_data_url = Some(payload.to_string());
+            if entry.content.trim().is_empty() {
+                entry.content = "Image".to_string();
+            }
+        }
+    }
+
+    Some(entry)
+}
+
+fn parse_history_entries(
+    text: &str,
+    max_items: usize,
+) -> Result<VecDeque<ClipboardEntry>, CommandError> {
+    let items = match serde_json::from_str::<Vec<ClipboardEntry>>(text) {
+        Ok(parsed) => parsed,
+        Err(_) => {
+            let raw_value = serde_json::from_str::<serde_json::Value>(text)
+                .map_err(|error| CommandError::Serialization(error.to_string()))?;
+            let raw_items = raw_value.as_array().ok_or_else(|| {
+                CommandError::Serialization("History snapshot must be a JSON array".to_string())
+            })?;
+            let mut repaired = Vec::<ClipboardEntry>::with_capacity(raw_items.len());
+            for raw_item in raw_items {
+                if let Ok(entry) = serde_json::from_value::<ClipboardEntry>(raw_item.clone()) {
+                    repaired.push(entry);
+                    continue;
+                }
+                if let Some(entry) = parse_history_entry_compat(raw_item) {
+                    repaired.push(entry);
+                }
+            }
+            repaired
+        }
+    };
+    let sanitized = items
+        .into_iter()
+        .filter_map(sanitize_clipboard_entry_for_storage)
+        .collect::<Vec<_>>();
+
+    let mut history = VecDeque::from(sanitized);
+    trim_history(&mut history, max_items);
+    normalize_history_order(&mut history);
+    Ok(history)
+}
+
+fn read_text_with_retry_with_limit(
+    path: &Path,
+    max_bytes: Option<u64>,
+) -> Result<String, std::io::Error> {
+    const RETRY_COUNT: usize = 20;
+    const RETRY_DELAY_MS: u64 = 120;
+    if let Some(max_bytes) = max_bytes {
+        if let Ok(metadata) = fs::metadata(path) {
+            if metadata.len() > max_bytes {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "file too large: {} bytes exceeds limit {} bytes",
+                        metadata.len(),
+                        max_bytes
+                    ),
+                ));
+            }
+        }
+    }
+
+    let mut last_error: Option<std::io::Error> = None;
+
+    for attempt in 0..RETRY_COUNT {
+        match fs::read_to_string(path) {
+            Ok(value) => return Ok(value),
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    ErrorKind::PermissionDenied | ErrorKind::WouldBlock | ErrorKind::Interrupted
+                ) && attempt + 1 < RETRY_COUNT =>
+            {
+                last_error = Some(error);
+                std::thread::sleep(Duration::from_millis(RETRY_DELAY_MS));
+            }
+            Err(error) => return Err(error),
+        }
+    }
+
+    Err(last_error
+        .unwrap_or_else(|| std::io::Error::other("history file read failed after retries")))
+}
+
+fn read_settings_text_with_retry(path: &Path) -> Result<String, std::io::Error> {
+    read_text_with_retry_with_limit(path, Some(MAX_SETTINGS_FILE_BYTES))
+}
+
+fn read_history_text_with_retry(path: &Path) -> Result<String, std::io::Error> {
+    read_text_with_retry_with_limit(path, Some(MAX_HISTORY_FILE_BYTES))
+}
+
+fn resolve_history_path_from_base_dir(storage_path: &str, base_dir: &Path) -> PathBuf {
+    let trimmed = storage_path.trim();
+    if trimmed.is_empty() {
+        return base_dir.join(HISTORY_FILENAME);
+    }
+
+    let mut path = PathBuf::from(trimmed);
+    if path.is_relative() {
+        path = base_dir.join(path);
+    }
+
+    if !trimmed.to_ascii_lowercase().ends_with(".json") {
+        path.push(HISTORY_FILENAME);
+    }
+    path
+}
+
+fn app_config_history_file_path<R: Runtime>(app: &AppHandle<R>) -> Result<PathBuf, CommandError> {
+    let mut config_dir = app
+        .path()
+        .app_config_dir()
+        .map_err(|error| CommandError::Settings(error.to_string()))?;
+    fs::create_dir_all(&config_dir).map_err(|error| CommandError::Settings(error.to_string()))?;
+    config_dir.push(HISTORY_FILENAME);
+    Ok(config_dir)
+}
+
+fn resolve_history_file_path<R: Runtime>(
+    app: &AppHandle<R>,
+    settings: &AppSettings,
+) -> Result<PathBuf, CommandError> {
+    let config_dir = app_config_history_file_path(app)?
+        .parent()
+        .map(Path::to_path_buf)
+        .ok_or_else(|| CommandError::Settings("Invalid app config directory".to_string()))?;
+    let path = resolve_history_path_from_base_dir(&settings.history.storage_path, &config_dir);
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|error| CommandError::Settings(error.to_string()))?;
+    }
+
+    Ok(path)
+}
+
+fn recover_history_for_storage_path_switch<R: Runtime>(
+    app: &AppHandle<R>,
+    previous_settings: &AppSettings,
+    updated_settings: &AppSettings,
+) -> Option<VecDeque<ClipboardEntry>> {
+    if previous_settings.history.storage_path.trim() == updated_settings.history.storage_path.trim()
+    {
+        return None;
+    }
+
+    let mut candidates = Vec::<PathBuf>::new();
+    if let Ok(previous_path) = resolve_history_file_path(app, previous_settings) {
+        candidates.push(previous_path.clone());
+        candidates.push(history_backup_path(&previous_path));
+    }
+    if let Ok(app_config_path) = app_config_history_file_path(app) {
+        candidates.push(app_config_path.clone());
+        candidates.push(history_backup_path(&app_config_path));
+    }
+
+    let mut candidates = dedupe_paths_keep_order(candidates);
+    candidates.sort_by_key(|path| std::cmp::Reverse(file_modified_epoch_ms(path)));
+
+    for candidate in candidates {
+        if let Ok(text) = read_history_text_with_retry(&candidate) {
+            if let Ok(history) = parse_history_entries(&text, updated_settings.history.max_items) {
+                if !history.is_empty() {
+                    eprintln!(
+                        "[History] recovered {} items from previous storage path {}",
+                        history.len(),
+                        candidate.display()
+                    );
+                    return Some(history);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+fn write_text_file_with_backup(
+    path: &Path,
+    payload: &str,
+    backup_path: &Path,
+) -> Result<bool, CommandError> {
+    if path.exists() {
+        if let Ok(existing) = fs::read_to_string(path) {
+            if existing == payload {
+                return Ok(false);
+            }
+        }
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|error| CommandError::Settings(error.to_string()))?;
+    }
+
+    if path.exists() {
+        let _ = fs::copy(path, backup_path);
+    }
+
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, payload).map_err(|error| CommandError::Settings(error.to_string()))?;
+
+    if path.exists() {
+        let _ = fs::remove_file(path);
+    }
+
+    match fs::rename(&temp_path, path) {
+        Ok(_) => Ok(true),
+        Err(_) => {
+            fs::write(path, payload).map_err(|error| CommandError::Settings(error.to_string()))?;
+            let _ = fs::remove_file(&temp_path);
+            Ok(true)
+        }
+    }
+}
+
+fn persist_settings(path: &Path, settings: &AppSettings) -> Result<bool, CommandError> {
+    let payload = serde_json::to_string_pretty(settings)
+        .map_err(|error| CommandError::Serialization(error.to_string()))?;
+    let backup_path = settings_backup_path(path);
+    write_text_file_with_backup(path, &payload, &backup_path)
+}
+
+fn persist_history_snapshot<R: Runtime>(
+    app: &AppHandle<R>,
+    settings: &AppSettings,
+    entries: &[ClipboardEntry],
+) -> Result<(), CommandError> {
+    let payload = serde_json::to_string_pretty(entries)
+        .map_err(|error| CommandError::Serialization(error.to_string()))?;
+    let write_snapshot = |path: &Path, payload: &str| -> Result<(), CommandError> {
+        let backup_path = history_backup_path(path);
+        let _ = write_text_file_with_backup(path, payload, &backup_path)?;
+        Ok(())
+    };
+
+    let primary_path = resolve_history_file_path(app, settings)?;
+    let app_config_path = app_config_history_file_path(app)?;
+    let primary_result = write_snapshot(&primary_path, &payload);
+    let mirror_result = if app_config_path != primary_path {
+        write_snapshot(&app_config_path, &payload)
+    } else {
+        Ok(())
+    };
+
+    match (primary_result, mirror_result) {
+        (Ok(_), _) => Ok(()),
+        (Err(primary_error), Ok(_)) => {
+            eprintln!(
+                "[History] primary snapshot write failed, mirrored to app config: {}",
+                primary_error
+            );
+            Ok(())
+        }
+        (Err(primary_error), Err(_mirror_error)) => Err(primary_error),
+    }
+}
+
+fn load_history_snapshot<R: Runtime>(
+    app: &AppHandle<R>,
+    settings: &AppSettings,
+    canonical_settings_path: &Path,
+    settings_source_path: &Path,
+) -> Result<HistoryLoadResult, CommandError> {
+    let canonical_history_path = resolve_history_file_path(app, settings)?;
+    let app_config_history_path = app_config_history_file_path(app)?;
+    let mut empty_history_candidate: Option<HistoryLoadResult> = None;
+
+    if let Ok(text) = read_history_text_with_retry(&canonical_history_path) {
+        if let Ok(history) = parse_history_entries(&text, settings.history.max_items) {
+            if !history.is_empty() {
+                return Ok(HistoryLoadResult {
+                    history,
+                    source_path: Some(canonical_history_path.clone()),
+                    should_persist: false,
+                });
+            }
+            empty_history_candidate = Some(HistoryLoadResult {
+                history,
+                source_path: Some(canonical_history_path.clone()),
+                should_persist: false,
+            });
+        }
+        eprintln!(
+            "[History] primary snapshot parse failed {}",
+            canonical_history_path.display()
+        );
+    }
+
+    let canonical_backup_path = history_backup_path(&canonical_history_path);
+    if let Ok(text) = read_history_text_with_retry(&canonical_backup_path) {
+        if let Ok(history) = parse_history_entries(&text, settings.history.max_items) {
+            if !history.is_empty() {
+                return Ok(HistoryLoadResult {
+                    history,
+                    source_path: Some(canonical_backup_path.clone()),
+                    should_persist: true,
+                });
+            }
+            if empty_history_candidate.is_none() {
+                empty_history_candidate = Some(HistoryLoadResult {
+                    history,
+                    source_path: Some(canonical_backup_path.clone()),
+                    should_persist: true,
+                });
+            }
+        }
+        eprintln!(
+            "[History] backup snapshot parse failed {}",
+            canonical_backup_path.display()
+        );
+    }
+
+    let mut fallback_candidates = Vec::<PathBuf>::new();
+    if app_config_history_path != canonical_history_path {
+        fallback_candidates.push(app_config_history_path.clone());
+        fallback_candidates.push(history_backup_path(&app_config_history_path));
+    }
+    if let (Some(source_dir), Some(canonical_dir)) = (
+        settings_source_path.parent(),
+        canonical_settings_path.parent(),
+    ) {
+        if source_dir != canonical_dir {
+            let source_history_path =
+                resolve_history_path_from_base_dir(&settings.history.storage_path, source_dir);
+            fallback_candidates.push(source_history_path.clone());
+            fallback_candidates.push(history_backup_path(&source_history_path));
+        }
+    }
+
+    for legacy_dir in discover_legacy_storage_dirs(canonical_settings_path) {
+        let legacy_history_path =
+            resolve_history_path_from_base_dir(&settings.history.storage_path, &legacy_dir);
+        fallback_candidates.push(legacy_history_path.clone());
+        fallback_candidates.push(history_backup_path(&legacy_history_path));
+    }
+
+    let mut fallback_candidates = dedupe_paths_keep_order(fallback_candidates);
+    fallback_candidates.sort_by_key(|path| std::cmp::Reverse(file_modified_epoch_ms(path)));
+
+    for candidate in fallback_candidates {
+        if let Ok(text) = read_history_text_with_retry(&candidate) {
+            if let Ok(history) = parse_history_entries(&text, settings.history.max_items) {
+                if !history.is_empty() {
+                    return Ok(HistoryLoadResult {
+                        history,
+                        source_path: Some(candidate),
+                        should_persist: true,
+                    });
+                }
+                if empty_history_candidate.is_none() {
+                    empty_history_candidate = Some(HistoryLoadResult {
+                        history,
+                        source_path: Some(candidate),
+                        should_persist: true,
+                    });
+                }
+            }
+        }
+    }
+
+    if let Some(candidate) = empty_history_candidate {
+        return Ok(candidate);
+    }
+
+    Ok(HistoryLoadResult {
+        history: VecDeque::new(),
+        source_path: None,
+        should_persist: false,
+    })
+}
+
+fn persist_settings_state(settings_state: &AppSettingsState) -> Result<(), CommandError> {
+    let snapshot = settings_state
+        .data
+        .lock()
+        .map_err(|_| CommandError::Lock)?
+        .clone();
+    let _ = persist_settings(&settings_state.file_path, &snapshot)?;
+    Ok(())
+}
+
+fn load_settings(path: &Path) -> SettingsLoadResult {
+    let canonical_path = path.to_path_buf();
+    let canonical_backup = settings_backup_path(path);
+    let mut candidate_paths = vec![canonical_path.clone(), canonical_backup.clone()];
+    for legacy_dir in discover_legacy_storage_dirs(path) {
+        candidate_paths.push(legacy_dir.join(SETTINGS_FILENAME));
+        candidate_paths.push(legacy_dir.join(SETTINGS_BACKUP_FILENAME));
+    }
+    candidate_paths = dedupe_paths_keep_order(candidate_paths);
+
+    // Try canonical file and backup first to avoid restoring stale legacy snapshots.
+    for candidate in [&canonical_path, &canonical_backup] {
+        if let Ok(text) = read_settings_text_with_retry(candidate) {
+            match parse_settings_from_text(&text) {
+                Ok((settings, repaired)) => {
+                    return SettingsLoadResult {
+                        settings,
+                        source_path: candidate.clone(),
+                        should_persist: repaired || candidate.as_path() != canonical_path.as_path(),
+                    };
+                }
+                Err(error) => {
+                    eprintln!("[Settings] parse failed {}: {}", candidate.display(), error);
+                }
+            }
+        }
+    }
+
+    let mut legacy_candidates = candidate_paths
+        .into_iter()
+        .filter(|candidate| candidate != &canonical_path && candidate != &canonical_backup)
+        .collect::<Vec<_>>();
+    legacy_candidates.sort_by_key(|candidate| std::cmp::Reverse(file_modified_epoch_ms(candidate)));
+
+    for candidate in legacy_candidates {
+        if let Ok(text) = read_settings_text_with_retry(&candidate) {
+            match parse_settings_from_text(&text) {
+                Ok((settings, _repaired)) => {
+                    return SettingsLoadResult {
+                        settings,
+                        source_path: candidate,
+                        should_persist: true,
+                    };
+                }
+                Err(error) => {
+                    eprintln!("[Settings] parse failed {}: {}", candidate.display(), error);
+                }
+            }
+        }
+    }
+
+    SettingsLoadResult {
+        settings: AppSettings::default(),
+        source_path: canonical_path,
+        should_persist: true,
+    }
+}
+
+fn emit_settings_updated<R: Runtime>(app: &AppHandle<R>, settings: &AppSettings) {
+    let _ = app.emit(SETTINGS_UPDATED_EVENT, settings.clone());
+}
+
+#[cfg(target_os = "windows")]
+fn capture_last_foreground_window(flags: &RuntimeFlags) {
+    let hwnd = unsafe { GetForegroundWindow() };
+    if !hwnd.is_null() {
+        flags
+            .last_foreground_hwnd
+            .store(hwnd as isize, Ordering::Relaxed);
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn capture_last_foreground_window(_flags: &RuntimeFlags) {}
+
+#[cfg(target_os = "windows")]
+fn restore_foreground_window(hwnd_raw: isize) -> bool {
+    if hwnd_raw == 0 {
+        return false;
+    }
+
+    let hwnd = hwnd_raw as HWND;
+    if hwnd.is_null() {
+        return false;
+    }
+
+    if unsafe { IsWindow(hwnd) } == 0 {
+        return false;
+    }
+
+    let foreground = unsafe { GetForegroundWindow() };
+    if foreground == hwnd {
+        return true;
+    }
+
+    unsafe {
+        if IsIconic(hwnd) != 0 {
+            ShowWindow(hwnd, SW_RESTORE);
+        }
+        SetForegroundWindow(hwnd) != 0
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn restore_foreground_window(_hwnd_raw: isize) -> bool {
+    false
+}
+
+#[cfg(target_os = "windows")]
+fn keyboard_input(vk: u16, key_up: bool) -> INPUT {
+    INPUT {
+        r#type: INPUT_KEYBOARD,
+        Anonymous: INPUT_0 {
+            ki: KEYBDINPUT {
+                wVk: vk,
+                wScan: 0,
+                dwFlags: if key_up { KEYEVENTF_KEYUP } else { 0 },
+                time: 0,
+                dwExtraInfo: 0,
+            },
+        },
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn send_system_paste_shortcut(hwnd_raw: isize) -> Result<(), CommandError> {
+    const VK_VIRTUAL_V: u16 = 0x56;
+
+    let _ = restore_foreground_window(hwnd_raw);
+    std::thread::sleep(Duration::from_millis(18));
+
+    let mut inputs = [
+        keyboard_input(VK_CONTROL, false),
+        keyboard_input(VK_VIRTUAL_V, false),
+        keyboard_input(VK_VIRTUAL_V, true),
+        keyboard_input(VK_CONTROL, true),
+    ];
+
+    let sent = unsafe {
+        SendInput(
+            inputs.len() as u32,
+            inputs.as_mut_ptr(),
+            std::mem::size_of::<INPUT>() as i32,
+        )
+    };
+
+    if sent == inputs.len() as u32 {
+        Ok(())
+    } else {
+        Err(CommandError::Settings(
+            "Failed to dispatch Ctrl+V shortcut".to_string(),
+        ))
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn send_system_paste_shortcut(_hwnd_raw: isize) -> Result<(), CommandError> {
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+fn send_system_copy_shortcut(hwnd_raw: isize) -> Result<(), CommandError> {
+    const VK_VIRTUAL_C: u16 = 0x43;
+    const VK_VIRTUAL_INSERT: u16 = VK_INSERT;
+    const VK_VIRTUAL_SHIFT: u16 = VK_SHIFT;
+
+    if hwnd_raw == 0 {
+        return Err(CommandError::Settings(
+            "No foreground window available for copy shortcut".to_string(),
+        ));
+    }
+
+    let _ = restore_foreground_window(hwnd_raw);
+    std::thread::sleep(Duration::from_millis(16));
+
+    let send = |inputs: &mut [INPUT]| -> bool {
+        let sent = unsafe {
+            SendInput(
+                inputs.len() as u32,
+                inputs.as_mut_ptr(),
+                std::mem::size_of::<INPUT>() as i32,
+            )
+        };
+        sent == inputs.len() as u32
+    };
+
+    if is_windows_terminal_window(hwnd_raw) {
+        let mut primary = [
+            keyboard_input(VK_CONTROL, false),
+            keyboard_input(VK_VIRTUAL_SHIFT, false),
+            keyboard_input(VK_VIRTUAL_C, false),
+            keyboard_input(VK_VIRTUAL_C, true),
+            keyboard_input(VK_VIRTUAL_SHIFT, true),
+            keyboard_input(VK_CONTROL, true),
+        ];
+        let primary_sent = send(&mut primary);
+        std::thread::sleep(Duration::from_millis(10));
+        let mut fallback = [
+            keyboard_input(VK_CONTROL, false),
+            keyboard_input(VK_VIRTUAL_INSERT, false),
+            keyboard_input(VK_VIRTUAL_INSERT, true),
+            keyboard_input(VK_CONTROL, true),
+        ];
+        let fallback_sent = send(&mut fallback);
+        if primary_sent || fallback_sent {
+            return Ok(());
+        }
+        return Err(CommandError::Settings(
+            "Failed to dispatch terminal copy shortcut".to_string(),
+        ));
+    }
+
+    if is_console_like_window(hwnd_raw) {
+        let mut fallback = [
+            keyboard_input(VK_CONTROL, false),
+            keyboard_input(VK_VIRTUAL_INSERT, false),
+            keyboard_input(VK_VIRTUAL_INSERT, true),
+            keyboard_input(VK_CONTROL, true),
+        ];
+        if send(&mut fallback) {
+            return Ok(());
+        }
+        return Err(CommandError::Settings(
+            "Failed to dispatch console copy shortcut".to_string(),
+        ));
+    }
+
+    let mut inputs = [
+        keyboard_input(VK_CONTROL, false),
+        keyboard_input(VK_VIRTUAL_C, false),
+        keyboard_input(VK_VIRTUAL_C, true),
+        keyboard_input(VK_CONTROL, true),
+    ];
+    if send(&mut inputs) {
+        Ok(())
+    } else {
+        Err(CommandError::Settings(
+            "Failed to dispatch Ctrl+C shortcut".to_string(),
+        ))
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn send_system_copy_shortcut(_hwnd_raw: isize) -> Result<(), CommandError> {
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+fn is_terminal_host_process(hwnd_raw: isize) -> bool {
+    let (process_name, process_path) = window_process_identity(hwnd_raw);
+    let Some(name) = process_name else {
+        return false;
+    };
+
+    const TERMINAL_HOST_PROCESSES: &[&str] = &[
+        "windowsterminal.exe",
+        "wt.exe",
+        "powershell.exe",
+        "pwsh.exe",
+        "cmd.exe",
+        "conhost.exe",
+        "wezterm.exe",
+        "wezterm-gui.exe",
+        "mintty.exe",
+        "alacritty.exe",
+        "tabby.exe",
+        "hyper.exe",
+        "conemu.exe",
+        "conemu64.exe",
+        "cmder.exe",
+        "code.exe",
+        "code-insiders.exe",
+        "cursor.exe",
+        "vscodium.exe",
+        "windsurf.exe",
+    ];
+
+    if TERMINAL_HOST_PROCESSES
+        .iter()
+        .any(|candidate| name == *candidate)
+    {
+        return true;
+    }
+
+    process_path
+        .as_deref()
+        .is_some_and(|path| path.contains("\\windowsapps\\wt.exe"))
+}
+
+#[cfg(not(target_os = "windows"))]
+fn is_terminal_host_process(_hwnd_raw: isize) -> bool {
+    false
+}
+
+#[cfg(target_os = "windows")]
+fn is_console_like_window(hwnd_raw: isize) -> bool {
+    let class_like = window_class_name(hwnd_raw)
+        .map(|class_name| {
+            class_name.contains("CONSOLEWINDOWCLASS")
+                || class_name.contains("CASCADIA_HOSTING_WINDOW_CLASS")
+                || class_name.contains("PSEUDOCONSOLEWINDOW")
+                || class_name.contains("VIRTUALCONSOLECLASS")
+                || class_name.contains("MINTTY")
+                || class_name.contains("WEZTERM")
+                || class_name.contains("ALACRITTY")
+        })
+        .unwrap_or(false);
+
+    class_like || is_terminal_host_process(hwnd_raw)
+}
+
+#[cfg(target_os = "windows")]
+fn is_windows_terminal_window(hwnd_raw: isize) -> bool {
+    let class_like = window_class_name(hwnd_raw)
+        .map(|class_name| {
+            class_name.contains("CASCADIA_HOSTING_WINDOW_CLASS")
+                || class_name.contains("PSEUDOCONSOLEWINDOW")
+        })
+        .unwrap_or(false);
+
+    if class_like {
+        return true;
+    }
+
+    let (process_name, _) = window_process_identity(hwnd_raw);
+    process_name
+        .as_deref()
+        .is_some_and(|name| name == "windowsterminal.exe" || name == "wt.exe")
+}
+
+#[cfg(not(target_os = "windows"))]
+fn is_console_like_window(_hwnd_raw: isize) -> bool {
+    false
+}
+
+#[cfg(not(target_os = "windows"))]
+fn is_windows_terminal_window(_hwnd_raw: isize) -> bool {
+    false
+}
+
+#[cfg(target_os = "windows")]
+fn send_console_copy_shortcut(hwnd_raw: isize) -> Result<(), CommandError> {
+    const VK_VIRTUAL_INSERT: u16 = VK_INSERT;
+
+    if hwnd_raw == 0 {
+        return Err(CommandError::Settings(
+            "No foreground window available for console copy shortcut".to_string(),
+        ));
+    }
+
+    let _ = restore_foreground_window(hwnd_raw);
+    std::thread::sleep(Duration::from_millis(12));
+
+    let send = |inputs: &mut [INPUT]| -> bool {
+        let sent = unsafe {
+            SendInput(
+                inputs.len() as u32,
+                inputs.as_mut_ptr(),
+                std::mem::size_of::<INPUT>() as i32,
+            )
+        };
+        sent == inputs.len() as u32
+    };
+
+    let mut copy_insert = [
+        keyboard_input(VK_CONTROL, false),
+        keyboard_input(VK_VIRTUAL_INSERT, false),
+        keyboard_input(VK_VIRTUAL_INSERT, true),
+        keyboard_input(VK_CONTROL, true),
+    ];
+    if send(&mut copy_insert) {
+        return Ok(());
+    }
+
+    Err(CommandError::Settings(
+        "Failed to dispatch safe console copy shortcut".to_string(),
+    ))
+}
+
+#[cfg(not(target_os = "windows"))]
+fn send_console_copy_shortcut(_hwnd_raw: isize) -> Result<(), CommandError> {
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+fn window_class_name(hwnd_raw: isize) -> Option<String> {
+    let hwnd = hwnd_raw as HWND;
+    if hwnd_raw == 0 || hwnd.is_null() || unsafe { IsWindow(hwnd) } == 0 {
+        return None;
+    }
+
+    let mut buffer = [0u16; 128];
+    let class_len = unsafe { GetClassNameW(hwnd, buffer.as_mut_ptr(), buffer.len() as i32) };
+    if class_len <= 0 {
+        return None;
+    }
+
+    Some(String::from_utf16_lossy(&buffer[..class_len as usize]).to_ascii_uppercase())
+}
+
+#[cfg(target_os = "windows")]
+fn window_title(hwnd_raw: isize) -> Option<String> {
+    let hwnd = hwnd_raw as HWND;
+    if hwnd_raw == 0 || hwnd.is_null() || unsafe { IsWindow(hwnd) } == 0 {
+        return None;
+    }
+
+    let root = unsafe { GetAncestor(hwnd, GA_ROOT) };
+    let target = if root.is_null() { hwnd } else { root };
+    let mut buffer = [0u16; 512];
+    let title_len = unsafe { GetWindowTextW(target, buffer.as_mut_ptr(), buffer.len() as i32) };
+    if title_len <= 0 {
+        return None;
+    }
+
+    let title = String::from_utf16_lossy(&buffer[..title_len as usize])
+        .trim()
+        .to_string();
+    (!title.is_empty()).then_some(title)
+}
+
+#[cfg(target_os = "windows")]
+fn window_process_identity(hwnd_raw: isize) -> (Option<String>, Option<String>) {
+    let hwnd = hwnd_raw as HWND;
+    if hwnd_raw == 0 || hwnd.is_null() || unsafe { IsWindow(hwnd) } == 0 {
+        return (None, None);
+    }
+
+    let mut process_id = 0u32;
+    unsafe {
+        GetWindowThreadProcessId(hwnd, &mut process_id as *mut u32);
+    }
+    if process_id == 0 {
+        return (None, None);
+    }
+
+    let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, process_id) };
+    if handle.is_null() {
+        return (None, None);
+    }
+
+    let mut buffer = vec![0u16; 2048];
+    let mut length = buffer.len() as u32;
+    let success =
+        unsafe { QueryFullProcessImageNameW(handle, 0, buffer.as_mut_ptr(), &mut length) } != 0;
+    unsafe {
+        CloseHandle(handle);
+    }
+
+    if !success || length == 0 {
+        return (None, None);
+    }
+
+    let full_path = String::from_utf16_lossy(&buffer[..length as usize]).to_ascii_lowercase();
+    let executable = Path::new(&full_path)
+        .file_name()
+        .map(|value| value.to_string_lossy().to_ascii_lowercase());
+    (executable, Some(full_path))
+}
+
+#[cfg(target_os = "windows")]
+fn is_window_blocked_by_apps(hwnd_raw: isize, blocked_apps: &[String]) -> bool {
+    if blocked_apps.is_empty() || hwnd_raw == 0 {
+        return false;
+    }
+
+    let class_name = window_class_name(hwnd_raw).map(|value| value.to_ascii_lowercase());
+    let (process_name, process_path) = window_process_identity(hwnd_raw);
+
+    blocked_apps.iter().any(|item| {
+        let pattern = item.trim().to_ascii_lowercase();
+        if pattern.is_empty() {
+            return false;
+        }
+        class_name
+            .as_deref()
+            .is_some_and(|value| value.contains(&pattern))
+            || process_name
+                .as_deref()
+                .is_some_and(|value| value.contains(&pattern))
+            || process_path
+                .as_deref()
+                .is_some_and(|value| value.contains(&pattern))
+    })
+}
+
+#[cfg(not(target_os = "windows"))]
+fn is_window_blocked_by_apps(_hwnd_raw: isize, _blocked_apps: &[String]) -> bool {
+    false
+}
+
+#[cfg(target_os = "windows")]
+fn matching_app_rule(hwnd_raw: isize, rules: &[AppSelectionRule]) -> Option<AppSelectionRule> {
+    if hwnd_raw == 0 || !rules.iter().any(|rule| rule.enabled) {
+        return None;
+    }
+
+    let (process_name, process_path) = window_process_identity(hwnd_raw);
+    rules
+        .iter()
+        .filter(|rule| rule.enabled && !rule.app.is_empty())
+        .find(|rule| {
+            process_name
+                .as_deref()
+                .is_some_and(|value| value.contains(&rule.app))
+                || process_path
+                    .as_deref()
+                    .is_some_and(|value| value.contains(&rule.app))
+        })
+        .cloned()
+}
+
+#[cfg(not(target_os = "windows"))]
+fn matching_app_rule(_hwnd_raw: isize, _rules: &[AppSelectionRule]) -> Option<AppSelectionRule> {
+    None
+}
+
+#[cfg(target_os = "windows")]
+fn is_left_mouse_pressed() -> bool {
+    (unsafe { GetAsyncKeyState(VK_LBUTTON as i32) } as u16 & 0x8000) != 0
+}
+
+#[cfg(not(target_os = "windows"))]
+fn is_left_mouse_pressed() -> bool {
+    false
+}
+
+#[cfg(target_os = "windows")]
+fn is_escape_pressed() -> bool {
+    (unsafe { GetAsyncKeyState(VK_ESCAPE as i32) } as u16 & 0x8000) != 0
+}
+
+#[cfg(not(target_os = "windows"))]
+fn is_escape_pressed() -> bool {
+    false
+}
+
+#[cfg(target_os = "windows")]
+fn is_ctrl_pressed() -> bool {
+    (unsafe { GetAsyncKeyState(VK_CONTROL as i32) } as u16 & 0x8000) != 0
+}
+
+#[cfg(not(target_os = "windows"))]
+fn is_ctrl_pressed() -> bool {
+    false
+}
+
+#[cfg(target_os = "windows")]
+fn extended_top_chrome_exclusion_height(hwnd_raw: isize) -> i32 {
+    let Some(class_name) = window_class_name(hwnd_raw).map(|value| value.to_ascii_uppercase())
+    else {
+        return 0;
+    };
+
+    if class_name.contains("CHROME_WIDGETWIN") {
+        88
+    } else if class_name.contains("MOZILLAWINDOWCLASS") {
+        92
+    } else if class_name.contains("CABINETWCLASS") || class_name.contains("EXPLOREWCLASS") {
+        84
+    } else if class_name.contains("APPLICATIONFRAMEWINDOW") {
+        72
+    } else {
+        0
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn text_selection_surface_confidence_for_class(class_name: &str) -> TextSelectionSurfaceConfidence {
+    let class_name = class_name.to_ascii_uppercase();
+    if class_name.contains("EDIT")
+        || class_name.contains("RICHEDIT")
+        || class_name.contains("SCINTILLA")
+    {
+        TextSelectionSurfaceConfidence::Strong
+    } else if class_name.contains("RENDERWIDGET")
+        || class_name.contains("CHROME_RENDERWIDGETHOSTHWND")
+        || class_name.contains("WEBVIEW")
+        || class_name.contains("INTERNET EXPLORER_SERVER")
+        || class_name.contains("CEF")
+        || class_name.contains("DIRECTUIHWND")
+    {
+        TextSelectionSurfaceConfidence::Weak
+    } else {
+        TextSelectionSurfaceConfidence::None
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn window_client_top(hwnd_raw: isize) -> Option<i32> {
+    if hwnd_raw == 0 {
+        return None;
+    }
+
+    let hwnd = hwnd_raw as HWND;
+    if hwnd.is_null() || unsafe { IsWindow(hwnd) } == 0 {
+        return None;
+    }
+
+    let mut window_info = WINDOWINFO {
+        cbSize: std::mem::size_of::<WINDOWINFO>() as u32,
+        rcWindow: RECT {
+            left: 0,
+            top: 0,
+            right: 0,
+            bottom: 0,
+        },
+        rcClient: RECT {
+            left: 0,
+            top: 0,
+            right: 0,
+            bottom: 0,
+        },
+        dwStyle: 0,
+        dwExStyle: 0,
+        dwWindowStatus: 0,
+        cxWindowBorders: 0,
+        cyWindowBorders: 0,
+        atomWindowType: 0,
+        wCreatorVersion: 0,
+    };
+    if unsafe { GetWindowInfo(hwnd, &mut window_info as *mut WINDOWINFO) } == 0 {
+        return None;
+    }
+
+    Some(window_info.rcClient.top)
+}
+
+#[cfg(target_os = "windows")]
+fn text_selection_surface_confidence_at_point(
+    hwnd_raw: isize,
+    point: PhysicalPosition<i32>,
+) -> TextSelectionSurfaceConfidence {
+    if hwnd_raw == 0 {
+        return TextSelectionSurfaceConfidence::None;
+    }
+
+    let hwnd = hwnd_raw as HWND;
+    if hwnd.is_null() || unsafe { IsWindow(hwnd) } == 0 {
+        return TextSelectionSurfaceConfidence::None;
+    }
+
+    let mut rect = RECT {
+        left: 0,
+        top: 0,
+        right: 0,
+        bottom: 0,
+    };
+    if unsafe { GetWindowRect(hwnd, &mut rect as *mut RECT) } == 0 {
+        return TextSelectionSurfaceConfidence::None;
+    }
+
+    if point.x < rect.left || point.x >= rect.right || point.y < rect.top || point.y >= rect.bottom
+    {
+        return TextSelectionSurfaceConfidence::None;
+    }
+
+    if window_client_top(hwnd_raw).is_some_and(|client_top| point.y < client_top) {
+        return TextSelectionSurfaceConfidence::None;
     }
 
     if is_console_like_window(hwnd_raw) {
         return TextSelectionSurfaceConfidence::Strong;
     }
 
-    let extended_top_band_height = extended_top_chrome_exclusion_height(hwnd_raw);
-    if extended_top_band_height > 0 && point.y < rect.top.saturating_add(extended_top_band_height) {
-        return TextSelectionSurfaceConfidence::None;
+    let extended_top_band_height = extended_top_chrome_exclusion_height(hwnd_raw);
+    if extended_top_band_height > 0 && point.y < rect.top.saturating_add(extended_top_band_height) {
+        return TextSelectionSurfaceConfidence::None;
+    }
+
+    let hit_hwnd = unsafe {
+        WindowFromPoint(POINT {
+            x: point.x,
+            y: point.y,
+        })
+    };
+    if hit_hwnd.is_null() {
+        return TextSelectionSurfaceConfidence::None;
+    }
+
+    let hit_root = unsafe { GetAncestor(hit_hwnd, GA_ROOT) };
+    if !hit_root.is_null() && hit_root != hwnd {
+        return TextSelectionSurfaceConfidence::None;
+    }
+
+    let hit_raw = hit_hwnd as isize;
+    let hit_class_name = window_class_name(hit_raw);
+    let root_class_name = window_class_name(hwnd_raw);
+    let hit_confidence = hit_class_name
+        .as_deref()
+        .map(text_selection_surface_confidence_for_class)
+        .unwrap_or(TextSelectionSurfaceConfidence::None);
+    let root_confidence = root_class_name
+        .as_deref()
+        .map(text_selection_surface_confidence_for_class)
+        .unwrap_or(TextSelectionSurfaceConfidence::None);
+
+    if hit_raw == hwnd_raw {
+        return root_confidence;
+    }
+
+    if hit_confidence != TextSelectionSurfaceConfidence::None {
+        return hit_confidence;
+    }
+
+    if hit_class_name.is_some() && hit_class_name == root_class_name {
+        return root_confidence;
+    }
+
+    root_confidence
+}
+
+#[cfg(not(target_os = "windows"))]
+fn extended_top_chrome_exclusion_height(_hwnd_raw: isize) -> i32 {
+    0
+}
+
+#[cfg(not(target_os = "windows"))]
+fn text_selection_surface_confidence_for_class(
+    _class_name: &str,
+) -> TextSelectionSurfaceConfidence {
+    TextSelectionSurfaceConfidence::None
+}
+
+#[cfg(not(target_os = "windows"))]
+fn window_client_top(_hwnd_raw: isize) -> Option<i32> {
+    None
+}
+
+#[cfg(not(target_os = "windows"))]
+fn text_selection_surface_confidence_at_point(
+    _hwnd_raw: isize,
+    _point: PhysicalPosition<i32>,
+) -> TextSelectionSurfaceConfidence {
+    TextSelectionSurfaceConfidence::Weak
+}
+
+fn capture_clipboard_snapshot(clipboard: &mut Clipboard) -> ClipboardSnapshot {
+    if let Ok(text) = clipboard.get_text() {
+        return ClipboardSnapshot::Text(text);
+    }
+
+    if let Ok(image) = clipboard.get_image() {
+        return ClipboardSnapshot::Image(ClipboardImageSnapshot {
+            width: image.width,
+            height: image.height,
+            bytes: image.bytes.into_owned(),
+        });
+    }
+
+    ClipboardSnapshot::Empty
+}
+
+fn restore_clipboard_snapshot(
+    clipboard: &mut Clipboard,
+    snapshot: &ClipboardSnapshot,
+) -> Result<(), CommandError> {
+    match snapshot {
+        ClipboardSnapshot::Empty => Ok(()),
+        ClipboardSnapshot::Text(text) => clipboard
+            .set_text(text.clone())
+            .map_err(|error| CommandError::Clipboard(error.to_string())),
+        ClipboardSnapshot::Image(image) => clipboard
+            .set_image(ImageData {
+                width: image.width,
+                height: image.height,
+                bytes: Cow::Owned(image.bytes.clone()),
+            })
+            .map_err(|error| CommandError::Clipboard(error.to_string())),
+    }
+}
+
+fn read_clipboard_text_trimmed() -> Option<String> {
+    let mut clipboard = Clipboard::new().ok()?;
+    let value = clipboard.get_text().ok()?;
+    let trimmed = value.trim().to_string();
+    if trimmed.is_empty() {
+        None
+    } else {
+        Some(trimmed)
+    }
+}
+
+fn write_clipboard_text_with_retry(value: &str) -> Result<(), CommandError> {
+    let mut last_error: Option<String> = None;
+    for _ in 0..20 {
+        let mut clipboard = match Clipboard::new() {
+            Ok(instance) => instance,
+            Err(error) => {
+                last_error = Some(error.to_string());
+                std::thread::sleep(Duration::from_millis(12));
+                continue;
+            }
+        };
+
+        match clipboard.set_text(value.to_string()) {
+            Ok(()) => return Ok(()),
+            Err(arboard::Error::ClipboardOccupied) => {
+                last_error = Some("ClipboardOccupied".to_string());
+                std::thread::sleep(Duration::from_millis(12));
+            }
+            Err(error) => {
+                last_error = Some(error.to_string());
+                std::thread::sleep(Duration::from_millis(12));
+            }
+        }
+    }
+
+    Err(CommandError::Clipboard(last_error.unwrap_or_else(|| {
+        "failed to write clipboard text".to_string()
+    })))
+}
+
+#[cfg(target_os = "windows")]
+fn try_capture_console_selection_for_copy(hwnd_raw: isize, fallback_text: &str) -> Option<String> {
+    if hwnd_raw == 0 || !is_console_like_window(hwnd_raw) {
+        return None;
+    }
+
+    let before_sequence = clipboard_sequence_number();
+    if send_console_copy_shortcut(hwnd_raw).is_err() {
+        return None;
+    }
+
+    for _ in 0..8 {
+        std::thread::sleep(Duration::from_millis(10));
+        let Some(candidate) = read_clipboard_text_trimmed() else {
+            continue;
+        };
+        if candidate.is_empty() {
+            continue;
+        }
+
+        let sequence_changed = clipboard_sequence_number() != before_sequence;
+        if sequence_changed || candidate != fallback_text {
+            return Some(candidate);
+        }
+    }
+
+    None
+}
+
+#[cfg(not(target_os = "windows"))]
+fn try_capture_console_selection_for_copy(
+    _hwnd_raw: isize,
+    _fallback_text: &str,
+) -> Option<String> {
+    None
+}
+
+#[cfg(target_os = "windows")]
+fn clipboard_sequence_number() -> u32 {
+    unsafe { GetClipboardSequenceNumber() }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn clipboard_sequence_number() -> u32 {
+    0
+}
+
+fn capture_console_selection_without_shortcut(
+    hwnd_raw: isize,
+    before_text: Option<&str>,
+) -> Option<(String, bool)> {
+    let normalized_before = before_text.map(|value| value.trim()).unwrap_or("");
+    let before_sequence = clipboard_sequence_number();
+
+    // Some terminals update clipboard a little after mouse release.
+    // Poll briefly to avoid falling back to synthetic copy shortcuts.
+    for _ in 0..12 {
+        if let Some(after_text) = read_clipboard_text_trimmed() {
+            if normalized_before != after_text {
+                return Some((after_text, true));
+            }
+        }
+        std::thread::sleep(Duration::from_millis(9));
+    }
+
+    if let Some(captured) = try_capture_console_selection_for_copy(hwnd_raw, normalized_before) {
+        return Some((captured, true));
+    }
+
+    if let Some(after_text) = read_clipboard_text_trimmed() {
+        if clipboard_sequence_number() != before_sequence || after_text != normalized_before {
+            return Some((after_text, true));
+        }
+    }
+
+    None
+}
+
+fn capture_selected_text_once(hwnd_raw: isize) -> Result<(String, bool), CommandError> {
+    let mut clipboard =
+        Clipboard::new().map_err(|error| CommandError::Clipboard(error.to_string()))?;
+    let snapshot = capture_clipboard_snapshot(&mut clipboard);
+    let before_text = match &snapshot {
+        ClipboardSnapshot::Text(text) => Some(text.trim().to_string()),
+        _ => None,
+    };
+    let before_sequence = clipboard_sequence_number();
+
+    send_system_copy_shortcut(hwnd_raw)?;
+    let mut text = String::new();
+    let mut changed = false;
+    for _ in 0..20 {
+        std::thread::sleep(Duration::from_millis(18));
+        let candidate = match clipboard.get_text() {
+            Ok(value) => value.trim().to_string(),
+            Err(arboard::Error::ContentNotAvailable) => continue,
+            Err(arboard::Error::ClipboardOccupied) => continue,
+            Err(_) => continue,
+        };
+        if candidate.is_empty() {
+            continue;
+        }
+
+        let sequence_changed = clipboard_sequence_number() != before_sequence;
+        let text_changed = before_text
+            .as_ref()
+            .map(|previous| previous != &candidate)
+            .unwrap_or(true);
+
+        text = candidate;
+        changed = sequence_changed || text_changed;
+        if changed {
+            break;
+        }
+    }
+    let _ = restore_clipboard_snapshot(&mut clipboard, &snapshot);
+
+    if text.is_empty() {
+        return Err(CommandError::Settings(
+            "No selectable text captured".to_string(),
+        ));
+    }
+
+    Ok((text, changed))
+}
+
+/// Pastes `text` over the selection in the source window, then puts the user's previous
+/// clipboard contents back.
+fn paste_text_into_window(hwnd_raw: isize, text: &str) -> Result<(), CommandError> {
+    if !restore_foreground_window(hwnd_raw) {
+        return Err(CommandError::Settings("无法切换回划词来源窗口".to_string()));
+    }
+
+    let mut clipboard =
+        Clipboard::new().map_err(|error| CommandError::Clipboard(error.to_string()))?;
+    let snapshot = capture_clipboard_snapshot(&mut clipboard);
+    clipboard
+        .set_text(text.to_string())
+        .map_err(|error| CommandError::Clipboard(error.to_string()))?;
+    let pasted = send_system_paste_shortcut(hwnd_raw);
+    std::thread::sleep(Duration::from_millis(REPLACE_CLIPBOARD_RESTORE_DELAY_MS));
+    let _ = restore_clipboard_snapshot(&mut clipboard, &snapshot);
+    pasted
+}
+
+fn is_link_text(content: &str) -> bool {
+    let value = content.trim().to_ascii_lowercase();
+    value.starts_with("http://")
+        || value.starts_with("https://")
+        || value.starts_with("ftp://")
+        || value.starts_with("mailto:")
+}
+
+fn build_text_entry(content: String) -> ClipboardEntry {
+    let normalized = truncate_text_chars(content.trim(), MAX_CLIPBOARD_TEXT_CHARS);
+    let kind = if is_link_text(&normalized) {
+        ClipboardKind::Link
+    } else {
+        ClipboardKind::Text
+    };
+
+    ClipboardEntry {
+        id: now_id(),
+        kind,
+        content: normalized,
+        image_data_url: None,
+        thumbnail_data_url: None,
+        copied_at: Utc::now(),
+        pinned: false,
+    }
+}
+
+fn build_image_entry(image: ImageData<'static>) -> Result<ClipboardEntry, CommandError> {
+    let width = image.width as u32;
+    let height = image.height as u32;
+    let bytes = image.bytes.into_owned();
+
+    let rgba = RgbaImage::from_raw(width, height, bytes).ok_or_else(|| {
+        CommandError::InvalidImage("Invalid RGBA bytes for clipboard image".to_string())
+    })?;
+
+    // Generate original base64
+    let mut original_cursor = Cursor::new(Vec::new());
+    DynamicImage::ImageRgba8(rgba.clone())
+        .write_to(&mut original_cursor, ImageFormat::Png)
+        .map_err(|error| CommandError::InvalidImage(error.to_string()))?;
+    let original_encoded = BASE64.encode(original_cursor.into_inner());
+    let original_data_url = format!("data:image/png;base64,{original_encoded}");
+
+    if original_data_url.len() > MAX_CLIPBOARD_IMAGE_DATA_URL_CHARS {
+        return Err(CommandError::InvalidImage(format!(
+            "Clipboard image too large ({} bytes), skipped",
+            original_data_url.len()
+        )));
+    }
+
+    // Generate 120px thumbnail
+    let thumbnail_data_url = if width > MAX_THUMBNAIL_WIDTH {
+        let ratio = MAX_THUMBNAIL_WIDTH as f32 / width as f32;
+        let thumb_width = MAX_THUMBNAIL_WIDTH;
+        let thumb_height = (height as f32 * ratio) as u32;
+        let thumb = image::imageops::resize(
+            &rgba,
+            thumb_width,
+            thumb_height,
+            image::imageops::FilterType::Lanczos3,
+        );
+        let mut thumb_cursor = Cursor::new(Vec::new());
+        DynamicImage::ImageRgba8(thumb)
+            .write_to(&mut thumb_cursor, ImageFormat::Png)
+            .map_err(|error| CommandError::InvalidImage(error.to_string()))?;
+        let thumb_encoded = BASE64.encode(thumb_cursor.into_inner());
+        let thumb_data_url = format!("data:image/png;base64,{thumb_encoded}");
+        if thumb_data_url.len() <= MAX_THUMBNAIL_CHARS {
+            Some(thumb_data_url)
+        } else {
+            None
+        }
+    } else {
+        None
+    };
+
+    Ok(ClipboardEntry {
+        id: now_id(),
+        kind: ClipboardKind::Image,
+        content: format!("Image {}x{}", width, height),
+        image_data_url: Some(original_data_url),
+        thumbnail_data_url,
+        copied_at: Utc::now(),
+        pinned: false,
+    })
+}
+
+fn entry_matches(a: &ClipboardEntry, b: &ClipboardEntry) -> bool {
+    if a.kind != b.kind {
+        return false;
+    }
+
+    if a.kind == ClipboardKind::Image {
+        return a.image_data_url == b.image_data_url;
+    }
+
+    a.content == b.content
+}
+
+fn entry_signature(entry: &ClipboardEntry) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    entry.kind.hash(&mut hasher);
+    match entry.kind {
+        ClipboardKind::Image => entry
+            .image_data_url
+            .as_deref()
+            .unwrap_or("")
+            .hash(&mut hasher),
+        ClipboardKind::Text | ClipboardKind::Link => entry.content.hash(&mut hasher),
+    }
+    hasher.finish()
+}
+
+fn collect_history(history: &VecDeque<ClipboardEntry>) -> Vec<ClipboardEntry> {
+    history.iter().cloned().collect::<Vec<_>>()
+}
+
+fn looks_like_internal_clipboard_image_path(value: &str) -> bool {
+    let normalized = value.trim().trim_matches('"').to_ascii_lowercase();
+    if normalized.is_empty() {
+        return false;
+    }
+
+    let has_internal_dir = normalized.contains("\\clipboard_images\\snapparse_")
+        || normalized.contains("/clipboard_images/snapparse_");
+    if !has_internal_dir {
+        return false;
+    }
+
+    normalized.ends_with(".png")
+        || normalized.ends_with(".jpg")
+        || normalized.ends_with(".jpeg")
+        || normalized.ends_with(".webp")
+        || normalized.ends_with(".bmp")
+        || normalized.ends_with(".gif")
+}
+
+fn dedupe_history_entries(history: &mut VecDeque<ClipboardEntry>) -> bool {
+    if history.len() < 2 {
+        return false;
+    }
+
+    let original_len = history.len();
+    let mut changed = false;
+    let mut compacted = VecDeque::<ClipboardEntry>::new();
+
+    for entry in history.iter().cloned() {
+        if let Some(existing) = compacted
+            .iter_mut()
+            .find(|existing| entry_matches(existing, &entry))
+        {
+            let merged_pinned = existing.pinned || entry.pinned;
+            if existing.pinned != merged_pinned {
+                existing.pinned = merged_pinned;
+            }
+            changed = true;
+            continue;
+        }
+        compacted.push_back(entry);
+    }
+
+    if compacted.len() != original_len {
+        changed = true;
+    }
+
+    if changed {
+        *history = compacted;
+        normalize_history_order(history);
+    }
+    changed
+}
+
+fn decode_image_data_url_bytes(data_url: &str) -> Result<(String, Vec<u8>), CommandError> {
+    let trimmed = data_url.trim();
+    let (header, payload) = trimmed
+        .split_once(',')
+        .ok_or_else(|| CommandError::InvalidImage("Invalid image data URL".to_string()))?;
+    let lowered_header = header.to_ascii_lowercase();
+    if !lowered_header.starts_with("data:image/") || !lowered_header.contains(";base64") {
+        return Err(CommandError::InvalidImage(
+            "Unsupported image data URL format".to_string(),
+        ));
+    }
+
+    let mime_type = header
+        .trim_start_matches("data:")
+        .split(';')
+        .next()
+        .unwrap_or("image/png")
+        .to_ascii_lowercase();
+    let bytes = BASE64
+        .decode(payload.trim())
+        .map_err(|error| CommandError::InvalidImage(error.to_string()))?;
+    if bytes.is_empty() {
+        return Err(CommandError::InvalidImage(
+            "Image payload is empty".to_string(),
+        ));
+    }
+    Ok((mime_type, bytes))
+}
+
+fn extension_from_image_mime(mime_type: &str) -> &'static str {
+    match mime_type {
+        "image/png" => "png",
+        "image/jpeg" | "image/jpg" => "jpg",
+        "image/webp" => "webp",
+        "image/bmp" => "bmp",
+        "image/gif" => "gif",
+        _ => "png",
+    }
+}
+
+fn sanitize_clip_filename_token(value: &str) -> String {
+    let mut token = value
+        .chars()
+        .map(|ch| {
+            if ch.is_ascii_alphanumeric() || ch == '-' || ch == '_' {
+                ch
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    if token.is_empty() {
+        token = "clip".to_string();
+    }
+    token
+}
+
+fn resolve_clip_image_export_dir<R: Runtime>(
+    app: &AppHandle<R>,
+    settings: &AppSettings,
+) -> Result<PathBuf, CommandError> {
+    let history_path = resolve_history_file_path(app, settings)?;
+    let parent = history_path
+        .parent()
+        .map(Path::to_path_buf)
+        .ok_or_else(|| CommandError::Settings("Invalid history storage directory".to_string()))?;
+    let export_dir = parent.join("clipboard_images");
+    fs::create_dir_all(&export_dir).map_err(|error| CommandError::Settings(error.to_string()))?;
+    Ok(export_dir)
+}
+
+fn format_path_for_terminal_input(path: &Path) -> String {
+    let rendered = path.to_string_lossy().to_string();
+    if rendered.chars().any(|ch| ch.is_whitespace()) {
+        format!("\"{rendered}\"")
+    } else {
+        rendered
+    }
+}
+
+fn materialize_clipboard_image_path_for_terminal<R: Runtime>(
+    app: &AppHandle<R>,
+    settings: &AppSettings,
+    entry: &ClipboardEntry,
+) -> Result<String, CommandError> {
+    let data_url = entry
+        .image_data_url
+        .as_deref()
+        .ok_or_else(|| CommandError::InvalidImage("Missing image payload".to_string()))?;
+    let (mime_type, bytes) = decode_image_data_url_bytes(data_url)?;
+    let extension = extension_from_image_mime(&mime_type);
+    let export_dir = resolve_clip_image_export_dir(app, settings)?;
+    let file_name = format!(
+        "snapparse_{}_{}.{}",
+        now_epoch_millis(),
+        sanitize_clip_filename_token(&entry.id),
+        extension
+    );
+    let file_path = export_dir.join(file_name);
+    fs::write(&file_path, bytes).map_err(|error| CommandError::Settings(error.to_string()))?;
+    Ok(format_path_for_terminal_input(&file_path))
+}
+
+fn apply_entry_to_clipboard(
+    clipboard: &mut Clipboard,
+    target: &ClipboardEntry,
+) -> Result<(), CommandError> {
+    match target.kind {
+        ClipboardKind::Image => {
+            let data_url = target
+                .image_data_url
+                .clone()
+                .ok_or_else(|| CommandError::InvalidImage("Missing image payload".to_string()))?;
+            let image_data = to_image_data(&data_url)?;
+            clipboard
+                .set_image(image_data)
+                .map_err(|error| CommandError::Clipboard(error.to_string()))?;
+        }
+        ClipboardKind::Text | ClipboardKind::Link => {
+            clipboard
+                .set_text(target.content.clone())
+                .map_err(|error| CommandError::Clipboard(error.to_string()))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn trim_history(history: &mut VecDeque<ClipboardEntry>, max_items: usize) {
+    while history.len() > max_items {
+        if let Some(index) = history.iter().rposition(|entry| !entry.pinned) {
+            history.remove(index);
+        } else {
+            // All remaining items are pinned, remove the oldest pinned item
+            // to make room for the new entry
+            if let Some(index) = history.iter().rposition(|_| true) {
+                history.remove(index);
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+fn normalize_history_order(history: &mut VecDeque<ClipboardEntry>) {
+    let mut items = history.make_contiguous().to_vec();
+    items.sort_by_key(|item| std::cmp::Reverse(item.copied_at));
+    *history = VecDeque::from(items);
+}
+
+fn insert_or_promote(
+    history: &mut VecDeque<ClipboardEntry>,
+    mut incoming: ClipboardEntry,
+    max_items: usize,
+    dedupe: bool,
+) {
+    incoming.copied_at = Utc::now();
+
+    if dedupe {
+        if let Some(idx) = history
+            .iter()
+            .position(|entry| entry_matches(entry, &incoming))
+        {
+            if let Some(mut existing) = history.remove(idx) {
+                existing.copied_at = Utc::now();
+                existing.pinned = existing.pinned || incoming.pinned;
+                history.push_front(existing);
+            }
+        } else {
+            history.push_front(incoming);
+        }
+    } else {
+        history.push_front(incoming);
+    }
+
+    trim_history(history, max_items);
+    normalize_history_order(history);
+}
+
+fn capture_kind_allowed(kind: ClipboardKind, settings: &HistorySettings) -> bool {
+    match kind {
+        ClipboardKind::Text => settings.capture_text,
+        ClipboardKind::Link => settings.capture_link,
+        ClipboardKind::Image => settings.capture_image,
+    }
+}
+
+fn target_position_with_memory<R: Runtime>(
+    app: &AppHandle<R>,
+    remember_position: bool,
+    saved_x: Option<i32>,
+    saved_y: Option<i32>,
+    width: u32,
+    height: u32,
+) -> Option<PhysicalPosition<i32>> {
+    let follow_pointer = || -> Option<PhysicalPosition<i32>> {
+        let pointer = current_pointer_position()?;
+        let candidate =
+            PhysicalPosition::new(pointer.x.saturating_add(14), pointer.y.saturating_add(16));
+        let monitor = monitor_for_point(app, pointer)?;
+        Some(clamp_position_to_monitor(
+            &monitor, candidate, width, height,
+        ))
+    };
+
+    if remember_position {
+        if let (Some(x), Some(y)) = (saved_x, saved_y) {
+            let candidate = PhysicalPosition::new(x, y);
+            let monitor =
+                monitor_for_point(app, candidate).or_else(|| app.primary_monitor().ok().flatten());
+            return monitor
+                .map(|monitor| clamp_position_to_monitor(&monitor, candidate, width, height))
+                .or(Some(candidate));
+        }
+    }
+
+    follow_pointer()
+}
+
+fn main_window_target_position<R: Runtime>(
+    app: &AppHandle<R>,
+    settings: &AppSettings,
+) -> Option<PhysicalPosition<i32>> {
+    let width = clamp_main_window_width(
+        settings
+            .main_window_width
+            .unwrap_or(DEFAULT_MAIN_WINDOW_WIDTH),
+    );
+    let height = clamp_main_window_height(
+        settings
+            .main_window_height
+            .unwrap_or(DEFAULT_MAIN_WINDOW_HEIGHT),
+    );
+
+    target_position_with_memory(
+        app,
+        settings.window.remember_position,
+        settings.main_window_x,
+        settings.main_window_y,
+        width,
+        height,
+    )
+}
+
+fn apply_main_window_position<R: Runtime>(app: &AppHandle<R>) {
+    let Some(settings_state) = app.try_state::<AppSettingsState>() else {
+        return;
+    };
+
+    let snapshot = match settings_state.data.lock() {
+        Ok(settings) => settings.clone(),
+        Err(_) => return,
+    };
+
+    let Some(position) = main_window_target_position(app, &snapshot) else {
+        return;
+    };
+
+    if let Some(window) = app.get_webview_window(MAIN_WINDOW_LABEL) {
+        let _ = window.set_position(Position::Physical(position));
+    }
+
+    if snapshot.window.remember_position
+        && (snapshot.main_window_x != Some(position.x)
+            || snapshot.main_window_y != Some(position.y))
+    {
+        if let Ok(mut settings) = settings_state.data.lock() {
+            settings.main_window_x = Some(position.x);
+            settings.main_window_y = Some(position.y);
+        }
+        let _ = persist_settings_state(&settings_state);
+    }
+}
+
+fn selection_bar_width_for_settings(settings: &AppSettings, bar_items: &[String]) -> u32 {
+    const GAP_NORMAL: u32 = 4;
+    const GAP_COMPACT: u32 = 3;
+    const SHELL_PADDING_NORMAL: u32 = 22;
+    const SHELL_PADDING_COMPACT: u32 = 18;
+    const BRAND_WIDTH: u32 = 16;
+    const ITEM_COMPACT_WIDTH: u32 = 26;
+    const ITEM_ICON_WIDTH: u32 = 13;
+    const ITEM_TEXT_GAP: u32 = 4;
+    const ITEM_PADDING_X: u32 = 18;
+    const WIDTH_SAFETY_BUFFER: u32 = 64;
+    let compact_mode = settings.selection_assistant.compact_mode;
+
+    let estimate_label_width = |label: &str| -> u32 {
+        let mut width = 0f32;
+        for ch in label.chars() {
+            width += if is_cjk_char(ch) {
+                11.4
+            } else if ch.is_ascii_uppercase() {
+                7.6
+            } else if ch.is_ascii_lowercase() {
+                6.8
+            } else if ch.is_ascii_digit() {
+                6.5
+            } else if ch.is_ascii_whitespace() {
+                3.8
+            } else {
+                7.8
+            };
+        }
+        width.ceil() as u32
+    };
+
+    let item_width_for_label = |label: &str| -> u32 {
+        if compact_mode {
+            return ITEM_COMPACT_WIDTH;
+        }
+        let label_width = estimate_label_width(label).clamp(12, 128);
+        ITEM_PADDING_X
+            .saturating_add(ITEM_ICON_WIDTH)
+            .saturating_add(ITEM_TEXT_GAP)
+            .saturating_add(label_width)
+    };
+
+    let mut action_count = 0u32;
+    let mut action_width = 0u32;
+
+    for key in bar_items {
+        if is_builtin_selection_bar_key(key) {
+            action_count = action_count.saturating_add(1);
+            let label = match key.as_str() {
+                "copy" => "复制",
+                "summarize" => "总结",
+                "polish" => "优化",
+                "explain" => "解释",
+                "translate" => "翻译",
+                "search" => "搜索",
+                POLISH_REPLACE_BAR_KEY => "优化替换",
+                _ => "",
+            };
+            action_width = action_width.saturating_add(item_width_for_label(label));
+            continue;
+        }
+
+        let Some(custom_id) = parse_custom_selection_bar_key(key) else {
+            continue;
+        };
+        let Some(agent) = settings
+            .agents
+            .custom
+            .iter()
+            .find(|entry| entry.id == custom_id)
+        else {
+            continue;
+        };
+        action_count = action_count.saturating_add(1);
+        action_width = action_width.saturating_add(item_width_for_label(&agent.name));
+    }
+
+    let item_count = 1u32.saturating_add(action_count);
+    let gap = if compact_mode {
+        GAP_COMPACT
+    } else {
+        GAP_NORMAL
+    };
+    let shell_padding = if compact_mode {
+        SHELL_PADDING_COMPACT
+    } else {
+        SHELL_PADDING_NORMAL
+    };
+    let gap_width = item_count.saturating_sub(1).saturating_mul(gap);
+    let estimated = BRAND_WIDTH
+        .saturating_add(action_width)
+        .saturating_add(gap_width)
+        .saturating_add(shell_padding)
+        .saturating_add(WIDTH_SAFETY_BUFFER);
+
+    if compact_mode {
+        estimated.clamp(148, 760)
+    } else {
+        estimated.clamp(220, 1600)
+    }
+}
+
+fn selection_bar_target_position<R: Runtime>(
+    app: &AppHandle<R>,
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+) -> PhysicalPosition<i32> {
+    let pointer = PhysicalPosition::new(x, y);
+    let candidate = PhysicalPosition::new(x.saturating_add(8), y.saturating_add(10));
+    if let Some(monitor) = monitor_for_point(app, pointer) {
+        clamp_position_to_monitor(&monitor, candidate, width, height)
+    } else {
+        candidate
+    }
+}
+
+fn emit_selection_detected<R: Runtime>(app: &AppHandle<R>, payload: SelectionDetectedPayload) {
+    let _ = app.emit(SELECTION_DETECTED_EVENT, payload);
+}
+
+fn emit_selection_result<R: Runtime>(app: &AppHandle<R>, payload: SelectionResultPayload) {
+    let _ = app.emit(SELECTION_RESULT_UPDATED_EVENT, payload);
+}
+
+fn emit_selection_error<R: Runtime>(app: &AppHandle<R>, message: &str) {
+    let _ = app.emit(SELECTION_ERROR_EVENT, message.to_string());
+}
+
+fn emit_ocr_capture_started<R: Runtime>(app: &AppHandle<R>) {
+    let _ = app.emit(OCR_CAPTURE_STARTED_EVENT, true);
+}
+
+fn emit_ocr_capture_canceled<R: Runtime>(app: &AppHandle<R>) {
+    let _ = app.emit(OCR_CAPTURE_CANCELED_EVENT, true);
+}
+
+fn emit_ocr_result<R: Runtime>(app: &AppHandle<R>, payload: OcrResultPayload) {
+    let _ = app.emit(OCR_RESULT_UPDATED_EVENT, payload);
+}
+
+fn emit_ocr_error<R: Runtime>(app: &AppHandle<R>, message: &str) {
+    let _ = app.emit(OCR_ERROR_EVENT, message.to_string());
+}
+
+fn emit_history_updated<R: Runtime>(app: &AppHandle<R>, items: &[ClipboardEntry]) {
+    let _ = app.emit(HISTORY_UPDATED_EVENT, items.to_vec());
+}
+
+#[cfg(target_os = "windows")]
+fn apply_native_result_window_corner_preference<R: Runtime>(window: &tauri::WebviewWindow<R>) {
+    let Ok(hwnd) = window.hwnd() else {
+        return;
+    };
+    // Use native rounded window corners for stable rendering on Windows.
+    let preference: i32 = DWMWCP_ROUND;
+    unsafe {
+        let _ = DwmSetWindowAttribute(
+            hwnd.0 as HWND,
+            DWMWA_WINDOW_CORNER_PREFERENCE as u32,
+            &preference as *const _ as *const core::ffi::c_void,
+            std::mem::size_of::<i32>() as u32,
+        );
     }
+}
 
-    let hit_hwnd = unsafe {
-        WindowFromPoint(POINT {
-            x: point.x,
-            y: point.y,
-        })
+#[cfg(not(target_os = "windows"))]
+fn apply_native_result_window_corner_preference<R: Runtime>(_window: &tauri::WebviewWindow<R>) {}
+
+#[cfg(target_os = "windows")]
+fn acrylic_tint_alpha_for_settings(settings: &AppSettings) -> u8 {
+    (settings.appearance.window_opacity.clamp(0.0, 0.5) * 255.0).round() as u8
+}
+
+#[cfg(target_os = "windows")]
+fn apply_native_window_acrylic<R: Runtime>(app: &AppHandle<R>, settings: &AppSettings) {
+    let tint_alpha = acrylic_tint_alpha_for_settings(settings);
+    let labels = [
+        MAIN_WINDOW_LABEL,
+        SETTINGS_WINDOW_LABEL,
+        SELECTION_RESULT_WINDOW_LABEL,
+        OCR_RESULT_WINDOW_LABEL,
+    ];
+    for label in labels {
+        if let Some(window) = app.get_webview_window(label) {
+            let _ = window_vibrancy::apply_acrylic(&window, Some((0, 0, 0, tint_alpha)));
+        }
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn apply_native_window_acrylic<R: Runtime>(_app: &AppHandle<R>, _settings: &AppSettings) {}
+
+fn apply_result_windows_background<R: Runtime>(app: &AppHandle<R>, settings: &AppSettings) {
+    apply_native_window_acrylic(app, settings);
+    if let Some(window) = app.get_webview_window(SELECTION_RESULT_WINDOW_LABEL) {
+        apply_native_result_window_corner_preference(&window);
+    }
+    if let Some(window) = app.get_webview_window(OCR_RESULT_WINDOW_LABEL) {
+        apply_native_result_window_corner_preference(&window);
+    }
+}
+
+fn is_any_other_snapparse_window_focused<R: Runtime>(
+    app: &AppHandle<R>,
+    excluded_label: &str,
+) -> bool {
+    [
+        MAIN_WINDOW_LABEL,
+        SETTINGS_WINDOW_LABEL,
+        SELECTION_BAR_WINDOW_LABEL,
+        SELECTION_RESULT_WINDOW_LABEL,
+        OCR_CAPTURE_WINDOW_LABEL,
+        OCR_RESULT_WINDOW_LABEL,
+    ]
+    .iter()
+    .filter(|label| **label != excluded_label)
+    .any(|label| {
+        app.get_webview_window(label)
+            .and_then(|window| window.is_focused().ok())
+            .unwrap_or(false)
+    })
+}
+
+fn should_hide_window_after_blur<R: Runtime>(app: &AppHandle<R>, label: &str) -> bool {
+    let Some(window) = app.get_webview_window(label) else {
+        return false;
     };
-    if hit_hwnd.is_null() {
-        return TextSelectionSurfaceConfidence::None;
+    let still_visible = window.is_visible().unwrap_or(false);
+    let still_unfocused = window.is_focused().map(|focused| !focused).unwrap_or(false);
+    still_visible && still_unfocused && !is_any_other_snapparse_window_focused(app, label)
+}
+
+fn suppress_selection_result_blur_auto_hide<R: Runtime>(app: &AppHandle<R>, delay_ms: u64) {
+    if let Some(runtime) = app.try_state::<SelectionRuntimeState>() {
+        runtime.suppress_result_blur_until_ms.store(
+            now_epoch_millis().saturating_add(delay_ms),
+            Ordering::Relaxed,
+        );
     }
+}
 
-    let hit_root = unsafe { GetAncestor(hit_hwnd, GA_ROOT) };
-    if !hit_root.is_null() && hit_root != hwnd {
-        return TextSelectionSurfaceConfidence::None;
+fn suppress_ocr_result_blur_auto_hide<R: Runtime>(app: &AppHandle<R>, delay_ms: u64) {
+    if let Some(runtime) = app.try_state::<OcrRuntimeState>() {
+        runtime.suppress_result_blur_until_ms.store(
+            now_epoch_millis().saturating_add(delay_ms),
+            Ordering::Relaxed,
+        );
     }
+}
 
-    let hit_raw = hit_hwnd as isize;
-    let hit_class_name = window_class_name(hit_raw);
-    let root_class_name = window_class_name(hwnd_raw);
-    let hit_confidence = hit_class_name
-        .as_deref()
-        .map(text_selection_surface_confidence_for_class)
-        .unwrap_or(TextSelectionSurfaceConfidence::None);
-    let root_confidence = root_class_name
-        .as_deref()
-        .map(text_selection_surface_confidence_for_class)
-        .unwrap_or(TextSelectionSurfaceConfidence::None);
+fn selection_result_window_is_pinned<R: Runtime>(app: &AppHandle<R>) -> bool {
+    let pinned_by_window = app
+        .get_webview_window(SELECTION_RESULT_WINDOW_LABEL)
+        .and_then(|window| {
+            if !window.is_visible().ok().unwrap_or(false) {
+                return None;
+            }
+            window.is_always_on_top().ok()
+        });
+    let pinned_by_settings = app
+        .try_state::<AppSettingsState>()
+        .and_then(|state| {
+            state
+                .data
+                .lock()
+                .ok()
+                .map(|settings| settings.selection_assistant.result_window_always_on_top)
+        })
+        .unwrap_or(false);
+    pinned_by_window.unwrap_or(pinned_by_settings)
+}
 
-    if hit_raw == hwnd_raw {
-        return root_confidence;
+fn ocr_result_window_is_pinned<R: Runtime>(app: &AppHandle<R>) -> bool {
+    let pinned_by_window = app
+        .get_webview_window(OCR_RESULT_WINDOW_LABEL)
+        .and_then(|window| {
+            if !window.is_visible().ok().unwrap_or(false) {
+                return None;
+            }
+            window.is_always_on_top().ok()
+        });
+    let pinned_by_settings = app
+        .try_state::<AppSettingsState>()
+        .and_then(|state| {
+            state
+                .data
+                .lock()
+                .ok()
+                .map(|settings| settings.ocr.result_window_always_on_top)
+        })
+        .unwrap_or(false);
+    pinned_by_window.unwrap_or(pinned_by_settings)
+}
+
+fn show_selection_bar_window<R: Runtime>(
+    app: &AppHandle<R>,
+    payload: &SelectionDetectedPayload,
+) -> Result<(), CommandError> {
+    let Some(window) = app.get_webview_window(SELECTION_BAR_WINDOW_LABEL) else {
+        return Err(CommandError::Settings(
+            "Selection bar window not found".to_string(),
+        ));
+    };
+
+    let mut width = app
+        .try_state::<AppSettingsState>()
+        .and_then(|settings_state| {
+            settings_state
+                .data
+                .lock()
+                .ok()
+                .map(|settings| selection_bar_width_for_settings(&settings, &payload.bar_items))
+        })
+        .unwrap_or(DEFAULT_SELECTION_BAR_WIDTH);
+    let height = SELECTION_BAR_HEIGHT;
+
+    // Keep width within current monitor logical bounds to prevent any horizontal overflow.
+    let pointer = PhysicalPosition::new(payload.x, payload.y);
+    if let Some(monitor) = monitor_for_point(app, pointer) {
+        let monitor_width_px = monitor.size().width;
+        let monitor_logical_width = ((monitor_width_px as f64) / monitor.scale_factor().max(0.1))
+            .floor()
+            .clamp(0.0, u32::MAX as f64) as u32;
+        let max_safe_width = monitor_logical_width.saturating_sub(12).max(180);
+        width = width.min(max_safe_width);
+    }
+
+    // Keep terminal/native selection highlight: show bar without activating it first.
+    if let Some(runtime) = app.try_state::<SelectionRuntimeState>() {
+        bump_selection_bar_close_guard(&runtime);
     }
+    let _ = window.set_focusable(false);
+    let _ = window.set_size(Size::Logical(LogicalSize::new(width as f64, height as f64)));
+    let target = selection_bar_target_position(app, payload.x, payload.y, width, height);
+    let _ = window.set_position(Position::Physical(target));
+    let _ = window.show();
+    let app_handle = app.clone();
+    std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(80));
+        if let Some(window) = app_handle.get_webview_window(SELECTION_BAR_WINDOW_LABEL) {
+            let _ = window.set_focusable(true);
+        }
+    });
+    Ok(())
+}
 
-    if hit_confidence != TextSelectionSurfaceConfidence::None {
-        return hit_confidence;
+fn hide_selection_bar_window<R: Runtime>(app: &AppHandle<R>) {
+    if let Some(runtime) = app.try_state::<SelectionRuntimeState>() {
+        remember_selection_bar_hidden(&runtime);
     }
-
-    if hit_class_name.is_some() && hit_class_name == root_class_name {
-        return root_confidence;
+    if let Some(window) = app.get_webview_window(SELECTION_BAR_WINDOW_LABEL) {
+        let _ = window.hide();
     }
-
-    root_confidence
-}
-
-#[cfg(not(target_os = "windows"))]
-fn extended_top_chrome_exclusion_height(_hwnd_raw: isize) -> i32 {
-    0
 }
 
-#[cfg(not(target_os = "windows"))]
-fn text_selection_surface_confidence_for_class(
-    _class_name: &str,
-) -> TextSelectionSurfaceConfidence {
-    TextSelectionSurfaceConfidence::None
-}
+fn show_selection_result_window<R: Runtime>(app: &AppHandle<R>) -> Result<(), CommandError> {
+    let Some(settings_state) = app.try_state::<AppSettingsState>() else {
+        return Err(CommandError::Settings(
+            "Settings state unavailable".to_string(),
+        ));
+    };
+    let settings_snapshot = settings_state
+        .data
+        .lock()
+        .map(|settings| settings.clone())
+        .unwrap_or_else(|_| AppSettings::default());
+    let always_on_top = settings_snapshot
+        .selection_assistant
+        .result_window_always_on_top;
 
-#[cfg(not(target_os = "windows"))]
-fn window_client_top(_hwnd_raw: isize) -> Option<i32> {
-    None
-}
+    let Some(window) = app.get_webview_window(SELECTION_RESULT_WINDOW_LABEL) else {
+        return Err(CommandError::Settings(
+            "Selection result window not found".to_string(),
+        ));
+    };
 
-#[cfg(not(target_os = "windows"))]
-fn text_selection_surface_confidence_at_point(
-    _hwnd_raw: isize,
-    _point: PhysicalPosition<i32>,
-) -> TextSelectionSurfaceConfidence {
-    TextSelectionSurfaceConfidence::Weak
-}
+    let target_width = clamp_selection_result_window_width(
+        settings_snapshot
+            .selection_result_window_width
+            .unwrap_or(DEFAULT_SELECTION_RESULT_WINDOW_WIDTH),
+    );
+    let target_height = clamp_selection_result_window_height(
+        settings_snapshot
+            .selection_result_window_height
+            .unwrap_or(DEFAULT_SELECTION_RESULT_WINDOW_HEIGHT),
+    );
 
-fn capture_clipboard_snapshot(clipboard: &mut Clipboard) -> ClipboardSnapshot {
-    if let Ok(text) = clipboard.get_text() {
-        return ClipboardSnapshot::Text(text);
+    if settings_snapshot.window.remember_main_window_size {
+        let _ = window.set_size(Size::Logical(LogicalSize::new(
+            target_width as f64,
+            target_height as f64,
+        )));
     }
 
-    if let Ok(image) = clipboard.get_image() {
-        return ClipboardSnapshot::Image(ClipboardImageSnapshot {
-            width: image.width,
-            height: image.height,
-            bytes: image.bytes.into_owned(),
-        });
+    if let Some(target_position) = target_position_with_memory(
+        app,
+        settings_snapshot
+            .selection_assistant
+            .remember_result_window_position,
+        settings_snapshot.selection_result_window_x,
+        settings_snapshot.selection_result_window_y,
+        target_width,
+        target_height,
+    ) {
+        let _ = window.set_position(Position::Physical(target_position));
+        if settings_snapshot
+            .selection_assistant
+            .remember_result_window_position
+            && (settings_snapshot.selection_result_window_x != Some(target_position.x)
+                || settings_snapshot.selection_result_window_y != Some(target_position.y))
+        {
+            if let Ok(mut settings) = settings_state.data.lock() {
+                settings.selection_result_window_x = Some(target_position.x);
+                settings.selection_result_window_y = Some(target_position.y);
+            }
+            let _ = persist_settings_state(&settings_state);
+        }
     }
 
-    ClipboardSnapshot::Empty
+    let _ = window.set_always_on_top(always_on_top);
+    suppress_selection_result_blur_auto_hide(app, RESULT_WINDOW_BLUR_SUPPRESS_MS);
+    let _ = window.show();
+    let _ = window.set_focus();
+    Ok(())
 }
 
-fn restore_clipboard_snapshot(
-    clipboard: &mut Clipboard,
-    snapshot: &ClipboardSnapshot,
-) -> Result<(), CommandError> {
-    match snapshot {
-        ClipboardSnapshot::Empty => Ok(()),
-        ClipboardSnapshot::Text(text) => clipboard
-            .set_text(text.clone())
-            .map_err(|error| CommandError::Clipboard(error.to_string())),
-        ClipboardSnapshot::Image(image) => clipboard
-            .set_image(ImageData {
-                width: image.width,
-                height: image.height,
-                bytes: Cow::Owned(image.bytes.clone()),
-            })
-            .map_err(|error| CommandError::Clipboard(error.to_string())),
+fn show_ocr_capture_window<R: Runtime>(app: &AppHandle<R>) -> Result<(), CommandError> {
+    let Some(window) = app.get_webview_window(OCR_CAPTURE_WINDOW_LABEL) else {
+        return Err(CommandError::Settings(
+            "OCR capture window not found".to_string(),
+        ));
+    };
+
+    let mut target_position: Option<PhysicalPosition<i32>> = None;
+    let mut target_size: Option<tauri::PhysicalSize<u32>> = None;
+    let pointer = current_pointer_position().unwrap_or(PhysicalPosition::new(40, 40));
+    if let Some(monitor) =
+        monitor_for_point(app, pointer).or_else(|| app.primary_monitor().ok().flatten())
+    {
+        let position = *monitor.position();
+        let size = tauri::PhysicalSize::new(monitor.size().width, monitor.size().height);
+        target_position = Some(position);
+        target_size = Some(size);
+        let _ = window.set_position(Position::Physical(position));
+        let _ = window.set_size(Size::Physical(size));
     }
+
+    // Keep source app popups/tooltips alive while drawing OCR region:
+    // show capture overlay without activating/focusing this window.
+    let _ = window.set_focusable(false);
+    let _ = window.show();
+    reinforce_ocr_capture_window_front(app, target_position, target_size);
+    Ok(())
 }
 
-fn read_clipboard_text_trimmed() -> Option<String> {
-    let mut clipboard = Clipboard::new().ok()?;
-    let value = clipboard.get_text().ok()?;
-    let trimmed = value.trim().to_string();
-    if trimmed.is_empty() {
-        None
-    } else {
-        Some(trimmed)
+fn hide_ocr_capture_window<R: Runtime>(app: &AppHandle<R>) {
+    if let Some(window) = app.get_webview_window(OCR_CAPTURE_WINDOW_LABEL) {
+        let _ = window.hide();
     }
 }
 
-fn write_clipboard_text_with_retry(value: &str) -> Result<(), CommandError> {
-    let mut last_error: Option<String> = None;
-    for _ in 0..20 {
-        let mut clipboard = match Clipboard::new() {
-            Ok(instance) => instance,
-            Err(error) => {
-                last_error = Some(error.to_string());
-                std::thread::sleep(Duration::from_millis(12));
-                continue;
-            }
-        };
+fn show_ocr_result_window<R: Runtime>(app: &AppHandle<R>) -> Result<(), CommandError> {
+    let Some(settings_state) = app.try_state::<AppSettingsState>() else {
+        return Err(CommandError::Settings(
+            "Settings state unavailable".to_string(),
+        ));
+    };
+    let settings_snapshot = settings_state
+        .data
+        .lock()
+        .map(|settings| settings.clone())
+        .unwrap_or_else(|_| AppSettings::default());
+    let always_on_top = settings_snapshot.ocr.result_window_always_on_top;
 
-        match clipboard.set_text(value.to_string()) {
-            Ok(()) => return Ok(()),
-            Err(arboard::Error::ClipboardOccupied) => {
-                last_error = Some("ClipboardOccupied".to_string());
-                std::thread::sleep(Duration::from_millis(12));
-            }
-            Err(error) => {
-                last_error = Some(error.to_string());
-                std::thread::sleep(Duration::from_millis(12));
+    let Some(window) = app.get_webview_window(OCR_RESULT_WINDOW_LABEL) else {
+        return Err(CommandError::Settings(
+            "OCR result window not found".to_string(),
+        ));
+    };
+
+    let target_width = clamp_ocr_result_window_width(
+        settings_snapshot
+            .ocr_result_window_width
+            .unwrap_or(DEFAULT_OCR_RESULT_WINDOW_WIDTH),
+    );
+    let target_height = clamp_ocr_result_window_height(
+        settings_snapshot
+            .ocr_result_window_height
+            .unwrap_or(DEFAULT_OCR_RESULT_WINDOW_HEIGHT),
+    );
+
+    if settings_snapshot.window.remember_main_window_size {
+        let _ = window.set_size(Size::Logical(LogicalSize::new(
+            target_width as f64,
+            target_height as f64,
+        )));
+    }
+
+    if let Some(target_position) = target_position_with_memory(
+        app,
+        settings_snapshot.ocr.remember_result_window_position,
+        settings_snapshot.ocr_result_window_x,
+        settings_snapshot.ocr_result_window_y,
+        target_width,
+        target_height,
+    ) {
+        let _ = window.set_position(Position::Physical(target_position));
+        if settings_snapshot.ocr.remember_result_window_position
+            && (settings_snapshot.ocr_result_window_x != Some(target_position.x)
+                || settings_snapshot.ocr_result_window_y != Some(target_position.y))
+        {
+            if let Ok(mut settings) = settings_state.data.lock() {
+                settings.ocr_result_window_x = Some(target_position.x);
+                settings.ocr_result_window_y = Some(target_position.y);
             }
+            let _ = persist_settings_state(&settings_state);
         }
     }
 
-    Err(CommandError::Clipboard(last_error.unwrap_or_else(|| {
-        "failed to write clipboard text".to_string()
-    })))
+    let _ = window.set_always_on_top(always_on_top);
+    suppress_ocr_result_blur_auto_hide(app, RESULT_WINDOW_BLUR_SUPPRESS_MS);
+    let _ = window.show();
+    let _ = window.set_focus();
+    Ok(())
 }
 
-#[cfg(target_os = "windows")]
-fn try_capture_console_selection_for_copy(hwnd_raw: isize, fallback_text: &str) -> Option<String> {
-    if hwnd_raw == 0 || !is_console_like_window(hwnd_raw) {
-        return None;
-    }
+fn deactivate_ocr_capture_runtime(ocr_runtime: &OcrRuntimeState) {
+    ocr_runtime.capture_active.store(false, Ordering::Relaxed);
+    ocr_runtime
+        .suppress_blur_until_ms
+        .store(0, Ordering::Relaxed);
+}
 
-    let before_sequence = clipboard_sequence_number();
-    if send_console_copy_shortcut(hwnd_raw).is_err() {
-        return None;
+fn reset_ocr_capture_runtime_state(ocr_runtime: &OcrRuntimeState) {
+    deactivate_ocr_capture_runtime(ocr_runtime);
+    if let Ok(mut snapshot) = ocr_runtime.capture_snapshot.lock() {
+        *snapshot = None;
     }
+}
 
-    for _ in 0..8 {
-        std::thread::sleep(Duration::from_millis(10));
-        let Some(candidate) = read_clipboard_text_trimmed() else {
-            continue;
-        };
-        if candidate.is_empty() {
-            continue;
-        }
+fn reassert_ocr_capture_window_front<R: Runtime>(
+    app: &AppHandle<R>,
+    target_position: Option<PhysicalPosition<i32>>,
+    target_size: Option<tauri::PhysicalSize<u32>>,
+) {
+    let Some(window) = app.get_webview_window(OCR_CAPTURE_WINDOW_LABEL) else {
+        return;
+    };
 
-        let sequence_changed = clipboard_sequence_number() != before_sequence;
-        if sequence_changed || candidate != fallback_text {
-            return Some(candidate);
-        }
+    if let Some(position) = target_position {
+        let _ = window.set_position(Position::Physical(position));
     }
-
-    None
+    if let Some(size) = target_size {
+        let _ = window.set_size(Size::Physical(size));
+    }
+    let _ = window.show();
+    let _ = window.set_focusable(false);
+    let _ = window.set_always_on_top(false);
+    let _ = window.set_always_on_top(true);
 }
 
-#[cfg(not(target_os = "windows"))]
-fn try_capture_console_selection_for_copy(
-    _hwnd_raw: isize,
-    _fallback_text: &str,
-) -> Option<String> {
-    None
-}
+fn reinforce_ocr_capture_window_front<R: Runtime>(
+    app: &AppHandle<R>,
+    target_position: Option<PhysicalPosition<i32>>,
+    target_size: Option<tauri::PhysicalSize<u32>>,
+) {
+    reassert_ocr_capture_window_front(app, target_position, target_size);
 
-#[cfg(target_os = "windows")]
-fn clipboard_sequence_number() -> u32 {
-    unsafe { GetClipboardSequenceNumber() }
+    for delay_ms in [
+        OCR_CAPTURE_TOPMOST_REASSERT_SHORT_MS,
+        OCR_CAPTURE_TOPMOST_REASSERT_LONG_MS,
+    ] {
+        let app_handle = app.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(delay_ms));
+            reassert_ocr_capture_window_front(&app_handle, target_position, target_size);
+        });
+    }
 }
 
-#[cfg(not(target_os = "windows"))]
-fn clipboard_sequence_number() -> u32 {
-    0
+fn start_ocr_capture_workflow<R: Runtime>(app: &AppHandle<R>) -> Result<(), CommandError> {
+    let Some(settings_state) = app.try_state::<AppSettingsState>() else {
+        return Err(CommandError::Settings(
+            "Settings state unavailable".to_string(),
+        ));
+    };
+    let enabled = settings_state
+        .data
+        .lock()
+        .map(|settings| settings.ocr.enabled)
+        .unwrap_or(false);
+    if !enabled {
+        return Err(CommandError::Settings(
+            "请先在设置中启用智能 OCR".to_string(),
+        ));
+    }
+
+    let Some(ocr_runtime) = app.try_state::<OcrRuntimeState>() else {
+        return Err(CommandError::Settings(
+            "OCR runtime state unavailable".to_string(),
+        ));
+    };
+    if !app
+        .get_webview_window(OCR_CAPTURE_WINDOW_LABEL)
+        .and_then(|window| window.is_visible().ok())
+        .unwrap_or(false)
+        && ocr_runtime.capture_active.load(Ordering::Relaxed)
+    {
+        reset_ocr_capture_runtime_state(&ocr_runtime);
+    }
+    ocr_runtime.capture_active.store(true, Ordering::Relaxed);
+    ocr_runtime.suppress_blur_until_ms.store(
+        now_epoch_millis().saturating_add(OCR_CAPTURE_BLUR_SUPPRESS_MS),
+        Ordering::Relaxed,
+    );
+    if let Err(error) = refresh_ocr_capture_snapshot(&ocr_runtime) {
+        eprintln!("[OCR] preload capture snapshot failed: {error}");
+        if let Ok(mut snapshot) = ocr_runtime.capture_snapshot.lock() {
+            *snapshot = None;
+        }
+    }
+    show_ocr_capture_window(app)?;
+    emit_ocr_capture_started(app);
+    Ok(())
 }
 
-fn capture_console_selection_without_shortcut(
-    hwnd_raw: isize,
-    before_text: Option<&str>,
-) -> Option<(String, bool)> {
-    let normalized_before = before_text.map(|value| value.trim()).unwrap_or("");
-    let before_sequence = clipboard_sequence_number();
+fn open_in_default_browser(url: &str) -> Result<(), CommandError> {
+    let normalized = url.trim();
+    let lowered = normalized.to_ascii_lowercase();
+    if normalized.is_empty()
+        || normalized.contains('\r')
+        || normalized.contains('\n')
+        || !(lowered.starts_with("http://") || lowered.starts_with("https://"))
+    {
+        return Err(CommandError::Settings(
+            "搜索链接无效，仅支持 http/https 协议".to_string(),
+        ));
+    }
 
-    // Some terminals update clipboard a little after mouse release.
-    // Poll briefly to avoid falling back to synthetic copy shortcuts.
-    for _ in 0..12 {
-        if let Some(after_text) = read_clipboard_text_trimmed() {
-            if normalized_before != after_text {
-                return Some((after_text, true));
-            }
+    #[cfg(target_os = "windows")]
+    {
+        let open: Vec<u16> = OsStr::new("open").encode_wide().chain(Some(0)).collect();
+        let target: Vec<u16> = OsStr::new(normalized)
+            .encode_wide()
+            .chain(Some(0))
+            .collect();
+        let result = unsafe {
+            ShellExecuteW(
+                std::ptr::null_mut(),
+                open.as_ptr(),
+                target.as_ptr(),
+                std::ptr::null(),
+                std::ptr::null(),
+                SW_SHOWNORMAL,
+            )
+        } as isize;
+        if result <= 32 {
+            return Err(CommandError::Settings(format!(
+                "无法使用默认浏览器打开链接（系统错误码: {result}）"
+            )));
         }
-        std::thread::sleep(Duration::from_millis(9));
+        Ok(())
     }
 
-    if let Some(captured) = try_capture_console_selection_for_copy(hwnd_raw, normalized_before) {
-        return Some((captured, true));
+    #[cfg(not(target_os = "windows"))]
+    {
+        let _ = url;
+        Err(CommandError::Settings(
+            "Open browser is currently implemented for Windows only".to_string(),
+        ))
     }
+}
 
-    if let Some(after_text) = read_clipboard_text_trimmed() {
-        if clipboard_sequence_number() != before_sequence || after_text != normalized_before {
-            return Some((after_text, true));
+fn resolve_tts_voice(
+    tts: &TtsSettings,
+    language_hint: Option<&str>,
+    voice_override: Option<&str>,
+    text: &str,
+) -> String {
+    if let Some(override_voice) = voice_override {
+        let trimmed = override_voice.trim();
+        if !trimmed.is_empty() {
+            return trimmed.to_string();
         }
     }
 
-    None
+    let language =
+        detect_language(text).or_else(|| find_language(language_hint.unwrap_or_default()));
+    match language {
+        Some(language) if language.code == "zh-CN" => tts.voice_zh_cn.clone(),
+        Some(language) if language.code == "en-US" => tts.voice_en_us.clone(),
+        Some(language) => language.tts_voice.to_string(),
+        None => tts.voice_en_us.clone(),
+    }
 }
 
-fn capture_selected_text_once(hwnd_raw: isize) -> Result<(String, bool), CommandError> {
-    let mut clipboard =
-        Clipboard::new().map_err(|error| CommandError::Clipboard(error.to_string()))?;
-    let snapshot = capture_clipboard_snapshot(&mut clipboard);
-    let before_text = match &snapshot {
-        ClipboardSnapshot::Text(text) => Some(text.trim().to_string()),
-        _ => None,
-    };
-    let before_sequence = clipboard_sequence_number();
+fn format_tts_rate_arg(rate_percent: i32) -> String {
+    let clamped = rate_percent.clamp(MIN_TTS_RATE_PERCENT, MAX_TTS_RATE_PERCENT);
+    if clamped >= 0 {
+        format!("+{}%", clamped)
+    } else {
+        format!("{}%", clamped)
+    }
+}
 
-    send_system_copy_shortcut(hwnd_raw)?;
-    let mut text = String::new();
-    let mut changed = false;
-    for _ in 0..20 {
-        std::thread::sleep(Duration::from_millis(18));
-        let candidate = match clipboard.get_text() {
-            Ok(value) => value.trim().to_string(),
-            Err(arboard::Error::ContentNotAvailable) => continue,
-            Err(arboard::Error::ClipboardOccupied) => continue,
-            Err(_) => continue,
-        };
-        if candidate.is_empty() {
-            continue;
-        }
+fn configure_background_command(command: &mut Command) {
+    #[cfg(target_os = "windows")]
+    {
+        command.creation_flags(CREATE_NO_WINDOW);
+    }
+}
 
-        let sequence_changed = clipboard_sequence_number() != before_sequence;
-        let text_changed = before_text
-            .as_ref()
-            .map(|previous| previous != &candidate)
-            .unwrap_or(true);
+fn run_edge_tts_process(
+    executable: &str,
+    prefix_args: &[&str],
+    voice: &str,
+    rate: &str,
+    text: &str,
+    output_path: &Path,
+) -> Result<(), String> {
+    let mut args = prefix_args
+        .iter()
+        .map(|item| (*item).to_string())
+        .collect::<Vec<_>>();
+    args.extend([
+        "--voice".to_string(),
+        voice.to_string(),
+        "--rate".to_string(),
+        rate.to_string(),
+        "--text".to_string(),
+        text.to_string(),
+        "--write-media".to_string(),
+        output_path.to_string_lossy().into_owned(),
+    ]);
 
-        text = candidate;
-        changed = sequence_changed || text_changed;
-        if changed {
-            break;
-        }
-    }
-    let _ = restore_clipboard_snapshot(&mut clipboard, &snapshot);
+    let mut command = Command::new(executable);
+    configure_background_command(&mut command);
+    let output = command
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|error| format!("{executable}: {error}"))?;
 
-    if text.is_empty() {
-        return Err(CommandError::Settings(
-            "No selectable text captured".to_string(),
-        ));
+    if output.status.success() {
+        return Ok(());
     }
 
-    Ok((text, changed))
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    if stderr.is_empty() {
+        Err(format!(
+            "{executable}: exited with status {}",
+            output.status
+        ))
+    } else {
+        Err(format!("{executable}: {stderr}"))
+    }
 }
 
-fn is_link_text(content: &str) -> bool {
-    let value = content.trim().to_ascii_lowercase();
-    value.starts_with("http://")
-        || value.starts_with("https://")
-        || value.starts_with("ftp://")
-        || value.starts_with("mailto:")
+fn is_missing_edge_tts_error(message: &str) -> bool {
+    let lowered = message.to_ascii_lowercase();
+    lowered.contains("no module named edge_tts")
+        || lowered.contains("is not recognized")
+        || lowered.contains("not recognized as")
+        || lowered.contains("not found")
+        || lowered.contains("cannot find")
+        || lowered.contains("no such file")
 }
 
-fn build_text_entry(content: String) -> ClipboardEntry {
-    let normalized = truncate_text_chars(content.trim(), MAX_CLIPBOARD_TEXT_CHARS);
-    let kind = if is_link_text(&normalized) {
-        ClipboardKind::Link
-    } else {
-        ClipboardKind::Text
-    };
+fn has_edge_tts_runtime() -> bool {
+    let probes: [(&str, &[&str]); 3] = [
+        ("edge-tts", &["--help"]),
+        ("python", &["-m", "edge_tts", "--help"]),
+        ("py", &["-m", "edge_tts", "--help"]),
+    ];
 
-    ClipboardEntry {
-        id: now_id(),
-        kind,
-        content: normalized,
-        image_data_url: None,
-        thumbnail_data_url: None,
-        copied_at: Utc::now(),
-        pinned: false,
+    for (executable, args) in probes {
+        let mut command = Command::new(executable);
+        configure_background_command(&mut command);
+        let output = command
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .output();
+        if output
+            .map(|result| result.status.success())
+            .unwrap_or(false)
+        {
+            return true;
+        }
     }
-}
 
-fn build_image_entry(image: ImageData<'static>) -> Result<ClipboardEntry, CommandError> {
-    let width = image.width as u32;
-    let height = image.height as u32;
-    let bytes = image.bytes.into_owned();
-
-    let rgba = RgbaImage::from_raw(width, height, bytes).ok_or_else(|| {
-        CommandError::InvalidImage("Invalid RGBA bytes for clipboard image".to_string())
-    })?;
+    false
+}
 
-    // Generate original base64
-    let mut original_cursor = Cursor::new(Vec::new());
-    DynamicImage::ImageRgba8(rgba.clone())
-        .write_to(&mut original_cursor, ImageFormat::Png)
-        .map_err(|error| CommandError::InvalidImage(error.to_string()))?;
-    let original_encoded = BASE64.encode(original_cursor.into_inner());
-    let original_data_url = format!("data:image/png;base64,{original_encoded}");
+fn try_auto_install_edge_tts() -> Result<(), String> {
+    let mut errors = Vec::new();
+    for executable in ["python", "py"] {
+        let mut command = Command::new(executable);
+        configure_background_command(&mut command);
+        let output = match command
+            .args([
+                "-m",
+                "pip",
+                "install",
+                "edge-tts",
+                "--disable-pip-version-check",
+            ])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()
+        {
+            Ok(result) => result,
+            Err(error) => {
+                errors.push(format!("{executable}: {error}"));
+                continue;
+            }
+        };
 
-    if original_data_url.len() > MAX_CLIPBOARD_IMAGE_DATA_URL_CHARS {
-        return Err(CommandError::InvalidImage(format!(
-            "Clipboard image too large ({} bytes), skipped",
-            original_data_url.len()
-        )));
-    }
+        if output.status.success() {
+            return Ok(());
+        }
 
-    // Generate 120px thumbnail
-    let thumbnail_data_url = if width > MAX_THUMBNAIL_WIDTH {
-        let ratio = MAX_THUMBNAIL_WIDTH as f32 / width as f32;
-        let thumb_width = MAX_THUMBNAIL_WIDTH;
-        let thumb_height = (height as f32 * ratio) as u32;
-        let thumb = image::imageops::resize(
-            &rgba,
-            thumb_width,
-            thumb_height,
-            image::imageops::FilterType::Lanczos3,
-        );
-        let mut thumb_cursor = Cursor::new(Vec::new());
-        DynamicImage::ImageRgba8(thumb)
-            .write_to(&mut thumb_cursor, ImageFormat::Png)
-            .map_err(|error| CommandError::InvalidImage(error.to_string()))?;
-        let thumb_encoded = BASE64.encode(thumb_cursor.into_inner());
-        let thumb_data_url = format!("data:image/png;base64,{thumb_encoded}");
-        if thumb_data_url.len() <= MAX_THUMBNAIL_CHARS {
-            Some(thumb_data_url)
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        if stderr.is_empty() {
+            errors.push(format!(
+                "{executable}: pip install exited with {}",
+                output.status
+            ));
         } else {
-            None
+            errors.push(format!("{executable}: {stderr}"));
         }
-    } else {
-        None
-    };
+    }
 
-    Ok(ClipboardEntry {
-        id: now_id(),
-        kind: ClipboardKind::Image,
-        content: format!("Image {}x{}", width, height),
-        image_data_url: Some(original_data_url),
-        thumbnail_data_url,
-        copied_at: Utc::now(),
-        pinned: false,
-    })
+    if errors.is_empty() {
+        Err("未找到可用的 Python/pip 环境".to_string())
+    } else {
+        Err(errors.join(" | "))
+    }
 }
 
-fn entry_matches(a: &ClipboardEntry, b: &ClipboardEntry) -> bool {
-    if a.kind != b.kind {
-        return false;
+fn try_auto_install_edge_tts_with_lock() -> Result<(), String> {
+    if EDGE_TTS_AUTO_INSTALL_ATTEMPTED
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return Err(EDGE_TTS_INSTALL_IN_PROGRESS.to_string());
     }
 
-    if a.kind == ClipboardKind::Image {
-        return a.image_data_url == b.image_data_url;
+    match try_auto_install_edge_tts() {
+        Ok(()) => Ok(()),
+        Err(error) => {
+            EDGE_TTS_AUTO_INSTALL_ATTEMPTED.store(false, Ordering::Release);
+            Err(error)
+        }
     }
-
-    a.content == b.content
 }
 
-fn entry_signature(entry: &ClipboardEntry) -> u64 {
-    let mut hasher = std::collections::hash_map::DefaultHasher::new();
-    entry.kind.hash(&mut hasher);
-    match entry.kind {
-        ClipboardKind::Image => entry
-            .image_data_url
-            .as_deref()
-            .unwrap_or("")
-            .hash(&mut hasher),
-        ClipboardKind::Text | ClipboardKind::Link => entry.content.hash(&mut hasher),
+fn execute_tts_attempt(
+    attempt_errors: &mut Vec<String>,
+    cmd: &str,
+    prefix: &[&str],
+    voice: &str,
+    rate: &str,
+    text: &str,
+    output_path: &Path,
+) -> bool {
+    match run_edge_tts_process(cmd, prefix, voice, rate, text, output_path) {
+        Ok(_) => true,
+        Err(error) => {
+            attempt_errors.push(error);
+            false
+        }
     }
-    hasher.finish()
-}
-
-fn collect_history(history: &VecDeque<ClipboardEntry>) -> Vec<ClipboardEntry> {
-    history.iter().cloned().collect::<Vec<_>>()
 }
 
-fn looks_like_internal_clipboard_image_path(value: &str) -> bool {
-    let normalized = value.trim().trim_matches('"').to_ascii_lowercase();
-    if normalized.is_empty() {
-        return false;
+fn synthesize_tts_audio(
+    tts: &TtsSettings,
+    text: &str,
+    language_hint: Option<&str>,
+    voice_override: Option<&str>,
+) -> Result<SynthesizeTtsResult, CommandError> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Err(CommandError::Settings("TTS 文本为空".to_string()));
     }
 
-    let has_internal_dir = normalized.contains("\\clipboard_images\\snapparse_")
-        || normalized.contains("/clipboard_images/snapparse_");
-    if !has_internal_dir {
-        return false;
+    if text.chars().count() > MAX_TTS_TEXT_CHARS {
+        return Err(CommandError::Settings(format!(
+            "TTS 文本过长（最多 {MAX_TTS_TEXT_CHARS} 个字符）"
+        )));
     }
 
-    normalized.ends_with(".png")
-        || normalized.ends_with(".jpg")
-        || normalized.ends_with(".jpeg")
-        || normalized.ends_with(".webp")
-        || normalized.ends_with(".bmp")
-        || normalized.ends_with(".gif")
-}
+    let voice = resolve_tts_voice(tts, language_hint, voice_override, text);
+    let rate = format_tts_rate_arg(tts.rate_percent);
+    let mut output_path = std::env::temp_dir();
+    output_path.push(format!("snapparse-tts-{}.mp3", now_id()));
 
-fn dedupe_history_entries(history: &mut VecDeque<ClipboardEntry>) -> bool {
-    if history.len() < 2 {
-        return false;
-    }
+    let mut attempt_errors = Vec::new();
 
-    let original_len = history.len();
-    let mut changed = false;
-    let mut compacted = VecDeque::<ClipboardEntry>::new();
+    let mut succeeded = match tts.runtime_mode {
+        TtsRuntimeMode::EdgeCliOnly => execute_tts_attempt(
+            &mut attempt_errors,
+            "edge-tts",
+            &[],
+            &voice,
+            &rate,
+            text,
+            &output_path,
+        ),
+        TtsRuntimeMode::PythonModuleOnly => {
+            execute_tts_attempt(
+                &mut attempt_errors,
+                "python",
+                &["-m", "edge_tts"],
+                &voice,
+                &rate,
+                text,
+                &output_path,
+            ) || execute_tts_attempt(
+                &mut attempt_errors,
+                "py",
+                &["-m", "edge_tts"],
+                &voice,
+                &rate,
+                text,
+                &output_path,
+            )
+        }
+        TtsRuntimeMode::DualFallback => {
+            execute_tts_attempt(
+                &mut attempt_errors,
+                "edge-tts",
+                &[],
+                &voice,
+                &rate,
+                text,
+                &output_path,
+            ) || execute_tts_attempt(
+                &mut attempt_errors,
+                "python",
+                &["-m", "edge_tts"],
+                &voice,
+                &rate,
+                text,
+                &output_path,
+            ) || execute_tts_attempt(
+                &mut attempt_errors,
+                "py",
+                &["-m", "edge_tts"],
+                &voice,
+                &rate,
+                text,
+                &output_path,
+            )
+        }
+    };
 
-    for entry in history.iter().cloned() {
-        if let Some(existing) = compacted
-            .iter_mut()
-            .find(|existing| entry_matches(existing, &entry))
-        {
-            let merged_pinned = existing.pinned || entry.pinned;
-            if existing.pinned != merged_pinned {
-                existing.pinned = merged_pinned;
+    let missing_dependency = !succeeded
+        && !attempt_errors.is_empty()
+        && attempt_errors
+            .iter()
+            .all(|error| is_missing_edge_tts_error(error));
+    if missing_dependency {
+        match try_auto_install_edge_tts_with_lock() {
+            Ok(_) => {
+                attempt_errors.clear();
+                succeeded = match tts.runtime_mode {
+                    TtsRuntimeMode::EdgeCliOnly => execute_tts_attempt(
+                        &mut attempt_errors,
+                        "edge-tts",
+                        &[],
+                        &voice,
+                        &rate,
+                        text,
+                        &output_path,
+                    ),
+                    TtsRuntimeMode::PythonModuleOnly => {
+                        execute_tts_attempt(
+                            &mut attempt_errors,
+                            "python",
+                            &["-m", "edge_tts"],
+                            &voice,
+                            &rate,
+                            text,
+                            &output_path,
+                        ) || execute_tts_attempt(
+                            &mut attempt_errors,
+                            "py",
+                            &["-m", "edge_tts"],
+                            &voice,
+                            &rate,
+                            text,
+                            &output_path,
+                        )
+                    }
+                    TtsRuntimeMode::DualFallback => {
+                        execute_tts_attempt(
+                            &mut attempt_errors,
+                            "edge-tts",
+                            &[],
+                            &voice,
+                            &rate,
+                            text,
+                            &output_path,
+                        ) || execute_tts_attempt(
+                            &mut attempt_errors,
+                            "python",
+                            &["-m", "edge_tts"],
+                            &voice,
+                            &rate,
+                            text,
+                            &output_path,
+                        ) || execute_tts_attempt(
+                            &mut attempt_errors,
+                            "py",
+                            &["-m", "edge_tts"],
+                            &voice,
+                            &rate,
+                            text,
+                            &output_path,
+                        )
+                    }
+                };
+            }
+            Err(error) => {
+                if error == EDGE_TTS_INSTALL_IN_PROGRESS {
+                    attempt_errors.push("Edge TTS 正在后台初始化，请稍后重试".to_string());
+                } else {
+                    attempt_errors.push(format!("auto-install failed: {error}"));
+                }
             }
-            changed = true;
-            continue;
         }
-        compacted.push_back(entry);
-    }
-
-    if compacted.len() != original_len {
-        changed = true;
     }
 
-    if changed {
-        *history = compacted;
-        normalize_history_order(history);
+    if !succeeded {
+        let _ = fs::remove_file(&output_path);
+        let detail = if attempt_errors.is_empty() {
+            "未知错误".to_string()
+        } else {
+            attempt_errors.join(" | ")
+        };
+        return Err(CommandError::Settings(format!(
+            "TTS 调用失败，请确认已安装 edge-tts（python -m pip install edge-tts）: {detail}"
+        )));
     }
-    changed
-}
 
-fn decode_image_data_url_bytes(data_url: &str) -> Result<(String, Vec<u8>), CommandError> {
-    let trimmed = data_url.trim();
-    let (header, payload) = trimmed
-        .split_once(',')
-        .ok_or_else(|| CommandError::InvalidImage("Invalid image data URL".to_string()))?;
-    let lowered_header = header.to_ascii_lowercase();
-    if !lowered_header.starts_with("data:image/") || !lowered_header.contains(";base64") {
-        return Err(CommandError::InvalidImage(
-            "Unsupported image data URL format".to_string(),
-        ));
-    }
+    let bytes_result = fs::read(&output_path);
+    let _ = fs::remove_file(&output_path);
+    let bytes = bytes_result.map_err(|error| {
+        CommandError::Settings(format!(
+            "读取 TTS 音频失败（{}）: {}",
+            output_path.display(),
+            error
+        ))
+    })?;
 
-    let mime_type = header
-        .trim_start_matches("data:")
-        .split(';')
-        .next()
-        .unwrap_or("image/png")
-        .to_ascii_lowercase();
-    let bytes = BASE64
-        .decode(payload.trim())
-        .map_err(|error| CommandError::InvalidImage(error.to_string()))?;
     if bytes.is_empty() {
-        return Err(CommandError::InvalidImage(
-            "Image payload is empty".to_string(),
-        ));
+        return Err(CommandError::Settings("TTS 返回空音频".to_string()));
     }
-    Ok((mime_type, bytes))
-}
 
-fn extension_from_image_mime(mime_type: &str) -> &'static str {
-    match mime_type {
-        "image/png" => "png",
-        "image/jpeg" | "image/jpg" => "jpg",
-        "image/webp" => "webp",
-        "image/bmp" => "bmp",
-        "image/gif" => "gif",
-        _ => "png",
-    }
+    Ok(SynthesizeTtsResult {
+        audio_base64: BASE64.encode(bytes),
+        mime_type: "audio/mpeg".to_string(),
+        voice_used: voice,
+    })
 }
 
-fn sanitize_clip_filename_token(value: &str) -> String {
-    let mut token = value
-        .chars()
-        .map(|ch| {
-            if ch.is_ascii_alphanumeric() || ch == '-' || ch == '_' {
-                ch
-            } else {
-                '_'
-            }
-        })
-        .collect::<String>();
-    if token.is_empty() {
-        token = "clip".to_string();
+fn build_search_url(template: &str, text: &str) -> String {
+    let encoded = urlencoding::encode(text);
+    if template.contains("{query}") {
+        template.replace("{query}", &encoded)
+    } else if template.contains("{}") {
+        template.replacen("{}", &encoded, 1)
+    } else {
+        format!("{template}{encoded}")
     }
-    token
 }
 
-fn resolve_clip_image_export_dir<R: Runtime>(
-    app: &AppHandle<R>,
+fn choose_builtin_prompt(
+    action: SelectionActionKind,
+    text: &str,
+    translate_from: Option<&str>,
+    translate_to: Option<&str>,
     settings: &AppSettings,
-) -> Result<PathBuf, CommandError> {
-    let history_path = resolve_history_file_path(app, settings)?;
-    let parent = history_path
-        .parent()
-        .map(Path::to_path_buf)
-        .ok_or_else(|| CommandError::Settings("Invalid history storage directory".to_string()))?;
-    let export_dir = parent.join("clipboard_images");
-    fs::create_dir_all(&export_dir).map_err(|error| CommandError::Settings(error.to_string()))?;
-    Ok(export_dir)
-}
+) -> (String, String) {
+    fn output_language_label(language: &str) -> &'static str {
+        if language.eq_ignore_ascii_case("en-US") {
+            "English"
+        } else {
+            "Chinese (Simplified)"
+        }
+    }
 
-fn format_path_for_terminal_input(path: &Path) -> String {
-    let rendered = path.to_string_lossy().to_string();
-    if rendered.chars().any(|ch| ch.is_whitespace()) {
-        format!("\"{rendered}\"")
-    } else {
-        rendered
+    fn language_label(code: &str, text: &str) -> Cow<'static, str> {
+        if code == "auto" {
+            return match detect_language(text) {
+                Some(detected) => Cow::Owned(format!("{} (auto-detected)", detected.english_name)),
+                None => Cow::Borrowed("Auto Detect"),
+            };
+        }
+        match find_language(code) {
+            Some(language) if language.native_name != language.english_name => Cow::Owned(format!(
+                "{} / {}",
+                language.english_name, language.native_name
+            )),
+            Some(language) => Cow::Borrowed(language.english_name),
+            None => Cow::Owned(code.to_string()),
+        }
     }
+
+    let (Some(config), Some((default_system, default_user))) = (
+        settings.agents.builtin.for_kind(action),
+        builtin_prompt_defaults(action),
+    ) else {
+        return (
+            "You are a text processing assistant. Follow the user's instruction and return only the final output.".to_string(),
+            format!("Text:\n{text}"),
+        );
+    };
+    let ui_language = settings.language.as_str();
+    let to = translate_to.unwrap_or(if ui_language.eq_ignore_ascii_case("en-US") {
+        "en-US"
+    } else {
+        "zh-CN"
+    });
+    let source_language = language_label(translate_from.unwrap_or("auto"), text);
+    let target_language = language_label(to, "");
+    let date = Local::now().format("%Y-%m-%d").to_string();
+    let variable = |name: &str| -> Option<&str> {
+        match name {
+            "text" => Some(text),
+            "output_language" => Some(output_language_label(ui_language)),
+            "target_language" => Some(&target_language),
+            "source_language" => Some(&source_language),
+            "app_name" => Some(APP_DISPLAY_NAME),
+            "date" => Some(&date),
+            _ => None,
+        }
+    };
+    let template = |custom: &str, default: &'static str| {
+        if custom.trim().is_empty() {
+            render_prompt_template(default, variable)
+        } else {
+            render_prompt_template(custom, variable)
+        }
+    };
+    (
+        template(&config.system_prompt, default_system),
+        template(&config.user_prompt, default_user),
+    )
 }
 
-fn materialize_clipboard_image_path_for_terminal<R: Runtime>(
-    app: &AppHandle<R>,
-    settings: &AppSettings,
-    entry: &ClipboardEntry,
-) -> Result<String, CommandError> {
-    let data_url = entry
-        .image_data_url
-        .as_deref()
-        .ok_or_else(|| CommandError::InvalidImage("Missing image payload".to_string()))?;
-    let (mime_type, bytes) = decode_image_data_url_bytes(data_url)?;
-    let extension = extension_from_image_mime(&mime_type);
-    let export_dir = resolve_clip_image_export_dir(app, settings)?;
-    let file_name = format!(
-        "snapparse_{}_{}.{}",
-        now_epoch_millis(),
-        sanitize_clip_filename_token(&entry.id),
-        extension
-    );
-    let file_path = export_dir.join(file_name);
-    fs::write(&file_path, bytes).map_err(|error| CommandError::Settings(error.to_string()))?;
-    Ok(format_path_for_terminal_input(&file_path))
+/// Default system and user templates of the built-in actions.
+fn builtin_prompt_defaults(action: SelectionActionKind) -> Option<(&'static str, &'static str)> {
+    match action {
+        SelectionActionKind::Summarize => Some((
+            "You are a senior summarization assistant.
+Output language: {output_language}.
+Requirements:
+- Return only the final summary text.
+- Preserve key facts, entities, numbers, dates, and technical terms accurately.
+- Remove redundancy and keep the summary concise and clear.
+- Do not add headings, commentary, or extra explanations unless explicitly requested.",
+            "Task: Summarize the content faithfully and concisely.
+Text:
+{text}",
+        )),
+        SelectionActionKind::Polish => Some((
+            "You are a professional writing editor.
+Output language: {output_language}.
+Requirements:
+- Return only the rewritten text.
+- Preserve the original meaning, factual details, and intent.
+- Improve clarity, grammar, fluency, and structure.
+- Keep names, numbers, code snippets, URLs, and quoted text accurate.
+- Do not add commentary, labels, or extra sections.",
+            "Task: Rewrite and improve the text while preserving meaning.
+Text:
+{text}",
+        )),
+        SelectionActionKind::Explain => Some((
+            "You are a practical programming explainer and problem-solving assistant.
+Output language: {output_language}.
+Requirements:
+- Return only content directly related to the input text.
+- Prefer simple and clear language; avoid unnecessary jargon.
+- Prioritize code and programming issues when present.
+- Use exactly three sections in this order:
+  1) Problem Understanding
+  2) Why It Happens
+  3) Solution Steps
+- Section headings must also be in the output language.
+- In Solution Steps, provide concrete, low-risk, executable steps.
+- If missing key context, state the minimum missing info and continue with best-effort guidance.
+- Do not add greetings, disclaimers, or unrelated commentary.",
+            "Task: Explain the issue clearly and provide an easy-to-follow solution.
+Text:
+{text}",
+        )),
+        SelectionActionKind::Translate => Some((
+            "You are a professional translator.
+Target language: {target_language}.
+Requirements:
+- Return only the translated text.
+- Preserve meaning faithfully; do not omit, add, or invent information.
+- Keep proper nouns, numbers, code, URLs, and formatting accurate.
+- Preserve line breaks and list structure when useful for readability.
+- Do not add notes, explanations, or bilingual output unless explicitly requested.",
+            "Source language: {source_language}
+Target language: {target_language}
+Task: Translate the text.
+Text:
+{text}",
+        )),
+        SelectionActionKind::Custom => None,
+    }
 }
 
-fn apply_entry_to_clipboard(
-    clipboard: &mut Clipboard,
-    target: &ClipboardEntry,
-) -> Result<(), CommandError> {
-    match target.kind {
-        ClipboardKind::Image => {
-            let data_url = target
-                .image_data_url
-                .clone()
-                .ok_or_else(|| CommandError::InvalidImage("Missing image payload".to_string()))?;
-            let image_data = to_image_data(&data_url)?;
-            clipboard
-                .set_image(image_data)
-                .map_err(|error| CommandError::Clipboard(error.to_string()))?;
-        }
-        ClipboardKind::Text | ClipboardKind::Link => {
-            clipboard
-                .set_text(target.content.clone())
-                .map_err(|error| CommandError::Clipboard(error.to_string()))?;
+enum TemplatePart<'a> {
+    Literal(&'a str),
+    Variable(&'a str),
+    /// `{?name}` keeps its section when the variable is non-empty, `{!name}` when it is empty.
+    SectionStart {
+        name: &'a str,
+        when_set: bool,
+    },
+    SectionEnd(&'a str),
+}
+
+/// Splits a prompt template into literal text, `{name}` placeholders and `{?name}`/`{!name}`
+/// ... `{/name}` sections. Braces that do not wrap an identifier (JSON examples, code) stay
+/// literal.
+fn parse_prompt_template(template: &str) -> Vec<TemplatePart<'_>> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        let after = &rest[open + 1..];
+        let sigil = after
+            .chars()
+            .next()
+            .filter(|ch| matches!(ch, '?' | '!' | '/'));
+        let body = &after[sigil.map_or(0, char::len_utf8)..];
+        let name_len = body
+            .find(|ch: char| !(ch.is_ascii_alphanumeric() || ch == '_'))
+            .unwrap_or(body.len());
+        let name = &body[..name_len];
+        let is_tag = !name.is_empty()
+            && !name.starts_with(|ch: char| ch.is_ascii_digit())
+            && body[name_len..].starts_with('}');
+        if !is_tag {
+            parts.push(TemplatePart::Literal(&rest[..=open]));
+            rest = after;
+            continue;
         }
+        parts.push(TemplatePart::Literal(&rest[..open]));
+        parts.push(match sigil {
+            Some('?') => TemplatePart::SectionStart {
+                name,
+                when_set: true,
+            },
+            Some('!') => TemplatePart::SectionStart {
+                name,
+                when_set: false,
+            },
+            Some(_) => TemplatePart::SectionEnd(name),
+            None => TemplatePart::Variable(name),
+        });
+        rest = &body[name_len + 1..];
     }
-
-    Ok(())
+    parts.push(TemplatePart::Literal(rest));
+    parts
 }
 
-fn trim_history(history: &mut VecDeque<ClipboardEntry>, max_items: usize) {
-    while history.len() > max_items {
-        if let Some(index) = history.iter().rposition(|entry| !entry.pinned) {
-            history.remove(index);
-        } else {
-            // All remaining items are pinned, remove the oldest pinned item
-            // to make room for the new entry
-            if let Some(index) = history.iter().rposition(|_| true) {
-                history.remove(index);
-            } else {
-                break;
+/// Fills template variables and resolves sections; unknown names are kept verbatim.
+fn render_prompt_template<'v>(
+    template: &str,
+    variable: impl Fn(&str) -> Option<&'v str>,
+) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut sections: Vec<bool> = Vec::new();
+    for part in parse_prompt_template(template) {
+        let visible = sections.iter().all(|shown| *shown);
+        match part {
+            TemplatePart::Literal(literal) if visible => output.push_str(literal),
+            TemplatePart::Variable(name) if visible => match variable(name) {
+                Some(value) => output.push_str(value),
+                None => {
+                    output.push('{');
+                    output.push_str(name);
+                    output.push('}');
+                }
+            },
+            TemplatePart::SectionStart { name, when_set } => {
+                let is_set = variable(name).is_some_and(|value| !value.trim().is_empty());
+                sections.push(is_set == when_set);
             }
+            TemplatePart::SectionEnd(_) => {
+                sections.pop();
+            }
+            _ => {}
         }
     }
+    output
 }
 
-fn normalize_history_order(history: &mut VecDeque<ClipboardEntry>) {
-    let mut items = history.make_contiguous().to_vec();
-    items.sort_by_key(|item| std::cmp::Reverse(item.copied_at));
-    *history = VecDeque::from(items);
-}
-
-fn insert_or_promote(
-    history: &mut VecDeque<ClipboardEntry>,
-    mut incoming: ClipboardEntry,
-    max_items: usize,
-    dedupe: bool,
-) {
-    incoming.copied_at = Utc::now();
-
-    if dedupe {
-        if let Some(idx) = history
-            .iter()
-            .position(|entry| entry_matches(entry, &incoming))
-        {
-            if let Some(mut existing) = history.remove(idx) {
-                existing.copied_at = Utc::now();
-                existing.pinned = existing.pinned || incoming.pinned;
-                history.push_front(existing);
+/// Checks that every variable is known and sections are balanced; returns the variables used.
+fn validate_prompt_template<'t>(template: &'t str, known: &[&str]) -> Result<Vec<&'t str>, String> {
+    let mut used = Vec::new();
+    let mut open_sections: Vec<&str> = Vec::new();
+    for part in parse_prompt_template(template) {
+        let name = match part {
+            TemplatePart::Literal(_) => continue,
+            TemplatePart::Variable(name) => name,
+            TemplatePart::SectionStart { name, .. } => {
+                open_sections.push(name);
+                name
             }
-        } else {
-            history.push_front(incoming);
+            TemplatePart::SectionEnd(name) => {
+                if open_sections.pop() != Some(name) {
+                    return Err(format!("条件段结束标记 {{/{name}}} 没有对应的开始标记"));
+                }
+                continue;
+            }
+        };
+        if !known.contains(&name) {
+            let known = known
+                .iter()
+                .map(|item| format!("{{{item}}}"))
+                .collect::<Vec<_>>()
+                .join("、");
+            return Err(format!("包含未知变量 {{{name}}}，可用变量：{known}"));
         }
-    } else {
-        history.push_front(incoming);
+        used.push(name);
+    }
+    if let Some(name) = open_sections.pop() {
+        return Err(format!("条件段 {name} 缺少结束标记 {{/{name}}}"));
     }
+    Ok(used)
+}
 
-    trim_history(history, max_items);
-    normalize_history_order(history);
+/// Window the current selection came from, captured when the selection bar is shown.
+#[derive(Clone, Debug, Default)]
+struct SelectionSourceWindow {
+    text_hash: u64,
+    hwnd: isize,
+    process_name: Option<String>,
+    window_title: Option<String>,
 }
 
-fn capture_kind_allowed(kind: ClipboardKind, settings: &HistorySettings) -> bool {
-    match kind {
-        ClipboardKind::Text => settings.capture_text,
-        ClipboardKind::Link => settings.capture_link,
-        ClipboardKind::Image => settings.capture_image,
+#[cfg(target_os = "windows")]
+fn capture_selection_source_window(hwnd_raw: isize, text: &str) -> SelectionSourceWindow {
+    let (process_name, _) = window_process_identity(hwnd_raw);
+    SelectionSourceWindow {
+        text_hash: stable_text_hash(text.trim()),
+        hwnd: hwnd_raw,
+        process_name: process_name.map(|name| name.trim_end_matches(".exe").to_string()),
+        window_title: window_title(hwnd_raw),
     }
 }
 
-fn target_position_with_memory<R: Runtime>(
-    app: &AppHandle<R>,
-    remember_position: bool,
-    saved_x: Option<i32>,
-    saved_y: Option<i32>,
-    width: u32,
-    height: u32,
-) -> Option<PhysicalPosition<i32>> {
-    let follow_pointer = || -> Option<PhysicalPosition<i32>> {
-        let pointer = current_pointer_position()?;
-        let candidate =
-            PhysicalPosition::new(pointer.x.saturating_add(14), pointer.y.saturating_add(16));
-        let monitor = monitor_for_point(app, pointer)?;
-        Some(clamp_position_to_monitor(
-            &monitor, candidate, width, height,
-        ))
-    };
-
-    if remember_position {
-        if let (Some(x), Some(y)) = (saved_x, saved_y) {
-            let candidate = PhysicalPosition::new(x, y);
-            let monitor =
-                monitor_for_point(app, candidate).or_else(|| app.primary_monitor().ok().flatten());
-            return monitor
-                .map(|monitor| clamp_position_to_monitor(&monitor, candidate, width, height))
-                .or(Some(candidate));
-        }
+#[cfg(not(target_os = "windows"))]
+fn capture_selection_source_window(hwnd_raw: isize, text: &str) -> SelectionSourceWindow {
+    SelectionSourceWindow {
+        text_hash: stable_text_hash(text.trim()),
+        hwnd: hwnd_raw,
+        ..SelectionSourceWindow::default()
     }
-
-    follow_pointer()
 }
 
-fn main_window_target_position<R: Runtime>(
-    app: &AppHandle<R>,
-    settings: &AppSettings,
-) -> Option<PhysicalPosition<i32>> {
-    let width = clamp_main_window_width(
-        settings
-            .main_window_width
-            .unwrap_or(DEFAULT_MAIN_WINDOW_WIDTH),
-    );
-    let height = clamp_main_window_height(
-        settings
-            .main_window_height
-            .unwrap_or(DEFAULT_MAIN_WINDOW_HEIGHT),
-    );
-
-    target_position_with_memory(
-        app,
-        settings.window.remember_position,
-        settings.main_window_x,
-        settings.main_window_y,
-        width,
-        height,
-    )
+/// The captured source window, if it belongs to `text` rather than an older selection.
+fn selection_source_for_text<R: Runtime>(app: &AppHandle<R>, text: &str) -> SelectionSourceWindow {
+    let text_hash = stable_text_hash(text.trim());
+    app.try_state::<SelectionRuntimeState>()
+        .and_then(|runtime| {
+            runtime
+                .selection_source
+                .lock()
+                .ok()
+                .map(|source| source.clone())
+        })
+        .filter(|source| source.text_hash == text_hash)
+        .unwrap_or_default()
 }
 
-fn apply_main_window_position<R: Runtime>(app: &AppHandle<R>) {
-    let Some(settings_state) = app.try_state::<AppSettingsState>() else {
-        return;
-    };
-
-    let snapshot = match settings_state.data.lock() {
-        Ok(settings) => settings.clone(),
-        Err(_) => return,
-    };
-
-    let Some(position) = main_window_target_position(app, &snapshot) else {
-        return;
-    };
-
-    if let Some(window) = app.get_webview_window(MAIN_WINDOW_LABEL) {
-        let _ = window.set_position(Position::Physical(position));
+/// Hides the result window and pastes `output` into the window the selection came from. The
+/// temporary clipboard write is kept out of the clipboard history.
+async fn replace_selection_in_source<R: Runtime>(
+    app: &AppHandle<R>,
+    source: &SelectionSourceWindow,
+    output: &str,
+) -> Result<(), CommandError> {
+    if source.hwnd == 0 {
+        return Err(CommandError::Settings(
+            "未找到划词来源窗口，无法替换".to_string(),
+        ));
     }
-
-    if snapshot.window.remember_position
-        && (snapshot.main_window_x != Some(position.x)
-            || snapshot.main_window_y != Some(position.y))
-    {
-        if let Ok(mut settings) = settings_state.data.lock() {
-            settings.main_window_x = Some(position.x);
-            settings.main_window_y = Some(position.y);
+    let output = output.trim().to_string();
+    if output.is_empty() {
+        return Err(CommandError::Settings("没有可替换的结果".to_string()));
+    }
+
+    if let Some(state) = app.try_state::<Mutex<ClipboardState>>() {
+        if let Ok(mut locked) = state.lock() {
+            locked.pending_ignored_text = Some(output.clone());
         }
-        let _ = persist_settings_state(&settings_state);
     }
+    if let Some(window) = app.get_webview_window(SELECTION_RESULT_WINDOW_LABEL) {
+        let _ = window.hide();
+    }
+    let hwnd = source.hwnd;
+    tauri::async_runtime::spawn_blocking(move || paste_text_into_window(hwnd, &output))
+        .await
+        .map_err(|error| CommandError::Settings(format!("替换任务失败: {error}")))?
 }
 
-fn selection_bar_width_for_settings(settings: &AppSettings) -> u32 {
-    const GAP_NORMAL: u32 = 4;
-    const GAP_COMPACT: u32 = 3;
-    const SHELL_PADDING_NORMAL: u32 = 22;
-    const SHELL_PADDING_COMPACT: u32 = 18;
-    const BRAND_WIDTH: u32 = 16;
-    const ITEM_COMPACT_WIDTH: u32 = 26;
-    const ITEM_ICON_WIDTH: u32 = 13;
-    const ITEM_TEXT_GAP: u32 = 4;
-    const ITEM_PADDING_X: u32 = 18;
-    const WIDTH_SAFETY_BUFFER: u32 = 64;
-    let compact_mode = settings.selection_assistant.compact_mode;
+/// Whether the agent's system or user prompt references the variable `name`.
+fn agent_template_uses(agent: &CustomAgent, name: &str) -> bool {
+    [&agent.system_prompt, &agent.prompt].iter().any(|template| {
+        parse_prompt_template(template).iter().any(|part| {
+            matches!(part, TemplatePart::Variable(used) | TemplatePart::SectionStart { name: used, .. } if *used == name)
+        })
+    })
+}
 
-    let estimate_label_width = |label: &str| -> u32 {
-        let mut width = 0f32;
-        for ch in label.chars() {
-            width += if is_cjk_char(ch) {
-                11.4
-            } else if ch.is_ascii_uppercase() {
-                7.6
-            } else if ch.is_ascii_lowercase() {
-                6.8
-            } else if ch.is_ascii_digit() {
-                6.5
-            } else if ch.is_ascii_whitespace() {
-                3.8
-            } else {
-                7.8
-            };
-        }
-        width.ceil() as u32
+/// Renders a custom agent's system and user prompts with the runtime variables and the
+/// user-defined constants.
+fn custom_agent_prompts(
+    agent: &CustomAgent,
+    settings: &AppSettings,
+    text: &str,
+    translate_to: Option<&str>,
+    source: &SelectionSourceWindow,
+) -> (String, String) {
+    let clipboard = if agent_template_uses(agent, "clipboard") {
+        read_clipboard_text_trimmed().unwrap_or_default()
+    } else {
+        String::new()
     };
-
-    let item_width_for_label = |label: &str| -> u32 {
-        if compact_mode {
-            return ITEM_COMPACT_WIDTH;
+    let language_name = |code: &str| {
+        find_language(code)
+            .map(|language| language.english_name.to_string())
+            .unwrap_or_else(|| code.to_string())
+    };
+    let ui_language = language_name(&settings.language);
+    let target_language =
+        language_name(translate_to.unwrap_or(&settings.selection_assistant.default_translate_to));
+    let now = Local::now();
+    let date = now.format("%Y-%m-%d").to_string();
+    let time = now.format("%H:%M").to_string();
+    let variable = |name: &str| -> Option<&str> {
+        match name {
+            "text" => Some(text),
+            "ui_language" => Some(&ui_language),
+            "target_language" => Some(&target_language),
+            "clipboard" => Some(&clipboard),
+            "source_app" => Some(source.process_name.as_deref().unwrap_or("")),
+            "window_title" => Some(source.window_title.as_deref().unwrap_or("")),
+            "date" => Some(&date),
+            "time" => Some(&time),
+            "app_name" => Some(APP_DISPLAY_NAME),
+            _ => settings
+                .agents
+                .constants
+                .iter()
+                .find(|constant| constant.name == name)
+                .map(|constant| constant.value.as_str()),
         }
-        let label_width = estimate_label_width(label).clamp(12, 128);
-        ITEM_PADDING_X
-            .saturating_add(ITEM_ICON_WIDTH)
-            .saturating_add(ITEM_TEXT_GAP)
-            .saturating_add(label_width)
     };
+    let system_prompt = if agent.system_prompt.is_empty() {
+        custom_agent_system_prompt().to_string()
+    } else {
+        render_prompt_template(&agent.system_prompt, variable)
+    };
+    (
+        system_prompt,
+        render_prompt_template(&agent.prompt, variable),
+    )
+}
 
-    let order =
-        normalize_selection_bar_order(settings.agents.bar_order.clone(), &settings.agents.custom);
-    let mut action_count = 0u32;
-    let mut action_width = 0u32;
-
-    for item in order {
-        if !item.enabled {
+fn is_list_item_line(line: &str) -> bool {
+    let trimmed = line.trim_start();
+    if ["- ", "* ", "• ", "· "]
+        .iter()
+        .any(|marker| trimmed.starts_with(marker))
+    {
+        return true;
+    }
+    let digits = trimmed.chars().take_while(char::is_ascii_digit).count();
+    digits > 0
+        && trimmed[digits..].starts_with(['.', ')', '、'])
+        && trimmed[digits..]
+            .chars()
+            .nth(1)
+            .is_some_and(char::is_whitespace)
+}
+
+/// Joins hard-wrapped lines (typical of OCR and PDF copies) back into paragraphs, keeping
+/// blank-line paragraph breaks and list items.
+fn fix_line_breaks(text: &str) -> String {
+    let mut paragraphs: Vec<String> = Vec::new();
+    let mut current = String::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() {
+            if !current.is_empty() {
+                paragraphs.push(std::mem::take(&mut current));
+            }
             continue;
         }
-
-        if is_builtin_selection_bar_key(&item.key) {
-            action_count = action_count.saturating_add(1);
-            let label = match item.key.as_str() {
-                "copy" => "复制",
-                "summarize" => "总结",
-                "polish" => "优化",
-                "explain" => "解释",
-                "translate" => "翻译",
-                "search" => "搜索",
-                _ => "",
-            };
-            action_width = action_width.saturating_add(item_width_for_label(label));
+        if current.is_empty() {
+            current.push_str(line);
             continue;
         }
+        let previous = current.chars().last().unwrap_or(' ');
+        let next = line.chars().next().unwrap_or(' ');
+        if is_list_item_line(line) {
+            current.push('\n');
+        } else if previous == '-'
+            && current
+                .chars()
+                .rev()
+                .nth(1)
+                .is_some_and(char::is_alphabetic)
+            && next.is_lowercase()
+        {
+            current.pop();
+        } else if !(is_cjk_char(previous) || is_cjk_char(next)) {
+            current.push(' ');
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        paragraphs.push(current);
+    }
+    paragraphs.join("\n\n")
+}
 
-        let Some(custom_id) = parse_custom_selection_bar_key(&item.key) else {
-            continue;
-        };
-        let Some(agent) = settings
-            .agents
-            .custom
-            .iter()
-            .find(|entry| entry.id == custom_id)
-        else {
-            continue;
-        };
-        action_count = action_count.saturating_add(1);
-        action_width = action_width.saturating_add(item_width_for_label(&agent.name));
+/// Parses `[label](target)` starting at `start`; returns the label and the index after `)`.
+fn markdown_link_at(chars: &[char], start: usize) -> Option<(String, usize)> {
+    if chars.get(start) != Some(&'[') {
+        return None;
+    }
+    let close = start + chars[start..].iter().position(|ch| *ch == ']')?;
+    if chars.get(close + 1) != Some(&'(') {
+        return None;
     }
+    let end = close + 1 + chars[close + 1..].iter().position(|ch| *ch == ')')?;
+    Some((chars[start + 1..close].iter().collect(), end + 1))
+}
 
-    let item_count = 1u32.saturating_add(action_count);
-    let gap = if compact_mode {
-        GAP_COMPACT
-    } else {
-        GAP_NORMAL
-    };
-    let shell_padding = if compact_mode {
-        SHELL_PADDING_COMPACT
-    } else {
-        SHELL_PADDING_NORMAL
-    };
-    let gap_width = item_count.saturating_sub(1).saturating_mul(gap);
-    let estimated = BRAND_WIDTH
-        .saturating_add(action_width)
-        .saturating_add(gap_width)
-        .saturating_add(shell_padding)
-        .saturating_add(WIDTH_SAFETY_BUFFER);
+/// Removes common Markdown markup, keeping the readable text.
+fn strip_markdown(text: &str) -> String {
+    let mut output = Vec::new();
+    for line in text.lines() {
+        let mut line = line.trim_end();
+        if line.trim_start().starts_with("```") {
+            continue;
+        }
+        let trimmed = line.trim_start();
+        if let Some(rest) = trimmed.strip_prefix('>') {
+            line = rest.trim_start();
+        }
+        let heading = line.trim_start();
+        let hashes = heading.chars().take_while(|ch| *ch == '#').count();
+        if (1..=6).contains(&hashes) && heading[hashes..].starts_with(' ') {
+            line = heading[hashes..].trim_start();
+        }
 
-    if compact_mode {
-        estimated.clamp(148, 760)
-    } else {
-        estimated.clamp(220, 1600)
+        let chars: Vec<char> = line.chars().collect();
+        let mut cleaned = String::with_capacity(line.len());
+        let mut idx = 0;
+        while idx < chars.len() {
+            let ch = chars[idx];
+            let next = chars.get(idx + 1).copied();
+            match ch {
+                '*' if next == Some('*') => {
+                    idx += 2;
+                    continue;
+                }
+                // `__` inside a word (snake__case) is not emphasis.
+                '_' if next == Some('_')
+                    && !(idx > 0
+                        && chars[idx - 1].is_alphanumeric()
+                        && chars
+                            .get(idx + 2)
+                            .is_some_and(|item| item.is_alphanumeric())) =>
+                {
+                    idx += 2;
+                    continue;
+                }
+                '`' => {
+                    idx += 1;
+                    continue;
+                }
+                '[' | '!' => {
+                    let start = if ch == '!' { idx + 1 } else { idx };
+                    if let Some((label, end)) = markdown_link_at(&chars, start) {
+                        cleaned.push_str(&label);
+                        idx = end;
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+            cleaned.push(ch);
+            idx += 1;
+        }
+        output.push(cleaned);
+    }
+    output.join("\n")
+}
+
+fn apply_local_transform(transform: LocalTransform, text: &str) -> String {
+    match transform {
+        LocalTransform::FixLineBreaks => fix_line_breaks(text),
+        LocalTransform::CollapseWhitespace => text
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        LocalTransform::RemoveEmptyLines => text
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        LocalTransform::StripMarkdown => strip_markdown(text),
+    }
+    .trim()
+    .to_string()
+}
+
+fn workflow_step_label(step: &WorkflowStep) -> String {
+    match step.kind {
+        WorkflowStepKind::Builtin => match step.action {
+            WorkflowBuiltinAction::Translate => "翻译",
+            WorkflowBuiltinAction::Summarize => "总结",
+            WorkflowBuiltinAction::Polish => "润色",
+            WorkflowBuiltinAction::Explain => "解释",
+        },
+        WorkflowStepKind::Prompt => "自定义提示词",
+        WorkflowStepKind::Transform => match step.transform {
+            LocalTransform::FixLineBreaks => "修复换行",
+            LocalTransform::CollapseWhitespace => "合并空白",
+            LocalTransform::RemoveEmptyLines => "删除空行",
+            LocalTransform::StripMarkdown => "去除 Markdown",
+        },
     }
+    .to_string()
 }
 
-fn selection_bar_target_position<R: Runtime>(
-    app: &AppHandle<R>,
-    x: i32,
-    y: i32,
-    width: u32,
-    height: u32,
-) -> PhysicalPosition<i32> {
-    let pointer = PhysicalPosition::new(x, y);
-    let candidate = PhysicalPosition::new(x.saturating_add(8), y.saturating_add(10));
-    if let Some(monitor) = monitor_for_point(app, pointer) {
-        clamp_position_to_monitor(&monitor, candidate, width, height)
-    } else {
-        candidate
-    }
+/// Progress of a workflow run; the last model step's messages back follow-up questions.
+#[derive(Default)]
+struct WorkflowRun {
+    steps: Vec<WorkflowStepPayload>,
+    messages: Vec<ChatMessage>,
 }
 
-fn emit_selection_detected<R: Runtime>(app: &AppHandle<R>, payload: SelectionDetectedPayload) {
-    let _ = app.emit(SELECTION_DETECTED_EVENT, payload);
+/// Runs a workflow agent's steps in order, feeding each step's output into the next one.
+/// `on_progress` sees every step so far, including the one still streaming, and returns
+/// false to cancel.
+#[allow(clippy::too_many_arguments)]
+async fn run_workflow<R: Runtime>(
+    app: &AppHandle<R>,
+    settings: &AppSettings,
+    agent: &CustomAgent,
+    input: &str,
+    translate_to: Option<&str>,
+    source: &SelectionSourceWindow,
+    regenerate: bool,
+    run: &mut WorkflowRun,
+    mut on_progress: impl FnMut(&[WorkflowStepPayload]) -> bool,
+) -> Result<LlmActionOutcome, CommandError> {
+    let mut current = input.to_string();
+    let mut outcome = LlmActionOutcome {
+        text: String::new(),
+        reasoning: String::new(),
+        from_cache: true,
+        usage: None,
+        provider: None,
+        tool_calls: Vec::new(),
+    };
+    let mut used_model = false;
+    for step in &agent.steps {
+        run.steps.push(WorkflowStepPayload {
+            label: workflow_step_label(step),
+            output: String::new(),
+            done: false,
+        });
+        if !on_progress(&run.steps) {
+            return Err(CommandError::Model(ModelError::Canceled));
+        }
+
+        let (action, (system_prompt, user_prompt), params) = match step.kind {
+            WorkflowStepKind::Transform => {
+                current = apply_local_transform(step.transform, &current);
+                if let Some(last) = run.steps.last_mut() {
+                    last.output = current.clone();
+                    last.done = true;
+                }
+                continue;
+            }
+            WorkflowStepKind::Builtin => {
+                let kind = step.action.kind();
+                let prompts = if step.action == WorkflowBuiltinAction::Translate {
+                    let to = resolve_translate_target(settings, translate_to, &current);
+                    translate_prompt(settings, &current, Some("auto"), Some(&to))
+                } else {
+                    choose_builtin_prompt(kind, &current, None, None, settings)
+                };
+                let params = settings
+                    .agents
+                    .builtin
+                    .for_kind(kind)
+                    .map(|config| config.params.clone())
+                    .unwrap_or_default();
+                (step.action.action_name(), prompts, params)
+            }
+            WorkflowStepKind::Prompt => {
+                let step_agent = CustomAgent {
+                    prompt: step.prompt.clone(),
+                    ..agent.clone()
+                };
+                let prompts =
+                    custom_agent_prompts(&step_agent, settings, &current, translate_to, source);
+                ("custom", prompts, agent.params.clone())
+            }
+        };
+
+        let messages = single_turn_messages(&system_prompt, &user_prompt);
+        let mut step_output = String::new();
+        let mut last_emit_ms = 0u64;
+        let step_outcome = run_llm_action(
+            app,
+            settings,
+            action,
+            &messages,
+            &params,
+            regenerate,
+            |delta| {
+                if let StreamDelta::Answer(text) = delta {
+                    step_output.push_str(text);
+                }
+                let now = now_epoch_millis();
+                if now.saturating_sub(last_emit_ms) < STREAM_EMIT_THROTTLE_MS {
+                    return true;
+                }
+                last_emit_ms = now;
+                if let Some(last) = run.steps.last_mut() {
+                    last.output = step_output.clone();
+                }
+                on_progress(&run.steps)
+            },
+        )
+        .await?;
+
+        current = step_outcome.text.trim().to_string();
+        if let Some(last) = run.steps.last_mut() {
+            last.output = current.clone();
+            last.done = true;
+        }
+        used_model = true;
+        outcome.from_cache &= step_outcome.from_cache;
+        outcome.usage = merge_request_usage(outcome.usage, step_outcome.usage);
+        outcome.provider = step_outcome.provider.or(outcome.provider);
+        outcome.reasoning = step_outcome.reasoning;
+        run.messages = messages;
+    }
+    if !on_progress(&run.steps) {
+        return Err(CommandError::Model(ModelError::Canceled));
+    }
+    outcome.text = current;
+    outcome.from_cache &= used_model;
+    Ok(outcome)
 }
 
-fn emit_selection_result<R: Runtime>(app: &AppHandle<R>, payload: SelectionResultPayload) {
-    let _ = app.emit(SELECTION_RESULT_UPDATED_EVENT, payload);
+/// Parses an agent's output schema; empty means free-form text.
+fn parse_output_schema(text: &str) -> Result<Option<serde_json::Value>, String> {
+    if text.trim().is_empty() {
+        return Ok(None);
+    }
+    if text.chars().count() > MAX_OUTPUT_SCHEMA_CHARS {
+        return Err(format!("不能超过 {MAX_OUTPUT_SCHEMA_CHARS} 个字符"));
+    }
+    let schema =
+        serde_json::from_str::<serde_json::Value>(text).map_err(|error| error.to_string())?;
+    check_output_schema_node(&schema, "$")?;
+    Ok(Some(schema))
 }
 
-fn emit_selection_error<R: Runtime>(app: &AppHandle<R>, message: &str) {
-    let _ = app.emit(SELECTION_ERROR_EVENT, message.to_string());
+fn schema_type_names(schema: &serde_json::Value) -> Option<Vec<&str>> {
+    match schema.get("type")? {
+        serde_json::Value::String(name) => Some(vec![name.as_str()]),
+        serde_json::Value::Array(names) => names.iter().map(|name| name.as_str()).collect(),
+        _ => None,
+    }
 }
 
-fn emit_ocr_capture_started<R: Runtime>(app: &AppHandle<R>) {
-    let _ = app.emit(OCR_CAPTURE_STARTED_EVENT, true);
+/// Rejects schema nodes the local validator would misread, such as unknown type names.
+fn check_output_schema_node(schema: &serde_json::Value, path: &str) -> Result<(), String> {
+    let Some(node) = schema.as_object() else {
+        return Err(format!("{path} 必须是 JSON 对象"));
+    };
+    if node.contains_key("type") {
+        let names = schema_type_names(schema)
+            .ok_or_else(|| format!("{path}.type 必须是字符串或字符串数组"))?;
+        if let Some(unknown) = names.iter().find(|name| !JSON_SCHEMA_TYPES.contains(name)) {
+            return Err(format!("{path}.type 不支持 {unknown}"));
+        }
+    }
+    if let Some(properties) = node.get("properties") {
+        let Some(properties) = properties.as_object() else {
+            return Err(format!("{path}.properties 必须是 JSON 对象"));
+        };
+        for (name, property) in properties {
+            check_output_schema_node(property, &format!("{path}.properties.{name}"))?;
+        }
+    }
+    if let Some(required) = node.get("required") {
+        if !required
+            .as_array()
+            .is_some_and(|names| names.iter().all(serde_json::Value::is_string))
+        {
+            return Err(format!("{path}.required 必须是字符串数组"));
+        }
+    }
+    if let Some(items) = node.get("items") {
+        check_output_schema_node(items, &format!("{path}.items"))?;
+    }
+    Ok(())
 }
 
-fn emit_ocr_capture_canceled<R: Runtime>(app: &AppHandle<R>) {
-    let _ = app.emit(OCR_CAPTURE_CANCELED_EVENT, true);
+fn json_type_name(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "boolean",
+        serde_json::Value::Number(number) if number.is_f64() => "number",
+        serde_json::Value::Number(_) => "integer",
+        serde_json::Value::String(_) => "string",
+        serde_json::Value::Array(_) => "array",
+        serde_json::Value::Object(_) => "object",
+    }
 }
 
-fn emit_ocr_result<R: Runtime>(app: &AppHandle<R>, payload: OcrResultPayload) {
-    let _ = app.emit(OCR_RESULT_UPDATED_EVENT, payload);
+fn json_type_matches(value: &serde_json::Value, name: &str) -> bool {
+    match name {
+        "number" => value.is_number(),
+        "integer" => value.as_f64().is_some_and(|number| number.fract() == 0.0),
+        _ => json_type_name(value) == name,
+    }
 }
 
-fn emit_ocr_error<R: Runtime>(app: &AppHandle<R>, message: &str) {
-    let _ = app.emit(OCR_ERROR_EVENT, message.to_string());
+/// Checks a value against the JSON Schema keywords agents commonly use: `type`, `enum`,
+/// `properties`, `required`, `additionalProperties`, `items`, `minItems`/`maxItems` and
+/// `minimum`/`maximum`. Other keywords are ignored. Returns the first mismatch.
+fn validate_json_value(
+    value: &serde_json::Value,
+    schema: &serde_json::Value,
+    path: &str,
+) -> Result<(), String> {
+    if !schema.is_object() {
+        return Ok(());
+    }
+    if let Some(names) = schema_type_names(schema) {
+        if !names.iter().any(|name| json_type_matches(value, name)) {
+            return Err(format!(
+                "{path}: expected {}, got {}",
+                names.join(" or "),
+                json_type_name(value)
+            ));
+        }
+    }
+    if let Some(options) = schema.get("enum").and_then(|options| options.as_array()) {
+        if !options.contains(value) {
+            return Err(format!("{path}: must be one of {}", json!(options)));
+        }
+    }
+    if let Some(number) = value.as_f64() {
+        if let Some(minimum) = schema["minimum"].as_f64().filter(|min| number < *min) {
+            return Err(format!("{path}: must be at least {minimum}"));
+        }
+        if let Some(maximum) = schema["maximum"].as_f64().filter(|max| number > *max) {
+            return Err(format!("{path}: must be at most {maximum}"));
+        }
+    }
+    match value {
+        serde_json::Value::Object(map) => {
+            if let Some(required) = schema["required"].as_array() {
+                if let Some(missing) = required
+                    .iter()
+                    .filter_map(|name| name.as_str())
+                    .find(|name| !map.contains_key(*name))
+                {
+                    return Err(format!("{path}: missing required property \"{missing}\""));
+                }
+            }
+            for (key, item) in map {
+                let item_path = format!("{path}.{key}");
+                match schema["properties"].get(key) {
+                    Some(property) => validate_json_value(item, property, &item_path)?,
+                    None if schema["additionalProperties"] == json!(false) => {
+                        return Err(format!("{item_path}: property is not allowed"));
+                    }
+                    None => validate_json_value(item, &schema["additionalProperties"], &item_path)?,
+                }
+            }
+        }
+        serde_json::Value::Array(items) => {
+            let count = items.len() as u64;
+            if let Some(min_items) = schema["minItems"].as_u64().filter(|min| count < *min) {
+                return Err(format!("{path}: needs at least {min_items} items"));
+            }
+            if let Some(max_items) = schema["maxItems"].as_u64().filter(|max| count > *max) {
+                return Err(format!("{path}: allows at most {max_items} items"));
+            }
+            for (index, item) in items.iter().enumerate() {
+                validate_json_value(item, &schema["items"], &format!("{path}[{index}]"))?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
 }
 
-fn emit_history_updated<R: Runtime>(app: &AppHandle<R>, items: &[ClipboardEntry]) {
-    let _ = app.emit(HISTORY_UPDATED_EVENT, items.to_vec());
+/// Parses the JSON in a model reply, tolerating code fences or text around it.
+fn extract_json_value(raw: &str) -> Result<serde_json::Value, String> {
+    let trimmed = raw.trim();
+    if let Ok(value) = serde_json::from_str(trimmed) {
+        return Ok(value);
+    }
+    let start = trimmed
+        .find(['{', '['])
+        .ok_or_else(|| "the reply contains no JSON".to_string())?;
+    let close = if trimmed[start..].starts_with('{') {
+        '}'
+    } else {
+        ']'
+    };
+    let end = trimmed
+        .rfind(close)
+        .filter(|end| *end > start)
+        .ok_or_else(|| "the JSON is incomplete".to_string())?;
+    serde_json::from_str(&trimmed[start..=end]).map_err(|error| format!("invalid JSON: {error}"))
+}
+
+/// Runs a structured-output agent. The schema is appended to the system prompt and sent as the
+/// provider's `response_format`; a reply that fails to parse or validate is sent back with the
+/// error until one passes or the attempts run out. Only a reply that passes is cached. Retries
+/// stay in `messages` so follow-up questions see the corrected exchange.
+async fn run_structured_output<R: Runtime, F>(
+    app: &AppHandle<R>,
+    settings: &AppSettings,
+    schema: &serde_json::Value,
+    messages: &mut Vec<ChatMessage>,
+    params: &GenerationParams,
+    regenerate: bool,
+    mut on_delta: F,
+) -> Result<(LlmActionOutcome, serde_json::Value), CommandError>
+where
+    F: FnMut(StreamDelta<'_>) -> bool,
+{
+    if let Some(system) = messages
+        .first_mut()
+        .filter(|message| message.role == "system")
+    {
+        system.content.push_str(&format!(
+            "\n\nReturn only a JSON value that conforms to this JSON Schema, without markdown fences or commentary:\n{schema}"
+        ));
+    }
+    let params = GenerationParams {
+        json_schema: Some(schema.clone()),
+        ..params.clone()
+    };
+    // A reply validated after a retry answers the original request, so it is cached under it.
+    let cache_key = response_cache_key(&settings.llm, &params, messages);
+    let mut usage = None;
+    let mut last_error = String::new();
+    for attempt in 0..STRUCTURED_OUTPUT_MAX_ATTEMPTS {
+        let mut outcome = run_llm_action(
+            app,
+            settings,
+            "custom",
+            messages,
+            &params,
+            regenerate,
+            &mut on_delta,
+        )
+        .await?;
+        usage = merge_request_usage(usage, outcome.usage.take());
+        let checked = extract_json_value(&outcome.text)
+            .and_then(|value| validate_json_value(&value, schema, "$").map(|()| value));
+        match checked {
+            Ok(value) => {
+                let answered_by_primary = outcome
+                    .provider
+                    .as_ref()
+                    .is_some_and(|provider| !provider.is_fallback);
+                if answered_by_primary && settings.response_cache.enabled {
+                    store_cached_llm_response(
+                        app,
+                        &app.state::<ResponseCacheState>(),
+                        &settings.response_cache,
+                        &settings.llm,
+                        cache_key,
+                        &outcome.text,
+                    );
+                }
+                outcome.text = format!(
+                    "```json\n{}\n```",
+                    serde_json::to_string_pretty(&value).unwrap_or_default()
+                );
+                outcome.usage = usage;
+                return Ok((outcome, value));
+            }
+            Err(error) => {
+                if attempt + 1 < STRUCTURED_OUTPUT_MAX_ATTEMPTS {
+                    messages.push(ChatMessage::new("assistant", outcome.text));
+                    messages.push(ChatMessage::new(
+                        "user",
+                        format!(
+                            "The previous reply is invalid: {error}\nReply again with only the corrected JSON."
+                        ),
+                    ));
+                }
+                last_error = error;
+            }
+        }
+    }
+    Err(ModelError::InvalidStructuredOutput { detail: last_error }.into())
 }
 
-#[cfg(target_os = "windows")]
-fn apply_native_result_window_corner_preference<R: Runtime>(window: &tauri::WebviewWindow<R>) {
-    let Ok(hwnd) = window.hwnd() else {
-        return;
+/// Evaluates an arithmetic expression for the `evaluate_math` agent tool.
+fn evaluate_math_expression(expression: &str) -> Result<f64, String> {
+    if expression.chars().count() > MAX_MATH_EXPRESSION_CHARS {
+        return Err(format!(
+            "the expression is longer than {MAX_MATH_EXPRESSION_CHARS} characters"
+        ));
+    }
+    let mut parser = MathParser {
+        chars: expression
+            .chars()
+            .filter(|ch| !ch.is_whitespace())
+            .collect(),
+        pos: 0,
+        depth: 0,
     };
-    // Use native rounded window corners for stable rendering on Windows.
-    let preference: i32 = DWMWCP_ROUND;
-    unsafe {
-        let _ = DwmSetWindowAttribute(
-            hwnd.0 as HWND,
-            DWMWA_WINDOW_CORNER_PREFERENCE as u32,
-            &preference as *const _ as *const core::ffi::c_void,
-            std::mem::size_of::<i32>() as u32,
-        );
+    let value = parser.expression()?;
+    if let Some(ch) = parser.peek() {
+        return Err(format!("unexpected '{ch}'"));
+    }
+    if !value.is_finite() {
+        return Err("the result is not a finite number".to_string());
     }
+    Ok(value)
 }
 
-#[cfg(not(target_os = "windows"))]
-fn apply_native_result_window_corner_preference<R: Runtime>(_window: &tauri::WebviewWindow<R>) {}
-
-#[cfg(target_os = "windows")]
-fn acrylic_tint_alpha_for_settings(settings: &AppSettings) -> u8 {
-    (settings.appearance.window_opacity.clamp(0.0, 0.5) * 255.0).round() as u8
+struct MathParser {
+    chars: Vec<char>,
+    pos: usize,
+    depth: usize,
 }
 
-#[cfg(target_os = "windows")]
-fn apply_native_window_acrylic<R: Runtime>(app: &AppHandle<R>, settings: &AppSettings) {
-    let tint_alpha = acrylic_tint_alpha_for_settings(settings);
-    let labels = [
-        MAIN_WINDOW_LABEL,
-        SETTINGS_WINDOW_LABEL,
-        SELECTION_RESULT_WINDOW_LABEL,
-        OCR_RESULT_WINDOW_LABEL,
-    ];
-    for label in labels {
-        if let Some(window) = app.get_webview_window(label) {
-            let _ = window_vibrancy::apply_acrylic(&window, Some((0, 0, 0, tint_alpha)));
+impl MathParser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, ch: char) -> bool {
+        let matched = self.peek() == Some(ch);
+        if matched {
+            self.pos += 1;
         }
+        matched
     }
-}
 
-#[cfg(not(target_os = "windows"))]
-fn apply_native_window_acrylic<R: Runtime>(_app: &AppHandle<R>, _settings: &AppSettings) {}
+    /// Runs a recursive step, failing instead of overflowing the stack on deep nesting.
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<f64, String>) -> Result<f64, String> {
+        if self.depth >= MAX_MATH_NESTING {
+            return Err("expression too deeply nested".to_string());
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn expression(&mut self) -> Result<f64, String> {
+        let mut value = self.term()?;
+        loop {
+            if self.eat('+') {
+                value += self.term()?;
+            } else if self.eat('-') {
+                value -= self.term()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
 
-fn apply_result_windows_background<R: Runtime>(app: &AppHandle<R>, settings: &AppSettings) {
-    apply_native_window_acrylic(app, settings);
-    if let Some(window) = app.get_webview_window(SELECTION_RESULT_WINDOW_LABEL) {
-        apply_native_result_window_corner_preference(&window);
+    fn term(&mut self) -> Result<f64, String> {
+        let mut value = self.unary()?;
+        loop {
+            if self.eat('*') || self.eat('×') {
+                value *= self.unary()?;
+            } else if self.eat('/') || self.eat('÷') {
+                let divisor = self.unary()?;
+                if divisor == 0.0 {
+                    return Err("division by zero".to_string());
+                }
+                value /= divisor;
+            } else if self.eat('%') {
+                value %= self.unary()?;
+            } else {
+                return Ok(value);
+            }
+        }
     }
-    if let Some(window) = app.get_webview_window(OCR_RESULT_WINDOW_LABEL) {
-        apply_native_result_window_corner_preference(&window);
+
+    fn unary(&mut self) -> Result<f64, String> {
+        if self.eat('-') {
+            return Ok(-self.nested(Self::unary)?);
+        }
+        if self.eat('+') {
+            return self.nested(Self::unary);
+        }
+        let base = self.primary()?;
+        if self.eat('^') {
+            return Ok(base.powf(self.nested(Self::unary)?));
+        }
+        Ok(base)
     }
-}
 
-fn is_any_other_snapparse_window_focused<R: Runtime>(
-    app: &AppHandle<R>,
-    excluded_label: &str,
-) -> bool {
-    [
-        MAIN_WINDOW_LABEL,
-        SETTINGS_WINDOW_LABEL,
-        SELECTION_BAR_WINDOW_LABEL,
-        SELECTION_RESULT_WINDOW_LABEL,
-        OCR_CAPTURE_WINDOW_LABEL,
-        OCR_RESULT_WINDOW_LABEL,
-    ]
-    .iter()
-    .filter(|label| **label != excluded_label)
-    .any(|label| {
-        app.get_webview_window(label)
-            .and_then(|window| window.is_focused().ok())
-            .unwrap_or(false)
-    })
+    fn primary(&mut self) -> Result<f64, String> {
+        if self.eat('(') {
+            let value = self.nested(Self::expression)?;
+            return if self.eat(')') {
+                Ok(value)
+            } else {
+                Err("missing ')'".to_string())
+            };
+        }
+        let start = self.pos;
+        if self
+            .peek()
+            .is_some_and(|ch| ch.is_ascii_digit() || ch == '.')
+        {
+            while self
+                .peek()
+                .is_some_and(|ch| ch.is_ascii_digit() || ch == '.')
+            {
+                self.pos += 1;
+            }
+            let literal: String = self.chars[start..self.pos].iter().collect();
+            return literal
+                .parse()
+                .map_err(|_| format!("invalid number {literal}"));
+        }
+        while self.peek().is_some_and(|ch| ch.is_ascii_alphabetic()) {
+            self.pos += 1;
+        }
+        let name = self.chars[start..self.pos]
+            .iter()
+            .collect::<String>()
+            .to_ascii_lowercase();
+        match name.as_str() {
+            "" => Err(match self.peek() {
+                Some(ch) => format!("unexpected '{ch}'"),
+                None => "unexpected end of expression".to_string(),
+            }),
+            "pi" => Ok(std::f64::consts::PI),
+            "e" => Ok(std::f64::consts::E),
+            _ => {
+                let function: fn(f64) -> f64 = match name.as_str() {
+                    "sqrt" => f64::sqrt,
+                    "abs" => f64::abs,
+                    "ln" => f64::ln,
+                    "log" => f64::log10,
+                    "exp" => f64::exp,
+                    "sin" => f64::sin,
+                    "cos" => f64::cos,
+                    "tan" => f64::tan,
+                    "round" => f64::round,
+                    "floor" => f64::floor,
+                    "ceil" => f64::ceil,
+                    _ => return Err(format!("unknown name {name}")),
+                };
+                if !self.eat('(') {
+                    return Err(format!("{name} needs parentheses"));
+                }
+                let argument = self.nested(Self::expression)?;
+                if !self.eat(')') {
+                    return Err("missing ')'".to_string());
+                }
+                Ok(function(argument))
+            }
+        }
+    }
 }
 
-fn should_hide_window_after_blur<R: Runtime>(app: &AppHandle<R>, label: &str) -> bool {
-    let Some(window) = app.get_webview_window(label) else {
-        return false;
-    };
-    let still_visible = window.is_visible().unwrap_or(false);
-    let still_unfocused = window.is_focused().map(|focused| !focused).unwrap_or(false);
-    still_visible && still_unfocused && !is_any_other_snapparse_window_focused(app, label)
+fn format_math_result(value: f64) -> String {
+    let text = format!("{value:.12}");
+    text.trim_end_matches('0').trim_end_matches('.').to_string()
 }
 
-fn suppress_selection_result_blur_auto_hide<R: Runtime>(app: &AppHandle<R>, delay_ms: u64) {
-    if let Some(runtime) = app.try_state::<SelectionRuntimeState>() {
-        runtime.suppress_result_blur_until_ms.store(
-            now_epoch_millis().saturating_add(delay_ms),
-            Ordering::Relaxed,
-        );
+fn search_clipboard_history_text<R: Runtime>(
+    app: &AppHandle<R>,
+    query: &str,
+    limit: usize,
+) -> Result<String, String> {
+    let needle = query.trim().to_lowercase();
+    if needle.is_empty() {
+        return Err("query is empty".to_string());
+    }
+    let state = app.state::<Mutex<ClipboardState>>();
+    let locked = state
+        .lock()
+        .map_err(|_| "clipboard history is busy".to_string())?;
+    let matches = locked
+        .history
+        .iter()
+        .filter(|entry| matches!(entry.kind, ClipboardKind::Text | ClipboardKind::Link))
+        .filter(|entry| entry.content.to_lowercase().contains(&needle))
+        .take(limit)
+        .map(|entry| {
+            format!(
+                "[{}] {}",
+                entry
+                    .copied_at
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M"),
+                truncate_text_chars(&entry.content, 500)
+            )
+        })
+        .collect::<Vec<_>>();
+    if matches.is_empty() {
+        Ok("No matching clipboard entries.".to_string())
+    } else {
+        Ok(matches.join("\n"))
     }
 }
 
-fn suppress_ocr_result_blur_auto_hide<R: Runtime>(app: &AppHandle<R>, delay_ms: u64) {
-    if let Some(runtime) = app.try_state::<OcrRuntimeState>() {
-        runtime.suppress_result_blur_until_ms.store(
-            now_epoch_millis().saturating_add(delay_ms),
-            Ordering::Relaxed,
+/// Pins `text` in the clipboard history, adding it first when it is not there yet.
+fn save_snippet_to_history<R: Runtime>(
+    app: &AppHandle<R>,
+    settings: &AppSettings,
+    text: &str,
+) -> Result<(), CommandError> {
+    let content = text.trim().to_string();
+    if content.is_empty() {
+        return Err(CommandError::Settings("内容为空".to_string()));
+    }
+    let state = app.state::<Mutex<ClipboardState>>();
+    let mut locked = state.lock().map_err(|_| CommandError::Lock)?;
+    let mut found = false;
+    for entry in locked.history.iter_mut() {
+        if matches!(entry.kind, ClipboardKind::Text | ClipboardKind::Link)
+            && entry.content == content
+        {
+            entry.pinned = true;
+            found = true;
+        }
+    }
+    if !found {
+        let mut incoming = build_text_entry(content);
+        incoming.pinned = true;
+        insert_or_promote(
+            &mut locked.history,
+            incoming,
+            settings.history.max_items,
+            settings.history.dedupe,
         );
     }
+    let updated = collect_history(&locked.history);
+    drop(locked);
+    persist_history_snapshot(app, settings, &updated)?;
+    emit_history_updated(app, &updated);
+    Ok(())
 }
 
-fn selection_result_window_is_pinned<R: Runtime>(app: &AppHandle<R>) -> bool {
-    let pinned_by_window = app
-        .get_webview_window(SELECTION_RESULT_WINDOW_LABEL)
-        .and_then(|window| {
-            if !window.is_visible().ok().unwrap_or(false) {
-                return None;
-            }
-            window.is_always_on_top().ok()
-        });
-    let pinned_by_settings = app
-        .try_state::<AppSettingsState>()
-        .and_then(|state| {
-            state
-                .data
-                .lock()
-                .ok()
-                .map(|settings| settings.selection_assistant.result_window_always_on_top)
-        })
-        .unwrap_or(false);
-    pinned_by_window.unwrap_or(pinned_by_settings)
-}
-
-fn ocr_result_window_is_pinned<R: Runtime>(app: &AppHandle<R>) -> bool {
-    let pinned_by_window = app
-        .get_webview_window(OCR_RESULT_WINDOW_LABEL)
-        .and_then(|window| {
-            if !window.is_visible().ok().unwrap_or(false) {
-                return None;
-            }
-            window.is_always_on_top().ok()
-        });
-    let pinned_by_settings = app
-        .try_state::<AppSettingsState>()
-        .and_then(|state| {
-            state
-                .data
-                .lock()
-                .ok()
-                .map(|settings| settings.ocr.result_window_always_on_top)
-        })
-        .unwrap_or(false);
-    pinned_by_window.unwrap_or(pinned_by_settings)
+/// Runs one agent tool call; errors are reported back to the model as the tool result.
+fn execute_agent_tool<R: Runtime>(
+    app: &AppHandle<R>,
+    settings: &AppSettings,
+    tool: AgentTool,
+    arguments: &str,
+) -> Result<String, String> {
+    let arguments = if arguments.trim().is_empty() {
+        json!({})
+    } else {
+        serde_json::from_str::<serde_json::Value>(arguments)
+            .map_err(|error| format!("invalid arguments: {error}"))?
+    };
+    let text_argument = |name: &str| {
+        arguments[name]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| format!("missing \"{name}\""))
+    };
+    let output = match tool {
+        AgentTool::ReadClipboard => read_clipboard_text_trimmed()
+            .unwrap_or_else(|| "The clipboard has no text.".to_string()),
+        AgentTool::SearchClipboardHistory => {
+            let limit = arguments["limit"]
+                .as_u64()
+                .map_or(5, |limit| limit as usize)
+                .clamp(1, MAX_TOOL_HISTORY_RESULTS);
+            search_clipboard_history_text(app, &text_argument("query")?, limit)?
+        }
+        AgentTool::CurrentDatetime => Local::now()
+            .format("%Y-%m-%d %H:%M:%S %:z (%A)")
+            .to_string(),
+        AgentTool::EvaluateMath => {
+            evaluate_math_expression(&text_argument("expression")?).map(format_math_result)?
+        }
+        AgentTool::OpenUrl => {
+            let url = text_argument("url")?;
+            open_in_default_browser(&url).map_err(|error| error.to_string())?;
+            format!("Opened {}", url.trim())
+        }
+        AgentTool::SaveSnippet => {
+            save_snippet_to_history(app, settings, &text_argument("text")?)
+                .map_err(|error| error.to_string())?;
+            "Saved to favorites.".to_string()
+        }
+    };
+    Ok(truncate_text_chars(&output, MAX_TOOL_OUTPUT_CHARS))
 }
 
-fn show_selection_bar_window<R: Runtime>(
+/// `run_llm_action` for actions that may use agent tools. Without tools this is one request;
+/// with tools, the calls the model asks for run locally and are answered until it replies in
+/// text, and the last allowed round disables tool calls so it has to. The exchange stays out
+/// of `messages` so follow-ups only see the final answer; `trace` records every call.
+#[allow(clippy::too_many_arguments)]
+async fn run_agent_llm_action<R: Runtime, F>(
     app: &AppHandle<R>,
-    payload: &SelectionDetectedPayload,
-) -> Result<(), CommandError> {
-    let Some(window) = app.get_webview_window(SELECTION_BAR_WINDOW_LABEL) else {
-        return Err(CommandError::Settings(
-            "Selection bar window not found".to_string(),
-        ));
+    settings: &AppSettings,
+    action: &str,
+    messages: &[ChatMessage],
+    params: &GenerationParams,
+    regenerate: bool,
+    tools: &[AgentTool],
+    trace: &mut Vec<ToolCallTracePayload>,
+    mut on_delta: F,
+) -> Result<LlmActionOutcome, CommandError>
+where
+    F: FnMut(StreamDelta<'_>) -> bool,
+{
+    if tools.is_empty() {
+        return run_llm_action(
+            app, settings, action, messages, params, regenerate, on_delta,
+        )
+        .await;
+    }
+    let mut exchange = messages.to_vec();
+    let mut params = GenerationParams {
+        tools: tools.iter().map(|tool| tool.definition()).collect(),
+        ..params.clone()
     };
-
-    let mut width = app
-        .try_state::<AppSettingsState>()
-        .and_then(|settings_state| {
-            settings_state
-                .data
-                .lock()
-                .ok()
-                .map(|settings| selection_bar_width_for_settings(&settings))
-        })
-        .unwrap_or(DEFAULT_SELECTION_BAR_WIDTH);
-    let height = SELECTION_BAR_HEIGHT;
-
-    // Keep width within current monitor logical bounds to prevent any horizontal overflow.
-    let pointer = PhysicalPosition::new(payload.x, payload.y);
-    if let Some(monitor) = monitor_for_point(app, pointer) {
-        let monitor_width_px = monitor.size().width;
-        let monitor_logical_width = ((monitor_width_px as f64) / monitor.scale_factor().max(0.1))
-            .floor()
-            .clamp(0.0, u32::MAX as f64) as u32;
-        let max_safe_width = monitor_logical_width.saturating_sub(12).max(180);
-        width = width.min(max_safe_width);
+    let mut usage = None;
+    for round in 0..=MAX_TOOL_CALL_ROUNDS {
+        params.disable_tool_calls = round == MAX_TOOL_CALL_ROUNDS;
+        // Tool results depend on when they ran, so cached replies are never reused.
+        let mut outcome = run_llm_action(
+            app,
+            settings,
+            action,
+            &exchange,
+            &params,
+            true,
+            &mut on_delta,
+        )
+        .await?;
+        usage = merge_request_usage(usage, outcome.usage.take());
+        if outcome.tool_calls.is_empty() {
+            outcome.usage = usage;
+            return Ok(outcome);
+        }
+        let mut assistant = ChatMessage::new("assistant", outcome.text.clone());
+        for (index, call) in outcome.tool_calls.iter_mut().enumerate() {
+            if call.id.is_empty() {
+                call.id = format!("call_{round}_{index}");
+            }
+            assistant.tool_calls.push(call.to_wire());
+        }
+        exchange.push(assistant);
+        for call in &outcome.tool_calls {
+            let result = match AgentTool::from_function_name(&call.name)
+                .filter(|tool| tools.contains(tool))
+            {
+                Some(tool) => execute_agent_tool(app, settings, tool, &call.arguments),
+                None => Err(format!("unknown tool {}", call.name)),
+            };
+            let is_error = result.is_err();
+            let output = result.unwrap_or_else(|error| format!("Error: {error}"));
+            trace.push(ToolCallTracePayload {
+                name: call.name.clone(),
+                arguments: call.arguments.clone(),
+                output: output.clone(),
+                is_error,
+            });
+            let mut reply = ChatMessage::new("tool", output);
+            reply.tool_call_id = Some(call.id.clone());
+            exchange.push(reply);
+        }
     }
-
-    // Keep terminal/native selection highlight: show bar without activating it first.
-    if let Some(runtime) = app.try_state::<SelectionRuntimeState>() {
-        bump_selection_bar_close_guard(&runtime);
+    Err(ModelError::ToolLoopExceeded {
+        rounds: MAX_TOOL_CALL_ROUNDS,
     }
-    let _ = window.set_focusable(false);
-    let _ = window.set_size(Size::Logical(LogicalSize::new(width as f64, height as f64)));
-    let target = selection_bar_target_position(app, payload.x, payload.y, width, height);
-    let _ = window.set_position(Position::Physical(target));
-    let _ = window.show();
-    let app_handle = app.clone();
-    std::thread::spawn(move || {
-        std::thread::sleep(Duration::from_millis(80));
-        if let Some(window) = app_handle.get_webview_window(SELECTION_BAR_WINDOW_LABEL) {
-            let _ = window.set_focusable(true);
-        }
-    });
-    Ok(())
+    .into())
 }
 
-fn hide_selection_bar_window<R: Runtime>(app: &AppHandle<R>) {
-    if let Some(runtime) = app.try_state::<SelectionRuntimeState>() {
-        remember_selection_bar_hidden(&runtime);
+/// How a custom agent runs and what it produced besides text. Shared by the selection and OCR
+/// result commands, which only differ in how they stream updates to their windows.
+#[derive(Default)]
+struct AgentRun {
+    workflow_agent: Option<CustomAgent>,
+    output_schema: Option<serde_json::Value>,
+    tools: Vec<AgentTool>,
+    workflow: WorkflowRun,
+    structured_output: Option<serde_json::Value>,
+    tool_trace: Vec<ToolCallTracePayload>,
+}
+
+impl AgentRun {
+    fn for_agent(agent: &CustomAgent) -> Result<Self, CommandError> {
+        if !agent.steps.is_empty() {
+            return Ok(Self {
+                workflow_agent: Some(agent.clone()),
+                ..Self::default()
+            });
+        }
+        check_agent_tool_combination(agent)?;
+        Ok(Self {
+            output_schema: parse_output_schema(&agent.output_schema)
+                .map_err(CommandError::Settings)?,
+            tools: agent.tools.clone(),
+            ..Self::default()
+        })
+    }
+
+    /// Runs a workflow or structured-output agent. Returns `None` for plain chat agents, which
+    /// the caller runs with `tools` and `tool_trace` so it keeps its own streaming display.
+    #[allow(clippy::too_many_arguments)]
+    async fn run_special<R: Runtime>(
+        &mut self,
+        app: &AppHandle<R>,
+        settings: &AppSettings,
+        input: &str,
+        translate_to: Option<&str>,
+        source: &SelectionSourceWindow,
+        regenerate: bool,
+        params: &GenerationParams,
+        messages: &mut Vec<ChatMessage>,
+        streamed_output: &mut String,
+        streamed_reasoning: &mut String,
+        is_active: impl Fn() -> bool,
+        on_steps: impl FnMut(&[WorkflowStepPayload]) -> bool,
+    ) -> Option<Result<LlmActionOutcome, CommandError>> {
+        if let Some(agent) = &self.workflow_agent {
+            let result = run_workflow(
+                app,
+                settings,
+                agent,
+                input,
+                translate_to,
+                source,
+                regenerate,
+                &mut self.workflow,
+                on_steps,
+            )
+            .await;
+            if !self.workflow.messages.is_empty() {
+                *messages = std::mem::take(&mut self.workflow.messages);
+            }
+            *streamed_output = self
+                .workflow
+                .steps
+                .last()
+                .map(|step| step.output.clone())
+                .unwrap_or_default();
+            return Some(result);
+        }
+        let schema = self.output_schema.as_ref()?;
+        let result = run_structured_output(
+            app,
+            settings,
+            schema,
+            messages,
+            params,
+            regenerate,
+            |delta| {
+                if let StreamDelta::Reasoning(text) = delta {
+                    streamed_reasoning.push_str(text);
+                }
+                is_active()
+            },
+        )
+        .await
+        .map(|(outcome, value)| {
+            self.structured_output = Some(value);
+            outcome
+        });
+        Some(result)
     }
-    if let Some(window) = app.get_webview_window(SELECTION_BAR_WINDOW_LABEL) {
-        let _ = window.hide();
+
+    /// Hands over the workflow steps, tool trace and structured value for a result payload.
+    fn take_outputs(
+        &mut self,
+    ) -> (
+        Vec<WorkflowStepPayload>,
+        Vec<ToolCallTracePayload>,
+        Option<serde_json::Value>,
+    ) {
+        (
+            std::mem::take(&mut self.workflow.steps),
+            std::mem::take(&mut self.tool_trace),
+            self.structured_output.take(),
+        )
     }
 }
 
-fn show_selection_result_window<R: Runtime>(app: &AppHandle<R>) -> Result<(), CommandError> {
-    let Some(settings_state) = app.try_state::<AppSettingsState>() else {
-        return Err(CommandError::Settings(
-            "Settings state unavailable".to_string(),
-        ));
-    };
-    let settings_snapshot = settings_state
-        .data
-        .lock()
-        .map(|settings| settings.clone())
-        .unwrap_or_else(|_| AppSettings::default());
-    let always_on_top = settings_snapshot
-        .selection_assistant
-        .result_window_always_on_top;
+fn custom_agent_system_prompt() -> &'static str {
+    "You are a configurable text-processing assistant.
+Follow the user's custom instruction exactly.
+Requirements:
+- Return only the final processed text.
+- Do not add preface, labels, markdown fences, or explanations unless explicitly requested.
+- Preserve factual details, names, numbers, code snippets, and URLs accurately.
+- Respect the language, tone, and format requested by the user."
+}
 
-    let Some(window) = app.get_webview_window(SELECTION_RESULT_WINDOW_LABEL) else {
-        return Err(CommandError::Settings(
-            "Selection result window not found".to_string(),
-        ));
-    };
+fn extract_llm_text_content(value: &serde_json::Value) -> String {
+    if let Some(text) = value
+        .get("choices")
+        .and_then(|choices| choices.get(0))
+        .and_then(|item| item.get("text"))
+        .and_then(|text| text.as_str())
+    {
+        return text.trim().to_string();
+    }
 
-    let target_width = clamp_selection_result_window_width(
-        settings_snapshot
-            .selection_result_window_width
-            .unwrap_or(DEFAULT_SELECTION_RESULT_WINDOW_WIDTH),
-    );
-    let target_height = clamp_selection_result_window_height(
-        settings_snapshot
-            .selection_result_window_height
-            .unwrap_or(DEFAULT_SELECTION_RESULT_WINDOW_HEIGHT),
-    );
+    if let Some(content) = value
+        .get("choices")
+        .and_then(|choices| choices.get(0))
+        .and_then(|item| item.get("message"))
+        .and_then(|message| message.get("content"))
+    {
+        if let Some(text) = content.as_str() {
+            return text.trim().to_string();
+        }
 
-    if settings_snapshot.window.remember_main_window_size {
-        let _ = window.set_size(Size::Logical(LogicalSize::new(
-            target_width as f64,
-            target_height as f64,
-        )));
+        if let Some(items) = content.as_array() {
+            let mut merged = String::new();
+            for item in items {
+                if let Some(text) = item.get("text").and_then(|value| value.as_str()) {
+                    merged.push_str(text);
+                }
+            }
+            return merged.trim().to_string();
+        }
     }
 
-    if let Some(target_position) = target_position_with_memory(
-        app,
-        settings_snapshot
-            .selection_assistant
-            .remember_result_window_position,
-        settings_snapshot.selection_result_window_x,
-        settings_snapshot.selection_result_window_y,
-        target_width,
-        target_height,
-    ) {
-        let _ = window.set_position(Position::Physical(target_position));
-        if settings_snapshot
-            .selection_assistant
-            .remember_result_window_position
-            && (settings_snapshot.selection_result_window_x != Some(target_position.x)
-                || settings_snapshot.selection_result_window_y != Some(target_position.y))
-        {
-            if let Ok(mut settings) = settings_state.data.lock() {
-                settings.selection_result_window_x = Some(target_position.x);
-                settings.selection_result_window_y = Some(target_position.y);
+    if let Some(output_text) = value.get("output_text").and_then(|item| item.as_str()) {
+        return output_text.trim().to_string();
+    }
+
+    if let Some(output_items) = value.get("output").and_then(|item| item.as_array()) {
+        let mut merged = String::new();
+        for output_item in output_items {
+            if let Some(content_items) = output_item.get("content").and_then(|item| item.as_array())
+            {
+                for content_item in content_items {
+                    if let Some(text) = content_item.get("text").and_then(|item| item.as_str()) {
+                        merged.push_str(text);
+                    }
+                }
             }
-            let _ = persist_settings_state(&settings_state);
+        }
+        if !merged.trim().is_empty() {
+            return merged.trim().to_string();
         }
     }
 
-    let _ = window.set_always_on_top(always_on_top);
-    suppress_selection_result_blur_auto_hide(app, RESULT_WINDOW_BLUR_SUPPRESS_MS);
-    let _ = window.show();
-    let _ = window.set_focus();
-    Ok(())
+    String::new()
 }
 
-fn show_ocr_capture_window<R: Runtime>(app: &AppHandle<R>) -> Result<(), CommandError> {
-    let Some(window) = app.get_webview_window(OCR_CAPTURE_WINDOW_LABEL) else {
-        return Err(CommandError::Settings(
-            "OCR capture window not found".to_string(),
-        ));
-    };
-
-    let mut target_position: Option<PhysicalPosition<i32>> = None;
-    let mut target_size: Option<tauri::PhysicalSize<u32>> = None;
-    let pointer = current_pointer_position().unwrap_or(PhysicalPosition::new(40, 40));
-    if let Some(monitor) =
-        monitor_for_point(app, pointer).or_else(|| app.primary_monitor().ok().flatten())
-    {
-        let position = *monitor.position();
-        let size = tauri::PhysicalSize::new(monitor.size().width, monitor.size().height);
-        target_position = Some(position);
-        target_size = Some(size);
-        let _ = window.set_position(Position::Physical(position));
-        let _ = window.set_size(Size::Physical(size));
+fn build_auth_header_value(api_key: &str, prefer_bearer: bool) -> String {
+    let trimmed = api_key.trim();
+    if trimmed.to_ascii_lowercase().starts_with("bearer ") {
+        trimmed.to_string()
+    } else if prefer_bearer {
+        format!("Bearer {trimmed}")
+    } else {
+        trimmed.to_string()
     }
-
-    // Keep source app popups/tooltips alive while drawing OCR region:
-    // show capture overlay without activating/focusing this window.
-    let _ = window.set_focusable(false);
-    let _ = window.show();
-    reinforce_ocr_capture_window_front(app, target_position, target_size);
-    Ok(())
 }
 
-fn hide_ocr_capture_window<R: Runtime>(app: &AppHandle<R>) {
-    if let Some(window) = app.get_webview_window(OCR_CAPTURE_WINDOW_LABEL) {
-        let _ = window.hide();
+fn auth_header_candidates(api_key: &str, prefer_bearer: bool) -> Vec<String> {
+    let mut candidates = Vec::<String>::new();
+    let preferred = build_auth_header_value(api_key, prefer_bearer);
+    push_unique_string(&mut candidates, preferred);
+    if !api_key.trim().to_ascii_lowercase().starts_with("bearer ") {
+        push_unique_string(
+            &mut candidates,
+            build_auth_header_value(api_key, !prefer_bearer),
+        );
     }
+    candidates
 }
 
-fn show_ocr_result_window<R: Runtime>(app: &AppHandle<R>) -> Result<(), CommandError> {
-    let Some(settings_state) = app.try_state::<AppSettingsState>() else {
-        return Err(CommandError::Settings(
-            "Settings state unavailable".to_string(),
-        ));
-    };
-    let settings_snapshot = settings_state
-        .data
-        .lock()
-        .map(|settings| settings.clone())
-        .unwrap_or_else(|_| AppSettings::default());
-    let always_on_top = settings_snapshot.ocr.result_window_always_on_top;
-
-    let Some(window) = app.get_webview_window(OCR_RESULT_WINDOW_LABEL) else {
-        return Err(CommandError::Settings(
-            "OCR result window not found".to_string(),
-        ));
-    };
-
-    let target_width = clamp_ocr_result_window_width(
-        settings_snapshot
-            .ocr_result_window_width
-            .unwrap_or(DEFAULT_OCR_RESULT_WINDOW_WIDTH),
-    );
-    let target_height = clamp_ocr_result_window_height(
-        settings_snapshot
-            .ocr_result_window_height
-            .unwrap_or(DEFAULT_OCR_RESULT_WINDOW_HEIGHT),
-    );
+fn should_use_glm_layout_parsing(vision: &VisionSettings) -> bool {
+    let base_url = vision.base_url.trim().to_ascii_lowercase();
+    let model = vision.model.trim().to_ascii_lowercase();
+    base_url.contains("/layout_parsing")
+        || base_url.contains("open.bigmodel.cn")
+        || model.starts_with("glm-ocr")
+}
 
-    if settings_snapshot.window.remember_main_window_size {
-        let _ = window.set_size(Size::Logical(LogicalSize::new(
-            target_width as f64,
-            target_height as f64,
-        )));
+fn resolve_glm_layout_parsing_endpoint(vision: &VisionSettings) -> String {
+    let base_url = vision.base_url.trim();
+    let model = vision.model.trim().to_ascii_lowercase();
+    if base_url.is_empty()
+        || (model.starts_with("glm-ocr")
+            && base_url.to_ascii_lowercase().contains("/chat/completions"))
+    {
+        "https://open.bigmodel.cn/api/paas/v4/layout_parsing".to_string()
+    } else {
+        base_url.to_string()
     }
+}
 
-    if let Some(target_position) = target_position_with_memory(
-        app,
-        settings_snapshot.ocr.remember_result_window_position,
-        settings_snapshot.ocr_result_window_x,
-        settings_snapshot.ocr_result_window_y,
-        target_width,
-        target_height,
-    ) {
-        let _ = window.set_position(Position::Physical(target_position));
-        if settings_snapshot.ocr.remember_result_window_position
-            && (settings_snapshot.ocr_result_window_x != Some(target_position.x)
-                || settings_snapshot.ocr_result_window_y != Some(target_position.y))
-        {
-            if let Ok(mut settings) = settings_state.data.lock() {
-                settings.ocr_result_window_x = Some(target_position.x);
-                settings.ocr_result_window_y = Some(target_position.y);
-            }
-            let _ = persist_settings_state(&settings_state);
+fn push_unique_string(bucket: &mut Vec<String>, value: String) {
+    if value.trim().is_empty() {
+        return;
+    }
+    if bucket.iter().any(|item| item == &value) {
+        return;
+    }
+    bucket.push(value);
+}
+
+fn vision_file_payload_candidates(image_data_url: &str) -> Vec<String> {
+    let mut candidates = Vec::<String>::new();
+    let value = image_data_url.trim();
+
+    if value.starts_with("http://") || value.starts_with("https://") {
+        push_unique_string(&mut candidates, value.to_string());
+        return candidates;
+    }
+
+    // Keep original payload first (e.g. data:image/png;base64,...) so server can infer type.
+    push_unique_string(&mut candidates, value.to_string());
+
+    if let Some((prefix, payload)) = value.split_once(',') {
+        if prefix.to_ascii_lowercase().contains("base64") {
+            push_unique_string(&mut candidates, payload.trim().to_string());
         }
     }
 
-    let _ = window.set_always_on_top(always_on_top);
-    suppress_ocr_result_blur_auto_hide(app, RESULT_WINDOW_BLUR_SUPPRESS_MS);
-    let _ = window.show();
-    let _ = window.set_focus();
-    Ok(())
+    candidates
 }
 
-fn deactivate_ocr_capture_runtime(ocr_runtime: &OcrRuntimeState) {
-    ocr_runtime.capture_active.store(false, Ordering::Relaxed);
-    ocr_runtime
-        .suppress_blur_until_ms
-        .store(0, Ordering::Relaxed);
+fn push_candidate_text(raw: &str, bucket: &mut Vec<String>) {
+    let trimmed = raw.trim();
+    if trimmed.is_empty() {
+        return;
+    }
+    if bucket.iter().any(|item| item == trimmed) {
+        return;
+    }
+    bucket.push(trimmed.to_string());
 }
 
-fn reset_ocr_capture_runtime_state(ocr_runtime: &OcrRuntimeState) {
-    deactivate_ocr_capture_runtime(ocr_runtime);
-    if let Ok(mut snapshot) = ocr_runtime.capture_snapshot.lock() {
-        *snapshot = None;
+fn collect_ocr_text_candidates(value: &serde_json::Value, bucket: &mut Vec<String>) {
+    if let Some(text) = value.as_str() {
+        push_candidate_text(text, bucket);
+        return;
     }
-}
 
-fn reassert_ocr_capture_window_front<R: Runtime>(
-    app: &AppHandle<R>,
-    target_position: Option<PhysicalPosition<i32>>,
-    target_size: Option<tauri::PhysicalSize<u32>>,
-) {
-    let Some(window) = app.get_webview_window(OCR_CAPTURE_WINDOW_LABEL) else {
+    if let Some(items) = value.as_array() {
+        for item in items {
+            collect_ocr_text_candidates(item, bucket);
+        }
+        return;
+    }
+
+    let Some(map) = value.as_object() else {
         return;
     };
 
-    if let Some(position) = target_position {
-        let _ = window.set_position(Position::Physical(position));
+    for key in [
+        "text",
+        "content",
+        "markdown",
+        "md_results",
+        "md_result",
+        "ocr_text",
+        "full_text",
+        "output_text",
+    ] {
+        if let Some(item) = map.get(key) {
+            collect_ocr_text_candidates(item, bucket);
+        }
     }
-    if let Some(size) = target_size {
-        let _ = window.set_size(Size::Physical(size));
+    for key in [
+        "result",
+        "results",
+        "data",
+        "pages",
+        "blocks",
+        "items",
+        "layout_details",
+        "layout_visualization",
+        "paragraphs",
+        "lines",
+    ] {
+        if let Some(item) = map.get(key) {
+            collect_ocr_text_candidates(item, bucket);
+        }
     }
-    let _ = window.show();
-    let _ = window.set_focusable(false);
-    let _ = window.set_always_on_top(false);
-    let _ = window.set_always_on_top(true);
 }
 
-fn reinforce_ocr_capture_window_front<R: Runtime>(
-    app: &AppHandle<R>,
-    target_position: Option<PhysicalPosition<i32>>,
-    target_size: Option<tauri::PhysicalSize<u32>>,
-) {
-    reassert_ocr_capture_window_front(app, target_position, target_size);
-
-    for delay_ms in [
-        OCR_CAPTURE_TOPMOST_REASSERT_SHORT_MS,
-        OCR_CAPTURE_TOPMOST_REASSERT_LONG_MS,
-    ] {
-        let app_handle = app.clone();
-        std::thread::spawn(move || {
-            std::thread::sleep(Duration::from_millis(delay_ms));
-            reassert_ocr_capture_window_front(&app_handle, target_position, target_size);
-        });
+fn strip_markdown_heading_prefix(line: &str) -> Option<&str> {
+    let trimmed = line.trim_start();
+    let bytes = trimmed.as_bytes();
+    let mut idx = 0usize;
+    while idx < bytes.len() && idx < 6 && bytes[idx] == b'#' {
+        idx += 1;
+    }
+    if idx == 0 || idx >= bytes.len() || !bytes[idx].is_ascii_whitespace() {
+        return None;
     }
+    Some(trimmed[idx..].trim_start())
 }
 
-fn start_ocr_capture_workflow<R: Runtime>(app: &AppHandle<R>) -> Result<(), CommandError> {
-    let Some(settings_state) = app.try_state::<AppSettingsState>() else {
-        return Err(CommandError::Settings(
-            "Settings state unavailable".to_string(),
-        ));
-    };
-    let enabled = settings_state
-        .data
-        .lock()
-        .map(|settings| settings.ocr.enabled)
-        .unwrap_or(false);
-    if !enabled {
-        return Err(CommandError::Settings(
-            "请先在设置中启用智能 OCR".to_string(),
-        ));
+fn normalize_ocr_text_output(raw: &str) -> String {
+    let lines = raw
+        .lines()
+        .map(|line| {
+            if let Some(rest) = strip_markdown_heading_prefix(line) {
+                rest.to_string()
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>();
+    lines.join("\n").trim().to_string()
+}
+
+fn extract_vision_ocr_text(value: &serde_json::Value) -> String {
+    if let Some(markdown) = value.get("md_results").and_then(|item| item.as_str()) {
+        let normalized = normalize_ocr_text_output(markdown);
+        if !normalized.is_empty() {
+            return normalized;
+        }
     }
 
-    let Some(ocr_runtime) = app.try_state::<OcrRuntimeState>() else {
+    let mut candidates = Vec::<String>::new();
+    collect_ocr_text_candidates(value, &mut candidates);
+
+    let longest = candidates
+        .into_iter()
+        .max_by_key(|item| item.chars().count())
+        .unwrap_or_default();
+
+    if !longest.is_empty() {
+        return normalize_ocr_text_output(&longest);
+    }
+
+    normalize_ocr_text_output(&extract_llm_text_content(value))
+}
+
+async fn call_glm_layout_parsing_ocr(
+    client: &reqwest::Client,
+    rate_limiter: &ModelRateLimitState,
+    vision: &VisionSettings,
+    image_data_url: &str,
+) -> Result<ModelCompletion, CommandError> {
+    let endpoint = resolve_glm_layout_parsing_endpoint(vision);
+    let auth_headers = auth_header_candidates(&vision.api_key, false);
+    let file_candidates = vision_file_payload_candidates(image_data_url);
+    if file_candidates.is_empty() {
         return Err(CommandError::Settings(
-            "OCR runtime state unavailable".to_string(),
+            "OCR image payload is empty".to_string(),
         ));
-    };
-    if !app
-        .get_webview_window(OCR_CAPTURE_WINDOW_LABEL)
-        .and_then(|window| window.is_visible().ok())
-        .unwrap_or(false)
-        && ocr_runtime.capture_active.load(Ordering::Relaxed)
-    {
-        reset_ocr_capture_runtime_state(&ocr_runtime);
-    }
-    ocr_runtime.capture_active.store(true, Ordering::Relaxed);
-    ocr_runtime.suppress_blur_until_ms.store(
-        now_epoch_millis().saturating_add(OCR_CAPTURE_BLUR_SUPPRESS_MS),
-        Ordering::Relaxed,
-    );
-    if let Err(error) = refresh_ocr_capture_snapshot(&ocr_runtime) {
-        eprintln!("[OCR] preload capture snapshot failed: {error}");
-        if let Ok(mut snapshot) = ocr_runtime.capture_snapshot.lock() {
-            *snapshot = None;
-        }
     }
-    show_ocr_capture_window(app)?;
-    emit_ocr_capture_started(app);
-    Ok(())
-}
 
-fn open_in_default_browser(url: &str) -> Result<(), CommandError> {
-    let normalized = url.trim();
-    let lowered = normalized.to_ascii_lowercase();
-    if normalized.is_empty()
-        || normalized.contains('\r')
-        || normalized.contains('\n')
-        || !(lowered.starts_with("http://") || lowered.starts_with("https://"))
-    {
-        return Err(CommandError::Settings(
-            "搜索链接无效，仅支持 http/https 协议".to_string(),
-        ));
-    }
+    let model_name = vision.model.trim();
+    let mut last_error: Option<ModelError> = None;
+    let rate_limit_key = model_rate_limit_key(&endpoint, model_name);
+    let estimated_tokens = vision
+        .max_tokens
+        .saturating_add(VISION_IMAGE_TOKEN_ESTIMATE);
+    for file_payload in file_candidates {
+        for (auth_index, auth_header) in auth_headers.iter().enumerate() {
+            let request_body = json!({
+                "model": model_name,
+                "file": file_payload
+            });
+
+            let mut final_status: Option<reqwest::StatusCode> = None;
+            let mut final_headers = reqwest::header::HeaderMap::new();
+            let mut final_value: serde_json::Value = json!({});
+            let mut final_raw_body = String::new();
+
+            for attempt in 0..MODEL_REQUEST_MAX_ATTEMPTS {
+                acquire_model_rate_slot(
+                    rate_limiter,
+                    &rate_limit_key,
+                    vision.requests_per_minute,
+                    vision.tokens_per_minute,
+                    estimated_tokens,
+                )
+                .await?;
+                let response = match client
+                    .post(&endpoint)
+                    .timeout(Duration::from_millis(vision.timeout_ms))
+                    .header(AUTHORIZATION, auth_header.as_str())
+                    .header(CONTENT_TYPE, "application/json")
+                    .headers(extra_header_map(&vision.extra_headers))
+                    .json(&request_body)
+                    .send()
+                    .await
+                {
+                    Ok(resp) => resp,
+                    Err(error) => {
+                        if should_retry_network_error(&error)
+                            && attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS
+                        {
+                            last_error = Some(ModelError::from(error));
+                            sleep_with_backoff(attempt).await;
+                            continue;
+                        }
+                        return Err(ModelError::from(error).into());
+                    }
+                };
+
+                let status = response.status();
+                let headers = response.headers().clone();
+                note_model_rate_limit_headers(rate_limiter, &rate_limit_key, &headers);
+                let raw_body = match read_response_body_lossy(response).await {
+                    Ok(value) => value,
+                    Err(error) => {
+                        if should_retry_network_error(&error)
+                            && attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS
+                        {
+                            last_error = Some(ModelError::from(error));
+                            sleep_with_backoff(attempt).await;
+                            continue;
+                        }
+                        return Err(ModelError::from(error).into());
+                    }
+                };
+                let parsed_value = serde_json::from_str::<serde_json::Value>(&raw_body)
+                    .unwrap_or_else(|_| {
+                        if raw_body.trim().is_empty() {
+                            json!({ "error": "Unknown GLM OCR response" })
+                        } else {
+                            json!({ "raw": format_response_body_for_error(&raw_body) })
+                        }
+                    });
+
+                let retry_hint_ms = rate_limit_retry_delay_ms(&headers);
+                final_status = Some(status);
+                final_headers = headers;
+                final_value = parsed_value;
+                final_raw_body = raw_body;
 
-    #[cfg(target_os = "windows")]
-    {
-        let open: Vec<u16> = OsStr::new("open").encode_wide().chain(Some(0)).collect();
-        let target: Vec<u16> = OsStr::new(normalized)
-            .encode_wide()
-            .chain(Some(0))
-            .collect();
-        let result = unsafe {
-            ShellExecuteW(
-                std::ptr::null_mut(),
-                open.as_ptr(),
-                target.as_ptr(),
-                std::ptr::null(),
-                std::ptr::null(),
-                SW_SHOWNORMAL,
-            )
-        } as isize;
-        if result <= 32 {
-            return Err(CommandError::Settings(format!(
-                "无法使用默认浏览器打开链接（系统错误码: {result}）"
-            )));
-        }
-        Ok(())
-    }
+                if should_retry_http_status(status)
+                    && attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS
+                    && sleep_before_retry(attempt, retry_hint_ms).await
+                {
+                    continue;
+                }
+                break;
+            }
 
-    #[cfg(not(target_os = "windows"))]
-    {
-        let _ = url;
-        Err(CommandError::Settings(
-            "Open browser is currently implemented for Windows only".to_string(),
-        ))
-    }
-}
+            let Some(status) = final_status else {
+                continue;
+            };
+            let value = final_value;
 
-fn resolve_tts_voice(
-    tts: &TtsSettings,
-    language_hint: Option<&str>,
-    voice_override: Option<&str>,
-    text: &str,
-) -> String {
-    if let Some(override_voice) = voice_override {
-        let trimmed = override_voice.trim();
-        if !trimmed.is_empty() {
-            return trimmed.to_string();
-        }
-    }
+            if !status.is_success() {
+                let detail = if final_raw_body.trim().is_empty() {
+                    value.to_string()
+                } else {
+                    final_raw_body
+                };
+                let is_auth_error = status.as_u16() == 401 || status.as_u16() == 403;
+                last_error = Some(classify_http_failure(status, &final_headers, &detail));
+                if auth_index == 0 && !is_auth_error {
+                    break;
+                }
+                continue;
+            }
 
-    let mut cjk_count = 0usize;
-    let mut latin_count = 0usize;
-    for ch in text.chars().take(1200) {
-        if ('\u{4E00}'..='\u{9FFF}').contains(&ch)
-            || ('\u{3400}'..='\u{4DBF}').contains(&ch)
-            || ('\u{3040}'..='\u{30FF}').contains(&ch)
-            || ('\u{AC00}'..='\u{D7AF}').contains(&ch)
-        {
-            cjk_count = cjk_count.saturating_add(1);
-        } else if ch.is_ascii_alphabetic() {
-            latin_count = latin_count.saturating_add(1);
+            let text = extract_vision_ocr_text(&value).trim().to_string();
+            if text.is_empty() {
+                last_error = Some(ModelError::EmptyResponse);
+                continue;
+            }
+            let usage = parse_token_usage(&value)
+                .unwrap_or_else(|| estimated_token_usage(VISION_IMAGE_TOKEN_ESTIMATE, &text));
+            return Ok(ModelCompletion {
+                text,
+                reasoning: String::new(),
+                usage: Some(usage),
+                tool_calls: Vec::new(),
+            });
         }
     }
-    if cjk_count > 0 && cjk_count >= latin_count / 2 {
-        return tts.voice_zh_cn.clone();
-    }
-    if latin_count > 0 {
-        return tts.voice_en_us.clone();
-    }
 
-    let hint = language_hint
-        .unwrap_or_default()
-        .trim()
-        .to_ascii_lowercase();
-    if hint.starts_with("zh") {
-        tts.voice_zh_cn.clone()
-    } else {
-        tts.voice_en_us.clone()
-    }
+    Err(last_error.unwrap_or(ModelError::EmptyResponse).into())
 }
 
-fn format_tts_rate_arg(rate_percent: i32) -> String {
-    let clamped = rate_percent.clamp(MIN_TTS_RATE_PERCENT, MAX_TTS_RATE_PERCENT);
-    if clamped >= 0 {
-        format!("+{}%", clamped)
-    } else {
-        format!("{}%", clamped)
+fn extract_stream_delta_text(value: &serde_json::Value) -> String {
+    let Some(choice) = value.get("choices").and_then(|choices| choices.get(0)) else {
+        return String::new();
+    };
+
+    if let Some(content) = choice
+        .get("delta")
+        .and_then(|delta| delta.get("content"))
+        .and_then(|content| content.as_str())
+    {
+        return content.to_string();
     }
-}
 
-fn configure_background_command(command: &mut Command) {
-    #[cfg(target_os = "windows")]
+    if let Some(items) = choice
+        .get("delta")
+        .and_then(|delta| delta.get("content"))
+        .and_then(|content| content.as_array())
     {
-        command.creation_flags(CREATE_NO_WINDOW);
+        let mut merged = String::new();
+        for item in items {
+            if let Some(text) = item.get("text").and_then(|value| value.as_str()) {
+                merged.push_str(text);
+            }
+        }
+        return merged;
     }
+
+    String::new()
 }
 
-fn run_edge_tts_process(
-    executable: &str,
-    prefix_args: &[&str],
-    voice: &str,
-    rate: &str,
-    text: &str,
-    output_path: &Path,
-) -> Result<(), String> {
-    let mut args = prefix_args
+fn extract_stream_reasoning_delta(value: &serde_json::Value) -> String {
+    let Some(delta) = value
+        .get("choices")
+        .and_then(|choices| choices.get(0))
+        .and_then(|choice| choice.get("delta"))
+    else {
+        return String::new();
+    };
+    ["reasoning_content", "reasoning"]
         .iter()
-        .map(|item| (*item).to_string())
-        .collect::<Vec<_>>();
-    args.extend([
-        "--voice".to_string(),
-        voice.to_string(),
-        "--rate".to_string(),
-        rate.to_string(),
-        "--text".to_string(),
-        text.to_string(),
-        "--write-media".to_string(),
-        output_path.to_string_lossy().into_owned(),
-    ]);
-
-    let mut command = Command::new(executable);
-    configure_background_command(&mut command);
-    let output = command
-        .args(args)
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::piped())
-        .output()
-        .map_err(|error| format!("{executable}: {error}"))?;
-
-    if output.status.success() {
-        return Ok(());
-    }
+        .find_map(|key| delta.get(*key).and_then(|value| value.as_str()))
+        .unwrap_or_default()
+        .to_string()
+}
 
-    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
-    if stderr.is_empty() {
-        Err(format!(
-            "{executable}: exited with status {}",
-            output.status
-        ))
-    } else {
-        Err(format!("{executable}: {stderr}"))
-    }
+fn extract_llm_reasoning_content(value: &serde_json::Value) -> String {
+    let Some(message) = value
+        .get("choices")
+        .and_then(|choices| choices.get(0))
+        .and_then(|choice| choice.get("message"))
+    else {
+        return String::new();
+    };
+    ["reasoning_content", "reasoning"]
+        .iter()
+        .find_map(|key| message.get(*key).and_then(|value| value.as_str()))
+        .unwrap_or_default()
+        .trim()
+        .to_string()
 }
 
-fn is_missing_edge_tts_error(message: &str) -> bool {
-    let lowered = message.to_ascii_lowercase();
-    lowered.contains("no module named edge_tts")
-        || lowered.contains("is not recognized")
-        || lowered.contains("not recognized as")
-        || lowered.contains("not found")
-        || lowered.contains("cannot find")
-        || lowered.contains("no such file")
+enum StreamDelta<'a> {
+    Answer(&'a str),
+    Reasoning(&'a str),
 }
 
-fn has_edge_tts_runtime() -> bool {
-    let probes: [(&str, &[&str]); 3] = [
-        ("edge-tts", &["--help"]),
-        ("python", &["-m", "edge_tts", "--help"]),
-        ("py", &["-m", "edge_tts", "--help"]),
-    ];
+const THINK_OPEN_TAG: &str = "<think>";
+const THINK_CLOSE_TAG: &str = "</think>";
 
-    for (executable, args) in probes {
-        let mut command = Command::new(executable);
-        configure_background_command(&mut command);
-        let output = command
-            .args(args)
-            .stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .output();
-        if output
-            .map(|result| result.status.success())
-            .unwrap_or(false)
-        {
-            return true;
+/// Splits inline `<think>...</think>` blocks out of answer text as it streams in. A trailing
+/// fragment that could be the start of a tag is held back until the next chunk arrives.
+#[derive(Default)]
+struct ThinkTagSplitter {
+    in_think: bool,
+    pending: String,
+}
+
+impl ThinkTagSplitter {
+    fn push(&mut self, chunk: &str) -> (String, String) {
+        self.pending.push_str(chunk);
+        let mut answer = String::new();
+        let mut reasoning = String::new();
+        loop {
+            let tag = if self.in_think {
+                THINK_CLOSE_TAG
+            } else {
+                THINK_OPEN_TAG
+            };
+            let target = if self.in_think {
+                &mut reasoning
+            } else {
+                &mut answer
+            };
+            if let Some(index) = self.pending.find(tag) {
+                target.push_str(&self.pending[..index]);
+                self.pending.drain(..index + tag.len());
+                self.in_think = !self.in_think;
+                continue;
+            }
+            let keep = (1..tag.len())
+                .rev()
+                .find(|len| self.pending.ends_with(&tag[..*len]))
+                .unwrap_or(0);
+            let emit_len = self.pending.len() - keep;
+            target.push_str(&self.pending[..emit_len]);
+            self.pending.drain(..emit_len);
+            return (answer, reasoning);
         }
     }
 
-    false
+    fn finish(&mut self) -> (String, String) {
+        let rest = std::mem::take(&mut self.pending);
+        if self.in_think {
+            (String::new(), rest)
+        } else {
+            (rest, String::new())
+        }
+    }
 }
 
-fn try_auto_install_edge_tts() -> Result<(), String> {
-    let mut errors = Vec::new();
-    for executable in ["python", "py"] {
-        let mut command = Command::new(executable);
-        configure_background_command(&mut command);
-        let output = match command
-            .args([
-                "-m",
-                "pip",
-                "install",
-                "edge-tts",
-                "--disable-pip-version-check",
-            ])
-            .stdin(Stdio::null())
-            .stdout(Stdio::null())
-            .stderr(Stdio::piped())
-            .output()
-        {
-            Ok(result) => result,
-            Err(error) => {
-                errors.push(format!("{executable}: {error}"));
-                continue;
-            }
-        };
+fn split_think_tags(text: &str) -> (String, String) {
+    let mut splitter = ThinkTagSplitter::default();
+    let (mut answer, mut reasoning) = splitter.push(text);
+    let (answer_tail, reasoning_tail) = splitter.finish();
+    answer.push_str(&answer_tail);
+    reasoning.push_str(&reasoning_tail);
+    (answer.trim().to_string(), reasoning.trim().to_string())
+}
 
-        if output.status.success() {
-            return Ok(());
+#[derive(Default)]
+struct StreamAccumulator {
+    content: String,
+    reasoning: String,
+    usage: Option<TokenUsage>,
+    tool_calls: Vec<ToolCallRequest>,
+    think_splitter: ThinkTagSplitter,
+}
+
+impl StreamAccumulator {
+    fn emit(
+        &mut self,
+        answer: &str,
+        reasoning: &str,
+        on_delta: &mut impl FnMut(StreamDelta<'_>) -> bool,
+    ) -> Result<(), CommandError> {
+        if !reasoning.is_empty() {
+            self.reasoning.push_str(reasoning);
+            if !on_delta(StreamDelta::Reasoning(reasoning)) {
+                return Err(ModelError::Canceled.into());
+            }
         }
-
-        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
-        if stderr.is_empty() {
-            errors.push(format!(
-                "{executable}: pip install exited with {}",
-                output.status
-            ));
-        } else {
-            errors.push(format!("{executable}: {stderr}"));
+        if !answer.is_empty() {
+            self.content.push_str(answer);
+            if !on_delta(StreamDelta::Answer(answer)) {
+                return Err(ModelError::Canceled.into());
+            }
         }
+        Ok(())
     }
 
-    if errors.is_empty() {
-        Err("未找到可用的 Python/pip 环境".to_string())
-    } else {
-        Err(errors.join(" | "))
+    fn push_content(
+        &mut self,
+        text: &str,
+        on_delta: &mut impl FnMut(StreamDelta<'_>) -> bool,
+    ) -> Result<(), CommandError> {
+        let (answer, reasoning) = self.think_splitter.push(text);
+        self.emit(&answer, &reasoning, on_delta)
     }
-}
 
-fn try_auto_install_edge_tts_with_lock() -> Result<(), String> {
-    if EDGE_TTS_AUTO_INSTALL_ATTEMPTED
-        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
-        .is_err()
-    {
-        return Err(EDGE_TTS_INSTALL_IN_PROGRESS.to_string());
+    fn finish(
+        &mut self,
+        on_delta: &mut impl FnMut(StreamDelta<'_>) -> bool,
+    ) -> Result<(), CommandError> {
+        let (answer, reasoning) = self.think_splitter.finish();
+        self.emit(&answer, &reasoning, on_delta)
     }
+}
 
-    match try_auto_install_edge_tts() {
-        Ok(()) => Ok(()),
-        Err(error) => {
-            EDGE_TTS_AUTO_INSTALL_ATTEMPTED.store(false, Ordering::Release);
-            Err(error)
-        }
+/// Reads a non-streamed chat completion, separating reasoning from the answer whether the
+/// provider reports it in a dedicated field or inline in `<think>` tags.
+fn completion_from_json_value(value: &serde_json::Value) -> StreamAccumulator {
+    let (answer, inline_reasoning) = split_think_tags(&extract_llm_text_content(value));
+    let reasoning = extract_llm_reasoning_content(value);
+    StreamAccumulator {
+        content: answer,
+        reasoning: if reasoning.is_empty() {
+            inline_reasoning
+        } else {
+            reasoning
+        },
+        usage: parse_token_usage(value),
+        tool_calls: extract_tool_calls(value),
+        think_splitter: ThinkTagSplitter::default(),
     }
 }
 
-fn execute_tts_attempt(
-    attempt_errors: &mut Vec<String>,
-    cmd: &str,
-    prefix: &[&str],
-    voice: &str,
-    rate: &str,
-    text: &str,
-    output_path: &Path,
-) -> bool {
-    match run_edge_tts_process(cmd, prefix, voice, rate, text, output_path) {
-        Ok(_) => true,
-        Err(error) => {
-            attempt_errors.push(error);
-            false
+fn extract_tool_calls(value: &serde_json::Value) -> Vec<ToolCallRequest> {
+    let Some(calls) = value
+        .pointer("/choices/0/message/tool_calls")
+        .and_then(|calls| calls.as_array())
+    else {
+        return Vec::new();
+    };
+    calls
+        .iter()
+        .map(|call| ToolCallRequest {
+            id: call["id"].as_str().unwrap_or_default().to_string(),
+            name: call["function"]["name"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            arguments: match &call["function"]["arguments"] {
+                serde_json::Value::String(arguments) => arguments.clone(),
+                serde_json::Value::Null => String::new(),
+                arguments => arguments.to_string(),
+            },
+        })
+        .collect()
+}
+
+/// Merges streamed `tool_calls` fragments: the id and name arrive once per call and the
+/// arguments in pieces, keyed by `index`.
+fn accumulate_tool_call_deltas(value: &serde_json::Value, calls: &mut Vec<ToolCallRequest>) {
+    let Some(deltas) = value
+        .pointer("/choices/0/delta/tool_calls")
+        .and_then(|deltas| deltas.as_array())
+    else {
+        return;
+    };
+    for delta in deltas {
+        let index = delta["index"].as_u64().unwrap_or(0);
+        let Some(index) = usize::try_from(index)
+            .ok()
+            .filter(|index| *index < MAX_TOOL_CALLS_PER_REPLY)
+        else {
+            continue;
+        };
+        if calls.len() <= index {
+            calls.resize_with(index + 1, ToolCallRequest::default);
+        }
+        let call = &mut calls[index];
+        if let Some(id) = delta["id"].as_str().filter(|id| !id.is_empty()) {
+            call.id = id.to_string();
+        }
+        if let Some(name) = delta["function"]["name"]
+            .as_str()
+            .filter(|name| !name.is_empty())
+        {
+            call.name = name.to_string();
+        }
+        if let Some(arguments) = delta["function"]["arguments"].as_str() {
+            call.arguments.push_str(arguments);
         }
     }
 }
 
-fn synthesize_tts_audio(
-    tts: &TtsSettings,
-    text: &str,
-    language_hint: Option<&str>,
-    voice_override: Option<&str>,
-) -> Result<SynthesizeTtsResult, CommandError> {
-    let text = text.trim();
-    if text.is_empty() {
-        return Err(CommandError::Settings("TTS 文本为空".to_string()));
+fn parse_token_usage(value: &serde_json::Value) -> Option<TokenUsage> {
+    let usage = value.get("usage").filter(|usage| usage.is_object())?;
+    let read = |keys: &[&str]| {
+        keys.iter()
+            .find_map(|key| usage.get(*key).and_then(|value| value.as_u64()))
+    };
+    let prompt_tokens = read(&["prompt_tokens", "input_tokens"]);
+    let completion_tokens = read(&["completion_tokens", "output_tokens"]);
+    let total_tokens = read(&["total_tokens"]);
+    if prompt_tokens.is_none() && completion_tokens.is_none() && total_tokens.is_none() {
+        return None;
     }
+    let prompt_tokens = prompt_tokens.unwrap_or(0);
+    let completion_tokens = completion_tokens
+        .unwrap_or_else(|| total_tokens.unwrap_or(0).saturating_sub(prompt_tokens));
+    Some(TokenUsage {
+        prompt_tokens,
+        completion_tokens,
+        total_tokens: total_tokens.unwrap_or(prompt_tokens + completion_tokens),
+        estimated: false,
+    })
+}
 
-    if text.chars().count() > MAX_TTS_TEXT_CHARS {
-        return Err(CommandError::Settings(format!(
-            "TTS 文本过长（最多 {MAX_TTS_TEXT_CHARS} 个字符）"
-        )));
+fn estimated_token_usage(prompt_tokens: u32, output_text: &str) -> TokenUsage {
+    let completion_tokens = u64::from(estimate_text_tokens(output_text));
+    TokenUsage {
+        prompt_tokens: u64::from(prompt_tokens),
+        completion_tokens,
+        total_tokens: u64::from(prompt_tokens) + completion_tokens,
+        estimated: true,
     }
+}
 
-    let voice = resolve_tts_voice(tts, language_hint, voice_override, text);
-    let rate = format_tts_rate_arg(tts.rate_percent);
-    let mut output_path = std::env::temp_dir();
-    output_path.push(format!("snapparse-tts-{}.mp3", now_id()));
-
-    let mut attempt_errors = Vec::new();
+fn stream_finish_reason(value: &serde_json::Value) -> Option<&str> {
+    value
+        .get("choices")
+        .and_then(|choices| choices.get(0))
+        .and_then(|choice| choice.get("finish_reason"))
+        .and_then(|reason| reason.as_str())
+}
 
-    let mut succeeded = match tts.runtime_mode {
-        TtsRuntimeMode::EdgeCliOnly => execute_tts_attempt(
-            &mut attempt_errors,
-            "edge-tts",
-            &[],
-            &voice,
-            &rate,
-            text,
-            &output_path,
-        ),
-        TtsRuntimeMode::PythonModuleOnly => {
-            execute_tts_attempt(
-                &mut attempt_errors,
-                "python",
-                &["-m", "edge_tts"],
-                &voice,
-                &rate,
-                text,
-                &output_path,
-            ) || execute_tts_attempt(
-                &mut attempt_errors,
-                "py",
-                &["-m", "edge_tts"],
-                &voice,
-                &rate,
-                text,
-                &output_path,
-            )
-        }
-        TtsRuntimeMode::DualFallback => {
-            execute_tts_attempt(
-                &mut attempt_errors,
-                "edge-tts",
-                &[],
-                &voice,
-                &rate,
-                text,
-                &output_path,
-            ) || execute_tts_attempt(
-                &mut attempt_errors,
-                "python",
-                &["-m", "edge_tts"],
-                &voice,
-                &rate,
-                text,
-                &output_path,
-            ) || execute_tts_attempt(
-                &mut attempt_errors,
-                "py",
-                &["-m", "edge_tts"],
-                &voice,
-                &rate,
-                text,
-                &output_path,
-            )
-        }
-    };
+fn is_stream_payload_finished(value: &serde_json::Value) -> bool {
+    stream_finish_reason(value)
+        .map(|reason| !reason.is_empty())
+        .unwrap_or(false)
+}
 
-    let missing_dependency = !succeeded
-        && !attempt_errors.is_empty()
-        && attempt_errors
-            .iter()
-            .all(|error| is_missing_edge_tts_error(error));
-    if missing_dependency {
-        match try_auto_install_edge_tts_with_lock() {
-            Ok(_) => {
-                attempt_errors.clear();
-                succeeded = match tts.runtime_mode {
-                    TtsRuntimeMode::EdgeCliOnly => execute_tts_attempt(
-                        &mut attempt_errors,
-                        "edge-tts",
-                        &[],
-                        &voice,
-                        &rate,
-                        text,
-                        &output_path,
-                    ),
-                    TtsRuntimeMode::PythonModuleOnly => {
-                        execute_tts_attempt(
-                            &mut attempt_errors,
-                            "python",
-                            &["-m", "edge_tts"],
-                            &voice,
-                            &rate,
-                            text,
-                            &output_path,
-                        ) || execute_tts_attempt(
-                            &mut attempt_errors,
-                            "py",
-                            &["-m", "edge_tts"],
-                            &voice,
-                            &rate,
-                            text,
-                            &output_path,
-                        )
-                    }
-                    TtsRuntimeMode::DualFallback => {
-                        execute_tts_attempt(
-                            &mut attempt_errors,
-                            "edge-tts",
-                            &[],
-                            &voice,
-                            &rate,
-                            text,
-                            &output_path,
-                        ) || execute_tts_attempt(
-                            &mut attempt_errors,
-                            "python",
-                            &["-m", "edge_tts"],
-                            &voice,
-                            &rate,
-                            text,
-                            &output_path,
-                        ) || execute_tts_attempt(
-                            &mut attempt_errors,
-                            "py",
-                            &["-m", "edge_tts"],
-                            &voice,
-                            &rate,
-                            text,
-                            &output_path,
-                        )
-                    }
-                };
-            }
-            Err(error) => {
-                if error == EDGE_TTS_INSTALL_IN_PROGRESS {
-                    attempt_errors.push("Edge TTS 正在后台初始化，请稍后重试".to_string());
-                } else {
-                    attempt_errors.push(format!("auto-install failed: {error}"));
-                }
-            }
-        }
+/// Returns true once the stream is complete. When usage reporting was requested the usage chunk
+/// arrives after `finish_reason`, so a finished choice only ends the stream once usage was seen;
+/// otherwise `[DONE]` or the end of the body does.
+fn process_stream_data_payload(
+    payload: &str,
+    stream: &mut StreamAccumulator,
+    on_delta: &mut impl FnMut(StreamDelta<'_>) -> bool,
+) -> Result<bool, CommandError> {
+    if payload.is_empty() {
+        return Ok(false);
+    }
+    if payload == "[DONE]" {
+        return Ok(true);
     }
 
-    if !succeeded {
-        let _ = fs::remove_file(&output_path);
-        let detail = if attempt_errors.is_empty() {
-            "未知错误".to_string()
-        } else {
-            attempt_errors.join(" | ")
-        };
-        return Err(CommandError::Settings(format!(
-            "TTS 调用失败，请确认已安装 edge-tts（python -m pip install edge-tts）: {detail}"
-        )));
+    let Ok(value) = serde_json::from_str::<serde_json::Value>(payload) else {
+        return Ok(false);
+    };
+
+    if let Some(error) = value.get("error") {
+        return Err(classify_stream_error_payload(error).into());
+    }
+    if let Some(reported) = parse_token_usage(&value) {
+        stream.usage = Some(reported);
     }
 
-    let bytes_result = fs::read(&output_path);
-    let _ = fs::remove_file(&output_path);
-    let bytes = bytes_result.map_err(|error| {
-        CommandError::Settings(format!(
-            "读取 TTS 音频失败（{}）: {}",
-            output_path.display(),
-            error
-        ))
-    })?;
+    let reasoning = extract_stream_reasoning_delta(&value);
+    stream.emit("", &reasoning, on_delta)?;
+    let delta = extract_stream_delta_text(&value);
+    if !delta.is_empty() {
+        stream.push_content(&delta, on_delta)?;
+    }
+    accumulate_tool_call_deltas(&value, &mut stream.tool_calls);
 
-    if bytes.is_empty() {
-        return Err(CommandError::Settings("TTS 返回空音频".to_string()));
+    if stream.content.trim().is_empty() && stream_finish_reason(&value) == Some("content_filter") {
+        return Err(ModelError::ContentFiltered.into());
     }
 
-    Ok(SynthesizeTtsResult {
-        audio_base64: BASE64.encode(bytes),
-        mime_type: "audio/mpeg".to_string(),
-        voice_used: voice,
-    })
+    Ok(is_stream_payload_finished(&value) && stream.usage.is_some())
 }
 
-fn build_search_url(template: &str, text: &str) -> String {
-    let encoded = urlencoding::encode(text);
-    if template.contains("{query}") {
-        template.replace("{query}", &encoded)
-    } else if template.contains("{}") {
-        template.replacen("{}", &encoded, 1)
-    } else {
-        format!("{template}{encoded}")
+fn parse_sse_text_content(
+    body: &str,
+    on_delta: &mut impl FnMut(StreamDelta<'_>) -> bool,
+) -> Result<(StreamAccumulator, bool), CommandError> {
+    let mut content = StreamAccumulator::default();
+    let mut event_data = String::new();
+    let mut saw_data = false;
+
+    let mut flush_event = |event_data: &mut String,
+                           content: &mut StreamAccumulator,
+                           saw_data: &mut bool|
+     -> Result<bool, CommandError> {
+        if event_data.trim().is_empty() {
+            event_data.clear();
+            return Ok(false);
+        }
+        *saw_data = true;
+        let payload = event_data.trim().to_string();
+        event_data.clear();
+        process_stream_data_payload(&payload, content, on_delta)
+    };
+
+    for raw_line in body.lines() {
+        let line = raw_line.trim_end_matches('\r');
+        if line.trim().is_empty() {
+            if flush_event(&mut event_data, &mut content, &mut saw_data)? {
+                content.finish(on_delta)?;
+                return Ok((content, true));
+            }
+            continue;
+        }
+        if let Some(payload) = line.strip_prefix("data:") {
+            if !event_data.is_empty() {
+                event_data.push('\n');
+            }
+            event_data.push_str(payload.trim_start());
+        }
     }
+
+    let done = flush_event(&mut event_data, &mut content, &mut saw_data)?;
+    content.finish(on_delta)?;
+    Ok((content, saw_data || done))
 }
 
-fn choose_builtin_prompt(
-    action: SelectionActionKind,
-    text: &str,
-    translate_from: Option<&str>,
-    translate_to: Option<&str>,
-    ui_language: &str,
-) -> (String, String) {
-    fn is_english_ui(language: &str) -> bool {
-        language.eq_ignore_ascii_case("en-US")
+async fn read_response_body_lossy(response: reqwest::Response) -> Result<String, reqwest::Error> {
+    let mut response = response;
+    let mut bytes = Vec::<u8>::new();
+    loop {
+        match response.chunk().await {
+            Ok(Some(chunk)) => bytes.extend_from_slice(&chunk),
+            Ok(None) => break,
+            Err(error) => {
+                if bytes.is_empty() {
+                    return Err(error);
+                }
+                eprintln!(
+                    "[HTTP] body read interrupted after {} bytes, using partial content: {}",
+                    bytes.len(),
+                    error
+                );
+                break;
+            }
+        }
     }
+    Ok(String::from_utf8_lossy(&bytes).to_string())
+}
 
-    fn output_language_label(language: &str) -> &'static str {
-        if is_english_ui(language) {
-            "English"
-        } else {
-            "Chinese (Simplified)"
-        }
+async fn call_llm_for_action(
+    client: &reqwest::Client,
+    rate_limiter: &ModelRateLimitState,
+    llm: &LlmSettings,
+    params: &GenerationParams,
+    messages: &[ChatMessage],
+    stream_enabled: bool,
+    mut on_delta: impl FnMut(StreamDelta<'_>) -> bool,
+) -> Result<ModelCompletion, CommandError> {
+    if !llm.enabled || llm.api_key.trim().is_empty() {
+        return Err(CommandError::Settings(
+            "请先在设置中配置并启用大模型 API".to_string(),
+        ));
     }
 
-    fn language_label(code: &str) -> Cow<'static, str> {
-        match code {
-            "auto" => Cow::Borrowed("Auto Detect"),
-            "zh-CN" => Cow::Borrowed("Chinese (Simplified)"),
-            "en-US" => Cow::Borrowed("English"),
-            "ja-JP" => Cow::Borrowed("Japanese"),
-            "ko-KR" => Cow::Borrowed("Korean"),
-            _ => Cow::Owned(code.to_string()),
-        }
+    let mut request_body = json!({
+        "model": llm.model,
+        "temperature": llm.temperature,
+        "max_tokens": llm.max_tokens,
+        "stream": stream_enabled,
+        "messages": messages
+    });
+    apply_generation_params(&mut request_body, params)?;
+    if stream_enabled {
+        request_body["stream_options"] = json!({ "include_usage": true });
     }
+    let mut last_error: Option<ModelError> = None;
+    let rate_limit_key = model_rate_limit_key(&llm.base_url, &llm.model);
+    let prompt_tokens = estimate_messages_tokens(messages);
+    let estimated_tokens = prompt_tokens.saturating_add(llm.max_tokens);
 
-    let output_language = output_language_label(ui_language);
-    match action {
-        SelectionActionKind::Summarize => {
-            let system_template = "You are a senior summarization assistant.
-Output language: {output_language}.
-Requirements:
-- Return only the final summary text.
-- Preserve key facts, entities, numbers, dates, and technical terms accurately.
-- Remove redundancy and keep the summary concise and clear.
-- Do not add headings, commentary, or extra explanations unless explicitly requested.";
-            let user_prompt = format!(
-                "Task: Summarize the content faithfully and concisely.
-Text:
-{text}"
-            );
-            (
-                system_template.replace("{output_language}", output_language),
-                user_prompt,
-            )
-        }
-        SelectionActionKind::Polish => {
-            let system_template = "You are a professional writing editor.
-Output language: {output_language}.
-Requirements:
-- Return only the rewritten text.
-- Preserve the original meaning, factual details, and intent.
-- Improve clarity, grammar, fluency, and structure.
-- Keep names, numbers, code snippets, URLs, and quoted text accurate.
-- Do not add commentary, labels, or extra sections.";
-            let user_prompt = format!(
-                "Task: Rewrite and improve the text while preserving meaning.
-Text:
-{text}"
-            );
-            (
-                system_template.replace("{output_language}", output_language),
-                user_prompt,
-            )
-        }
-        SelectionActionKind::Explain => {
-            let system_template = "You are a practical programming explainer and problem-solving assistant.
-Output language: {output_language}.
-Requirements:
-- Return only content directly related to the input text.
-- Prefer simple and clear language; avoid unnecessary jargon.
-- Prioritize code and programming issues when present.
-- Use exactly three sections in this order:
-  1) Problem Understanding
-  2) Why It Happens
-  3) Solution Steps
-- Section headings must also be in the output language.
-- In Solution Steps, provide concrete, low-risk, executable steps.
-- If missing key context, state the minimum missing info and continue with best-effort guidance.
-- Do not add greetings, disclaimers, or unrelated commentary.";
-            let user_prompt = format!(
-                "Task: Explain the issue clearly and provide an easy-to-follow solution.
-Text:
-{text}"
-            );
-            (
-                system_template.replace("{output_language}", output_language),
-                user_prompt,
-            )
-        }
-        SelectionActionKind::Translate => {
-            let from = translate_from.unwrap_or("auto");
-            let to = translate_to.unwrap_or(if is_english_ui(ui_language) {
-                "en-US"
-            } else {
-                "zh-CN"
-            });
-            let from_display = language_label(from);
-            let to_display = language_label(to);
-            let system_template = "You are a professional translator.
-Target language: {target_language}.
-Requirements:
-- Return only the translated text.
-- Preserve meaning faithfully; do not omit, add, or invent information.
-- Keep proper nouns, numbers, code, URLs, and formatting accurate.
-- Preserve line breaks and list structure when useful for readability.
-- Do not add notes, explanations, or bilingual output unless explicitly requested.";
-            let user_prompt = format!(
-                "Source language: {from_display}
-Target language: {to_display}
-Task: Translate the text.
-Text:
-{text}"
-            );
-            (
-                system_template.replace("{target_language}", &to_display),
-                user_prompt,
-            )
+    for attempt in 0..MODEL_REQUEST_MAX_ATTEMPTS {
+        acquire_model_rate_slot(
+            rate_limiter,
+            &rate_limit_key,
+            llm.requests_per_minute,
+            llm.tokens_per_minute,
+            estimated_tokens,
+        )
+        .await?;
+        let mut response = match client
+            .post(llm.base_url.trim())
+            .timeout(Duration::from_millis(llm.timeout_ms))
+            .header(AUTHORIZATION, format!("Bearer {}", llm.api_key.trim()))
+            .header(CONTENT_TYPE, "application/json")
+            .headers(extra_header_map(&llm.extra_headers))
+            .json(&request_body)
+            .send()
+            .await
+        {
+            Ok(resp) => resp,
+            Err(error) => {
+                if should_retry_network_error(&error) && attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS {
+                    last_error = Some(ModelError::from(error));
+                    sleep_with_backoff(attempt).await;
+                    continue;
+                }
+                return Err(ModelError::from(error).into());
+            }
+        };
+
+        let status = response.status();
+        note_model_rate_limit_headers(rate_limiter, &rate_limit_key, response.headers());
+        if !status.is_success() {
+            let headers = response.headers().clone();
+            let raw_body = read_response_body_lossy(response)
+                .await
+                .unwrap_or_else(|error| format!("(failed to read response body: {error})"));
+            // Older OpenAI-compatible gateways reject `stream_options`; retry without it.
+            if status.as_u16() == 400
+                && request_body.get("stream_options").is_some()
+                && raw_body.to_ascii_lowercase().contains("stream_options")
+                && attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS
+            {
+                if let Some(body) = request_body.as_object_mut() {
+                    body.remove("stream_options");
+                }
+                continue;
+            }
+            let failure = classify_http_failure(status, &headers, &raw_body);
+            // Gateways without structured output reject the request as invalid; they get JSON
+            // mode instead and the reply is validated locally.
+            if matches!(
+                failure,
+                ModelError::ProviderError {
+                    status: 400 | 422,
+                    ..
+                }
+            ) && request_body
+                .pointer("/response_format/type")
+                .and_then(|value| value.as_str())
+                == Some("json_schema")
+                && attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS
+            {
+                request_body["response_format"] = json!({ "type": "json_object" });
+                continue;
+            }
+            if should_retry_http_status(status)
+                && attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS
+                && sleep_before_retry(attempt, rate_limit_retry_delay_ms(&headers)).await
+            {
+                last_error = Some(failure);
+                continue;
+            }
+            return Err(failure.into());
         }
-        SelectionActionKind::Custom => (
-            "You are a text processing assistant. Follow the user's instruction and return only the final output.".to_string(),
-            format!("Text:\n{text}"),
-        ),
-    }
-}
 
-fn custom_agent_system_prompt() -> &'static str {
-    "You are a configurable text-processing assistant.
-Follow the user's custom instruction exactly.
-Requirements:
-- Return only the final processed text.
-- Do not add preface, labels, markdown fences, or explanations unless explicitly requested.
-- Preserve factual details, names, numbers, code snippets, and URLs accurately.
-- Respect the language, tone, and format requested by the user."
-}
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        let mut stream = StreamAccumulator::default();
+
+        if content_type.contains("text/event-stream") {
+            let mut line_buf = String::new();
+            let mut event_data = String::new();
+            let mut stream_raw = String::new();
+            let mut stream_done = false;
+            let mut should_retry_stream = false;
+
+            let mut flush_event = |event_data: &mut String,
+                                   stream: &mut StreamAccumulator|
+             -> Result<bool, CommandError> {
+                if event_data.trim().is_empty() {
+                    event_data.clear();
+                    return Ok(false);
+                }
+                let payload = event_data.trim().to_string();
+                event_data.clear();
+                process_stream_data_payload(&payload, stream, &mut on_delta)
+            };
+
+            loop {
+                let chunk = match response.chunk().await {
+                    Ok(value) => value,
+                    Err(error) => {
+                        let nothing_streamed =
+                            stream.content.is_empty() && stream.reasoning.is_empty();
+                        if nothing_streamed
+                            && should_retry_network_error(&error)
+                            && attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS
+                        {
+                            should_retry_stream = true;
+                            last_error = Some(ModelError::from(error));
+                            break;
+                        }
+                        if !nothing_streamed && should_retry_network_error(&error) {
+                            // Some providers may close or truncate chunked responses after
+                            // streaming useful content. Keep partial output instead of failing.
+                            eprintln!(
+                                "[LLM] stream body read interrupted, using partial output: {}",
+                                error
+                            );
+                            break;
+                        }
+                        return Err(ModelError::from(error).into());
+                    }
+                };
+
+                let Some(chunk) = chunk else {
+                    break;
+                };
+
+                let chunk_text = String::from_utf8_lossy(&chunk);
+                stream_raw.push_str(&chunk_text);
+                line_buf.push_str(&chunk_text);
+
+                while let Some(line_end) = line_buf.find('\n') {
+                    let raw_line = line_buf[..line_end].to_string();
+                    line_buf.drain(..=line_end);
+                    let line = raw_line.trim_end_matches('\r');
 
-fn extract_llm_text_content(value: &serde_json::Value) -> String {
-    if let Some(text) = value
-        .get("choices")
-        .and_then(|choices| choices.get(0))
-        .and_then(|item| item.get("text"))
-        .and_then(|text| text.as_str())
-    {
-        return text.trim().to_string();
-    }
+                    if line.trim().is_empty() {
+                        if flush_event(&mut event_data, &mut stream)? {
+                            stream_done = true;
+                            break;
+                        }
+                        continue;
+                    }
 
-    if let Some(content) = value
-        .get("choices")
-        .and_then(|choices| choices.get(0))
-        .and_then(|item| item.get("message"))
-        .and_then(|message| message.get("content"))
-    {
-        if let Some(text) = content.as_str() {
-            return text.trim().to_string();
-        }
+                    if let Some(payload) = line.strip_prefix("data:") {
+                        if !event_data.is_empty() {
+                            event_data.push('\n');
+                        }
+                        event_data.push_str(payload.trim_start());
+                    }
+                }
 
-        if let Some(items) = content.as_array() {
-            let mut merged = String::new();
-            for item in items {
-                if let Some(text) = item.get("text").and_then(|value| value.as_str()) {
-                    merged.push_str(text);
+                if stream_done {
+                    break;
                 }
             }
-            return merged.trim().to_string();
-        }
-    }
 
-    if let Some(output_text) = value.get("output_text").and_then(|item| item.as_str()) {
-        return output_text.trim().to_string();
-    }
+            if should_retry_stream {
+                sleep_with_backoff(attempt).await;
+                continue;
+            }
 
-    if let Some(output_items) = value.get("output").and_then(|item| item.as_array()) {
-        let mut merged = String::new();
-        for output_item in output_items {
-            if let Some(content_items) = output_item.get("content").and_then(|item| item.as_array())
+            if !stream_done {
+                let tail = line_buf.trim_end_matches('\r').trim();
+                if let Some(payload) = tail.strip_prefix("data:") {
+                    if !event_data.is_empty() {
+                        event_data.push('\n');
+                    }
+                    event_data.push_str(payload.trim_start());
+                }
+                if !event_data.trim().is_empty() {
+                    let _ = flush_event(&mut event_data, &mut stream)?;
+                }
+            }
+            stream.finish(&mut on_delta)?;
+
+            if stream.content.trim().is_empty()
+                && stream.tool_calls.is_empty()
+                && !stream_raw.trim().is_empty()
             {
-                for content_item in content_items {
-                    if let Some(text) = content_item.get("text").and_then(|item| item.as_str()) {
-                        merged.push_str(text);
+                let (fallback_stream, saw_sse) =
+                    parse_sse_text_content(&stream_raw, &mut on_delta)?;
+                if saw_sse {
+                    stream = fallback_stream;
+                } else if let Ok(value) = serde_json::from_str::<serde_json::Value>(&stream_raw) {
+                    stream = completion_from_json_value(&value);
+                }
+            }
+        } else {
+            let body_text = match read_response_body_lossy(response).await {
+                Ok(value) => value,
+                Err(error) => {
+                    if should_retry_network_error(&error)
+                        && attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS
+                    {
+                        last_error = Some(ModelError::from(error));
+                        sleep_with_backoff(attempt).await;
+                        continue;
                     }
+                    return Err(ModelError::from(error).into());
+                }
+            };
+            if let Ok(value) = serde_json::from_str::<serde_json::Value>(&body_text) {
+                stream = completion_from_json_value(&value);
+                if stream.content.trim().is_empty()
+                    && stream_finish_reason(&value) == Some("content_filter")
+                {
+                    return Err(ModelError::ContentFiltered.into());
+                }
+            }
+            if stream.content.trim().is_empty() && stream.tool_calls.is_empty() {
+                let (fallback_stream, saw_sse) = parse_sse_text_content(&body_text, &mut on_delta)?;
+                if saw_sse {
+                    stream = fallback_stream;
+                } else {
+                    let (answer, reasoning) = split_think_tags(&body_text);
+                    stream.content = answer;
+                    stream.reasoning = reasoning;
                 }
             }
         }
-        if !merged.trim().is_empty() {
-            return merged.trim().to_string();
-        }
-    }
-
-    String::new()
-}
 
-fn build_auth_header_value(api_key: &str, prefer_bearer: bool) -> String {
-    let trimmed = api_key.trim();
-    if trimmed.to_ascii_lowercase().starts_with("bearer ") {
-        trimmed.to_string()
-    } else if prefer_bearer {
-        format!("Bearer {trimmed}")
-    } else {
-        trimmed.to_string()
-    }
-}
+        let normalized = stream.content.trim().to_string();
+        if !normalized.is_empty() || !stream.tool_calls.is_empty() {
+            let usage = stream
+                .usage
+                .unwrap_or_else(|| estimated_token_usage(prompt_tokens, &normalized));
+            return Ok(ModelCompletion {
+                text: normalized,
+                reasoning: stream.reasoning.trim().to_string(),
+                usage: Some(usage),
+                tool_calls: stream.tool_calls,
+            });
+        }
 
-fn auth_header_candidates(api_key: &str, prefer_bearer: bool) -> Vec<String> {
-    let mut candidates = Vec::<String>::new();
-    let preferred = build_auth_header_value(api_key, prefer_bearer);
-    push_unique_string(&mut candidates, preferred);
-    if !api_key.trim().to_ascii_lowercase().starts_with("bearer ") {
-        push_unique_string(
-            &mut candidates,
-            build_auth_header_value(api_key, !prefer_bearer),
-        );
+        if attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS {
+            last_error = Some(ModelError::EmptyResponse);
+            sleep_with_backoff(attempt).await;
+            continue;
+        }
+        return Err(ModelError::EmptyResponse.into());
     }
-    candidates
-}
 
-fn should_use_glm_layout_parsing(vision: &VisionSettings) -> bool {
-    let base_url = vision.base_url.trim().to_ascii_lowercase();
-    let model = vision.model.trim().to_ascii_lowercase();
-    base_url.contains("/layout_parsing")
-        || base_url.contains("open.bigmodel.cn")
-        || model.starts_with("glm-ocr")
+    Err(last_error.unwrap_or(ModelError::EmptyResponse).into())
 }
 
-fn resolve_glm_layout_parsing_endpoint(vision: &VisionSettings) -> String {
-    let base_url = vision.base_url.trim();
-    let model = vision.model.trim().to_ascii_lowercase();
-    if base_url.is_empty()
-        || (model.starts_with("glm-ocr")
-            && base_url.to_ascii_lowercase().contains("/chat/completions"))
-    {
-        "https://open.bigmodel.cn/api/paas/v4/layout_parsing".to_string()
-    } else {
-        base_url.to_string()
-    }
+fn response_cache_file_path<R: Runtime>(app: &AppHandle<R>) -> Result<PathBuf, CommandError> {
+    let mut dir = app
+        .path()
+        .app_cache_dir()
+        .map_err(|error| CommandError::Settings(error.to_string()))?;
+    fs::create_dir_all(&dir).map_err(|error| CommandError::Settings(error.to_string()))?;
+    dir.push(RESPONSE_CACHE_FILENAME);
+    Ok(dir)
 }
 
-fn push_unique_string(bucket: &mut Vec<String>, value: String) {
-    if value.trim().is_empty() {
-        return;
-    }
-    if bucket.iter().any(|item| item == &value) {
-        return;
-    }
-    bucket.push(value);
+fn response_cache_key(
+    llm: &LlmSettings,
+    params: &GenerationParams,
+    messages: &[ChatMessage],
+) -> String {
+    // SHA-256 keeps keys identical across builds, unlike `DefaultHasher`. Every field is
+    // length-prefixed so adjacent values cannot run into each other.
+    let mut hasher = Sha256::new();
+    let mut feed = |value: &[u8]| {
+        hasher.update((value.len() as u64).to_le_bytes());
+        hasher.update(value);
+    };
+    feed(llm.base_url.trim().trim_end_matches('/').as_bytes());
+    feed(llm.model.trim().as_bytes());
+    for message in messages {
+        feed(message.role.as_bytes());
+        feed(message.content.as_bytes());
+    }
+    feed(&llm.temperature.to_bits().to_le_bytes());
+    feed(serde_json::to_string(params).unwrap_or_default().as_bytes());
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
 }
 
-fn vision_file_payload_candidates(image_data_url: &str) -> Vec<String> {
-    let mut candidates = Vec::<String>::new();
-    let value = image_data_url.trim();
-
-    if value.starts_with("http://") || value.starts_with("https://") {
-        push_unique_string(&mut candidates, value.to_string());
-        return candidates;
-    }
-
-    // Keep original payload first (e.g. data:image/png;base64,...) so server can infer type.
-    push_unique_string(&mut candidates, value.to_string());
-
-    if let Some((prefix, payload)) = value.split_once(',') {
-        if prefix.to_ascii_lowercase().contains("base64") {
-            push_unique_string(&mut candidates, payload.trim().to_string());
+fn load_response_cache_store<R: Runtime>(
+    app: &AppHandle<R>,
+    settings: &ResponseCacheSettings,
+) -> ResponseCacheStore {
+    let Ok(path) = response_cache_file_path(app) else {
+        return ResponseCacheStore::default();
+    };
+    if !path.exists() {
+        return ResponseCacheStore::default();
+    }
+    // JSON field names and escaping add to the in-memory size estimate, so allow twice the limit.
+    let max_file_bytes = u64::from(settings.max_size_mb) * 2 * 1024 * 1024;
+    match read_text_with_retry_with_limit(&path, Some(max_file_bytes))
+        .map_err(|error| error.to_string())
+        .and_then(|text| {
+            serde_json::from_str::<ResponseCacheStore>(&text).map_err(|error| error.to_string())
+        }) {
+        Ok(store) => store,
+        Err(error) => {
+            eprintln!("[ResponseCache] load failed, starting empty: {error}");
+            ResponseCacheStore::default()
         }
     }
-
-    candidates
-}
-
-fn push_candidate_text(raw: &str, bucket: &mut Vec<String>) {
-    let trimmed = raw.trim();
-    if trimmed.is_empty() {
-        return;
-    }
-    if bucket.iter().any(|item| item == trimmed) {
-        return;
-    }
-    bucket.push(trimmed.to_string());
 }
 
-fn collect_ocr_text_candidates(value: &serde_json::Value, bucket: &mut Vec<String>) {
-    if let Some(text) = value.as_str() {
-        push_candidate_text(text, bucket);
-        return;
-    }
-
-    if let Some(items) = value.as_array() {
-        for item in items {
-            collect_ocr_text_candidates(item, bucket);
-        }
-        return;
-    }
-
-    let Some(map) = value.as_object() else {
-        return;
-    };
-
-    for key in [
-        "text",
-        "content",
-        "markdown",
-        "md_results",
-        "md_result",
-        "ocr_text",
-        "full_text",
-        "output_text",
-    ] {
-        if let Some(item) = map.get(key) {
-            collect_ocr_text_candidates(item, bucket);
-        }
-    }
-    for key in [
-        "result",
-        "results",
-        "data",
-        "pages",
-        "blocks",
-        "items",
-        "layout_details",
-        "layout_visualization",
-        "paragraphs",
-        "lines",
-    ] {
-        if let Some(item) = map.get(key) {
-            collect_ocr_text_candidates(item, bucket);
-        }
-    }
+fn persist_response_cache_store<R: Runtime>(
+    app: &AppHandle<R>,
+    cache_state: &ResponseCacheState,
+) -> Result<(), CommandError> {
+    let _file = cache_state
+        .file_lock
+        .lock()
+        .map_err(|_| CommandError::Lock)?;
+    cache_state.persist_pending.store(false, Ordering::Release);
+    let payload = {
+        let guard = cache_state.store.lock().map_err(|_| CommandError::Lock)?;
+        let Some(store) = guard.as_ref() else {
+            return Ok(());
+        };
+        serde_json::to_string(store)
+            .map_err(|error| CommandError::Serialization(error.to_string()))?
+    };
+    let path = response_cache_file_path(app)?;
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, payload).map_err(|error| CommandError::Settings(error.to_string()))?;
+    // Renaming over the old file is atomic, so a crash never leaves the cache missing.
+    fs::rename(&temp_path, &path).map_err(|error| CommandError::Settings(error.to_string()))
 }
 
-fn strip_markdown_heading_prefix(line: &str) -> Option<&str> {
-    let trimmed = line.trim_start();
-    let bytes = trimmed.as_bytes();
-    let mut idx = 0usize;
-    while idx < bytes.len() && idx < 6 && bytes[idx] == b'#' {
-        idx += 1;
-    }
-    if idx == 0 || idx >= bytes.len() || !bytes[idx].is_ascii_whitespace() {
-        return None;
+/// Saves the cache file on a blocking thread after `RESPONSE_CACHE_PERSIST_DELAY_MS`, so model
+/// calls never wait on the write; changes made in the meantime go out with the same save.
+fn schedule_response_cache_persist<R: Runtime>(
+    app: &AppHandle<R>,
+    cache_state: &ResponseCacheState,
+) {
+    if cache_state.persist_pending.swap(true, Ordering::AcqRel) {
+        return;
     }
-    Some(trimmed[idx..].trim_start())
+    let app = app.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        std::thread::sleep(Duration::from_millis(RESPONSE_CACHE_PERSIST_DELAY_MS));
+        if let Err(error) = persist_response_cache_store(&app, &app.state::<ResponseCacheState>()) {
+            eprintln!("[ResponseCache] persist failed: {error}");
+        }
+    });
 }
 
-fn normalize_ocr_text_output(raw: &str) -> String {
-    let lines = raw
-        .lines()
-        .map(|line| {
-            if let Some(rest) = strip_markdown_heading_prefix(line) {
-                rest.to_string()
-            } else {
-                line.to_string()
+/// Drops expired entries, then evicts least recently used ones until the store fits the size
+/// limit. Returns whether anything was removed.
+fn prune_response_cache_store(
+    store: &mut ResponseCacheStore,
+    settings: &ResponseCacheSettings,
+    now_ms: u64,
+) -> bool {
+    let ttl_ms = u64::from(settings.ttl_hours) * 60 * 60 * 1000;
+    let before = store.entries.len();
+    store
+        .entries
+        .retain(|_, entry| now_ms.saturating_sub(entry.created_at_ms) <= ttl_ms);
+
+    let max_bytes = settings.max_size_mb as usize * 1024 * 1024;
+    let mut total_bytes: usize = store
+        .entries
+        .iter()
+        .map(|(key, entry)| entry.approximate_size_bytes(key))
+        .sum();
+    if total_bytes > max_bytes {
+        let mut by_last_used: Vec<(u64, String)> = store
+            .entries
+            .iter()
+            .map(|(key, entry)| (entry.last_used_at_ms, key.clone()))
+            .collect();
+        by_last_used.sort_unstable();
+        for (_, key) in by_last_used {
+            if total_bytes <= max_bytes {
+                break;
+            }
+            if let Some(entry) = store.entries.remove(&key) {
+                total_bytes = total_bytes.saturating_sub(entry.approximate_size_bytes(&key));
             }
-        })
-        .collect::<Vec<_>>();
-    lines.join("\n").trim().to_string()
-}
-
-fn extract_vision_ocr_text(value: &serde_json::Value) -> String {
-    if let Some(markdown) = value.get("md_results").and_then(|item| item.as_str()) {
-        let normalized = normalize_ocr_text_output(markdown);
-        if !normalized.is_empty() {
-            return normalized;
         }
     }
 
-    let mut candidates = Vec::<String>::new();
-    collect_ocr_text_candidates(value, &mut candidates);
-
-    let longest = candidates
-        .into_iter()
-        .max_by_key(|item| item.chars().count())
-        .unwrap_or_default();
+    store.entries.len() != before
+}
 
-    if !longest.is_empty() {
-        return normalize_ocr_text_output(&longest);
+fn lookup_cached_llm_response<R: Runtime>(
+    app: &AppHandle<R>,
+    cache_state: &ResponseCacheState,
+    settings: &ResponseCacheSettings,
+    key: &str,
+) -> Option<String> {
+    let mut guard = cache_state.store.lock().ok()?;
+    let store = guard.get_or_insert_with(|| load_response_cache_store(app, settings));
+    let now = now_epoch_millis();
+    let pruned = prune_response_cache_store(store, settings, now);
+    let hit = store.entries.get_mut(key).map(|entry| {
+        entry.last_used_at_ms = now;
+        entry.output_text.clone()
+    });
+    if hit.is_some() {
+        store.unsaved_hits = store.unsaved_hits.saturating_add(1);
     }
-
-    normalize_ocr_text_output(&extract_llm_text_content(value))
+    if pruned || store.unsaved_hits >= RESPONSE_CACHE_HIT_PERSIST_BATCH {
+        store.unsaved_hits = 0;
+        schedule_response_cache_persist(app, cache_state);
+    }
+    hit
 }
 
-async fn call_glm_layout_parsing_ocr(
-    client: &reqwest::Client,
-    vision: &VisionSettings,
-    image_data_url: &str,
-) -> Result<String, CommandError> {
-    let endpoint = resolve_glm_layout_parsing_endpoint(vision);
-    let auth_headers = auth_header_candidates(&vision.api_key, false);
-    let file_candidates = vision_file_payload_candidates(image_data_url);
-    if file_candidates.is_empty() {
-        return Err(CommandError::Settings(
-            "OCR image payload is empty".to_string(),
-        ));
-    }
+fn store_cached_llm_response<R: Runtime>(
+    app: &AppHandle<R>,
+    cache_state: &ResponseCacheState,
+    settings: &ResponseCacheSettings,
+    llm: &LlmSettings,
+    key: String,
+    output_text: &str,
+) {
+    let Ok(mut guard) = cache_state.store.lock() else {
+        return;
+    };
+    let store = guard.get_or_insert_with(|| load_response_cache_store(app, settings));
+    let now = now_epoch_millis();
+    store.entries.insert(
+        key,
+        ResponseCacheEntry {
+            base_url: llm.base_url.trim().to_string(),
+            model: llm.model.trim().to_string(),
+            output_text: output_text.to_string(),
+            created_at_ms: now,
+            last_used_at_ms: now,
+        },
+    );
+    prune_response_cache_store(store, settings, now);
+    store.unsaved_hits = 0;
+    schedule_response_cache_persist(app, cache_state);
+}
 
-    let model_name = vision.model.trim();
-    let mut last_error: Option<String> = None;
-    for file_payload in file_candidates {
-        for (auth_index, auth_header) in auth_headers.iter().enumerate() {
-            let request_body = json!({
-                "model": model_name,
-                "file": file_payload
-            });
+struct LlmActionOutcome {
+    text: String,
+    reasoning: String,
+    from_cache: bool,
+    usage: Option<RequestUsagePayload>,
+    provider: Option<ProviderPayload>,
+    tool_calls: Vec<ToolCallRequest>,
+}
 
-            let mut final_status: Option<reqwest::StatusCode> = None;
-            let mut final_value: serde_json::Value = json!({});
-            let mut final_raw_body = String::new();
+struct LlmProviderCandidate {
+    provider: ProviderPayload,
+    llm: LlmSettings,
+}
 
-            for attempt in 0..MODEL_REQUEST_MAX_ATTEMPTS {
-                let response = match client
-                    .post(&endpoint)
-                    .timeout(Duration::from_millis(vision.timeout_ms))
-                    .header(AUTHORIZATION, auth_header.as_str())
-                    .header(CONTENT_TYPE, "application/json")
-                    .json(&request_body)
-                    .send()
-                    .await
-                {
-                    Ok(resp) => resp,
-                    Err(error) => {
-                        if should_retry_network_error(&error)
-                            && attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS
-                        {
-                            last_error = Some(error.to_string());
-                            sleep_with_backoff(attempt).await;
-                            continue;
-                        }
-                        return Err(CommandError::Settings(error.to_string()));
-                    }
-                };
+fn provider_display_name(base_url: &str) -> String {
+    reqwest::Url::parse(base_url.trim())
+        .ok()
+        .and_then(|url| url.host_str().map(|host| host.to_string()))
+        .unwrap_or_else(|| base_url.trim().to_string())
+}
 
-                let status = response.status();
-                let raw_body = match read_response_body_lossy(response).await {
-                    Ok(value) => value,
-                    Err(error) => {
-                        if should_retry_network_error(&error)
-                            && attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS
-                        {
-                            last_error = Some(error.to_string());
-                            sleep_with_backoff(attempt).await;
-                            continue;
-                        }
-                        return Err(CommandError::Settings(error.to_string()));
-                    }
-                };
-                let parsed_value = serde_json::from_str::<serde_json::Value>(&raw_body)
-                    .unwrap_or_else(|_| {
-                        if raw_body.trim().is_empty() {
-                            json!({ "error": "Unknown GLM OCR response" })
-                        } else {
-                            json!({ "raw": format_response_body_for_error(&raw_body) })
-                        }
-                    });
+/// Builds the failover chain: the primary settings first, then every usable fallback profile.
+/// Providers still cooling down from a recent failure move to the back as a last resort.
+fn llm_provider_chain(
+    llm: &LlmSettings,
+    health: &ProviderHealthState,
+    now_ms: u64,
+) -> Vec<LlmProviderCandidate> {
+    let mut chain = vec![LlmProviderCandidate {
+        provider: ProviderPayload {
+            id: "primary".to_string(),
+            name: provider_display_name(&llm.base_url),
+            model: llm.model.clone(),
+            is_fallback: false,
+        },
+        llm: llm.clone(),
+    }];
+    for profile in &llm.fallback_profiles {
+        if !profile.enabled || profile.base_url.is_empty() || profile.api_key.is_empty() {
+            continue;
+        }
+        let mut candidate = llm.clone();
+        candidate.base_url = profile.base_url.clone();
+        candidate.api_key = profile.api_key.clone();
+        if !profile.model.is_empty() {
+            candidate.model = profile.model.clone();
+        }
+        candidate.extra_headers = profile.extra_headers.clone();
+        candidate.fallback_profiles = Vec::new();
+        chain.push(LlmProviderCandidate {
+            provider: ProviderPayload {
+                id: profile.id.clone(),
+                name: if profile.name.is_empty() {
+                    provider_display_name(&profile.base_url)
+                } else {
+                    profile.name.clone()
+                },
+                model: candidate.model.clone(),
+                is_fallback: true,
+            },
+            llm: candidate,
+        });
+    }
+    chain.sort_by_key(|candidate| {
+        health.is_cooling_down(&provider_health_key(&candidate.llm), now_ms)
+    });
+    chain
+}
+
+fn provider_health_key(llm: &LlmSettings) -> String {
+    model_rate_limit_key(&llm.base_url, &llm.model)
+}
+
+/// Failures tied to one provider, so another provider may still succeed: transport errors,
+/// timeouts, rate limits, server errors, rejected credentials and a missing model or endpoint
+/// (404). Other client errors such as a 400 describe the request and would fail everywhere.
+fn should_fail_over(error: &CommandError) -> bool {
+    match error {
+        CommandError::Model(
+            ModelError::RateLimited { .. }
+            | ModelError::Timeout
+            | ModelError::Network(_)
+            | ModelError::Unauthorized { .. },
+        ) => true,
+        CommandError::Model(ModelError::ProviderError { status, .. }) => {
+            *status == 404 || *status >= 500
+        }
+        _ => false,
+    }
+}
 
-                final_status = Some(status);
-                final_value = parsed_value;
-                final_raw_body = raw_body;
+/// Runs a text action against the configured LLM. Identical requests are served from the
+/// on-disk response cache unless `regenerate` is set; a hit is replayed through `on_delta` in one
+/// piece so callers keep a single emission path. Live calls are checked against the monthly
+/// usage budget and recorded in the usage ledger under `action`. Reasoning deltas are dropped
+/// here when the reasoning display setting hides them. A provider failure moves on to the next
+/// fallback profile as long as nothing has been streamed to the caller yet.
+async fn run_llm_action<R: Runtime, F>(
+    app: &AppHandle<R>,
+    settings: &AppSettings,
+    action: &str,
+    messages: &[ChatMessage],
+    params: &GenerationParams,
+    regenerate: bool,
+    mut on_delta: F,
+) -> Result<LlmActionOutcome, CommandError>
+where
+    F: FnMut(StreamDelta<'_>) -> bool,
+{
+    let show_reasoning = settings.llm.reasoning_display != ReasoningDisplay::Hide;
+    let cache_state = app.state::<ResponseCacheState>();
+    let cache_settings = &settings.response_cache;
+    let cache_key = cache_settings
+        .enabled
+        .then(|| response_cache_key(&settings.llm, params, messages));
+
+    if let Some(key) = cache_key.as_deref().filter(|_| !regenerate) {
+        if let Some(cached) = lookup_cached_llm_response(app, &cache_state, cache_settings, key) {
+            if !on_delta(StreamDelta::Answer(&cached)) {
+                return Err(ModelError::Canceled.into());
+            }
+            return Ok(LlmActionOutcome {
+                text: cached,
+                reasoning: String::new(),
+                from_cache: true,
+                usage: None,
+                provider: None,
+                tool_calls: Vec::new(),
+            });
+        }
+    }
 
-                if should_retry_http_status(status) && attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS {
-                    sleep_with_backoff(attempt).await;
-                    continue;
+    let usage_state = app.state::<UsageLedgerState>();
+    ensure_usage_budget_available(app, &usage_state, &settings.usage)?;
+    let client = app.state::<HttpClientState>().client()?;
+    let rate_limiter = app.state::<ModelRateLimitState>();
+    let health = app.state::<ProviderHealthState>();
+    let chain = llm_provider_chain(&settings.llm, &health, now_epoch_millis());
+    let mut streamed_any = false;
+    let mut answered = None;
+    for (index, candidate) in chain.iter().enumerate() {
+        let result = call_llm_for_action(
+            &client,
+            &rate_limiter,
+            &candidate.llm,
+            params,
+            messages,
+            true,
+            |delta| match delta {
+                StreamDelta::Reasoning(_) if !show_reasoning => true,
+                delta => {
+                    streamed_any = true;
+                    on_delta(delta)
                 }
+            },
+        )
+        .await;
+        let health_key = provider_health_key(&candidate.llm);
+        match result {
+            Ok(completion) => {
+                health.record_success(&health_key);
+                answered = Some((candidate, completion));
                 break;
             }
-
-            let Some(status) = final_status else {
-                continue;
-            };
-            let value = final_value;
-
-            if !status.is_success() {
-                let detail = if final_raw_body.trim().is_empty() {
-                    value.to_string()
-                } else {
-                    format_response_body_for_error(&final_raw_body)
-                };
-                last_error = Some(format!("GLM OCR 调用失败（{}）: {}", status, detail));
-                let is_auth_error = status.as_u16() == 401 || status.as_u16() == 403;
-                if auth_index == 0 && !is_auth_error {
-                    break;
+            Err(error) => {
+                if !should_fail_over(&error) {
+                    return Err(error);
                 }
-                continue;
+                health.record_failure(&health_key, now_epoch_millis());
+                if streamed_any || index + 1 == chain.len() {
+                    return Err(error);
+                }
+                eprintln!(
+                    "[Failover] provider {} failed, trying next: {error}",
+                    candidate.provider.name
+                );
             }
+        }
+    }
+    let Some((candidate, completion)) = answered else {
+        return Err(ModelError::EmptyResponse.into());
+    };
+    let usage = completion.usage.map(|usage| {
+        record_model_usage(
+            app,
+            &usage_state,
+            &settings.usage,
+            &candidate.llm.model,
+            action,
+            usage,
+        )
+    });
 
-            let text = extract_vision_ocr_text(&value);
-            if text.trim().is_empty() {
-                last_error = Some(format!("GLM OCR 返回内容为空: {}", value));
-                continue;
-            }
-            return Ok(text.trim().to_string());
+    // Structured replies are cached by `run_structured_output` once they pass validation, and
+    // fallback answers are not cached because the key describes the primary provider.
+    if let Some(key) =
+        cache_key.filter(|_| params.json_schema.is_none() && !candidate.provider.is_fallback)
+    {
+        if !completion.text.trim().is_empty() && completion.tool_calls.is_empty() {
+            store_cached_llm_response(
+                app,
+                &cache_state,
+                cache_settings,
+                &settings.llm,
+                key,
+                &completion.text,
+            );
         }
     }
+    Ok(LlmActionOutcome {
+        text: completion.text,
+        reasoning: if show_reasoning {
+            completion.reasoning
+        } else {
+            String::new()
+        },
+        from_cache: false,
+        usage,
+        provider: Some(candidate.provider.clone()),
+        tool_calls: completion.tool_calls,
+    })
+}
 
-    Err(CommandError::Settings(last_error.unwrap_or_else(|| {
-        "GLM OCR 调用失败，请检查接口地址、API Key 和模型配置".to_string()
-    })))
+fn merge_request_usage(
+    total: Option<RequestUsagePayload>,
+    next: Option<RequestUsagePayload>,
+) -> Option<RequestUsagePayload> {
+    match (total, next) {
+        (Some(mut total), Some(next)) => {
+            total.prompt_tokens += next.prompt_tokens;
+            total.completion_tokens += next.completion_tokens;
+            total.total_tokens += next.total_tokens;
+            total.estimated |= next.estimated;
+            total.estimated_cost_usd = match (total.estimated_cost_usd, next.estimated_cost_usd) {
+                (Some(left), Some(right)) => Some(left + right),
+                (left, right) => left.or(right),
+            };
+            Some(total)
+        }
+        (total, next) => total.or(next),
+    }
 }
 
-fn extract_stream_delta_text(value: &serde_json::Value) -> String {
-    let Some(choice) = value.get("choices").and_then(|choices| choices.get(0)) else {
-        return String::new();
-    };
+/// Shared context for a selection action whose input was split into chunks.
+struct ChunkedSelectionRun<'a> {
+    app: &'a AppHandle,
+    settings: &'a AppSettings,
+    params: &'a GenerationParams,
+    regenerate: bool,
+    stream_base: &'a SelectionResultPayload,
+    task_nonce: u64,
+}
 
-    if let Some(content) = choice
-        .get("delta")
-        .and_then(|delta| delta.get("content"))
-        .and_then(|content| content.as_str())
-    {
-        return content.to_string();
+impl ChunkedSelectionRun<'_> {
+    fn emit(&self, output: &str, phase: &'static str, completed: usize, total: usize) {
+        self.emit_segments(output, &[], phase, completed, total);
     }
 
-    if let Some(items) = choice
-        .get("delta")
-        .and_then(|delta| delta.get("content"))
-        .and_then(|content| content.as_array())
-    {
-        let mut merged = String::new();
-        for item in items {
-            if let Some(text) = item.get("text").and_then(|value| value.as_str()) {
-                merged.push_str(text);
+    fn emit_segments(
+        &self,
+        output: &str,
+        segments: &[BilingualSegmentPayload],
+        phase: &'static str,
+        completed: usize,
+        total: usize,
+    ) {
+        let mut update = self.stream_base.clone();
+        update.output_text = output.to_string();
+        update.segments = segments.to_vec();
+        update.chunk_progress = Some(ChunkProgressPayload {
+            phase,
+            completed,
+            total,
+        });
+        emit_selection_result(self.app, update);
+    }
+
+    /// Runs one chunk request, streaming `prefix` plus the chunk's answer when `phase` is given.
+    async fn run_chunk(
+        &self,
+        action: &str,
+        messages: &[ChatMessage],
+        stream: Option<(&str, &'static str, usize, usize)>,
+    ) -> Result<LlmActionOutcome, CommandError> {
+        let mut chunk_output = String::new();
+        let mut last_emit_ms = 0u64;
+        run_llm_action(
+            self.app,
+            self.settings,
+            action,
+            messages,
+            self.params,
+            self.regenerate,
+            |delta| {
+                if !is_selection_result_task_active(self.app, self.task_nonce) {
+                    return false;
+                }
+                let (StreamDelta::Answer(text), Some((prefix, phase, completed, total))) =
+                    (delta, stream)
+                else {
+                    return true;
+                };
+                chunk_output.push_str(text);
+                let now = now_epoch_millis();
+                if now.saturating_sub(last_emit_ms) < STREAM_EMIT_THROTTLE_MS
+                    && !text.contains('\n')
+                {
+                    return true;
+                }
+                last_emit_ms = now;
+                self.emit(&format!("{prefix}{chunk_output}"), phase, completed, total);
+                true
+            },
+        )
+        .await
+    }
+
+    /// Translates chunk by chunk in order, keeping the original paragraph spacing between
+    /// translated chunks. Bilingual chunks are shown once each chunk is complete.
+    async fn translate(
+        &self,
+        chunks: &[String],
+        translate_from: Option<&str>,
+        translate_to: Option<&str>,
+        bilingual_mode: BilingualMode,
+        output: &mut String,
+        segments: &mut Vec<BilingualSegmentPayload>,
+    ) -> Result<LlmActionOutcome, CommandError> {
+        let total = chunks.len();
+        let mut combined = LlmActionOutcome {
+            text: String::new(),
+            reasoning: String::new(),
+            from_cache: true,
+            usage: None,
+            provider: None,
+            tool_calls: Vec::new(),
+        };
+        for (index, chunk) in chunks.iter().enumerate() {
+            let body = chunk.trim_end();
+            let separator = &chunk[body.len()..];
+            let outcome = if bilingual_mode == BilingualMode::Off {
+                let (system_prompt, user_prompt) =
+                    translate_prompt(self.settings, body, translate_from, translate_to);
+                let messages = single_turn_messages(&system_prompt, &user_prompt);
+                let prefix = output.clone();
+                let outcome = self
+                    .run_chunk(
+                        "translate",
+                        &messages,
+                        Some((&prefix, "translate", index, total)),
+                    )
+                    .await?;
+                output.push_str(outcome.text.trim());
+                outcome
+            } else {
+                let sources = split_bilingual_sources(body, bilingual_mode);
+                let (system_prompt, user_prompt) = bilingual_translate_prompt(
+                    self.settings,
+                    &sources,
+                    translate_from,
+                    translate_to,
+                );
+                let messages = single_turn_messages(&system_prompt, &user_prompt);
+                self.emit_segments(output, segments, "translate", index, total);
+                let outcome = self.run_chunk("translate", &messages, None).await?;
+                let (chunk_segments, chunk_text) = parse_bilingual_output(&outcome.text, &sources);
+                segments.extend(chunk_segments);
+                output.push_str(&chunk_text);
+                outcome
+            };
+            output.push_str(separator);
+            self.emit_segments(output, segments, "translate", index + 1, total);
+            combined.from_cache &= outcome.from_cache;
+            combined.usage = merge_request_usage(combined.usage, outcome.usage);
+            combined.provider = outcome.provider.or(combined.provider);
+        }
+        combined.text = output.trim_end().to_string();
+        Ok(combined)
+    }
+
+    /// Map-reduce summary: every chunk is summarized on its own, then the partial summaries are
+    /// merged, regrouping them first if they are still too long for one request.
+    async fn summarize(
+        &self,
+        chunks: Vec<String>,
+        output: &mut String,
+    ) -> Result<LlmActionOutcome, CommandError> {
+        let mut usage = None;
+        let mut from_cache = true;
+        let mut provider = None;
+        let mut level = chunks;
+        for _ in 0..MAX_SUMMARY_REDUCE_ROUNDS {
+            let total = level.len();
+            let mut partials = Vec::with_capacity(total);
+            for (index, chunk) in level.iter().enumerate() {
+                self.emit("", "map", index, total);
+                let (system_prompt, user_prompt) = choose_builtin_prompt(
+                    SelectionActionKind::Summarize,
+                    chunk.trim(),
+                    None,
+                    None,
+                    self.settings,
+                );
+                let user_prompt = format!(
+                    "This is part {} of {} of a longer document; summarize this part only.\n{user_prompt}",
+                    index + 1,
+                    total
+                );
+                let messages = single_turn_messages(&system_prompt, &user_prompt);
+                let outcome = self.run_chunk("summary", &messages, None).await?;
+                from_cache &= outcome.from_cache;
+                usage = merge_request_usage(usage, outcome.usage);
+                provider = outcome.provider.or(provider);
+                partials.push(outcome.text.trim().to_string());
+            }
+            let joined = partials.join("\n\n");
+            match chunk_long_input(&joined, self.settings.llm.chunk_tokens) {
+                Some(regrouped) => level = regrouped,
+                None => {
+                    level = vec![joined];
+                    break;
+                }
             }
         }
-        return merged;
+
+        let partial_summaries = level.join("\n\n");
+        let (system_prompt, _) = choose_builtin_prompt(
+            SelectionActionKind::Summarize,
+            "",
+            None,
+            None,
+            self.settings,
+        );
+        let user_prompt = format!(
+            "Task: Merge the partial summaries of one long document into a single faithful and concise summary. Remove repetition between parts.
+Partial summaries:
+{partial_summaries}"
+        );
+        let messages = single_turn_messages(&system_prompt, &user_prompt);
+        let outcome = self
+            .run_chunk("summary", &messages, Some(("", "reduce", 0, 1)))
+            .await?;
+        *output = outcome.text.clone();
+        Ok(LlmActionOutcome {
+            from_cache: from_cache && outcome.from_cache,
+            usage: merge_request_usage(usage, outcome.usage),
+            provider: outcome.provider.or(provider),
+            ..outcome
+        })
     }
-
-    String::new()
 }
 
-fn is_stream_payload_finished(value: &serde_json::Value) -> bool {
-    value
-        .get("choices")
-        .and_then(|choices| choices.get(0))
-        .and_then(|choice| choice.get("finish_reason"))
-        .and_then(|reason| reason.as_str())
-        .map(|reason| !reason.is_empty())
-        .unwrap_or(false)
+/// Stands in for the full prompt of a chunked run in the action log and in follow-ups, which
+/// would not fit the context window. Only the first chunk of the input is kept.
+fn chunked_exchange_messages(system_prompt: &str, chunks: &[String]) -> Vec<ChatMessage> {
+    let first = chunks.first().map(String::as_str).unwrap_or_default();
+    let user_prompt = format!(
+        "The text below is the beginning of a longer input that was processed in {} parts. \
+         Answer follow-up questions based on it and on your previous answer.\n\n{first}",
+        chunks.len()
+    );
+    single_turn_messages(system_prompt, &user_prompt)
 }
 
-fn process_stream_data_payload(
-    payload: &str,
-    content: &mut String,
-    on_delta: &mut impl FnMut(&str) -> bool,
-) -> Result<bool, CommandError> {
-    if payload.is_empty() {
-        return Ok(false);
-    }
-    if payload == "[DONE]" {
-        return Ok(true);
-    }
-
-    let Ok(value) = serde_json::from_str::<serde_json::Value>(payload) else {
-        return Ok(false);
+/// Keeps the finished exchange so the result window can ask follow-up questions about it.
+/// Answers longer than a quarter of the context window, e.g. the translation of a chunked
+/// run, are stored cut down to their beginning.
+fn remember_conversation<R: Runtime>(
+    app: &AppHandle<R>,
+    origin: ConversationOrigin,
+    mut messages: Vec<ChatMessage>,
+) {
+    let (request_id, answer) = match &origin {
+        ConversationOrigin::Selection(payload) => (&payload.request_id, &payload.output_text),
+        ConversationOrigin::Ocr(payload) => (&payload.request_id, &payload.output_text),
+    };
+    let context_tokens = app
+        .try_state::<AppSettingsState>()
+        .and_then(|state| {
+            state
+                .data
+                .lock()
+                .ok()
+                .map(|settings| settings.llm.context_tokens)
+        })
+        .unwrap_or(DEFAULT_CONTEXT_TOKENS);
+    let answer_tokens = context_tokens / 4;
+    let answer = if estimate_text_tokens(answer) > answer_tokens {
+        split_text_into_chunks(answer, answer_tokens)
+            .into_iter()
+            .next()
+            .unwrap_or_default()
+    } else {
+        answer.clone()
     };
+    messages.push(ChatMessage::new("assistant", answer));
+    let base_len = messages.len();
+    let request_id = request_id.clone();
 
-    if let Some(error) = value.get("error") {
-        return Err(CommandError::Settings(format!("模型流式响应错误: {error}")));
+    let state = app.state::<ConversationState>();
+    let Ok(mut sessions) = state.sessions.lock() else {
+        return;
+    };
+    sessions.insert(
+        request_id,
+        ConversationSession {
+            origin,
+            messages,
+            base_len,
+            follow_ups: Vec::new(),
+            updated_at_ms: now_epoch_millis(),
+        },
+    );
+    while sessions.len() > MAX_CONVERSATION_SESSIONS {
+        let Some(oldest) = sessions
+            .iter()
+            .min_by_key(|(_, session)| session.updated_at_ms)
+            .map(|(key, _)| key.clone())
+        else {
+            break;
+        };
+        sessions.remove(&oldest);
     }
+}
 
-    let delta = extract_stream_delta_text(&value);
-    if !delta.is_empty() {
-        content.push_str(&delta);
-        if !on_delta(&delta) {
-            return Err(CommandError::Settings(TASK_REPLACED_ERROR.to_string()));
-        }
+/// Drops the oldest follow-up pairs until the request fits the context budget. The original
+/// exchange (the first `base_len` messages) and the new question are always kept.
+fn truncate_conversation_messages(
+    messages: &mut Vec<ChatMessage>,
+    base_len: usize,
+    budget_tokens: u32,
+) {
+    while messages.len() > base_len + 1 && estimate_messages_tokens(messages) > budget_tokens {
+        messages.drain(base_len..base_len + 2);
     }
+}
 
-    Ok(is_stream_payload_finished(&value))
+/// Tokens available for the follow-up request once room for the answer is reserved.
+fn conversation_context_budget(llm: &LlmSettings) -> u32 {
+    let reserved = llm.max_tokens.min(llm.context_tokens / 2);
+    llm.context_tokens.saturating_sub(reserved)
 }
 
-fn parse_sse_text_content(
-    body: &str,
-    on_delta: &mut impl FnMut(&str) -> bool,
-) -> Result<(String, bool), CommandError> {
-    let mut content = String::new();
-    let mut event_data = String::new();
-    let mut saw_data = false;
+fn begin_conversation_turn<R: Runtime>(
+    app: &AppHandle<R>,
+    request_id: &str,
+    question: &str,
+    llm: &LlmSettings,
+) -> Result<
+    (
+        ConversationOrigin,
+        Vec<FollowUpTurnPayload>,
+        Vec<ChatMessage>,
+    ),
+    CommandError,
+> {
+    let state = app.state::<ConversationState>();
+    let sessions = state.sessions.lock().map_err(|_| CommandError::Lock)?;
+    let session = sessions.get(request_id).ok_or_else(|| {
+        CommandError::Settings("该结果的对话已过期，请重新运行后再追问".to_string())
+    })?;
+    let mut messages = session.messages.clone();
+    messages.push(ChatMessage::new("user", question));
+    truncate_conversation_messages(
+        &mut messages,
+        session.base_len,
+        conversation_context_budget(llm),
+    );
+    Ok((session.origin.clone(), session.follow_ups.clone(), messages))
+}
 
-    let mut flush_event = |event_data: &mut String,
-                           content: &mut String,
-                           saw_data: &mut bool|
-     -> Result<bool, CommandError> {
-        if event_data.trim().is_empty() {
-            event_data.clear();
-            return Ok(false);
-        }
-        *saw_data = true;
-        let payload = event_data.trim().to_string();
-        event_data.clear();
-        process_stream_data_payload(&payload, content, on_delta)
+fn complete_conversation_turn<R: Runtime>(
+    app: &AppHandle<R>,
+    request_id: &str,
+    turn: &FollowUpTurnPayload,
+) {
+    let state = app.state::<ConversationState>();
+    let Ok(mut sessions) = state.sessions.lock() else {
+        return;
+    };
+    let Some(session) = sessions.get_mut(request_id) else {
+        return;
     };
+    session
+        .messages
+        .push(ChatMessage::new("user", turn.question.clone()));
+    session
+        .messages
+        .push(ChatMessage::new("assistant", turn.answer.clone()));
+    session.follow_ups.push(turn.clone());
+    if session.follow_ups.len() > MAX_CONVERSATION_FOLLOW_UPS {
+        session.follow_ups.remove(0);
+        let base_len = session.base_len;
+        session.messages.drain(base_len..base_len + 2);
+    }
+    session.updated_at_ms = now_epoch_millis();
+}
+
+fn normalize_follow_up_question(message: &str) -> Result<String, CommandError> {
+    let question = message.trim();
+    if question.is_empty() {
+        return Err(CommandError::Settings("追问内容为空".to_string()));
+    }
+    if question.chars().count() > MAX_CONVERSATION_MESSAGE_CHARS {
+        return Err(CommandError::Settings(format!(
+            "追问内容过长，请控制在 {MAX_CONVERSATION_MESSAGE_CHARS} 字以内"
+        )));
+    }
+    Ok(question.to_string())
+}
 
-    for raw_line in body.lines() {
-        let line = raw_line.trim_end_matches('\r');
-        if line.trim().is_empty() {
-            if flush_event(&mut event_data, &mut content, &mut saw_data)? {
-                return Ok((content, true));
-            }
-            continue;
+fn action_log_file_path<R: Runtime>(app: &AppHandle<R>) -> Result<PathBuf, CommandError> {
+    let mut dir = app
+        .path()
+        .app_config_dir()
+        .map_err(|error| CommandError::Settings(error.to_string()))?;
+    fs::create_dir_all(&dir).map_err(|error| CommandError::Settings(error.to_string()))?;
+    dir.push(ACTION_LOG_FILENAME);
+    Ok(dir)
+}
+
+/// Reads the JSON Lines log (oldest first) and returns the newest entries first together with
+/// the number of records in the file. Unreadable lines, e.g. from an interrupted write, are skipped.
+fn load_action_log<R: Runtime>(app: &AppHandle<R>) -> (Vec<ActionLogEntry>, usize) {
+    let Ok(path) = action_log_file_path(app) else {
+        return (Vec::new(), 0);
+    };
+    if !path.exists() {
+        return (Vec::new(), 0);
+    }
+    match read_history_text_with_retry(&path) {
+        Ok(text) => {
+            let lines: Vec<&str> = text
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .collect();
+            let mut entries: Vec<ActionLogEntry> = lines
+                .iter()
+                .rev()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect();
+            entries.truncate(MAX_ACTION_LOG_ENTRIES);
+            (entries, lines.len())
         }
-        if let Some(payload) = line.strip_prefix("data:") {
-            if !event_data.is_empty() {
-                event_data.push('\n');
-            }
-            event_data.push_str(payload.trim_start());
+        Err(error) => {
+            eprintln!("[ActionLog] load failed, starting empty: {error}");
+            (Vec::new(), 0)
         }
     }
+}
 
-    let done = flush_event(&mut event_data, &mut content, &mut saw_data)?;
-    Ok((content, saw_data || done))
+fn action_log_line(entry: &ActionLogEntry) -> Result<String, CommandError> {
+    let mut line = serde_json::to_string(entry)
+        .map_err(|error| CommandError::Serialization(error.to_string()))?;
+    line.push('\n');
+    Ok(line)
 }
 
-async fn read_response_body_lossy(response: reqwest::Response) -> Result<String, reqwest::Error> {
-    let mut response = response;
-    let mut bytes = Vec::<u8>::new();
-    loop {
-        match response.chunk().await {
-            Ok(Some(chunk)) => bytes.extend_from_slice(&chunk),
-            Ok(None) => break,
-            Err(error) => {
-                if bytes.is_empty() {
-                    return Err(error);
-                }
-                eprintln!(
-                    "[HTTP] body read interrupted after {} bytes, using partial content: {}",
-                    bytes.len(),
-                    error
-                );
-                break;
-            }
-        }
-    }
-    Ok(String::from_utf8_lossy(&bytes).to_string())
+fn append_action_log<R: Runtime>(
+    app: &AppHandle<R>,
+    entry: &ActionLogEntry,
+) -> Result<(), CommandError> {
+    let path = action_log_file_path(app)?;
+    let line = action_log_line(entry)?;
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .map_err(|error| CommandError::Settings(error.to_string()))
+}
+
+/// Rewrites the whole log with `entries`; used for compaction and clearing.
+fn persist_action_log<R: Runtime>(
+    app: &AppHandle<R>,
+    entries: &[ActionLogEntry],
+) -> Result<(), CommandError> {
+    let path = action_log_file_path(app)?;
+    let mut payload = String::new();
+    for entry in entries.iter().rev() {
+        payload.push_str(&action_log_line(entry)?);
+    }
+    let mut backup_path = path.clone();
+    backup_path.set_file_name(format!("{ACTION_LOG_FILENAME}.bak"));
+    write_text_file_with_backup(&path, &payload, &backup_path)?;
+    app.state::<ActionLogState>()
+        .file_records
+        .store(entries.len(), Ordering::Relaxed);
+    Ok(())
 }
 
-async fn call_llm_for_action(
-    client: &reqwest::Client,
-    llm: &LlmSettings,
-    system_prompt: &str,
-    user_prompt: &str,
-    stream_enabled: bool,
-    mut on_delta: impl FnMut(&str) -> bool,
-) -> Result<String, CommandError> {
-    if !llm.enabled || llm.api_key.trim().is_empty() {
-        return Err(CommandError::Settings(
-            "请先在设置中配置并启用大模型 API".to_string(),
-        ));
+fn with_action_log<R: Runtime, T>(
+    app: &AppHandle<R>,
+    f: impl FnOnce(&mut Vec<ActionLogEntry>) -> T,
+) -> Result<T, CommandError> {
+    let state = app.state::<ActionLogState>();
+    let mut guard = state.entries.lock().map_err(|_| CommandError::Lock)?;
+    let entries = guard.get_or_insert_with(|| {
+        let (entries, file_records) = load_action_log(app);
+        state.file_records.store(file_records, Ordering::Relaxed);
+        entries
+    });
+    Ok(f(entries))
+}
+
+/// Stores the OCR capture next to the action log so a history entry can point back to the
+/// original image. Returns the file path, or `None` if the image could not be written.
+fn save_ocr_capture_image<R: Runtime>(
+    app: &AppHandle<R>,
+    request_id: &str,
+    image_data_url: &str,
+) -> Option<String> {
+    let (_, encoded) = image_data_url.split_once(";base64,")?;
+    let bytes = BASE64.decode(encoded.trim()).ok()?;
+    let mut dir = app.path().app_cache_dir().ok()?;
+    dir.push(OCR_CAPTURE_IMAGE_DIR);
+    fs::create_dir_all(&dir).ok()?;
+    dir.push(format!("{request_id}.png"));
+    match fs::write(&dir, bytes) {
+        Ok(()) => Some(dir.to_string_lossy().to_string()),
+        Err(error) => {
+            eprintln!("[ActionLog] failed to save OCR capture: {error}");
+            None
+        }
     }
+}
 
-    let request_body = json!({
-        "model": llm.model,
-        "temperature": llm.temperature,
-        "max_tokens": llm.max_tokens,
-        "stream": stream_enabled,
-        "messages": [
-            {"role": "system", "content": system_prompt},
-            {"role": "user", "content": user_prompt}
-        ]
-    });
-    let mut last_error: Option<String> = None;
+fn action_log_enabled<R: Runtime>(app: &AppHandle<R>) -> bool {
+    app.state::<AppSettingsState>()
+        .data
+        .lock()
+        .map(|settings| settings.history.record_ai_actions)
+        .unwrap_or(true)
+}
 
-    for attempt in 0..MODEL_REQUEST_MAX_ATTEMPTS {
-        let mut response = match client
-            .post(llm.base_url.trim())
-            .timeout(Duration::from_millis(llm.timeout_ms))
-            .header(AUTHORIZATION, format!("Bearer {}", llm.api_key.trim()))
-            .header(CONTENT_TYPE, "application/json")
-            .json(&request_body)
-            .send()
-            .await
-        {
-            Ok(resp) => resp,
-            Err(error) => {
-                if should_retry_network_error(&error) && attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS {
-                    last_error = Some(error.to_string());
-                    sleep_with_backoff(attempt).await;
-                    continue;
+fn record_action_log<R: Runtime>(app: &AppHandle<R>, entry: ActionLogEntry) {
+    if !action_log_enabled(app) {
+        if let Some(path) = entry.ocr_image_path {
+            let _ = fs::remove_file(path);
+        }
+        return;
+    }
+    let result = with_action_log(app, |entries| {
+        let appended = append_action_log(app, &entry);
+        entries.insert(0, entry);
+        if entries.len() > MAX_ACTION_LOG_ENTRIES {
+            for evicted in entries.drain(MAX_ACTION_LOG_ENTRIES..) {
+                if let Some(path) = evicted.ocr_image_path {
+                    let _ = fs::remove_file(path);
                 }
-                return Err(CommandError::Settings(error.to_string()));
-            }
-        };
-
-        let status = response.status();
-        if !status.is_success() {
-            let raw_body = read_response_body_lossy(response)
-                .await
-                .unwrap_or_else(|error| format!("(failed to read response body: {error})"));
-            let message = format!(
-                "模型接口调用失败（{}）: {}",
-                status,
-                format_response_body_for_error(&raw_body)
-            );
-            if should_retry_http_status(status) && attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS {
-                last_error = Some(message);
-                sleep_with_backoff(attempt).await;
-                continue;
             }
-            return Err(CommandError::Settings(message));
         }
+        let state = app.state::<ActionLogState>();
+        let file_records = state.file_records.fetch_add(1, Ordering::Relaxed) + 1;
+        if appended.is_err() || file_records >= ACTION_LOG_COMPACT_RECORDS {
+            persist_action_log(app, entries)
+        } else {
+            Ok(())
+        }
+    });
+    if let Err(error) = result.and_then(|persisted| persisted) {
+        eprintln!("[ActionLog] record failed: {error}");
+    }
+}
 
-        let content_type = response
-            .headers()
-            .get(CONTENT_TYPE)
-            .and_then(|value| value.to_str().ok())
-            .unwrap_or("")
-            .to_ascii_lowercase();
-        let mut content = String::new();
+fn word_list_file_path<R: Runtime>(app: &AppHandle<R>) -> Result<PathBuf, CommandError> {
+    let mut dir = app
+        .path()
+        .app_config_dir()
+        .map_err(|error| CommandError::Settings(error.to_string()))?;
+    fs::create_dir_all(&dir).map_err(|error| CommandError::Settings(error.to_string()))?;
+    dir.push(WORD_LIST_FILENAME);
+    Ok(dir)
+}
 
-        if content_type.contains("text/event-stream") {
-            let mut line_buf = String::new();
-            let mut event_data = String::new();
-            let mut stream_raw = String::new();
-            let mut stream_done = false;
-            let mut should_retry_stream = false;
+fn load_word_list<R: Runtime>(app: &AppHandle<R>) -> Vec<WordListEntry> {
+    let Ok(path) = word_list_file_path(app) else {
+        return Vec::new();
+    };
+    if !path.exists() {
+        return Vec::new();
+    }
+    match read_history_text_with_retry(&path)
+        .map_err(|error| error.to_string())
+        .and_then(|text| {
+            serde_json::from_str::<Vec<WordListEntry>>(&text).map_err(|error| error.to_string())
+        }) {
+        Ok(entries) => entries,
+        Err(error) => {
+            eprintln!("[WordList] load failed, starting empty: {error}");
+            Vec::new()
+        }
+    }
+}
 
-            let mut flush_event =
-                |event_data: &mut String, content: &mut String| -> Result<bool, CommandError> {
-                    if event_data.trim().is_empty() {
-                        event_data.clear();
-                        return Ok(false);
-                    }
-                    let payload = event_data.trim().to_string();
-                    event_data.clear();
-                    process_stream_data_payload(&payload, content, &mut on_delta)
-                };
+fn persist_word_list<R: Runtime>(
+    app: &AppHandle<R>,
+    entries: &[WordListEntry],
+) -> Result<(), CommandError> {
+    let path = word_list_file_path(app)?;
+    let payload = serde_json::to_string(entries)
+        .map_err(|error| CommandError::Serialization(error.to_string()))?;
+    let mut backup_path = path.clone();
+    backup_path.set_file_name(format!("{WORD_LIST_FILENAME}.bak"));
+    write_text_file_with_backup(&path, &payload, &backup_path)?;
+    Ok(())
+}
 
-            loop {
-                let chunk = match response.chunk().await {
-                    Ok(value) => value,
-                    Err(error) => {
-                        if content.is_empty()
-                            && should_retry_network_error(&error)
-                            && attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS
-                        {
-                            should_retry_stream = true;
-                            last_error = Some(error.to_string());
-                            break;
-                        }
-                        if !content.is_empty() && should_retry_network_error(&error) {
-                            // Some providers may close or truncate chunked responses after
-                            // streaming useful content. Keep partial output instead of failing.
-                            eprintln!(
-                                "[LLM] stream body read interrupted, using partial output: {}",
-                                error
-                            );
-                            break;
-                        }
-                        return Err(CommandError::Settings(error.to_string()));
-                    }
-                };
+fn with_word_list<R: Runtime, T>(
+    app: &AppHandle<R>,
+    f: impl FnOnce(&mut Vec<WordListEntry>) -> T,
+) -> Result<T, CommandError> {
+    let state = app.state::<WordListState>();
+    let mut guard = state.entries.lock().map_err(|_| CommandError::Lock)?;
+    let entries = guard.get_or_insert_with(|| load_word_list(app));
+    Ok(f(entries))
+}
 
-                let Some(chunk) = chunk else {
-                    break;
-                };
+fn find_action_log_entry<R: Runtime>(
+    app: &AppHandle<R>,
+    id: &str,
+) -> Result<ActionLogEntry, CommandError> {
+    with_action_log(app, |entries| {
+        entries.iter().find(|entry| entry.id == id).cloned()
+    })?
+    .ok_or(CommandError::NotFound)
+}
 
-                let chunk_text = String::from_utf8_lossy(&chunk);
-                stream_raw.push_str(&chunk_text);
-                line_buf.push_str(&chunk_text);
+fn csv_escape(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
 
-                while let Some(line_end) = line_buf.find('\n') {
-                    let raw_line = line_buf[..line_end].to_string();
-                    line_buf.drain(..=line_end);
-                    let line = raw_line.trim_end_matches('\r');
+fn usage_ledger_file_path<R: Runtime>(app: &AppHandle<R>) -> Result<PathBuf, CommandError> {
+    let mut dir = app
+        .path()
+        .app_config_dir()
+        .map_err(|error| CommandError::Settings(error.to_string()))?;
+    fs::create_dir_all(&dir).map_err(|error| CommandError::Settings(error.to_string()))?;
+    dir.push(USAGE_LEDGER_FILENAME);
+    Ok(dir)
+}
 
-                    if line.trim().is_empty() {
-                        if flush_event(&mut event_data, &mut content)? {
-                            stream_done = true;
-                            break;
-                        }
-                        continue;
-                    }
+fn load_usage_ledger<R: Runtime>(app: &AppHandle<R>) -> UsageLedger {
+    let Ok(path) = usage_ledger_file_path(app) else {
+        return UsageLedger::default();
+    };
+    if !path.exists() {
+        return UsageLedger::default();
+    }
+    match read_settings_text_with_retry(&path)
+        .map_err(|error| error.to_string())
+        .and_then(|text| {
+            serde_json::from_str::<UsageLedger>(&text).map_err(|error| error.to_string())
+        }) {
+        Ok(ledger) => ledger,
+        Err(error) => {
+            eprintln!("[Usage] ledger load failed, starting empty: {error}");
+            UsageLedger::default()
+        }
+    }
+}
 
-                    if let Some(payload) = line.strip_prefix("data:") {
-                        if !event_data.is_empty() {
-                            event_data.push('\n');
-                        }
-                        event_data.push_str(payload.trim_start());
-                    }
-                }
+fn persist_usage_ledger<R: Runtime>(
+    app: &AppHandle<R>,
+    ledger: &UsageLedger,
+) -> Result<(), CommandError> {
+    let path = usage_ledger_file_path(app)?;
+    let payload = serde_json::to_string_pretty(ledger)
+        .map_err(|error| CommandError::Serialization(error.to_string()))?;
+    let mut backup_path = path.clone();
+    backup_path.set_file_name(format!("{USAGE_LEDGER_FILENAME}.bak"));
+    write_text_file_with_backup(&path, &payload, &backup_path)?;
+    Ok(())
+}
 
-                if stream_done {
-                    break;
-                }
-            }
+fn usage_day_key(date: chrono::NaiveDate) -> String {
+    date.format("%Y-%m-%d").to_string()
+}
 
-            if should_retry_stream {
-                sleep_with_backoff(attempt).await;
-                continue;
-            }
+fn lookup_model_price(usage: &UsageSettings, model: &str) -> Option<(f64, f64)> {
+    let lowered = model.trim().to_ascii_lowercase();
+    let bare = lowered.rsplit('/').next().unwrap_or(&lowered);
+    if let Some(found) = usage
+        .price_overrides
+        .iter()
+        .filter(|item| {
+            let candidate = item.model.to_ascii_lowercase();
+            lowered.starts_with(&candidate) || bare.starts_with(&candidate)
+        })
+        .max_by_key(|item| item.model.len())
+    {
+        return Some((found.input_per_million_usd, found.output_per_million_usd));
+    }
+    MODEL_PRICE_TABLE
+        .iter()
+        .filter(|(prefix, _, _)| bare.starts_with(prefix))
+        .max_by_key(|(prefix, _, _)| prefix.len())
+        .map(|(_, input, output)| (*input, *output))
+}
 
-            if !stream_done {
-                let tail = line_buf.trim_end_matches('\r').trim();
-                if let Some(payload) = tail.strip_prefix("data:") {
-                    if !event_data.is_empty() {
-                        event_data.push('\n');
-                    }
-                    event_data.push_str(payload.trim_start());
-                }
-                if !event_data.trim().is_empty() {
-                    let _ = flush_event(&mut event_data, &mut content)?;
-                }
-            }
+fn estimate_usage_cost_usd(usage: &UsageSettings, model: &str, tokens: &TokenUsage) -> Option<f64> {
+    let (input_price, output_price) = lookup_model_price(usage, model)?;
+    Some(
+        (tokens.prompt_tokens as f64 * input_price
+            + tokens.completion_tokens as f64 * output_price)
+            / 1_000_000.0,
+    )
+}
 
-            if content.trim().is_empty() && !stream_raw.trim().is_empty() {
-                let (fallback_content, saw_sse) =
-                    parse_sse_text_content(&stream_raw, &mut on_delta)?;
-                if saw_sse {
-                    content = fallback_content;
-                } else if let Ok(value) = serde_json::from_str::<serde_json::Value>(&stream_raw) {
-                    content = extract_llm_text_content(&value);
-                }
-            }
-        } else {
-            let body_text = match read_response_body_lossy(response).await {
-                Ok(value) => value,
-                Err(error) => {
-                    if should_retry_network_error(&error)
-                        && attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS
-                    {
-                        last_error = Some(error.to_string());
-                        sleep_with_backoff(attempt).await;
-                        continue;
-                    }
-                    return Err(CommandError::Settings(error.to_string()));
-                }
-            };
-            if let Ok(value) = serde_json::from_str::<serde_json::Value>(&body_text) {
-                content = extract_llm_text_content(&value);
-            }
-            if content.trim().is_empty() {
-                let (fallback_content, saw_sse) =
-                    parse_sse_text_content(&body_text, &mut on_delta)?;
-                if saw_sse {
-                    content = fallback_content;
-                } else {
-                    content = body_text.trim().to_string();
-                }
-            }
-        }
+fn record_model_usage<R: Runtime>(
+    app: &AppHandle<R>,
+    usage_state: &UsageLedgerState,
+    usage_settings: &UsageSettings,
+    model: &str,
+    action: &str,
+    tokens: TokenUsage,
+) -> RequestUsagePayload {
+    let model = model.trim().to_string();
+    let cost = estimate_usage_cost_usd(usage_settings, &model, &tokens);
+    let payload = RequestUsagePayload {
+        model: model.clone(),
+        prompt_tokens: tokens.prompt_tokens,
+        completion_tokens: tokens.completion_tokens,
+        total_tokens: tokens.total_tokens,
+        estimated: tokens.estimated,
+        estimated_cost_usd: cost,
+    };
 
-        let normalized = content.trim().to_string();
-        if !normalized.is_empty() {
-            return Ok(normalized);
+    let Ok(mut guard) = usage_state.ledger.lock() else {
+        return payload;
+    };
+    let ledger = guard.get_or_insert_with(|| load_usage_ledger(app));
+    let today = Local::now().date_naive();
+    let day = usage_day_key(today);
+    let oldest_day = usage_day_key(today - chrono::Duration::days(USAGE_LEDGER_RETENTION_DAYS));
+    ledger.rows.retain(|row| row.day >= oldest_day);
+    let row_index = match ledger
+        .rows
+        .iter()
+        .position(|row| row.day == day && row.model == model && row.action == action)
+    {
+        Some(index) => index,
+        None => {
+            ledger.rows.push(UsageLedgerRow {
+                day,
+                model,
+                action: action.to_string(),
+                requests: 0,
+                prompt_tokens: 0,
+                completion_tokens: 0,
+                estimated_cost_usd: 0.0,
+            });
+            ledger.rows.len() - 1
         }
+    };
+    let row = &mut ledger.rows[row_index];
+    row.requests += 1;
+    row.prompt_tokens += tokens.prompt_tokens;
+    row.completion_tokens += tokens.completion_tokens;
+    row.estimated_cost_usd += cost.unwrap_or(0.0);
 
-        let message = "模型返回内容为空，请检查模型和提示词配置".to_string();
-        if attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS {
-            last_error = Some(message);
-            sleep_with_backoff(attempt).await;
-            continue;
+    if let Err(error) = persist_usage_ledger(app, ledger) {
+        eprintln!("[Usage] ledger persist failed: {error}");
+    }
+    payload
+}
+
+fn current_month_usage(ledger: &UsageLedger) -> UsageTotals {
+    let month_prefix = Local::now().format("%Y-%m-").to_string();
+    let mut totals = UsageTotals::default();
+    for row in ledger
+        .rows
+        .iter()
+        .filter(|row| row.day.starts_with(&month_prefix))
+    {
+        totals.add_row(row);
+    }
+    totals
+}
+
+fn ensure_usage_budget_available<R: Runtime>(
+    app: &AppHandle<R>,
+    usage_state: &UsageLedgerState,
+    usage_settings: &UsageSettings,
+) -> Result<(), CommandError> {
+    if usage_settings.monthly_budget_usd <= 0.0 && usage_settings.monthly_token_budget == 0 {
+        return Ok(());
+    }
+    let mut guard = usage_state.ledger.lock().map_err(|_| CommandError::Lock)?;
+    let ledger = guard.get_or_insert_with(|| load_usage_ledger(app));
+    let month = current_month_usage(ledger);
+    if usage_settings.monthly_budget_usd > 0.0
+        && month.estimated_cost_usd >= usage_settings.monthly_budget_usd
+    {
+        return Err(ModelError::BudgetExceeded {
+            detail: format!(
+                "${:.2} / ${:.2}",
+                month.estimated_cost_usd, usage_settings.monthly_budget_usd
+            ),
+        }
+        .into());
+    }
+    if usage_settings.monthly_token_budget > 0
+        && month.total_tokens >= usage_settings.monthly_token_budget
+    {
+        return Err(ModelError::BudgetExceeded {
+            detail: format!(
+                "{} / {} tokens",
+                month.total_tokens, usage_settings.monthly_token_budget
+            ),
         }
-        return Err(CommandError::Settings(message));
+        .into());
     }
+    Ok(())
+}
 
-    Err(CommandError::Settings(last_error.unwrap_or_else(|| {
-        "模型接口调用失败，请稍后重试".to_string()
-    })))
+fn usage_breakdown<'a>(
+    rows: impl Iterator<Item = &'a UsageLedgerRow>,
+    key_of: impl Fn(&UsageLedgerRow) -> &str,
+) -> Vec<UsageBreakdownItem> {
+    let mut grouped: HashMap<String, UsageTotals> = HashMap::new();
+    for row in rows {
+        grouped
+            .entry(key_of(row).to_string())
+            .or_default()
+            .add_row(row);
+    }
+    let mut items: Vec<UsageBreakdownItem> = grouped
+        .into_iter()
+        .map(|(key, totals)| UsageBreakdownItem { key, totals })
+        .collect();
+    items.sort_by(|left, right| left.key.cmp(&right.key));
+    items
 }
 
 fn rgba_image_to_data_url(image: &RgbaImage) -> Result<String, CommandError> {
@@ -6252,9 +13270,10 @@ fn capture_ocr_area_to_data_url<R: Runtime>(
 
 async fn call_vision_ocr(
     client: &reqwest::Client,
+    rate_limiter: &ModelRateLimitState,
     vision: &VisionSettings,
     image_data_url: &str,
-) -> Result<String, CommandError> {
+) -> Result<ModelCompletion, CommandError> {
     if vision.api_key.trim().is_empty() {
         return Err(CommandError::Settings(
             "请先在智能 OCR 设置中填写视觉模型 API Key".to_string(),
@@ -6278,7 +13297,8 @@ async fn call_vision_ocr(
     }
 
     if should_use_glm_layout_parsing(vision) {
-        return call_glm_layout_parsing_ocr(client, vision, normalized_image_input).await;
+        return call_glm_layout_parsing_ocr(client, rate_limiter, vision, normalized_image_input)
+            .await;
     }
 
     let request_body = json!({
@@ -6300,8 +13320,20 @@ async fn call_vision_ocr(
             }
         ]
     });
-    let mut last_error: Option<String> = None;
+    let mut last_error: Option<ModelError> = None;
+    let rate_limit_key = model_rate_limit_key(&vision.base_url, &vision.model);
+    let estimated_tokens = vision
+        .max_tokens
+        .saturating_add(VISION_IMAGE_TOKEN_ESTIMATE);
     for attempt in 0..MODEL_REQUEST_MAX_ATTEMPTS {
+        acquire_model_rate_slot(
+            rate_limiter,
+            &rate_limit_key,
+            vision.requests_per_minute,
+            vision.tokens_per_minute,
+            estimated_tokens,
+        )
+        .await?;
         let response = match client
             .post(vision.base_url.trim())
             .timeout(Duration::from_millis(vision.timeout_ms))
@@ -6310,6 +13342,7 @@ async fn call_vision_ocr(
                 build_auth_header_value(&vision.api_key, true),
             )
             .header(CONTENT_TYPE, "application/json")
+            .headers(extra_header_map(&vision.extra_headers))
             .json(&request_body)
             .send()
             .await
@@ -6317,68 +13350,80 @@ async fn call_vision_ocr(
             Ok(resp) => resp,
             Err(error) => {
                 if should_retry_network_error(&error) && attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS {
-                    last_error = Some(error.to_string());
+                    last_error = Some(ModelError::from(error));
                     sleep_with_backoff(attempt).await;
                     continue;
                 }
-                return Err(CommandError::Settings(error.to_string()));
+                return Err(ModelError::from(error).into());
             }
         };
 
         let status = response.status();
+        let headers = response.headers().clone();
+        note_model_rate_limit_headers(rate_limiter, &rate_limit_key, &headers);
         let raw_body = match read_response_body_lossy(response).await {
             Ok(value) => value,
             Err(error) => {
                 if should_retry_network_error(&error) && attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS {
-                    last_error = Some(error.to_string());
+                    last_error = Some(ModelError::from(error));
                     sleep_with_backoff(attempt).await;
                     continue;
                 }
-                return Err(CommandError::Settings(error.to_string()));
+                return Err(ModelError::from(error).into());
             }
         };
         if !status.is_success() {
-            let message = format!(
-                "OCR 视觉模型调用失败（{}）: {}",
-                status,
-                format_response_body_for_error(&raw_body)
-            );
-            if should_retry_http_status(status) && attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS {
-                last_error = Some(message);
-                sleep_with_backoff(attempt).await;
+            let failure = classify_http_failure(status, &headers, &raw_body);
+            if should_retry_http_status(status)
+                && attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS
+                && sleep_before_retry(attempt, rate_limit_retry_delay_ms(&headers)).await
+            {
+                last_error = Some(failure);
                 continue;
             }
-            return Err(CommandError::Settings(message));
+            return Err(failure.into());
         }
 
         let value = match serde_json::from_str::<serde_json::Value>(&raw_body) {
             Ok(parsed) => parsed,
             Err(error) => {
                 if attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS {
-                    last_error = Some(error.to_string());
+                    last_error = Some(ModelError::ProviderError {
+                        status: status.as_u16(),
+                        body: format_response_body_for_error(&raw_body),
+                    });
                     sleep_with_backoff(attempt).await;
                     continue;
                 }
                 return Err(CommandError::Serialization(error.to_string()));
             }
         };
-        let text = extract_llm_text_content(&value);
-        if !text.trim().is_empty() {
-            return Ok(text.trim().to_string());
+        let completion = completion_from_json_value(&value);
+        let text = completion.content;
+        if !text.is_empty() {
+            let usage = completion
+                .usage
+                .unwrap_or_else(|| estimated_token_usage(VISION_IMAGE_TOKEN_ESTIMATE, &text));
+            return Ok(ModelCompletion {
+                text,
+                reasoning: completion.reasoning,
+                usage: Some(usage),
+                tool_calls: Vec::new(),
+            });
+        }
+        if stream_finish_reason(&value) == Some("content_filter") {
+            return Err(ModelError::ContentFiltered.into());
         }
 
-        let message = "OCR 识别结果为空，请调整截图区域或模型配置".to_string();
         if attempt + 1 < MODEL_REQUEST_MAX_ATTEMPTS {
-            last_error = Some(message);
+            last_error = Some(ModelError::EmptyResponse);
             sleep_with_backoff(attempt).await;
             continue;
         }
-        return Err(CommandError::Settings(message));
+        return Err(ModelError::EmptyResponse.into());
     }
 
-    Err(CommandError::Settings(last_error.unwrap_or_else(|| {
-        "OCR 视觉模型调用失败，请稍后重试".to_string()
-    })))
+    Err(last_error.unwrap_or(ModelError::EmptyResponse).into())
 }
 
 async fn test_openai_compatible_model(
@@ -6387,6 +13432,7 @@ async fn test_openai_compatible_model(
     api_key: &str,
     model: &str,
     timeout_ms: u64,
+    extra_headers: &[ExtraHeader],
 ) -> Result<String, CommandError> {
     let request_body = json!({
         "model": model,
@@ -6404,6 +13450,7 @@ async fn test_openai_compatible_model(
         .timeout(Duration::from_millis(timeout_ms))
         .header(AUTHORIZATION, format!("Bearer {}", api_key.trim()))
         .header(CONTENT_TYPE, "application/json")
+        .headers(extra_header_map(extra_headers))
         .json(&request_body)
         .send()
         .await
@@ -6969,6 +14016,11 @@ fn apply_runtime_settings_effects<R: Runtime>(
         }
     }
 
+    if previous_settings.network != updated_settings.network {
+        app.state::<HttpClientState>()
+            .rebuild(&updated_settings.network);
+    }
+
     if previous_settings.ocr.result_window_always_on_top
         != updated_settings.ocr.result_window_always_on_top
     {
@@ -7145,6 +14197,17 @@ fn update_settings_internal(
     };
     let settings_changed = previous_settings != updated_settings;
 
+    if let Err(error) = validate_agent_settings(&updated_settings.agents, &previous_settings.agents)
+        .and_then(|()| {
+            validate_custom_agent_prompts(&updated_settings.agents, &previous_settings.agents)
+        })
+    {
+        if let Ok(mut settings) = with_settings_lock(settings_state) {
+            *settings = previous_settings;
+        }
+        return Err(error);
+    }
+
     if let Err(error) =
         sync_shortcuts_after_settings_update(app, &previous_settings, &updated_settings)
     {
@@ -7292,7 +14355,7 @@ fn was_selection_recently_dismissed(
 }
 
 fn is_task_replaced_error(error: &CommandError) -> bool {
-    matches!(error, CommandError::Settings(message) if message == TASK_REPLACED_ERROR)
+    matches!(error, CommandError::Model(ModelError::Canceled))
 }
 
 fn begin_selection_result_task<R: Runtime>(app: &AppHandle<R>) -> u64 {
@@ -7335,22 +14398,70 @@ fn is_ocr_result_task_active<R: Runtime>(app: &AppHandle<R>, nonce: u64) -> bool
         .unwrap_or(true)
 }
 
-fn publish_selection_detected<R: Runtime>(
-    app: &AppHandle<R>,
+fn publish_selection_detected(
+    app: &AppHandle,
     text: String,
     mode: SelectionTriggerMode,
+    source_hwnd: isize,
 ) -> Result<(), CommandError> {
     if text.trim().is_empty() {
         return Ok(());
     }
+    if let Some(runtime) = app.try_state::<SelectionRuntimeState>() {
+        if let Ok(mut source) = runtime.selection_source.lock() {
+            *source = capture_selection_source_window(source_hwnd, &text);
+        }
+    }
+
+    let settings = app
+        .state::<AppSettingsState>()
+        .data
+        .lock()
+        .map_err(|_| CommandError::Lock)?
+        .clone();
+    let rule = matching_app_rule(source_hwnd, &settings.selection_assistant.app_rules);
+    let translate_to = rule
+        .as_ref()
+        .map(|rule| rule.translate_to.clone())
+        .filter(|value| !value.is_empty());
+
+    if let Some((rule, (action, custom_agent_id))) = rule.as_ref().and_then(|rule| {
+        selection_action_for_bar_key(&rule.auto_run)
+            .filter(|(action, _)| app_rule_auto_run_applies(&settings, rule, *action, &text))
+            .map(|action| (rule, action))
+    }) {
+        let translate_from = if rule.translate_from.is_empty() {
+            "auto".to_string()
+        } else {
+            rule.translate_from.clone()
+        };
+        let payload = RunSelectionActionPayload {
+            action,
+            text,
+            custom_agent_id,
+            translate_from: Some(translate_from),
+            translate_to,
+            regenerate: false,
+            bilingual_mode: None,
+            replace_selection: false,
+        };
+        hide_selection_bar_window(app);
+        let app_handle = app.clone();
+        tauri::async_runtime::spawn(async move {
+            let settings_state = app_handle.state::<AppSettingsState>();
+            if let Err(error) =
+                run_selection_action(app_handle.clone(), payload, settings_state).await
+            {
+                if !is_task_replaced_error(&error) {
+                    emit_selection_error(&app_handle, &error.to_string());
+                }
+            }
+        });
+        return Ok(());
+    }
 
     let pointer = current_pointer_position().unwrap_or(PhysicalPosition::new(40, 40));
-    let payload = SelectionDetectedPayload {
-        text,
-        x: pointer.x,
-        y: pointer.y,
-        mode,
-    };
+    let payload = selection_detected_payload(&settings, text, pointer, mode, rule.as_ref());
     emit_selection_detected(app, payload.clone());
     show_selection_bar_window(app, &payload)?;
     if let Some(runtime) = app.try_state::<SelectionRuntimeState>() {
@@ -7742,6 +14853,7 @@ fn start_selection_detector_thread(app: AppHandle) {
                             &app,
                             selected,
                             SelectionTriggerMode::AutoDetect,
+                            hwnd,
                         ) {
                             emit_selection_error(&app, &error.to_string());
                         }
@@ -7823,6 +14935,7 @@ fn start_selection_detector_thread(app: AppHandle) {
                             &app,
                             text,
                             SelectionTriggerMode::CopyTrigger,
+                            active_hwnd,
                         ) {
                             emit_selection_error(&app, &error.to_string());
                         }
@@ -7851,17 +14964,22 @@ fn show_settings_window_cmd(app: AppHandle) {
 fn open_selection_bar(
     app: AppHandle,
     payload: SelectionBarOpenPayload,
+    settings_state: State<'_, AppSettingsState>,
 ) -> Result<(), CommandError> {
     let normalized_text = payload.text.trim().to_string();
     if normalized_text.is_empty() {
         return Ok(());
     }
 
-    let detected = SelectionDetectedPayload {
-        text: normalized_text,
-        x: payload.x,
-        y: payload.y,
-        mode: payload.mode,
+    let detected = {
+        let settings = settings_state.data.lock().map_err(|_| CommandError::Lock)?;
+        selection_detected_payload(
+            &settings,
+            normalized_text,
+            PhysicalPosition::new(payload.x, payload.y),
+            payload.mode,
+            None,
+        )
     };
     emit_selection_detected(&app, detected.clone());
     show_selection_bar_window(&app, &detected)
@@ -7955,6 +15073,19 @@ fn minimize_selection_result_window(app: AppHandle) -> Result<(), CommandError>
     Ok(())
 }
 
+#[tauri::command]
+async fn replace_selection_with_result(
+    app: AppHandle,
+    payload: ReplaceSelectionPayload,
+) -> Result<(), CommandError> {
+    let source = selection_source_for_text(&app, &payload.source_text);
+    let replaced = replace_selection_in_source(&app, &source, &payload.output_text).await;
+    if replaced.is_err() {
+        let _ = show_selection_result_window(&app);
+    }
+    replaced
+}
+
 #[tauri::command]
 fn close_selection_result_window(app: AppHandle) -> Result<(), CommandError> {
     let Some(window) = app.get_webview_window(SELECTION_RESULT_WINDOW_LABEL) else {
@@ -7974,9 +15105,9 @@ async fn run_selection_action(
     app: AppHandle,
     payload: RunSelectionActionPayload,
     settings_state: State<'_, AppSettingsState>,
-    http_client_state: State<'_, HttpClientState>,
 ) -> Result<SelectionResultPayload, CommandError> {
     let task_nonce = begin_selection_result_task(&app);
+    let started_at_ms = now_epoch_millis();
     let source_text = payload.text.trim().to_string();
     if source_text.is_empty() {
         return Err(CommandError::Settings("划词内容为空".to_string()));
@@ -7990,8 +15121,27 @@ async fn run_selection_action(
 
     let mut custom_agent_name: Option<String> = None;
     let mut custom_agent_icon: Option<String> = None;
+    let mut custom_agent_id: Option<String> = None;
     let mut translate_from: Option<String> = None;
     let mut translate_to: Option<String> = None;
+    let mut custom_params: Option<GenerationParams> = None;
+    let mut bilingual_mode = BilingualMode::Off;
+    let mut bilingual_sources: Option<Vec<BilingualSource>> = None;
+    let mut dictionary_lookup = false;
+    let mut agent_run = AgentRun::default();
+    let mut source = SelectionSourceWindow::default();
+    // Captured up front: a newer selection would replace the recorded source window.
+    let replace_target = payload
+        .replace_selection
+        .then(|| selection_source_for_text(&app, &source_text));
+    if replace_target
+        .as_ref()
+        .is_some_and(|target| target.hwnd == 0)
+    {
+        return Err(CommandError::Settings(
+            "未找到划词来源窗口，无法替换".to_string(),
+        ));
+    }
 
     let (action_name, system_prompt, user_prompt) = match payload.action {
         SelectionActionKind::Summarize => {
@@ -8000,7 +15150,7 @@ async fn run_selection_action(
                 &source_text,
                 None,
                 None,
-                &snapshot.language,
+                &snapshot,
             );
             ("summary".to_string(), prompts.0, prompts.1)
         }
@@ -8010,7 +15160,7 @@ async fn run_selection_action(
                 &source_text,
                 None,
                 None,
-                &snapshot.language,
+                &snapshot,
             );
             ("polish".to_string(), prompts.0, prompts.1)
         }
@@ -8020,7 +15170,7 @@ async fn run_selection_action(
                 &source_text,
                 None,
                 None,
-                &snapshot.language,
+                &snapshot,
             );
             ("explain".to_string(), prompts.0, prompts.1)
         }
@@ -8031,27 +15181,35 @@ async fn run_selection_action(
                     .clone()
                     .unwrap_or_else(|| "auto".to_string()),
             );
-            let default_translate_to =
-                if snapshot.selection_assistant.default_translate_to.is_empty() {
-                    default_translate_target_for_language(&snapshot.language).to_string()
-                } else {
-                    snapshot.selection_assistant.default_translate_to.clone()
-                };
-            let requested_translate_to = payload
-                .translate_to
-                .clone()
-                .unwrap_or_else(|| default_translate_to.clone());
-            translate_to = Some(normalize_translate_language(
-                &requested_translate_to,
-                &default_translate_to,
-            ));
-            let prompts = choose_builtin_prompt(
-                SelectionActionKind::Translate,
+            translate_to = Some(resolve_translate_target(
+                &snapshot,
+                payload.translate_to.as_deref(),
                 &source_text,
-                translate_from.as_deref(),
-                translate_to.as_deref(),
-                &snapshot.language,
-            );
+            ));
+            bilingual_mode = payload
+                .bilingual_mode
+                .unwrap_or(snapshot.selection_assistant.bilingual_mode);
+            dictionary_lookup = is_dictionary_lookup(&snapshot.selection_assistant, &source_text);
+            let prompts = if dictionary_lookup {
+                dictionary_prompt(&source_text, translate_to.as_deref())
+            } else if bilingual_mode == BilingualMode::Off {
+                translate_prompt(
+                    &snapshot,
+                    &source_text,
+                    translate_from.as_deref(),
+                    translate_to.as_deref(),
+                )
+            } else {
+                let sources = split_bilingual_sources(&source_text, bilingual_mode);
+                let prompts = bilingual_translate_prompt(
+                    &snapshot,
+                    &sources,
+                    translate_from.as_deref(),
+                    translate_to.as_deref(),
+                );
+                bilingual_sources = Some(sources);
+                prompts
+            };
             ("translate".to_string(), prompts.0, prompts.1)
         }
         SelectionActionKind::Custom => {
@@ -8066,14 +15224,20 @@ async fn run_selection_action(
                 .find(|item| item.id == custom_id)
                 .cloned()
                 .ok_or_else(|| CommandError::Settings("未找到可用的自定义 Agent".to_string()))?;
+            custom_agent_id = Some(agent.id.clone());
             custom_agent_name = Some(agent.name.clone());
             custom_agent_icon = Some(agent.icon.clone());
-            let resolved_prompt = agent.prompt.replace("{text}", &source_text);
-            (
-                "custom".to_string(),
-                custom_agent_system_prompt().to_string(),
-                resolved_prompt,
-            )
+            custom_params = Some(agent.params.clone());
+            source = selection_source_for_text(&app, &source_text);
+            let (system_prompt, user_prompt) = custom_agent_prompts(
+                &agent,
+                &snapshot,
+                &source_text,
+                translate_to.as_deref(),
+                &source,
+            );
+            agent_run = AgentRun::for_agent(&agent)?;
+            ("custom".to_string(), system_prompt, user_prompt)
         }
     };
 
@@ -8083,64 +15247,239 @@ async fn run_selection_action(
         action: action_name.clone(),
         source_text: source_text.clone(),
         output_text: String::new(),
+        reasoning_text: String::new(),
         translate_from: translate_from.clone(),
         translate_to: translate_to.clone(),
         custom_agent_name: custom_agent_name.clone(),
+        custom_agent_id: custom_agent_id.clone(),
         custom_agent_icon: custom_agent_icon.clone(),
         is_streaming: true,
+        from_cache: false,
+        usage: None,
+        provider: None,
+        chunk_progress: None,
+        segments: Vec::new(),
+        dictionary: None,
+        follow_ups: Vec::new(),
+        glossary_warnings: Vec::new(),
+        workflow_steps: Vec::new(),
+        structured_output: None,
+        tool_calls: Vec::new(),
+        diff_hunks: Vec::new(),
         error_message: None,
+        error_detail: None,
     };
 
     hide_selection_bar_window(&app);
-    show_selection_result_window(&app)?;
+    if replace_target.is_none() {
+        show_selection_result_window(&app)?;
+    }
     emit_selection_result(&app, stream_payload.clone());
 
     let mut streamed_output = String::new();
+    let mut streamed_reasoning = String::new();
     let mut last_emit_ms = 0u64;
     let stream_base = stream_payload.clone();
     let app_for_stream = app.clone();
-    let llm_stream_enabled = true;
-
-    let llm_result = call_llm_for_action(
-        &http_client_state.client,
-        &snapshot.llm,
-        &system_prompt,
-        &user_prompt,
-        llm_stream_enabled,
-        |delta| {
-            if !is_selection_result_task_active(&app_for_stream, task_nonce) {
-                return false;
-            }
-            streamed_output.push_str(delta);
-
-            let now = now_epoch_millis();
-            if now.saturating_sub(last_emit_ms) < STREAM_EMIT_THROTTLE_MS && !delta.contains('\n') {
-                return true;
-            }
-            last_emit_ms = now;
 
-            let mut update = stream_base.clone();
-            update.output_text = streamed_output.clone();
-            emit_selection_result(&app_for_stream, update);
-            true
-        },
-    )
-    .await;
+    let generation_params = custom_params.unwrap_or_else(|| {
+        snapshot
+            .agents
+            .builtin
+            .for_action(&action_name)
+            .map(|config| config.params.clone())
+            .unwrap_or_default()
+    });
+    let mut messages = single_turn_messages(&system_prompt, &user_prompt);
+    let input_chunks = match payload.action {
+        SelectionActionKind::Summarize | SelectionActionKind::Translate => {
+            chunk_long_input(&source_text, snapshot.llm.chunk_tokens)
+        }
+        _ => None,
+    };
+    let mut segments: Vec<BilingualSegmentPayload> = Vec::new();
+    let llm_result = if let Some(chunks) = input_chunks {
+        messages = chunked_exchange_messages(&system_prompt, &chunks);
+        let run = ChunkedSelectionRun {
+            app: &app,
+            settings: &snapshot,
+            params: &generation_params,
+            regenerate: payload.regenerate,
+            stream_base: &stream_base,
+            task_nonce,
+        };
+        if matches!(payload.action, SelectionActionKind::Translate) {
+            bilingual_sources = None;
+            run.translate(
+                &chunks,
+                translate_from.as_deref(),
+                translate_to.as_deref(),
+                bilingual_mode,
+                &mut streamed_output,
+                &mut segments,
+            )
+            .await
+        } else {
+            run.summarize(chunks, &mut streamed_output).await
+        }
+    } else if let Some(result) = agent_run
+        .run_special(
+            &app,
+            &snapshot,
+            &source_text,
+            translate_to.as_deref(),
+            &source,
+            payload.regenerate,
+            &generation_params,
+            &mut messages,
+            &mut streamed_output,
+            &mut streamed_reasoning,
+            || is_selection_result_task_active(&app_for_stream, task_nonce),
+            |steps| {
+                if !is_selection_result_task_active(&app_for_stream, task_nonce) {
+                    return false;
+                }
+                let mut update = stream_base.clone();
+                update.output_text = steps
+                    .last()
+                    .map(|step| step.output.clone())
+                    .unwrap_or_default();
+                update.workflow_steps = steps.to_vec();
+                emit_selection_result(&app_for_stream, update);
+                true
+            },
+        )
+        .await
+    {
+        result
+    } else {
+        run_agent_llm_action(
+            &app,
+            &snapshot,
+            &action_name,
+            &messages,
+            &generation_params,
+            payload.regenerate,
+            &agent_run.tools,
+            &mut agent_run.tool_trace,
+            |delta| {
+                if !is_selection_result_task_active(&app_for_stream, task_nonce) {
+                    return false;
+                }
+                let chunk = match delta {
+                    StreamDelta::Answer(text) => {
+                        streamed_output.push_str(text);
+                        text
+                    }
+                    StreamDelta::Reasoning(text) => {
+                        streamed_reasoning.push_str(text);
+                        text
+                    }
+                };
+
+                let now = now_epoch_millis();
+                if now.saturating_sub(last_emit_ms) < STREAM_EMIT_THROTTLE_MS
+                    && !chunk.contains('\n')
+                {
+                    return true;
+                }
+                last_emit_ms = now;
+
+                let mut update = stream_base.clone();
+                match &bilingual_sources {
+                    Some(sources) => {
+                        (update.segments, update.output_text) =
+                            parse_bilingual_output(&streamed_output, sources);
+                    }
+                    // Dictionary JSON is only shown once it is complete.
+                    None if dictionary_lookup => {}
+                    None => update.output_text = streamed_output.clone(),
+                }
+                update.reasoning_text = streamed_reasoning.clone();
+                emit_selection_result(&app_for_stream, update);
+                true
+            },
+        )
+        .await
+    };
+    if let Some(sources) = &bilingual_sources {
+        (segments, streamed_output) = parse_bilingual_output(&streamed_output, sources);
+    }
 
     if !is_selection_result_task_active(&app, task_nonce) {
         let mut canceled = stream_base.clone();
         canceled.is_streaming = false;
         canceled.output_text = streamed_output;
+        canceled.segments = segments;
+        canceled.reasoning_text = streamed_reasoning;
         canceled.error_message = None;
         return Ok(canceled);
     }
 
     match llm_result {
-        Ok(output_text) => {
+        Ok(outcome) => {
             let mut result = stream_base;
-            result.output_text = output_text;
+            match &bilingual_sources {
+                Some(sources) => {
+                    (result.segments, result.output_text) =
+                        parse_bilingual_output(&outcome.text, sources);
+                }
+                None => {
+                    result.output_text = outcome.text;
+                    result.segments = segments;
+                }
+            }
+            if dictionary_lookup {
+                if let Some(entry) = parse_dictionary_entry(&result.output_text, &source_text) {
+                    result.output_text = dictionary_entry_markdown(&entry);
+                    result.dictionary = Some(entry);
+                }
+            }
+            (
+                result.workflow_steps,
+                result.tool_calls,
+                result.structured_output,
+            ) = agent_run.take_outputs();
+            result.reasoning_text = outcome.reasoning;
             result.is_streaming = false;
+            result.from_cache = outcome.from_cache;
+            result.usage = outcome.usage;
+            result.provider = outcome.provider;
+            if matches!(payload.action, SelectionActionKind::Polish) {
+                result.diff_hunks = polish_diff_hunks(&source_text, &result.output_text);
+            }
+            if result.action == "translate" && !dictionary_lookup {
+                result.glossary_warnings = glossary_warnings(
+                    &snapshot.glossary,
+                    &source_text,
+                    &result.output_text,
+                    result.translate_from.as_deref(),
+                    result.translate_to.as_deref(),
+                );
+            }
+            let mut log_entry = ActionLogEntry::from_selection(
+                &result,
+                &messages,
+                &snapshot.llm.model,
+                started_at_ms,
+            );
+            log_entry.custom_agent_id = payload.custom_agent_id.clone();
+            record_action_log(&app, log_entry);
+            remember_conversation(
+                &app,
+                ConversationOrigin::Selection(Box::new(result.clone())),
+                messages,
+            );
             emit_selection_result(&app, result.clone());
+            if let Some(target) = &replace_target {
+                if let Err(error) =
+                    replace_selection_in_source(&app, target, &result.output_text).await
+                {
+                    // Fall back to showing the result so the output is not lost.
+                    let _ = show_selection_result_window(&app);
+                    return Err(error);
+                }
+            }
             Ok(result)
         }
         Err(error) => {
@@ -8148,13 +15487,31 @@ async fn run_selection_action(
                 let mut canceled = stream_base;
                 canceled.is_streaming = false;
                 canceled.output_text = streamed_output;
+                canceled.segments = segments;
+                canceled.reasoning_text = streamed_reasoning;
                 canceled.error_message = None;
                 return Ok(canceled);
             }
             stream_payload.is_streaming = false;
-            stream_payload.error_message = Some(error.to_string());
+            stream_payload.error_message = Some(error.localized_message(&snapshot.language));
+            stream_payload.error_detail = error.model_error_payload(&snapshot.language);
             stream_payload.output_text = streamed_output;
+            stream_payload.segments = segments;
+            (stream_payload.workflow_steps, stream_payload.tool_calls, _) =
+                agent_run.take_outputs();
+            stream_payload.reasoning_text = streamed_reasoning;
+            let mut log_entry = ActionLogEntry::from_selection(
+                &stream_payload,
+                &messages,
+                &snapshot.llm.model,
+                started_at_ms,
+            );
+            log_entry.custom_agent_id = payload.custom_agent_id.clone();
+            record_action_log(&app, log_entry);
             emit_selection_result(&app, stream_payload);
+            if replace_target.is_some() {
+                let _ = show_selection_result_window(&app);
+            }
             Err(error)
         }
     }
@@ -8165,9 +15522,9 @@ async fn run_ocr_action_cmd(
     app: AppHandle,
     payload: RunOcrActionPayload,
     settings_state: State<'_, AppSettingsState>,
-    http_client_state: State<'_, HttpClientState>,
 ) -> Result<OcrResultPayload, CommandError> {
     let task_nonce = begin_ocr_result_task(&app);
+    let started_at_ms = now_epoch_millis();
     let ocr_text = payload.ocr_text.trim().to_string();
     if ocr_text.is_empty() {
         return Err(CommandError::Settings("OCR 文本为空".to_string()));
@@ -8181,8 +15538,11 @@ async fn run_ocr_action_cmd(
 
     let mut custom_agent_name: Option<String> = None;
     let mut custom_agent_icon: Option<String> = None;
+    let mut custom_agent_id: Option<String> = None;
     let mut translate_from: Option<String> = None;
     let mut translate_to: Option<String> = None;
+    let mut custom_params: Option<GenerationParams> = None;
+    let mut agent_run = AgentRun::default();
 
     let (action_name, system_prompt, user_prompt) = match payload.action {
         SelectionActionKind::Summarize => {
@@ -8191,7 +15551,7 @@ async fn run_ocr_action_cmd(
                 &ocr_text,
                 None,
                 None,
-                &snapshot.language,
+                &snapshot,
             );
             ("summary".to_string(), prompts.0, prompts.1)
         }
@@ -8201,7 +15561,7 @@ async fn run_ocr_action_cmd(
                 &ocr_text,
                 None,
                 None,
-                &snapshot.language,
+                &snapshot,
             );
             ("polish".to_string(), prompts.0, prompts.1)
         }
@@ -8211,7 +15571,7 @@ async fn run_ocr_action_cmd(
                 &ocr_text,
                 None,
                 None,
-                &snapshot.language,
+                &snapshot,
             );
             ("explain".to_string(), prompts.0, prompts.1)
         }
@@ -8222,26 +15582,16 @@ async fn run_ocr_action_cmd(
                     .clone()
                     .unwrap_or_else(|| "auto".to_string()),
             );
-            let default_translate_to =
-                if snapshot.selection_assistant.default_translate_to.is_empty() {
-                    default_translate_target_for_language(&snapshot.language).to_string()
-                } else {
-                    snapshot.selection_assistant.default_translate_to.clone()
-                };
-            let requested_translate_to = payload
-                .translate_to
-                .clone()
-                .unwrap_or_else(|| default_translate_to.clone());
-            translate_to = Some(normalize_translate_language(
-                &requested_translate_to,
-                &default_translate_to,
+            translate_to = Some(resolve_translate_target(
+                &snapshot,
+                payload.translate_to.as_deref(),
+                &ocr_text,
             ));
-            let prompts = choose_builtin_prompt(
-                SelectionActionKind::Translate,
+            let prompts = translate_prompt(
+                &snapshot,
                 &ocr_text,
                 translate_from.as_deref(),
                 translate_to.as_deref(),
-                &snapshot.language,
             );
             ("translate".to_string(), prompts.0, prompts.1)
         }
@@ -8268,14 +15618,19 @@ async fn run_ocr_action_cmd(
                 .find(|item| item.id == custom_id)
                 .cloned()
                 .ok_or_else(|| CommandError::Settings("未找到可用的自定义 Agent".to_string()))?;
+            custom_agent_id = Some(agent.id.clone());
             custom_agent_name = Some(agent.name.clone());
             custom_agent_icon = Some(agent.icon.clone());
-            let resolved_prompt = agent.prompt.replace("{text}", &ocr_text);
-            (
-                "custom".to_string(),
-                custom_agent_system_prompt().to_string(),
-                resolved_prompt,
-            )
+            custom_params = Some(agent.params.clone());
+            let (system_prompt, user_prompt) = custom_agent_prompts(
+                &agent,
+                &snapshot,
+                &ocr_text,
+                translate_to.as_deref(),
+                &SelectionSourceWindow::default(),
+            );
+            agent_run = AgentRun::for_agent(&agent)?;
+            ("custom".to_string(), system_prompt, user_prompt)
         }
     };
 
@@ -8285,60 +15640,160 @@ async fn run_ocr_action_cmd(
         action: action_name,
         ocr_text: ocr_text.clone(),
         output_text: String::new(),
+        reasoning_text: String::new(),
         translate_from: translate_from.clone(),
         translate_to: translate_to.clone(),
         custom_agent_name: custom_agent_name.clone(),
+        custom_agent_id: custom_agent_id.clone(),
         custom_agent_icon: custom_agent_icon.clone(),
         is_streaming: true,
+        from_cache: false,
+        usage: None,
+        provider: None,
+        follow_ups: Vec::new(),
+        glossary_warnings: Vec::new(),
+        workflow_steps: Vec::new(),
+        structured_output: None,
+        tool_calls: Vec::new(),
         error_message: None,
+        error_detail: None,
     };
 
     show_ocr_result_window(&app)?;
     emit_ocr_result(&app, stream_payload.clone());
 
     let mut streamed_output = String::new();
+    let mut streamed_reasoning = String::new();
     let mut last_emit_ms = 0u64;
     let stream_base = stream_payload.clone();
     let app_for_stream = app.clone();
 
-    let llm_result = call_llm_for_action(
-        &http_client_state.client,
-        &snapshot.llm,
-        &system_prompt,
-        &user_prompt,
-        true,
-        |delta| {
-            if !is_ocr_result_task_active(&app_for_stream, task_nonce) {
-                return false;
-            }
-            streamed_output.push_str(delta);
+    let generation_params = custom_params.unwrap_or_else(|| {
+        snapshot
+            .agents
+            .builtin
+            .for_action(&stream_payload.action)
+            .map(|config| config.params.clone())
+            .unwrap_or_default()
+    });
+    let mut messages = single_turn_messages(&system_prompt, &user_prompt);
+    let llm_result = if let Some(result) = agent_run
+        .run_special(
+            &app,
+            &snapshot,
+            &stream_base.ocr_text,
+            stream_base.translate_to.as_deref(),
+            &SelectionSourceWindow::default(),
+            payload.regenerate,
+            &generation_params,
+            &mut messages,
+            &mut streamed_output,
+            &mut streamed_reasoning,
+            || is_ocr_result_task_active(&app_for_stream, task_nonce),
+            |steps| {
+                if !is_ocr_result_task_active(&app_for_stream, task_nonce) {
+                    return false;
+                }
+                let mut update = stream_base.clone();
+                update.output_text = steps
+                    .last()
+                    .map(|step| step.output.clone())
+                    .unwrap_or_default();
+                update.workflow_steps = steps.to_vec();
+                emit_ocr_result(&app_for_stream, update);
+                true
+            },
+        )
+        .await
+    {
+        result
+    } else {
+        run_agent_llm_action(
+            &app,
+            &snapshot,
+            &stream_base.action,
+            &messages,
+            &generation_params,
+            payload.regenerate,
+            &agent_run.tools,
+            &mut agent_run.tool_trace,
+            |delta| {
+                if !is_ocr_result_task_active(&app_for_stream, task_nonce) {
+                    return false;
+                }
+                let chunk = match delta {
+                    StreamDelta::Answer(text) => {
+                        streamed_output.push_str(text);
+                        text
+                    }
+                    StreamDelta::Reasoning(text) => {
+                        streamed_reasoning.push_str(text);
+                        text
+                    }
+                };
 
-            let now = now_epoch_millis();
-            if now.saturating_sub(last_emit_ms) < STREAM_EMIT_THROTTLE_MS && !delta.contains('\n') {
-                return true;
-            }
-            last_emit_ms = now;
+                let now = now_epoch_millis();
+                if now.saturating_sub(last_emit_ms) < STREAM_EMIT_THROTTLE_MS
+                    && !chunk.contains('\n')
+                {
+                    return true;
+                }
+                last_emit_ms = now;
 
-            let mut update = stream_base.clone();
-            update.output_text = streamed_output.clone();
-            emit_ocr_result(&app_for_stream, update);
-            true
-        },
-    )
-    .await;
+                let mut update = stream_base.clone();
+                update.output_text = streamed_output.clone();
+                update.reasoning_text = streamed_reasoning.clone();
+                emit_ocr_result(&app_for_stream, update);
+                true
+            },
+        )
+        .await
+    };
 
     if !is_ocr_result_task_active(&app, task_nonce) {
         let mut canceled = stream_base.clone();
         canceled.output_text = streamed_output;
+        canceled.reasoning_text = streamed_reasoning;
         canceled.is_streaming = false;
         canceled.error_message = None;
         return Ok(canceled);
     }
 
     match llm_result {
-        Ok(output_text) => {
-            stream_payload.output_text = output_text;
+        Ok(outcome) => {
+            stream_payload.output_text = outcome.text;
+            (
+                stream_payload.workflow_steps,
+                stream_payload.tool_calls,
+                stream_payload.structured_output,
+            ) = agent_run.take_outputs();
+            stream_payload.reasoning_text = outcome.reasoning;
             stream_payload.is_streaming = false;
+            stream_payload.from_cache = outcome.from_cache;
+            stream_payload.usage = outcome.usage;
+            stream_payload.provider = outcome.provider;
+            if stream_payload.action == "translate" {
+                stream_payload.glossary_warnings = glossary_warnings(
+                    &snapshot.glossary,
+                    &stream_payload.ocr_text,
+                    &stream_payload.output_text,
+                    stream_payload.translate_from.as_deref(),
+                    stream_payload.translate_to.as_deref(),
+                );
+            }
+            let mut log_entry = ActionLogEntry::from_ocr(
+                &stream_payload,
+                &messages,
+                &snapshot.llm.model,
+                started_at_ms,
+            );
+            log_entry.custom_agent_id = payload.custom_agent_id.clone();
+            record_action_log(&app, log_entry);
+            remember_conversation(
+                &app,
+                ConversationOrigin::Ocr(Box::new(stream_payload.clone())),
+                messages,
+            );
             emit_ocr_result(&app, stream_payload.clone());
             Ok(stream_payload)
         }
@@ -8346,16 +15801,29 @@ async fn run_ocr_action_cmd(
             if is_task_replaced_error(&error) {
                 let mut canceled = stream_base;
                 canceled.output_text = streamed_output;
+                canceled.reasoning_text = streamed_reasoning;
                 canceled.is_streaming = false;
                 canceled.error_message = None;
                 return Ok(canceled);
             }
             stream_payload.output_text = streamed_output;
+            (stream_payload.workflow_steps, stream_payload.tool_calls, _) =
+                agent_run.take_outputs();
+            stream_payload.reasoning_text = streamed_reasoning;
             stream_payload.is_streaming = false;
             if stream_payload.output_text.trim().is_empty() {
-                stream_payload.error_message = Some(error.to_string());
+                stream_payload.error_message = Some(error.localized_message(&snapshot.language));
+                stream_payload.error_detail = error.model_error_payload(&snapshot.language);
+                let mut log_entry = ActionLogEntry::from_ocr(
+                    &stream_payload,
+                    &messages,
+                    &snapshot.llm.model,
+                    started_at_ms,
+                );
+                log_entry.custom_agent_id = payload.custom_agent_id.clone();
+                record_action_log(&app, log_entry);
                 emit_ocr_result(&app, stream_payload.clone());
-                emit_ocr_error(&app, &error.to_string());
+                emit_ocr_error(&app, &error.localized_message(&snapshot.language));
                 Err(error)
             } else {
                 stream_payload.error_message = None;
@@ -8370,6 +15838,260 @@ async fn run_ocr_action_cmd(
     }
 }
 
+#[tauri::command]
+async fn continue_selection_conversation(
+    app: AppHandle,
+    payload: ContinueConversationPayload,
+    settings_state: State<'_, AppSettingsState>,
+) -> Result<SelectionResultPayload, CommandError> {
+    let task_nonce = begin_selection_result_task(&app);
+    let question = normalize_follow_up_question(&payload.message)?;
+    let snapshot = settings_state
+        .data
+        .lock()
+        .map_err(|_| CommandError::Lock)?
+        .clone();
+
+    let (origin, mut follow_ups, messages) =
+        begin_conversation_turn(&app, &payload.request_id, &question, &snapshot.llm)?;
+    let ConversationOrigin::Selection(base) = origin else {
+        return Err(CommandError::Settings(
+            "该结果不属于划词结果窗口".to_string(),
+        ));
+    };
+    follow_ups.push(FollowUpTurnPayload {
+        question: question.clone(),
+        answer: String::new(),
+        reasoning: String::new(),
+    });
+
+    let mut stream_payload = *base;
+    stream_payload.follow_ups = follow_ups;
+    stream_payload.is_streaming = true;
+    stream_payload.from_cache = false;
+    stream_payload.usage = None;
+    stream_payload.error_message = None;
+    stream_payload.error_detail = None;
+
+    show_selection_result_window(&app)?;
+    emit_selection_result(&app, stream_payload.clone());
+
+    let mut streamed_output = String::new();
+    let mut streamed_reasoning = String::new();
+    let mut last_emit_ms = 0u64;
+    let stream_base = stream_payload.clone();
+    let app_for_stream = app.clone();
+
+    let llm_result = run_llm_action(
+        &app,
+        &snapshot,
+        "follow_up",
+        &messages,
+        &GenerationParams::default(),
+        payload.regenerate,
+        |delta| {
+            if !is_selection_result_task_active(&app_for_stream, task_nonce) {
+                return false;
+            }
+            let chunk = match delta {
+                StreamDelta::Answer(text) => {
+                    streamed_output.push_str(text);
+                    text
+                }
+                StreamDelta::Reasoning(text) => {
+                    streamed_reasoning.push_str(text);
+                    text
+                }
+            };
+
+            let now = now_epoch_millis();
+            if now.saturating_sub(last_emit_ms) < STREAM_EMIT_THROTTLE_MS && !chunk.contains('\n') {
+                return true;
+            }
+            last_emit_ms = now;
+
+            let mut update = stream_base.clone();
+            if let Some(turn) = update.follow_ups.last_mut() {
+                turn.answer = streamed_output.clone();
+                turn.reasoning = streamed_reasoning.clone();
+            }
+            emit_selection_result(&app_for_stream, update);
+            true
+        },
+    )
+    .await;
+
+    let finish = |mut result: SelectionResultPayload, answer: String, reasoning: String| {
+        if let Some(turn) = result.follow_ups.last_mut() {
+            turn.answer = answer;
+            turn.reasoning = reasoning;
+        }
+        result.is_streaming = false;
+        result
+    };
+
+    if !is_selection_result_task_active(&app, task_nonce) {
+        return Ok(finish(stream_base, streamed_output, streamed_reasoning));
+    }
+
+    match llm_result {
+        Ok(outcome) => {
+            complete_conversation_turn(
+                &app,
+                &payload.request_id,
+                &FollowUpTurnPayload {
+                    question,
+                    answer: outcome.text.clone(),
+                    reasoning: outcome.reasoning.clone(),
+                },
+            );
+            let mut result = finish(stream_base, outcome.text, outcome.reasoning);
+            result.from_cache = outcome.from_cache;
+            result.usage = outcome.usage;
+            result.provider = outcome.provider;
+            emit_selection_result(&app, result.clone());
+            Ok(result)
+        }
+        Err(error) => {
+            if is_task_replaced_error(&error) {
+                return Ok(finish(stream_base, streamed_output, streamed_reasoning));
+            }
+            let mut failed = finish(stream_payload, streamed_output, streamed_reasoning);
+            failed.error_message = Some(error.localized_message(&snapshot.language));
+            failed.error_detail = error.model_error_payload(&snapshot.language);
+            emit_selection_result(&app, failed);
+            Err(error)
+        }
+    }
+}
+
+#[tauri::command]
+async fn continue_ocr_conversation_cmd(
+    app: AppHandle,
+    payload: ContinueConversationPayload,
+    settings_state: State<'_, AppSettingsState>,
+) -> Result<OcrResultPayload, CommandError> {
+    let task_nonce = begin_ocr_result_task(&app);
+    let question = normalize_follow_up_question(&payload.message)?;
+    let snapshot = settings_state
+        .data
+        .lock()
+        .map_err(|_| CommandError::Lock)?
+        .clone();
+
+    let (origin, mut follow_ups, messages) =
+        begin_conversation_turn(&app, &payload.request_id, &question, &snapshot.llm)?;
+    let ConversationOrigin::Ocr(base) = origin else {
+        return Err(CommandError::Settings(
+            "该结果不属于 OCR 结果窗口".to_string(),
+        ));
+    };
+    follow_ups.push(FollowUpTurnPayload {
+        question: question.clone(),
+        answer: String::new(),
+        reasoning: String::new(),
+    });
+
+    let mut stream_payload = *base;
+    stream_payload.follow_ups = follow_ups;
+    stream_payload.is_streaming = true;
+    stream_payload.from_cache = false;
+    stream_payload.usage = None;
+    stream_payload.error_message = None;
+    stream_payload.error_detail = None;
+
+    show_ocr_result_window(&app)?;
+    emit_ocr_result(&app, stream_payload.clone());
+
+    let mut streamed_output = String::new();
+    let mut streamed_reasoning = String::new();
+    let mut last_emit_ms = 0u64;
+    let stream_base = stream_payload.clone();
+    let app_for_stream = app.clone();
+
+    let llm_result = run_llm_action(
+        &app,
+        &snapshot,
+        "follow_up",
+        &messages,
+        &GenerationParams::default(),
+        payload.regenerate,
+        |delta| {
+            if !is_ocr_result_task_active(&app_for_stream, task_nonce) {
+                return false;
+            }
+            let chunk = match delta {
+                StreamDelta::Answer(text) => {
+                    streamed_output.push_str(text);
+                    text
+                }
+                StreamDelta::Reasoning(text) => {
+                    streamed_reasoning.push_str(text);
+                    text
+                }
+            };
+
+            let now = now_epoch_millis();
+            if now.saturating_sub(last_emit_ms) < STREAM_EMIT_THROTTLE_MS && !chunk.contains('\n') {
+                return true;
+            }
+            last_emit_ms = now;
+
+            let mut update = stream_base.clone();
+            if let Some(turn) = update.follow_ups.last_mut() {
+                turn.answer = streamed_output.clone();
+                turn.reasoning = streamed_reasoning.clone();
+            }
+            emit_ocr_result(&app_for_stream, update);
+            true
+        },
+    )
+    .await;
+
+    let finish = |mut result: OcrResultPayload, answer: String, reasoning: String| {
+        if let Some(turn) = result.follow_ups.last_mut() {
+            turn.answer = answer;
+            turn.reasoning = reasoning;
+        }
+        result.is_streaming = false;
+        result
+    };
+
+    if !is_ocr_result_task_active(&app, task_nonce) {
+        return Ok(finish(stream_base, streamed_output, streamed_reasoning));
+    }
+
+    match llm_result {
+        Ok(outcome) => {
+            complete_conversation_turn(
+                &app,
+                &payload.request_id,
+                &FollowUpTurnPayload {
+                    question,
+                    answer: outcome.text.clone(),
+                    reasoning: outcome.reasoning.clone(),
+                },
+            );
+            let mut result = finish(stream_base, outcome.text, outcome.reasoning);
+            result.from_cache = outcome.from_cache;
+            result.usage = outcome.usage;
+            result.provider = outcome.provider;
+            emit_ocr_result(&app, result.clone());
+            Ok(result)
+        }
+        Err(error) => {
+            if is_task_replaced_error(&error) {
+                return Ok(finish(stream_base, streamed_output, streamed_reasoning));
+            }
+            let mut failed = finish(stream_payload, streamed_output, streamed_reasoning);
+            failed.error_message = Some(error.localized_message(&snapshot.language));
+            failed.error_detail = error.model_error_payload(&snapshot.language);
+            emit_ocr_result(&app, failed);
+            Err(error)
+        }
+    }
+}
+
 #[tauri::command]
 async fn synthesize_tts_cmd(
     payload: SynthesizeTtsPayload,
@@ -8479,8 +16201,10 @@ async fn complete_ocr_capture_cmd(
     settings_state: State<'_, AppSettingsState>,
     ocr_runtime: State<'_, OcrRuntimeState>,
     http_client_state: State<'_, HttpClientState>,
+    rate_limit_state: State<'_, ModelRateLimitState>,
 ) -> Result<(), CommandError> {
     let task_nonce = begin_ocr_result_task(&app);
+    let started_at_ms = now_epoch_millis();
     deactivate_ocr_capture_runtime(&ocr_runtime);
 
     let snapshot = settings_state
@@ -8518,30 +16242,75 @@ async fn complete_ocr_capture_cmd(
         .to_string(),
         ocr_text: String::new(),
         output_text: String::new(),
+        reasoning_text: String::new(),
         translate_from: None,
         translate_to: None,
+        custom_agent_id: None,
         custom_agent_name: None,
         custom_agent_icon: None,
         is_streaming: true,
+        from_cache: false,
+        usage: None,
+        provider: None,
+        follow_ups: Vec::new(),
+        glossary_warnings: Vec::new(),
+        workflow_steps: Vec::new(),
+        structured_output: None,
+        tool_calls: Vec::new(),
         error_message: None,
+        error_detail: None,
     };
 
     show_ocr_result_window(&app)?;
     emit_ocr_result(&app, ocr_payload.clone());
-
-    let ocr_text = match call_vision_ocr(
-        &http_client_state.client,
-        &snapshot.ocr.vision,
-        &image_data_url,
-    )
-    .await
-    {
-        Ok(text) => text,
+    let ocr_image_path = snapshot
+        .history
+        .record_ai_actions
+        .then(|| save_ocr_capture_image(&app, &ocr_payload.request_id, &image_data_url))
+        .flatten();
+
+    let usage_state = app.state::<UsageLedgerState>();
+    let vision_result = match ensure_usage_budget_available(&app, &usage_state, &snapshot.usage) {
+        Ok(()) => {
+            call_vision_ocr(
+                &http_client_state.client()?,
+                &rate_limit_state,
+                &snapshot.ocr.vision,
+                &image_data_url,
+            )
+            .await
+        }
+        Err(error) => Err(error),
+    };
+    let ocr_text = match vision_result {
+        Ok(completion) => {
+            if let Some(usage) = completion.usage {
+                record_model_usage(
+                    &app,
+                    &usage_state,
+                    &snapshot.usage,
+                    &snapshot.ocr.vision.model,
+                    "ocr",
+                    usage,
+                );
+            }
+            completion.text
+        }
         Err(error) => {
             ocr_payload.is_streaming = false;
-            ocr_payload.error_message = Some(error.to_string());
+            ocr_payload.error_message = Some(error.localized_message(&snapshot.language));
+            ocr_payload.error_detail = error.model_error_payload(&snapshot.language);
+            let mut log_entry = ActionLogEntry::from_ocr(
+                &ocr_payload,
+                &[],
+                &snapshot.ocr.vision.model,
+                started_at_ms,
+            );
+            log_entry.action = "ocr".to_string();
+            log_entry.ocr_image_path = ocr_image_path;
+            record_action_log(&app, log_entry);
             emit_ocr_result(&app, ocr_payload);
-            emit_ocr_error(&app, &error.to_string());
+            emit_ocr_error(&app, &error.localized_message(&snapshot.language));
             return Err(error);
         }
     };
@@ -8555,14 +16324,22 @@ async fn complete_ocr_capture_cmd(
     if !snapshot.ocr.auto_run_after_capture {
         ocr_payload.is_streaming = false;
         ocr_payload.output_text = ocr_text;
+        let mut log_entry =
+            ActionLogEntry::from_ocr(&ocr_payload, &[], &snapshot.ocr.vision.model, started_at_ms);
+        log_entry.action = "ocr".to_string();
+        log_entry.ocr_image_path = ocr_image_path;
+        record_action_log(&app, log_entry);
         emit_ocr_result(&app, ocr_payload);
         return Ok(());
     }
 
     let mut custom_agent_name: Option<String> = None;
     let mut custom_agent_icon: Option<String> = None;
+    let mut custom_agent_id: Option<String> = None;
     let mut translate_from: Option<String> = None;
     let mut translate_to: Option<String> = None;
+    let mut custom_params: Option<GenerationParams> = None;
+    let mut agent_run = AgentRun::default();
 
     let (action_name, system_prompt, user_prompt) = match snapshot.ocr.default_action {
         OcrDefaultAction::Summarize => {
@@ -8571,7 +16348,7 @@ async fn complete_ocr_capture_cmd(
                 &ocr_text,
                 None,
                 None,
-                &snapshot.language,
+                &snapshot,
             );
             ("summary".to_string(), prompts.0, prompts.1)
         }
@@ -8581,7 +16358,7 @@ async fn complete_ocr_capture_cmd(
                 &ocr_text,
                 None,
                 None,
-                &snapshot.language,
+                &snapshot,
             );
             ("polish".to_string(), prompts.0, prompts.1)
         }
@@ -8591,25 +16368,18 @@ async fn complete_ocr_capture_cmd(
                 &ocr_text,
                 None,
                 None,
-                &snapshot.language,
+                &snapshot,
             );
             ("explain".to_string(), prompts.0, prompts.1)
         }
         OcrDefaultAction::Translate => {
             translate_from = Some("auto".to_string());
-            translate_to = Some(
-                if snapshot.selection_assistant.default_translate_to.is_empty() {
-                    default_translate_target_for_language(&snapshot.language).to_string()
-                } else {
-                    snapshot.selection_assistant.default_translate_to.clone()
-                },
-            );
-            let prompts = choose_builtin_prompt(
-                SelectionActionKind::Translate,
+            translate_to = Some(resolve_translate_target(&snapshot, None, &ocr_text));
+            let prompts = translate_prompt(
+                &snapshot,
                 &ocr_text,
                 translate_from.as_deref(),
                 translate_to.as_deref(),
-                &snapshot.language,
             );
             ("translate".to_string(), prompts.0, prompts.1)
         }
@@ -8623,14 +16393,19 @@ async fn complete_ocr_capture_cmd(
                 .or_else(|| snapshot.agents.custom.first())
                 .cloned()
                 .ok_or_else(|| CommandError::Settings("未找到可用的自定义 Agent".to_string()))?;
+            custom_agent_id = Some(agent.id.clone());
             custom_agent_name = Some(agent.name.clone());
             custom_agent_icon = Some(agent.icon.clone());
-            let resolved_prompt = agent.prompt.replace("{text}", &ocr_text);
-            (
-                "custom".to_string(),
-                custom_agent_system_prompt().to_string(),
-                resolved_prompt,
-            )
+            custom_params = Some(agent.params.clone());
+            let (system_prompt, user_prompt) = custom_agent_prompts(
+                &agent,
+                &snapshot,
+                &ocr_text,
+                translate_to.as_deref(),
+                &SelectionSourceWindow::default(),
+            );
+            agent_run = AgentRun::for_agent(&agent)?;
+            ("custom".to_string(), system_prompt, user_prompt)
         }
     };
 
@@ -8639,53 +16414,152 @@ async fn complete_ocr_capture_cmd(
         action: action_name,
         ocr_text,
         output_text: String::new(),
+        reasoning_text: String::new(),
         translate_from,
         translate_to,
         custom_agent_name,
+        custom_agent_id: custom_agent_id.clone(),
         custom_agent_icon,
         is_streaming: true,
+        from_cache: false,
+        usage: None,
+        provider: None,
+        follow_ups: Vec::new(),
+        glossary_warnings: Vec::new(),
+        workflow_steps: Vec::new(),
+        structured_output: None,
+        tool_calls: Vec::new(),
         error_message: None,
+        error_detail: None,
+    };
+    emit_ocr_result(&app, stream_payload.clone());
+
+    let mut streamed_output = String::new();
+    let mut streamed_reasoning = String::new();
+    let mut last_emit_ms = 0u64;
+    let stream_base = stream_payload.clone();
+    let app_for_stream = app.clone();
+
+    let generation_params = custom_params.unwrap_or_else(|| {
+        snapshot
+            .agents
+            .builtin
+            .for_action(&stream_payload.action)
+            .map(|config| config.params.clone())
+            .unwrap_or_default()
+    });
+    let mut messages = single_turn_messages(&system_prompt, &user_prompt);
+    let llm_result = if let Some(result) = agent_run
+        .run_special(
+            &app,
+            &snapshot,
+            &stream_base.ocr_text,
+            stream_base.translate_to.as_deref(),
+            &SelectionSourceWindow::default(),
+            false,
+            &generation_params,
+            &mut messages,
+            &mut streamed_output,
+            &mut streamed_reasoning,
+            || is_ocr_result_task_active(&app_for_stream, task_nonce),
+            |steps| {
+                if !is_ocr_result_task_active(&app_for_stream, task_nonce) {
+                    return false;
+                }
+                let mut update = stream_base.clone();
+                update.output_text = steps
+                    .last()
+                    .map(|step| step.output.clone())
+                    .unwrap_or_default();
+                update.workflow_steps = steps.to_vec();
+                emit_ocr_result(&app_for_stream, update);
+                true
+            },
+        )
+        .await
+    {
+        result
+    } else {
+        run_agent_llm_action(
+            &app,
+            &snapshot,
+            &stream_base.action,
+            &messages,
+            &generation_params,
+            false,
+            &agent_run.tools,
+            &mut agent_run.tool_trace,
+            |delta| {
+                if !is_ocr_result_task_active(&app_for_stream, task_nonce) {
+                    return false;
+                }
+                let chunk = match delta {
+                    StreamDelta::Answer(text) => {
+                        streamed_output.push_str(text);
+                        text
+                    }
+                    StreamDelta::Reasoning(text) => {
+                        streamed_reasoning.push_str(text);
+                        text
+                    }
+                };
+                let now = now_epoch_millis();
+                if now.saturating_sub(last_emit_ms) < STREAM_EMIT_THROTTLE_MS
+                    && !chunk.contains('\n')
+                {
+                    return true;
+                }
+                last_emit_ms = now;
+                let mut update = stream_base.clone();
+                update.output_text = streamed_output.clone();
+                update.reasoning_text = streamed_reasoning.clone();
+                emit_ocr_result(&app_for_stream, update);
+                true
+            },
+        )
+        .await
     };
-    emit_ocr_result(&app, stream_payload.clone());
-
-    let mut streamed_output = String::new();
-    let mut last_emit_ms = 0u64;
-    let stream_base = stream_payload.clone();
-    let app_for_stream = app.clone();
-    let llm_stream_enabled = true;
-
-    let llm_result = call_llm_for_action(
-        &http_client_state.client,
-        &snapshot.llm,
-        &system_prompt,
-        &user_prompt,
-        llm_stream_enabled,
-        |delta| {
-            if !is_ocr_result_task_active(&app_for_stream, task_nonce) {
-                return false;
-            }
-            streamed_output.push_str(delta);
-            let now = now_epoch_millis();
-            if now.saturating_sub(last_emit_ms) < STREAM_EMIT_THROTTLE_MS && !delta.contains('\n') {
-                return true;
-            }
-            last_emit_ms = now;
-            let mut update = stream_base.clone();
-            update.output_text = streamed_output.clone();
-            emit_ocr_result(&app_for_stream, update);
-            true
-        },
-    )
-    .await;
 
     if !is_ocr_result_task_active(&app, task_nonce) {
         return Ok(());
     }
 
     match llm_result {
-        Ok(output_text) => {
-            stream_payload.output_text = output_text;
+        Ok(outcome) => {
+            stream_payload.output_text = outcome.text;
+            (
+                stream_payload.workflow_steps,
+                stream_payload.tool_calls,
+                stream_payload.structured_output,
+            ) = agent_run.take_outputs();
+            stream_payload.reasoning_text = outcome.reasoning;
             stream_payload.is_streaming = false;
+            stream_payload.from_cache = outcome.from_cache;
+            stream_payload.usage = outcome.usage;
+            stream_payload.provider = outcome.provider;
+            if stream_payload.action == "translate" {
+                stream_payload.glossary_warnings = glossary_warnings(
+                    &snapshot.glossary,
+                    &stream_payload.ocr_text,
+                    &stream_payload.output_text,
+                    stream_payload.translate_from.as_deref(),
+                    stream_payload.translate_to.as_deref(),
+                );
+            }
+            let mut log_entry = ActionLogEntry::from_ocr(
+                &stream_payload,
+                &messages,
+                &snapshot.llm.model,
+                started_at_ms,
+            );
+            log_entry.custom_agent_id = custom_agent_id;
+            log_entry.ocr_image_path = ocr_image_path;
+            record_action_log(&app, log_entry);
+            remember_conversation(
+                &app,
+                ConversationOrigin::Ocr(Box::new(stream_payload.clone())),
+                messages,
+            );
             emit_ocr_result(&app, stream_payload);
             Ok(())
         }
@@ -8694,11 +16568,24 @@ async fn complete_ocr_capture_cmd(
                 return Ok(());
             }
             stream_payload.output_text = streamed_output;
+            (stream_payload.workflow_steps, stream_payload.tool_calls, _) =
+                agent_run.take_outputs();
+            stream_payload.reasoning_text = streamed_reasoning;
             stream_payload.is_streaming = false;
             if stream_payload.output_text.trim().is_empty() {
-                stream_payload.error_message = Some(error.to_string());
+                stream_payload.error_message = Some(error.localized_message(&snapshot.language));
+                stream_payload.error_detail = error.model_error_payload(&snapshot.language);
+                let mut log_entry = ActionLogEntry::from_ocr(
+                    &stream_payload,
+                    &messages,
+                    &snapshot.llm.model,
+                    started_at_ms,
+                );
+                log_entry.custom_agent_id = custom_agent_id;
+                log_entry.ocr_image_path = ocr_image_path;
+                record_action_log(&app, log_entry);
                 emit_ocr_result(&app, stream_payload);
-                emit_ocr_error(&app, &error.to_string());
+                emit_ocr_error(&app, &error.localized_message(&snapshot.language));
                 Err(error)
             } else {
                 stream_payload.error_message = None;
@@ -8775,11 +16662,12 @@ async fn test_llm_api_cmd(
     }
 
     test_openai_compatible_model(
-        &http_client_state.client,
+        &http_client_state.client()?,
         &snapshot.llm.base_url,
         &snapshot.llm.api_key,
         &snapshot.llm.model,
         snapshot.llm.timeout_ms,
+        &snapshot.llm.extra_headers,
     )
     .await
 }
@@ -8788,6 +16676,7 @@ async fn test_llm_api_cmd(
 async fn test_ocr_vision_api_cmd(
     settings_state: State<'_, AppSettingsState>,
     http_client_state: State<'_, HttpClientState>,
+    rate_limit_state: State<'_, ModelRateLimitState>,
 ) -> Result<String, CommandError> {
     let snapshot = settings_state
         .data
@@ -8803,9 +16692,14 @@ async fn test_ocr_vision_api_cmd(
     }
 
     if should_use_glm_layout_parsing(&vision) {
-        let text =
-            call_glm_layout_parsing_ocr(&http_client_state.client, &vision, GLM_OCR_TEST_IMAGE_URL)
-                .await?;
+        let text = call_glm_layout_parsing_ocr(
+            &http_client_state.client()?,
+            &rate_limit_state,
+            &vision,
+            GLM_OCR_TEST_IMAGE_URL,
+        )
+        .await?
+        .text;
         let normalized = text.trim();
         if normalized.is_empty() {
             return Ok("OK".to_string());
@@ -8814,11 +16708,12 @@ async fn test_ocr_vision_api_cmd(
     }
 
     test_openai_compatible_model(
-        &http_client_state.client,
+        &http_client_state.client()?,
         &vision.base_url,
         &vision.api_key,
         &vision.model,
         vision.timeout_ms,
+        &vision.extra_headers,
     )
     .await
 }
@@ -8916,9 +16811,14 @@ fn reset_settings(
             result_window_always_on_top: Some(
                 defaults.selection_assistant.result_window_always_on_top,
             ),
+            smart_translate_target: Some(defaults.selection_assistant.smart_translate_target),
+            bilingual_mode: Some(defaults.selection_assistant.bilingual_mode),
+            dictionary_mode: Some(defaults.selection_assistant.dictionary_mode),
             remember_result_window_position: Some(
                 defaults.selection_assistant.remember_result_window_position,
             ),
+            app_rules: Some(defaults.selection_assistant.app_rules.clone()),
+            smart_bar_suggestions: Some(defaults.selection_assistant.smart_bar_suggestions),
         }),
         llm: Some(LlmSettingsPatch {
             enabled: Some(defaults.llm.enabled),
@@ -8928,6 +16828,13 @@ fn reset_settings(
             temperature: Some(defaults.llm.temperature),
             max_tokens: Some(defaults.llm.max_tokens),
             timeout_ms: Some(defaults.llm.timeout_ms),
+            requests_per_minute: Some(defaults.llm.requests_per_minute),
+            tokens_per_minute: Some(defaults.llm.tokens_per_minute),
+            reasoning_display: Some(defaults.llm.reasoning_display),
+            extra_headers: Some(defaults.llm.extra_headers.clone()),
+            fallback_profiles: Some(defaults.llm.fallback_profiles.clone()),
+            chunk_tokens: Some(defaults.llm.chunk_tokens),
+            context_tokens: Some(defaults.llm.context_tokens),
         }),
         tts: Some(TtsSettingsPatch {
             runtime_mode: Some(defaults.tts.runtime_mode),
@@ -8937,7 +16844,9 @@ fn reset_settings(
         }),
         agents: Some(AgentSettingsPatch {
             custom: Some(defaults.agents.custom.clone()),
+            builtin: Some(defaults.agents.builtin.clone()),
             bar_order: Some(defaults.agents.bar_order.clone()),
+            constants: Some(defaults.agents.constants.clone()),
         }),
         shortcuts: Some(ShortcutSettingsPatch {
             toggle_main: Some(defaults.shortcuts.toggle_main.clone()),
@@ -8958,6 +16867,9 @@ fn reset_settings(
                 temperature: Some(defaults.ocr.vision.temperature),
                 max_tokens: Some(defaults.ocr.vision.max_tokens),
                 timeout_ms: Some(defaults.ocr.vision.timeout_ms),
+                requests_per_minute: Some(defaults.ocr.vision.requests_per_minute),
+                tokens_per_minute: Some(defaults.ocr.vision.tokens_per_minute),
+                extra_headers: Some(defaults.ocr.vision.extra_headers.clone()),
             }),
         }),
         appearance: Some(AppearanceSettingsPatch {
@@ -8989,6 +16901,28 @@ fn reset_settings(
             promote_after_paste: Some(defaults.history.promote_after_paste),
             open_at_top_on_show: Some(defaults.history.open_at_top_on_show),
             storage_path: Some(defaults.history.storage_path.clone()),
+            record_ai_actions: Some(defaults.history.record_ai_actions),
+        }),
+        response_cache: Some(ResponseCacheSettingsPatch {
+            enabled: Some(defaults.response_cache.enabled),
+            max_size_mb: Some(defaults.response_cache.max_size_mb),
+            ttl_hours: Some(defaults.response_cache.ttl_hours),
+        }),
+        usage: Some(UsageSettingsPatch {
+            monthly_budget_usd: Some(defaults.usage.monthly_budget_usd),
+            monthly_token_budget: Some(defaults.usage.monthly_token_budget),
+            price_overrides: Some(defaults.usage.price_overrides.clone()),
+        }),
+        network: Some(NetworkSettingsPatch {
+            proxy_url: Some(defaults.network.proxy_url.clone()),
+            proxy_username: Some(defaults.network.proxy_username.clone()),
+            proxy_password: Some(defaults.network.proxy_password.clone()),
+            no_proxy: Some(defaults.network.no_proxy.clone()),
+            extra_ca_cert_path: Some(defaults.network.extra_ca_cert_path.clone()),
+        }),
+        glossary: Some(GlossarySettingsPatch {
+            entries: Some(defaults.glossary.entries.clone()),
+            do_not_translate: Some(defaults.glossary.do_not_translate.clone()),
         }),
         selection_result_window_width: defaults.selection_result_window_width,
         selection_result_window_height: defaults.selection_result_window_height,
@@ -9055,6 +16989,8 @@ fn import_settings(
 
     let (previous_settings, updated_settings) = {
         let mut settings = with_settings_lock(&settings_state)?;
+        validate_agent_settings(&incoming.agents, &settings.agents)?;
+        validate_custom_agent_prompts(&incoming.agents, &settings.agents)?;
         let previous = settings.clone();
         *settings = incoming;
         (previous, settings.clone())
@@ -9085,6 +17021,302 @@ fn import_settings(
     Ok(updated_settings)
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GlossaryImportPayload {
+    settings: AppSettings,
+    imported_entries: usize,
+    imported_terms: usize,
+    skipped_rows: usize,
+}
+
+/// Merges CSV/TSV glossary rows into the settings; rows replace existing entries with the same
+/// source term and language pair unless `replace` clears the glossary first.
+#[tauri::command]
+fn import_glossary(
+    app: AppHandle,
+    payload: String,
+    source_language: Option<String>,
+    target_language: Option<String>,
+    replace: Option<bool>,
+    settings_state: State<'_, AppSettingsState>,
+    history_state: State<'_, Mutex<ClipboardState>>,
+) -> Result<GlossaryImportPayload, CommandError> {
+    let (entries, terms, skipped_rows) = parse_glossary_table(
+        &payload,
+        source_language.as_deref().unwrap_or_default(),
+        target_language.as_deref().unwrap_or_default(),
+    );
+    if entries.is_empty() && terms.is_empty() {
+        return Err(CommandError::Settings(
+            "未从内容中解析到术语，请检查 CSV/TSV 格式".to_string(),
+        ));
+    }
+
+    let mut glossary = if replace.unwrap_or(false) {
+        GlossarySettings::default()
+    } else {
+        with_settings_lock(&settings_state)?.glossary.clone()
+    };
+    let entry_key = |entry: &GlossaryEntry| {
+        (
+            entry.source.to_lowercase(),
+            entry.source_language.clone(),
+            entry.target_language.clone(),
+        )
+    };
+    let imported_entry_keys: HashSet<_> = entries.iter().map(entry_key).collect();
+    let imported_term_keys: HashSet<_> = terms.iter().map(|term| term.to_lowercase()).collect();
+    for entry in entries {
+        let lowered_source = entry.source.to_lowercase();
+        glossary.entries.retain(|existing| {
+            !(existing.source.to_lowercase() == lowered_source
+                && existing.source_language == entry.source_language
+                && existing.target_language == entry.target_language)
+        });
+        glossary.entries.push(entry);
+    }
+    glossary.do_not_translate.extend(terms);
+
+    let patch = SettingsPatch {
+        glossary: Some(GlossarySettingsPatch {
+            entries: Some(glossary.entries),
+            do_not_translate: Some(glossary.do_not_translate),
+        }),
+        ..SettingsPatch::default()
+    };
+    let settings = update_settings_internal(&app, &settings_state, &history_state, patch)?;
+    // Normalization drops entries past the size limits, so count what was actually stored.
+    let imported_entries = settings
+        .glossary
+        .entries
+        .iter()
+        .filter(|entry| imported_entry_keys.contains(&entry_key(entry)))
+        .count();
+    let imported_terms = settings
+        .glossary
+        .do_not_translate
+        .iter()
+        .filter(|term| imported_term_keys.contains(&term.to_lowercase()))
+        .count();
+    Ok(GlossaryImportPayload {
+        settings,
+        imported_entries,
+        imported_terms,
+        skipped_rows,
+    })
+}
+
+fn parse_agent_pack(payload: &str) -> Result<AgentPack, CommandError> {
+    let pack = serde_json::from_str::<AgentPack>(payload)
+        .map_err(|error| CommandError::Settings(format!("Agent 包格式无效：{error}")))?;
+    if pack.format != AGENT_PACK_FORMAT {
+        return Err(CommandError::Settings(
+            "不是 SnapParse Agent 包".to_string(),
+        ));
+    }
+    if pack.version > AGENT_PACK_VERSION {
+        return Err(CommandError::Settings(format!(
+            "Agent 包版本 {} 高于当前支持的版本 {AGENT_PACK_VERSION}，请先升级应用",
+            pack.version
+        )));
+    }
+    if pack.agents.is_empty() {
+        return Err(CommandError::Settings("Agent 包中没有 Agent".to_string()));
+    }
+    Ok(pack)
+}
+
+/// Variables referenced by an agent's prompts, in first-use order.
+fn agent_required_variables(agent: &CustomAgent) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    let step_prompts = agent.steps.iter().map(|step| step.prompt.as_str());
+    for template in [agent.system_prompt.as_str(), agent.prompt.as_str()]
+        .into_iter()
+        .chain(step_prompts)
+    {
+        for part in parse_prompt_template(template) {
+            let (TemplatePart::Variable(name) | TemplatePart::SectionStart { name, .. }) = part
+            else {
+                continue;
+            };
+            if !names.iter().any(|item| item == name) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names
+}
+
+/// Variables an imported agent needs that are neither built in nor defined as constants.
+fn missing_agent_variables(entry: &AgentPackEntry, constants: &[PromptConstant]) -> Vec<String> {
+    let agent = CustomAgent {
+        prompt: entry.prompt.clone(),
+        system_prompt: entry.system_prompt.clone(),
+        steps: entry.steps.clone(),
+        ..CustomAgent::default()
+    };
+    agent_required_variables(&agent)
+        .into_iter()
+        .chain(entry.required_variables.iter().cloned())
+        .filter(|name| {
+            !CUSTOM_AGENT_TEMPLATE_VARIABLES.contains(&name.as_str())
+                && !constants.iter().any(|constant| &constant.name == name)
+        })
+        .fold(Vec::new(), |mut names, name| {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+            names
+        })
+}
+
+#[tauri::command]
+fn export_agents(
+    ids: Option<Vec<String>>,
+    settings_state: State<'_, AppSettingsState>,
+) -> Result<String, CommandError> {
+    let settings = with_settings_lock(&settings_state)?;
+    let agents = settings
+        .agents
+        .custom
+        .iter()
+        .filter(|agent| ids.as_ref().is_none_or(|ids| ids.contains(&agent.id)))
+        .map(|agent| AgentPackEntry {
+            id: agent.id.clone(),
+            name: agent.name.clone(),
+            icon: agent.icon.clone(),
+            prompt: agent.prompt.clone(),
+            system_prompt: agent.system_prompt.clone(),
+            steps: agent.steps.clone(),
+            output_schema: agent.output_schema.clone(),
+            tools: agent.tools.clone(),
+            params: agent.params.clone(),
+            required_variables: agent_required_variables(agent),
+        })
+        .collect::<Vec<_>>();
+    if agents.is_empty() {
+        return Err(CommandError::Settings("没有可导出的 Agent".to_string()));
+    }
+    let pack = AgentPack {
+        format: AGENT_PACK_FORMAT.to_string(),
+        version: AGENT_PACK_VERSION,
+        exported_at_ms: now_epoch_millis(),
+        agents,
+    };
+    serde_json::to_string_pretty(&pack)
+        .map_err(|error| CommandError::Serialization(error.to_string()))
+}
+
+/// Lists the agents in a pack so the user can choose which ones to import.
+#[tauri::command]
+fn preview_agent_pack(
+    payload: String,
+    settings_state: State<'_, AppSettingsState>,
+) -> Result<Vec<AgentPackPreviewItem>, CommandError> {
+    let pack = parse_agent_pack(&payload)?;
+    let settings = with_settings_lock(&settings_state)?;
+    Ok(pack
+        .agents
+        .iter()
+        .map(|entry| AgentPackPreviewItem {
+            id: entry.id.clone(),
+            name: entry.name.clone(),
+            icon: entry.icon.clone(),
+            icon_supported: SUPPORTED_AGENT_ICONS.contains(&entry.icon.as_str()),
+            id_conflict: settings
+                .agents
+                .custom
+                .iter()
+                .any(|agent| agent.id == entry.id),
+            missing_variables: missing_agent_variables(entry, &settings.agents.constants),
+            tools: entry.tools.clone(),
+        })
+        .collect())
+}
+
+/// Appends the selected agents of a pack; other settings are left untouched.
+#[tauri::command]
+fn import_agents(
+    app: AppHandle,
+    payload: String,
+    ids: Option<Vec<String>>,
+    settings_state: State<'_, AppSettingsState>,
+    history_state: State<'_, Mutex<ClipboardState>>,
+) -> Result<AgentImportPayload, CommandError> {
+    let pack = parse_agent_pack(&payload)?;
+    let selected = pack
+        .agents
+        .into_iter()
+        .filter(|entry| ids.as_ref().is_none_or(|ids| ids.contains(&entry.id)))
+        .collect::<Vec<_>>();
+    if selected.is_empty() {
+        return Err(CommandError::Settings(
+            "请至少选择一个要导入的 Agent".to_string(),
+        ));
+    }
+
+    let agents = with_settings_lock(&settings_state)?.agents.clone();
+    if agents.custom.len() + selected.len() > MAX_CUSTOM_AGENTS {
+        return Err(CommandError::Settings(format!(
+            "最多保存 {MAX_CUSTOM_AGENTS} 个自定义 Agent，当前还可导入 {} 个",
+            MAX_CUSTOM_AGENTS.saturating_sub(agents.custom.len())
+        )));
+    }
+
+    let mut custom = agents.custom;
+    let mut constants = agents.constants;
+    let mut added_constants = Vec::new();
+    let imported = selected.len();
+    for (index, entry) in selected.into_iter().enumerate() {
+        for name in missing_agent_variables(&entry, &constants) {
+            constants.push(PromptConstant {
+                name: name.clone(),
+                value: String::new(),
+            });
+            added_constants.push(name);
+        }
+        let id_taken = |id: &str| id.trim().is_empty() || custom.iter().any(|agent| agent.id == id);
+        let id = if id_taken(&entry.id) {
+            format!("agent-{}-{}", custom.len() + index, now_epoch_millis())
+        } else {
+            entry.id
+        };
+        let icon = if SUPPORTED_AGENT_ICONS.contains(&entry.icon.as_str()) {
+            entry.icon
+        } else {
+            CustomAgent::default().icon
+        };
+        custom.push(CustomAgent {
+            id,
+            name: entry.name,
+            icon,
+            prompt: entry.prompt,
+            system_prompt: entry.system_prompt,
+            steps: entry.steps,
+            output_schema: entry.output_schema,
+            tools: entry.tools,
+            params: entry.params,
+            ..CustomAgent::default()
+        });
+    }
+
+    let patch = SettingsPatch {
+        agents: Some(AgentSettingsPatch {
+            custom: Some(custom),
+            constants: Some(constants),
+            ..AgentSettingsPatch::default()
+        }),
+        ..SettingsPatch::default()
+    };
+    let settings = update_settings_internal(&app, &settings_state, &history_state, patch)?;
+    Ok(AgentImportPayload {
+        settings,
+        imported,
+        added_constants,
+    })
+}
+
 #[tauri::command]
 fn set_auto_hide_on_blur(
     app: AppHandle,
@@ -9561,6 +17793,384 @@ fn clear_history(
     Ok(updated)
 }
 
+#[tauri::command]
+fn clear_response_cache_cmd(
+    app: AppHandle,
+    cache_state: State<'_, ResponseCacheState>,
+) -> Result<(), CommandError> {
+    *cache_state.store.lock().map_err(|_| CommandError::Lock)? =
+        Some(ResponseCacheStore::default());
+    let _file = cache_state
+        .file_lock
+        .lock()
+        .map_err(|_| CommandError::Lock)?;
+    let path = response_cache_file_path(&app)?;
+    if path.exists() {
+        fs::remove_file(&path).map_err(|error| CommandError::Settings(error.to_string()))?;
+    }
+    Ok(())
+}
+
+fn query_action_log<R: Runtime>(
+    app: &AppHandle<R>,
+    query: &ActionLogQueryPayload,
+) -> Result<Vec<ActionLogEntry>, CommandError> {
+    let lowered_query = query
+        .query
+        .as_deref()
+        .map(|value| value.trim().to_lowercase())
+        .unwrap_or_default();
+    let offset = query.offset.unwrap_or(0);
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_ACTION_LOG_PAGE_SIZE)
+        .clamp(1, MAX_ACTION_LOG_ENTRIES);
+    with_action_log(app, |entries| {
+        entries
+            .iter()
+            .filter(|entry| query.surface.is_none_or(|surface| entry.surface == surface))
+            .filter(|entry| lowered_query.is_empty() || entry.matches_query(&lowered_query))
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect()
+    })
+}
+
+#[tauri::command]
+fn list_action_log(
+    app: AppHandle,
+    payload: Option<ActionLogQueryPayload>,
+) -> Result<Vec<ActionLogEntry>, CommandError> {
+    let mut query = payload.unwrap_or(ActionLogQueryPayload {
+        query: None,
+        surface: None,
+        offset: None,
+        limit: None,
+    });
+    query.query = None;
+    query_action_log(&app, &query)
+}
+
+#[tauri::command]
+fn search_action_log(
+    app: AppHandle,
+    payload: ActionLogQueryPayload,
+) -> Result<Vec<ActionLogEntry>, CommandError> {
+    query_action_log(&app, &payload)
+}
+
+#[tauri::command]
+fn reopen_action_log_entry(app: AppHandle, id: String) -> Result<(), CommandError> {
+    let entry = find_action_log_entry(&app, &id)?;
+    let messages = if entry.user_prompt.is_empty() {
+        Vec::new()
+    } else {
+        single_turn_messages(&entry.system_prompt, &entry.user_prompt)
+    };
+    match entry.surface {
+        ActionSurface::Selection => {
+            begin_selection_result_task(&app);
+            let diff_hunks = if entry.action == "polish" && entry.error_message.is_none() {
+                polish_diff_hunks(&entry.source_text, &entry.output_text)
+            } else {
+                Vec::new()
+            };
+            let payload = SelectionResultPayload {
+                request_id: entry.request_id,
+                action: entry.action,
+                source_text: entry.source_text,
+                output_text: entry.output_text,
+                reasoning_text: String::new(),
+                translate_from: entry.translate_from,
+                translate_to: entry.translate_to,
+                custom_agent_name: entry.custom_agent_name,
+                custom_agent_id: entry.custom_agent_id.clone(),
+                custom_agent_icon: entry.custom_agent_icon,
+                is_streaming: false,
+                from_cache: entry.from_cache,
+                usage: None,
+                provider: None,
+                chunk_progress: None,
+                segments: Vec::new(),
+                dictionary: None,
+                follow_ups: Vec::new(),
+                glossary_warnings: Vec::new(),
+                workflow_steps: Vec::new(),
+                structured_output: None,
+                tool_calls: Vec::new(),
+                diff_hunks,
+                error_message: entry.error_message,
+                error_detail: None,
+            };
+            if !messages.is_empty() && payload.error_message.is_none() {
+                remember_conversation(
+                    &app,
+                    ConversationOrigin::Selection(Box::new(payload.clone())),
+                    messages,
+                );
+            }
+            show_selection_result_window(&app)?;
+            emit_selection_result(&app, payload);
+        }
+        ActionSurface::Ocr => {
+            begin_ocr_result_task(&app);
+            let payload = OcrResultPayload {
+                request_id: entry.request_id,
+                action: entry.action,
+                ocr_text: entry.source_text,
+                output_text: entry.output_text,
+                reasoning_text: String::new(),
+                translate_from: entry.translate_from,
+                translate_to: entry.translate_to,
+                custom_agent_name: entry.custom_agent_name,
+                custom_agent_id: entry.custom_agent_id.clone(),
+                custom_agent_icon: entry.custom_agent_icon,
+                is_streaming: false,
+                from_cache: entry.from_cache,
+                usage: None,
+                provider: None,
+                follow_ups: Vec::new(),
+                glossary_warnings: Vec::new(),
+                workflow_steps: Vec::new(),
+                structured_output: None,
+                tool_calls: Vec::new(),
+                error_message: entry.error_message,
+                error_detail: None,
+            };
+            if !messages.is_empty() && payload.error_message.is_none() {
+                remember_conversation(
+                    &app,
+                    ConversationOrigin::Ocr(Box::new(payload.clone())),
+                    messages,
+                );
+            }
+            show_ocr_result_window(&app)?;
+            emit_ocr_result(&app, payload);
+        }
+    }
+    Ok(())
+}
+
+#[tauri::command]
+async fn rerun_action_log_entry(
+    app: AppHandle,
+    payload: RerunActionLogPayload,
+) -> Result<(), CommandError> {
+    let entry = find_action_log_entry(&app, &payload.id)?;
+    if entry.source_text.trim().is_empty() {
+        return Err(CommandError::Settings(
+            "该记录没有可重新运行的文本".to_string(),
+        ));
+    }
+    match entry.surface {
+        ActionSurface::Selection => {
+            run_selection_action(
+                app.clone(),
+                RunSelectionActionPayload {
+                    action: payload.action,
+                    text: entry.source_text,
+                    custom_agent_id: payload.custom_agent_id,
+                    translate_from: payload.translate_from,
+                    translate_to: payload.translate_to,
+                    regenerate: false,
+                    bilingual_mode: None,
+                    replace_selection: false,
+                },
+                app.state::<AppSettingsState>(),
+            )
+            .await?;
+        }
+        ActionSurface::Ocr => {
+            run_ocr_action_cmd(
+                app.clone(),
+                RunOcrActionPayload {
+                    action: payload.action,
+                    ocr_text: entry.source_text,
+                    custom_agent_id: payload.custom_agent_id,
+                    translate_from: payload.translate_from,
+                    translate_to: payload.translate_to,
+                    regenerate: false,
+                },
+                app.state::<AppSettingsState>(),
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+#[tauri::command]
+fn export_action_log(app: AppHandle, format: Option<String>) -> Result<String, CommandError> {
+    let entries = with_action_log(&app, |entries| entries.clone())?;
+    match format.as_deref().map(str::trim) {
+        None | Some("") | Some("json") => serde_json::to_string_pretty(&entries)
+            .map_err(|error| CommandError::Serialization(error.to_string())),
+        Some("csv") => {
+            let mut output = String::from(
+                "createdAt,surface,action,agent,model,latencyMs,fromCache,sourceText,outputText,ocrImagePath,errorCode,errorMessage\n",
+            );
+            for entry in &entries {
+                let created_at = DateTime::<Utc>::from_timestamp_millis(entry.created_at_ms as i64)
+                    .map(|value| value.to_rfc3339())
+                    .unwrap_or_default();
+                let surface = match entry.surface {
+                    ActionSurface::Selection => "selection",
+                    ActionSurface::Ocr => "ocr",
+                };
+                let fields = [
+                    created_at,
+                    surface.to_string(),
+                    entry.action.clone(),
+                    entry.custom_agent_name.clone().unwrap_or_default(),
+                    entry.model.clone(),
+                    entry.latency_ms.to_string(),
+                    entry.from_cache.to_string(),
+                    entry.source_text.clone(),
+                    entry.output_text.clone(),
+                    entry.ocr_image_path.clone().unwrap_or_default(),
+                    entry.error_code.clone().unwrap_or_default(),
+                    entry.error_message.clone().unwrap_or_default(),
+                ];
+                let line: Vec<String> = fields.iter().map(|value| csv_escape(value)).collect();
+                output.push_str(&line.join(","));
+                output.push('\n');
+            }
+            Ok(output)
+        }
+        Some(other) => Err(CommandError::Settings(format!("不支持的导出格式: {other}"))),
+    }
+}
+
+#[tauri::command]
+fn clear_action_log(app: AppHandle) -> Result<(), CommandError> {
+    with_action_log(&app, |entries| {
+        for entry in entries.drain(..) {
+            if let Some(path) = entry.ocr_image_path {
+                let _ = fs::remove_file(path);
+            }
+        }
+        persist_action_log(&app, entries)
+    })?
+}
+
+/// Saves a looked-up word; saving the same headword again refreshes it and moves it to the top.
+#[tauri::command]
+fn save_word_to_list(
+    app: AppHandle,
+    entry: DictionaryEntryPayload,
+    context: Option<String>,
+) -> Result<WordListEntry, CommandError> {
+    let headword = entry.headword.trim().to_lowercase();
+    if headword.is_empty() {
+        return Err(CommandError::Settings(
+            "单词为空，无法加入生词本".to_string(),
+        ));
+    }
+    with_word_list(&app, |entries| {
+        let previous = entries
+            .iter()
+            .position(|item| item.entry.headword.trim().to_lowercase() == headword)
+            .map(|index| entries.remove(index));
+        let saved = WordListEntry {
+            id: previous
+                .as_ref()
+                .map(|item| item.id.clone())
+                .unwrap_or_else(|| now_id().to_string()),
+            context: context
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+                .or_else(|| previous.as_ref().map(|item| item.context.clone()))
+                .unwrap_or_default(),
+            lookup_count: previous.map(|item| item.lookup_count + 1).unwrap_or(1),
+            saved_at_ms: now_epoch_millis(),
+            entry,
+        };
+        entries.insert(0, saved.clone());
+        entries.truncate(MAX_WORD_LIST_ENTRIES);
+        persist_word_list(&app, entries).map(|()| saved)
+    })?
+}
+
+#[tauri::command]
+fn list_word_list(app: AppHandle) -> Result<Vec<WordListEntry>, CommandError> {
+    with_word_list(&app, |entries| entries.clone())
+}
+
+#[tauri::command]
+fn remove_word_from_list(app: AppHandle, id: String) -> Result<(), CommandError> {
+    with_word_list(&app, |entries| {
+        entries.retain(|item| item.id != id);
+        persist_word_list(&app, entries)
+    })?
+}
+
+/// Languages offered as translation source and target, in registry order.
+#[tauri::command]
+fn list_translate_languages() -> Vec<LanguageOptionPayload> {
+    LANGUAGE_REGISTRY
+        .iter()
+        .map(|language| LanguageOptionPayload {
+            code: language.code,
+            english_name: language.english_name,
+            native_name: language.native_name,
+        })
+        .collect()
+}
+
+/// Default prompt templates, shown as placeholders and restored by "reset to default".
+#[tauri::command]
+fn get_builtin_prompt_defaults() -> BuiltinPromptDefaultsPayload {
+    let template = |kind| {
+        let (system_prompt, user_prompt) = builtin_prompt_defaults(kind).unwrap_or_default();
+        BuiltinPromptTemplatePayload {
+            system_prompt,
+            user_prompt,
+        }
+    };
+    BuiltinPromptDefaultsPayload {
+        summarize: template(SelectionActionKind::Summarize),
+        polish: template(SelectionActionKind::Polish),
+        explain: template(SelectionActionKind::Explain),
+        translate: template(SelectionActionKind::Translate),
+        variables: PROMPT_TEMPLATE_VARIABLES.to_vec(),
+    }
+}
+
+#[tauri::command]
+fn get_usage_stats(
+    app: AppHandle,
+    days: Option<u32>,
+    settings_state: State<'_, AppSettingsState>,
+    usage_state: State<'_, UsageLedgerState>,
+) -> Result<UsageStatsPayload, CommandError> {
+    let usage_settings = with_settings_lock(&settings_state)?.usage.clone();
+    let days = days
+        .unwrap_or(DEFAULT_USAGE_STATS_DAYS)
+        .clamp(1, MAX_USAGE_STATS_DAYS);
+    let oldest_day =
+        usage_day_key(Local::now().date_naive() - chrono::Duration::days(i64::from(days) - 1));
+
+    let mut guard = usage_state.ledger.lock().map_err(|_| CommandError::Lock)?;
+    let ledger = guard.get_or_insert_with(|| load_usage_ledger(&app));
+    let in_range = || ledger.rows.iter().filter(|row| row.day >= oldest_day);
+    let mut totals = UsageTotals::default();
+    for row in in_range() {
+        totals.add_row(row);
+    }
+
+    Ok(UsageStatsPayload {
+        days,
+        totals,
+        by_day: usage_breakdown(in_range(), |row| &row.day),
+        by_model: usage_breakdown(in_range(), |row| &row.model),
+        by_action: usage_breakdown(in_range(), |row| &row.action),
+        current_month: current_month_usage(ledger),
+        monthly_budget_usd: usage_settings.monthly_budget_usd,
+        monthly_token_budget: usage_settings.monthly_token_budget,
+    })
+}
+
 pub fn run() {
     tauri::Builder::default()
         .manage(Mutex::new(ClipboardState::default()))
@@ -9568,6 +18178,13 @@ pub fn run() {
         .manage(SelectionRuntimeState::default())
         .manage(OcrRuntimeState::default())
         .manage(HttpClientState::default())
+        .manage(ModelRateLimitState::default())
+        .manage(ProviderHealthState::default())
+        .manage(ResponseCacheState::default())
+        .manage(UsageLedgerState::default())
+        .manage(ConversationState::default())
+        .manage(ActionLogState::default())
+        .manage(WordListState::default())
         .setup(|app| {
             let app_handle = app.handle().clone();
             let path = settings_file_path(&app_handle)?;
@@ -9607,6 +18224,11 @@ pub fn run() {
                 file_path: path,
                 data: Mutex::new(settings.clone()),
             });
+            if settings.network != NetworkSettings::default() {
+                app_handle
+                    .state::<HttpClientState>()
+                    .rebuild(&settings.network);
+            }
             {
                 let history_state = app_handle.state::<Mutex<ClipboardState>>();
                 if let Ok(mut history) = history_state.lock() {
@@ -10081,6 +18703,8 @@ pub fn run() {
             open_search_with_text,
             run_selection_action,
             run_ocr_action_cmd,
+            continue_selection_conversation,
+            continue_ocr_conversation_cmd,
             synthesize_tts_cmd,
             start_main_window_drag_cmd,
             set_main_window_pinned_cmd,
@@ -10089,6 +18713,7 @@ pub fn run() {
             get_result_window_pinned_cmd,
             minimize_selection_result_window,
             close_selection_result_window,
+            replace_selection_with_result,
             start_ocr_capture_cmd,
             cancel_ocr_capture_cmd,
             complete_ocr_capture_cmd,
@@ -10120,7 +18745,24 @@ pub fn run() {
             toggle_pin,
             toggle_favorite_text_cmd,
             remove_item,
-            clear_history
+            clear_history,
+            clear_response_cache_cmd,
+            import_glossary,
+            get_builtin_prompt_defaults,
+            list_translate_languages,
+            export_agents,
+            preview_agent_pack,
+            import_agents,
+            get_usage_stats,
+            list_action_log,
+            search_action_log,
+            reopen_action_log_entry,
+            rerun_action_log_entry,
+            export_action_log,
+            clear_action_log,
+            save_word_to_list,
+            list_word_list,
+            remove_word_from_list
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -61,12 +61,15 @@ import {
   PenTool,
   Pin,
   PinOff,
+  Plus,
+  Replace,
   RefreshCw,
   Rocket,
   RotateCcw,
   Ruler,
   ScanSearch,
   Search,
+  Send,
   Settings,
   Shield,
   SlidersHorizontal,
@@ -90,21 +93,35 @@ import ReactMarkdown from "react-markdown";
 import remarkBreaks from "remark-breaks";
 import remarkGfm from "remark-gfm";
 import type {
+  AppSelectionRule,
   AppSettings,
   AppSettingsPatch,
+  BilingualMode,
+  BuiltinActionConfig,
+  BuiltinActionSettings,
   BuiltinSelectionBarActionKey,
   ClipboardEntry,
   CustomAgent,
   DefaultOpenCategory,
+  ExtraHeader,
   FilterKind,
+  GenerationParams,
+  GlossaryEntry,
+  LlmProviderProfile,
   OcrActionKind,
   PasteBehavior,
+  PromptConstant,
   SelectionActionKind,
   SelectionBarActionKey,
   SelectionBarItemConfig,
   SelectionTriggerMode,
+  ReasoningDisplay,
   ThemePreset,
-  TtsRuntimeMode
+  TtsRuntimeMode,
+  AgentTool,
+  UsageBreakdownItem,
+  UsageStats,
+  WorkflowStep
 } from "./types";
 import appLogo from "../icon_transparent.png";
 
@@ -121,8 +138,16 @@ const SELECTION_MAX_CHARS_RANGE = { min: 128, max: 100000 };
 const SELECTION_BAR_OPACITY_RANGE = { min: 0.35, max: 0.94 };
 const LLM_TEMPERATURE_RANGE = { min: 0, max: 2 };
 const MODEL_MAX_TOKENS_RANGE = { min: 128, max: 8192 };
+const LLM_CHUNK_TOKENS_RANGE = { min: 0, max: 64000 };
+const LLM_CONTEXT_TOKENS_RANGE = { min: 2000, max: 2000000 };
 const MODEL_TIMEOUT_MS_RANGE = { min: 5000, max: 120000 };
 const OCR_VISION_MAX_TOKENS_RANGE = { min: 256, max: 8192 };
+const MODEL_REQUESTS_PER_MINUTE_MAX = 10000;
+const MODEL_TOKENS_PER_MINUTE_MAX = 10000000;
+const RESPONSE_CACHE_SIZE_MB_RANGE = { min: 1, max: 512 };
+const RESPONSE_CACHE_TTL_HOURS_RANGE = { min: 1, max: 2160 };
+const MONTHLY_BUDGET_USD_MAX = 100000;
+const USAGE_STATS_DAY_OPTIONS = [7, 30, 90, 365];
 const TTS_RATE_PERCENT_RANGE = { min: -50, max: 100 };
 const APPEARANCE_BLUR_RANGE = { min: 0, max: 36 };
 const APPEARANCE_SATURATE_RANGE = { min: 60, max: 220 };
@@ -133,6 +158,23 @@ const APPEARANCE_RADIUS_RANGE = { min: 4, max: 16 };
 const APPEARANCE_FONT_SCALE_RANGE = { min: 0.85, max: 1.25 };
 const APPEARANCE_PERSIST_DEBOUNCE_MS = 260;
 
+const DEFAULT_GENERATION_PARAMS: GenerationParams = {
+  topP: null,
+  frequencyPenalty: null,
+  presencePenalty: null,
+  stop: [],
+  seed: null,
+  responseFormat: "text",
+  extraBody: ""
+};
+
+const BUILTIN_ACTION_PARAM_OPTIONS: Array<{ key: keyof BuiltinActionSettings; label: string }> = [
+  { key: "summarize", label: "总结" },
+  { key: "polish", label: "润色" },
+  { key: "explain", label: "解释" },
+  { key: "translate", label: "翻译" }
+];
+
 const FALLBACK_SETTINGS: AppSettings = {
   version: 9,
   themePreset: "dark",
@@ -155,8 +197,13 @@ const FALLBACK_SETTINGS: AppSettings = {
     maxChars: 12000,
     blockedApps: [],
     defaultTranslateTo: "en-US",
+    smartTranslateTarget: true,
+    bilingualMode: "off",
+    dictionaryMode: true,
     resultWindowAlwaysOnTop: true,
-    rememberResultWindowPosition: true
+    rememberResultWindowPosition: true,
+    appRules: [],
+    smartBarSuggestions: true
   },
   llm: {
     enabled: true,
@@ -165,7 +212,14 @@ const FALLBACK_SETTINGS: AppSettings = {
     model: "gpt-4o-mini",
     temperature: 0.3,
     maxTokens: 1024,
-    timeoutMs: 30000
+    timeoutMs: 30000,
+    requestsPerMinute: 0,
+    tokensPerMinute: 0,
+    reasoningDisplay: "collapse",
+    extraHeaders: [],
+    fallbackProfiles: [],
+    chunkTokens: 3000,
+    contextTokens: 16000
   },
   tts: {
     runtimeMode: "dual-fallback",
@@ -174,7 +228,14 @@ const FALLBACK_SETTINGS: AppSettings = {
     ratePercent: 0
   },
   agents: {
+    builtin: {
+      summarize: { params: DEFAULT_GENERATION_PARAMS, systemPrompt: "", userPrompt: "" },
+      polish: { params: DEFAULT_GENERATION_PARAMS, systemPrompt: "", userPrompt: "" },
+      explain: { params: DEFAULT_GENERATION_PARAMS, systemPrompt: "", userPrompt: "" },
+      translate: { params: DEFAULT_GENERATION_PARAMS, systemPrompt: "", userPrompt: "" }
+    },
     custom: [],
+    constants: [],
     barOrder: [
       { key: "copy", enabled: true, order: 0 },
       { key: "summarize", enabled: true, order: 1 },
@@ -202,7 +263,10 @@ const FALLBACK_SETTINGS: AppSettings = {
       model: "gpt-4o-mini",
       temperature: 0,
       maxTokens: 2048,
-      timeoutMs: 30000
+      timeoutMs: 30000,
+      requestsPerMinute: 0,
+      tokensPerMinute: 0,
+      extraHeaders: []
     }
   },
   appearance: {
@@ -233,7 +297,29 @@ const FALLBACK_SETTINGS: AppSettings = {
     collapseTopBar: false,
     promoteAfterPaste: true,
     openAtTopOnShow: true,
-    storagePath: ""
+    storagePath: "",
+    recordAiActions: true
+  },
+  responseCache: {
+    enabled: true,
+    maxSizeMb: 32,
+    ttlHours: 168
+  },
+  usage: {
+    monthlyBudgetUsd: 0,
+    monthlyTokenBudget: 0,
+    priceOverrides: []
+  },
+  network: {
+    proxyUrl: "",
+    proxyUsername: "",
+    proxyPassword: "",
+    noProxy: "",
+    extraCaCertPath: ""
+  },
+  glossary: {
+    entries: [],
+    doNotTranslate: []
   }
 };
 
@@ -243,14 +329,239 @@ interface FilterOption {
   icon: LucideIcon;
 }
 
-type TranslateLanguageCode = "auto" | "zh-CN" | "en-US" | "ja-JP" | "ko-KR";
-type TranslateTargetLanguageCode = Exclude<TranslateLanguageCode, "auto">;
+// BCP-47 tags from the backend language registry, plus "auto" for source detection.
+type TranslateLanguageCode = string;
+type TranslateTargetLanguageCode = string;
+
+type SelectionContentKind =
+  | "stack-trace"
+  | "json"
+  | "code"
+  | "url"
+  | "math"
+  | "foreign-language"
+  | "long-paragraph";
 
 interface SelectionDetectedPayload {
   text: string;
   x: number;
   y: number;
   mode: SelectionTriggerMode;
+  barItems: SelectionBarActionKey[];
+  translateFrom: string | null;
+  translateTo: string | null;
+  contentKinds: SelectionContentKind[];
+  suggestedItems: SelectionBarActionKey[];
+}
+
+type ModelErrorCode =
+  | "unauthorized"
+  | "rate_limited"
+  | "timeout"
+  | "network"
+  | "provider_error"
+  | "stream_error"
+  | "empty_response"
+  | "context_too_long"
+  | "content_filtered"
+  | "budget_exceeded"
+  | "invalid_output"
+  | "tool_loop_exceeded"
+  | "canceled";
+
+interface ModelErrorPayload {
+  code: ModelErrorCode;
+  message: string;
+  status?: number | null;
+  retryAfterMs?: number | null;
+}
+
+/** Model error as returned by a rejected command; the message is localized here. */
+interface ModelCommandError {
+  code: ModelErrorCode;
+  detail?: string | null;
+  status?: number | null;
+  retryAfterMs?: number | null;
+}
+
+const MODEL_ERROR_MESSAGES: Record<ModelErrorCode, { zh: string; en: string }> = {
+  unauthorized: {
+    zh: "模型接口鉴权失败，请检查 API Key",
+    en: "Model API rejected the credentials, check the API key"
+  },
+  rate_limited: {
+    zh: "模型接口请求过于频繁，已被限流",
+    en: "Model API rate limit reached"
+  },
+  timeout: {
+    zh: "模型接口请求超时，请稍后重试",
+    en: "Model API request timed out, please retry later"
+  },
+  network: { zh: "网络连接失败", en: "Network request failed" },
+  provider_error: { zh: "模型接口调用失败", en: "Model API request failed" },
+  stream_error: {
+    zh: "模型在输出过程中返回错误",
+    en: "Model API returned an error while streaming"
+  },
+  empty_response: {
+    zh: "模型返回内容为空，请检查模型和提示词配置",
+    en: "Model returned an empty response, check the model and prompt settings"
+  },
+  context_too_long: {
+    zh: "输入内容超出模型上下文长度，请缩短文本或更换模型",
+    en: "Input exceeds the model context window, shorten the text or switch models"
+  },
+  content_filtered: {
+    zh: "模型因内容安全策略拒绝了本次请求",
+    en: "The model refused this request due to its content policy"
+  },
+  budget_exceeded: {
+    zh: "本月模型用量已达到预算上限，请在设置中调整预算后重试",
+    en: "Monthly model usage budget reached, raise the budget in settings to continue"
+  },
+  invalid_output: {
+    zh: "模型输出未通过 JSON Schema 校验",
+    en: "Model output failed JSON Schema validation"
+  },
+  tool_loop_exceeded: {
+    zh: "工具调用轮数超过上限仍未得到回答",
+    en: "The model kept calling tools without answering"
+  },
+  canceled: { zh: "请求已取消", en: "Request canceled" }
+};
+
+function describeInvokeError(error: unknown, language: string): string {
+  if (error && typeof error === "object" && "code" in error) {
+    const modelError = error as ModelCommandError;
+    const messages = MODEL_ERROR_MESSAGES[modelError.code];
+    if (messages) {
+      const base = language === "en-US" ? messages.en : messages.zh;
+      const status = modelError.status ? `（${modelError.status}）` : "";
+      return modelError.detail ? `${base}${status}: ${modelError.detail}` : `${base}${status}`;
+    }
+  }
+  return String(error);
+}
+
+interface ProviderPayload {
+  id: string;
+  name: string;
+  model: string;
+  isFallback: boolean;
+}
+
+interface FollowUpTurnPayload {
+  question: string;
+  answer: string;
+  reasoning?: string;
+}
+
+interface RequestUsagePayload {
+  model: string;
+  promptTokens: number;
+  completionTokens: number;
+  totalTokens: number;
+  estimated: boolean;
+  estimatedCostUsd?: number | null;
+}
+
+interface ChunkProgressPayload {
+  phase: "map" | "reduce" | "translate";
+  completed: number;
+  total: number;
+}
+
+interface BilingualSegmentPayload {
+  source: string;
+  translation: string;
+}
+
+interface DictionarySense {
+  partOfSpeech: string;
+  definitions: string[];
+  examples: string[];
+}
+
+interface DictionaryEntryPayload {
+  headword: string;
+  pronunciation: string;
+  language: string;
+  translation: string;
+  senses: DictionarySense[];
+  synonyms: string[];
+}
+
+interface ActionLogEntry {
+  id: string;
+  requestId: string;
+  createdAtMs: number;
+  surface: "selection" | "ocr";
+  action: string;
+  customAgentId?: string | null;
+  customAgentName?: string | null;
+  translateFrom?: string | null;
+  translateTo?: string | null;
+  sourceText: string;
+  model: string;
+  outputText: string;
+  latencyMs: number;
+  fromCache: boolean;
+  errorMessage?: string | null;
+}
+
+/** Log action names mapped to the action a rerun should request; others cannot be rerun. */
+const ACTION_LOG_RERUN_ACTIONS: Record<string, string> = {
+  summary: "summarize",
+  polish: "polish",
+  explain: "explain",
+  translate: "translate",
+  custom: "custom"
+};
+
+interface WordListEntry {
+  id: string;
+  entry: DictionaryEntryPayload;
+  context: string;
+  savedAtMs: number;
+  lookupCount: number;
+}
+
+interface GlossaryWarningPayload {
+  term: string;
+  expected: string;
+  kind: "glossary" | "do-not-translate";
+}
+
+interface BuiltinPromptTemplatePayload {
+  systemPrompt: string;
+  userPrompt: string;
+}
+
+type BuiltinPromptDefaultsPayload = Record<keyof BuiltinActionSettings, BuiltinPromptTemplatePayload> & {
+  variables: string[];
+};
+
+interface AgentPackPreviewItem {
+  id: string;
+  name: string;
+  icon: string;
+  iconSupported: boolean;
+  idConflict: boolean;
+  missingVariables: string[];
+  tools: AgentTool[];
+}
+
+interface AgentImportPayload {
+  settings: AppSettings;
+  imported: number;
+  addedConstants: string[];
+}
+
+interface GlossaryImportPayload {
+  settings: AppSettings;
+  importedEntries: number;
+  importedTerms: number;
+  skippedRows: number;
 }
 
 interface SelectionResultPayload {
@@ -258,12 +569,46 @@ interface SelectionResultPayload {
   action: string;
   sourceText: string;
   outputText: string;
+  reasoningText?: string;
   translateFrom?: string | null;
   translateTo?: string | null;
+  customAgentId?: string | null;
   customAgentName?: string | null;
   customAgentIcon?: string | null;
   isStreaming: boolean;
+  fromCache?: boolean;
+  usage?: RequestUsagePayload | null;
+  provider?: ProviderPayload | null;
+  chunkProgress?: ChunkProgressPayload | null;
+  segments?: BilingualSegmentPayload[];
+  dictionary?: DictionaryEntryPayload | null;
+  followUps?: FollowUpTurnPayload[];
+  glossaryWarnings?: GlossaryWarningPayload[];
+  workflowSteps?: WorkflowStepPayload[];
+  structuredOutput?: unknown;
+  toolCalls?: ToolCallTracePayload[];
+  diffHunks?: DiffHunkPayload[];
   errorMessage?: string | null;
+  errorDetail?: ModelErrorPayload | null;
+}
+
+interface DiffHunkPayload {
+  changed: boolean;
+  original: string;
+  revised: string;
+}
+
+interface WorkflowStepPayload {
+  label: string;
+  output: string;
+  done: boolean;
+}
+
+interface ToolCallTracePayload {
+  name: string;
+  arguments: string;
+  output: string;
+  isError: boolean;
 }
 
 interface OcrResultPayload {
@@ -271,12 +616,23 @@ interface OcrResultPayload {
   action: string;
   ocrText: string;
   outputText: string;
+  reasoningText?: string;
   translateFrom?: string | null;
   translateTo?: string | null;
+  customAgentId?: string | null;
   customAgentName?: string | null;
   customAgentIcon?: string | null;
   isStreaming: boolean;
+  fromCache?: boolean;
+  usage?: RequestUsagePayload | null;
+  provider?: ProviderPayload | null;
+  followUps?: FollowUpTurnPayload[];
+  glossaryWarnings?: GlossaryWarningPayload[];
+  workflowSteps?: WorkflowStepPayload[];
+  structuredOutput?: unknown;
+  toolCalls?: ToolCallTracePayload[];
   errorMessage?: string | null;
+  errorDetail?: ModelErrorPayload | null;
 }
 
 interface TtsSynthesizeResult {
@@ -314,6 +670,364 @@ function normalizeMarkdownForDisplay(text: string) {
   return compactOrderedHeading.replace(/\n{3,}/g, "\n\n");
 }
 
+function formatChunkProgress(progress?: ChunkProgressPayload | null): string {
+  if (!progress) return "";
+  switch (progress.phase) {
+    case "map":
+      return `正在分段摘要（${Math.min(progress.completed + 1, progress.total)}/${progress.total}）...`;
+    case "reduce":
+      return "正在合并分段摘要...";
+    default:
+      return `正在分段翻译（${Math.min(progress.completed + 1, progress.total)}/${progress.total}）...`;
+  }
+}
+
+function GlossaryWarnings({ warnings }: { warnings?: GlossaryWarningPayload[] }) {
+  if (!warnings?.length) return null;
+
+  return (
+    <div className="result-glossary-warnings">
+      {warnings.map((item) => (
+        <span key={`${item.kind}-${item.term}`}>
+          {item.kind === "glossary"
+            ? `术语未按词表翻译：${item.term} → ${item.expected}`
+            : `应保留原文：${item.term}`}
+        </span>
+      ))}
+    </div>
+  );
+}
+
+function DictionaryCard({
+  entry,
+  saved,
+  onSave
+}: {
+  entry: DictionaryEntryPayload;
+  saved: boolean;
+  onSave: () => void;
+}) {
+  return (
+    <div className="selection-result-text dictionary-card">
+      <div className="dictionary-head">
+        <strong>{entry.headword}</strong>
+        {entry.pronunciation ? (
+          <span className="dictionary-pronunciation">/{entry.pronunciation.replace(/^\/|\/$/g, "")}/</span>
+        ) : null}
+        <button className="tonal-btn dictionary-save-btn" onClick={onSave} disabled={saved}>
+          <BookOpen size={13} />
+          <span>{saved ? "已加入生词本" : "加入生词本"}</span>
+        </button>
+      </div>
+      {entry.translation ? <p className="dictionary-translation">{entry.translation}</p> : null}
+      {entry.senses.map((sense, index) => (
+        <section className="dictionary-sense" key={`sense-${index}`}>
+          {sense.partOfSpeech ? <em>{sense.partOfSpeech}</em> : null}
+          <ol>
+            {sense.definitions.map((definition, definitionIndex) => (
+              <li key={`definition-${definitionIndex}`}>{definition}</li>
+            ))}
+          </ol>
+          {sense.examples.map((example, exampleIndex) => (
+            <p className="dictionary-example" key={`example-${exampleIndex}`}>
+              {example}
+            </p>
+          ))}
+        </section>
+      ))}
+      {entry.synonyms.length ? (
+        <p className="dictionary-synonyms">近义词：{entry.synonyms.join(", ")}</p>
+      ) : null}
+    </div>
+  );
+}
+
+interface StructuredOutputTableData {
+  columns: string[];
+  rows: string[][];
+}
+
+function structuredCellText(value: unknown) {
+  if (value === null || value === undefined) return "";
+  return typeof value === "object" ? JSON.stringify(value) : String(value);
+}
+
+function isJsonRecord(value: unknown): value is Record<string, unknown> {
+  return typeof value === "object" && value !== null && !Array.isArray(value);
+}
+
+// Arrays of objects become one row per item; a single object becomes field/value rows.
+function structuredOutputTable(value: unknown): StructuredOutputTableData {
+  if (Array.isArray(value)) {
+    if (value.length && value.every(isJsonRecord)) {
+      const columns = Array.from(new Set(value.flatMap((item) => Object.keys(item))));
+      return {
+        columns,
+        rows: value.map((item) => columns.map((column) => structuredCellText(item[column])))
+      };
+    }
+    return { columns: ["值"], rows: value.map((item) => [structuredCellText(item)]) };
+  }
+  if (isJsonRecord(value)) {
+    return {
+      columns: ["字段", "值"],
+      rows: Object.entries(value).map(([key, item]) => [key, structuredCellText(item)])
+    };
+  }
+  return { columns: ["值"], rows: [[structuredCellText(value)]] };
+}
+
+function structuredOutputCsv(table: StructuredOutputTableData) {
+  const escape = (cell: string) => (/[",\r\n]/.test(cell) ? `"${cell.replace(/"/g, '""')}"` : cell);
+  return [table.columns, ...table.rows].map((row) => row.map(escape).join(",")).join("\n");
+}
+
+function structuredOutputMarkdown(table: StructuredOutputTableData) {
+  const escape = (cell: string) => cell.replace(/\|/g, "\\|").replace(/\r?\n/g, "<br>");
+  return [table.columns, table.columns.map(() => "---"), ...table.rows]
+    .map((row) => `| ${row.map(escape).join(" | ")} |`)
+    .join("\n");
+}
+
+const STRUCTURED_COPY_FORMATS = [
+  { key: "json", label: "JSON" },
+  { key: "csv", label: "CSV" },
+  { key: "markdown", label: "Markdown" }
+] as const;
+
+function StructuredOutputTable({ value }: { value: unknown }) {
+  const table = useMemo(() => structuredOutputTable(value), [value]);
+  const [copiedFormat, setCopiedFormat] = useState<string | null>(null);
+
+  async function copyAs(format: (typeof STRUCTURED_COPY_FORMATS)[number]["key"]) {
+    const text =
+      format === "json"
+        ? JSON.stringify(value, null, 2)
+        : format === "csv"
+          ? structuredOutputCsv(table)
+          : structuredOutputMarkdown(table);
+    try {
+      await navigator.clipboard.writeText(text);
+      setCopiedFormat(format);
+    } catch (error) {
+      console.error("[StructuredOutput] copy failed:", error);
+    }
+  }
+
+  return (
+    <div className="selection-result-text structured-output">
+      <div className="structured-output-actions">
+        {STRUCTURED_COPY_FORMATS.map((item) => (
+          <button className="tonal-btn compact" key={item.key} onClick={() => void copyAs(item.key)}>
+            <Copy size={12} />
+            <span>{copiedFormat === item.key ? "已复制" : `复制 ${item.label}`}</span>
+          </button>
+        ))}
+      </div>
+      <div className="structured-output-scroll">
+        <table>
+          <thead>
+            <tr>
+              {table.columns.map((column) => (
+                <th key={column}>{column}</th>
+              ))}
+            </tr>
+          </thead>
+          <tbody>
+            {table.rows.map((row, rowIndex) => (
+              <tr key={`row-${rowIndex}`}>
+                {row.map((cell, cellIndex) => (
+                  <td key={`cell-${rowIndex}-${cellIndex}`}>{cell}</td>
+                ))}
+              </tr>
+            ))}
+          </tbody>
+        </table>
+      </div>
+    </div>
+  );
+}
+
+function ToolCallTrace({ calls }: { calls?: ToolCallTracePayload[] }) {
+  if (!calls?.length) return null;
+
+  return (
+    <details className="result-tool-calls">
+      <summary>调用了 {calls.length} 次工具</summary>
+      {calls.map((call, index) => (
+        <div className={`result-tool-call${call.isError ? " error" : ""}`} key={`tool-call-${index}`}>
+          <code>
+            {AGENT_TOOL_OPTIONS.find((item) => item.functionName === call.name)?.label ?? call.name}
+            {call.arguments && call.arguments !== "{}" ? ` ${call.arguments}` : ""}
+          </code>
+          <div className="result-reasoning-text">{call.output}</div>
+        </div>
+      ))}
+    </details>
+  );
+}
+
+// Rejected hunks keep the original text; everything else takes the polished revision.
+function applyDiffHunks(hunks: DiffHunkPayload[], rejected: Set<number>) {
+  return hunks
+    .map((hunk, index) => (hunk.changed && rejected.has(index) ? hunk.original : hunk.revised))
+    .join("");
+}
+
+function PolishDiff({
+  hunks,
+  rejected,
+  onChange
+}: {
+  hunks: DiffHunkPayload[] | null;
+  rejected: Set<number>;
+  onChange: (next: Set<number>) => void;
+}) {
+  if (!hunks) return null;
+  const changedIndexes = hunks.flatMap((hunk, index) => (hunk.changed ? [index] : []));
+
+  function toggle(index: number) {
+    const next = new Set(rejected);
+    if (next.has(index)) {
+      next.delete(index);
+    } else {
+      next.add(index);
+    }
+    onChange(next);
+  }
+
+  return (
+    <details className="result-polish-diff">
+      <summary>
+        修改对比：{changedIndexes.length} 处，已接受 {changedIndexes.length - rejected.size} 处
+      </summary>
+      <div className="polish-diff-actions">
+        <button className="tonal-btn compact" onClick={() => onChange(new Set())}>
+          全部接受
+        </button>
+        <button className="tonal-btn compact" onClick={() => onChange(new Set(changedIndexes))}>
+          全部拒绝
+        </button>
+      </div>
+      <div className="polish-diff-text">
+        {hunks.map((hunk, index) =>
+          hunk.changed ? (
+            <button
+              key={`diff-hunk-${index}`}
+              className={`polish-diff-hunk${rejected.has(index) ? " rejected" : ""}`}
+              title={rejected.has(index) ? "已拒绝，点击接受" : "已接受，点击拒绝"}
+              onClick={() => toggle(index)}
+            >
+              {hunk.original && <del>{hunk.original}</del>}
+              {hunk.revised && <ins>{hunk.revised}</ins>}
+            </button>
+          ) : (
+            <span key={`diff-hunk-${index}`}>{hunk.original}</span>
+          )
+        )}
+      </div>
+    </details>
+  );
+}
+
+function WorkflowSteps({ steps }: { steps?: WorkflowStepPayload[] }) {
+  if (!steps?.length) return null;
+
+  return (
+    <div className="result-workflow-steps">
+      {steps.map((step, index) => (
+        <details className="result-workflow-step" key={`workflow-step-${index}`}>
+          <summary>
+            {index + 1}/{steps.length} {step.label}
+            {step.done ? "" : "（进行中）"}
+          </summary>
+          <div className="result-reasoning-text">{step.output}</div>
+        </details>
+      ))}
+    </div>
+  );
+}
+
+function FollowUpThread({
+  turns,
+  streaming,
+  error,
+  display,
+  onSend
+}: {
+  turns?: FollowUpTurnPayload[];
+  streaming: boolean;
+  error: string;
+  display: ReasoningDisplay;
+  onSend: (message: string) => Promise<boolean>;
+}) {
+  const [draft, setDraft] = useState("");
+
+  async function submit() {
+    const message = draft.trim();
+    if (!message || streaming) return;
+    if (await onSend(message)) {
+      setDraft("");
+    }
+  }
+
+  return (
+    <div className="result-follow-ups">
+      {turns?.length ? (
+        <div className="result-follow-up-turns">
+          {turns.map((turn, index) => (
+            <div className="result-follow-up-turn" key={`follow-up-${index}`}>
+              <p className="result-follow-up-question">{turn.question}</p>
+              <ReasoningBlock text={turn.reasoning} display={display} />
+              <MarkdownText
+                className="markdown-render-body"
+                text={turn.answer || (streaming && index === turns.length - 1 ? "正在处理..." : "")}
+              />
+            </div>
+          ))}
+        </div>
+      ) : null}
+      {error && <span className="result-follow-up-error">{error}</span>}
+      <form
+        className="result-follow-up-input"
+        onSubmit={(event) => {
+          event.preventDefault();
+          void submit();
+        }}
+      >
+        <input
+          className="filled-control"
+          value={draft}
+          placeholder="继续追问..."
+          disabled={streaming}
+          onChange={(event) => setDraft(event.target.value)}
+        />
+        <button
+          type="submit"
+          className="icon-btn"
+          aria-label="Send follow-up"
+          title="发送"
+          disabled={streaming || !draft.trim()}
+        >
+          <Send size={14} />
+        </button>
+      </form>
+    </div>
+  );
+}
+
+function ReasoningBlock({ text, display }: { text?: string; display: ReasoningDisplay }) {
+  const reasoning = text?.trim() || "";
+  if (!reasoning || display === "hide") return null;
+
+  return (
+    <details className="result-reasoning" open={display === "show"}>
+      <summary>思考过程</summary>
+      <div className="result-reasoning-text">{reasoning}</div>
+    </details>
+  );
+}
+
 function MarkdownText({ text, className }: { text: string; className?: string }) {
   const normalizedText = useMemo(() => normalizeMarkdownForDisplay(text || ""), [text]);
 
@@ -402,6 +1116,14 @@ const TTS_RUNTIME_MODE_OPTIONS: Array<{ key: TtsRuntimeMode; label: string }> =
   { key: "python-module-only", label: "仅 python -m edge_tts" }
 ];
 
+const LLM_FALLBACK_PROFILES_MAX = 8;
+
+const REASONING_DISPLAY_OPTIONS: Array<{ key: ReasoningDisplay; label: string }> = [
+  { key: "hide", label: "隐藏思考过程" },
+  { key: "collapse", label: "折叠显示（推荐）" },
+  { key: "show", label: "完整展开" }
+];
+
 const TTS_ZH_VOICE_OPTIONS = [
   { key: "zh-CN-XiaoxiaoNeural", label: "Xiaoxiao (女声)" },
   { key: "zh-CN-YunxiNeural", label: "Yunxi (男声)" },
@@ -416,13 +1138,45 @@ const TTS_EN_VOICE_OPTIONS = [
   { key: "en-US-DavisNeural", label: "Davis (Male)" }
 ];
 
-const TRANSLATE_LANGUAGE_OPTIONS: Array<{ key: TranslateLanguageCode; label: string }> = [
-  { key: "auto", label: "自动检测" },
-  { key: "zh-CN", label: "简体中文" },
-  { key: "en-US", label: "English" },
-  { key: "ja-JP", label: "日本語" },
-  { key: "ko-KR", label: "한국어" }
-];
+interface LanguageOptionPayload {
+  code: string;
+  englishName: string;
+  nativeName: string;
+}
+
+interface TranslateLanguageOption {
+  key: TranslateLanguageCode;
+  label: string;
+}
+
+const AUTO_LANGUAGE_OPTION: TranslateLanguageOption = { key: "auto", label: "自动检测" };
+
+let translateLanguageOptionsRequest: Promise<TranslateLanguageOption[]> | null = null;
+
+/** Translation targets from the backend language registry, fetched once per window. */
+function useTranslateLanguageOptions() {
+  const [options, setOptions] = useState<TranslateLanguageOption[]>([]);
+
+  useEffect(() => {
+    let active = true;
+    translateLanguageOptionsRequest ??= invoke<LanguageOptionPayload[]>(
+      "list_translate_languages"
+    ).then((languages) => languages.map((item) => ({ key: item.code, label: item.nativeName })));
+    void translateLanguageOptionsRequest
+      .then((next) => {
+        if (active) setOptions(next);
+      })
+      .catch((invokeError) => {
+        translateLanguageOptionsRequest = null;
+        console.error("[Languages] load failed:", invokeError);
+      });
+    return () => {
+      active = false;
+    };
+  }, []);
+
+  return options;
+}
 
 const SETTING_GROUPS: SettingGroup[] = [
   {
@@ -481,13 +1235,16 @@ const BUILTIN_SELECTION_BAR_ACTIONS: Array<{
   icon: LucideIcon;
   action?: SelectionActionKind;
   direct?: "copy" | "search";
+  // Pastes the result over the selection instead of opening the result window.
+  replace?: boolean;
 }> = [
   { key: "copy", label: "复制", icon: Copy, direct: "copy" },
   { key: "summarize", label: "总结", icon: ScanSearch, action: "summarize" },
   { key: "polish", label: "优化", icon: SlidersHorizontal, action: "polish" },
   { key: "explain", label: "解释", icon: Info, action: "explain" },
   { key: "translate", label: "翻译", icon: Languages, action: "translate" },
-  { key: "search", label: "搜索", icon: Search, direct: "search" }
+  { key: "search", label: "搜索", icon: Search, direct: "search" },
+  { key: "polish-replace", label: "优化替换", icon: Replace, action: "polish", replace: true }
 ];
 
 const BUILTIN_SELECTION_BAR_ACTION_MAP = BUILTIN_SELECTION_BAR_ACTIONS.reduce(
@@ -568,6 +1325,43 @@ const CUSTOM_AGENT_ICON_MAP: Record<string, LucideIcon> = CUSTOM_AGENT_ICON_OPTI
 const CUSTOM_AGENT_MAX_COUNT = 30;
 const CUSTOM_AGENT_NAME_MAX_UNITS = 8;
 const CUSTOM_AGENT_PROMPT_TEMPLATE = "Process the following text according to my instruction:\n{text}";
+const MAX_WORKFLOW_STEPS = 8;
+const WORKFLOW_STEP_KIND_OPTIONS: Array<{ key: WorkflowStep["kind"]; label: string }> = [
+  { key: "builtin", label: "内置动作" },
+  { key: "prompt", label: "自定义提示词" },
+  { key: "transform", label: "本地处理" }
+];
+const WORKFLOW_BUILTIN_OPTIONS: Array<{ key: WorkflowStep["action"]; label: string }> = [
+  { key: "translate", label: "翻译" },
+  { key: "summarize", label: "总结" },
+  { key: "polish", label: "润色" },
+  { key: "explain", label: "解释" }
+];
+const LOCAL_TRANSFORM_OPTIONS: Array<{ key: WorkflowStep["transform"]; label: string }> = [
+  { key: "fix-line-breaks", label: "修复换行" },
+  { key: "collapse-whitespace", label: "合并空白" },
+  { key: "remove-empty-lines", label: "删除空行" },
+  { key: "strip-markdown", label: "去除 Markdown" }
+];
+const AGENT_TOOL_OPTIONS: Array<{ key: AgentTool; functionName: string; label: string }> = [
+  { key: "read-clipboard", functionName: "read_clipboard", label: "读取剪贴板" },
+  { key: "search-clipboard-history", functionName: "search_clipboard_history", label: "搜索剪贴板历史" },
+  { key: "current-datetime", functionName: "get_current_datetime", label: "获取日期时间" },
+  { key: "evaluate-math", functionName: "evaluate_math", label: "计算数学表达式" },
+  { key: "open-url", functionName: "open_url", label: "在浏览器中打开链接" },
+  { key: "save-snippet", functionName: "save_snippet", label: "保存片段到收藏" }
+];
+const CUSTOM_AGENT_TEMPLATE_VARIABLES = [
+  "text",
+  "ui_language",
+  "target_language",
+  "clipboard",
+  "source_app",
+  "window_title",
+  "date",
+  "time",
+  "app_name"
+];
 const MAX_SELECTION_BAR_ENABLED_ITEMS = 8;
 
 function isCjkChar(char: string) {
@@ -607,7 +1401,8 @@ function isBuiltinSelectionBarActionKey(value: string): value is BuiltinSelectio
 function defaultSelectionBarOrder(customAgents: CustomAgent[]): SelectionBarItemConfig[] {
   const builtins = BUILTIN_SELECTION_BAR_ACTIONS.map((item, index) => ({
     key: item.key as SelectionBarActionKey,
-    enabled: true,
+    // Writing into the source application is opt-in.
+    enabled: !item.replace,
     order: index
   }));
   const custom = customAgents.map((agent, index) => ({
@@ -630,8 +1425,13 @@ function normalizeCustomAgents(input: CustomAgent[]) {
         name: normalizedName || fallbackName,
         icon: item.icon?.trim() || "Sparkles",
         prompt: item.prompt ?? "",
+        systemPrompt: item.systemPrompt ?? "",
+        steps: Array.isArray(item.steps) ? item.steps.slice(0, MAX_WORKFLOW_STEPS) : [],
+        outputSchema: item.outputSchema ?? "",
+        tools: Array.isArray(item.tools) ? item.tools : [],
         enabled: true,
-        order: index
+        order: index,
+        params: sanitizeGenerationParams(item.params)
       };
     })
     .slice(0, CUSTOM_AGENT_MAX_COUNT);
@@ -697,11 +1497,147 @@ function makeDefaultCustomAgent(index: number): CustomAgent {
     name: `Agent ${index + 1}`,
     icon: "Sparkles",
     prompt: CUSTOM_AGENT_PROMPT_TEMPLATE,
+    systemPrompt: "",
+    steps: [],
+    outputSchema: "",
+    tools: [],
     enabled: true,
-    order: index
+    order: index,
+    params: DEFAULT_GENERATION_PARAMS
+  };
+}
+
+function sanitizeGenerationParams(input: Partial<GenerationParams> | undefined): GenerationParams {
+  const optionalNumber = (value: unknown) =>
+    typeof value === "number" && Number.isFinite(value) ? value : null;
+  return {
+    topP: optionalNumber(input?.topP),
+    frequencyPenalty: optionalNumber(input?.frequencyPenalty),
+    presencePenalty: optionalNumber(input?.presencePenalty),
+    stop: Array.isArray(input?.stop) ? input.stop.filter((item) => typeof item === "string" && item) : [],
+    seed: optionalNumber(input?.seed),
+    responseFormat: input?.responseFormat === "json-object" ? "json-object" : "text",
+    extraBody: typeof input?.extraBody === "string" ? input.extraBody : ""
+  };
+}
+
+function sanitizeBuiltinActionConfig(
+  input: Partial<BuiltinActionConfig> | undefined
+): BuiltinActionConfig {
+  return {
+    params: sanitizeGenerationParams(input?.params),
+    systemPrompt: typeof input?.systemPrompt === "string" ? input.systemPrompt : "",
+    userPrompt: typeof input?.userPrompt === "string" ? input.userPrompt : ""
   };
 }
 
+function GenerationParamsEditor({
+  idPrefix,
+  value,
+  onChange
+}: {
+  idPrefix: string;
+  value: GenerationParams;
+  onChange: (next: GenerationParams) => void;
+}) {
+  const [drafts, setDrafts] = useState<Partial<Record<string, string>>>({});
+  const numberFields: Array<{
+    key: "topP" | "frequencyPenalty" | "presencePenalty" | "seed";
+    label: string;
+    step: number;
+  }> = [
+    { key: "topP", label: "top_p（0 ~ 1）", step: 0.05 },
+    { key: "frequencyPenalty", label: "frequency_penalty（-2 ~ 2）", step: 0.1 },
+    { key: "presencePenalty", label: "presence_penalty（-2 ~ 2）", step: 0.1 },
+    { key: "seed", label: "seed", step: 1 }
+  ];
+
+  function commitDraft(key: string, apply: (text: string) => GenerationParams) {
+    const draft = drafts[key];
+    if (draft === undefined) return;
+    setDrafts((prev) => ({ ...prev, [key]: undefined }));
+    onChange(apply(draft));
+  }
+
+  return (
+    <div className="generation-params-editor">
+      {numberFields.map((field) => (
+        <div className="filled-control" key={field.key}>
+          <label htmlFor={`${idPrefix}-${field.key}`}>{field.label}</label>
+          <input
+            id={`${idPrefix}-${field.key}`}
+            type="number"
+            step={field.step}
+            placeholder="留空使用模型默认值"
+            value={drafts[field.key] ?? (value[field.key] === null ? "" : String(value[field.key]))}
+            onChange={(event) => {
+              const text = event.target.value;
+              setDrafts((prev) => ({ ...prev, [field.key]: text }));
+            }}
+            onBlur={() =>
+              commitDraft(field.key, (text) => {
+                const parsed = text.trim() === "" ? null : Number(text);
+                const next = parsed === null || !Number.isFinite(parsed) ? null : parsed;
+                return {
+                  ...value,
+                  [field.key]: field.key === "seed" && next !== null ? Math.round(next) : next
+                };
+              })
+            }
+          />
+        </div>
+      ))}
+      <div className="filled-control">
+        <label htmlFor={`${idPrefix}-stop`}>停止序列（每行一个，最多 4 个）</label>
+        <textarea
+          id={`${idPrefix}-stop`}
+          className="settings-json"
+          value={drafts.stop ?? value.stop.join("\n")}
+          onChange={(event) => {
+            const text = event.target.value;
+            setDrafts((prev) => ({ ...prev, stop: text }));
+          }}
+          onBlur={() =>
+            commitDraft("stop", (text) => ({
+              ...value,
+              stop: text.split(/\r?\n/).filter((item) => item.length > 0)
+            }))
+          }
+        />
+      </div>
+      <label className="check-row">
+        <span>JSON 模式（response_format: json_object）</span>
+        <input
+          className="md2-check"
+          type="checkbox"
+          checked={value.responseFormat === "json-object"}
+          onChange={(event) => {
+            onChange({ ...value, responseFormat: event.target.checked ? "json-object" : "text" });
+          }}
+        />
+      </label>
+      <div className="filled-control">
+        <label htmlFor={`${idPrefix}-extra-body`}>额外请求体（JSON 对象，合并到请求中）</label>
+        <textarea
+          id={`${idPrefix}-extra-body`}
+          className="settings-json"
+          placeholder='例如：{"enable_thinking": false}'
+          value={drafts.extraBody ?? value.extraBody}
+          onChange={(event) => {
+            const text = event.target.value;
+            setDrafts((prev) => ({ ...prev, extraBody: text }));
+          }}
+          onBlur={() => commitDraft("extraBody", (text) => ({ ...value, extraBody: text }))}
+        />
+      </div>
+      <p className="help-text">
+        不能覆盖 model、messages、stream、temperature、max_tokens、top_p、tools、tool_choice 和
+        response_format 等由设置控制的字段。
+      </p>
+    </div>
+  );
+}
+
 function clampPollMs(value: number) {
   if (!Number.isFinite(value)) return FALLBACK_SETTINGS.history.pollMs;
   return Math.min(POLL_MS_RANGE.max, Math.max(POLL_MS_RANGE.min, Math.round(value)));
@@ -796,24 +1732,84 @@ function parseTtsRuntimeMode(value: unknown): TtsRuntimeMode {
     : "dual-fallback";
 }
 
-function parseTranslateTarget(
-  value: unknown,
-  fallback: TranslateTargetLanguageCode = FALLBACK_SETTINGS.selectionAssistant.defaultTranslateTo
-): TranslateTargetLanguageCode {
-  const allowed: TranslateTargetLanguageCode[] = ["zh-CN", "en-US", "ja-JP", "ko-KR"];
-  return allowed.includes(value as TranslateTargetLanguageCode)
-    ? (value as TranslateTargetLanguageCode)
-    : fallback;
+function sanitizeExtraHeaders(value: unknown): ExtraHeader[] {
+  if (!Array.isArray(value)) return [];
+  return value
+    .filter((item): item is ExtraHeader => typeof item?.name === "string")
+    .map((item) => ({ name: item.name.trim(), value: String(item.value ?? "").trim() }))
+    .filter((item) => item.name);
 }
 
-function parseRequestId(value: string): bigint | null {
-  const trimmed = value.trim();
-  if (!trimmed || !/^\d+$/.test(trimmed)) return null;
-  try {
-    return BigInt(trimmed);
-  } catch {
-    return null;
-  }
+function sanitizeFallbackProfiles(value: unknown): LlmProviderProfile[] {
+  if (!Array.isArray(value)) return [];
+  return value
+    .filter((item): item is LlmProviderProfile => Boolean(item) && typeof item === "object")
+    .map((item, index) => ({
+      id: item.id?.trim() || `provider-${Date.now()}-${index}`,
+      name: item.name ?? "",
+      enabled: item.enabled ?? true,
+      baseUrl: item.baseUrl ?? "",
+      apiKey: item.apiKey ?? "",
+      model: item.model ?? "",
+      extraHeaders: sanitizeExtraHeaders(item.extraHeaders)
+    }))
+    .slice(0, LLM_FALLBACK_PROFILES_MAX);
+}
+
+function formatExtraHeaders(headers: ExtraHeader[]): string {
+  return headers.map((item) => `${item.name}: ${item.value}`).join("\n");
+}
+
+function parseExtraHeaders(text: string): ExtraHeader[] {
+  return text
+    .split(/\r?\n/)
+    .map((line) => {
+      const separator = line.indexOf(":");
+      if (separator <= 0) return null;
+      return {
+        name: line.slice(0, separator).trim(),
+        value: line.slice(separator + 1).trim()
+      };
+    })
+    .filter((item): item is ExtraHeader => Boolean(item?.name));
+}
+
+function parseReasoningDisplay(value: unknown): ReasoningDisplay {
+  return REASONING_DISPLAY_OPTIONS.some((item) => item.key === value)
+    ? (value as ReasoningDisplay)
+    : "collapse";
+}
+
+const BILINGUAL_MODE_OPTIONS: Array<{ key: BilingualMode; label: string }> = [
+  { key: "off", label: "仅译文" },
+  { key: "paragraph", label: "逐段对照" },
+  { key: "sentence", label: "逐句对照" }
+];
+
+function parseBilingualMode(value: unknown): BilingualMode {
+  return BILINGUAL_MODE_OPTIONS.some((item) => item.key === value)
+    ? (value as BilingualMode)
+    : "off";
+}
+
+function parseTranslateTarget(
+  value: unknown,
+  fallback: TranslateTargetLanguageCode = FALLBACK_SETTINGS.selectionAssistant.defaultTranslateTo
+): TranslateTargetLanguageCode {
+  // The backend maps unknown codes back to its default, so only the tag shape is checked here.
+  return typeof value === "string" && /^[a-z]{2,3}(-[A-Za-z0-9]{2,8})*$/.test(value)
+    ? (value as TranslateTargetLanguageCode)
+    : fallback;
+}
+
+function parseRequestId(value: string): bigint | null {
+  const trimmed = value.trim();
+  if (!trimmed || !/^\d+$/.test(trimmed)) return null;
+  try {
+    return BigInt(trimmed);
+  } catch {
+    return null;
+  }
 }
 
 function isIncomingRequestFresh(currentId: string, incomingId: string): boolean {
@@ -855,6 +1851,32 @@ function formatBytes(bytes: number) {
   return `${value.toFixed(fractionDigits)} ${units[unitIndex]}`;
 }
 
+function formatUsageTokens(tokens: number) {
+  return `${tokens.toLocaleString()} tokens`;
+}
+
+function formatUsageCost(usd: number) {
+  return `$${usd.toFixed(usd >= 1 ? 2 : 4)}`;
+}
+
+function UsageBreakdownList({ items }: { items: UsageBreakdownItem[] }) {
+  return (
+    <ul className="word-list">
+      {items.map((item) => (
+        <li className="word-list-row" key={item.key}>
+          <div className="action-log-summary">
+            <strong>{item.key || "未知"}</strong>
+            <p className="help-text">
+              {item.requests} 次请求 · {formatUsageTokens(item.totalTokens)} ·{" "}
+              {formatUsageCost(item.estimatedCostUsd)}
+            </p>
+          </div>
+        </li>
+      ))}
+    </ul>
+  );
+}
+
 function kindLabel(kind: ClipboardEntry["kind"]) {
   if (kind === "image") return "Image";
   if (kind === "link") return "Link";
@@ -1067,6 +2089,12 @@ function sanitizeSettings(input: AppSettings): AppSettings {
             .filter((item) => item.length > 0)
         : [],
       defaultTranslateTo: parseTranslateTarget(input.selectionAssistant?.defaultTranslateTo),
+      // Settings saved before the option existed keep their fixed translation target.
+      smartTranslateTarget: input.selectionAssistant?.smartTranslateTarget ?? false,
+      bilingualMode: parseBilingualMode(input.selectionAssistant?.bilingualMode),
+      dictionaryMode:
+        input.selectionAssistant?.dictionaryMode ??
+        FALLBACK_SETTINGS.selectionAssistant.dictionaryMode,
       resultWindowAlwaysOnTop: Boolean(
         input.selectionAssistant?.resultWindowAlwaysOnTop ??
           FALLBACK_SETTINGS.selectionAssistant.resultWindowAlwaysOnTop
@@ -1075,7 +2103,23 @@ function sanitizeSettings(input: AppSettings): AppSettings {
         input.selectionAssistant?.rememberResultWindowPosition ??
           legacyRememberWindowPosition ??
           FALLBACK_SETTINGS.selectionAssistant.rememberResultWindowPosition
-      )
+      ),
+      appRules: Array.isArray(input.selectionAssistant?.appRules)
+        ? input.selectionAssistant.appRules
+            .filter((rule) => typeof rule?.app === "string" && rule.app.trim().length > 0)
+            .map((rule) => ({
+              id: String(rule.id ?? ""),
+              app: rule.app.trim().toLowerCase(),
+              enabled: rule.enabled ?? true,
+              barItems: Array.isArray(rule.barItems) ? rule.barItems : [],
+              autoRun: rule.autoRun ?? "",
+              translateFrom: rule.translateFrom ?? "",
+              translateTo: rule.translateTo ?? ""
+            }))
+        : [],
+      smartBarSuggestions:
+        input.selectionAssistant?.smartBarSuggestions ??
+        FALLBACK_SETTINGS.selectionAssistant.smartBarSuggestions
     },
     llm: {
       enabled: true,
@@ -1105,6 +2149,41 @@ function sanitizeSettings(input: AppSettings): AppSettings {
           Number(input.llm?.timeoutMs ?? FALLBACK_SETTINGS.llm.timeoutMs) ||
             FALLBACK_SETTINGS.llm.timeoutMs
         )
+      ),
+      requestsPerMinute: Math.round(
+        clampNumberValue(
+          input.llm?.requestsPerMinute,
+          0,
+          MODEL_REQUESTS_PER_MINUTE_MAX,
+          FALLBACK_SETTINGS.llm.requestsPerMinute
+        )
+      ),
+      tokensPerMinute: Math.round(
+        clampNumberValue(
+          input.llm?.tokensPerMinute,
+          0,
+          MODEL_TOKENS_PER_MINUTE_MAX,
+          FALLBACK_SETTINGS.llm.tokensPerMinute
+        )
+      ),
+      reasoningDisplay: parseReasoningDisplay(input.llm?.reasoningDisplay),
+      extraHeaders: sanitizeExtraHeaders(input.llm?.extraHeaders),
+      fallbackProfiles: sanitizeFallbackProfiles(input.llm?.fallbackProfiles),
+      chunkTokens: Math.round(
+        clampNumberValue(
+          input.llm?.chunkTokens,
+          LLM_CHUNK_TOKENS_RANGE.min,
+          LLM_CHUNK_TOKENS_RANGE.max,
+          FALLBACK_SETTINGS.llm.chunkTokens
+        )
+      ),
+      contextTokens: Math.round(
+        clampNumberValue(
+          input.llm?.contextTokens,
+          LLM_CONTEXT_TOKENS_RANGE.min,
+          LLM_CONTEXT_TOKENS_RANGE.max,
+          FALLBACK_SETTINGS.llm.contextTokens
+        )
       )
     },
     tts: {
@@ -1124,7 +2203,18 @@ function sanitizeSettings(input: AppSettings): AppSettings {
     },
     agents: {
       custom: sanitizedCustomAgents,
-      barOrder: sanitizedBarOrder
+      barOrder: sanitizedBarOrder,
+      constants: Array.isArray(input.agents?.constants)
+        ? input.agents.constants
+            .filter((item) => typeof item?.name === "string")
+            .map((item) => ({ name: item.name, value: String(item.value ?? "") }))
+        : [],
+      builtin: {
+        summarize: sanitizeBuiltinActionConfig(input.agents?.builtin?.summarize),
+        polish: sanitizeBuiltinActionConfig(input.agents?.builtin?.polish),
+        explain: sanitizeBuiltinActionConfig(input.agents?.builtin?.explain),
+        translate: sanitizeBuiltinActionConfig(input.agents?.builtin?.translate)
+      }
     },
     shortcuts: {
       toggleMain: input.shortcuts?.toggleMain?.trim() || FALLBACK_SHORTCUT,
@@ -1178,7 +2268,24 @@ function sanitizeSettings(input: AppSettings): AppSettings {
             Number(input.ocr?.vision?.timeoutMs ?? FALLBACK_SETTINGS.ocr.vision.timeoutMs) ||
               FALLBACK_SETTINGS.ocr.vision.timeoutMs
           )
-        )
+        ),
+        requestsPerMinute: Math.round(
+          clampNumberValue(
+            input.ocr?.vision?.requestsPerMinute,
+            0,
+            MODEL_REQUESTS_PER_MINUTE_MAX,
+            FALLBACK_SETTINGS.ocr.vision.requestsPerMinute
+          )
+        ),
+        tokensPerMinute: Math.round(
+          clampNumberValue(
+            input.ocr?.vision?.tokensPerMinute,
+            0,
+            MODEL_TOKENS_PER_MINUTE_MAX,
+            FALLBACK_SETTINGS.ocr.vision.tokensPerMinute
+          )
+        ),
+        extraHeaders: sanitizeExtraHeaders(input.ocr?.vision?.extraHeaders)
       }
     },
     appearance: {
@@ -1280,7 +2387,65 @@ function sanitizeSettings(input: AppSettings): AppSettings {
           legacyOpenAtTopOnShow ??
           FALLBACK_SETTINGS.history.openAtTopOnShow
       ),
-      storagePath: input.history.storagePath?.trim() || ""
+      storagePath: input.history.storagePath?.trim() || "",
+      recordAiActions: Boolean(
+        input.history.recordAiActions ?? FALLBACK_SETTINGS.history.recordAiActions
+      )
+    },
+    responseCache: {
+      enabled: Boolean(
+        input.responseCache?.enabled ?? FALLBACK_SETTINGS.responseCache.enabled
+      ),
+      maxSizeMb: Math.round(
+        clampNumberValue(
+          input.responseCache?.maxSizeMb,
+          RESPONSE_CACHE_SIZE_MB_RANGE.min,
+          RESPONSE_CACHE_SIZE_MB_RANGE.max,
+          FALLBACK_SETTINGS.responseCache.maxSizeMb
+        )
+      ),
+      ttlHours: Math.round(
+        clampNumberValue(
+          input.responseCache?.ttlHours,
+          RESPONSE_CACHE_TTL_HOURS_RANGE.min,
+          RESPONSE_CACHE_TTL_HOURS_RANGE.max,
+          FALLBACK_SETTINGS.responseCache.ttlHours
+        )
+      )
+    },
+    usage: {
+      monthlyBudgetUsd: clampNumberValue(
+        input.usage?.monthlyBudgetUsd,
+        0,
+        MONTHLY_BUDGET_USD_MAX,
+        FALLBACK_SETTINGS.usage.monthlyBudgetUsd
+      ),
+      monthlyTokenBudget: Math.round(
+        clampNumberValue(
+          input.usage?.monthlyTokenBudget,
+          0,
+          Number.MAX_SAFE_INTEGER,
+          FALLBACK_SETTINGS.usage.monthlyTokenBudget
+        )
+      ),
+      priceOverrides: Array.isArray(input.usage?.priceOverrides)
+        ? input.usage.priceOverrides.filter((item) => item.model?.trim())
+        : []
+    },
+    network: {
+      proxyUrl: input.network?.proxyUrl?.trim() || "",
+      proxyUsername: input.network?.proxyUsername?.trim() || "",
+      proxyPassword: input.network?.proxyPassword || "",
+      noProxy: input.network?.noProxy?.trim() || "",
+      extraCaCertPath: input.network?.extraCaCertPath?.trim() || ""
+    },
+    glossary: {
+      entries: Array.isArray(input.glossary?.entries)
+        ? input.glossary.entries.filter((item) => typeof item?.source === "string")
+        : FALLBACK_SETTINGS.glossary.entries,
+      doNotTranslate: Array.isArray(input.glossary?.doNotTranslate)
+        ? input.glossary.doNotTranslate.filter((item) => typeof item === "string")
+        : FALLBACK_SETTINGS.glossary.doNotTranslate
     }
   };
 }
@@ -2250,30 +3415,39 @@ function SelectionBarWindow({ settingsApi }: { settingsApi: SettingsApi }) {
   }, [settings.agents.custom]);
 
   const orderedActions = useMemo<SelectionBarRenderAction[]>(() => {
-    const order = normalizeSelectionBarOrder(settings.agents.barOrder, settings.agents.custom);
+    // The backend resolves app rules into the key list; fall back to the global order.
+    const keys = selection?.barItems?.length
+      ? selection.barItems
+      : normalizeSelectionBarOrder(settings.agents.barOrder, settings.agents.custom)
+          .filter((item) => item.enabled)
+          .map((item) => item.key);
     const result: SelectionBarRenderAction[] = [];
-    for (const item of order) {
-      if (!item.enabled) continue;
-      if (isBuiltinSelectionBarActionKey(item.key)) {
+    for (const key of keys) {
+      if (isBuiltinSelectionBarActionKey(key)) {
         result.push({
-          key: item.key,
+          key,
           kind: "builtin",
-          meta: BUILTIN_SELECTION_BAR_ACTION_MAP[item.key]
+          meta: BUILTIN_SELECTION_BAR_ACTION_MAP[key]
         });
         continue;
       }
-      const customId = parseCustomAgentActionKey(item.key);
+      const customId = parseCustomAgentActionKey(key);
       if (!customId) continue;
       const agent = customAgentMap.get(customId);
       if (!agent) continue;
       result.push({
-        key: item.key,
+        key,
         kind: "custom",
         agent
       });
     }
     return result;
-  }, [customAgentMap, settings.agents.barOrder, settings.agents.custom]);
+  }, [customAgentMap, selection?.barItems, settings.agents.barOrder, settings.agents.custom]);
+
+  const suggestedKeys = useMemo(
+    () => new Set<string>(selection?.suggestedItems ?? []),
+    [selection?.suggestedItems]
+  );
 
   async function copySelection() {
     if (!selection?.text) return;
@@ -2313,10 +3487,17 @@ function SelectionBarWindow({ settingsApi }: { settingsApi: SettingsApi }) {
     }
   }
 
-  async function runAction(action: SelectionActionKind, customAgentId?: string) {
+  async function runAction(
+    action: SelectionActionKind,
+    customAgentId?: string,
+    replaceSelection = false
+  ) {
     if (!selection?.text) return;
-    const key = customAgentId ? `${action}:${customAgentId}` : action;
-    const defaultTranslateTarget = settings.selectionAssistant.defaultTranslateTo;
+    const key = customAgentId
+      ? `${action}:${customAgentId}`
+      : replaceSelection
+        ? `${action}:replace`
+        : action;
     setBusyAction(key);
     try {
       await invoke<SelectionResultPayload>("run_selection_action", {
@@ -2324,8 +3505,10 @@ function SelectionBarWindow({ settingsApi }: { settingsApi: SettingsApi }) {
           action,
           text: selection.text,
           customAgentId,
-          translateFrom: action === "translate" ? "auto" : null,
-          translateTo: action === "translate" ? defaultTranslateTarget : null
+          translateFrom: action === "translate" ? (selection.translateFrom ?? "auto") : null,
+          // Left to the backend so smart targeting can look at the text, unless an app rule sets it.
+          translateTo: selection.translateTo ?? null,
+          replaceSelection
         }
       });
     } catch (invokeError) {
@@ -2349,14 +3532,14 @@ function SelectionBarWindow({ settingsApi }: { settingsApi: SettingsApi }) {
           if (item.kind === "builtin") {
             const meta = item.meta;
             const Icon = meta.icon;
-            const busyKey = meta.action ?? null;
+            const busyKey = meta.action ? (meta.replace ? `${meta.action}:replace` : meta.action) : null;
             const isBusy = Boolean(busyKey && busyAction === busyKey);
             return (
               <button
                 key={item.key}
                 className={`selection-action-btn${
                   settings.selectionAssistant.compactMode ? " compact" : ""
-                }`}
+                }${suggestedKeys.has(item.key) ? " suggested" : ""}`}
                 disabled={isBusy}
                 onClick={() => {
                   if (meta.direct === "copy") {
@@ -2368,7 +3551,7 @@ function SelectionBarWindow({ settingsApi }: { settingsApi: SettingsApi }) {
                     return;
                   }
                   if (meta.action) {
-                    void runAction(meta.action);
+                    void runAction(meta.action, undefined, meta.replace);
                   }
                 }}
                 title={meta.label}
@@ -2389,7 +3572,7 @@ function SelectionBarWindow({ settingsApi }: { settingsApi: SettingsApi }) {
               key={item.key}
               className={`selection-action-btn custom${
                 settings.selectionAssistant.compactMode ? " compact" : ""
-              }`}
+              }${suggestedKeys.has(item.key) ? " suggested" : ""}`}
               disabled={isBusy}
               onClick={() => void runAction("custom", item.agent.id)}
               title={item.agent.name}
@@ -2408,6 +3591,7 @@ function SelectionBarWindow({ settingsApi }: { settingsApi: SettingsApi }) {
 
 function SelectionResultWindow({ settingsApi }: { settingsApi: SettingsApi }) {
   const { settings, updateSettings } = settingsApi;
+  const translateLanguages = useTranslateLanguageOptions();
   const [result, setResult] = useState<SelectionResultPayload | null>(null);
   const [isPinnedTop, setIsPinnedTop] = useState(
     () => settings.selectionAssistant.resultWindowAlwaysOnTop
@@ -2416,7 +3600,14 @@ function SelectionResultWindow({ settingsApi }: { settingsApi: SettingsApi }) {
   const [toLang, setToLang] = useState<TranslateTargetLanguageCode>(
     settings.selectionAssistant.defaultTranslateTo
   );
+  const [savedHeadword, setSavedHeadword] = useState("");
+  const [bilingualMode, setBilingualMode] = useState<BilingualMode>(
+    settings.selectionAssistant.bilingualMode
+  );
+  const [bilingualSide, setBilingualSide] = useState<"translation" | "source">("translation");
   const [sourceExpanded, setSourceExpanded] = useState(false);
+  const [rejectedHunks, setRejectedHunks] = useState<Set<number>>(() => new Set());
+  const [followUpError, setFollowUpError] = useState("");
   const latestRequestIdRef = useRef("");
   const ttsAudioRef = useRef<HTMLAudioElement | null>(null);
   const ttsAudioRevokeRef = useRef<(() => void) | null>(null);
@@ -2447,7 +3638,9 @@ function SelectionResultWindow({ settingsApi }: { settingsApi: SettingsApi }) {
           if (!current || current.requestId !== payload.requestId) {
             return payload;
           }
-          if (!current.isStreaming && payload.isStreaming) {
+          const isNewFollowUp =
+            (payload.followUps?.length ?? 0) > (current.followUps?.length ?? 0);
+          if (!current.isStreaming && payload.isStreaming && !isNewFollowUp) {
             return current;
           }
           if (!current.isStreaming && !current.errorMessage && Boolean(payload.errorMessage)) {
@@ -2459,8 +3652,11 @@ function SelectionResultWindow({ settingsApi }: { settingsApi: SettingsApi }) {
           stopTtsPlayback(false);
           latestRequestIdRef.current = payload.requestId;
           setSourceExpanded(false);
+          setRejectedHunks(new Set());
+          setFollowUpError("");
           setFromLang((payload.translateFrom as TranslateLanguageCode) || "auto");
           setToLang(parseTranslateTarget(payload.translateTo, settings.selectionAssistant.defaultTranslateTo));
+          setBilingualSide("translation");
           setOutputFavorited(false);
           favoriteToggleInFlightRef.current = false;
           void invoke<boolean>("get_result_window_pinned_cmd")
@@ -2506,14 +3702,41 @@ function SelectionResultWindow({ settingsApi }: { settingsApi: SettingsApi }) {
   }
 
   async function copyOutput() {
-    if (!result?.outputText) return;
+    if (!outputSpeakableText) return;
     try {
-      await navigator.clipboard.writeText(result.outputText);
+      await navigator.clipboard.writeText(outputSpeakableText);
     } catch (error) {
       console.error("[SelectionResultWindow] copy output failed:", error);
     }
   }
 
+  async function sendFollowUp(message: string) {
+    if (!result) return false;
+    setFollowUpError("");
+    try {
+      await invoke("continue_selection_conversation", {
+        payload: { requestId: result.requestId, message }
+      });
+      return true;
+    } catch (invokeError) {
+      console.error("[SelectionResultWindow] follow-up failed:", invokeError);
+      setFollowUpError(describeInvokeError(invokeError, settings.language));
+      return false;
+    }
+  }
+
+  async function replaceSourceSelection() {
+    if (!result?.sourceText || !outputSpeakableText) return;
+    try {
+      await invoke("replace_selection_with_result", {
+        payload: { sourceText: result.sourceText, outputText: outputSpeakableText }
+      });
+    } catch (invokeError) {
+      console.error("[SelectionResultWindow] replace selection failed:", invokeError);
+      window.alert(`替换原文失败：${String(invokeError)}`);
+    }
+  }
+
   async function addFavoriteText(value: string) {
     const text = value.trim();
     if (!text || favoriteToggleInFlightRef.current) return;
@@ -2533,6 +3756,19 @@ function SelectionResultWindow({ settingsApi }: { settingsApi: SettingsApi }) {
     }
   }
 
+  async function saveDictionaryWord(entry: DictionaryEntryPayload) {
+    try {
+      await invoke<WordListEntry>("save_word_to_list", {
+        entry,
+        context: result?.sourceText ?? null
+      });
+      setSavedHeadword(entry.headword);
+    } catch (error) {
+      console.error("[SelectionResultWindow] save word failed:", error);
+      window.alert(`加入生词本失败：${String(error)}`);
+    }
+  }
+
   async function persistTranslateTarget(nextTo: TranslateTargetLanguageCode) {
     if (settings.selectionAssistant.defaultTranslateTo === nextTo) return;
     await updateSettings({
@@ -2542,7 +3778,31 @@ function SelectionResultWindow({ settingsApi }: { settingsApi: SettingsApi }) {
     });
   }
 
-  async function rerunTranslate(nextFrom: TranslateLanguageCode, nextTo: TranslateTargetLanguageCode) {
+  async function regenerateResult() {
+    const action = result ? ACTION_LOG_RERUN_ACTIONS[result.action] : undefined;
+    if (!result?.sourceText || !action) return;
+    try {
+      await invoke<SelectionResultPayload>("run_selection_action", {
+        payload: {
+          action,
+          text: result.sourceText,
+          customAgentId: result.customAgentId ?? null,
+          translateFrom: result.translateFrom ?? null,
+          translateTo: result.translateTo ?? null,
+          bilingualMode: action === "translate" ? bilingualMode : null,
+          regenerate: true
+        }
+      });
+    } catch (invokeError) {
+      console.error("[SelectionResultWindow] regenerate failed:", invokeError);
+    }
+  }
+
+  async function rerunTranslate(
+    nextFrom: TranslateLanguageCode,
+    nextTo: TranslateTargetLanguageCode,
+    nextBilingualMode: BilingualMode = bilingualMode
+  ) {
     if (!result?.sourceText) return;
     try {
       await invoke<SelectionResultPayload>("run_selection_action", {
@@ -2550,7 +3810,8 @@ function SelectionResultWindow({ settingsApi }: { settingsApi: SettingsApi }) {
           action: "translate",
           text: result.sourceText,
           translateFrom: nextFrom,
-          translateTo: nextTo
+          translateTo: nextTo,
+          bilingualMode: nextBilingualMode
         }
       });
     } catch (invokeError) {
@@ -2674,7 +3935,7 @@ function SelectionResultWindow({ settingsApi }: { settingsApi: SettingsApi }) {
   }, [ttsLoading, ttsPlaying, stopTtsPlayback]);
 
   async function speakOutput() {
-    const text = result?.outputText?.trim() || "";
+    const text = outputSpeakableText;
     if (!text) return;
 
     if (ttsPlaying || ttsLoading) {
@@ -2727,16 +3988,29 @@ function SelectionResultWindow({ settingsApi }: { settingsApi: SettingsApi }) {
       setTtsLoading(false);
       setTtsPlaying(false);
       console.error("[SelectionResultWindow] TTS playback failed:", error);
-      window.alert(`语音播放失败：${String(error)}`);
+      window.alert(`语音播放失败：${describeInvokeError(error, settings.language)}`);
     }
   }
 
+  // A failed follow-up keeps the first answer on screen; its error shows under the thread.
+  const outputError = result?.followUps?.length ? "" : result?.errorMessage?.trim() || "";
+  const canFollowUp = Boolean(result?.outputText?.trim()) && !outputError;
+  const diffHunks = result?.diffHunks?.length && !outputError ? result.diffHunks : null;
+  const polishedText = diffHunks ? applyDiffHunks(diffHunks, rejectedHunks).trim() : null;
   const outputDisplayText =
-    result?.errorMessage?.trim() ||
+    outputError ||
+    polishedText ||
     result?.outputText?.trim() ||
-    (result?.isStreaming ? "正在处理..." : "等待处理结果...");
-  const outputSpeakableText = result?.outputText?.trim() || "";
-  const outputFavoriteText = result?.outputText?.trim() || "";
+    (result?.isStreaming
+      ? formatChunkProgress(result.chunkProgress) || "正在处理..."
+      : "等待处理结果...");
+  const bilingualSegments = result?.segments?.length ? result.segments : null;
+  // Copy, speech and favorites follow the side picked for bilingual results.
+  const outputSpeakableText =
+    bilingualSegments && bilingualSide === "source"
+      ? bilingualSegments.map((item) => item.source).join("\n")
+      : (polishedText ?? result?.outputText?.trim()) || "";
+  const outputFavoriteText = outputSpeakableText;
   const resultWindowMeta = useMemo<{ label: string; icon: LucideIcon }>(() => {
     switch (result?.action) {
       case "translate":
@@ -2807,7 +4081,7 @@ function SelectionResultWindow({ settingsApi }: { settingsApi: SettingsApi }) {
                     void rerunTranslate(next, toLang);
                   }}
                 >
-                  {TRANSLATE_LANGUAGE_OPTIONS.map((item) => (
+                  {[AUTO_LANGUAGE_OPTION, ...translateLanguages].map((item) => (
                     <option key={`from-${item.key}`} value={item.key}>
                       {item.label}
                     </option>
@@ -2829,19 +4103,84 @@ function SelectionResultWindow({ settingsApi }: { settingsApi: SettingsApi }) {
                     void rerunTranslate(fromLang, next);
                   }}
                 >
-                  {TRANSLATE_LANGUAGE_OPTIONS.filter((item) => item.key !== "auto").map((item) => (
+                  {translateLanguages.map((item) => (
                     <option key={`to-${item.key}`} value={item.key}>
                       {item.label}
                     </option>
                   ))}
                 </select>
               </div>
+              <div className="selection-translate-item">
+                <select
+                  className="selection-translate-select md2-select"
+                  id="translate-bilingual"
+                  aria-label="对照模式"
+                  disabled={Boolean(result?.isStreaming)}
+                  value={bilingualMode}
+                  onChange={(event) => {
+                    const next = parseBilingualMode(event.target.value);
+                    setBilingualMode(next);
+                    void rerunTranslate(fromLang, toLang, next);
+                  }}
+                >
+                  {BILINGUAL_MODE_OPTIONS.map((item) => (
+                    <option key={`bilingual-${item.key}`} value={item.key}>
+                      {item.label}
+                    </option>
+                  ))}
+                </select>
+              </div>
+              {bilingualSegments && (
+                <div className="selection-translate-item">
+                  <select
+                    className="selection-translate-select md2-select"
+                    aria-label="复制与朗读"
+                    value={bilingualSide}
+                    onChange={(event) =>
+                      setBilingualSide(event.target.value === "source" ? "source" : "translation")
+                    }
+                  >
+                    <option value="translation">复制/朗读译文</option>
+                    <option value="source">复制/朗读原文</option>
+                  </select>
+                </div>
+              )}
             </section>
           )}
 
           <section className="selection-result-body">
             <section className="selection-result-output">
-              <MarkdownText className="selection-result-text markdown-render-body" text={outputDisplayText} />
+              <ReasoningBlock
+                text={result?.reasoningText}
+                display={settings.llm.reasoningDisplay}
+              />
+              <WorkflowSteps steps={result?.workflowSteps} />
+              <ToolCallTrace calls={result?.toolCalls} />
+              <PolishDiff hunks={diffHunks} rejected={rejectedHunks} onChange={setRejectedHunks} />
+              {result?.structuredOutput != null && !outputError ? (
+                <StructuredOutputTable value={result.structuredOutput} />
+              ) : result?.dictionary && !outputError ? (
+                <DictionaryCard
+                  entry={result.dictionary}
+                  saved={savedHeadword === result.dictionary.headword}
+                  onSave={() => result.dictionary && void saveDictionaryWord(result.dictionary)}
+                />
+              ) : bilingualSegments && !outputError ? (
+                <div className="selection-result-text bilingual-segments">
+                  {bilingualSegments.map((item, index) => (
+                    <div className="bilingual-segment" key={`segment-${index}`}>
+                      <p className="bilingual-source">{item.source}</p>
+                      <p className="bilingual-translation">{item.translation}</p>
+                    </div>
+                  ))}
+                </div>
+              ) : (
+                <MarkdownText
+                  className="selection-result-text markdown-render-body"
+                  text={outputDisplayText}
+                />
+              )}
+              <GlossaryWarnings warnings={result?.glossaryWarnings} />
               <div className="result-overlay-actions">
                 <button
                   className={`icon-btn overlay-action-btn result-favorite-btn${
@@ -2865,6 +4204,24 @@ function SelectionResultWindow({ settingsApi }: { settingsApi: SettingsApi }) {
                 >
                   <Volume2 size={14} />
                 </button>
+                <button
+                  className="icon-btn overlay-action-btn result-regenerate-btn"
+                  onClick={() => void regenerateResult()}
+                  aria-label="Regenerate"
+                  title="重新生成"
+                  disabled={!result?.sourceText || Boolean(result?.isStreaming)}
+                >
+                  <RefreshCw size={14} />
+                </button>
+                <button
+                  className="icon-btn overlay-action-btn result-replace-btn"
+                  onClick={() => void replaceSourceSelection()}
+                  aria-label="Replace selection"
+                  title="替换原文"
+                  disabled={!outputSpeakableText || Boolean(result?.isStreaming)}
+                >
+                  <Replace size={14} />
+                </button>
                 <button
                   className="icon-btn overlay-action-btn result-copy-btn"
                   onClick={() => void copyOutput()}
@@ -2875,6 +4232,16 @@ function SelectionResultWindow({ settingsApi }: { settingsApi: SettingsApi }) {
               </div>
             </section>
 
+            {canFollowUp && (
+              <FollowUpThread
+                turns={result?.followUps}
+                streaming={Boolean(result?.isStreaming)}
+                error={followUpError}
+                display={settings.llm.reasoningDisplay}
+                onSend={sendFollowUp}
+              />
+            )}
+
             <section className={`selection-source-fold${sourceExpanded ? " open" : ""}`}>
               {sourceExpanded && (
                 <pre className="selection-source-text">
@@ -3054,6 +4421,7 @@ function OcrResultWindow({ settingsApi }: { settingsApi: SettingsApi }) {
   const [ttsPlayingPanel, setTtsPlayingPanel] = useState<"ocr" | "output" | null>(null);
   const [ocrFavorited, setOcrFavorited] = useState(false);
   const [outputFavorited, setOutputFavorited] = useState(false);
+  const [followUpError, setFollowUpError] = useState("");
 
   useEffect(() => {
     void invoke<boolean>("get_ocr_result_window_pinned_cmd")
@@ -3075,7 +4443,9 @@ function OcrResultWindow({ settingsApi }: { settingsApi: SettingsApi }) {
           if (!current || current.requestId !== payload.requestId) {
             return payload;
           }
-          if (!current.isStreaming && payload.isStreaming) {
+          const isNewFollowUp =
+            (payload.followUps?.length ?? 0) > (current.followUps?.length ?? 0);
+          if (!current.isStreaming && payload.isStreaming && !isNewFollowUp) {
             return current;
           }
           if (!current.isStreaming && !current.errorMessage && Boolean(payload.errorMessage)) {
@@ -3088,6 +4458,7 @@ function OcrResultWindow({ settingsApi }: { settingsApi: SettingsApi }) {
           latestRequestIdRef.current = payload.requestId;
           setOcrFavorited(false);
           setOutputFavorited(false);
+          setFollowUpError("");
           favoriteToggleInFlightRef.current.clear();
           void invoke<boolean>("get_ocr_result_window_pinned_cmd")
             .then((value) => setIsPinnedTop(Boolean(value)))
@@ -3362,12 +4733,48 @@ function OcrResultWindow({ settingsApi }: { settingsApi: SettingsApi }) {
       setTtsLoadingPanel(null);
       setTtsPlayingPanel(null);
       console.error("[OcrResultWindow] TTS playback failed:", error);
-      window.alert(`语音播放失败：${String(error)}`);
+      window.alert(`语音播放失败：${describeInvokeError(error, settings.language)}`);
+    }
+  }
+
+  async function regenerateResult() {
+    const action = result ? ACTION_LOG_RERUN_ACTIONS[result.action] : undefined;
+    if (!result?.ocrText || !action) return;
+    try {
+      await invoke<OcrResultPayload>("run_ocr_action_cmd", {
+        payload: {
+          action,
+          ocrText: result.ocrText,
+          customAgentId: result.customAgentId ?? null,
+          translateFrom: result.translateFrom ?? null,
+          translateTo: result.translateTo ?? null,
+          regenerate: true
+        }
+      });
+    } catch (invokeError) {
+      console.error("[OcrResultWindow] regenerate failed:", invokeError);
+    }
+  }
+
+  async function sendFollowUp(message: string) {
+    if (!result) return false;
+    setFollowUpError("");
+    try {
+      await invoke("continue_ocr_conversation_cmd", {
+        payload: { requestId: result.requestId, message }
+      });
+      return true;
+    } catch (invokeError) {
+      console.error("[OcrResultWindow] follow-up failed:", invokeError);
+      setFollowUpError(describeInvokeError(invokeError, settings.language));
+      return false;
     }
   }
 
+  const outputError = result?.followUps?.length ? "" : result?.errorMessage?.trim() || "";
+  const canFollowUp = Boolean(result?.outputText?.trim()) && !outputError;
   const outputText =
-    result?.errorMessage?.trim() ||
+    outputError ||
     result?.outputText?.trim() ||
     (result?.isStreaming ? "正在处理..." : "等待 OCR 处理结果...");
   const ocrSpeakableText = result?.ocrText?.trim() || "";
@@ -3473,6 +4880,15 @@ function OcrResultWindow({ settingsApi }: { settingsApi: SettingsApi }) {
                 >
                   <Volume2 size={14} />
                 </button>
+                <button
+                  className="icon-btn ocr-block-regenerate-btn"
+                  onClick={() => void regenerateResult()}
+                  aria-label="Regenerate output"
+                  title="重新生成"
+                  disabled={!result?.ocrText?.trim() || Boolean(result?.isStreaming)}
+                >
+                  <RefreshCw size={14} />
+                </button>
                 <button
                   className="icon-btn ocr-block-copy-btn"
                   onClick={() => void copyText(outputText)}
@@ -3483,7 +4899,27 @@ function OcrResultWindow({ settingsApi }: { settingsApi: SettingsApi }) {
               </div>
             </header>
             <section className="ocr-result-body-wrap">
-              <MarkdownText className="markdown-render-body" text={outputText} />
+              <ReasoningBlock
+                text={result?.reasoningText}
+                display={settings.llm.reasoningDisplay}
+              />
+              <WorkflowSteps steps={result?.workflowSteps} />
+              <ToolCallTrace calls={result?.toolCalls} />
+              {result?.structuredOutput != null && !outputError ? (
+                <StructuredOutputTable value={result.structuredOutput} />
+              ) : (
+                <MarkdownText className="markdown-render-body" text={outputText} />
+              )}
+              <GlossaryWarnings warnings={result?.glossaryWarnings} />
+              {canFollowUp && (
+                <FollowUpThread
+                  turns={result?.followUps}
+                  streaming={Boolean(result?.isStreaming)}
+                  error={followUpError}
+                  display={settings.llm.reasoningDisplay}
+                  onSend={sendFollowUp}
+                />
+              )}
             </section>
           </article>
         </section>
@@ -3508,6 +4944,7 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
     importSettings,
     refresh
   } = settingsApi;
+  const translateLanguages = useTranslateLanguageOptions();
 
   const [activeGroup, setActiveGroup] = useState<SettingGroup["key"]>("general");
   const [, setStatus] = useState("设置将自动保存");
@@ -3527,8 +4964,37 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
   const [testingLlmApi, setTestingLlmApi] = useState(false);
   const [testingOcrApi, setTestingOcrApi] = useState(false);
   const [showLlmApiKey, setShowLlmApiKey] = useState(false);
+  const [builtinParamsAction, setBuiltinParamsAction] =
+    useState<keyof BuiltinActionSettings>("translate");
+  const [builtinPromptDefaults, setBuiltinPromptDefaults] =
+    useState<BuiltinPromptDefaultsPayload | null>(null);
+  const [builtinPromptDrafts, setBuiltinPromptDrafts] = useState<
+    Partial<Record<"systemPrompt" | "userPrompt", string>>
+  >({});
+  const [doNotTranslateDraft, setDoNotTranslateDraft] = useState<string | null>(null);
+  const [constantDrafts, setConstantDrafts] = useState<PromptConstant[] | null>(null);
+  const [appRuleDrafts, setAppRuleDrafts] = useState<AppSelectionRule[] | null>(null);
+  const [agentPackText, setAgentPackText] = useState("");
+  const [agentPackPreview, setAgentPackPreview] = useState<AgentPackPreviewItem[] | null>(null);
+  const [agentPackSelection, setAgentPackSelection] = useState<string[]>([]);
+  const [agentPackFeedback, setAgentPackFeedback] = useState("");
+  const [glossaryImportText, setGlossaryImportText] = useState("");
+  const [glossaryImportTarget, setGlossaryImportTarget] = useState("");
+  const [glossaryImportReplace, setGlossaryImportReplace] = useState(false);
+  const [glossaryImportFeedback, setGlossaryImportFeedback] = useState("");
+  const [wordList, setWordList] = useState<WordListEntry[]>([]);
+  const [actionLog, setActionLog] = useState<ActionLogEntry[]>([]);
+  const [actionLogQuery, setActionLogQuery] = useState("");
+  const [actionLogExport, setActionLogExport] = useState("");
+  const [usageStats, setUsageStats] = useState<UsageStats | null>(null);
+  const [usageStatsDays, setUsageStatsDays] = useState(30);
+  const [extraHeaderDrafts, setExtraHeaderDrafts] = useState<
+    Partial<Record<"llm" | "vision", string>>
+  >({});
   const [showOcrVisionApiKey, setShowOcrVisionApiKey] = useState(false);
   const [llmApiTestFeedback, setLlmApiTestFeedback] = useState("");
+  const [clearingResponseCache, setClearingResponseCache] = useState(false);
+  const [responseCacheFeedback, setResponseCacheFeedback] = useState("");
   const [ocrApiTestFeedback, setOcrApiTestFeedback] = useState("");
   const [numberDrafts, setNumberDrafts] = useState<
     Partial<
@@ -3537,12 +5003,18 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
         | "selection-max-chars"
         | "llm-temperature"
         | "llm-max-tokens"
+        | "llm-chunk-tokens"
+        | "llm-context-tokens"
         | "llm-timeout-ms"
         | "ocr-vision-temperature"
         | "ocr-vision-max-tokens"
         | "ocr-vision-timeout-ms"
         | "poll-ms"
         | "history-max"
+        | "response-cache-size"
+        | "response-cache-ttl"
+        | "usage-budget-usd"
+        | "usage-token-budget"
         | "tts-rate",
         string
       >
@@ -3718,6 +5190,40 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
     }
   }, [error]);
 
+  useEffect(() => {
+    void invoke<BuiltinPromptDefaultsPayload>("get_builtin_prompt_defaults")
+      .then(setBuiltinPromptDefaults)
+      .catch((invokeError) => console.error("[Settings] load prompt defaults failed:", invokeError));
+  }, []);
+
+  useEffect(() => {
+    if (activeGroup !== "dataBackup") return;
+    void invoke<WordListEntry[]>("list_word_list")
+      .then(setWordList)
+      .catch((invokeError) => setStatus(`读取生词本失败：${String(invokeError)}`));
+  }, [activeGroup]);
+
+  useEffect(() => {
+    if (activeGroup !== "dataBackup") return;
+    void invoke<UsageStats>("get_usage_stats", { days: usageStatsDays })
+      .then(setUsageStats)
+      .catch((invokeError) => setStatus(`读取模型用量失败：${String(invokeError)}`));
+  }, [activeGroup, usageStatsDays]);
+
+  useEffect(() => {
+    if (activeGroup !== "dataBackup") return;
+    const query = actionLogQuery.trim();
+    const timer = window.setTimeout(() => {
+      const request = query
+        ? invoke<ActionLogEntry[]>("search_action_log", { payload: { query } })
+        : invoke<ActionLogEntry[]>("list_action_log");
+      void request
+        .then(setActionLog)
+        .catch((invokeError) => setStatus(`读取操作记录失败：${String(invokeError)}`));
+    }, 200);
+    return () => window.clearTimeout(timer);
+  }, [activeGroup, actionLogQuery]);
+
   useEffect(() => {
     let active = true;
     void getVersion()
@@ -4007,6 +5513,114 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
     };
   }, [settings.window.checkUpdatesOnStartup]);
 
+  function updateGlossaryEntry(index: number, patch: Partial<GlossaryEntry>) {
+    void applyPatch({
+      glossary: {
+        entries: settings.glossary.entries.map((entry, itemIndex) =>
+          itemIndex === index ? { ...entry, ...patch } : entry
+        )
+      }
+    });
+  }
+
+  async function saveBuiltinPrompt(field: "systemPrompt" | "userPrompt", value: string) {
+    const saved = await updateSettings({
+      agents: {
+        builtin: {
+          ...settings.agents.builtin,
+          [builtinParamsAction]: {
+            ...settings.agents.builtin[builtinParamsAction],
+            [field]: value
+          }
+        }
+      }
+    });
+    if (saved) {
+      setBuiltinPromptDrafts((drafts) => ({ ...drafts, [field]: undefined }));
+    }
+  }
+
+  async function reopenActionLogEntry(id: string) {
+    try {
+      await invoke("reopen_action_log_entry", { id });
+    } catch (invokeError) {
+      setStatus(`打开记录失败：${String(invokeError)}`);
+    }
+  }
+
+  async function rerunActionLogEntry(entry: ActionLogEntry) {
+    const action = ACTION_LOG_RERUN_ACTIONS[entry.action];
+    if (!action) return;
+    try {
+      await invoke("rerun_action_log_entry", {
+        payload: {
+          id: entry.id,
+          action,
+          customAgentId: entry.customAgentId ?? null,
+          translateFrom: entry.translateFrom ?? null,
+          translateTo: entry.translateTo ?? null
+        }
+      });
+    } catch (invokeError) {
+      setStatus(`重新运行失败：${describeInvokeError(invokeError, settings.language)}`);
+    }
+  }
+
+  async function exportActionLog(format: "json" | "csv") {
+    try {
+      const text = await invoke<string>("export_action_log", { format });
+      setActionLogExport(text);
+      await navigator.clipboard.writeText(text);
+      setStatus(`操作记录已导出为 ${format.toUpperCase()} 并复制到剪贴板`);
+    } catch (invokeError) {
+      setStatus(`导出操作记录失败：${String(invokeError)}`);
+    }
+  }
+
+  async function clearActionLog() {
+    if (!window.confirm("确定清空全部 AI 操作记录吗？")) return;
+    try {
+      await invoke("clear_action_log");
+      setActionLog([]);
+      setActionLogExport("");
+      setStatus("已清空 AI 操作记录");
+    } catch (invokeError) {
+      setStatus(`清空操作记录失败：${String(invokeError)}`);
+    }
+  }
+
+  async function removeWordListEntry(id: string) {
+    try {
+      await invoke("remove_word_from_list", { id });
+      setWordList((items) => items.filter((item) => item.id !== id));
+    } catch (invokeError) {
+      setStatus(`删除生词失败：${String(invokeError)}`);
+    }
+  }
+
+  async function importGlossaryFromTextarea() {
+    const payload = glossaryImportText.trim();
+    if (!payload) {
+      setGlossaryImportFeedback("请先粘贴 CSV 或 TSV 内容");
+      return;
+    }
+    try {
+      const result = await invoke<GlossaryImportPayload>("import_glossary", {
+        payload,
+        targetLanguage: glossaryImportTarget || null,
+        replace: glossaryImportReplace
+      });
+      await refresh();
+      setGlossaryImportText("");
+      setGlossaryImportFeedback(
+        `已导入 ${result.importedEntries} 条术语、${result.importedTerms} 个保留词` +
+          (result.skippedRows ? `，跳过 ${result.skippedRows} 行` : "")
+      );
+    } catch (invokeError) {
+      setGlossaryImportFeedback(`导入失败：${String(invokeError)}`);
+    }
+  }
+
   async function applyPatch(patch: AppSettingsPatch, successMessage?: string) {
     const result = await updateSettings(patch);
     if (result && successMessage) {
@@ -4026,6 +5640,32 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
     return normalized;
   }
 
+  const appRuleBarOptions = useMemo(() => {
+    return normalizeSelectionBarOrder(settings.agents.barOrder, settings.agents.custom).map(
+      (item) => {
+        if (isBuiltinSelectionBarActionKey(item.key)) {
+          const meta = BUILTIN_SELECTION_BAR_ACTION_MAP[item.key];
+          return {
+            key: item.key,
+            label: meta.label,
+            runnable: Boolean(meta.action) && !meta.replace
+          };
+        }
+        const customId = parseCustomAgentActionKey(item.key);
+        const agent = settings.agents.custom.find((entry) => entry.id === customId);
+        return { key: item.key, label: agent?.name ?? item.key, runnable: true };
+      }
+    );
+  }, [settings.agents.barOrder, settings.agents.custom]);
+
+  function updateAppRuleDraft(index: number, patch: Partial<AppSelectionRule>) {
+    setAppRuleDrafts((drafts) =>
+      (drafts ?? settings.selectionAssistant.appRules).map((rule, ruleIndex) =>
+        ruleIndex === index ? { ...rule, ...patch } : rule
+      )
+    );
+  }
+
   const blockedAppSet = useMemo(() => {
     return new Set(settings.selectionAssistant.blockedApps.map((item) => item.toLowerCase()));
   }, [settings.selectionAssistant.blockedApps]);
@@ -4072,7 +5712,7 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
       setStatus(`大模型 API ${nextFeedback}`);
       setLlmApiTestFeedback(nextFeedback);
     } catch (invokeError) {
-      const nextFeedback = String(invokeError);
+      const nextFeedback = describeInvokeError(invokeError, settings.language);
       setStatus(nextFeedback);
       setLlmApiTestFeedback(nextFeedback);
     } finally {
@@ -4080,6 +5720,20 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
     }
   }
 
+  async function clearResponseCache() {
+    if (clearingResponseCache) return;
+    setClearingResponseCache(true);
+    setResponseCacheFeedback("");
+    try {
+      await invoke("clear_response_cache_cmd");
+      setResponseCacheFeedback("缓存已清空");
+    } catch (invokeError) {
+      setResponseCacheFeedback(`清空失败：${String(invokeError)}`);
+    } finally {
+      setClearingResponseCache(false);
+    }
+  }
+
   async function testOcrApiConfig() {
     if (testingOcrApi) return;
     setTestingOcrApi(true);
@@ -4090,7 +5744,7 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
       setStatus(`OCR 模型 API ${nextFeedback}`);
       setOcrApiTestFeedback(nextFeedback);
     } catch (invokeError) {
-      const nextFeedback = String(invokeError);
+      const nextFeedback = describeInvokeError(invokeError, settings.language);
       setStatus(nextFeedback);
       setOcrApiTestFeedback(nextFeedback);
     } finally {
@@ -4134,12 +5788,18 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
       | "selection-max-chars"
       | "llm-temperature"
       | "llm-max-tokens"
+      | "llm-chunk-tokens"
+      | "llm-context-tokens"
       | "llm-timeout-ms"
       | "ocr-vision-temperature"
       | "ocr-vision-max-tokens"
       | "ocr-vision-timeout-ms"
       | "poll-ms"
       | "history-max"
+      | "response-cache-size"
+      | "response-cache-ttl"
+      | "usage-budget-usd"
+      | "usage-token-budget"
       | "tts-rate",
     persisted: number
   ) {
@@ -4152,12 +5812,18 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
       | "selection-max-chars"
       | "llm-temperature"
       | "llm-max-tokens"
+      | "llm-chunk-tokens"
+      | "llm-context-tokens"
       | "llm-timeout-ms"
       | "ocr-vision-temperature"
       | "ocr-vision-max-tokens"
       | "ocr-vision-timeout-ms"
       | "poll-ms"
       | "history-max"
+      | "response-cache-size"
+      | "response-cache-ttl"
+      | "usage-budget-usd"
+      | "usage-token-budget"
       | "tts-rate",
     value: string
   ) {
@@ -4173,12 +5839,18 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
       | "selection-max-chars"
       | "llm-temperature"
       | "llm-max-tokens"
+      | "llm-chunk-tokens"
+      | "llm-context-tokens"
       | "llm-timeout-ms"
       | "ocr-vision-temperature"
       | "ocr-vision-max-tokens"
       | "ocr-vision-timeout-ms"
       | "poll-ms"
       | "history-max"
+      | "response-cache-size"
+      | "response-cache-ttl"
+      | "usage-budget-usd"
+      | "usage-token-budget"
       | "tts-rate"
   ) {
     setNumberDrafts((prev) => {
@@ -4213,11 +5885,16 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
       | "selection-min-chars"
       | "selection-max-chars"
       | "llm-max-tokens"
+      | "llm-chunk-tokens"
+      | "llm-context-tokens"
       | "llm-timeout-ms"
       | "ocr-vision-max-tokens"
       | "ocr-vision-timeout-ms"
       | "poll-ms"
       | "history-max"
+      | "response-cache-size"
+      | "response-cache-ttl"
+      | "usage-token-budget"
       | "tts-rate",
     persisted: number,
     min: number,
@@ -4237,7 +5914,7 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
   }
 
   async function commitFloatInput(
-    key: "llm-temperature" | "ocr-vision-temperature",
+    key: "llm-temperature" | "ocr-vision-temperature" | "usage-budget-usd",
     persisted: number,
     min: number,
     max: number,
@@ -4257,8 +5934,18 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
     await applyPatch(patchFactory(next));
   }
 
-  async function pickHistoryStorageFolder() {
-    if (pickingStorageFolder) return;
+  function updateFallbackProfile(index: number, patch: Partial<LlmProviderProfile>) {
+    void applyPatch({
+      llm: {
+        fallbackProfiles: settings.llm.fallbackProfiles.map((profile, itemIndex) =>
+          itemIndex === index ? { ...profile, ...patch } : profile
+        )
+      }
+    });
+  }
+
+  async function pickHistoryStorageFolder() {
+    if (pickingStorageFolder) return;
     setPickingStorageFolder(true);
     try {
       const selected = await invoke<string | null>("pick_history_storage_folder");
@@ -4310,6 +5997,55 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
     }
   }
 
+  async function exportAgentPack() {
+    try {
+      const payload = await invoke<string>("export_agents", { ids: null });
+      setAgentPackText(payload);
+      setAgentPackPreview(null);
+      await navigator.clipboard.writeText(payload);
+      setAgentPackFeedback(`已导出 ${settings.agents.custom.length} 个 Agent 并复制到剪贴板`);
+    } catch (invokeError) {
+      setAgentPackFeedback(`导出失败：${String(invokeError)}`);
+    }
+  }
+
+  async function previewAgentPack() {
+    const payload = agentPackText.trim();
+    if (!payload) {
+      setAgentPackFeedback("请先粘贴 Agent 包 JSON");
+      return;
+    }
+    try {
+      const items = await invoke<AgentPackPreviewItem[]>("preview_agent_pack", { payload });
+      setAgentPackPreview(items);
+      setAgentPackSelection(items.map((item) => item.id));
+      setAgentPackFeedback("");
+    } catch (invokeError) {
+      setAgentPackPreview(null);
+      setAgentPackFeedback(String(invokeError));
+    }
+  }
+
+  async function importAgentPack() {
+    try {
+      const result = await invoke<AgentImportPayload>("import_agents", {
+        payload: agentPackText.trim(),
+        ids: agentPackSelection
+      });
+      await refresh();
+      setAgentPackPreview(null);
+      setAgentPackText("");
+      setAgentPackFeedback(
+        `已导入 ${result.imported} 个 Agent` +
+          (result.addedConstants.length
+            ? `，请在“提示词常量”中填写：${result.addedConstants.join("、")}`
+            : "")
+      );
+    } catch (invokeError) {
+      setAgentPackFeedback(`导入失败：${String(invokeError)}`);
+    }
+  }
+
   async function copyExportJson() {
     if (!exportText.trim()) {
       setStatus("请先点击“导出配置”生成内容");
@@ -4521,15 +6257,17 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
       selectionBarDraftOrder,
       normalizedCustom
     );
-    await applyPatch(
-      {
-        agents: {
-          custom: normalizedCustom,
-          barOrder: normalizedBarOrder
-        }
-      },
-      "自定义 Agent 与条形栏排序已更新"
-    );
+    const saved = await updateSettings({
+      agents: {
+        custom: normalizedCustom,
+        barOrder: normalizedBarOrder
+      }
+    });
+    if (!saved) {
+      setAgentEditorError("保存失败，请检查 Prompt 中的变量和条件段是否正确");
+      return;
+    }
+    setStatus("自定义 Agent 与条形栏排序已更新");
     closeAgentManager();
   }
 
@@ -4951,6 +6689,88 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
               </div>
             </article>
 
+            <article className="settings-card">
+              <h2>Agent 导入导出</h2>
+              <p className="help-text">Agent 包只包含名称、图标、提示词和请求参数，不含 API 密钥等其他配置。</p>
+              <div className="card-actions">
+                <button
+                  className="tonal-btn"
+                  onClick={() => void exportAgentPack()}
+                  disabled={!settings.agents.custom.length}
+                >
+                  <FileDown size={14} />
+                  <span>导出全部 Agent</span>
+                </button>
+                <button className="tonal-btn" onClick={() => void previewAgentPack()}>
+                  <FileUp size={14} />
+                  <span>解析 Agent 包</span>
+                </button>
+              </div>
+              <textarea
+                className="settings-json"
+                value={agentPackText}
+                onChange={(event) => {
+                  setAgentPackText(event.target.value);
+                  setAgentPackPreview(null);
+                }}
+                placeholder="粘贴 Agent 包 JSON 后点击“解析 Agent 包”"
+              />
+              {agentPackPreview && (
+                <>
+                  {agentPackPreview.map((item) => {
+                    const Icon = iconForCustomAgent(item.iconSupported ? item.icon : "Sparkles");
+                    const notes = [
+                      item.idConflict ? "ID 重复，将生成新 ID" : "",
+                      item.iconSupported ? "" : "图标不受支持，将使用默认图标",
+                      item.missingVariables.length
+                        ? `需要常量：${item.missingVariables.join("、")}`
+                        : "",
+                      item.tools.length
+                        ? `使用工具：${item.tools
+                            .map(
+                              (tool) =>
+                                AGENT_TOOL_OPTIONS.find((option) => option.key === tool)?.label ??
+                                tool
+                            )
+                            .join("、")}`
+                        : ""
+                    ].filter(Boolean);
+                    return (
+                      <label className="check-row agent-pack-row" key={`agent-pack-${item.id}`}>
+                        <span>
+                          <Icon size={13} /> {item.name}
+                          {notes.length ? <small className="help-text">{notes.join("；")}</small> : null}
+                        </span>
+                        <input
+                          className="md2-check"
+                          type="checkbox"
+                          checked={agentPackSelection.includes(item.id)}
+                          onChange={(event) => {
+                            setAgentPackSelection((ids) =>
+                              event.target.checked
+                                ? [...ids, item.id]
+                                : ids.filter((id) => id !== item.id)
+                            );
+                          }}
+                        />
+                      </label>
+                    );
+                  })}
+                  <div className="card-actions">
+                    <button
+                      className="tonal-btn primary"
+                      onClick={() => void importAgentPack()}
+                      disabled={!agentPackSelection.length}
+                    >
+                      <CheckCircle2 size={14} />
+                      <span>导入所选 Agent</span>
+                    </button>
+                  </div>
+                </>
+              )}
+              {agentPackFeedback && <p className="help-text">{agentPackFeedback}</p>}
+            </article>
+
             <article className="settings-card">
               <h2>划词助手行为</h2>
               <label className="check-row">
@@ -5003,13 +6823,58 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
                     });
                   }}
                 >
-                  {TRANSLATE_LANGUAGE_OPTIONS.filter((item) => item.key !== "auto").map((item) => (
+                  {translateLanguages.map((item) => (
                     <option key={`selection-default-target-${item.key}`} value={item.key}>
                       {item.label}
                     </option>
                   ))}
                 </select>
               </div>
+              <label className="check-row">
+                <span>智能目标语言（外文译为界面语言，界面语言文本译为默认目标语言）</span>
+                <input
+                  className="md2-check"
+                  type="checkbox"
+                  checked={settings.selectionAssistant.smartTranslateTarget}
+                  onChange={(event) => {
+                    void applyPatch({
+                      selectionAssistant: { smartTranslateTarget: event.target.checked }
+                    });
+                  }}
+                />
+              </label>
+              <div className="filled-control">
+                <label htmlFor="selection-bilingual-mode">双语对照</label>
+                <select
+                  id="selection-bilingual-mode"
+                  className="md2-select"
+                  value={settings.selectionAssistant.bilingualMode}
+                  onChange={(event) => {
+                    void applyPatch({
+                      selectionAssistant: { bilingualMode: parseBilingualMode(event.target.value) }
+                    });
+                  }}
+                >
+                  {BILINGUAL_MODE_OPTIONS.map((item) => (
+                    <option key={`selection-bilingual-${item.key}`} value={item.key}>
+                      {item.label}
+                    </option>
+                  ))}
+                </select>
+              </div>
+              <label className="check-row">
+                <span>词典模式（单词或短语翻译时显示释义、音标与例句）</span>
+                <input
+                  className="md2-check"
+                  type="checkbox"
+                  checked={settings.selectionAssistant.dictionaryMode}
+                  onChange={(event) => {
+                    void applyPatch({
+                      selectionAssistant: { dictionaryMode: event.target.checked }
+                    });
+                  }}
+                />
+              </label>
 
               <div className="filled-control">
                 <label htmlFor="selection-search-url">搜索 URL 模板</label>
@@ -5140,6 +7005,165 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
                 </div>
               </div>
 
+              <div className="filled-control">
+                <label>按应用规则</label>
+                <p className="help-text">
+                  划词来源的进程名或路径包含匹配内容时生效，按顺序取第一条。勾选顺序即条形栏按钮顺序，不勾选则沿用全局顺序；设置自动执行后不再弹出条形栏。指定源语言时，只有检测到该语言才自动执行；文本已是翻译目标语言时不自动翻译。
+                </p>
+                {(appRuleDrafts ?? settings.selectionAssistant.appRules).map((rule, index) => (
+                  <div className="app-rule-card" key={rule.id || `app-rule-${index}`}>
+                    <div className="app-rule-head">
+                      <input
+                        value={rule.app}
+                        placeholder="进程名，如 code.exe、chrome.exe"
+                        onChange={(event) => updateAppRuleDraft(index, { app: event.target.value })}
+                      />
+                      <label className="check-row">
+                        <span>启用</span>
+                        <input
+                          className="md2-check"
+                          type="checkbox"
+                          checked={rule.enabled}
+                          onChange={(event) =>
+                            updateAppRuleDraft(index, { enabled: event.target.checked })
+                          }
+                        />
+                      </label>
+                      <button
+                        className="icon-btn"
+                        title="删除规则"
+                        onClick={() =>
+                          setAppRuleDrafts((drafts) =>
+                            (drafts ?? settings.selectionAssistant.appRules).filter(
+                              (_, ruleIndex) => ruleIndex !== index
+                            )
+                          )
+                        }
+                      >
+                        <Trash2 size={14} />
+                      </button>
+                    </div>
+                    <div className="app-rule-bar-items">
+                      {appRuleBarOptions.map((option) => {
+                        const position = rule.barItems.indexOf(option.key);
+                        return (
+                          <label className="check-row" key={option.key}>
+                            <span>
+                              {position >= 0 ? `${position + 1}. ` : ""}
+                              {option.label}
+                            </span>
+                            <input
+                              className="md2-check"
+                              type="checkbox"
+                              checked={position >= 0}
+                              disabled={
+                                position < 0 &&
+                                rule.barItems.length >= MAX_SELECTION_BAR_ENABLED_ITEMS
+                              }
+                              onChange={(event) =>
+                                updateAppRuleDraft(index, {
+                                  barItems: event.target.checked
+                                    ? [...rule.barItems, option.key]
+                                    : rule.barItems.filter((key) => key !== option.key)
+                                })
+                              }
+                            />
+                          </label>
+                        );
+                      })}
+                    </div>
+                    <div className="app-rule-selects">
+                      <select
+                        className="md2-select"
+                        value={rule.autoRun}
+                        onChange={(event) =>
+                          updateAppRuleDraft(index, {
+                            autoRun: event.target.value as AppSelectionRule["autoRun"]
+                          })
+                        }
+                      >
+                        <option value="">显示条形栏</option>
+                        {appRuleBarOptions
+                          .filter((option) => option.runnable)
+                          .map((option) => (
+                            <option key={option.key} value={option.key}>
+                              自动执行：{option.label}
+                            </option>
+                          ))}
+                      </select>
+                      <select
+                        className="md2-select"
+                        value={rule.translateFrom}
+                        onChange={(event) =>
+                          updateAppRuleDraft(index, { translateFrom: event.target.value })
+                        }
+                      >
+                        <option value="">任意源语言</option>
+                        {translateLanguages.map((item) => (
+                          <option key={item.key} value={item.key}>
+                            源语言为{item.label}
+                          </option>
+                        ))}
+                      </select>
+                      <select
+                        className="md2-select"
+                        value={rule.translateTo}
+                        onChange={(event) =>
+                          updateAppRuleDraft(index, { translateTo: event.target.value })
+                        }
+                      >
+                        <option value="">沿用默认翻译目标</option>
+                        {translateLanguages.map((item) => (
+                          <option key={item.key} value={item.key}>
+                            翻译为{item.label}
+                          </option>
+                        ))}
+                      </select>
+                    </div>
+                  </div>
+                ))}
+                <div className="card-actions">
+                  <button
+                    className="tonal-btn"
+                    onClick={() =>
+                      setAppRuleDrafts((drafts) => [
+                        ...(drafts ?? settings.selectionAssistant.appRules),
+                        {
+                          id: "",
+                          app: "",
+                          enabled: true,
+                          barItems: [],
+                          autoRun: "",
+                          translateFrom: "",
+                          translateTo: ""
+                        }
+                      ])
+                    }
+                  >
+                    <Plus size={14} />
+                    <span>添加规则</span>
+                  </button>
+                  <button
+                    className="tonal-btn"
+                    disabled={appRuleDrafts === null}
+                    onClick={() => {
+                      if (appRuleDrafts === null) return;
+                      void updateSettings({
+                        selectionAssistant: { appRules: appRuleDrafts }
+                      }).then((saved) => {
+                        if (saved) {
+                          setAppRuleDrafts(null);
+                          setStatus("应用规则已保存");
+                        }
+                      });
+                    }}
+                  >
+                    <CheckCircle2 size={14} />
+                    <span>保存规则</span>
+                  </button>
+                </div>
+              </div>
+
               <div className="filled-control">
                 <label htmlFor="selection-bar-opacity">条形栏透明度</label>
                 <div className="appearance-range-row">
@@ -5175,6 +7199,19 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
                   }}
                 />
               </label>
+              <label className="check-row">
+                <span>按内容推荐操作（代码、报错、外文等，推荐项前置并高亮）</span>
+                <input
+                  className="md2-check"
+                  type="checkbox"
+                  checked={settings.selectionAssistant.smartBarSuggestions}
+                  onChange={(event) => {
+                    void applyPatch({
+                      selectionAssistant: { smartBarSuggestions: event.target.checked }
+                    });
+                  }}
+                />
+              </label>
               <label className="check-row">
                 <span>结果窗口默认置顶</span>
                 <input
@@ -5290,6 +7327,86 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
               </div>
             </article>
 
+            <article className="settings-card">
+              <h2>模型响应缓存</h2>
+              <label className="check-row">
+                <span>缓存相同请求的模型回复</span>
+                <input
+                  className="md2-check"
+                  type="checkbox"
+                  checked={settings.responseCache.enabled}
+                  onChange={(event) => {
+                    void applyPatch({ responseCache: { enabled: event.target.checked } });
+                  }}
+                />
+              </label>
+              <div className="filled-control">
+                <label htmlFor="response-cache-size">缓存上限 (MB)</label>
+                <input
+                  id="response-cache-size"
+                  type="number"
+                  min={RESPONSE_CACHE_SIZE_MB_RANGE.min}
+                  max={RESPONSE_CACHE_SIZE_MB_RANGE.max}
+                  step={1}
+                  value={getNumberInputValue("response-cache-size", settings.responseCache.maxSizeMb)}
+                  onChange={(event) => {
+                    setNumberInputValue("response-cache-size", event.target.value);
+                  }}
+                  onKeyDown={blurNumberInputOnEnter}
+                  onBlur={() => {
+                    void commitIntegerInput(
+                      "response-cache-size",
+                      settings.responseCache.maxSizeMb,
+                      RESPONSE_CACHE_SIZE_MB_RANGE.min,
+                      RESPONSE_CACHE_SIZE_MB_RANGE.max,
+                      (next) => ({ responseCache: { maxSizeMb: next } })
+                    );
+                  }}
+                />
+              </div>
+              <div className="filled-control">
+                <label htmlFor="response-cache-ttl">有效期 (小时)</label>
+                <input
+                  id="response-cache-ttl"
+                  type="number"
+                  min={RESPONSE_CACHE_TTL_HOURS_RANGE.min}
+                  max={RESPONSE_CACHE_TTL_HOURS_RANGE.max}
+                  step={1}
+                  value={getNumberInputValue("response-cache-ttl", settings.responseCache.ttlHours)}
+                  onChange={(event) => {
+                    setNumberInputValue("response-cache-ttl", event.target.value);
+                  }}
+                  onKeyDown={blurNumberInputOnEnter}
+                  onBlur={() => {
+                    void commitIntegerInput(
+                      "response-cache-ttl",
+                      settings.responseCache.ttlHours,
+                      RESPONSE_CACHE_TTL_HOURS_RANGE.min,
+                      RESPONSE_CACHE_TTL_HOURS_RANGE.max,
+                      (next) => ({ responseCache: { ttlHours: next } })
+                    );
+                  }}
+                />
+              </div>
+              <p className="help-text">
+                结果窗口中的“重新生成”会跳过缓存并用新回复替换缓存内容。
+              </p>
+              <div className="settings-inline-actions">
+                <p className="inline-action-result" aria-live="polite">
+                  {responseCacheFeedback}
+                </p>
+                <button
+                  type="button"
+                  className="path-picker-btn"
+                  onClick={() => void clearResponseCache()}
+                  disabled={clearingResponseCache}
+                >
+                  <Trash2 size={13} />
+                  <span>{clearingResponseCache ? "清空中..." : "清空缓存"}</span>
+                </button>
+              </div>
+            </article>
+
           </section>
         )}
 
@@ -5540,6 +7657,54 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
                   }}
                 />
               </div>
+              <div className="filled-control">
+                <label htmlFor="llm-chunk-tokens">长文本分段 Tokens（0 为不分段）</label>
+                <input
+                  id="llm-chunk-tokens"
+                  type="number"
+                  min={LLM_CHUNK_TOKENS_RANGE.min}
+                  max={LLM_CHUNK_TOKENS_RANGE.max}
+                  step={500}
+                  value={getNumberInputValue("llm-chunk-tokens", settings.llm.chunkTokens)}
+                  onChange={(event) => {
+                    setNumberInputValue("llm-chunk-tokens", event.target.value);
+                  }}
+                  onKeyDown={blurNumberInputOnEnter}
+                  onBlur={() => {
+                    void commitIntegerInput(
+                      "llm-chunk-tokens",
+                      settings.llm.chunkTokens,
+                      LLM_CHUNK_TOKENS_RANGE.min,
+                      LLM_CHUNK_TOKENS_RANGE.max,
+                      (next) => ({ llm: { chunkTokens: next } })
+                    );
+                  }}
+                />
+              </div>
+              <div className="filled-control">
+                <label htmlFor="llm-context-tokens">模型上下文窗口 Tokens</label>
+                <input
+                  id="llm-context-tokens"
+                  type="number"
+                  min={LLM_CONTEXT_TOKENS_RANGE.min}
+                  max={LLM_CONTEXT_TOKENS_RANGE.max}
+                  step={1000}
+                  value={getNumberInputValue("llm-context-tokens", settings.llm.contextTokens)}
+                  onChange={(event) => {
+                    setNumberInputValue("llm-context-tokens", event.target.value);
+                  }}
+                  onKeyDown={blurNumberInputOnEnter}
+                  onBlur={() => {
+                    void commitIntegerInput(
+                      "llm-context-tokens",
+                      settings.llm.contextTokens,
+                      LLM_CONTEXT_TOKENS_RANGE.min,
+                      LLM_CONTEXT_TOKENS_RANGE.max,
+                      (next) => ({ llm: { contextTokens: next } })
+                    );
+                  }}
+                />
+              </div>
               <div className="filled-control">
                 <label htmlFor="llm-timeout-ms">Timeout (ms)</label>
                 <input
@@ -5564,66 +7729,580 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
                   }}
                 />
               </div>
-              <div className="settings-inline-actions">
-                <p className="inline-action-result" aria-live="polite">
-                  {ocrApiTestFeedback}
-                </p>
-                <button
-                  type="button"
-                  className="path-picker-btn"
-                  onClick={() => void testOcrApiConfig()}
-                  disabled={testingOcrApi || updating}
-                >
-                  <Zap size={13} />
-                  <span>{testingOcrApi ? "测试中..." : "测试 API 配置"}</span>
-                </button>
-              </div>
-            </article>
-          </section>
-        )}
-
-        {activeGroup === "tts" && (
-          <section className="settings-stack">
-            <article className="settings-card">
-              <h2>语音播放（Edge TTS）</h2>
-
               <div className="filled-control">
-                <label htmlFor="tts-runtime-mode">调用模式</label>
+                <label htmlFor="llm-reasoning-display">思考过程</label>
                 <select
-                  id="tts-runtime-mode"
+                  id="llm-reasoning-display"
                   className="md2-select"
-                  value={settings.tts.runtimeMode}
+                  value={settings.llm.reasoningDisplay}
                   onChange={(event) => {
                     void applyPatch({
-                      tts: {
-                        runtimeMode: parseTtsRuntimeMode(event.target.value)
+                      llm: {
+                        reasoningDisplay: parseReasoningDisplay(event.target.value)
                       }
                     });
                   }}
                 >
-                  {TTS_RUNTIME_MODE_OPTIONS.map((item) => (
+                  {REASONING_DISPLAY_OPTIONS.map((item) => (
                     <option key={item.key} value={item.key}>
                       {item.label}
                     </option>
                   ))}
                 </select>
               </div>
-
               <div className="filled-control">
-                <label htmlFor="tts-voice-zh">中文 Voice</label>
-                <select
-                  id="tts-voice-zh"
-                  className="md2-select"
-                  value={settings.tts.voiceZhCn}
+                <label htmlFor="llm-extra-headers">大模型额外请求头（每行一个 Name: Value）</label>
+                <textarea
+                  id="llm-extra-headers"
+                  className="settings-json"
+                  placeholder="例如：X-Org-Id: team-a"
+                  value={extraHeaderDrafts.llm ?? formatExtraHeaders(settings.llm.extraHeaders)}
                   onChange={(event) => {
-                    void applyPatch({
-                      tts: { voiceZhCn: event.target.value }
-                    });
+                    const value = event.target.value;
+                    setExtraHeaderDrafts((prev) => ({ ...prev, llm: value }));
                   }}
-                >
-                  {!TTS_ZH_VOICE_OPTIONS.some((item) => item.key === settings.tts.voiceZhCn) && (
-                    <option value={settings.tts.voiceZhCn}>{settings.tts.voiceZhCn}</option>
-                  )}
+                  onBlur={() => {
+                    const draft = extraHeaderDrafts.llm;
+                    if (draft === undefined) return;
+                    setExtraHeaderDrafts((prev) => ({ ...prev, llm: undefined }));
+                    void applyPatch({ llm: { extraHeaders: parseExtraHeaders(draft) } });
+                  }}
+                />
+              </div>
+              <div className="filled-control">
+                <label>备用服务（主接口失败时按顺序尝试）</label>
+                {settings.llm.fallbackProfiles.map((profile, index) => (
+                  <div className="fallback-profile-row" key={profile.id}>
+                    <input
+                      aria-label="名称"
+                      placeholder="名称"
+                      value={profile.name}
+                      onChange={(event) => {
+                        updateFallbackProfile(index, { name: event.target.value });
+                      }}
+                    />
+                    <input
+                      aria-label="Base URL"
+                      placeholder="Base URL"
+                      value={profile.baseUrl}
+                      onChange={(event) => {
+                        updateFallbackProfile(index, { baseUrl: event.target.value });
+                      }}
+                    />
+                    <input
+                      aria-label="API Key"
+                      placeholder="API Key"
+                      type="password"
+                      value={profile.apiKey}
+                      onChange={(event) => {
+                        updateFallbackProfile(index, { apiKey: event.target.value });
+                      }}
+                    />
+                    <input
+                      aria-label="模型名称"
+                      placeholder="模型名称（留空沿用主模型）"
+                      value={profile.model}
+                      onChange={(event) => {
+                        updateFallbackProfile(index, { model: event.target.value });
+                      }}
+                    />
+                    <label className="check-row">
+                      <span>启用</span>
+                      <input
+                        className="md2-check"
+                        type="checkbox"
+                        checked={profile.enabled}
+                        onChange={(event) => {
+                          updateFallbackProfile(index, { enabled: event.target.checked });
+                        }}
+                      />
+                    </label>
+                    <button
+                      type="button"
+                      className="path-picker-btn"
+                      onClick={() => {
+                        void applyPatch({
+                          llm: {
+                            fallbackProfiles: settings.llm.fallbackProfiles.filter(
+                              (_, itemIndex) => itemIndex !== index
+                            )
+                          }
+                        });
+                      }}
+                    >
+                      <Trash2 size={13} />
+                      <span>删除</span>
+                    </button>
+                  </div>
+                ))}
+                <button
+                  type="button"
+                  className="path-picker-btn"
+                  disabled={settings.llm.fallbackProfiles.length >= LLM_FALLBACK_PROFILES_MAX}
+                  onClick={() => {
+                    void applyPatch({
+                      llm: {
+                        fallbackProfiles: [
+                          ...settings.llm.fallbackProfiles,
+                          {
+                            id: `provider-${Date.now()}`,
+                            name: "",
+                            enabled: true,
+                            baseUrl: "",
+                            apiKey: "",
+                            model: "",
+                            extraHeaders: []
+                          }
+                        ]
+                      }
+                    });
+                  }}
+                >
+                  <Plus size={13} />
+                  <span>添加备用服务</span>
+                </button>
+              </div>
+              <div className="filled-control">
+                <label htmlFor="ocr-vision-extra-headers">
+                  OCR 视觉模型额外请求头（每行一个 Name: Value）
+                </label>
+                <textarea
+                  id="ocr-vision-extra-headers"
+                  className="settings-json"
+                  placeholder="例如：X-Org-Id: team-a"
+                  value={
+                    extraHeaderDrafts.vision ?? formatExtraHeaders(settings.ocr.vision.extraHeaders)
+                  }
+                  onChange={(event) => {
+                    const value = event.target.value;
+                    setExtraHeaderDrafts((prev) => ({ ...prev, vision: value }));
+                  }}
+                  onBlur={() => {
+                    const draft = extraHeaderDrafts.vision;
+                    if (draft === undefined) return;
+                    setExtraHeaderDrafts((prev) => ({ ...prev, vision: undefined }));
+                    void applyPatch({
+                      ocr: { vision: { extraHeaders: parseExtraHeaders(draft) } }
+                    });
+                  }}
+                />
+              </div>
+              <div className="settings-inline-actions">
+                <p className="inline-action-result" aria-live="polite">
+                  {ocrApiTestFeedback}
+                </p>
+                <button
+                  type="button"
+                  className="path-picker-btn"
+                  onClick={() => void testOcrApiConfig()}
+                  disabled={testingOcrApi || updating}
+                >
+                  <Zap size={13} />
+                  <span>{testingOcrApi ? "测试中..." : "测试 API 配置"}</span>
+                </button>
+              </div>
+            </article>
+            <article className="settings-card">
+              <h2>内置动作提示词与请求参数</h2>
+              <div className="filled-control">
+                <label htmlFor="builtin-params-action">动作</label>
+                <select
+                  id="builtin-params-action"
+                  className="md2-select"
+                  value={builtinParamsAction}
+                  onChange={(event) => {
+                    setBuiltinParamsAction(event.target.value as keyof BuiltinActionSettings);
+                    setBuiltinPromptDrafts({});
+                  }}
+                >
+                  {BUILTIN_ACTION_PARAM_OPTIONS.map((item) => (
+                    <option key={item.key} value={item.key}>
+                      {item.label}
+                    </option>
+                  ))}
+                </select>
+              </div>
+              <GenerationParamsEditor
+                key={builtinParamsAction}
+                idPrefix={`builtin-${builtinParamsAction}`}
+                value={settings.agents.builtin[builtinParamsAction].params}
+                onChange={(params) => {
+                  void applyPatch({
+                    agents: {
+                      builtin: {
+                        ...settings.agents.builtin,
+                        [builtinParamsAction]: {
+                          ...settings.agents.builtin[builtinParamsAction],
+                          params
+                        }
+                      }
+                    }
+                  });
+                }}
+              />
+              {(["systemPrompt", "userPrompt"] as const).map((field) => (
+                <div className="filled-control" key={`builtin-prompt-${field}`}>
+                  <label htmlFor={`builtin-${builtinParamsAction}-${field}`}>
+                    {field === "systemPrompt" ? "系统提示词" : "用户提示词"}
+                  </label>
+                  <textarea
+                    id={`builtin-${builtinParamsAction}-${field}`}
+                    className="settings-json agent-prompt-input"
+                    value={
+                      builtinPromptDrafts[field] ??
+                      (settings.agents.builtin[builtinParamsAction][field] ||
+                        builtinPromptDefaults?.[builtinParamsAction][field] ||
+                        "")
+                    }
+                    onChange={(event) =>
+                      setBuiltinPromptDrafts((drafts) => ({ ...drafts, [field]: event.target.value }))
+                    }
+                    onBlur={() => {
+                      const draft = builtinPromptDrafts[field];
+                      if (draft === undefined) return;
+                      // Saving the unchanged default keeps following future default updates.
+                      const isDefault = draft === builtinPromptDefaults?.[builtinParamsAction][field];
+                      void saveBuiltinPrompt(field, isDefault ? "" : draft);
+                    }}
+                  />
+                </div>
+              ))}
+              <p className="help-text">
+                可用变量：
+                {(builtinPromptDefaults?.variables ?? []).map((name) => `{${name}}`).join("、")}
+                ；提示词中必须包含 {"{text}"}。
+              </p>
+              <div className="card-actions">
+                <button
+                  className="tonal-btn"
+                  disabled={
+                    !settings.agents.builtin[builtinParamsAction].systemPrompt &&
+                    !settings.agents.builtin[builtinParamsAction].userPrompt
+                  }
+                  onClick={() => {
+                    setBuiltinPromptDrafts({});
+                    void applyPatch(
+                      {
+                        agents: {
+                          builtin: {
+                            ...settings.agents.builtin,
+                            [builtinParamsAction]: {
+                              ...settings.agents.builtin[builtinParamsAction],
+                              systemPrompt: "",
+                              userPrompt: ""
+                            }
+                          }
+                        }
+                      },
+                      "已恢复默认提示词"
+                    );
+                  }}
+                >
+                  <RotateCcw size={14} />
+                  <span>恢复默认提示词</span>
+                </button>
+              </div>
+            </article>
+            <article className="settings-card">
+              <h2>提示词常量</h2>
+              <p className="help-text">
+                在自定义 Agent 的提示词中用 {"{名称}"} 引用，名称仅支持字母、数字和下划线。
+              </p>
+              {(constantDrafts ?? settings.agents.constants).map((item, index) => (
+                <div className="prompt-constant-row" key={`prompt-constant-${index}`}>
+                  <input
+                    value={item.name}
+                    placeholder="名称，如 signature"
+                    onChange={(event) =>
+                      setConstantDrafts((drafts) =>
+                        (drafts ?? settings.agents.constants).map((draft, draftIndex) =>
+                          draftIndex === index ? { ...draft, name: event.target.value } : draft
+                        )
+                      )
+                    }
+                  />
+                  <input
+                    value={item.value}
+                    placeholder="值"
+                    onChange={(event) =>
+                      setConstantDrafts((drafts) =>
+                        (drafts ?? settings.agents.constants).map((draft, draftIndex) =>
+                          draftIndex === index ? { ...draft, value: event.target.value } : draft
+                        )
+                      )
+                    }
+                  />
+                  <button
+                    className="icon-btn"
+                    title="删除常量"
+                    onClick={() =>
+                      setConstantDrafts((drafts) =>
+                        (drafts ?? settings.agents.constants).filter(
+                          (_, draftIndex) => draftIndex !== index
+                        )
+                      )
+                    }
+                  >
+                    <Trash2 size={14} />
+                  </button>
+                </div>
+              ))}
+              <div className="card-actions">
+                <button
+                  className="tonal-btn"
+                  onClick={() =>
+                    setConstantDrafts((drafts) => [
+                      ...(drafts ?? settings.agents.constants),
+                      { name: "", value: "" }
+                    ])
+                  }
+                >
+                  <Plus size={14} />
+                  <span>添加常量</span>
+                </button>
+                <button
+                  className="tonal-btn"
+                  disabled={constantDrafts === null}
+                  onClick={() => {
+                    if (constantDrafts === null) return;
+                    void updateSettings({ agents: { constants: constantDrafts } }).then((saved) => {
+                      if (saved) {
+                        setConstantDrafts(null);
+                        setStatus("提示词常量已保存");
+                      }
+                    });
+                  }}
+                >
+                  <CheckCircle2 size={14} />
+                  <span>保存常量</span>
+                </button>
+              </div>
+            </article>
+            <article className="settings-card">
+              <h2>翻译术语表</h2>
+              <p className="help-text">
+                原文中出现的术语会写入翻译提示词；译文未使用指定译法时，结果窗口会给出提示。
+              </p>
+              {settings.glossary.entries.map((entry, index) => (
+                <div className="glossary-entry-row" key={`glossary-${index}`}>
+                  <input
+                    value={entry.source}
+                    placeholder="原文术语"
+                    onChange={(event) => updateGlossaryEntry(index, { source: event.target.value })}
+                  />
+                  <input
+                    value={entry.target}
+                    placeholder="译法"
+                    onChange={(event) => updateGlossaryEntry(index, { target: event.target.value })}
+                  />
+                  <select
+                    className="md2-select"
+                    value={entry.targetLanguage}
+                    onChange={(event) =>
+                      updateGlossaryEntry(index, { targetLanguage: event.target.value })
+                    }
+                  >
+                    <option value="">任意目标语言</option>
+                    {translateLanguages.map((item) => (
+                      <option key={item.key} value={item.key}>
+                        {item.label}
+                      </option>
+                    ))}
+                  </select>
+                  <button
+                    className="icon-btn"
+                    title="删除术语"
+                    onClick={() => {
+                      void applyPatch({
+                        glossary: {
+                          entries: settings.glossary.entries.filter(
+                            (_, itemIndex) => itemIndex !== index
+                          )
+                        }
+                      });
+                    }}
+                  >
+                    <Trash2 size={14} />
+                  </button>
+                </div>
+              ))}
+              <button
+                className="tonal-btn"
+                onClick={() => {
+                  void applyPatch({
+                    glossary: {
+                      entries: [
+                        ...settings.glossary.entries,
+                        { source: "", target: "", sourceLanguage: "", targetLanguage: "" }
+                      ]
+                    }
+                  });
+                }}
+              >
+                <Plus size={14} />
+                添加术语
+              </button>
+              <div className="filled-control">
+                <label htmlFor="glossary-do-not-translate">不翻译的词（每行一个，如产品名、代码标识符）</label>
+                <textarea
+                  id="glossary-do-not-translate"
+                  className="settings-json"
+                  value={doNotTranslateDraft ?? settings.glossary.doNotTranslate.join("\n")}
+                  onChange={(event) => setDoNotTranslateDraft(event.target.value)}
+                  onBlur={() => {
+                    if (doNotTranslateDraft === null) return;
+                    const terms = doNotTranslateDraft
+                      .split("\n")
+                      .map((item) => item.trim())
+                      .filter(Boolean);
+                    setDoNotTranslateDraft(null);
+                    void applyPatch({ glossary: { doNotTranslate: terms } });
+                  }}
+                />
+              </div>
+              <div className="filled-control">
+                <label htmlFor="glossary-import">从 CSV / TSV 导入（原文,译法[,源语言,目标语言]；译法为空的行视为不翻译的词）</label>
+                <textarea
+                  id="glossary-import"
+                  className="settings-json"
+                  value={glossaryImportText}
+                  placeholder={"source,target\nrate limit,限流\nSnapParse,"}
+                  onChange={(event) => setGlossaryImportText(event.target.value)}
+                />
+              </div>
+              <div className="glossary-import-actions">
+                <select
+                  className="md2-select"
+                  value={glossaryImportTarget}
+                  onChange={(event) => setGlossaryImportTarget(event.target.value)}
+                >
+                  <option value="">任意目标语言</option>
+                  {translateLanguages.map((item) => (
+                    <option key={item.key} value={item.key}>
+                      {item.label}
+                    </option>
+                  ))}
+                </select>
+                <label className="check-row">
+                  <span>替换现有术语表</span>
+                  <input
+                    className="md2-check"
+                    type="checkbox"
+                    checked={glossaryImportReplace}
+                    onChange={(event) => setGlossaryImportReplace(event.target.checked)}
+                  />
+                </label>
+                <button className="tonal-btn" onClick={() => void importGlossaryFromTextarea()}>
+                  导入
+                </button>
+              </div>
+              {glossaryImportFeedback && <p className="help-text">{glossaryImportFeedback}</p>}
+            </article>
+            <article className="settings-card">
+              <h2>网络与代理</h2>
+              <div className="filled-control">
+                <label htmlFor="network-proxy-url">代理地址</label>
+                <input
+                  id="network-proxy-url"
+                  value={settings.network.proxyUrl}
+                  placeholder="留空使用系统代理，例如 http://127.0.0.1:7890 或 socks5://127.0.0.1:1080"
+                  onChange={(event) => {
+                    void applyPatch({ network: { proxyUrl: event.target.value } });
+                  }}
+                />
+              </div>
+              <div className="filled-control">
+                <label htmlFor="network-proxy-username">代理用户名</label>
+                <input
+                  id="network-proxy-username"
+                  value={settings.network.proxyUsername}
+                  onChange={(event) => {
+                    void applyPatch({ network: { proxyUsername: event.target.value } });
+                  }}
+                />
+              </div>
+              <div className="filled-control">
+                <label htmlFor="network-proxy-password">代理密码</label>
+                <input
+                  id="network-proxy-password"
+                  type="password"
+                  value={settings.network.proxyPassword}
+                  onChange={(event) => {
+                    void applyPatch({ network: { proxyPassword: event.target.value } });
+                  }}
+                />
+              </div>
+              <div className="filled-control">
+                <label htmlFor="network-no-proxy">不走代理的地址（逗号分隔）</label>
+                <input
+                  id="network-no-proxy"
+                  value={settings.network.noProxy}
+                  placeholder="例如：localhost, 127.0.0.1, .corp.example.com, 10.0.0.0/8"
+                  onChange={(event) => {
+                    void applyPatch({ network: { noProxy: event.target.value } });
+                  }}
+                />
+              </div>
+              <div className="filled-control">
+                <label htmlFor="network-extra-ca">额外信任的根证书（PEM 文件路径）</label>
+                <input
+                  id="network-extra-ca"
+                  value={settings.network.extraCaCertPath}
+                  placeholder="企业 TLS 检查网关的根证书，留空不添加"
+                  onChange={(event) => {
+                    void applyPatch({ network: { extraCaCertPath: event.target.value } });
+                  }}
+                />
+              </div>
+              <p className="help-text">
+                代理或证书配置无效时，模型请求会直接提示错误，而不会绕过代理。
+              </p>
+            </article>
+          </section>
+        )}
+
+        {activeGroup === "tts" && (
+          <section className="settings-stack">
+            <article className="settings-card">
+              <h2>语音播放（Edge TTS）</h2>
+
+              <div className="filled-control">
+                <label htmlFor="tts-runtime-mode">调用模式</label>
+                <select
+                  id="tts-runtime-mode"
+                  className="md2-select"
+                  value={settings.tts.runtimeMode}
+                  onChange={(event) => {
+                    void applyPatch({
+                      tts: {
+                        runtimeMode: parseTtsRuntimeMode(event.target.value)
+                      }
+                    });
+                  }}
+                >
+                  {TTS_RUNTIME_MODE_OPTIONS.map((item) => (
+                    <option key={item.key} value={item.key}>
+                      {item.label}
+                    </option>
+                  ))}
+                </select>
+              </div>
+
+              <div className="filled-control">
+                <label htmlFor="tts-voice-zh">中文 Voice</label>
+                <select
+                  id="tts-voice-zh"
+                  className="md2-select"
+                  value={settings.tts.voiceZhCn}
+                  onChange={(event) => {
+                    void applyPatch({
+                      tts: { voiceZhCn: event.target.value }
+                    });
+                  }}
+                >
+                  {!TTS_ZH_VOICE_OPTIONS.some((item) => item.key === settings.tts.voiceZhCn) && (
+                    <option value={settings.tts.voiceZhCn}>{settings.tts.voiceZhCn}</option>
+                  )}
                   {TTS_ZH_VOICE_OPTIONS.map((item) => (
                     <option key={item.key} value={item.key}>
                       {item.label}
@@ -5967,6 +8646,227 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
               </p>
             </article>
 
+            <article className="settings-card">
+              <h2>生词本</h2>
+              {wordList.length ? (
+                <ul className="word-list">
+                  {wordList.map((item) => (
+                    <li className="word-list-row" key={item.id}>
+                      <div>
+                        <strong>{item.entry.headword}</strong>
+                        {item.entry.pronunciation ? (
+                          <span className="dictionary-pronunciation">
+                            /{item.entry.pronunciation.replace(/^\/|\/$/g, "")}/
+                          </span>
+                        ) : null}
+                        <p className="help-text">
+                          {item.entry.translation}
+                          {item.lookupCount > 1 ? ` · 查询 ${item.lookupCount} 次` : ""}
+                        </p>
+                      </div>
+                      <button
+                        className="icon-btn"
+                        onClick={() => void removeWordListEntry(item.id)}
+                        aria-label="Remove word"
+                        title="删除"
+                      >
+                        <Trash2 size={14} />
+                      </button>
+                    </li>
+                  ))}
+                </ul>
+              ) : (
+                <p className="help-text">在划词翻译的词典结果中点击“加入生词本”即可保存单词。</p>
+              )}
+            </article>
+
+            <article className="settings-card">
+              <h2>模型用量</h2>
+              {usageStats ? (
+                <>
+                  <p className="help-text">
+                    本月：{usageStats.currentMonth.requests} 次请求 ·{" "}
+                    {formatUsageTokens(usageStats.currentMonth.totalTokens)}
+                    {settings.usage.monthlyTokenBudget > 0
+                      ? ` / ${formatUsageTokens(settings.usage.monthlyTokenBudget)}`
+                      : ""}{" "}
+                    · {formatUsageCost(usageStats.currentMonth.estimatedCostUsd)}
+                    {settings.usage.monthlyBudgetUsd > 0
+                      ? ` / ${formatUsageCost(settings.usage.monthlyBudgetUsd)}`
+                      : ""}
+                  </p>
+                  <div className="filled-control">
+                    <label htmlFor="usage-stats-days">统计范围</label>
+                    <select
+                      id="usage-stats-days"
+                      className="md2-select"
+                      value={usageStatsDays}
+                      onChange={(event) => setUsageStatsDays(Number(event.target.value))}
+                    >
+                      {USAGE_STATS_DAY_OPTIONS.map((days) => (
+                        <option key={days} value={days}>
+                          最近 {days} 天
+                        </option>
+                      ))}
+                    </select>
+                  </div>
+                  <p className="help-text">
+                    合计：{usageStats.totals.requests} 次请求 ·{" "}
+                    {formatUsageTokens(usageStats.totals.totalTokens)} ·{" "}
+                    {formatUsageCost(usageStats.totals.estimatedCostUsd)}
+                  </p>
+                  {usageStats.byAction.length ? (
+                    <>
+                      <h3>按动作</h3>
+                      <UsageBreakdownList items={usageStats.byAction} />
+                      <h3>按模型</h3>
+                      <UsageBreakdownList items={usageStats.byModel} />
+                    </>
+                  ) : (
+                    <p className="help-text">该时间范围内暂无模型调用。</p>
+                  )}
+                </>
+              ) : null}
+              <div className="filled-control">
+                <label htmlFor="usage-budget-usd">每月费用预算 (USD)</label>
+                <input
+                  id="usage-budget-usd"
+                  type="number"
+                  min={0}
+                  max={MONTHLY_BUDGET_USD_MAX}
+                  step={0.01}
+                  value={getNumberInputValue("usage-budget-usd", settings.usage.monthlyBudgetUsd)}
+                  onChange={(event) => {
+                    setNumberInputValue("usage-budget-usd", event.target.value);
+                  }}
+                  onKeyDown={blurNumberInputOnEnter}
+                  onBlur={() => {
+                    void commitFloatInput(
+                      "usage-budget-usd",
+                      settings.usage.monthlyBudgetUsd,
+                      0,
+                      MONTHLY_BUDGET_USD_MAX,
+                      2,
+                      (next) => ({ usage: { monthlyBudgetUsd: next } })
+                    );
+                  }}
+                />
+              </div>
+              <div className="filled-control">
+                <label htmlFor="usage-token-budget">每月 Token 预算</label>
+                <input
+                  id="usage-token-budget"
+                  type="number"
+                  min={0}
+                  step={1000}
+                  value={getNumberInputValue(
+                    "usage-token-budget",
+                    settings.usage.monthlyTokenBudget
+                  )}
+                  onChange={(event) => {
+                    setNumberInputValue("usage-token-budget", event.target.value);
+                  }}
+                  onKeyDown={blurNumberInputOnEnter}
+                  onBlur={() => {
+                    void commitIntegerInput(
+                      "usage-token-budget",
+                      settings.usage.monthlyTokenBudget,
+                      0,
+                      Number.MAX_SAFE_INTEGER,
+                      (next) => ({ usage: { monthlyTokenBudget: next } })
+                    );
+                  }}
+                />
+              </div>
+              <p className="help-text">预算为 0 表示不限制；费用按模型单价估算，仅供参考。</p>
+            </article>
+
+            <article className="settings-card">
+              <h2>AI 操作记录</h2>
+              <label className="check-row">
+                <span>记录划词与 OCR 的 AI 操作</span>
+                <input
+                  className="md2-check"
+                  type="checkbox"
+                  checked={settings.history.recordAiActions}
+                  onChange={(event) => {
+                    void applyPatch({ history: { recordAiActions: event.target.checked } });
+                  }}
+                />
+              </label>
+              <div className="filled-control">
+                <label htmlFor="action-log-search">搜索记录</label>
+                <input
+                  id="action-log-search"
+                  value={actionLogQuery}
+                  placeholder="按原文、结果、动作或模型搜索"
+                  onChange={(event) => setActionLogQuery(event.target.value)}
+                />
+              </div>
+              {actionLog.length ? (
+                <ul className="word-list">
+                  {actionLog.map((entry) => (
+                    <li className="word-list-row" key={entry.id}>
+                      <div className="action-log-summary">
+                        <strong>
+                          {entry.customAgentName || entry.action}
+                          {entry.surface === "ocr" ? " · OCR" : ""}
+                        </strong>
+                        <p className="help-text">
+                          {new Date(entry.createdAtMs).toLocaleString()} · {entry.model} ·{" "}
+                          {entry.fromCache ? "缓存" : `${entry.latencyMs} ms`}
+                        </p>
+                        <p className="help-text">{snippet(entry.sourceText)}</p>
+                        {entry.errorMessage ? (
+                          <p className="help-text action-log-error">{entry.errorMessage}</p>
+                        ) : null}
+                      </div>
+                      <div className="card-actions">
+                        <button
+                          className="icon-btn"
+                          onClick={() => void reopenActionLogEntry(entry.id)}
+                          aria-label="Reopen result"
+                          title="打开结果"
+                        >
+                          <ExternalLink size={14} />
+                        </button>
+                        <button
+                          className="icon-btn"
+                          onClick={() => void rerunActionLogEntry(entry)}
+                          aria-label="Run again"
+                          title="重新运行"
+                          disabled={!ACTION_LOG_RERUN_ACTIONS[entry.action]}
+                        >
+                          <RefreshCw size={14} />
+                        </button>
+                      </div>
+                    </li>
+                  ))}
+                </ul>
+              ) : (
+                <p className="help-text">
+                  {actionLogQuery.trim() ? "没有匹配的记录。" : "暂无 AI 操作记录。"}
+                </p>
+              )}
+              <div className="card-actions">
+                <button className="tonal-btn" onClick={() => void exportActionLog("json")}>
+                  <FileDown size={14} />
+                  <span>导出 JSON</span>
+                </button>
+                <button className="tonal-btn" onClick={() => void exportActionLog("csv")}>
+                  <FileDown size={14} />
+                  <span>导出 CSV</span>
+                </button>
+                <button className="tonal-btn" onClick={() => void clearActionLog()}>
+                  <Trash2 size={14} />
+                  <span>清空记录</span>
+                </button>
+              </div>
+              {actionLogExport && (
+                <textarea className="settings-json" readOnly value={actionLogExport} />
+              )}
+            </article>
+
             <article className="settings-card">
               <h2>历史维护</h2>
               <div className="card-actions">
@@ -6153,6 +9053,21 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
                         </div>
                       </div>
 
+                      <div className="filled-control">
+                        <label htmlFor="agent-system-prompt">系统提示词（可选）</label>
+                        <textarea
+                          id="agent-system-prompt"
+                          className="settings-json agent-prompt-input"
+                          value={activeAgentDraft.systemPrompt}
+                          placeholder="留空使用默认系统提示词"
+                          onChange={(event) => {
+                            setCustomAgentDraft(activeAgentDraft.id, {
+                              systemPrompt: event.target.value
+                            });
+                          }}
+                        />
+                      </div>
+
                       <div className="filled-control">
                         <label htmlFor="agent-prompt">Prompt</label>
                         <textarea
@@ -6166,6 +9081,203 @@ function SettingsWindow({ settingsApi }: { settingsApi: SettingsApi }) {
                           }}
                         />
                       </div>
+                      <p className="help-text">
+                        可用变量：
+                        {[
+                          ...CUSTOM_AGENT_TEMPLATE_VARIABLES,
+                          ...settings.agents.constants.map((item) => item.name)
+                        ]
+                          .map((name) => `{${name}}`)
+                          .join("、")}
+                        。{"{?clipboard}…{/clipboard}"} 仅在变量非空时保留，
+                        {"{!clipboard}…{/clipboard}"} 仅在变量为空时保留。
+                      </p>
+
+                      <div className="filled-control">
+                        <label>工作流步骤（设置后按顺序执行，上一步的输出作为下一步的 {"{text}"}，Prompt 将被忽略）</label>
+                        {activeAgentDraft.steps.map((step, index) => {
+                          const updateStep = (patch: Partial<WorkflowStep>) =>
+                            setCustomAgentDraft(activeAgentDraft.id, {
+                              steps: activeAgentDraft.steps.map((item, itemIndex) =>
+                                itemIndex === index ? { ...item, ...patch } : item
+                              )
+                            });
+                          const moveStep = (offset: number) => {
+                            const steps = [...activeAgentDraft.steps];
+                            const [moved] = steps.splice(index, 1);
+                            steps.splice(index + offset, 0, moved);
+                            setCustomAgentDraft(activeAgentDraft.id, { steps });
+                          };
+                          return (
+                            <div className="workflow-step-row" key={`workflow-step-${index}`}>
+                              <span className="workflow-step-index">{index + 1}</span>
+                              <select
+                                className="md2-select"
+                                value={step.kind}
+                                onChange={(event) =>
+                                  updateStep({ kind: event.target.value as WorkflowStep["kind"] })
+                                }
+                              >
+                                {WORKFLOW_STEP_KIND_OPTIONS.map((item) => (
+                                  <option key={item.key} value={item.key}>
+                                    {item.label}
+                                  </option>
+                                ))}
+                              </select>
+                              {step.kind === "builtin" && (
+                                <select
+                                  className="md2-select"
+                                  value={step.action}
+                                  onChange={(event) =>
+                                    updateStep({
+                                      action: event.target.value as WorkflowStep["action"]
+                                    })
+                                  }
+                                >
+                                  {WORKFLOW_BUILTIN_OPTIONS.map((item) => (
+                                    <option key={item.key} value={item.key}>
+                                      {item.label}
+                                    </option>
+                                  ))}
+                                </select>
+                              )}
+                              {step.kind === "transform" && (
+                                <select
+                                  className="md2-select"
+                                  value={step.transform}
+                                  onChange={(event) =>
+                                    updateStep({
+                                      transform: event.target.value as WorkflowStep["transform"]
+                                    })
+                                  }
+                                >
+                                  {LOCAL_TRANSFORM_OPTIONS.map((item) => (
+                                    <option key={item.key} value={item.key}>
+                                      {item.label}
+                                    </option>
+                                  ))}
+                                </select>
+                              )}
+                              {step.kind === "prompt" && (
+                                <textarea
+                                  className="settings-json workflow-step-prompt"
+                                  value={step.prompt}
+                                  placeholder={CUSTOM_AGENT_PROMPT_TEMPLATE}
+                                  onChange={(event) => updateStep({ prompt: event.target.value })}
+                                />
+                              )}
+                              <button
+                                className="icon-btn compact"
+                                onClick={() => moveStep(-1)}
+                                disabled={index === 0}
+                                aria-label="上移"
+                                title="上移"
+                              >
+                                <ChevronUp size={13} />
+                              </button>
+                              <button
+                                className="icon-btn compact"
+                                onClick={() => moveStep(1)}
+                                disabled={index === activeAgentDraft.steps.length - 1}
+                                aria-label="下移"
+                                title="下移"
+                              >
+                                <ChevronDown size={13} />
+                              </button>
+                              <button
+                                className="icon-btn compact"
+                                onClick={() =>
+                                  setCustomAgentDraft(activeAgentDraft.id, {
+                                    steps: activeAgentDraft.steps.filter(
+                                      (_, itemIndex) => itemIndex !== index
+                                    )
+                                  })
+                                }
+                                aria-label="删除步骤"
+                                title="删除步骤"
+                              >
+                                <Trash2 size={12} />
+                              </button>
+                            </div>
+                          );
+                        })}
+                        <button
+                          className="tonal-btn compact"
+                          disabled={activeAgentDraft.steps.length >= MAX_WORKFLOW_STEPS}
+                          onClick={() =>
+                            setCustomAgentDraft(activeAgentDraft.id, {
+                              steps: [
+                                ...activeAgentDraft.steps,
+                                {
+                                  kind: "builtin",
+                                  action: "translate",
+                                  prompt: CUSTOM_AGENT_PROMPT_TEMPLATE,
+                                  transform: "fix-line-breaks"
+                                }
+                              ]
+                            })
+                          }
+                        >
+                          <Plus size={13} />
+                          <span>添加步骤</span>
+                        </button>
+                      </div>
+
+                      <div className="filled-control">
+                        <label htmlFor="agent-output-schema">输出 JSON Schema（可选）</label>
+                        <textarea
+                          id="agent-output-schema"
+                          className="settings-json"
+                          value={activeAgentDraft.outputSchema}
+                          placeholder={'{"type": "object", "properties": {"total": {"type": "number"}}, "required": ["total"]}'}
+                          onChange={(event) => {
+                            setCustomAgentDraft(activeAgentDraft.id, {
+                              outputSchema: event.target.value
+                            });
+                          }}
+                        />
+                      </div>
+                      <p className="help-text">
+                        设置后模型以 JSON 输出，结果按 Schema 校验，不通过时自动重试，并在结果窗口以表格展示，可复制为 JSON、CSV 或 Markdown。工作流 Agent 不能设置此项。
+                      </p>
+
+                      <div className="filled-control">
+                        <label>可调用的工具</label>
+                        <div className="agent-tool-options">
+                          {AGENT_TOOL_OPTIONS.map((item) => (
+                            <label className="check-row" key={item.key}>
+                              <span>{item.label}</span>
+                              <input
+                                className="md2-check"
+                                type="checkbox"
+                                checked={activeAgentDraft.tools.includes(item.key)}
+                                onChange={(event) =>
+                                  setCustomAgentDraft(activeAgentDraft.id, {
+                                    tools: event.target.checked
+                                      ? [...activeAgentDraft.tools, item.key]
+                                      : activeAgentDraft.tools.filter((tool) => tool !== item.key)
+                                  })
+                                }
+                              />
+                            </label>
+                          ))}
+                        </div>
+                      </div>
+                      <p className="help-text">
+                        需要模型支持 tool calling。工具结果不会缓存，调用记录显示在结果窗口；不能与输出 JSON Schema 或工作流步骤同时使用，打开链接也不能与读取剪贴板的工具同时启用。
+                      </p>
+
+                      <details className="agent-advanced-params">
+                        <summary>高级请求参数</summary>
+                        <GenerationParamsEditor
+                          key={activeAgentDraft.id}
+                          idPrefix={`agent-${activeAgentDraft.id}`}
+                          value={activeAgentDraft.params}
+                          onChange={(params) => {
+                            setCustomAgentDraft(activeAgentDraft.id, { params });
+                          }}
+                        />
+                      </details>
                     </>
                   ) : (
                     <p className="help-text">请选择或创建一个 Agent 进行编辑。</p>
@@ -20,13 +20,17 @@ export type SelectionTriggerMode = "auto-detect" | "copy-trigger";
 export type SelectionActionKind = "summarize" | "polish" | "explain" | "translate" | "custom";
 export type OcrActionKind = "translate" | "summarize" | "polish" | "explain" | "custom";
 export type TtsRuntimeMode = "dual-fallback" | "edge-cli-only" | "python-module-only";
+export type BilingualMode = "off" | "paragraph" | "sentence";
+
+export type ReasoningDisplay = "hide" | "collapse" | "show";
 export type BuiltinSelectionBarActionKey =
   | "copy"
   | "summarize"
   | "polish"
   | "explain"
   | "translate"
-  | "search";
+  | "search"
+  | "polish-replace";
 export type SelectionBarActionKey = BuiltinSelectionBarActionKey | `custom:${string}`;
 
 export interface WindowSettings {
@@ -47,9 +51,39 @@ export interface SelectionAssistantSettings {
   minChars: number;
   maxChars: number;
   blockedApps: string[];
-  defaultTranslateTo: "zh-CN" | "en-US" | "ja-JP" | "ko-KR";
+  defaultTranslateTo: string;
+  smartTranslateTarget: boolean;
+  bilingualMode: BilingualMode;
+  dictionaryMode: boolean;
   resultWindowAlwaysOnTop: boolean;
   rememberResultWindowPosition: boolean;
+  appRules: AppSelectionRule[];
+  smartBarSuggestions: boolean;
+}
+
+export interface AppSelectionRule {
+  id: string;
+  app: string;
+  enabled: boolean;
+  barItems: SelectionBarActionKey[];
+  autoRun: SelectionBarActionKey | "";
+  translateFrom: string;
+  translateTo: string;
+}
+
+export interface ExtraHeader {
+  name: string;
+  value: string;
+}
+
+export interface LlmProviderProfile {
+  id: string;
+  name: string;
+  enabled: boolean;
+  baseUrl: string;
+  apiKey: string;
+  model: string;
+  extraHeaders: ExtraHeader[];
 }
 
 export interface LlmSettings {
@@ -60,6 +94,38 @@ export interface LlmSettings {
   temperature: number;
   maxTokens: number;
   timeoutMs: number;
+  requestsPerMinute: number;
+  tokensPerMinute: number;
+  reasoningDisplay: ReasoningDisplay;
+  extraHeaders: ExtraHeader[];
+  fallbackProfiles: LlmProviderProfile[];
+  chunkTokens: number;
+  contextTokens: number;
+}
+
+export type ResponseFormatMode = "text" | "json-object";
+
+export interface GenerationParams {
+  topP: number | null;
+  frequencyPenalty: number | null;
+  presencePenalty: number | null;
+  stop: string[];
+  seed: number | null;
+  responseFormat: ResponseFormatMode;
+  extraBody: string;
+}
+
+export interface BuiltinActionConfig {
+  params: GenerationParams;
+  systemPrompt: string;
+  userPrompt: string;
+}
+
+export interface BuiltinActionSettings {
+  summarize: BuiltinActionConfig;
+  polish: BuiltinActionConfig;
+  explain: BuiltinActionConfig;
+  translate: BuiltinActionConfig;
 }
 
 export interface CustomAgent {
@@ -67,8 +133,41 @@ export interface CustomAgent {
   name: string;
   icon: string;
   prompt: string;
+  systemPrompt: string;
+  steps: WorkflowStep[];
+  outputSchema: string;
+  tools: AgentTool[];
   enabled: boolean;
   order: number;
+  params: GenerationParams;
+}
+
+export type AgentTool =
+  | "read-clipboard"
+  | "search-clipboard-history"
+  | "current-datetime"
+  | "evaluate-math"
+  | "open-url"
+  | "save-snippet";
+
+export type WorkflowStepKind = "builtin" | "prompt" | "transform";
+export type WorkflowBuiltinAction = "translate" | "summarize" | "polish" | "explain";
+export type LocalTransform =
+  | "fix-line-breaks"
+  | "collapse-whitespace"
+  | "remove-empty-lines"
+  | "strip-markdown";
+
+export interface WorkflowStep {
+  kind: WorkflowStepKind;
+  action: WorkflowBuiltinAction;
+  prompt: string;
+  transform: LocalTransform;
+}
+
+export interface PromptConstant {
+  name: string;
+  value: string;
 }
 
 export interface SelectionBarItemConfig {
@@ -80,6 +179,8 @@ export interface SelectionBarItemConfig {
 export interface AgentSettings {
   custom: CustomAgent[];
   barOrder: SelectionBarItemConfig[];
+  builtin: BuiltinActionSettings;
+  constants: PromptConstant[];
 }
 
 export interface ShortcutSettings {
@@ -95,6 +196,9 @@ export interface VisionSettings {
   temperature: number;
   maxTokens: number;
   timeoutMs: number;
+  requestsPerMinute: number;
+  tokensPerMinute: number;
+  extraHeaders: ExtraHeader[];
 }
 
 export interface OcrSettings {
@@ -144,6 +248,45 @@ export interface HistorySettings {
   promoteAfterPaste: boolean;
   openAtTopOnShow: boolean;
   storagePath: string;
+  recordAiActions: boolean;
+}
+
+export interface ResponseCacheSettings {
+  enabled: boolean;
+  maxSizeMb: number;
+  ttlHours: number;
+}
+
+export interface NetworkSettings {
+  proxyUrl: string;
+  proxyUsername: string;
+  proxyPassword: string;
+  noProxy: string;
+  extraCaCertPath: string;
+}
+
+export interface GlossaryEntry {
+  source: string;
+  target: string;
+  sourceLanguage: string;
+  targetLanguage: string;
+}
+
+export interface GlossarySettings {
+  entries: GlossaryEntry[];
+  doNotTranslate: string[];
+}
+
+export interface ModelPriceOverride {
+  model: string;
+  inputPerMillionUsd: number;
+  outputPerMillionUsd: number;
+}
+
+export interface UsageSettings {
+  monthlyBudgetUsd: number;
+  monthlyTokenBudget: number;
+  priceOverrides: ModelPriceOverride[];
 }
 
 export interface AppSettings {
@@ -159,6 +302,10 @@ export interface AppSettings {
   ocr: OcrSettings;
   appearance: AppearanceSettings;
   history: HistorySettings;
+  responseCache: ResponseCacheSettings;
+  usage: UsageSettings;
+  network: NetworkSettings;
+  glossary: GlossarySettings;
 }
 
 export interface WindowSettingsPatch {
@@ -179,9 +326,13 @@ export interface SelectionAssistantSettingsPatch {
   minChars?: number;
   maxChars?: number;
   blockedApps?: string[];
-  defaultTranslateTo?: "zh-CN" | "en-US" | "ja-JP" | "ko-KR";
+  defaultTranslateTo?: string;
+  smartTranslateTarget?: boolean;
+  bilingualMode?: BilingualMode;
   resultWindowAlwaysOnTop?: boolean;
   rememberResultWindowPosition?: boolean;
+  appRules?: AppSelectionRule[];
+  smartBarSuggestions?: boolean;
 }
 
 export interface LlmSettingsPatch {
@@ -192,11 +343,20 @@ export interface LlmSettingsPatch {
   temperature?: number;
   maxTokens?: number;
   timeoutMs?: number;
+  requestsPerMinute?: number;
+  tokensPerMinute?: number;
+  reasoningDisplay?: ReasoningDisplay;
+  extraHeaders?: ExtraHeader[];
+  fallbackProfiles?: LlmProviderProfile[];
+  chunkTokens?: number;
+  contextTokens?: number;
 }
 
 export interface AgentSettingsPatch {
   custom?: CustomAgent[];
   barOrder?: SelectionBarItemConfig[];
+  builtin?: BuiltinActionSettings;
+  constants?: PromptConstant[];
 }
 
 export interface ShortcutSettingsPatch {
@@ -212,6 +372,9 @@ export interface VisionSettingsPatch {
   temperature?: number;
   maxTokens?: number;
   timeoutMs?: number;
+  requestsPerMinute?: number;
+  tokensPerMinute?: number;
+  extraHeaders?: ExtraHeader[];
 }
 
 export interface OcrSettingsPatch {
@@ -261,6 +424,32 @@ export interface HistorySettingsPatch {
   promoteAfterPaste?: boolean;
   openAtTopOnShow?: boolean;
   storagePath?: string;
+  recordAiActions?: boolean;
+}
+
+export interface ResponseCacheSettingsPatch {
+  enabled?: boolean;
+  maxSizeMb?: number;
+  ttlHours?: number;
+}
+
+export interface NetworkSettingsPatch {
+  proxyUrl?: string;
+  proxyUsername?: string;
+  proxyPassword?: string;
+  noProxy?: string;
+  extraCaCertPath?: string;
+}
+
+export interface GlossarySettingsPatch {
+  entries?: GlossaryEntry[];
+  doNotTranslate?: string[];
+}
+
+export interface UsageSettingsPatch {
+  monthlyBudgetUsd?: number;
+  monthlyTokenBudget?: number;
+  priceOverrides?: ModelPriceOverride[];
 }
 
 export interface AppSettingsPatch {
@@ -275,4 +464,63 @@ export interface AppSettingsPatch {
   ocr?: OcrSettingsPatch;
   appearance?: AppearanceSettingsPatch;
   history?: HistorySettingsPatch;
+  responseCache?: ResponseCacheSettingsPatch;
+  usage?: UsageSettingsPatch;
+  network?: NetworkSettingsPatch;
+  glossary?: GlossarySettingsPatch;
+}
+
+export interface UsageTotals {
+  requests: number;
+  promptTokens: number;
+  completionTokens: number;
+  totalTokens: number;
+  estimatedCostUsd: number;
+}
+
+export interface UsageBreakdownItem extends UsageTotals {
+  key: string;
+}
+
+export interface UsageStats {
+  days: number;
+  totals: UsageTotals;
+  byDay: UsageBreakdownItem[];
+  byModel: UsageBreakdownItem[];
+  byAction: UsageBreakdownItem[];
+  currentMonth: UsageTotals;
+  monthlyBudgetUsd: number;
+  monthlyTokenBudget: number;
+}
+
+export type ActionSurface = "selection" | "ocr";
+
+export interface ActionLogEntry {
+  id: string;
+  requestId: string;
+  createdAtMs: number;
+  surface: ActionSurface;
+  action: string;
+  customAgentId?: string | null;
+  customAgentName?: string | null;
+  customAgentIcon?: string | null;
+  translateFrom?: string | null;
+  translateTo?: string | null;
+  sourceText: string;
+  ocrImagePath?: string | null;
+  systemPrompt: string;
+  userPrompt: string;
+  model: string;
+  outputText: string;
+  latencyMs: number;
+  fromCache: boolean;
+  errorCode?: string | null;
+  errorMessage?: string | null;
+}
+
+export interface ActionLogQuery {
+  query?: string;
+  surface?: ActionSurface;
+  offset?: number;
+  limit?: number;
 }